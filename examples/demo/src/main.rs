@@ -3,8 +3,8 @@ use egui::ScrollArea;
 use ringbuf::{Consumer, Producer, RingBuffer};
 
 use rusty_daw_io::{
-    ConfigStatus, FatalErrorHandler, FatalStreamError, ProcessInfo, RtProcessHandler, StreamInfo,
-    SystemOptions,
+    ConfigStatus, FatalErrorHandler, FatalStreamError, ProcessControl, ProcessInfo,
+    RtProcessHandler, StreamInfo, SystemOptions,
 };
 
 static SPACING: f32 = 30.0;
@@ -27,7 +27,9 @@ struct MyRtProcessHandler {}
 
 impl RtProcessHandler for MyRtProcessHandler {
     fn init(&mut self, stream_info: &StreamInfo) {}
-    fn process(&mut self, proc_info: ProcessInfo) {}
+    fn process(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+        ProcessControl::Continue
+    }
 }
 
 struct MyFatalErrorHandler {
@@ -1,6 +1,7 @@
 use rusty_daw_io::{
-    AudioBusConfig, Config, DevicesInfo, FatalErrorHandler, FatalStreamError, MidiControllerConfig,
-    ProcessInfo, RtProcessHandler, StreamInfo,
+    AudioBusConfig, Config, DevicesInfo, FailOrRename, FatalErrorHandler, FatalStreamError,
+    JackSelfConnect, MidiControllerConfig, MidiTimestamps, ProcessControl, ProcessInfo,
+    RtProcessHandler, RtThreadConfig, StreamDirection, StreamInfo,
 };
 
 fn main() {
@@ -21,6 +22,7 @@ fn main() {
                 String::from("system:capture_1"),
                 String::from("system:capture_2"),
             ],
+            ..Default::default()
         }],
         audio_out_busses: vec![AudioBusConfig {
             id: String::from("audio_out"),
@@ -28,22 +30,39 @@ fn main() {
                 String::from("system:playback_1"),
                 String::from("system:playback_2"),
             ],
+            ..Default::default()
         }],
 
         sample_rate: None,
         buffer_size: None,
+        direction: StreamDirection::default(),
 
         midi_server: Some(String::from("Jack")),
 
         midi_in_controllers: vec![MidiControllerConfig {
             id: String::from("midi_in"),
             system_port: String::from("system:midi_capture_2"),
+            ..Default::default()
         }],
 
         midi_out_controllers: vec![MidiControllerConfig {
             id: String::from("midi_out"),
             system_port: String::from("system:midi_playback_1"),
+            ..Default::default()
         }],
+
+        midi_in_timestamps: MidiTimestamps::default(),
+
+        jack_self_connect: JackSelfConnect::SystemPorts,
+        jack_server_name: None,
+        jack_pretty_names: false,
+        buffer_size_history: None,
+        rt_thread: RtThreadConfig::default(),
+        spawn_timeout: None,
+        on_duplicate_id: FailOrRename::default(),
+        input_peaks: false,
+        allow_denormals: false,
+        resample: None,
     };
 
     let stream_handle = rusty_daw_io::spawn_rt_thread(
@@ -66,7 +85,9 @@ struct MyRtProcessHandler {}
 
 impl RtProcessHandler for MyRtProcessHandler {
     fn init(&mut self, stream_info: &StreamInfo) {}
-    fn process(&mut self, proc_info: ProcessInfo) {}
+    fn process(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+        ProcessControl::Continue
+    }
 }
 
 struct MyFatalErrorHandler {}
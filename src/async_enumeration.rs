@@ -0,0 +1,203 @@
+//! Enumeration that can be awaited instead of blocking, e.g. from the event handler of an
+//! async UI.
+//!
+//! This doesn't depend on any async runtime. Each enumeration runs on a thread of its own,
+//! and its future is woken once the thread is done.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::enumeration::{enumerate_audio_server, enumerate_midi_server, enumerate_servers};
+use crate::{AudioServerInfo, Backend, EnumerationError, MidiServerInfo};
+
+/// Enumerate the audio devices of a single backend without blocking.
+///
+/// See `enumerate_audio_devices()`.
+pub fn enumerate_audio_devices_async(backend: Backend) -> EnumerationFuture<AudioServerInfo> {
+    EnumerationFuture::spawn(backend, enumerate_audio_server)
+}
+
+/// Enumerate the midi devices of a single backend without blocking.
+///
+/// See `enumerate_midi_devices()`.
+pub fn enumerate_midi_devices_async(backend: Backend) -> EnumerationFuture<MidiServerInfo> {
+    EnumerationFuture::spawn(backend, enumerate_midi_server)
+}
+
+/// Enumerate the audio and the midi devices of a single backend without blocking.
+///
+/// See `enumerate_devices()`.
+pub fn enumerate_devices_async(
+    backend: Backend,
+) -> EnumerationFuture<(AudioServerInfo, MidiServerInfo)> {
+    EnumerationFuture::spawn(backend, enumerate_servers)
+}
+
+/// The result of an enumeration, once its thread is done.
+///
+/// Like `PendingEnumeration`, the thread can't be interrupted while it is talking to the
+/// server. Dropping this only means that its result is thrown away once it is done.
+pub struct EnumerationFuture<T> {
+    backend: Backend,
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+struct Slot<T> {
+    result: Option<Result<T, EnumerationError>>,
+    waker: Option<Waker>,
+}
+
+impl<T: Send + 'static> EnumerationFuture<T> {
+    fn spawn(backend: Backend, enumerate: fn(Backend) -> Option<T>) -> Self {
+        let future = Self {
+            backend,
+            slot: Arc::new(Mutex::new(Slot {
+                result: None,
+                waker: None,
+            })),
+        };
+
+        if !backend.is_compiled() {
+            future.fail(EnumerationError::BackendNotCompiled(backend));
+            return future;
+        }
+
+        let sender = Sender {
+            backend,
+            slot: Arc::clone(&future.slot),
+        };
+        let spawned = std::thread::Builder::new()
+            .name(format!("rusty-daw-io {} enumeration", backend))
+            .spawn(move || {
+                let result =
+                    enumerate(backend).ok_or(EnumerationError::BackendNotCompiled(backend));
+                sender.send(result);
+            });
+        if spawned.is_err() {
+            future.fail(EnumerationError::EnumerationThreadFailed(backend));
+        }
+
+        future
+    }
+}
+
+impl<T> EnumerationFuture<T> {
+    /// The backend that is being enumerated.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    fn fail(&self, error: EnumerationError) {
+        if let Ok(mut slot) = self.slot.lock() {
+            slot.result = Some(Err(error));
+        }
+    }
+}
+
+impl<T> Future for EnumerationFuture<T> {
+    type Output = Result<T, EnumerationError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = match self.slot.lock() {
+            Ok(slot) => slot,
+            Err(_) => {
+                return Poll::Ready(Err(EnumerationError::EnumerationThreadFailed(self.backend)))
+            }
+        };
+
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Hands the result of the enumeration thread to its future. If the thread panics this is
+/// dropped without a result, which fails the future instead of leaving it pending forever.
+struct Sender<T> {
+    backend: Backend,
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Sender<T> {
+    fn send(self, result: Result<T, EnumerationError>) {
+        if let Ok(mut slot) = self.slot.lock() {
+            slot.result = Some(result);
+        }
+        // Dropping wakes the future.
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = match self.slot.lock() {
+            Ok(mut slot) => {
+                if slot.result.is_none() {
+                    slot.result =
+                        Some(Err(EnumerationError::EnumerationThreadFailed(self.backend)));
+                }
+                slot.waker.take()
+            }
+            Err(_) => None,
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DUMMY_DEVICE_NAME;
+    use std::task::Wake;
+    use std::thread::Thread;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Poll `future` to completion on this thread.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn enumerates_without_blocking() {
+        let server = block_on(enumerate_audio_devices_async(Backend::Dummy)).unwrap();
+        assert!(server.available);
+        assert_eq!(server.devices[0].name, DUMMY_DEVICE_NAME);
+
+        let (audio_server, midi_server) =
+            block_on(enumerate_devices_async(Backend::Dummy)).unwrap();
+        assert_eq!(audio_server.name, Backend::Dummy.as_str());
+        assert_eq!(midi_server.name, Backend::Dummy.as_str());
+        assert!(!midi_server.in_devices.is_empty());
+
+        let future = enumerate_midi_devices_async(Backend::CoreAudio);
+        assert_eq!(future.backend(), Backend::CoreAudio);
+        assert!(matches!(
+            block_on(future),
+            Err(EnumerationError::BackendNotCompiled(Backend::CoreAudio))
+        ));
+    }
+}
@@ -1,19 +1,58 @@
+use crate::{AudioBus, ChannelLayout, ChannelPosition};
+
 #[derive(Debug)]
 pub struct AudioBusBuffer {
     pub(crate) channel_buffers: Vec<Vec<f32>>,
     pub(crate) frames: usize,
+    layout: ChannelLayout,
+    peaks: Option<Vec<f32>>,
 }
 
 impl AudioBusBuffer {
+    #[cfg(test)]
     pub(crate) fn new(channels: u16, max_buffer_size: u32) -> AudioBusBuffer {
+        Self::with_layout(ChannelLayout::for_channels(channels), max_buffer_size)
+    }
+
+    /// A buffer for the given bus, with the bus's channel layout.
+    pub(crate) fn for_bus(bus: &AudioBus, max_buffer_size: u32) -> AudioBusBuffer {
+        Self::with_layout(bus.channel_layout.clone(), max_buffer_size)
+    }
+
+    /// A buffer for the given input bus, which measures the peak level of every channel
+    /// each cycle if `measure_peaks` is set.
+    pub(crate) fn for_input_bus(
+        bus: &AudioBus,
+        max_buffer_size: u32,
+        measure_peaks: bool,
+    ) -> AudioBusBuffer {
+        let mut buffer = Self::for_bus(bus, max_buffer_size);
+        if measure_peaks {
+            buffer.peaks = Some(vec![0.0; buffer.channel_buffers.len()]);
+        }
+        buffer
+    }
+
+    fn with_layout(layout: ChannelLayout, max_buffer_size: u32) -> AudioBusBuffer {
         let mut channel_buffers = Vec::<Vec<f32>>::new();
-        for _ in 0..channels {
+        for _ in 0..layout.num_channels() {
             channel_buffers.push(Vec::<f32>::with_capacity(max_buffer_size as usize));
         }
 
         AudioBusBuffer {
             channel_buffers,
             frames: 0,
+            layout,
+            peaks: None,
+        }
+    }
+
+    /// Make room for `max_buffer_size` frames in every channel, so a cycle of up to that
+    /// many frames doesn't allocate. This allocates, so it must be called between cycles.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn reserve_frames(&mut self, max_buffer_size: usize) {
+        for channel in self.channel_buffers.iter_mut() {
+            channel.reserve(max_buffer_size.saturating_sub(channel.len()));
         }
     }
 
@@ -29,6 +68,34 @@ impl AudioBusBuffer {
         self.frames = frames;
     }
 
+    /// Measure the peaks of this cycle, once the channels have been filled. This does
+    /// nothing if the buffer doesn't measure peaks, and never allocates.
+    pub(crate) fn update_peaks(&mut self) {
+        if let Some(peaks) = self.peaks.as_mut() {
+            let frames = self.frames;
+            for (peak, channel) in peaks.iter_mut().zip(self.channel_buffers.iter()) {
+                *peak = channel
+                    .iter()
+                    .take(frames)
+                    .fold(0.0f32, |peak, s| peak.max(s.abs()));
+            }
+        }
+    }
+
+    /// The peak level (the largest absolute sample value) of the given channel in this
+    /// cycle.
+    ///
+    /// This is only measured for audio input busses when `Config::input_peaks` is set, and
+    /// is `None` otherwise.
+    pub fn peak(&self, channel: usize) -> Option<f32> {
+        self.peaks.as_ref().and_then(|p| p.get(channel).copied())
+    }
+
+    /// The peak level of every channel in this cycle, see `peak()`.
+    pub fn peaks(&self) -> Option<&[f32]> {
+        self.peaks.as_deref()
+    }
+
     pub fn get(&self, channel: usize) -> Option<&[f32]> {
         self.channel_buffers.get(channel).map(|c| c.as_slice())
     }
@@ -39,6 +106,20 @@ impl AudioBusBuffer {
             .map(|c| c.as_mut_slice())
     }
 
+    /// The channel with the given position in the bus's channel layout.
+    pub fn channel(&self, position: ChannelPosition) -> Option<&[f32]> {
+        self.get(self.layout.channel_index(position)?)
+    }
+
+    /// The channel with the given position in the bus's channel layout.
+    pub fn channel_mut(&mut self, position: ChannelPosition) -> Option<&mut [f32]> {
+        self.get_mut(self.layout.channel_index(position)?)
+    }
+
+    pub fn layout(&self) -> &ChannelLayout {
+        &self.layout
+    }
+
     pub fn channels(&self) -> &[Vec<f32>] {
         self.channel_buffers.as_slice()
     }
@@ -54,6 +135,60 @@ impl AudioBusBuffer {
     pub fn frames(&self) -> usize {
         self.frames
     }
+
+    /// Copy the frames of this cycle into `interleaved`, one sample per channel for every
+    /// frame (e.g. `L R L R ...` for a stereo bus).
+    ///
+    /// This stops at whichever runs out first, `frames()` or `interleaved`, and returns
+    /// the number of frames that were copied. The rest of `interleaved` is left as it is.
+    pub fn interleave_into(&self, interleaved: &mut [f32]) -> usize {
+        let channels = self.channel_buffers.len();
+        let frames = match interleaved.len().checked_div(channels) {
+            Some(frames) => self.frames.min(frames),
+            None => return 0,
+        };
+        if frames == 0 {
+            return 0;
+        }
+
+        for (channel_i, channel) in self.channel_buffers.iter().enumerate() {
+            for (frame, sample) in interleaved[channel_i..]
+                .iter_mut()
+                .step_by(channels)
+                .zip(channel[..frames].iter())
+            {
+                *frame = *sample;
+            }
+        }
+
+        frames
+    }
+
+    /// Copy interleaved frames into the channels of this cycle. This is the counterpart of
+    /// `interleave_into()`, and likewise returns the number of frames that were copied.
+    ///
+    /// The frames of this cycle past the end of `interleaved` are left as they are.
+    pub fn deinterleave_from(&mut self, interleaved: &[f32]) -> usize {
+        let channels = self.channel_buffers.len();
+        let frames = match interleaved.len().checked_div(channels) {
+            Some(frames) => self.frames.min(frames),
+            None => return 0,
+        };
+        if frames == 0 {
+            return 0;
+        }
+
+        for (channel_i, channel) in self.channel_buffers.iter_mut().enumerate() {
+            for (sample, frame) in channel[..frames]
+                .iter_mut()
+                .zip(interleaved[channel_i..].iter().step_by(channels))
+            {
+                *sample = *frame;
+            }
+        }
+
+        frames
+    }
 }
 
 impl std::ops::Index<usize> for AudioBusBuffer {
@@ -68,3 +203,35 @@ impl std::ops::IndexMut<usize> for AudioBusBuffer {
         self.channel_buffers[index].as_mut_slice()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleave_round_trip() {
+        let mut buffer = AudioBusBuffer::new(2, 4);
+        buffer.clear_and_resize(3);
+        buffer[0].copy_from_slice(&[1.0, 2.0, 3.0]);
+        buffer[1].copy_from_slice(&[-1.0, -2.0, -3.0]);
+
+        let mut interleaved = [0.0; 8];
+        assert_eq!(buffer.interleave_into(&mut interleaved), 3);
+        assert_eq!(interleaved, [1.0, -1.0, 2.0, -2.0, 3.0, -3.0, 0.0, 0.0]);
+
+        // A partial last frame is not copied.
+        let mut short = [0.0; 3];
+        assert_eq!(buffer.interleave_into(&mut short), 1);
+        assert_eq!(short, [1.0, -1.0, 0.0]);
+
+        buffer.clear_and_resize(3);
+        assert_eq!(buffer.deinterleave_from(&interleaved[..4]), 2);
+        assert_eq!(&buffer[0], &[1.0, 2.0, 0.0]);
+        assert_eq!(&buffer[1], &[-1.0, -2.0, 0.0]);
+
+        assert_eq!(
+            AudioBusBuffer::new(0, 4).interleave_into(&mut interleaved),
+            0
+        );
+    }
+}
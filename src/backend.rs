@@ -0,0 +1,138 @@
+/// Every audio backend this crate knows about, whether or not it was compiled into the
+/// current build.
+///
+/// The name of a backend (`as_str()`) is the name of its audio server, which is what gets
+/// stored in `Config::audio_server`. This means a config that selects a backend that is
+/// missing on the current machine can still be loaded, shown, and saved again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Backend {
+    Jack,
+    Alsa,
+    /// PulseAudio, behind the `pulse` feature. Every sink is a device, and the sources can be
+    /// recorded along with any of them. It has no MIDI and can't add busses to a running stream.
+    PulseAudio,
+    /// The native PipeWire API. This isn't implemented yet, since it needs the PipeWire
+    /// client library and its bindings at build time. PipeWire can still be used through
+    /// its Jack compatibility layer (`pipewire-jack`) with the `Jack` backend.
+    PipeWire,
+    Wasapi,
+    /// ASIO. This isn't implemented yet, since it needs the Steinberg ASIO SDK (which can't
+    /// be redistributed with this crate) and its bindings at build time. Until then, the
+    /// `Wasapi` backend is the one to use on Windows.
+    Asio,
+    CoreAudio,
+    /// The cross-platform CPAL library, behind the `cpal` feature. This is a fallback for
+    /// platforms without a native backend; it has no MIDI and can't add busses to a running
+    /// stream, so the native backends are preferred wherever they exist.
+    Cpal,
+    Dummy,
+}
+
+impl Backend {
+    /// Every backend, in the order they should be presented to the user.
+    pub const ALL: [Backend; 9] = [
+        Backend::Jack,
+        Backend::Alsa,
+        Backend::PulseAudio,
+        Backend::PipeWire,
+        Backend::Wasapi,
+        Backend::Asio,
+        Backend::CoreAudio,
+        Backend::Cpal,
+        Backend::Dummy,
+    ];
+
+    /// The name of the audio server of this backend.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Backend::Jack => "Jack",
+            Backend::Alsa => "ALSA",
+            Backend::PulseAudio => "PulseAudio",
+            Backend::PipeWire => "PipeWire",
+            Backend::Wasapi => "WASAPI",
+            Backend::Asio => "ASIO",
+            Backend::CoreAudio => "CoreAudio",
+            Backend::Cpal => "CPAL",
+            Backend::Dummy => crate::DUMMY_SERVER_NAME,
+        }
+    }
+
+    /// Returns `true` if this backend was compiled into the current build.
+    ///
+    /// This doesn't mean the backend is available. Use `DevicesInfo` to check whether its
+    /// server is actually running.
+    pub fn is_compiled(&self) -> bool {
+        match self {
+            Backend::Jack => cfg!(target_os = "linux"),
+            Backend::Alsa => cfg!(all(target_os = "linux", feature = "alsa-backend")),
+            Backend::PulseAudio => cfg!(all(target_os = "linux", feature = "pulse")),
+            Backend::Wasapi => cfg!(target_os = "windows"),
+            Backend::Cpal => cfg!(feature = "cpal"),
+            Backend::Dummy => true,
+            Backend::PipeWire | Backend::Asio | Backend::CoreAudio => false,
+        }
+    }
+
+    /// Whether the audio server decides the buffer size of a stream (e.g. the Jack server or
+    /// the device period of WASAPI's shared mode). `Config::buffer_size` is only a request
+    /// on these, and a different size is used with a warning instead of failing.
+    pub fn server_sets_buffer_size(&self) -> bool {
+        matches!(self, Backend::Jack | Backend::Wasapi)
+    }
+
+    /// Whether this backend resolves system port patterns (with `*` and `?` wildcards) in
+    /// `AudioBusConfig::system_ports` and `MidiControllerConfig::system_port`. Every other
+    /// backend only takes exact port names.
+    pub fn resolves_port_patterns(&self) -> bool {
+        matches!(self, Backend::Jack | Backend::Dummy)
+    }
+
+    /// Every backend that was compiled into the current build.
+    pub fn compiled() -> Vec<Backend> {
+        Backend::ALL
+            .iter()
+            .copied()
+            .filter(|b| b.is_compiled())
+            .collect()
+    }
+}
+
+impl std::str::FromStr for Backend {
+    type Err = ();
+
+    /// Parse a backend from the name returned by `as_str()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Backend::ALL
+            .iter()
+            .copied()
+            .find(|b| b.as_str() == s)
+            .ok_or(())
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for backend in Backend::ALL.iter() {
+            assert_eq!(backend.as_str().parse::<Backend>(), Ok(*backend));
+        }
+
+        assert!("Jack Audio".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn dummy_is_always_compiled() {
+        assert!(Backend::Dummy.is_compiled());
+        assert!(Backend::compiled().contains(&Backend::Dummy));
+        assert!(!Backend::CoreAudio.is_compiled());
+    }
+}
@@ -0,0 +1,272 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::gain_ramp::{ramp_frames, GainRamp};
+use crate::{AudioBusBuffer, AudioBusConfig, ChannelGainError};
+
+/// How long it takes a channel gain to ramp to a new value. This is short enough to follow
+/// a fader, and long enough to avoid zipper noise while it is dragged.
+const RAMP_TIME: Duration = Duration::from_millis(5);
+
+/// The gain of one channel, shared between the realtime thread and the stream handle.
+///
+/// Gains are stored as the bits of an `f32`.
+#[derive(Debug)]
+struct SharedGain {
+    current: AtomicU32,
+    target: AtomicU32,
+}
+
+#[derive(Debug)]
+struct BusGains {
+    id: String,
+    channels: Vec<SharedGain>,
+}
+
+impl BusGains {
+    fn new(bus: &AudioBusConfig) -> Self {
+        let num_channels = bus.channel_layout().num_channels();
+
+        Self {
+            id: bus.id.clone(),
+            channels: (0..num_channels)
+                .map(|channel| {
+                    let gain = bus.channel_gain(channel).to_bits();
+                    SharedGain {
+                        current: AtomicU32::new(gain),
+                        target: AtomicU32::new(gain),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ChannelGainsShared {
+    audio_in: Vec<BusGains>,
+    audio_out: Vec<BusGains>,
+}
+
+/// The realtime half of the per-channel gains of the busses a stream was spawned with.
+/// Each gain smoothly ramps toward the target set by the user.
+///
+/// Busses past the ones this was created with (e.g. ones added to a running Jack stream)
+/// are left untouched.
+#[derive(Debug)]
+pub(crate) struct ChannelGains {
+    shared: Arc<ChannelGainsShared>,
+    audio_in: Vec<Vec<GainRamp>>,
+    audio_out: Vec<Vec<GainRamp>>,
+}
+
+impl ChannelGains {
+    pub fn new(audio_in_busses: &[AudioBusConfig], audio_out_busses: &[AudioBusConfig]) -> Self {
+        let shared = ChannelGainsShared {
+            audio_in: audio_in_busses.iter().map(BusGains::new).collect(),
+            audio_out: audio_out_busses.iter().map(BusGains::new).collect(),
+        };
+        let ramps = |busses: &[BusGains]| -> Vec<Vec<GainRamp>> {
+            busses
+                .iter()
+                .map(|bus| {
+                    bus.channels
+                        .iter()
+                        .map(|gain| {
+                            GainRamp::new(f32::from_bits(gain.target.load(Ordering::Relaxed)))
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+
+        Self {
+            audio_in: ramps(&shared.audio_in),
+            audio_out: ramps(&shared.audio_out),
+            shared: Arc::new(shared),
+        }
+    }
+
+    pub fn handle(&self) -> ChannelGainsHandle {
+        ChannelGainsHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Apply the gains to the first `frames` frames of the audio input busses, right after
+    /// they were copied from the device.
+    pub fn apply_inputs(&mut self, busses: &mut [AudioBusBuffer], frames: usize, sample_rate: u32) {
+        apply(
+            &self.shared.audio_in,
+            &mut self.audio_in,
+            busses,
+            frames,
+            sample_rate,
+        );
+    }
+
+    /// Apply the gains to the first `frames` frames of the audio output busses, right
+    /// before they are copied to the device.
+    pub fn apply_outputs(
+        &mut self,
+        busses: &mut [AudioBusBuffer],
+        frames: usize,
+        sample_rate: u32,
+    ) {
+        apply(
+            &self.shared.audio_out,
+            &mut self.audio_out,
+            busses,
+            frames,
+            sample_rate,
+        );
+    }
+}
+
+fn apply(
+    shared: &[BusGains],
+    ramps: &mut [Vec<GainRamp>],
+    busses: &mut [AudioBusBuffer],
+    frames: usize,
+    sample_rate: u32,
+) {
+    let ramp_frames = ramp_frames(RAMP_TIME, sample_rate);
+
+    for ((audio_buffer, bus_ramps), bus_gains) in
+        busses.iter_mut().zip(ramps.iter_mut()).zip(shared.iter())
+    {
+        for ((channel, ramp), gain) in audio_buffer
+            .channel_buffers
+            .iter_mut()
+            .zip(bus_ramps.iter_mut())
+            .zip(bus_gains.channels.iter())
+        {
+            ramp.set_target(
+                f32::from_bits(gain.target.load(Ordering::Relaxed)),
+                ramp_frames,
+            );
+
+            let ramping = ramp.is_ramping();
+            let frames = frames.min(channel.len());
+            ramp.apply(&mut channel[..frames]);
+
+            if ramping {
+                gain.current
+                    .store(ramp.current().to_bits(), Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// The non-realtime half of the per-channel gains.
+#[derive(Debug)]
+pub(crate) struct ChannelGainsHandle {
+    shared: Arc<ChannelGainsShared>,
+}
+
+impl ChannelGainsHandle {
+    pub fn set_channel_gain(
+        &self,
+        bus_id: &str,
+        channel: usize,
+        gain: f32,
+    ) -> Result<(), ChannelGainError> {
+        self.find(bus_id, channel)?
+            .target
+            .store(gain.to_bits(), Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn channel_gain(&self, bus_id: &str, channel: usize) -> Result<f32, ChannelGainError> {
+        let gain = self.find(bus_id, channel)?;
+        Ok(f32::from_bits(gain.current.load(Ordering::Relaxed)))
+    }
+
+    /// Bus IDs are unique across inputs and outputs.
+    fn find(&self, bus_id: &str, channel: usize) -> Result<&SharedGain, ChannelGainError> {
+        let bus = self
+            .shared
+            .audio_in
+            .iter()
+            .chain(self.shared.audio_out.iter())
+            .find(|bus| bus.id == bus_id)
+            .ok_or_else(|| ChannelGainError::BusNotFound(String::from(bus_id)))?;
+
+        bus.channels
+            .get(channel)
+            .ok_or_else(|| ChannelGainError::ChannelNotFound {
+                bus_id: String::from(bus_id),
+                channel,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gain_ramp::tests::ones;
+
+    fn bus(id: &str, channel_gains: Option<Vec<f32>>) -> AudioBusConfig {
+        AudioBusConfig {
+            id: String::from(id),
+            system_ports: vec![String::from("port_1"), String::from("port_2")],
+            channel_gains,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn initial_gains_come_from_the_config() {
+        let mut gains = ChannelGains::new(&[bus("In", Some(vec![0.5]))], &[bus("Out", None)]);
+        let handle = gains.handle();
+
+        let mut busses = ones(4);
+        gains.apply_inputs(&mut busses, 4, 48000);
+        assert_eq!(&busses[0][0], &[0.5, 0.5, 0.5, 0.5]);
+        assert_eq!(&busses[0][1], &[1.0, 1.0, 1.0, 1.0]);
+
+        assert_eq!(handle.channel_gain("In", 0), Ok(0.5));
+        assert_eq!(handle.channel_gain("Out", 1), Ok(1.0));
+    }
+
+    #[test]
+    fn each_channel_ramps_on_its_own() {
+        let mut gains = ChannelGains::new(&[], &[bus("Out", None)]);
+        let handle = gains.handle();
+
+        // 5ms at 1000Hz is a ramp of 5 frames.
+        handle.set_channel_gain("Out", 1, 0.0).unwrap();
+
+        let mut busses = ones(4);
+        gains.apply_outputs(&mut busses, 4, 1000);
+        assert_eq!(&busses[0][0], &[1.0, 1.0, 1.0, 1.0]);
+        assert!((busses[0][1][0] - 0.8).abs() < 1e-6);
+        assert!((busses[0][1][3] - 0.2).abs() < 1e-6);
+        assert!(handle.channel_gain("Out", 1).unwrap() > 0.0);
+
+        let mut busses = ones(4);
+        gains.apply_outputs(&mut busses, 4, 1000);
+        assert_eq!(&busses[0][1], &[0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(handle.channel_gain("Out", 1), Ok(0.0));
+    }
+
+    #[test]
+    fn unknown_channels_are_errors() {
+        let gains = ChannelGains::new(&[bus("In", None)], &[]);
+        let handle = gains.handle();
+
+        assert_eq!(
+            handle.set_channel_gain("Out", 0, 0.5),
+            Err(ChannelGainError::BusNotFound(String::from("Out")))
+        );
+        assert_eq!(
+            handle.set_channel_gain("In", 2, 0.5),
+            Err(ChannelGainError::ChannelNotFound {
+                bus_id: String::from("In"),
+                channel: 2,
+            })
+        );
+    }
+}
@@ -0,0 +1,119 @@
+/// The speaker position of a single channel in a `ChannelLayout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ChannelPosition {
+    /// The only channel of a mono bus, or the center speaker of a surround bus.
+    Center,
+    Left,
+    Right,
+    Lfe,
+    SurroundLeft,
+    SurroundRight,
+    RearLeft,
+    RearRight,
+    /// A channel without a speaker position, numbered from zero.
+    Discrete(u16),
+}
+
+impl std::fmt::Display for ChannelPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelPosition::Center => write!(f, "Center"),
+            ChannelPosition::Left => write!(f, "Left"),
+            ChannelPosition::Right => write!(f, "Right"),
+            ChannelPosition::Lfe => write!(f, "LFE"),
+            ChannelPosition::SurroundLeft => write!(f, "Surround Left"),
+            ChannelPosition::SurroundRight => write!(f, "Surround Right"),
+            ChannelPosition::RearLeft => write!(f, "Rear Left"),
+            ChannelPosition::RearRight => write!(f, "Rear Right"),
+            // Counted from one, like the channels of an interface.
+            ChannelPosition::Discrete(i) => write!(f, "{}", u32::from(*i) + 1),
+        }
+    }
+}
+
+/// What each channel of an audio bus is for, in channel order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ChannelLayout {
+    /// A single `Center` channel.
+    Mono,
+    /// A `Left` and a `Right` channel.
+    Stereo,
+    /// Any number of channels with the given positions.
+    Positions(Vec<ChannelPosition>),
+}
+
+const MONO: [ChannelPosition; 1] = [ChannelPosition::Center];
+const STEREO: [ChannelPosition; 2] = [ChannelPosition::Left, ChannelPosition::Right];
+
+impl ChannelLayout {
+    /// The layout used for a bus that doesn't set one: `Mono` for one channel, `Stereo`
+    /// for two, and `Discrete` positions for any other number of channels.
+    pub fn for_channels(channels: u16) -> Self {
+        match channels {
+            1 => ChannelLayout::Mono,
+            2 => ChannelLayout::Stereo,
+            _ => ChannelLayout::Positions((0..channels).map(ChannelPosition::Discrete).collect()),
+        }
+    }
+
+    /// The position of each channel, in channel order.
+    pub fn positions(&self) -> &[ChannelPosition] {
+        match self {
+            ChannelLayout::Mono => &MONO,
+            ChannelLayout::Stereo => &STEREO,
+            ChannelLayout::Positions(positions) => positions.as_slice(),
+        }
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.positions().len()
+    }
+
+    /// The index of the channel with the given position.
+    pub fn channel_index(&self, position: ChannelPosition) -> Option<usize> {
+        self.positions().iter().position(|p| *p == position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_layouts() {
+        assert_eq!(ChannelLayout::for_channels(1), ChannelLayout::Mono);
+        assert_eq!(ChannelLayout::for_channels(2), ChannelLayout::Stereo);
+        assert_eq!(
+            ChannelLayout::for_channels(3).positions(),
+            &[
+                ChannelPosition::Discrete(0),
+                ChannelPosition::Discrete(1),
+                ChannelPosition::Discrete(2)
+            ]
+        );
+
+        assert_eq!(
+            ChannelLayout::Stereo.channel_index(ChannelPosition::Right),
+            Some(1)
+        );
+        assert_eq!(
+            ChannelLayout::Mono.channel_index(ChannelPosition::Left),
+            None
+        );
+
+        let surround = ChannelLayout::Positions(vec![
+            ChannelPosition::Left,
+            ChannelPosition::Right,
+            ChannelPosition::Center,
+            ChannelPosition::Lfe,
+            ChannelPosition::SurroundLeft,
+            ChannelPosition::SurroundRight,
+        ]);
+        assert_eq!(surround.num_channels(), 6);
+        assert_eq!(surround.channel_index(ChannelPosition::Lfe), Some(3));
+    }
+}
@@ -1,4 +1,12 @@
-#[derive(Debug, Clone, PartialEq)]
+use std::collections::HashSet;
+
+use crate::{
+    AudioDeviceInfo, AudioServerInfo, Backend, ChannelLayout, ConfigError, MidiFilter,
+    MidiServerInfo, PortMixing, ResampleConfig, SpawnRtThreadError,
+};
+
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AudioBusConfig {
     /// The ID to use for this bus. This ID is for the "internal" bus that appears to the user
     /// as list of available sources/sends. This is not necessarily the same as the name of the actual
@@ -15,23 +23,309 @@ pub struct AudioBusConfig {
     pub id: String,
 
     /// The ports (of the system device) that this bus will be connected to.
+    ///
+    /// With Jack these can be the full name or alias of any port in the graph, including the
+    /// ports of other applications, and spawning only fails if connecting to one fails.
+    ///
+    /// With the backends where `Backend::resolves_port_patterns()`, a name may also be a
+    /// pattern where `*` matches any run of characters and `?` any single character (e.g.
+    /// `*:playback_1`). Each pattern is one channel of the bus, and connects to the first
+    /// port of the device that matches it.
     pub system_ports: Vec<String>,
+
+    /// What each channel is for. This must have one channel per system port, unless
+    /// `port_mixing` is set. If this is `None`, `ChannelLayout::for_channels()` is used.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub channel_layout: Option<ChannelLayout>,
+
+    /// The names of the ports that are created for this bus, one per system port, e.g.
+    /// `["out_L", "out_R"]` so that saved Jack sessions find them again. A channel without a
+    /// name here (or every channel if this is `None`) uses `Config::audio_port_name()`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub port_names: Option<Vec<String>>,
+
+    /// How the channels of this bus are mixed into its system ports when the channel layout
+    /// has more or fewer channels than there are system ports. See `PortMixing`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub port_mixing: PortMixing,
+
+    /// The linear gain applied to each channel of this bus at the I/O boundary: to inputs
+    /// before the process handler sees them, and to outputs after it has written them (and
+    /// after the master gain). A channel without a gain here (or every channel if this is
+    /// `None`) is at unity. This can be changed while the stream runs with
+    /// `StreamHandle::set_channel_gain()`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub channel_gains: Option<Vec<f32>>,
+}
+
+impl AudioBusConfig {
+    /// The channel layout of this bus, falling back to the default layout for its number
+    /// of system ports.
+    pub fn channel_layout(&self) -> ChannelLayout {
+        self.channel_layout
+            .clone()
+            .unwrap_or_else(|| ChannelLayout::for_channels(self.system_ports.len() as u16))
+    }
+
+    /// The name of the port that will be created for the given channel (starting at 0) of
+    /// this bus.
+    pub fn port_name(&self, channel: usize) -> String {
+        self.port_names
+            .as_ref()
+            .and_then(|names| names.get(channel))
+            .cloned()
+            .unwrap_or_else(|| Config::audio_port_name(&self.id, channel))
+    }
+
+    /// The friendly name of the port of the given channel (starting at 0) of this bus, for
+    /// `Config::jack_pretty_names`: the bus id and the position of the channel, e.g.
+    /// "Main • Left". The port of a mono bus is just named after the bus.
+    pub fn pretty_port_name(&self, channel: usize) -> String {
+        let channel_layout = self.channel_layout();
+        match channel_layout.positions().get(channel) {
+            Some(position) if channel_layout != ChannelLayout::Mono => {
+                format!("{} • {}", self.id, position)
+            }
+            _ => self.id.clone(),
+        }
+    }
+
+    /// The gain of the given channel (starting at 0) of this bus when the stream starts.
+    pub fn channel_gain(&self, channel: usize) -> f32 {
+        self.channel_gains
+            .as_ref()
+            .and_then(|gains| gains.get(channel))
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// A stereo output bus connected to the default left and right playback ports of
+    /// `device`.
+    ///
+    /// Returns `None` if the device doesn't have both ports.
+    pub fn stereo_out(id: &str, device: &AudioDeviceInfo) -> Option<Self> {
+        if device.default_out_port_left == device.default_out_port_right {
+            return None;
+        }
+        Self::from_out_port_indices(
+            id,
+            device,
+            &[device.default_out_port_left, device.default_out_port_right],
+        )
+    }
+
+    /// A mono input bus connected to the default capture port of `device`.
+    ///
+    /// Returns `None` if the device doesn't have that port.
+    pub fn mono_in(id: &str, device: &AudioDeviceInfo) -> Option<Self> {
+        Self::from_in_port_indices(id, device, &[device.default_in_port?])
+    }
+
+    /// An output bus with one channel for each of the given indices into
+    /// `device.out_ports`, in that order.
+    ///
+    /// Returns `None` if `indices` is empty or any of them is out of range.
+    pub fn from_out_port_indices(
+        id: &str,
+        device: &AudioDeviceInfo,
+        indices: &[usize],
+    ) -> Option<Self> {
+        Self::from_port_indices(id, &device.out_ports, indices)
+    }
+
+    /// An input bus with one channel for each of the given indices into `device.in_ports`,
+    /// in that order.
+    ///
+    /// Returns `None` if `indices` is empty or any of them is out of range.
+    pub fn from_in_port_indices(
+        id: &str,
+        device: &AudioDeviceInfo,
+        indices: &[usize],
+    ) -> Option<Self> {
+        Self::from_port_indices(id, &device.in_ports, indices)
+    }
+
+    fn from_port_indices(id: &str, ports: &[String], indices: &[usize]) -> Option<Self> {
+        if indices.is_empty() {
+            return None;
+        }
+        let system_ports = indices
+            .iter()
+            .map(|&i| ports.get(i).cloned())
+            .collect::<Option<Vec<String>>>()?;
+
+        Some(AudioBusConfig {
+            id: String::from(id),
+            system_ports,
+            ..Default::default()
+        })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MidiControllerConfig {
     /// The ID to use for this controller. This ID is for the "internal" controller that appears to the user
     /// as list of available sources/sends. This is not necessarily the same as the name of the actual
     /// system hardware device that this "internal" controller is connected to.
     ///
-    /// This ID *must* be unique for each `AudioBusConfig` and `MidiControllerConfig`.
+    /// This ID *must* be unique for each `AudioBusConfig` and `MidiControllerConfig`, with one exception:
+    /// a bidirectional controller (e.g. a control surface with feedback) may use the same ID once in
+    /// `midi_in_controllers` and once in `midi_out_controllers`. In that case the ports that
+    /// are created for it are suffixed with `_in` and `_out` so they don't collide.
     pub id: String,
 
     /// The name of the system port this controller is connected to.
+    ///
+    /// Like `AudioBusConfig::system_ports`, this may be a pattern with the backends where
+    /// `Backend::resolves_port_patterns()`.
     pub system_port: String,
+
+    /// Connect to a system port by its role instead of by name.
+    ///
+    /// If this is set, the system port is resolved when the stream is spawned and
+    /// `system_port` is ignored. This makes configs more portable since port names
+    /// can differ between systems.
+    pub system_port_role: Option<MidiPortRole>,
+
+    /// The name of the port that is created for this controller. If this is `None`, the
+    /// port is named after the id (see `Config::midi_in_port_name()`).
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub port_name: Option<String>,
+
+    /// The messages this controller drops before they reach its buffer. This only applies
+    /// to input controllers, and passes everything by default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub filter: MidiFilter,
+
+    /// The number of events the buffer of this controller holds per process cycle. Events
+    /// past that are dropped and counted (see `StreamHandle::midi_in_dropped_events()`), so
+    /// raise this for controllers that send dense automation or large SysEx dumps.
+    ///
+    /// Set this to `None` to use `MIDI_BUFFER_SIZE`. This only applies to input controllers.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub buffer_capacity: Option<usize>,
+
+    /// More system ports this controller connects to, besides `system_port` (e.g. a synth
+    /// and a monitor for an output controller).
+    ///
+    /// Only Jack can connect a controller to more than one port, so this is ignored by
+    /// every other backend.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub connections: Vec<String>,
+
+    /// Whether this controller is connected to `system_port` and `connections` when it is
+    /// created. If this is `false`, only its port is registered and the connections are
+    /// left to the user, like `JackSelfConnect::Never` does for the whole stream.
+    ///
+    /// This only applies to Jack. Every other backend opens `system_port` directly.
+    #[cfg_attr(feature = "serde", serde(default = "default_auto_connect"))]
+    pub auto_connect: bool,
+}
+
+impl Default for MidiControllerConfig {
+    /// A controller with no id or system port yet, which is connected automatically and
+    /// passes every message.
+    fn default() -> Self {
+        MidiControllerConfig {
+            id: String::new(),
+            system_port: String::new(),
+            system_port_role: None,
+            port_name: None,
+            filter: MidiFilter::default(),
+            buffer_capacity: None,
+            connections: Vec::new(),
+            auto_connect: true,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn default_auto_connect() -> bool {
+    true
+}
+
+/// A role of a system midi port that can be resolved to a concrete port name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum MidiPortRole {
+    /// Jack's built-in `Midi-Through` device. This is commonly mapped to
+    /// "system:midi_capture_1" and "system:midi_playback_1".
+    MidiThrough,
+
+    /// The first port of the user's hardware midi input device. This is commonly
+    /// mapped to "system:midi_capture_2".
+    DefaultHardwareIn,
+
+    /// The first port of the user's hardware midi output device. This is commonly
+    /// mapped to "system:midi_playback_2".
+    DefaultHardwareOut,
+}
+
+impl MidiPortRole {
+    /// The identifier used for this role in config files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MidiPortRole::MidiThrough => "midi_through",
+            MidiPortRole::DefaultHardwareIn => "default_hardware_in",
+            MidiPortRole::DefaultHardwareOut => "default_hardware_out",
+        }
+    }
+
+    /// Resolve this role to one of the given system ports.
+    ///
+    /// * `ports` - The available system ports (capture ports for input, playback ports for output).
+    /// * `is_input` - Whether the ports belong to an input controller.
+    ///
+    /// This returns `None` if no suitable port exists.
+    pub fn resolve<'a>(&self, ports: &'a [String], is_input: bool) -> Option<&'a String> {
+        let (through_port, hardware_port) = if is_input {
+            ("system:midi_capture_1", "system:midi_capture_2")
+        } else {
+            ("system:midi_playback_1", "system:midi_playback_2")
+        };
+
+        match self {
+            MidiPortRole::MidiThrough => ports.iter().find(|p| p.as_str() == through_port),
+            MidiPortRole::DefaultHardwareIn | MidiPortRole::DefaultHardwareOut => {
+                if (*self == MidiPortRole::DefaultHardwareIn) != is_input {
+                    return None;
+                }
+
+                // Fallback to the first available port that isn't the `Midi-Through` device.
+                ports
+                    .iter()
+                    .find(|p| p.as_str() == hardware_port)
+                    .or_else(|| ports.iter().find(|p| p.as_str() != through_port))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for MidiPortRole {
+    type Err = ();
+
+    /// Parse a role from the identifier returned by `as_str()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "midi_through" => Ok(MidiPortRole::MidiThrough),
+            "default_hardware_in" => Ok(MidiPortRole::DefaultHardwareIn),
+            "default_hardware_out" => Ok(MidiPortRole::DefaultHardwareOut),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for MidiPortRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Config {
     /// The name of the audio server to use.
     pub audio_server: String,
@@ -69,12 +363,26 @@ pub struct Config {
 
     /// The maximum number of frames per channel.
     ///
-    /// Set this to `None` to use the default settings of the system device.
+    /// Set this to `None` to use the default settings of the system device. Spawning fails
+    /// with `SpawnRtThreadError::UnsupportedBufferSize` if the device doesn't support this
+    /// size, except where the server decides the size (see `Backend::server_sets_buffer_size`),
+    /// which only logs a warning and uses the server's. Check `StreamInfo::max_audio_buffer_size`
+    /// for the size that is actually used.
     pub buffer_size: Option<u32>,
 
+    /// Which sides of the system audio device to open, e.g. only playback for an app that
+    /// has nothing to record. The busses of a side that isn't opened are left out of the
+    /// stream, and a warning is logged.
+    pub direction: StreamDirection,
+
     /// The name of the midi server to use.
     ///
-    /// Set this to `None` for no midi.
+    /// Set this to `None` to disable midi. A stream without midi registers no midi ports and
+    /// allocates no midi buffers, and `ProcessInfo::midi_in`/`midi_out` are empty.
+    ///
+    /// A stream can only use the midi server of its own backend (e.g. `"Jack"` with Jack
+    /// audio); with any other server no midi controllers are created, and a warning is
+    /// logged.
     pub midi_server: Option<String>,
 
     /// The midi input controllers to create/use. These are the "internal" controllers that appear to the user
@@ -86,4 +394,1434 @@ pub struct Config {
     /// as list of available sources/sends. This is not necessarily the same as the actual
     /// system hardware devices that these "internal" controllers are connected to.
     pub midi_out_controllers: Vec<MidiControllerConfig>,
+
+    /// How the `RawMidi::delta_frames` of the events in `ProcessInfo::midi_in` are counted:
+    /// from the start of each process cycle (the default), or from the start of the stream.
+    pub midi_in_timestamps: MidiTimestamps,
+
+    /// Which connections the Jack client makes to the system ports on its own. This is
+    /// ignored by every other backend.
+    pub jack_self_connect: JackSelfConnect,
+
+    /// The name of the Jack server to connect to, as given to `jackd --name`. This is
+    /// ignored by every other backend.
+    ///
+    /// Set this to `None` to use the default server, which is the one named by the
+    /// `JACK_DEFAULT_SERVER` environment variable, or `"default"` if it isn't set.
+    pub jack_server_name: Option<String>,
+
+    /// Give every port this client registers a pretty name in Jack's metadata, which
+    /// patchbays like Carla and QjackCtl show instead of the port name. It is made of the
+    /// bus id and the position of the channel (e.g. "Main • Left"), or the controller id
+    /// for midi ports. See `AudioBusConfig::pretty_port_name()`.
+    ///
+    /// This is off by default, and ignored by every other backend.
+    pub jack_pretty_names: bool,
+
+    /// The number of process cycles whose buffer size is kept for
+    /// `StreamHandle::recent_buffer_sizes()`.
+    ///
+    /// Set this to `None` to use `DEFAULT_BUFFER_SIZE_HISTORY`.
+    pub buffer_size_history: Option<usize>,
+
+    /// How the audio thread is scheduled on the backends that spawn their own.
+    pub rt_thread: RtThreadConfig,
+
+    /// The maximum time spawning the stream may take, from connecting to the server up
+    /// to connecting the ports. If it takes longer, spawning fails with
+    /// `SpawnRtThreadError::Timeout` and everything that was created is cleaned up.
+    ///
+    /// Set this to `None` for no limit.
+    pub spawn_timeout: Option<std::time::Duration>,
+
+    /// What to do when two or more busses/controllers have the same ID.
+    pub on_duplicate_id: FailOrRename,
+
+    /// Measure the peak level of every channel of the audio input busses each cycle, for
+    /// `AudioBusBuffer::peak()`. This is off by default so it costs nothing when unused.
+    pub input_peaks: bool,
+
+    /// Leave the floating point mode of the audio thread as it is while
+    /// `RtProcessHandler::process` runs.
+    ///
+    /// By default denormal floats are flushed to zero during `process` on x86 (with the FTZ
+    /// and DAZ flags), since they can make decaying feedback loops many times slower. On
+    /// other targets this does nothing.
+    pub allow_denormals: bool,
+
+    /// Run the process handler at this sample rate instead of the device's, converting
+    /// between the two in software (see `Resampled`). `sample_rate` is still the rate
+    /// requested from the device.
+    pub resample: Option<ResampleConfig>,
+}
+
+/// Which sides of the system audio device a stream opens.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum StreamDirection {
+    /// Open the playback side, and the capture side when there are audio input busses.
+    #[default]
+    Duplex,
+
+    /// Only open the playback side, even if the device could capture.
+    OutputOnly,
+
+    /// Only open the capture side.
+    ///
+    /// WASAPI streams are driven by the render endpoint of the system device, so that is
+    /// still opened there and plays silence. So are PulseAudio streams without any audio
+    /// input busses, which are driven by the sink.
+    InputOnly,
+}
+
+impl StreamDirection {
+    /// Whether streams with this direction have audio input busses.
+    pub fn has_input(self) -> bool {
+        self != StreamDirection::OutputOnly
+    }
+
+    /// Whether streams with this direction have audio output busses.
+    pub fn has_output(self) -> bool {
+        self != StreamDirection::InputOnly
+    }
+
+    /// The identifier used for this direction in config files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StreamDirection::Duplex => "duplex",
+            StreamDirection::OutputOnly => "output_only",
+            StreamDirection::InputOnly => "input_only",
+        }
+    }
+}
+
+impl std::str::FromStr for StreamDirection {
+    type Err = ();
+
+    /// Parse a direction from the identifier returned by `as_str()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "duplex" => Ok(StreamDirection::Duplex),
+            "output_only" => Ok(StreamDirection::OutputOnly),
+            "input_only" => Ok(StreamDirection::InputOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What to do when two or more busses/controllers in a `Config` have the same ID.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FailOrRename {
+    /// Fail with `SpawnRtThreadError::IdNotUnique`.
+    #[default]
+    Fail,
+
+    /// Rename the duplicates with `Config::rename_duplicate_ids()` and spawn the stream
+    /// with the renamed IDs. The renames are logged, and the new IDs (and the port names
+    /// created from them) are reported in the `StreamInfo`.
+    Rename,
+}
+
+impl FailOrRename {
+    /// The identifier used for this policy in config files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailOrRename::Fail => "fail",
+            FailOrRename::Rename => "rename",
+        }
+    }
+}
+
+impl std::str::FromStr for FailOrRename {
+    type Err = ();
+
+    /// Parse a policy from the identifier returned by `as_str()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(FailOrRename::Fail),
+            "rename" => Ok(FailOrRename::Rename),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the `RawMidi::delta_frames` of midi input events are counted from. See
+/// `Config::midi_in_timestamps`.
+///
+/// This only applies to the input controllers. The events of the output controllers are
+/// always counted from the start of the process cycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum MidiTimestamps {
+    /// Frames since the start of the process cycle the event arrived in.
+    #[default]
+    CycleRelative,
+
+    /// Frames since the start of the stream, i.e. `ProcessInfo::cycle_start_frame` plus the
+    /// frames since the start of the cycle. This makes it easy to write the incoming events
+    /// to a timeline, without stitching the cycles together.
+    ///
+    /// `delta_frames` is a `u32`, so this wraps around to 0 after `u32::MAX` frames (a little
+    /// over 24 hours at 48 kHz).
+    Absolute,
+}
+
+impl MidiTimestamps {
+    /// The `delta_frames` of an input event that arrived `frame` frames into the cycle
+    /// starting at `cycle_start_frame`.
+    pub(crate) fn delta_frames(self, cycle_start_frame: u64, frame: u32) -> u32 {
+        match self {
+            MidiTimestamps::CycleRelative => frame,
+            MidiTimestamps::Absolute => (cycle_start_frame as u32).wrapping_add(frame),
+        }
+    }
+
+    /// The frames since the start of the cycle of an input event, the inverse of
+    /// `delta_frames()`.
+    pub(crate) fn cycle_frame(self, cycle_start_frame: u64, delta_frames: u32) -> u32 {
+        match self {
+            MidiTimestamps::CycleRelative => delta_frames,
+            MidiTimestamps::Absolute => delta_frames.wrapping_sub(cycle_start_frame as u32),
+        }
+    }
+
+    /// The identifier used for this mode in config files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MidiTimestamps::CycleRelative => "cycle_relative",
+            MidiTimestamps::Absolute => "absolute",
+        }
+    }
+}
+
+impl std::str::FromStr for MidiTimestamps {
+    type Err = ();
+
+    /// Parse a mode from the identifier returned by `as_str()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cycle_relative" => Ok(MidiTimestamps::CycleRelative),
+            "absolute" => Ok(MidiTimestamps::Absolute),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A bus/controller ID that was changed by `Config::rename_duplicate_ids()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdRename {
+    pub old_id: String,
+    pub new_id: String,
+}
+
+/// Whether the Jack client connects its own ports to the system ports in the `Config`.
+///
+/// This only controls the connections made by this crate after the client is activated. It
+/// can't keep other clients (such as a session manager or a patchbay) from connecting to the
+/// ports of this client, and there is no standard Jack metadata key that would.
+///
+/// The Jack server has its own self-connect mode (`jackd --self-connect-mode`). It is set when
+/// the server is started and can't be changed by a client. If the server is set to refuse
+/// self-connections, spawning the stream with `JackSelfConnect::SystemPorts` fails with an error
+/// from Jack, so use `JackSelfConnect::Never` on such servers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum JackSelfConnect {
+    /// Connect every bus and controller to the system ports given in the `Config`.
+    #[default]
+    SystemPorts,
+
+    /// Only register the ports and leave all of the connections to the user. The names of
+    /// the registered ports are in `AudioBus::client_ports` and `MidiController::client_port`.
+    ///
+    /// The system ports in the `Config` are still used to determine the number of channels
+    /// of each bus.
+    Never,
+}
+
+impl JackSelfConnect {
+    /// The identifier used for this policy in config files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JackSelfConnect::SystemPorts => "system_ports",
+            JackSelfConnect::Never => "never",
+        }
+    }
+}
+
+impl std::str::FromStr for JackSelfConnect {
+    type Err = ();
+
+    /// Parse a policy from the identifier returned by `as_str()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system_ports" => Ok(JackSelfConnect::SystemPorts),
+            "never" => Ok(JackSelfConnect::Never),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The priority of the audio thread in the default `RtThreadConfig`.
+pub const DEFAULT_RT_PRIORITY: u8 = 70;
+
+/// How the audio thread of the backends that spawn their own (ALSA and WASAPI) is
+/// scheduled.
+///
+/// Jack schedules its process thread itself (see `jackd --realtime-priority`), so this is
+/// ignored there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RtThreadConfig {
+    pub policy: RtThreadPolicy,
+
+    /// The priority of the audio thread, from 1 (lowest) to 99 (highest).
+    ///
+    /// On Linux this is the `SCHED_FIFO`/`SCHED_RR` priority, which the user needs an
+    /// `rtprio` limit of at least this for. On Windows the thread joins the MMCSS
+    /// "Pro Audio" task, at normal priority below 50, high priority below 90, and critical
+    /// priority from there.
+    pub priority: u8,
+
+    /// Fail with `SpawnRtThreadError::RtPriorityDenied` if the OS doesn't allow the policy
+    /// and priority. Otherwise a warning is logged and the thread keeps running with normal
+    /// scheduling.
+    pub required: bool,
+}
+
+impl Default for RtThreadConfig {
+    fn default() -> Self {
+        Self {
+            policy: RtThreadPolicy::default(),
+            priority: DEFAULT_RT_PRIORITY,
+            required: false,
+        }
+    }
+}
+
+/// The scheduling policy of the audio thread.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum RtThreadPolicy {
+    /// Leave the thread with the scheduling it was spawned with.
+    Normal,
+
+    /// `SCHED_FIFO` on Linux, and MMCSS on Windows.
+    #[default]
+    Fifo,
+
+    /// `SCHED_RR` on Linux, and the same as `Fifo` on Windows.
+    RoundRobin,
+}
+
+impl RtThreadPolicy {
+    /// The identifier used for this policy in config files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RtThreadPolicy::Normal => "normal",
+            RtThreadPolicy::Fifo => "fifo",
+            RtThreadPolicy::RoundRobin => "round_robin",
+        }
+    }
+}
+
+impl std::str::FromStr for RtThreadPolicy {
+    type Err = ();
+
+    /// Parse a policy from the identifier returned by `as_str()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(RtThreadPolicy::Normal),
+            "fifo" => Ok(RtThreadPolicy::Fifo),
+            "round_robin" => Ok(RtThreadPolicy::RoundRobin),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Config {
+    /// The backend selected by `audio_server`, or `None` if it isn't the name of any known
+    /// backend.
+    ///
+    /// The backend doesn't have to be compiled into the current build.
+    pub fn backend(&self) -> Option<Backend> {
+        self.audio_server.parse().ok()
+    }
+
+    /// The backend selected by `midi_server`, or `None` if midi is disabled or it isn't the
+    /// name of any known backend.
+    pub fn midi_backend(&self) -> Option<Backend> {
+        self.midi_server.as_ref().and_then(|s| s.parse().ok())
+    }
+
+    /// Whether midi is enabled, i.e. `midi_server` is set.
+    pub fn midi_enabled(&self) -> bool {
+        self.midi_server.is_some()
+    }
+
+    /// Whether a stream of `backend` creates the midi controllers of this config, i.e.
+    /// midi is enabled and `midi_server` is the midi server of `backend`.
+    pub(crate) fn creates_midi_controllers(&self, backend: Backend) -> bool {
+        self.midi_enabled() && self.midi_backend() == Some(backend)
+    }
+
+    /// Why a stream of `backend`, whose midi server is the one of the same name, won't
+    /// create the midi controllers of this config, or `None` if it will (or there are none
+    /// and midi is disabled).
+    pub(crate) fn skipped_midi_reason(&self, backend: Backend) -> Option<String> {
+        match &self.midi_server {
+            None => {
+                let controllers = self.midi_in_controllers.len() + self.midi_out_controllers.len();
+                if controllers == 0 {
+                    None
+                } else {
+                    Some(format!(
+                        "midi_server is not set, so the {} midi controllers in the config are not created",
+                        controllers
+                    ))
+                }
+            }
+            Some(_) if self.midi_backend() == Some(backend) => None,
+            Some(server) => Some(format!(
+                "The {} backend can only use the \"{}\" midi server, not \"{}\", so no midi controllers are created. Set midi_server to None to disable midi",
+                backend, backend, server
+            )),
+        }
+    }
+
+    /// The number of process cycles whose buffer size is kept, with the default applied.
+    pub(crate) fn buffer_size_history_len(&self) -> usize {
+        self.buffer_size_history
+            .unwrap_or(crate::DEFAULT_BUFFER_SIZE_HISTORY)
+    }
+
+    /// Make every ID unique by appending a number (" #2", " #3", ...) to each duplicate,
+    /// and return what was renamed. The first bus/controller with an ID keeps it.
+    ///
+    /// A midi controller that appears once in the input list and once in the output list
+    /// is a bidirectional controller, so that isn't counted as a duplicate.
+    pub fn rename_duplicate_ids(&mut self) -> Vec<IdRename> {
+        let mut all_ids: HashSet<String> = self
+            .audio_in_busses
+            .iter()
+            .chain(self.audio_out_busses.iter())
+            .map(|b| b.id.clone())
+            .chain(
+                self.midi_in_controllers
+                    .iter()
+                    .chain(self.midi_out_controllers.iter())
+                    .map(|c| c.id.clone()),
+            )
+            .collect();
+        let mut renames = Vec::new();
+
+        let mut rename = |id: &mut String, renames: &mut Vec<IdRename>| {
+            let new_id = (2..)
+                .map(|i| format!("{} #{}", id, i))
+                .find(|new_id| !all_ids.contains(new_id))
+                .unwrap();
+
+            all_ids.insert(new_id.clone());
+            renames.push(IdRename {
+                old_id: std::mem::replace(id, new_id.clone()),
+                new_id,
+            });
+        };
+
+        let mut bus_ids = HashSet::new();
+        for bus in self
+            .audio_in_busses
+            .iter_mut()
+            .chain(self.audio_out_busses.iter_mut())
+        {
+            if bus_ids.contains(&bus.id) {
+                rename(&mut bus.id, &mut renames);
+            }
+            bus_ids.insert(bus.id.clone());
+        }
+
+        for controllers in [
+            &mut self.midi_in_controllers,
+            &mut self.midi_out_controllers,
+        ] {
+            let mut controller_ids = HashSet::new();
+            for controller in controllers.iter_mut() {
+                if bus_ids.contains(&controller.id) || controller_ids.contains(&controller.id) {
+                    rename(&mut controller.id, &mut renames);
+                }
+                controller_ids.insert(controller.id.clone());
+            }
+        }
+
+        renames
+    }
+
+    /// Returns `true` if a midi controller with the given ID appears in both
+    /// `midi_in_controllers` and `midi_out_controllers`.
+    pub fn is_bidirectional_midi_controller(&self, id: &str) -> bool {
+        self.midi_in_controllers.iter().any(|c| c.id == id)
+            && self.midi_out_controllers.iter().any(|c| c.id == id)
+    }
+
+    /// The name of the port that will be created for the midi input controller with the given ID.
+    pub fn midi_in_port_name(&self, id: &str) -> String {
+        let controller = self.midi_in_controllers.iter().find(|c| c.id == id);
+        if let Some(port_name) = controller.and_then(|c| c.port_name.as_ref()) {
+            port_name.clone()
+        } else if self.is_bidirectional_midi_controller(id) {
+            format!("{}_in", id)
+        } else {
+            String::from(id)
+        }
+    }
+
+    /// The name of the port that will be created for the midi output controller with the given ID.
+    pub fn midi_out_port_name(&self, id: &str) -> String {
+        let controller = self.midi_out_controllers.iter().find(|c| c.id == id);
+        if let Some(port_name) = controller.and_then(|c| c.port_name.as_ref()) {
+            port_name.clone()
+        } else if self.is_bidirectional_midi_controller(id) {
+            format!("{}_out", id)
+        } else {
+            String::from(id)
+        }
+    }
+
+    /// The name of the port that will be created for the given channel (starting at 0) of the
+    /// audio bus with the given ID, unless the bus sets `AudioBusConfig::port_names`.
+    pub fn audio_port_name(bus_id: &str, channel: usize) -> String {
+        format!("{}_{}", bus_id, channel + 1)
+    }
+}
+
+/// A system port that a bus/controller in a `Config` resolves to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedPort {
+    /// The concrete name of the system port. If a pattern or role matched no port, this is
+    /// the pattern or role as written in the config.
+    pub name: String,
+
+    /// Whether or not this port currently exists on the system device.
+    pub exists: bool,
+}
+
+/// The system ports that an audio bus in a `Config` resolves to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedBus {
+    /// The ID of the bus.
+    pub id: String,
+
+    /// The system ports of this bus, in order.
+    pub ports: Vec<ResolvedPort>,
+}
+
+/// The system port that a midi controller in a `Config` resolves to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedController {
+    /// The ID of the controller.
+    pub id: String,
+
+    /// The system port of this controller.
+    pub port: ResolvedPort,
+}
+
+/// A read-only preview of every system port a `Config` will connect to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResolvedPorts {
+    /// Whether `Config::system_audio_device` was found on the audio server. If it wasn't,
+    /// none of the audio ports exist.
+    pub device_found: bool,
+
+    pub audio_in: Vec<ResolvedBus>,
+    pub audio_out: Vec<ResolvedBus>,
+    pub midi_in: Vec<ResolvedController>,
+    pub midi_out: Vec<ResolvedController>,
+}
+
+impl ResolvedPorts {
+    /// All of the resolved ports that don't currently exist on the system.
+    pub fn missing_ports(&self) -> Vec<&ResolvedPort> {
+        self.audio_in
+            .iter()
+            .chain(self.audio_out.iter())
+            .flat_map(|b| b.ports.iter())
+            .chain(
+                self.midi_in
+                    .iter()
+                    .chain(self.midi_out.iter())
+                    .map(|c| &c.port),
+            )
+            .filter(|p| !p.exists)
+            .collect()
+    }
+
+    /// Returns `true` if every resolved port currently exists on the system.
+    pub fn all_exist(&self) -> bool {
+        self.missing_ports().is_empty()
+    }
+}
+
+impl Config {
+    /// Resolve every bus and controller in this config to the concrete system ports
+    /// they will be connected to, without spawning a stream.
+    ///
+    /// Any port that doesn't currently exist in the given server info is flagged in
+    /// its `ResolvedPort::exists` field, and so is every midi port if `midi_server` isn't
+    /// the server of this config. Port patterns are only resolved if the backend of this
+    /// config resolves them (see `Backend::resolves_port_patterns()`).
+    pub fn resolved_ports(
+        &self,
+        audio_server: &AudioServerInfo,
+        midi_server: &MidiServerInfo,
+    ) -> ResolvedPorts {
+        let device = if self.audio_server == audio_server.name {
+            find_device(audio_server, &self.system_audio_device)
+        } else {
+            None
+        };
+        let resolves_patterns =
+            |backend: Option<Backend>| backend.map(|b| b.resolves_port_patterns()).unwrap_or(false);
+        let resolve_port = |name: &String, available: &[String], patterns: bool| {
+            let port = if patterns {
+                crate::port_pattern::resolve_system_port(name, available)
+            } else {
+                available.iter().find(|a| *a == name).map(|a| a.as_str())
+            };
+
+            match port {
+                Some(port) => ResolvedPort {
+                    name: String::from(port),
+                    exists: true,
+                },
+                None => ResolvedPort {
+                    name: name.clone(),
+                    exists: false,
+                },
+            }
+        };
+
+        let resolve_bus = |bus: &AudioBusConfig, available: &[String]| ResolvedBus {
+            id: bus.id.clone(),
+            ports: bus
+                .system_ports
+                .iter()
+                .map(|name| resolve_port(name, available, resolves_patterns(self.backend())))
+                .collect(),
+        };
+
+        let audio_in = self
+            .audio_in_busses
+            .iter()
+            .map(|b| resolve_bus(b, device.map(|d| &d.in_ports[..]).unwrap_or(&[])))
+            .collect();
+        let audio_out = self
+            .audio_out_busses
+            .iter()
+            .map(|b| resolve_bus(b, device.map(|d| &d.out_ports[..]).unwrap_or(&[])))
+            .collect();
+
+        let (midi_in, midi_out) = if self.midi_server.is_some() {
+            // The ports of another midi server say nothing about the ports of this one.
+            let same_server = self.midi_server.as_ref() == Some(&midi_server.name);
+            let available = same_server && midi_server.available;
+
+            let resolve_controller = |controller: &MidiControllerConfig, is_input: bool| {
+                let devices = if is_input {
+                    &midi_server.in_devices
+                } else {
+                    &midi_server.out_devices
+                };
+                let names: Vec<String> = if same_server {
+                    devices.iter().map(|d| d.name.clone()).collect()
+                } else {
+                    Vec::new()
+                };
+
+                let port = if let Some(role) = controller.system_port_role {
+                    match role.resolve(&names, is_input) {
+                        Some(name) => ResolvedPort {
+                            name: name.clone(),
+                            exists: available,
+                        },
+                        None => ResolvedPort {
+                            name: role.to_string(),
+                            exists: false,
+                        },
+                    }
+                } else {
+                    let port = resolve_port(
+                        &controller.system_port,
+                        &names,
+                        resolves_patterns(self.midi_backend()),
+                    );
+                    ResolvedPort {
+                        exists: available && port.exists,
+                        ..port
+                    }
+                };
+
+                ResolvedController {
+                    id: controller.id.clone(),
+                    port,
+                }
+            };
+
+            (
+                self.midi_in_controllers
+                    .iter()
+                    .map(|c| resolve_controller(c, true))
+                    .collect(),
+                self.midi_out_controllers
+                    .iter()
+                    .map(|c| resolve_controller(c, false))
+                    .collect(),
+            )
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+        ResolvedPorts {
+            device_found: device.is_some(),
+            audio_in,
+            audio_out,
+            midi_in,
+            midi_out,
+        }
+    }
+}
+
+impl Config {
+    /// Check this config against the enumerated devices before spawning a stream.
+    ///
+    /// This returns the first problem found. Use `validation_errors()` to get all of them.
+    pub fn validate(
+        &self,
+        audio_server: &AudioServerInfo,
+        midi_server: &MidiServerInfo,
+    ) -> Result<(), ConfigError> {
+        match self
+            .validation_errors(audio_server, midi_server)
+            .into_iter()
+            .next()
+        {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Every problem with this config that would make spawning a stream with the given
+    /// servers fail.
+    ///
+    /// Only the first duplicate ID is reported.
+    pub fn validation_errors(
+        &self,
+        audio_server: &AudioServerInfo,
+        midi_server: &MidiServerInfo,
+    ) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+
+        if self.audio_server != audio_server.name {
+            errors.push(ConfigError::AudioServerMismatch(self.audio_server.clone()));
+        } else if !audio_server.available {
+            errors.push(ConfigError::AudioServerUnavailable(
+                self.audio_server.clone(),
+            ));
+        }
+
+        // Duplicate ids always give duplicate port names, so those are only checked once the
+        // ids are unique.
+        match crate::check_duplicate_ids(self) {
+            Err(SpawnRtThreadError::IdNotUnique(id))
+                if self.on_duplicate_id == FailOrRename::Fail =>
+            {
+                errors.push(ConfigError::IdNotUnique(id));
+            }
+            Err(SpawnRtThreadError::PortNameNotUnique(id)) => {
+                errors.push(ConfigError::PortNameNotUnique(id));
+            }
+            _ => {}
+        }
+
+        for bus in self
+            .audio_in_busses
+            .iter()
+            .chain(self.audio_out_busses.iter())
+        {
+            if bus.system_ports.is_empty() {
+                errors.push(ConfigError::NoSystemPortsGiven(bus.id.clone()));
+            }
+        }
+
+        if let Err(SpawnRtThreadError::ChannelLayoutMismatch(id)) =
+            crate::check_channel_layouts(self)
+        {
+            errors.push(ConfigError::ChannelLayoutMismatch(id));
+        }
+
+        if audio_server.available {
+            match audio_server
+                .devices
+                .iter()
+                .find(|d| d.name == self.system_audio_device)
+            {
+                Some(device) => {
+                    let resolved = self.resolved_ports(audio_server, midi_server);
+                    for bus in resolved.audio_in.iter().chain(resolved.audio_out.iter()) {
+                        for port in bus.ports.iter().filter(|p| !p.exists) {
+                            errors.push(ConfigError::SystemPortNotFound(
+                                port.name.clone(),
+                                bus.id.clone(),
+                            ));
+                        }
+                    }
+
+                    if let Some(requested) = self.buffer_size {
+                        let range = if self.audio_in_busses.is_empty()
+                            || self.audio_out_busses.is_empty()
+                        {
+                            device.buffer_size_range
+                        } else {
+                            device
+                                .duplex_buffer_size_range
+                                .unwrap_or(device.buffer_size_range)
+                        };
+                        // The dummy backend runs at any buffer size.
+                        let checked = match self.backend() {
+                            Some(Backend::Dummy) | None => false,
+                            Some(backend) => !backend.server_sets_buffer_size(),
+                        };
+
+                        if checked && !range.contains(requested) {
+                            errors.push(ConfigError::UnsupportedBufferSize { requested, range });
+                        }
+                    }
+                }
+                None => errors.push(ConfigError::SystemDeviceNotFound(
+                    self.system_audio_device.clone(),
+                )),
+            }
+        }
+
+        if let Some(name) = &self.midi_server {
+            if *name != midi_server.name {
+                errors.push(ConfigError::UnknownMidiServer(name.clone()));
+            } else {
+                let resolved = self.resolved_ports(audio_server, midi_server);
+
+                for controller in resolved.midi_in.iter().chain(resolved.midi_out.iter()) {
+                    if !controller.port.exists {
+                        errors.push(ConfigError::SystemPortNotFound(
+                            controller.port.name.clone(),
+                            controller.id.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+
+        errors
+    }
+}
+
+impl Config {
+    /// A config that uses the default device of the given servers, e.g. for the first launch
+    /// of the app.
+    ///
+    /// It has a stereo output bus on the device's default left/right output ports (mono if
+    /// they are the same port), a mono input bus on the default input port, and a single midi
+    /// input controller on the midi server's default input port. The device's default sample
+    /// rate and buffer size are used.
+    ///
+    /// The input bus and the midi controller are left out if there is no port for them. This
+    /// returns an error if the device has no output ports.
+    pub fn default_from(
+        audio_server: &AudioServerInfo,
+        midi_server: &MidiServerInfo,
+    ) -> Result<Config, ConfigError> {
+        if !audio_server.available {
+            return Err(ConfigError::AudioServerUnavailable(
+                audio_server.name.clone(),
+            ));
+        }
+
+        let device = audio_server
+            .default_device_info()
+            .ok_or_else(|| ConfigError::NoAudioDeviceAvailable(audio_server.name.clone()))?;
+
+        let left_port = device
+            .out_ports
+            .get(device.default_out_port_left)
+            .or_else(|| device.out_ports.first())
+            .ok_or_else(|| ConfigError::NoPlaybackPorts(device.name.clone()))?;
+        let mut out_ports = vec![left_port.clone()];
+        if let Some(right_port) = device.out_ports.get(device.default_out_port_right) {
+            if right_port != left_port {
+                out_ports.push(right_port.clone());
+            }
+        }
+
+        let audio_in_busses: Vec<AudioBusConfig> = AudioBusConfig::mono_in("Mic In", device)
+            .into_iter()
+            .collect();
+
+        let midi_in_port = if midi_server.available {
+            midi_server.in_devices.get(midi_server.default_in_port)
+        } else {
+            None
+        };
+
+        Ok(Config {
+            audio_server: audio_server.name.clone(),
+            system_audio_device: device.name.clone(),
+            audio_in_busses,
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Speakers Out"),
+                system_ports: out_ports,
+                ..Default::default()
+            }],
+            sample_rate: device
+                .sample_rates
+                .get(device.default_sample_rate_index)
+                .copied(),
+            buffer_size: Some(
+                device
+                    .default_buffer_size
+                    .max(device.buffer_size_range.min)
+                    .min(device.buffer_size_range.max),
+            ),
+            midi_server: midi_in_port.map(|_| midi_server.name.clone()),
+            midi_in_controllers: midi_in_port
+                .map(|port| MidiControllerConfig {
+                    id: String::from("Midi In"),
+                    system_port: port.name.clone(),
+                    ..Default::default()
+                })
+                .into_iter()
+                .collect(),
+            ..Config::default()
+        })
+    }
+}
+
+/// Find the device with the given name, if the server is available.
+fn find_device<'a>(server: &'a AudioServerInfo, name: &str) -> Option<&'a AudioDeviceInfo> {
+    if !server.available {
+        return None;
+    }
+
+    server.device_by_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BufferSizeRange, ChannelPosition, MidiDeviceInfo};
+
+    fn test_audio_server() -> AudioServerInfo {
+        let mut server = AudioServerInfo::new(String::from("Jack"), None);
+        server.devices.push(AudioDeviceInfo {
+            name: String::from("Jack Device"),
+            in_ports: vec![
+                String::from("system:capture_1"),
+                String::from("system:capture_2"),
+            ],
+            out_ports: vec![
+                String::from("system:playback_1"),
+                String::from("system:playback_2"),
+            ],
+            sample_rates: vec![48000],
+            buffer_size_range: BufferSizeRange { min: 256, max: 256 },
+            duplex_buffer_size_range: Some(BufferSizeRange { min: 256, max: 256 }),
+            default_in_port: Some(0),
+            default_out_port_left: 0,
+            default_out_port_right: 1,
+            default_sample_rate_index: 0,
+            default_buffer_size: 256,
+        });
+        server.available = true;
+        server
+    }
+
+    fn test_midi_server() -> MidiServerInfo {
+        let mut server = MidiServerInfo::new(String::from("Jack"), None);
+        server.in_devices.push(MidiDeviceInfo {
+            name: String::from("system:midi_capture_1"),
+        });
+        server.out_devices.push(MidiDeviceInfo {
+            name: String::from("system:midi_playback_1"),
+        });
+        server.available = true;
+        server
+    }
+
+    fn test_config() -> Config {
+        Config {
+            audio_server: String::from("Jack"),
+            system_audio_device: String::from("Jack Device"),
+            audio_in_busses: vec![AudioBusConfig {
+                id: String::from("Mic In"),
+                system_ports: vec![String::from("system:capture_1")],
+                ..Default::default()
+            }],
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Speakers Out"),
+                system_ports: vec![
+                    String::from("system:playback_1"),
+                    String::from("system:playback_2"),
+                ],
+                ..Default::default()
+            }],
+            midi_server: Some(String::from("Jack")),
+            midi_in_controllers: vec![MidiControllerConfig {
+                id: String::from("Keys"),
+                system_port: String::from("system:midi_capture_1"),
+                ..Default::default()
+            }],
+            midi_out_controllers: vec![MidiControllerConfig {
+                id: String::from("Synth"),
+                system_port: String::from("system:midi_playback_1"),
+                ..Default::default()
+            }],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn resolve_existing_ports() {
+        let resolved = test_config().resolved_ports(&test_audio_server(), &test_midi_server());
+
+        assert!(resolved.device_found);
+        assert!(resolved.all_exist());
+        assert_eq!(resolved.audio_in[0].ports[0].name, "system:capture_1");
+        assert_eq!(resolved.audio_out[0].ports.len(), 2);
+        assert_eq!(resolved.midi_in[0].port.name, "system:midi_capture_1");
+        assert_eq!(resolved.midi_out[0].port.name, "system:midi_playback_1");
+    }
+
+    #[test]
+    fn resolve_missing_ports() {
+        let mut config = test_config();
+        config.audio_out_busses[0].system_ports[1] = String::from("system:playback_9");
+        config.midi_in_controllers[0].system_port = String::from("system:midi_capture_9");
+
+        let resolved = config.resolved_ports(&test_audio_server(), &test_midi_server());
+
+        let missing: Vec<&str> = resolved
+            .missing_ports()
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(missing, vec!["system:playback_9", "system:midi_capture_9"]);
+
+        // Every port is missing if the server is unavailable.
+        let mut audio_server = test_audio_server();
+        audio_server.available = false;
+        let resolved = test_config().resolved_ports(&audio_server, &test_midi_server());
+        assert_eq!(resolved.missing_ports().len(), 3);
+    }
+
+    #[test]
+    fn resolve_port_patterns() {
+        let mut config = test_config();
+        config.audio_in_busses[0].system_ports = vec![String::from("*:capture_?")];
+        config.audio_out_busses[0].system_ports = vec![
+            String::from("system:playback_2"),
+            String::from("*:playback_9*"),
+        ];
+        config.midi_in_controllers[0].system_port = String::from("system:midi_*");
+
+        let resolved = config.resolved_ports(&test_audio_server(), &test_midi_server());
+
+        // A pattern is one port, the first one that matches.
+        assert_eq!(resolved.audio_in[0].ports.len(), 1);
+        assert_eq!(resolved.audio_in[0].ports[0].name, "system:capture_1");
+        assert_eq!(resolved.audio_out[0].ports[0].name, "system:playback_2");
+        assert_eq!(resolved.midi_in[0].port.name, "system:midi_capture_1");
+        assert!(resolved.midi_in[0].port.exists);
+
+        let missing: Vec<&str> = resolved
+            .missing_ports()
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(missing, vec!["*:playback_9*"]);
+
+        // Patterns are taken literally by backends that don't resolve them.
+        config.audio_server = String::from("ALSA");
+        let mut audio_server = test_audio_server();
+        audio_server.name = String::from("ALSA");
+        let resolved = config.resolved_ports(&audio_server, &test_midi_server());
+        assert!(!resolved.audio_in[0].ports[0].exists);
+        assert_eq!(resolved.audio_in[0].ports[0].name, "*:capture_?");
+    }
+
+    #[test]
+    fn resolve_missing_device() {
+        let mut config = test_config();
+        config.system_audio_device = String::from("USB Interface");
+
+        // The ports aren't looked up on the default device instead.
+        let resolved = config.resolved_ports(&test_audio_server(), &test_midi_server());
+        assert!(!resolved.device_found);
+        assert_eq!(resolved.missing_ports().len(), 3);
+        assert!(resolved.midi_in[0].port.exists);
+
+        assert_eq!(
+            config.validation_errors(&test_audio_server(), &test_midi_server()),
+            vec![ConfigError::SystemDeviceNotFound(String::from(
+                "USB Interface"
+            ))]
+        );
+    }
+
+    #[test]
+    fn resolve_other_midi_server() {
+        let mut midi_server = test_midi_server();
+        midi_server.name = String::from("ALSA");
+
+        let resolved = test_config().resolved_ports(&test_audio_server(), &midi_server);
+
+        assert_eq!(resolved.midi_in.len(), 1);
+        assert_eq!(resolved.midi_in[0].port.name, "system:midi_capture_1");
+        assert!(!resolved.midi_in[0].port.exists);
+        assert!(!resolved.midi_out[0].port.exists);
+    }
+
+    #[test]
+    fn resolve_midi_port_roles() {
+        let in_ports = vec![
+            String::from("system:midi_capture_1"),
+            String::from("system:midi_capture_2"),
+        ];
+        let out_ports = vec![
+            String::from("system:midi_playback_1"),
+            String::from("system:midi_playback_2"),
+            String::from("system:midi_playback_3"),
+        ];
+
+        assert_eq!(
+            MidiPortRole::MidiThrough.resolve(&out_ports, false),
+            Some(&out_ports[0])
+        );
+        assert_eq!(
+            MidiPortRole::MidiThrough.resolve(&in_ports, true),
+            Some(&in_ports[0])
+        );
+        assert_eq!(
+            MidiPortRole::DefaultHardwareOut.resolve(&out_ports, false),
+            Some(&out_ports[1])
+        );
+        assert_eq!(
+            MidiPortRole::DefaultHardwareIn.resolve(&in_ports, true),
+            Some(&in_ports[1])
+        );
+
+        // A role can't be resolved for the wrong direction.
+        assert_eq!(
+            MidiPortRole::DefaultHardwareOut.resolve(&in_ports, true),
+            None
+        );
+
+        // Fallback to the first port that isn't the `Midi-Through` device.
+        let out_ports = vec![
+            String::from("system:midi_playback_1"),
+            String::from("a2j:USB Keyboard [20] (playback): USB Keyboard MIDI 1"),
+        ];
+        assert_eq!(
+            MidiPortRole::DefaultHardwareOut.resolve(&out_ports, false),
+            Some(&out_ports[1])
+        );
+
+        // Only the `Midi-Through` device is available.
+        assert_eq!(
+            MidiPortRole::DefaultHardwareOut.resolve(&out_ports[0..1], false),
+            None
+        );
+        assert_eq!(MidiPortRole::MidiThrough.resolve(&[], false), None);
+
+        for role in [
+            MidiPortRole::MidiThrough,
+            MidiPortRole::DefaultHardwareIn,
+            MidiPortRole::DefaultHardwareOut,
+        ]
+        .iter()
+        {
+            assert_eq!(role.as_str().parse(), Ok(*role));
+        }
+    }
+
+    #[test]
+    fn resolve_roles_in_config() {
+        let mut config = test_config();
+        config.midi_out_controllers[0].system_port = String::new();
+        config.midi_out_controllers[0].system_port_role = Some(MidiPortRole::MidiThrough);
+        config.midi_in_controllers[0].system_port_role = Some(MidiPortRole::DefaultHardwareIn);
+
+        let resolved = config.resolved_ports(&test_audio_server(), &test_midi_server());
+
+        assert_eq!(resolved.midi_out[0].port.name, "system:midi_playback_1");
+        assert!(resolved.midi_out[0].port.exists);
+
+        // The only midi in port is the `Midi-Through` device.
+        assert!(!resolved.midi_in[0].port.exists);
+    }
+
+    #[test]
+    fn resolve_without_midi() {
+        let mut config = test_config();
+        config.midi_server = None;
+
+        let resolved = config.resolved_ports(&test_audio_server(), &test_midi_server());
+
+        assert!(resolved.midi_in.is_empty());
+        assert!(resolved.midi_out.is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let mut config = test_config();
+        config.midi_in_controllers[0].system_port_role = Some(MidiPortRole::DefaultHardwareIn);
+        config.jack_self_connect = JackSelfConnect::Never;
+        config.sample_rate = Some(48000);
+
+        let json = serde_json::to_string(&config).unwrap();
+        let read_config: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(config, read_config);
+
+        let value = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            value["midi_in_controllers"][0]["system_port_role"],
+            "default_hardware_in"
+        );
+        assert_eq!(value["jack_self_connect"], "never");
+
+        // Missing fields fall back to their defaults.
+        let read_config: Config = serde_json::from_str(r#"{"audio_server": "Jack"}"#).unwrap();
+        assert_eq!(read_config.audio_server, "Jack");
+        assert_eq!(read_config.jack_self_connect, JackSelfConnect::SystemPorts);
+    }
+
+    #[test]
+    fn validate_against_devices() {
+        let config = test_config();
+        assert_eq!(
+            config.validate(&test_audio_server(), &test_midi_server()),
+            Ok(())
+        );
+
+        let mut config = test_config();
+        config.audio_in_busses[0].system_ports.clear();
+        config.audio_out_busses[0]
+            .system_ports
+            .push(String::from("system:playback_3"));
+        config.audio_in_busses.push(AudioBusConfig {
+            id: String::from("Speakers Out"),
+            system_ports: vec![String::from("system:capture_2")],
+            ..Default::default()
+        });
+        config.midi_out_controllers[0].system_port = String::from("system:midi_playback_9");
+
+        assert_eq!(
+            config.validation_errors(&test_audio_server(), &test_midi_server()),
+            vec![
+                ConfigError::IdNotUnique(String::from("Speakers Out")),
+                ConfigError::NoSystemPortsGiven(String::from("Mic In")),
+                ConfigError::SystemPortNotFound(
+                    String::from("system:playback_3"),
+                    String::from("Speakers Out")
+                ),
+                ConfigError::SystemPortNotFound(
+                    String::from("system:midi_playback_9"),
+                    String::from("Synth")
+                ),
+            ]
+        );
+        assert_eq!(
+            config.validate(&test_audio_server(), &test_midi_server()),
+            Err(ConfigError::IdNotUnique(String::from("Speakers Out")))
+        );
+
+        let mut config = test_config();
+        config.system_audio_device = String::from("USB Device");
+        config.midi_server = Some(String::from("ALSA"));
+        assert_eq!(
+            config.validation_errors(&test_audio_server(), &test_midi_server()),
+            vec![
+                ConfigError::SystemDeviceNotFound(String::from("USB Device")),
+                ConfigError::UnknownMidiServer(String::from("ALSA")),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_buffer_size() {
+        // The Jack server decides the buffer size, so any size is only a request.
+        let mut config = test_config();
+        config.buffer_size = Some(64);
+        assert_eq!(
+            config.validate(&test_audio_server(), &test_midi_server()),
+            Ok(())
+        );
+
+        let mut audio_server = test_audio_server();
+        audio_server.name = String::from("ALSA");
+        audio_server.devices[0].buffer_size_range = BufferSizeRange { min: 64, max: 1024 };
+        audio_server.devices[0].duplex_buffer_size_range = Some(BufferSizeRange {
+            min: 128,
+            max: 1024,
+        });
+        config.audio_server = String::from("ALSA");
+        config.midi_server = None;
+
+        assert_eq!(
+            config.validate(&audio_server, &test_midi_server()),
+            Err(ConfigError::UnsupportedBufferSize {
+                requested: 64,
+                range: BufferSizeRange {
+                    min: 128,
+                    max: 1024
+                },
+            })
+        );
+
+        config.audio_in_busses.clear();
+        assert_eq!(config.validate(&audio_server, &test_midi_server()), Ok(()));
+    }
+
+    #[test]
+    fn default_from_device_info() {
+        let audio_server = test_audio_server();
+        let midi_server = test_midi_server();
+
+        let config = Config::default_from(&audio_server, &midi_server).unwrap();
+        assert_eq!(config.system_audio_device, "Jack Device");
+        assert_eq!(
+            config.audio_in_busses[0].system_ports,
+            vec!["system:capture_1"]
+        );
+        assert_eq!(
+            config.audio_out_busses[0].system_ports,
+            vec!["system:playback_1", "system:playback_2"]
+        );
+        assert_eq!(config.sample_rate, Some(48000));
+        assert_eq!(config.buffer_size, Some(256));
+        assert_eq!(config.midi_server.as_deref(), Some("Jack"));
+        assert_eq!(
+            config.midi_in_controllers[0].system_port,
+            "system:midi_capture_1"
+        );
+        assert_eq!(config.validate(&audio_server, &midi_server), Ok(()));
+
+        // A mono playback-only device without midi.
+        let mut audio_server = test_audio_server();
+        audio_server.devices[0].in_ports.clear();
+        audio_server.devices[0].out_ports.truncate(1);
+        let mut midi_server = test_midi_server();
+        midi_server.available = false;
+
+        let config = Config::default_from(&audio_server, &midi_server).unwrap();
+        assert!(config.audio_in_busses.is_empty());
+        assert_eq!(
+            config.audio_out_busses[0].system_ports,
+            vec!["system:playback_1"]
+        );
+        assert_eq!(config.midi_server, None);
+        assert!(config.midi_in_controllers.is_empty());
+
+        audio_server.devices[0].out_ports.clear();
+        assert_eq!(
+            Config::default_from(&audio_server, &midi_server),
+            Err(ConfigError::NoPlaybackPorts(String::from("Jack Device")))
+        );
+    }
+
+    #[test]
+    fn skipped_midi() {
+        let mut config = test_config();
+        assert_eq!(config.midi_backend(), Some(Backend::Jack));
+        assert_eq!(config.skipped_midi_reason(Backend::Jack), None);
+        assert!(config.creates_midi_controllers(Backend::Jack));
+
+        config.midi_server = Some(String::from("ALSA"));
+        assert_eq!(config.midi_backend(), Some(Backend::Alsa));
+        assert!(config.midi_enabled());
+        assert!(!config.creates_midi_controllers(Backend::Jack));
+        assert!(config
+            .skipped_midi_reason(Backend::Jack)
+            .unwrap()
+            .contains("not \"ALSA\""));
+
+        config.midi_server = None;
+        assert_eq!(config.midi_backend(), None);
+        assert!(!config.midi_enabled());
+        assert!(config
+            .skipped_midi_reason(Backend::Jack)
+            .unwrap()
+            .contains("the 2 midi controllers"));
+
+        config.midi_in_controllers.clear();
+        config.midi_out_controllers.clear();
+        assert_eq!(config.skipped_midi_reason(Backend::Jack), None);
+    }
+
+    #[test]
+    fn busses_from_port_indices() {
+        let mut device = test_audio_server().devices.remove(0);
+
+        let speakers = AudioBusConfig::stereo_out("Speakers Out", &device).unwrap();
+        assert_eq!(
+            speakers.system_ports,
+            vec!["system:playback_1", "system:playback_2"]
+        );
+        assert_eq!(speakers.channel_layout(), ChannelLayout::Stereo);
+
+        let mic = AudioBusConfig::mono_in("Mic In", &device).unwrap();
+        assert_eq!(mic.system_ports, vec!["system:capture_1"]);
+
+        let swapped = AudioBusConfig::from_in_port_indices("Swapped", &device, &[1, 0]).unwrap();
+        assert_eq!(
+            swapped.system_ports,
+            vec!["system:capture_2", "system:capture_1"]
+        );
+
+        assert_eq!(
+            AudioBusConfig::from_out_port_indices("Out", &device, &[0, 2]),
+            None
+        );
+        assert_eq!(
+            AudioBusConfig::from_out_port_indices("Out", &device, &[]),
+            None
+        );
+
+        device.default_out_port_right = 0;
+        assert_eq!(AudioBusConfig::stereo_out("Speakers Out", &device), None);
+    }
+
+    #[test]
+    fn pretty_port_names() {
+        let device = test_audio_server().devices.remove(0);
+
+        let speakers = AudioBusConfig::stereo_out("Main", &device).unwrap();
+        assert_eq!(speakers.pretty_port_name(0), "Main • Left");
+        assert_eq!(speakers.pretty_port_name(1), "Main • Right");
+
+        let mic = AudioBusConfig::mono_in("Mic", &device).unwrap();
+        assert_eq!(mic.pretty_port_name(0), "Mic");
+
+        let mut surround = speakers.clone();
+        surround.channel_layout = Some(ChannelLayout::Positions(vec![
+            ChannelPosition::Lfe,
+            ChannelPosition::Discrete(2),
+        ]));
+        assert_eq!(surround.pretty_port_name(0), "Main • LFE");
+        assert_eq!(surround.pretty_port_name(1), "Main • 3");
+    }
 }
@@ -0,0 +1,210 @@
+use crate::{
+    AudioBusConfig, Config, ConfigBuildError, MidiControllerConfig, SpawnRtThreadError,
+    StreamDirection,
+};
+
+/// A fluent way to put together a `Config`.
+///
+/// ```
+/// use rusty_daw_io::ConfigBuilder;
+///
+/// let config = ConfigBuilder::new()
+///     .audio_server("Jack")
+///     .system_audio_device("Jack")
+///     .audio_out_bus("Main", &["system:playback_1", "system:playback_2"])
+///     .audio_in_bus("Mic", &["system:capture_1"])
+///     .midi_server("Jack")
+///     .midi_in_controller("Keys", "system:midi_capture_2")
+///     .build()
+///     .unwrap();
+///
+/// assert_eq!(config.audio_out_busses[0].system_ports.len(), 2);
+/// ```
+///
+/// Busses and controllers are added in order, so the index of each one in its `StreamInfo`
+/// list is the order it was added in.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from an existing config.
+    pub fn from_config(config: Config) -> Self {
+        Self { config }
+    }
+
+    pub fn audio_server(mut self, name: &str) -> Self {
+        self.config.audio_server = String::from(name);
+        self
+    }
+
+    pub fn system_audio_device(mut self, name: &str) -> Self {
+        self.config.system_audio_device = String::from(name);
+        self
+    }
+
+    /// Add an audio input bus connected to the given system ports.
+    pub fn audio_in_bus(mut self, id: &str, system_ports: &[&str]) -> Self {
+        self.config
+            .audio_in_busses
+            .push(audio_bus_config(id, system_ports));
+        self
+    }
+
+    /// Add an audio output bus connected to the given system ports.
+    pub fn audio_out_bus(mut self, id: &str, system_ports: &[&str]) -> Self {
+        self.config
+            .audio_out_busses
+            .push(audio_bus_config(id, system_ports));
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.config.sample_rate = Some(sample_rate);
+        self
+    }
+
+    pub fn buffer_size(mut self, buffer_size: u32) -> Self {
+        self.config.buffer_size = Some(buffer_size);
+        self
+    }
+
+    pub fn direction(mut self, direction: StreamDirection) -> Self {
+        self.config.direction = direction;
+        self
+    }
+
+    pub fn midi_server(mut self, name: &str) -> Self {
+        self.config.midi_server = Some(String::from(name));
+        self
+    }
+
+    /// Add a midi input controller connected to the given system port.
+    pub fn midi_in_controller(mut self, id: &str, system_port: &str) -> Self {
+        self.config
+            .midi_in_controllers
+            .push(midi_controller_config(id, system_port));
+        self
+    }
+
+    /// Add a midi output controller connected to the given system port.
+    pub fn midi_out_controller(mut self, id: &str, system_port: &str) -> Self {
+        self.config
+            .midi_out_controllers
+            .push(midi_controller_config(id, system_port));
+        self
+    }
+
+    /// Check that every bus and controller has a unique, non-empty ID and return the config.
+    pub fn build(self) -> Result<Config, ConfigBuildError> {
+        let config = self.config;
+
+        let has_empty_id = config
+            .audio_in_busses
+            .iter()
+            .chain(config.audio_out_busses.iter())
+            .any(|b| b.id.is_empty())
+            || config
+                .midi_in_controllers
+                .iter()
+                .chain(config.midi_out_controllers.iter())
+                .any(|c| c.id.is_empty());
+        if has_empty_id {
+            return Err(ConfigBuildError::EmptyId);
+        }
+
+        match crate::check_duplicate_ids(&config) {
+            Err(SpawnRtThreadError::IdNotUnique(id)) => Err(ConfigBuildError::IdNotUnique(id)),
+            Err(SpawnRtThreadError::PortNameNotUnique(id)) => {
+                Err(ConfigBuildError::PortNameNotUnique(id))
+            }
+            _ => Ok(config),
+        }
+    }
+}
+
+fn audio_bus_config(id: &str, system_ports: &[&str]) -> AudioBusConfig {
+    AudioBusConfig {
+        id: String::from(id),
+        system_ports: system_ports.iter().map(|p| String::from(*p)).collect(),
+        ..Default::default()
+    }
+}
+
+fn midi_controller_config(id: &str, system_port: &str) -> MidiControllerConfig {
+    MidiControllerConfig {
+        id: String::from(id),
+        system_port: String::from(system_port),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_config_in_order() {
+        let config = ConfigBuilder::new()
+            .audio_server("Jack")
+            .system_audio_device("Jack")
+            .audio_out_bus("Main", &["system:playback_1", "system:playback_2"])
+            .audio_out_bus("Headphones", &["system:playback_3"])
+            .audio_in_bus("Mic", &["system:capture_1"])
+            .midi_server("Jack")
+            .midi_in_controller("Keys", "system:midi_capture_2")
+            .buffer_size(256)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.audio_server, "Jack");
+        assert_eq!(config.audio_out_busses[0].id, "Main");
+        assert_eq!(config.audio_out_busses[1].id, "Headphones");
+        assert_eq!(
+            config.audio_out_busses[0].system_ports,
+            vec!["system:playback_1", "system:playback_2"]
+        );
+        assert_eq!(
+            config.audio_in_busses[0].system_ports,
+            vec!["system:capture_1"]
+        );
+        assert_eq!(config.midi_server.as_deref(), Some("Jack"));
+        assert_eq!(
+            config.midi_in_controllers[0].system_port,
+            "system:midi_capture_2"
+        );
+        assert_eq!(config.buffer_size, Some(256));
+        assert_eq!(config.sample_rate, None);
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        assert!(matches!(
+            ConfigBuilder::new()
+                .audio_out_bus("Main", &["system:playback_1"])
+                .audio_in_bus("Main", &["system:capture_1"])
+                .build(),
+            Err(ConfigBuildError::IdNotUnique(id)) if id == "Main"
+        ));
+
+        assert!(matches!(
+            ConfigBuilder::new()
+                .audio_out_bus("Main", &["system:playback_1"])
+                .midi_out_controller("", "system:midi_playback_1")
+                .build(),
+            Err(ConfigBuildError::EmptyId)
+        ));
+
+        // A bidirectional controller may use the same ID in both directions.
+        assert!(ConfigBuilder::new()
+            .midi_in_controller("Surface", "system:midi_capture_1")
+            .midi_out_controller("Surface", "system:midi_playback_1")
+            .build()
+            .is_ok());
+    }
+}
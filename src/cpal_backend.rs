@@ -0,0 +1,1598 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use log::{debug, info, warn};
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::channel_gain::{ChannelGains, ChannelGainsHandle};
+use crate::cycle_debug::{cycle_debugger, CycleDebugHandle, CycleDebugInfo, CycleDebugger};
+use crate::denormals::DenormalGuard;
+use crate::diagnostics::{BufferSizeHistory, RingBufferCounters};
+use crate::master_gain::{MasterGain, MasterGainHandle};
+use crate::port_mixing::{channel_routes, ChannelRoute};
+use crate::process_panic::catch_process_panic;
+use crate::sample_format::{Dither, Sample};
+use crate::shutdown_hook::ShutdownHook;
+use crate::silence_detect::{SilenceDetectHandle, SilenceDetector};
+use crate::spawn_deadline::SpawnDeadline;
+use crate::xruns::Xruns;
+use crate::{
+    AddBusError, AudioBus, AudioBusBuffer, AudioBusConfig, AudioDeviceInfo, AudioServerInfo,
+    Backend, BufferSizeRange, Config, DeviceIndex, DisconnectReason, EnumerationError,
+    FatalErrorHandler, FatalStreamError, FreewheelError, FreewheelHook, LatencyRange,
+    MidiController, MidiControllerConfig, OsStreamHandle, PortConnections, ProcessControl,
+    ProcessInfo, RtProcessHandler, RtThreadPolicy, SampleFormat, SpawnError, SpawnPhase,
+    SpawnRtThreadError, StreamDiagnostics, StreamDirection, StreamInfo,
+};
+
+/// CPAL only reports ranges of sample rates, so these are the ones that are listed for a
+/// device if they fall into one of its ranges.
+static PROBE_SAMPLE_RATES: [u32; 6] = [44100, 48000, 88200, 96000, 176400, 192000];
+
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+const DEFAULT_BUFFER_SIZE: u32 = 512;
+
+/// The buffer sizes that are reported for a device whose host can't tell before a stream is
+/// opened.
+const UNKNOWN_BUFFER_SIZE_RANGE: BufferSizeRange = BufferSizeRange { min: 16, max: 4096 };
+
+/// How many buffers of captured samples the fifo between the input and the output stream
+/// can hold.
+const CAPTURE_FIFO_BUFFERS: usize = 4;
+
+/// How long the audio thread waits for CPAL to drop the callback that owns the process
+/// handler after the streams are closed.
+const HANDLER_RETURN_TIMEOUT: Duration = Duration::from_secs(1);
+
+const PLAYBACK_PORT_PREFIX: &str = "playback_";
+const CAPTURE_PORT_PREFIX: &str = "capture_";
+
+pub(crate) fn refresh_audio_server(server: &mut AudioServerInfo) -> Result<(), EnumerationError> {
+    info!("Refreshing list of available CPAL audio devices...");
+
+    server.devices.clear();
+    server.default_device = 0;
+    server.available = false;
+
+    let host = cpal::default_host();
+    debug!("CPAL: using the {} host", host.id().name());
+
+    let output_devices = host
+        .output_devices()
+        .map_err(|e| EnumerationError::PlatformSpecific(Box::new(e)))?;
+
+    // Inputs are matched to the output device of the same name. Hosts that list them as
+    // separate devices (e.g. WASAPI) only get output ports.
+    let input_devices: Vec<(String, DeviceConfigs)> = host
+        .input_devices()
+        .map(|devices| {
+            devices
+                .filter_map(|device| {
+                    let name = device.name().ok()?;
+                    let configs = device_configs(device.supported_input_configs().ok()?)?;
+                    Some((name, configs))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let default_output = host.default_output_device().and_then(|d| d.name().ok());
+
+    for device in output_devices {
+        let name = match device.name() {
+            Ok(name) => name,
+            Err(e) => {
+                warn!("CPAL: failed to get the name of a device: {}", e);
+                continue;
+            }
+        };
+
+        // This crate only allows devices with playback.
+        let playback = match device
+            .supported_output_configs()
+            .ok()
+            .and_then(device_configs)
+        {
+            Some(playback) => playback,
+            None => {
+                info!(
+                    "CPAL: skipping device {} since it has no usable playback",
+                    &name
+                );
+                continue;
+            }
+        };
+        let capture = input_devices
+            .iter()
+            .find(|(input_name, _)| *input_name == name)
+            .map(|(_, configs)| configs);
+
+        let in_ports = capture
+            .map(|c| port_names(CAPTURE_PORT_PREFIX, c.max_channels))
+            .unwrap_or_default();
+        let out_ports = port_names(PLAYBACK_PORT_PREFIX, playback.max_channels);
+
+        // Only report the sample rates both directions can run at.
+        let sample_rates: Vec<u32> = if let Some(capture) = capture {
+            playback
+                .sample_rates
+                .iter()
+                .copied()
+                .filter(|rate| capture.sample_rates.contains(rate))
+                .collect()
+        } else {
+            playback.sample_rates.clone()
+        };
+        if sample_rates.is_empty() {
+            info!(
+                "CPAL: skipping device {} since it supports none of the probed sample rates",
+                &name
+            );
+            continue;
+        }
+
+        let default_sample_rate_index = sample_rates
+            .iter()
+            .position(|rate| *rate == DEFAULT_SAMPLE_RATE)
+            .or_else(|| sample_rates.iter().position(|rate| *rate == 44100))
+            .unwrap_or(0);
+
+        let buffer_size_range = playback.buffer_size_range;
+        let duplex_buffer_size_range =
+            capture.and_then(|c| buffer_size_range.intersection(&c.buffer_size_range));
+        let default_buffer_size =
+            DEFAULT_BUFFER_SIZE.clamp(buffer_size_range.min, buffer_size_range.max);
+        let default_in_port = if in_ports.is_empty() { None } else { Some(0) };
+
+        debug!(
+            "CPAL: found device {} with {} in ports, {} out ports, sample rates {:?}, buffer sizes {}..{}",
+            &name,
+            in_ports.len(),
+            out_ports.len(),
+            &sample_rates,
+            buffer_size_range.min,
+            buffer_size_range.max,
+        );
+
+        if default_output.as_ref() == Some(&name) {
+            server.default_device = server.devices.len();
+        }
+
+        server.devices.push(AudioDeviceInfo {
+            name,
+            in_ports,
+            out_ports,
+            sample_rates,
+            buffer_size_range,
+            duplex_buffer_size_range,
+
+            default_in_port,
+            default_out_port_left: 0,
+            default_out_port_right: 1.min(playback.max_channels as usize - 1), // Second port if stereo, first if mono.
+            default_sample_rate_index,
+            default_buffer_size,
+        });
+    }
+
+    server.available = !server.devices.is_empty();
+
+    if !server.available {
+        info!("CPAL server is unavailable: no CPAL device with playback was found.");
+    }
+
+    Ok(())
+}
+
+/// The latency `DevicesInfo::estimated_latency()` reports for a config of this backend.
+pub(crate) fn estimated_latency(server: &AudioServerInfo, config: &Config) -> Option<u32> {
+    server
+        .device_by_name(&config.system_audio_device)
+        .map(|device| config.buffer_size.unwrap_or(device.default_buffer_size))
+}
+
+/// The sample rate `DevicesInfo::sample_rate()` reports for a config of this backend.
+pub(crate) fn sample_rate(server: &AudioServerInfo, config: &Config) -> Option<u32> {
+    server
+        .device_by_name(&config.system_audio_device)
+        .map(|device| {
+            config
+                .sample_rate
+                .unwrap_or(device.sample_rates[device.default_sample_rate_index])
+        })
+}
+
+/// What a device supports in one direction, out of all of its CPAL configs.
+#[derive(Debug, PartialEq)]
+struct DeviceConfigs {
+    max_channels: u16,
+    sample_rates: Vec<u32>,
+    buffer_size_range: BufferSizeRange,
+}
+
+/// Summarize the configs of a device, or `None` if it has none.
+fn device_configs(
+    configs: impl Iterator<Item = cpal::SupportedStreamConfigRange>,
+) -> Option<DeviceConfigs> {
+    let configs: Vec<ConfigRange> = configs
+        .map(|c| ConfigRange {
+            channels: c.channels(),
+            min_sample_rate: c.min_sample_rate().0,
+            max_sample_rate: c.max_sample_rate().0,
+            buffer_size_range: match c.buffer_size() {
+                cpal::SupportedBufferSize::Range { min, max } => Some(BufferSizeRange {
+                    min: (*min).max(1),
+                    max: *max,
+                }),
+                cpal::SupportedBufferSize::Unknown => None,
+            },
+        })
+        .collect();
+
+    summarize_configs(&configs)
+}
+
+/// The parts of a `cpal::SupportedStreamConfigRange` that are reported.
+struct ConfigRange {
+    channels: u16,
+    min_sample_rate: u32,
+    max_sample_rate: u32,
+    buffer_size_range: Option<BufferSizeRange>,
+}
+
+fn summarize_configs(configs: &[ConfigRange]) -> Option<DeviceConfigs> {
+    let max_channels = configs.iter().map(|c| c.channels).max()?;
+
+    let sample_rates = PROBE_SAMPLE_RATES
+        .iter()
+        .copied()
+        .filter(|rate| {
+            configs
+                .iter()
+                .any(|c| c.min_sample_rate <= *rate && *rate <= c.max_sample_rate)
+        })
+        .collect();
+
+    let buffer_size_range = configs
+        .iter()
+        .filter_map(|c| c.buffer_size_range)
+        .reduce(|a, b| BufferSizeRange {
+            min: a.min.min(b.min),
+            max: a.max.max(b.max),
+        })
+        .unwrap_or(UNKNOWN_BUFFER_SIZE_RANGE);
+
+    Some(DeviceConfigs {
+        max_channels,
+        sample_rates,
+        buffer_size_range,
+    })
+}
+
+fn port_names(prefix: &str, channels: u16) -> Vec<String> {
+    (1..=channels).map(|i| format!("{}{}", prefix, i)).collect()
+}
+
+/// Parse the device channel a port like "playback_1" refers to.
+fn parse_port_channel(port: &str, prefix: &str) -> Option<usize> {
+    let n: usize = port.strip_prefix(prefix)?.parse().ok()?;
+
+    if n == 0 {
+        None
+    } else {
+        Some(n - 1)
+    }
+}
+
+pub(crate) struct CpalStreamHandle<P: RtProcessHandler, E: FatalErrorHandler> {
+    stream_info: StreamInfo,
+    running: Arc<AtomicBool>,
+
+    /// Closes the streams, which are owned by the audio thread since they can't be sent to
+    /// another thread.
+    close_tx: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<Option<P>>>,
+
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_debug_handle: CycleDebugHandle,
+    master_gain_handle: MasterGainHandle,
+    channel_gains_handle: ChannelGainsHandle,
+    silence_detect_handle: SilenceDetectHandle,
+    shutdown_hook: Arc<ShutdownHook>,
+    xruns: Arc<Xruns>,
+    /// The counters of the capture FIFO of a duplex stream.
+    capture_fifo: Option<Arc<RingBufferCounters>>,
+
+    // The fatal error handler is owned by the callbacks.
+    _fatal_error_handler: PhantomData<E>,
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> OsStreamHandle for CpalStreamHandle<P, E> {
+    type P = P;
+    type E = E;
+
+    fn stream_info(&self) -> &StreamInfo {
+        &self.stream_info
+    }
+
+    fn frames_processed(&self) -> u64 {
+        self.frames_processed.load(Ordering::Relaxed)
+    }
+
+    fn debug_next_cycles(&self, n: u32) {
+        self.cycle_debug_handle.debug_next_cycles(n);
+    }
+
+    fn diagnostics(&self) -> StreamDiagnostics {
+        StreamDiagnostics {
+            frames_processed: self.frames_processed(),
+            xruns: self.xruns.count(),
+            cycle_debug_queue: self.cycle_debug_handle.queue_stats(),
+            capture_fifo: self.capture_fifo.as_ref().map(|counters| counters.stats()),
+            ..StreamDiagnostics::default()
+        }
+    }
+
+    fn recent_buffer_sizes(&self) -> Vec<u32> {
+        self.buffer_size_history.recent()
+    }
+
+    fn midi_in_dropped_events(&self, _controller_id: &str) -> Option<u64> {
+        None
+    }
+
+    fn set_master_gain(&self, gain: f32) {
+        self.master_gain_handle.set_master_gain(gain);
+    }
+
+    fn set_master_gain_ramp_time(&self, ramp_time: Duration) {
+        self.master_gain_handle.set_master_gain_ramp_time(ramp_time);
+    }
+
+    fn master_gain(&self) -> f32 {
+        self.master_gain_handle.master_gain()
+    }
+
+    fn channel_gains_handle(&self) -> Option<&ChannelGainsHandle> {
+        Some(&self.channel_gains_handle)
+    }
+
+    fn silence_detect_handle(&self) -> Option<&SilenceDetectHandle> {
+        Some(&self.silence_detect_handle)
+    }
+
+    fn shutdown_hook(&self) -> &ShutdownHook {
+        &self.shutdown_hook
+    }
+
+    fn bus_latency(&self, _bus_id: &str) -> Option<LatencyRange> {
+        None
+    }
+
+    fn port_connections(&self, _bus_id: &str) -> Option<Vec<PortConnections>> {
+        None
+    }
+
+    fn set_freewheel(&self, _enabled: bool) -> Result<(), FreewheelError> {
+        Err(FreewheelError::NotSupported(Backend::Cpal))
+    }
+
+    fn is_freewheeling(&self) -> bool {
+        false
+    }
+
+    fn on_freewheel(&self, _hook: FreewheelHook) {}
+
+    fn add_audio_bus(
+        &mut self,
+        _bus: &AudioBusConfig,
+        _is_input: bool,
+    ) -> Result<AudioBus, AddBusError> {
+        Err(AddBusError::NotSupported(Backend::Cpal))
+    }
+
+    fn add_midi_controller(
+        &mut self,
+        _controller: &MidiControllerConfig,
+        _is_input: bool,
+    ) -> Result<MidiController, AddBusError> {
+        Err(AddBusError::NotSupported(Backend::Cpal))
+    }
+
+    fn xruns(&self) -> &Xruns {
+        &self.xruns
+    }
+
+    fn stop(&mut self) -> Option<P> {
+        let thread = self.thread.take()?;
+
+        self.shutdown_hook.run();
+
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(close_tx) = self.close_tx.take() {
+            // The audio thread has already closed the streams if the stream failed.
+            let _ = close_tx.send(());
+        }
+
+        match thread.join() {
+            Ok(rt_process_handler) => rt_process_handler,
+            Err(_) => {
+                warn!("CPAL: audio thread panicked");
+                None
+            }
+        }
+    }
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> Drop for CpalStreamHandle<P, E> {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+pub(crate) fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
+    config: &Config,
+    rt_process_handler: P,
+    fatal_error_handler: E,
+) -> Result<CpalStreamHandle<P, E>, SpawnError<P>> {
+    let mut rt_process_handler = Some(rt_process_handler);
+    spawn(config, &mut rt_process_handler, fatal_error_handler)
+        .map_err(|e| SpawnError::new(e, rt_process_handler))
+}
+
+/// Spawn the stream, leaving the handler in `rt_process_handler_slot` if it fails.
+fn spawn<P: RtProcessHandler, E: FatalErrorHandler>(
+    config: &Config,
+    rt_process_handler_slot: &mut Option<P>,
+    fatal_error_handler: E,
+) -> Result<CpalStreamHandle<P, E>, SpawnRtThreadError> {
+    info!("Spawning CPAL thread...");
+
+    let deadline = SpawnDeadline::new(config.spawn_timeout);
+
+    // CPAL runs the callbacks on threads of its own, whose scheduling it sets itself.
+    if config.rt_thread.required && config.rt_thread.policy != RtThreadPolicy::Normal {
+        return Err(SpawnRtThreadError::RtPriorityDenied(String::from(
+            "CPAL sets the scheduling of its audio threads itself",
+        )));
+    }
+
+    if config.midi_enabled() {
+        warn!("CPAL: MIDI is not supported, no MIDI controllers will be created");
+    }
+
+    let (cycle_debugger, cycle_debug_handle) = cycle_debugger();
+    let master_gain = MasterGain::new();
+    let channel_gains = ChannelGains::new(&config.audio_in_busses, &config.audio_out_busses);
+    let silence_detector = SilenceDetector::new(
+        config
+            .audio_in_busses
+            .iter()
+            .map(|bus| bus.id.clone())
+            .collect(),
+    );
+
+    let running = Arc::new(AtomicBool::new(true));
+    let frames_processed = Arc::new(AtomicU64::new(0));
+    let buffer_size_history = Arc::new(BufferSizeHistory::new(config.buffer_size_history_len()));
+    let master_gain_handle = master_gain.handle();
+    let channel_gains_handle = channel_gains.handle();
+    let silence_detect_handle = silence_detector.handle();
+    let shutdown_hook = Arc::new(ShutdownHook::new());
+    let xruns = Arc::new(Xruns::new());
+
+    let (opened_tx, opened_rx) = mpsc::channel();
+    let (close_tx, close_rx) = mpsc::channel();
+
+    let audio_thread = AudioThread {
+        config: config.clone(),
+        rt_process_handler: rt_process_handler_slot
+            .take()
+            .expect("the handler is only taken once"),
+        fatal_error_handler,
+        running: Arc::clone(&running),
+        frames_processed: Arc::clone(&frames_processed),
+        buffer_size_history: Arc::clone(&buffer_size_history),
+        cycle_debugger,
+        master_gain,
+        channel_gains,
+        silence_detector,
+        shutdown_hook: Arc::clone(&shutdown_hook),
+        xruns: Arc::clone(&xruns),
+        close_tx: close_tx.clone(),
+        close_rx,
+    };
+
+    // CPAL streams can't be sent to another thread, so they are opened (and closed) on a
+    // thread of their own.
+    let thread = std::thread::Builder::new()
+        .name(String::from("rusty-daw-io CPAL"))
+        .spawn(move || audio_thread.run(opened_tx))
+        .map_err(|e| SpawnRtThreadError::PlatformSpecific(Box::new(e)))?;
+
+    let (stream_info, capture_fifo) = match deadline.recv(SpawnPhase::Open, &opened_rx)? {
+        Some(Ok(opened)) => opened,
+        Some(Err(e)) => {
+            *rt_process_handler_slot = thread.join().ok().flatten();
+            return Err(e);
+        }
+        None => {
+            warn!("CPAL: audio thread panicked");
+            return Err(SpawnRtThreadError::PlatformSpecific(Box::new(
+                std::io::Error::other("the CPAL audio thread panicked"),
+            )));
+        }
+    };
+    shutdown_hook.set_stream_info(stream_info.clone());
+
+    let mut handle = CpalStreamHandle {
+        stream_info,
+        running,
+        close_tx: Some(close_tx),
+        thread: Some(thread),
+        frames_processed,
+        buffer_size_history,
+        cycle_debug_handle,
+        master_gain_handle,
+        channel_gains_handle,
+        silence_detect_handle,
+        shutdown_hook,
+        xruns,
+        capture_fifo,
+        _fatal_error_handler: PhantomData,
+    };
+
+    if let Err(e) = deadline.check(SpawnPhase::Activate) {
+        *rt_process_handler_slot = handle.stop();
+        return Err(e);
+    }
+
+    info!(
+        "Successfully spawned CPAL thread. Device: {}, Sample rate: {}, Max audio buffer size: {}, Format: {:?}",
+        &config.system_audio_device,
+        handle.stream_info.sample_rate,
+        handle.stream_info.max_audio_buffer_size,
+        handle.stream_info.sample_format,
+    );
+
+    Ok(handle)
+}
+
+/// What the audio thread sends back once the streams are open: the `StreamInfo`, and the
+/// counters of the capture FIFO if the stream is duplex.
+type Opened = (StreamInfo, Option<Arc<RingBufferCounters>>);
+
+/// Everything the audio thread needs to open the streams.
+struct AudioThread<P: RtProcessHandler, E: FatalErrorHandler> {
+    config: Config,
+    rt_process_handler: P,
+    fatal_error_handler: E,
+
+    running: Arc<AtomicBool>,
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_debugger: CycleDebugger,
+    master_gain: MasterGain,
+    channel_gains: ChannelGains,
+    silence_detector: SilenceDetector,
+    shutdown_hook: Arc<ShutdownHook>,
+    xruns: Arc<Xruns>,
+
+    close_tx: mpsc::Sender<()>,
+    close_rx: mpsc::Receiver<()>,
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> AudioThread<P, E> {
+    /// Open the streams and keep them open until the stream is stopped or fails. This
+    /// returns the process handler so it can be handed back from `CpalStreamHandle::stop()`,
+    /// or from `spawn_rt_thread()` if the streams could not be opened.
+    fn run(self, opened_tx: mpsc::Sender<Result<Opened, SpawnRtThreadError>>) -> Option<P> {
+        let AudioThread {
+            config,
+            mut rt_process_handler,
+            fatal_error_handler,
+            running,
+            frames_processed,
+            buffer_size_history,
+            cycle_debugger,
+            master_gain,
+            channel_gains,
+            silence_detector,
+            shutdown_hook,
+            xruns,
+            close_tx,
+            close_rx,
+        } = self;
+
+        let devices = match open_devices(&config) {
+            Ok(devices) => devices,
+            Err(e) => {
+                let _ = opened_tx.send(Err(e));
+                return Some(rt_process_handler);
+            }
+        };
+        let stream_info = devices.stream_info.clone();
+        let max_frames = stream_info.max_audio_buffer_size as usize;
+
+        // From here on the handler is owned by one of the callbacks, and is sent back once
+        // CPAL drops it.
+        rt_process_handler.init(&stream_info);
+        let (handler_tx, handler_rx) = mpsc::channel();
+
+        let shutdown = Arc::new(Shutdown {
+            running,
+            shutdown_hook,
+            fatal_error_handler: Mutex::new(Some(fatal_error_handler)),
+            close_tx: Mutex::new(Some(close_tx)),
+        });
+
+        let (capture_producer, capture_consumer) =
+            if devices.input.is_some() && devices.output.is_some() {
+                let (producer, consumer) =
+                    RingBuffer::<f32>::new(max_frames * devices.in_channels * CAPTURE_FIFO_BUFFERS)
+                        .split();
+                (Some(producer), Some(consumer))
+            } else {
+                (None, None)
+            };
+        let capture_fifo_counters = Arc::new(RingBufferCounters::default());
+        let opened_capture_fifo = capture_consumer
+            .as_ref()
+            .map(|_| Arc::clone(&capture_fifo_counters));
+
+        let process = CpalProcess {
+            rt_process_handler: Some(rt_process_handler),
+            handler_tx,
+            shutdown: Arc::clone(&shutdown),
+            audio_in_buffers: stream_info
+                .audio_in
+                .iter()
+                .map(|bus| {
+                    AudioBusBuffer::for_input_bus(bus, max_frames as u32, config.input_peaks)
+                })
+                .collect(),
+            audio_out_buffers: stream_info
+                .audio_out
+                .iter()
+                .map(|bus| AudioBusBuffer::for_bus(bus, max_frames as u32))
+                .collect(),
+            in_channels: devices.in_channels,
+            out_channels: devices.out_channels,
+            in_channel_map: devices.in_channel_map,
+            out_channel_map: devices.out_channel_map,
+            capture_fifo: capture_consumer,
+            capture_fifo_counters: Arc::clone(&capture_fifo_counters),
+            in_raw: vec![0.0; max_frames * devices.in_channels],
+            out_mix: vec![0.0; max_frames * devices.out_channels],
+            dither: Dither::new(),
+            stream_info: stream_info.clone(),
+            frames_processed,
+            buffer_size_history,
+            cycle_debugger,
+            master_gain,
+            channel_gains,
+            silence_detector,
+            flush_denormals: !config.allow_denormals,
+            xruns: Arc::clone(&xruns),
+        };
+
+        let device = config.system_audio_device.clone();
+        let on_error = || stream_error_callback(Arc::clone(&shutdown), device.clone());
+
+        // The process runs in the callback of the output stream, or in the one of the input
+        // stream if there is no output.
+        let opened = match (&devices.output, &devices.input, capture_producer) {
+            (Some(output), Some(input), Some(producer)) => {
+                build_capture_stream(input, producer, capture_fifo_counters, xruns, on_error())
+                    .and_then(|capture| {
+                        build_output_stream(output, process, on_error())
+                            .map(|playback| vec![capture, playback])
+                    })
+            }
+            (Some(output), _, _) => {
+                build_output_stream(output, process, on_error()).map(|playback| vec![playback])
+            }
+            (None, Some(input), _) => {
+                build_input_stream(input, process, on_error()).map(|capture| vec![capture])
+            }
+            (None, None, _) => unreachable!("a stream opens at least one direction"),
+        }
+        .map_err(cpal_error)
+        .and_then(|streams| {
+            for stream in streams.iter() {
+                stream.play().map_err(cpal_error)?;
+            }
+            Ok(streams)
+        });
+
+        match opened {
+            Ok(streams) => {
+                if opened_tx
+                    .send(Ok((stream_info, opened_capture_fifo)))
+                    .is_ok()
+                {
+                    // This returns once the stream is stopped, by the handle or a callback.
+                    let _ = close_rx.recv();
+                }
+                // Otherwise the spawn timed out and gave up on the stream.
+
+                drop(streams);
+            }
+            Err(e) => {
+                let _ = opened_tx.send(Err(e));
+            }
+        }
+
+        match handler_rx.recv_timeout(HANDLER_RETURN_TIMEOUT) {
+            Ok(mut rt_process_handler) => {
+                rt_process_handler.deinit();
+                Some(rt_process_handler)
+            }
+            Err(_) => {
+                warn!("CPAL: the closed stream did not give the process handler back");
+                None
+            }
+        }
+    }
+}
+
+/// A device direction that is ready to be opened.
+struct DeviceStream {
+    device: cpal::Device,
+    config: cpal::StreamConfig,
+    format: cpal::SampleFormat,
+}
+
+/// The device channels each channel of each bus is routed to.
+type ChannelMap = Vec<Vec<ChannelRoute<usize>>>;
+
+/// The devices to open, and how every bus maps to them.
+struct OpenDevices {
+    stream_info: StreamInfo,
+
+    input: Option<DeviceStream>,
+    output: Option<DeviceStream>,
+
+    in_channels: usize,
+    out_channels: usize,
+
+    in_channel_map: ChannelMap,
+    out_channel_map: ChannelMap,
+}
+
+/// Find the device of `config` and pick the configs to open it with.
+fn open_devices(config: &Config) -> Result<OpenDevices, SpawnRtThreadError> {
+    let host = cpal::default_host();
+    let device_name = &config.system_audio_device;
+    let not_found = || SpawnRtThreadError::SystemDeviceNotFound(device_name.clone());
+
+    let output = if config.direction.has_output() {
+        Some(find_device(host.output_devices(), device_name).ok_or_else(not_found)?)
+    } else {
+        None
+    };
+    let input =
+        if config.direction == StreamDirection::InputOnly || !config.audio_in_busses.is_empty() {
+            Some(find_device(host.input_devices(), device_name).ok_or_else(not_found)?)
+        } else {
+            None
+        };
+
+    let output_configs: Vec<cpal::SupportedStreamConfigRange> = match &output {
+        Some(device) => device
+            .supported_output_configs()
+            .map_err(cpal_error)?
+            .collect(),
+        None => Vec::new(),
+    };
+    let input_configs: Vec<cpal::SupportedStreamConfigRange> = match &input {
+        Some(device) => device
+            .supported_input_configs()
+            .map_err(cpal_error)?
+            .collect(),
+        None => Vec::new(),
+    };
+
+    // Map every bus channel to its device channel.
+
+    let (audio_in_busses, in_channel_map) = map_busses(
+        &config.audio_in_busses,
+        device_name,
+        CAPTURE_PORT_PREFIX,
+        max_channels(&input_configs),
+        true,
+    )?;
+    let (audio_out_busses, out_channel_map) = map_busses(
+        &config.audio_out_busses,
+        device_name,
+        PLAYBACK_PORT_PREFIX,
+        max_channels(&output_configs),
+        false,
+    )?;
+
+    // Keep the sample rate the device is set to, unless the config asks for another one.
+    let default_config = match (&output, &input) {
+        (Some(output), _) => output.default_output_config().ok(),
+        (None, Some(input)) => input.default_input_config().ok(),
+        (None, None) => None,
+    };
+    let sample_rate = config
+        .sample_rate
+        .or_else(|| default_config.map(|c| c.sample_rate().0))
+        .unwrap_or(DEFAULT_SAMPLE_RATE);
+
+    let unsupported = || {
+        SpawnRtThreadError::PlatformSpecific(Box::new(CpalConfigUnsupported {
+            device: device_name.clone(),
+            sample_rate,
+        }))
+    };
+    let output_config = match &output {
+        Some(_) => Some(
+            choose_config(&output_configs, min_channels(&out_channel_map), sample_rate)
+                .ok_or_else(unsupported)?,
+        ),
+        None => None,
+    };
+    let input_config = match &input {
+        Some(_) => Some(
+            choose_config(&input_configs, min_channels(&in_channel_map), sample_rate)
+                .ok_or_else(unsupported)?,
+        ),
+        None => None,
+    };
+
+    let buffer_size_ranges: Vec<BufferSizeRange> = output_config
+        .iter()
+        .chain(input_config.iter())
+        .filter_map(|c| known_buffer_size_range(c.buffer_size()))
+        .collect();
+    let (buffer_size, max_audio_buffer_size) =
+        choose_buffer_size(config.buffer_size, &buffer_size_ranges)?;
+
+    let sample_format = match output_config.as_ref().or(input_config.as_ref()) {
+        Some(c) => match c.sample_format() {
+            cpal::SampleFormat::F32 => SampleFormat::F32,
+            // Unsigned samples are converted like signed ones, offset by half their range.
+            cpal::SampleFormat::I16 | cpal::SampleFormat::U16 => SampleFormat::I16,
+        },
+        None => SampleFormat::F32,
+    };
+
+    let device_stream = |device: Option<cpal::Device>,
+                         config: Option<cpal::SupportedStreamConfig>| {
+        device.zip(config).map(|(device, config)| DeviceStream {
+            device,
+            config: cpal::StreamConfig {
+                channels: config.channels(),
+                sample_rate: cpal::SampleRate(sample_rate),
+                buffer_size: buffer_size.clone(),
+            },
+            format: config.sample_format(),
+        })
+    };
+    let output = device_stream(output, output_config);
+    let input = device_stream(input, input_config);
+
+    let stream_info = StreamInfo {
+        server_name: String::from(Backend::Cpal.as_str()),
+        audio_in: audio_in_busses,
+        audio_out: audio_out_busses,
+        midi_in: Vec::new(),
+        midi_out: Vec::new(),
+        sample_rate,
+        max_audio_buffer_size,
+        // CPAL doesn't report the latency of the device, so this is only the buffer that
+        // is known about.
+        latency_frames_in: if input.is_some() {
+            max_audio_buffer_size
+        } else {
+            0
+        },
+        latency_frames_out: if output.is_some() {
+            max_audio_buffer_size
+        } else {
+            0
+        },
+        sample_format,
+    };
+
+    Ok(OpenDevices {
+        stream_info,
+        in_channels: input
+            .as_ref()
+            .map(|i| i.config.channels as usize)
+            .unwrap_or(0),
+        out_channels: output
+            .as_ref()
+            .map(|o| o.config.channels as usize)
+            .unwrap_or(0),
+        input,
+        output,
+        in_channel_map,
+        out_channel_map,
+    })
+}
+
+fn find_device<I: Iterator<Item = cpal::Device>>(
+    devices: Result<I, cpal::DevicesError>,
+    name: &str,
+) -> Option<cpal::Device> {
+    devices
+        .ok()?
+        .find(|device| device.name().map(|n| n == name).unwrap_or(false))
+}
+
+/// Create the busses for a direction and route each of their channels to device channels.
+fn map_busses(
+    busses: &[AudioBusConfig],
+    device: &str,
+    port_prefix: &str,
+    device_channels: u16,
+    is_input: bool,
+) -> Result<(Vec<AudioBus>, ChannelMap), SpawnRtThreadError> {
+    let mut audio_busses = Vec::<AudioBus>::new();
+    let mut channel_map = ChannelMap::new();
+
+    for (bus_i, bus) in busses.iter().enumerate() {
+        if bus.system_ports.is_empty() {
+            return Err(SpawnRtThreadError::NoSystemPortsGiven(bus.id.clone()));
+        }
+
+        let mut channels = Vec::<usize>::new();
+        for system_port in bus.system_ports.iter() {
+            let channel = parse_port_channel(system_port, port_prefix)
+                .filter(|channel| *channel < usize::from(device_channels))
+                .ok_or_else(|| {
+                    SpawnRtThreadError::SystemPortNotFound(system_port.clone(), bus.id.clone())
+                })?;
+
+            channels.push(channel);
+        }
+
+        let channel_layout = bus.channel_layout();
+        let num_channels = channel_layout.num_channels() as u16;
+        audio_busses.push(AudioBus {
+            id_name: bus.id.clone(),
+            id_index: DeviceIndex::new(bus_i),
+            system_device: String::from(device),
+            system_half_duplex_device: None,
+            system_ports: bus.system_ports.clone(),
+            client_ports: Vec::new(),
+            channels: num_channels,
+            channel_layout,
+            connected_ports: num_channels,
+        });
+        channel_map.push(channel_routes(bus, &channels, is_input));
+    }
+
+    Ok((audio_busses, channel_map))
+}
+
+fn max_channels(configs: &[cpal::SupportedStreamConfigRange]) -> u16 {
+    configs.iter().map(|c| c.channels()).max().unwrap_or(0)
+}
+
+/// The minimum number of device channels needed to reach every channel in the map.
+fn min_channels(channel_map: &[Vec<ChannelRoute<usize>>]) -> u16 {
+    channel_map
+        .iter()
+        .flatten()
+        .flat_map(|route| route.ports.iter())
+        .map(|channel| *channel as u16 + 1)
+        .max()
+        .unwrap_or(1)
+}
+
+/// Pick the config with the fewest channels that still reaches every mapped channel at
+/// `sample_rate`, in the most precise sample format.
+fn choose_config(
+    configs: &[cpal::SupportedStreamConfigRange],
+    min_channels: u16,
+    sample_rate: u32,
+) -> Option<cpal::SupportedStreamConfig> {
+    configs
+        .iter()
+        .filter(|c| {
+            c.channels() >= min_channels
+                && c.min_sample_rate().0 <= sample_rate
+                && sample_rate <= c.max_sample_rate().0
+        })
+        .min_by_key(|c| {
+            let format_rank = match c.sample_format() {
+                cpal::SampleFormat::F32 => 0,
+                cpal::SampleFormat::I16 => 1,
+                cpal::SampleFormat::U16 => 2,
+            };
+            (c.channels(), format_rank)
+        })
+        .map(|c| c.clone().with_sample_rate(cpal::SampleRate(sample_rate)))
+}
+
+fn known_buffer_size_range(buffer_size: &cpal::SupportedBufferSize) -> Option<BufferSizeRange> {
+    match buffer_size {
+        cpal::SupportedBufferSize::Range { min, max } => Some(BufferSizeRange {
+            min: (*min).max(1),
+            max: *max,
+        }),
+        cpal::SupportedBufferSize::Unknown => None,
+    }
+}
+
+/// Pick the buffer size to open the streams with, and the most frames a cycle can have.
+///
+/// * `ranges` - The buffer sizes of each direction, if the host knows them.
+fn choose_buffer_size(
+    requested: Option<u32>,
+    ranges: &[BufferSizeRange],
+) -> Result<(cpal::BufferSize, u32), SpawnRtThreadError> {
+    // If the directions have no size in common, the output's range is used and opening the
+    // input reports the error.
+    let range = ranges
+        .iter()
+        .copied()
+        .reduce(|a, b| a.intersection(&b).unwrap_or(a));
+
+    match (requested, range) {
+        (Some(requested), Some(range)) if !range.contains(requested) => {
+            Err(SpawnRtThreadError::UnsupportedBufferSize { requested, range })
+        }
+        (Some(requested), _) => Ok((cpal::BufferSize::Fixed(requested), requested.max(1))),
+        (None, Some(range)) => {
+            let size = DEFAULT_BUFFER_SIZE.clamp(range.min, range.max);
+            Ok((cpal::BufferSize::Fixed(size), size))
+        }
+        // The host picks the size, and callbacks with more frames are split into cycles.
+        (None, None) => Ok((cpal::BufferSize::Default, DEFAULT_BUFFER_SIZE)),
+    }
+}
+
+type ErrorCallback = Box<dyn FnMut(cpal::StreamError) + Send + 'static>;
+
+/// Stop the stream once its device goes away. Other errors are only logged.
+fn stream_error_callback<E: FatalErrorHandler>(
+    shutdown: Arc<Shutdown<E>>,
+    device: String,
+) -> ErrorCallback {
+    Box::new(move |e| match e {
+        cpal::StreamError::DeviceNotAvailable => {
+            shutdown.stop(Some(FatalStreamError::AudioDeviceDisconnected {
+                backend: Backend::Cpal,
+                reason: DisconnectReason::DeviceRemoved,
+                message: format!("CPAL: device {} is no longer available", &device),
+            }))
+        }
+        cpal::StreamError::BackendSpecific { err } => {
+            warn!("CPAL: error in the stream on device {}: {}", &device, err);
+        }
+    })
+}
+
+fn build_output_stream<P: RtProcessHandler, E: FatalErrorHandler>(
+    stream: &DeviceStream,
+    process: CpalProcess<P, E>,
+    on_error: ErrorCallback,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    fn build<S: CpalSample, P: RtProcessHandler, E: FatalErrorHandler>(
+        stream: &DeviceStream,
+        mut process: CpalProcess<P, E>,
+        on_error: ErrorCallback,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError> {
+        stream.device.build_output_stream(
+            &stream.config,
+            move |data: &mut [S], _: &cpal::OutputCallbackInfo| process.write_output(data),
+            on_error,
+        )
+    }
+
+    match stream.format {
+        cpal::SampleFormat::F32 => build::<f32, P, E>(stream, process, on_error),
+        cpal::SampleFormat::I16 => build::<i16, P, E>(stream, process, on_error),
+        cpal::SampleFormat::U16 => build::<u16, P, E>(stream, process, on_error),
+    }
+}
+
+/// The input stream of a stream without outputs, which runs the process itself.
+fn build_input_stream<P: RtProcessHandler, E: FatalErrorHandler>(
+    stream: &DeviceStream,
+    process: CpalProcess<P, E>,
+    on_error: ErrorCallback,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    fn build<S: CpalSample, P: RtProcessHandler, E: FatalErrorHandler>(
+        stream: &DeviceStream,
+        mut process: CpalProcess<P, E>,
+        on_error: ErrorCallback,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError> {
+        stream.device.build_input_stream(
+            &stream.config,
+            move |data: &[S], _: &cpal::InputCallbackInfo| process.read_input(data),
+            on_error,
+        )
+    }
+
+    match stream.format {
+        cpal::SampleFormat::F32 => build::<f32, P, E>(stream, process, on_error),
+        cpal::SampleFormat::I16 => build::<i16, P, E>(stream, process, on_error),
+        cpal::SampleFormat::U16 => build::<u16, P, E>(stream, process, on_error),
+    }
+}
+
+/// The input stream of a duplex stream, which queues the captured samples for the output
+/// stream.
+fn build_capture_stream(
+    stream: &DeviceStream,
+    producer: Producer<f32>,
+    counters: Arc<RingBufferCounters>,
+    xruns: Arc<Xruns>,
+    on_error: ErrorCallback,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    fn build<S: CpalSample>(
+        stream: &DeviceStream,
+        mut producer: Producer<f32>,
+        counters: Arc<RingBufferCounters>,
+        xruns: Arc<Xruns>,
+        on_error: ErrorCallback,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError> {
+        stream.device.build_input_stream(
+            &stream.config,
+            move |data: &[S], _: &cpal::InputCallbackInfo| {
+                // If the fifo is full the newest samples are dropped.
+                let samples = data.iter().map(|s| Sample::to_f32(*s));
+                if counters.push_iter(&mut producer, samples) < data.len() {
+                    xruns.xrun();
+                }
+            },
+            on_error,
+        )
+    }
+
+    match stream.format {
+        cpal::SampleFormat::F32 => build::<f32>(stream, producer, counters, xruns, on_error),
+        cpal::SampleFormat::I16 => build::<i16>(stream, producer, counters, xruns, on_error),
+        cpal::SampleFormat::U16 => build::<u16>(stream, producer, counters, xruns, on_error),
+    }
+}
+
+fn cpal_error<T: std::error::Error + Send + 'static>(e: T) -> SpawnRtThreadError {
+    SpawnRtThreadError::PlatformSpecific(Box::new(e))
+}
+
+/// Stops the stream from one of the callbacks.
+struct Shutdown<E: FatalErrorHandler> {
+    running: Arc<AtomicBool>,
+    shutdown_hook: Arc<ShutdownHook>,
+    fatal_error_handler: Mutex<Option<E>>,
+
+    /// Wakes the audio thread to close the streams.
+    close_tx: Mutex<Option<mpsc::Sender<()>>>,
+}
+
+impl<E: FatalErrorHandler> Shutdown<E> {
+    /// Stop the stream, reporting `error` if it failed. This does nothing if the stream has
+    /// already stopped.
+    fn stop(&self, error: Option<FatalStreamError>) {
+        if !self.running.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        self.shutdown_hook.run();
+
+        if let Some(error) = error {
+            info!("{}", error);
+
+            if let Some(fatal_error_handler) = self.fatal_error_handler.lock().unwrap().take() {
+                fatal_error_handler.fatal_stream_error(error)
+            }
+        }
+
+        if let Some(close_tx) = self.close_tx.lock().unwrap().take() {
+            let _ = close_tx.send(());
+        }
+    }
+}
+
+struct CpalProcess<P: RtProcessHandler, E: FatalErrorHandler> {
+    /// This is only taken when the process is dropped.
+    rt_process_handler: Option<P>,
+    handler_tx: mpsc::Sender<P>,
+    shutdown: Arc<Shutdown<E>>,
+
+    audio_in_buffers: Vec<AudioBusBuffer>,
+    audio_out_buffers: Vec<AudioBusBuffer>,
+
+    in_channels: usize,
+    out_channels: usize,
+
+    in_channel_map: Vec<Vec<ChannelRoute<usize>>>,
+    out_channel_map: Vec<Vec<ChannelRoute<usize>>>,
+
+    /// The samples the input stream captured, if the output stream runs the process.
+    capture_fifo: Option<Consumer<f32>>,
+    capture_fifo_counters: Arc<RingBufferCounters>,
+
+    /// The interleaved samples of the current cycle.
+    in_raw: Vec<f32>,
+    out_mix: Vec<f32>,
+    dither: Dither,
+
+    stream_info: StreamInfo,
+
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_debugger: CycleDebugger,
+    master_gain: MasterGain,
+    channel_gains: ChannelGains,
+    silence_detector: SilenceDetector,
+    flush_denormals: bool,
+    xruns: Arc<Xruns>,
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> CpalProcess<P, E> {
+    /// The data callback of the output stream. Callbacks with more frames than the max
+    /// audio buffer size are split into several cycles.
+    fn write_output<S: CpalSample>(&mut self, data: &mut [S]) {
+        let max_frames = self.stream_info.max_audio_buffer_size as usize;
+
+        for chunk in data.chunks_mut(max_frames * self.out_channels) {
+            let frames = chunk.len() / self.out_channels;
+
+            if self.shutdown.running.load(Ordering::Relaxed) {
+                if let Some(fifo) = &mut self.capture_fifo {
+                    // If not enough samples were captured the rest of the cycle is silent.
+                    let samples = frames * self.in_channels;
+                    let read = self
+                        .capture_fifo_counters
+                        .pop_slice(fifo, &mut self.in_raw[0..samples]);
+                    if read < samples {
+                        for s in self.in_raw[read..samples].iter_mut() {
+                            *s = 0.0;
+                        }
+                        self.xruns.xrun();
+                    }
+                }
+
+                self.cycle(frames);
+            } else {
+                for s in self.out_mix.iter_mut() {
+                    *s = 0.0;
+                }
+            }
+
+            for (dst, src) in chunk.iter_mut().zip(self.out_mix.iter()) {
+                *dst = S::from_f32(*src, &mut self.dither);
+            }
+        }
+    }
+
+    /// The data callback of the input stream of a stream without outputs.
+    fn read_input<S: CpalSample>(&mut self, data: &[S]) {
+        let max_frames = self.stream_info.max_audio_buffer_size as usize;
+
+        for chunk in data.chunks(max_frames * self.in_channels) {
+            if !self.shutdown.running.load(Ordering::Relaxed) {
+                return;
+            }
+
+            for (dst, src) in self.in_raw.iter_mut().zip(chunk.iter()) {
+                *dst = Sample::to_f32(*src);
+            }
+
+            self.cycle(chunk.len() / self.in_channels);
+        }
+    }
+
+    /// Process one cycle from `in_raw` into `out_mix`.
+    fn cycle(&mut self, audio_frames: usize) {
+        let cycle_start_time = Instant::now();
+        let sample_rate = self.stream_info.sample_rate;
+
+        // Collect Audio Inputs
+
+        if self.in_channels > 0 {
+            deinterleave(
+                &self.in_raw,
+                self.in_channels,
+                audio_frames,
+                &self.in_channel_map,
+                &mut self.audio_in_buffers,
+            );
+            self.channel_gains
+                .apply_inputs(&mut self.audio_in_buffers, audio_frames, sample_rate);
+
+            for audio_buffer in self.audio_in_buffers.iter_mut() {
+                audio_buffer.update_peaks();
+            }
+            self.silence_detector
+                .process(&self.audio_in_buffers, audio_frames, sample_rate);
+        }
+
+        // Clear Audio Outputs
+
+        for audio_buffer in self.audio_out_buffers.iter_mut() {
+            audio_buffer.clear_and_resize(audio_frames);
+        }
+
+        let rt_process_handler = match self.rt_process_handler.as_mut() {
+            Some(rt_process_handler) => rt_process_handler,
+            None => return,
+        };
+        let audio_in_buffers = &self.audio_in_buffers;
+        let audio_out_buffers = &mut self.audio_out_buffers;
+        let flush_denormals = self.flush_denormals;
+        let cycle_start_frame = self.frames_processed.load(Ordering::Relaxed);
+
+        let result = catch_process_panic(|| {
+            let _denormals = DenormalGuard::new(flush_denormals);
+            rt_process_handler.process(ProcessInfo {
+                audio_in: audio_in_buffers.as_slice(),
+                audio_out: audio_out_buffers.as_mut_slice(),
+                audio_frames,
+
+                midi_in: &[],
+                midi_out: &mut [],
+
+                sample_rate,
+                transport: None,
+                cycle_start_frame,
+                cycle_start_time,
+            })
+        });
+        let control = match result {
+            Ok(control) => control,
+            Err(error) => {
+                for s in self.out_mix.iter_mut() {
+                    *s = 0.0;
+                }
+                self.shutdown.stop(Some(error));
+                return;
+            }
+        };
+
+        self.master_gain
+            .apply(&mut self.audio_out_buffers, audio_frames, sample_rate);
+        self.channel_gains
+            .apply_outputs(&mut self.audio_out_buffers, audio_frames, sample_rate);
+
+        // Copy processed data to Audio Outputs
+
+        if self.out_channels > 0 {
+            interleave(
+                &self.audio_out_buffers,
+                &self.out_channel_map,
+                self.out_channels,
+                audio_frames,
+                &mut self.out_mix,
+            );
+        }
+
+        let frames_processed = self
+            .frames_processed
+            .fetch_add(audio_frames as u64, Ordering::Relaxed);
+        self.buffer_size_history.push(audio_frames);
+
+        if self.cycle_debugger.begin_cycle() {
+            self.cycle_debugger.log(CycleDebugInfo {
+                frames_processed,
+                audio_frames,
+                audio_in_channels: self.in_channels,
+                audio_out_channels: self.out_channels,
+                midi_in_events: 0,
+                midi_out_events: 0,
+            });
+        }
+
+        if control == ProcessControl::Quit {
+            info!("CPAL: the process handler stopped the stream");
+            self.shutdown.stop(None);
+        }
+    }
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> Drop for CpalProcess<P, E> {
+    /// CPAL drops the callback (and with it the process) when its stream is closed, so this
+    /// is where the handler is given back to the audio thread.
+    fn drop(&mut self) {
+        if let Some(rt_process_handler) = self.rt_process_handler.take() {
+            let _ = self.handler_tx.send(rt_process_handler);
+        }
+    }
+}
+
+/// A sample format that CPAL can read/write to.
+trait CpalSample: Sample + cpal::Sample {}
+
+impl<S: Sample + cpal::Sample> CpalSample for S {}
+
+/// Copy an interleaved device buffer into the channels of each bus. Channels that are routed
+/// from more than one device channel get their sum.
+fn deinterleave(
+    interleaved: &[f32],
+    device_channels: usize,
+    frames: usize,
+    channel_map: &[Vec<ChannelRoute<usize>>],
+    busses: &mut [AudioBusBuffer],
+) {
+    for (audio_buffer, routes) in busses.iter_mut().zip(channel_map.iter()) {
+        for (channel, route) in audio_buffer.channel_buffers.iter_mut().zip(routes.iter()) {
+            // This should never allocate because each buffer was given a capacity of
+            // the max audio buffer size.
+            channel.clear();
+            channel.resize(frames, 0.0);
+
+            for device_channel in route.ports.iter() {
+                for (dst, src) in channel.iter_mut().zip(
+                    interleaved
+                        .iter()
+                        .skip(*device_channel)
+                        .step_by(device_channels),
+                ) {
+                    *dst += *src * route.gain;
+                }
+            }
+        }
+
+        audio_buffer.frames = frames;
+    }
+}
+
+/// Mix the channels of each bus into an interleaved device buffer. Device channels that more
+/// than one channel is routed to are summed.
+fn interleave(
+    busses: &[AudioBusBuffer],
+    channel_map: &[Vec<ChannelRoute<usize>>],
+    device_channels: usize,
+    frames: usize,
+    interleaved: &mut [f32],
+) {
+    for s in interleaved.iter_mut() {
+        *s = 0.0;
+    }
+
+    for (audio_buffer, routes) in busses.iter().zip(channel_map.iter()) {
+        for (channel, route) in audio_buffer.channel_buffers.iter().zip(routes.iter()) {
+            // Just in case the user resized the output buffer for some reason.
+            let len = channel.len().min(frames);
+            if len != frames {
+                warn!(
+                    "Warning: An audio output buffer was resized from {} to {} by the user",
+                    frames, len
+                );
+            }
+
+            for device_channel in route.ports.iter() {
+                for (dst, src) in interleaved
+                    .iter_mut()
+                    .skip(*device_channel)
+                    .step_by(device_channels)
+                    .zip(channel[0..len].iter())
+                {
+                    *dst += *src * route.gain;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CpalConfigUnsupported {
+    device: String,
+    sample_rate: u32,
+}
+
+impl std::error::Error for CpalConfigUnsupported {}
+
+impl std::fmt::Display for CpalConfigUnsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The CPAL device {} can't be opened at {} Hz with enough channels for every port",
+            &self.device, self.sample_rate
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(min: u32, max: u32) -> BufferSizeRange {
+        BufferSizeRange { min, max }
+    }
+
+    #[test]
+    fn parse_ports() {
+        assert_eq!(
+            parse_port_channel("playback_2", PLAYBACK_PORT_PREFIX),
+            Some(1)
+        );
+        assert_eq!(parse_port_channel("playback_0", PLAYBACK_PORT_PREFIX), None);
+        assert_eq!(parse_port_channel("capture_1", PLAYBACK_PORT_PREFIX), None);
+    }
+
+    #[test]
+    fn summarizes_config_ranges() {
+        let configs = [
+            ConfigRange {
+                channels: 2,
+                min_sample_rate: 44100,
+                max_sample_rate: 48000,
+                buffer_size_range: Some(range(64, 1024)),
+            },
+            ConfigRange {
+                channels: 8,
+                min_sample_rate: 96000,
+                max_sample_rate: 96000,
+                buffer_size_range: Some(range(32, 512)),
+            },
+        ];
+
+        assert_eq!(
+            summarize_configs(&configs),
+            Some(DeviceConfigs {
+                max_channels: 8,
+                sample_rates: vec![44100, 48000, 96000],
+                buffer_size_range: range(32, 1024),
+            })
+        );
+        assert_eq!(summarize_configs(&[]), None);
+
+        let unknown = [ConfigRange {
+            channels: 1,
+            min_sample_rate: 8000,
+            max_sample_rate: 384000,
+            buffer_size_range: None,
+        }];
+        let summary = summarize_configs(&unknown).unwrap();
+        assert_eq!(summary.sample_rates, PROBE_SAMPLE_RATES.to_vec());
+        assert_eq!(summary.buffer_size_range, UNKNOWN_BUFFER_SIZE_RANGE);
+    }
+
+    #[test]
+    fn chooses_buffer_size() {
+        assert!(matches!(
+            choose_buffer_size(Some(256), &[range(64, 1024)]),
+            Ok((cpal::BufferSize::Fixed(256), 256))
+        ));
+        assert!(matches!(
+            choose_buffer_size(None, &[range(1024, 4096)]),
+            Ok((cpal::BufferSize::Fixed(1024), 1024))
+        ));
+        assert!(matches!(
+            choose_buffer_size(None, &[]),
+            Ok((cpal::BufferSize::Default, DEFAULT_BUFFER_SIZE))
+        ));
+
+        // Both directions have to support the requested size.
+        assert!(matches!(
+            choose_buffer_size(Some(1024), &[range(64, 2048), range(64, 512)]),
+            Err(SpawnRtThreadError::UnsupportedBufferSize {
+                requested: 1024,
+                range: BufferSizeRange { min: 64, max: 512 },
+            })
+        ));
+    }
+
+    #[test]
+    fn deinterleave_and_interleave_route_channels() {
+        let route = |channel: usize| ChannelRoute {
+            ports: vec![channel],
+            gain: 1.0,
+        };
+
+        // Two device channels, two frames, swapped into a stereo bus.
+        let interleaved = [0.25, 0.5, -0.25, -0.5];
+        let channel_map = vec![vec![route(1), route(0)]];
+        let mut busses = vec![AudioBusBuffer::new(2, 2)];
+
+        deinterleave(&interleaved, 2, 2, &channel_map, &mut busses);
+        assert_eq!(&busses[0][0], &[0.5, -0.5]);
+        assert_eq!(&busses[0][1], &[0.25, -0.25]);
+
+        let mut out = [1.0; 4];
+        interleave(&busses, &channel_map, 2, 2, &mut out);
+        assert_eq!(out, interleaved);
+    }
+}
@@ -0,0 +1,212 @@
+use log::debug;
+
+use crate::diagnostics::{RingBufferCounters, RingBufferStats};
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The maximum number of cycle reports that can be queued before the flush thread gets to them.
+const DEBUG_QUEUE_SIZE: usize = 1024;
+
+/// How often the flush thread checks for new cycle reports.
+const FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Diagnostics for a single process cycle.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct CycleDebugInfo {
+    /// The total number of frames processed before this cycle.
+    pub frames_processed: u64,
+    pub audio_frames: usize,
+    pub audio_in_channels: usize,
+    pub audio_out_channels: usize,
+    pub midi_in_events: usize,
+    pub midi_out_events: usize,
+}
+
+impl std::fmt::Display for CycleDebugInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cycle at frame {}: {} frames, {} in channels, {} out channels, {} midi in events, {} midi out events",
+            self.frames_processed,
+            self.audio_frames,
+            self.audio_in_channels,
+            self.audio_out_channels,
+            self.midi_in_events,
+            self.midi_out_events,
+        )
+    }
+}
+
+/// Creates the realtime and non-realtime halves of a cycle debugger.
+pub(crate) fn cycle_debugger() -> (CycleDebugger, CycleDebugHandle) {
+    let (producer, consumer) = RingBuffer::<CycleDebugInfo>::new(DEBUG_QUEUE_SIZE).split();
+    let remaining = Arc::new(AtomicU32::new(0));
+    let queue_counters = Arc::new(RingBufferCounters::default());
+
+    (
+        CycleDebugger {
+            remaining: Arc::clone(&remaining),
+            producer,
+            queue_counters: Arc::clone(&queue_counters),
+        },
+        CycleDebugHandle {
+            remaining,
+            queue_counters,
+            consumer: Arc::new(Mutex::new(consumer)),
+            flushing: Arc::new(AtomicBool::new(false)),
+        },
+    )
+}
+
+/// The realtime half of the cycle debugger. This never allocates or blocks.
+pub(crate) struct CycleDebugger {
+    remaining: Arc<AtomicU32>,
+    producer: Producer<CycleDebugInfo>,
+    queue_counters: Arc<RingBufferCounters>,
+}
+
+impl CycleDebugger {
+    /// Returns `true` if the current cycle should be logged, and counts it
+    /// against the number of remaining cycles.
+    pub fn begin_cycle(&self) -> bool {
+        self.remaining
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Queue the diagnostics of the current cycle to be logged by the flush thread.
+    ///
+    /// If the queue is full then the report is dropped and counted as an overrun.
+    pub fn log(&mut self, info: CycleDebugInfo) {
+        if self.producer.push(info).is_err() {
+            self.queue_counters.overrun();
+        }
+    }
+}
+
+/// The non-realtime half of the cycle debugger.
+pub(crate) struct CycleDebugHandle {
+    remaining: Arc<AtomicU32>,
+    consumer: Arc<Mutex<Consumer<CycleDebugInfo>>>,
+    flushing: Arc<AtomicBool>,
+    queue_counters: Arc<RingBufferCounters>,
+}
+
+impl CycleDebugHandle {
+    /// Log detailed diagnostics for the next `n` process cycles. Setting this to `0`
+    /// stops any logging that is in progress.
+    ///
+    /// The diagnostics are flushed to the `debug` log level on a separate thread.
+    pub fn debug_next_cycles(&self, n: u32) {
+        self.remaining.store(n, Ordering::Release);
+
+        if n == 0 || self.flushing.swap(true, Ordering::AcqRel) {
+            // A flush thread is already running.
+            return;
+        }
+
+        let remaining = Arc::clone(&self.remaining);
+        let consumer = Arc::clone(&self.consumer);
+        let flushing = Arc::clone(&self.flushing);
+
+        std::thread::spawn(move || {
+            let mut consumer = match consumer.lock() {
+                Ok(c) => c,
+                Err(_) => return,
+            };
+
+            loop {
+                while let Some(info) = consumer.pop() {
+                    debug!("{}", info);
+                }
+
+                if remaining.load(Ordering::Acquire) == 0 {
+                    // Flush any reports pushed after the last check.
+                    while let Some(info) = consumer.pop() {
+                        debug!("{}", info);
+                    }
+
+                    flushing.store(false, Ordering::Release);
+
+                    // Keep running if more cycles were requested in the meantime.
+                    if remaining.load(Ordering::Acquire) == 0
+                        || flushing.swap(true, Ordering::AcqRel)
+                    {
+                        break;
+                    }
+                }
+
+                std::thread::sleep(FLUSH_INTERVAL);
+            }
+        });
+    }
+
+    /// The under/overrun counters of the queue the reports are passed through.
+    pub fn queue_stats(&self) -> RingBufferStats {
+        self.queue_counters.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_cycles_decrement_and_stop() {
+        let (debugger, handle) = cycle_debugger();
+
+        assert!(!debugger.begin_cycle());
+
+        handle.debug_next_cycles(2);
+        assert!(debugger.begin_cycle());
+        assert_eq!(handle.remaining.load(Ordering::Acquire), 1);
+        assert!(debugger.begin_cycle());
+        assert_eq!(handle.remaining.load(Ordering::Acquire), 0);
+
+        // Logging stops automatically.
+        assert!(!debugger.begin_cycle());
+        assert_eq!(handle.remaining.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn debug_cycles_are_flushed() {
+        let (mut debugger, handle) = cycle_debugger();
+
+        handle.debug_next_cycles(3);
+        while debugger.begin_cycle() {
+            debugger.log(CycleDebugInfo::default());
+        }
+
+        // The flush thread should drain the queue and exit.
+        for _ in 0..100 {
+            if !handle.flushing.load(Ordering::Acquire) {
+                break;
+            }
+            std::thread::sleep(FLUSH_INTERVAL);
+        }
+        assert!(!handle.flushing.load(Ordering::Acquire));
+        assert!(handle.consumer.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn debug_queue_overrun_is_counted() {
+        let (mut debugger, handle) = cycle_debugger();
+
+        // Don't start the flush thread so the queue fills up.
+        handle
+            .remaining
+            .store(DEBUG_QUEUE_SIZE as u32 + 2, Ordering::Release);
+        while debugger.begin_cycle() {
+            debugger.log(CycleDebugInfo::default());
+        }
+
+        assert_eq!(
+            handle.queue_stats(),
+            RingBufferStats {
+                overruns: 2,
+                underruns: 0
+            }
+        );
+    }
+}
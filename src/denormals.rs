@@ -0,0 +1,111 @@
+/// Treats denormal floats as zero on the current thread until it's dropped, then restores
+/// the previous floating point mode. The backends hold one while `RtProcessHandler::process`
+/// runs, unless `Config::allow_denormals` is set, since denormals in decaying feedback
+/// loops (reverb tails, filters) can be many times slower to compute on x86.
+pub(crate) struct DenormalGuard {
+    /// The mode to restore, if it was changed.
+    previous: Option<u32>,
+}
+
+impl DenormalGuard {
+    /// Flush denormals to zero if `flush` is set, and otherwise leave the mode as it is.
+    pub(crate) fn new(flush: bool) -> Self {
+        let previous = if flush {
+            mxcsr::flush_denormals()
+        } else {
+            None
+        };
+
+        Self { previous }
+    }
+}
+
+impl Drop for DenormalGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            mxcsr::restore(previous);
+        }
+    }
+}
+
+/// The FTZ and DAZ flags of SSE, which is what the `f32` math of every x86 target with SSE
+/// uses.
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse"
+))]
+mod mxcsr {
+    /// The flush-to-zero and denormals-are-zero bits of the MXCSR register.
+    const FTZ_DAZ: u32 = 0x8040;
+
+    /// Set FTZ and DAZ, returning the previous MXCSR if that changed it.
+    pub(super) fn flush_denormals() -> Option<u32> {
+        let previous = get();
+        if previous & FTZ_DAZ == FTZ_DAZ {
+            return None;
+        }
+
+        set(previous | FTZ_DAZ);
+        Some(previous)
+    }
+
+    pub(super) fn restore(previous: u32) {
+        set(previous);
+    }
+
+    fn get() -> u32 {
+        let mut mxcsr = 0u32;
+        unsafe {
+            std::arch::asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags));
+        }
+        mxcsr
+    }
+
+    fn set(mxcsr: u32) {
+        unsafe {
+            std::arch::asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack, readonly, preserves_flags));
+        }
+    }
+}
+
+/// Other targets either flush denormals already (e.g. ARMv7 NEON) or are left as they are.
+#[cfg(not(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse"
+)))]
+mod mxcsr {
+    pub(super) fn flush_denormals() -> Option<u32> {
+        None
+    }
+
+    pub(super) fn restore(_previous: u32) {}
+}
+
+#[cfg(all(
+    test,
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse"
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flushes_denormals_until_dropped() {
+        let denormal = std::hint::black_box(f32::MIN_POSITIVE);
+        let halve = |x: f32| std::hint::black_box(x) / 2.0;
+        assert!(halve(denormal) > 0.0);
+
+        {
+            let _guard = DenormalGuard::new(true);
+            assert_eq!(halve(denormal), 0.0);
+
+            // Nesting leaves the mode to the outer guard.
+            drop(DenormalGuard::new(true));
+            assert_eq!(halve(denormal), 0.0);
+        }
+        assert!(halve(denormal) > 0.0);
+
+        let _guard = DenormalGuard::new(false);
+        assert!(halve(denormal) > 0.0);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::AudioDeviceInfo;
+
+/// Suffixes that bridge clients commonly use to tell the capture and playback halves of the
+/// same device apart (e.g. "usb_in" and "usb_out" from `zita-a2j`/`zita-j2a`).
+static DIRECTION_SUFFIXES: [&str; 10] = [
+    "_in",
+    "_out",
+    " in",
+    " out",
+    "-in",
+    "-out",
+    "_capture",
+    "_playback",
+    " capture",
+    " playback",
+];
+
+/// A group of system ports that (heuristically) originate from the same physical device.
+///
+/// On Jack every port belongs to one "device", but the underlying sound cards can usually be
+/// told apart by the client prefix of the port names.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceGroup {
+    /// The display name of this group, e.g. "system" or "USB Interface".
+    pub name: String,
+
+    /// The name of the `AudioDeviceInfo` these ports belong to.
+    pub device: String,
+
+    pub in_ports: Vec<String>,
+    pub out_ports: Vec<String>,
+}
+
+/// Cluster the ports of a device into groups by their client prefix.
+///
+/// Ports without a prefix belong to a group with the name of the device itself. Groups are
+/// returned in the order their first port appears.
+pub(crate) fn group_device_ports(device: &AudioDeviceInfo) -> Vec<DeviceGroup> {
+    let mut groups = Vec::<DeviceGroup>::new();
+
+    for (port, is_input) in device
+        .in_ports
+        .iter()
+        .map(|p| (p, true))
+        .chain(device.out_ports.iter().map(|p| (p, false)))
+    {
+        let name = group_name(port).unwrap_or(&device.name);
+
+        let group = match groups.iter().position(|g| g.name == name) {
+            Some(i) => &mut groups[i],
+            None => {
+                groups.push(DeviceGroup {
+                    name: String::from(name),
+                    device: device.name.clone(),
+                    in_ports: Vec::new(),
+                    out_ports: Vec::new(),
+                });
+                groups.last_mut().unwrap()
+            }
+        };
+
+        if is_input {
+            group.in_ports.push(port.clone());
+        } else {
+            group.out_ports.push(port.clone());
+        }
+    }
+
+    groups
+}
+
+/// The name of the group a port belongs to, or `None` if the port has no client prefix.
+fn group_name(port: &str) -> Option<&str> {
+    let (client, _) = port.split_once(':')?;
+    let client = client.trim();
+
+    if client.is_empty() {
+        return None;
+    }
+
+    // Merge the capture and playback halves of a bridged device.
+    for suffix in DIRECTION_SUFFIXES.iter() {
+        if client.len() > suffix.len() {
+            let split = client.len() - suffix.len();
+
+            if client.is_char_boundary(split) && client[split..].eq_ignore_ascii_case(suffix) {
+                return Some(client[..split].trim_end());
+            }
+        }
+    }
+
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BufferSizeRange;
+
+    fn device(name: &str, in_ports: &[&str], out_ports: &[&str]) -> AudioDeviceInfo {
+        AudioDeviceInfo {
+            name: String::from(name),
+            in_ports: in_ports.iter().map(|p| String::from(*p)).collect(),
+            out_ports: out_ports.iter().map(|p| String::from(*p)).collect(),
+            sample_rates: vec![48000],
+            buffer_size_range: BufferSizeRange { min: 256, max: 256 },
+            duplex_buffer_size_range: Some(BufferSizeRange { min: 256, max: 256 }),
+            default_in_port: if in_ports.is_empty() { None } else { Some(0) },
+            default_out_port_left: 0,
+            default_out_port_right: 0,
+            default_sample_rate_index: 0,
+            default_buffer_size: 256,
+        }
+    }
+
+    fn names(groups: &[DeviceGroup]) -> Vec<&str> {
+        groups.iter().map(|g| g.name.as_str()).collect()
+    }
+
+    #[test]
+    fn group_jack_system_and_bridges() {
+        let device = device(
+            "Jack Device",
+            &[
+                "system:capture_1",
+                "system:capture_2",
+                "USB Interface:capture_1",
+                "usb_in:capture_1",
+            ],
+            &[
+                "system:playback_1",
+                "system:playback_2",
+                "USB Interface:playback_1",
+                "usb_out:playback_1",
+                "usb_out:playback_2",
+            ],
+        );
+
+        let groups = group_device_ports(&device);
+
+        assert_eq!(names(&groups), vec!["system", "USB Interface", "usb"]);
+
+        assert_eq!(groups[0].device, "Jack Device");
+        assert_eq!(
+            groups[0].in_ports,
+            vec!["system:capture_1", "system:capture_2"]
+        );
+        assert_eq!(
+            groups[0].out_ports,
+            vec!["system:playback_1", "system:playback_2"]
+        );
+
+        assert_eq!(groups[1].in_ports, vec!["USB Interface:capture_1"]);
+        assert_eq!(groups[1].out_ports, vec!["USB Interface:playback_1"]);
+
+        assert_eq!(groups[2].in_ports, vec!["usb_in:capture_1"]);
+        assert_eq!(
+            groups[2].out_ports,
+            vec!["usb_out:playback_1", "usb_out:playback_2"]
+        );
+    }
+
+    #[test]
+    fn group_bridge_suffix_variants() {
+        let device = device(
+            "Jack Device",
+            &["Built-in Audio In:capture_1", "HDMI-in:capture_1"],
+            &["Built-in Audio Out:playback_1", "HDMI-OUT:playback_1"],
+        );
+
+        let groups = group_device_ports(&device);
+
+        assert_eq!(names(&groups), vec!["Built-in Audio", "HDMI"]);
+        assert_eq!(groups[0].in_ports.len(), 1);
+        assert_eq!(groups[0].out_ports.len(), 1);
+        assert_eq!(groups[1].in_ports.len(), 1);
+        assert_eq!(groups[1].out_ports.len(), 1);
+    }
+
+    #[test]
+    fn group_unprefixed_ports_by_device() {
+        let device = device(
+            "hw:0",
+            &["capture_1", "capture_2"],
+            &["playback_1", "playback_2"],
+        );
+
+        let groups = group_device_ports(&device);
+
+        assert_eq!(names(&groups), vec!["hw:0"]);
+        assert_eq!(groups[0].in_ports, vec!["capture_1", "capture_2"]);
+        assert_eq!(groups[0].out_ports, vec!["playback_1", "playback_2"]);
+    }
+
+    #[test]
+    fn group_suffix_only_client_is_kept() {
+        // A client named just "in" shouldn't be reduced to an empty name.
+        let device = device("Jack Device", &[" in:capture_1", "_in:capture_1"], &[]);
+
+        let groups = group_device_ports(&device);
+
+        assert_eq!(names(&groups), vec!["in", "_in"]);
+    }
+
+    #[test]
+    fn group_non_ascii_client_names() {
+        let device = device(
+            "Jack Device",
+            &["Lautsprecher Einbau:capture_1", "Mikrofon ÜÜ:capture_1"],
+            &[],
+        );
+
+        let groups = group_device_ports(&device);
+
+        assert_eq!(names(&groups), vec!["Lautsprecher Einbau", "Mikrofon ÜÜ"]);
+    }
+}
@@ -0,0 +1,346 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::enumeration::enumerate_servers;
+use crate::{AudioServerInfo, Backend, EnumerationError, MidiServerInfo};
+
+/// How often backends without device change notifications are enumerated again.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long to wait for more notifications after the first one, since a new device
+/// usually registers all of its ports at once.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// A device that appeared or disappeared, from `watch_devices()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceChange {
+    AudioDeviceAdded(String),
+    AudioDeviceRemoved(String),
+
+    /// A port appeared on an audio device that was already there. On Jack, whose only
+    /// device is the server itself, this is how a new interface (e.g. bridged in with
+    /// `zita-a2j`) shows up.
+    AudioPortAdded {
+        device: String,
+        port: String,
+    },
+    AudioPortRemoved {
+        device: String,
+        port: String,
+    },
+
+    MidiInDeviceAdded(String),
+    MidiInDeviceRemoved(String),
+    MidiOutDeviceAdded(String),
+    MidiOutDeviceRemoved(String),
+}
+
+/// Whatever tells the watcher thread about device changes, kept alive for as long as the
+/// thread is watching.
+pub(crate) trait DeviceNotifier {
+    /// Returns `false` once the notifications have stopped, e.g. because the server shut
+    /// down.
+    fn is_connected(&self) -> bool;
+}
+
+/// Watch the devices of a single backend, calling `callback` on a helper thread every time
+/// a device or port appears or disappears. The devices that are there when this is called
+/// aren't reported.
+///
+/// Jack notifies the watcher whenever a port is registered. Every other backend doesn't
+/// have notifications yet and is enumerated again every two seconds. Dropping (or
+/// stopping) the returned watcher stops the watch.
+pub fn watch_devices<F: FnMut(DeviceChange) + Send + 'static>(
+    backend: Backend,
+    mut callback: F,
+) -> Result<DeviceWatcher, EnumerationError> {
+    if !backend.is_compiled() {
+        return Err(EnumerationError::BackendNotCompiled(backend));
+    }
+
+    let running = Arc::new(AtomicBool::new(true));
+    let (wake_tx, wake_rx) = mpsc::channel();
+
+    let thread_running = Arc::clone(&running);
+    let notifier_wake_tx = wake_tx.clone();
+
+    std::thread::Builder::new()
+        .name(format!("rusty-daw-io {} device watcher", backend))
+        .spawn(move || {
+            let mut notifier = device_notifier(backend, &notifier_wake_tx);
+            let mut devices = DeviceSnapshot::new(backend);
+
+            while thread_running.load(Ordering::Relaxed) {
+                let connected = notifier.as_ref().map(|n| n.is_connected()).unwrap_or(false);
+
+                let woken = if connected {
+                    wake_rx.recv().is_ok()
+                } else {
+                    wake_rx.recv_timeout(POLL_INTERVAL).is_ok()
+                };
+                if !thread_running.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                if woken {
+                    std::thread::sleep(SETTLE_TIME);
+                    while wake_rx.try_recv().is_ok() {}
+                }
+
+                // Try again in case the server was started since.
+                if !connected {
+                    notifier = device_notifier(backend, &notifier_wake_tx);
+                }
+
+                let new_devices = DeviceSnapshot::new(backend);
+                for change in devices.changes(&new_devices) {
+                    callback(change);
+                }
+                devices = new_devices;
+            }
+        })
+        .map_err(|_| EnumerationError::EnumerationThreadFailed(backend))?;
+
+    Ok(DeviceWatcher {
+        backend,
+        running,
+        wake_tx,
+    })
+}
+
+fn device_notifier(
+    backend: Backend,
+    wake_tx: &mpsc::Sender<()>,
+) -> Option<Box<dyn DeviceNotifier>> {
+    #[cfg(target_os = "linux")]
+    {
+        crate::linux::device_notifier(backend, wake_tx.clone())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (backend, wake_tx);
+        None
+    }
+}
+
+/// A device watch started with `watch_devices()`. Dropping this stops the watch.
+///
+/// The helper thread can't be interrupted while it is enumerating the devices, so the
+/// callback may still be called once after this is dropped.
+pub struct DeviceWatcher {
+    backend: Backend,
+    running: Arc<AtomicBool>,
+    wake_tx: mpsc::Sender<()>,
+}
+
+impl DeviceWatcher {
+    /// The backend that is being watched.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Stop watching.
+    pub fn stop(self) {}
+}
+
+impl Drop for DeviceWatcher {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        let _ = self.wake_tx.send(());
+    }
+}
+
+/// The devices of a backend at one point in time.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct DeviceSnapshot {
+    /// The name and the ports of every audio device.
+    audio_devices: Vec<(String, Vec<String>)>,
+    midi_in_devices: Vec<String>,
+    midi_out_devices: Vec<String>,
+}
+
+impl DeviceSnapshot {
+    fn new(backend: Backend) -> Self {
+        match enumerate_servers(backend) {
+            Some((audio, midi)) => Self::from_servers(Some(&audio), Some(&midi)),
+            None => Self::from_servers(None, None),
+        }
+    }
+
+    fn from_servers(audio: Option<&AudioServerInfo>, midi: Option<&MidiServerInfo>) -> Self {
+        let mut snapshot = DeviceSnapshot::default();
+
+        if let Some(audio) = audio.filter(|s| s.available) {
+            snapshot.audio_devices = audio
+                .devices
+                .iter()
+                .map(|d| {
+                    let ports = d.in_ports.iter().chain(d.out_ports.iter()).cloned();
+                    (d.name.clone(), ports.collect())
+                })
+                .collect();
+        }
+        if let Some(midi) = midi.filter(|s| s.available) {
+            snapshot.midi_in_devices = midi.in_devices.iter().map(|d| d.name.clone()).collect();
+            snapshot.midi_out_devices = midi.out_devices.iter().map(|d| d.name.clone()).collect();
+        }
+
+        snapshot
+    }
+
+    /// Everything that changed from `self` to `new`, with the removals of each kind of
+    /// device before its additions.
+    fn changes(&self, new: &DeviceSnapshot) -> Vec<DeviceChange> {
+        let mut changes = Vec::new();
+
+        let find = |snapshot: &DeviceSnapshot, name: &str| {
+            snapshot
+                .audio_devices
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, ports)| ports.clone())
+        };
+
+        for (name, ports) in self.audio_devices.iter() {
+            match find(new, name) {
+                None => changes.push(DeviceChange::AudioDeviceRemoved(name.clone())),
+                Some(new_ports) => {
+                    for port in ports.iter().filter(|p| !new_ports.contains(p)) {
+                        changes.push(DeviceChange::AudioPortRemoved {
+                            device: name.clone(),
+                            port: port.clone(),
+                        });
+                    }
+                    for port in new_ports.iter().filter(|p| !ports.contains(p)) {
+                        changes.push(DeviceChange::AudioPortAdded {
+                            device: name.clone(),
+                            port: port.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        for (name, _) in new.audio_devices.iter() {
+            if find(self, name).is_none() {
+                changes.push(DeviceChange::AudioDeviceAdded(name.clone()));
+            }
+        }
+
+        changes.extend(name_changes(
+            &self.midi_in_devices,
+            &new.midi_in_devices,
+            DeviceChange::MidiInDeviceRemoved,
+            DeviceChange::MidiInDeviceAdded,
+        ));
+        changes.extend(name_changes(
+            &self.midi_out_devices,
+            &new.midi_out_devices,
+            DeviceChange::MidiOutDeviceRemoved,
+            DeviceChange::MidiOutDeviceAdded,
+        ));
+
+        changes
+    }
+}
+
+/// The names in `old` that aren't in `new`, then the ones in `new` that aren't in `old`.
+fn name_changes(
+    old: &[String],
+    new: &[String],
+    removed: fn(String) -> DeviceChange,
+    added: fn(String) -> DeviceChange,
+) -> Vec<DeviceChange> {
+    let removed = old
+        .iter()
+        .filter(|n| !new.contains(n))
+        .map(|n| removed(n.clone()));
+    let added = new
+        .iter()
+        .filter(|n| !old.contains(n))
+        .map(|n| added(n.clone()));
+
+    removed.chain(added).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| String::from(*n)).collect()
+    }
+
+    #[test]
+    fn device_changes() {
+        let old = DeviceSnapshot {
+            audio_devices: vec![
+                (
+                    String::from("Jack"),
+                    names(&["system:capture_1", "system:playback_1"]),
+                ),
+                (String::from("Built-in"), names(&["capture_1"])),
+            ],
+            midi_in_devices: names(&["system:midi_capture_1"]),
+            midi_out_devices: names(&["system:midi_playback_1"]),
+        };
+        assert!(old.changes(&old).is_empty());
+
+        let new = DeviceSnapshot {
+            audio_devices: vec![
+                (
+                    String::from("Jack"),
+                    names(&["system:playback_1", "usb:capture_1"]),
+                ),
+                (String::from("USB Interface"), names(&["capture_1"])),
+            ],
+            midi_in_devices: names(&["system:midi_capture_1", "system:midi_capture_2"]),
+            midi_out_devices: Vec::new(),
+        };
+
+        assert_eq!(
+            old.changes(&new),
+            vec![
+                DeviceChange::AudioPortRemoved {
+                    device: String::from("Jack"),
+                    port: String::from("system:capture_1"),
+                },
+                DeviceChange::AudioPortAdded {
+                    device: String::from("Jack"),
+                    port: String::from("usb:capture_1"),
+                },
+                DeviceChange::AudioDeviceRemoved(String::from("Built-in")),
+                DeviceChange::AudioDeviceAdded(String::from("USB Interface")),
+                DeviceChange::MidiInDeviceAdded(String::from("system:midi_capture_2")),
+                DeviceChange::MidiOutDeviceRemoved(String::from("system:midi_playback_1")),
+            ]
+        );
+    }
+
+    #[test]
+    fn unavailable_servers_have_no_devices() {
+        let mut audio = AudioServerInfo::new(String::from("Jack"), None);
+        audio.devices = crate::DummyBackend::default().audio_server_info().devices;
+
+        assert!(DeviceSnapshot::from_servers(Some(&audio), None)
+            .audio_devices
+            .is_empty());
+
+        audio.available = true;
+        assert_eq!(
+            DeviceSnapshot::from_servers(Some(&audio), None)
+                .audio_devices
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn watch_dummy_devices() {
+        let watcher = watch_devices(Backend::Dummy, |_| {}).unwrap();
+        assert_eq!(watcher.backend(), Backend::Dummy);
+        watcher.stop();
+    }
+}
@@ -0,0 +1,290 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The number of process cycles kept by `StreamHandle::recent_buffer_sizes()` when
+/// `Config::buffer_size_history` is `None`.
+pub const DEFAULT_BUFFER_SIZE_HISTORY: usize = 64;
+
+/// Under/overrun counters for one of the ring buffers managed by this crate.
+///
+/// These are distinct from the audio server's xruns. An overrun here means the
+/// non-realtime side couldn't keep up with the realtime thread (or vice versa), not
+/// that the audio interface glitched.
+///
+/// Underruns are only counted by consumers that need a given amount of data every cycle,
+/// like the output stream of a CPAL duplex stream. The other consumers poll their ring
+/// buffers, so finding one empty just means there was nothing to do.
+#[derive(Debug, Default)]
+pub(crate) struct RingBufferCounters {
+    overruns: AtomicU64,
+    underruns: AtomicU64,
+}
+
+impl RingBufferCounters {
+    /// Count an attempt to push into a full ring buffer.
+    pub fn overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> RingBufferStats {
+        RingBufferStats {
+            overruns: self.overruns.load(Ordering::Relaxed),
+            underruns: self.underruns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Only CPAL's capture FIFO is read by a consumer that needs data every cycle.
+#[cfg(any(test, feature = "cpal"))]
+impl RingBufferCounters {
+    /// Count an attempt to pop more than the ring buffer held.
+    pub fn underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Push every item of `items` that fits, counting an overrun if some didn't. Returns
+    /// the number of items that were pushed.
+    pub fn push_iter<T, I: ExactSizeIterator<Item = T>>(
+        &self,
+        producer: &mut ringbuf::Producer<T>,
+        mut items: I,
+    ) -> usize {
+        let len = items.len();
+        let pushed = producer.push_iter(&mut items);
+        if pushed < len {
+            self.overrun();
+        }
+        pushed
+    }
+
+    /// Fill as much of `out` as the ring buffer holds, counting an underrun if it didn't
+    /// hold enough. Returns the number of items that were read.
+    pub fn pop_slice<T: Copy>(&self, consumer: &mut ringbuf::Consumer<T>, out: &mut [T]) -> usize {
+        let read = consumer.pop_slice(out);
+        if read < out.len() {
+            self.underrun();
+        }
+        read
+    }
+}
+
+/// A snapshot of the under/overrun counters of one of this crate's ring buffers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RingBufferStats {
+    /// The number of times data was dropped because the ring buffer was full.
+    pub overruns: u64,
+
+    /// The number of times data was expected but the ring buffer was empty. This stays at
+    /// zero for the ring buffers whose consumers poll them (see `StreamDiagnostics`).
+    pub underruns: u64,
+}
+
+impl std::fmt::Display for RingBufferStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} overruns, {} underruns",
+            self.overruns, self.underruns
+        )
+    }
+}
+
+/// A snapshot of the health of a running stream.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamDiagnostics {
+    /// The total number of frames that have been processed since the stream started.
+    pub frames_processed: u64,
+
+    /// The total number of xruns reported by the audio server or device.
+    pub xruns: u64,
+
+    /// The queue used to pass reports from `StreamHandle::debug_next_cycles()` off of the
+    /// realtime thread.
+    pub cycle_debug_queue: RingBufferStats,
+
+    /// The FIFO that brings the samples captured by the input stream of a CPAL duplex
+    /// stream to its output stream, or `None` for any other stream. Its underruns are the
+    /// cycles the output stream ran out of captured samples.
+    pub capture_fifo: Option<RingBufferStats>,
+
+    /// The message channel of a stream spawned with `spawn_rt_thread_with_messages()`, or
+    /// `None` for any other stream.
+    pub message_channel: Option<RingBufferStats>,
+
+    /// The ring buffer of every bus recorded by the recorders added with
+    /// `StreamHandle::add_recorder_diagnostics()`, by bus ID.
+    pub recorder: Vec<(String, RingBufferStats)>,
+}
+
+impl std::fmt::Display for StreamDiagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Stream diagnostics:")?;
+        writeln!(f, "    frames processed: {}", self.frames_processed)?;
+        writeln!(f, "    xruns: {}", self.xruns)?;
+        write!(f, "    cycle debug queue: {}", self.cycle_debug_queue)?;
+        if let Some(capture_fifo) = &self.capture_fifo {
+            write!(f, "\n    capture fifo: {}", capture_fifo)?;
+        }
+        if let Some(message_channel) = &self.message_channel {
+            write!(f, "\n    message channel: {}", message_channel)?;
+        }
+        for (bus_id, stats) in self.recorder.iter() {
+            write!(f, "\n    recorder ({}): {}", bus_id, stats)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The ring buffers that live next to a stream rather than in its backend, whose counters
+/// are added to the backend's `StreamDiagnostics`.
+#[derive(Debug, Default)]
+pub(crate) struct RingBufferSources {
+    pub message_channel: Option<Arc<RingBufferCounters>>,
+    pub recorder: Vec<(String, Arc<RingBufferCounters>)>,
+}
+
+impl RingBufferSources {
+    pub fn add_to(&self, diagnostics: &mut StreamDiagnostics) {
+        diagnostics.message_channel = self.message_channel.as_ref().map(|c| c.stats());
+        diagnostics.recorder = self
+            .recorder
+            .iter()
+            .map(|(bus_id, counters)| (bus_id.clone(), counters.stats()))
+            .collect();
+    }
+}
+
+/// The buffer sizes of the last few process cycles.
+///
+/// This is only written to by the realtime thread. Writing never allocates or blocks, but a
+/// read that races with a write may see a slot that was just overwritten.
+#[derive(Debug)]
+pub(crate) struct BufferSizeHistory {
+    sizes: Vec<AtomicU32>,
+    written: AtomicU64,
+}
+
+impl BufferSizeHistory {
+    /// * `len` - The number of cycles to keep. If this is `0` nothing is recorded.
+    pub fn new(len: usize) -> Self {
+        Self {
+            sizes: (0..len).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicU64::new(0),
+        }
+    }
+
+    /// Record the buffer size of a process cycle.
+    pub fn push(&self, audio_frames: usize) {
+        if self.sizes.is_empty() {
+            return;
+        }
+
+        let written = self.written.load(Ordering::Relaxed);
+        self.sizes[(written % self.sizes.len() as u64) as usize]
+            .store(audio_frames as u32, Ordering::Relaxed);
+        self.written.store(written + 1, Ordering::Release);
+    }
+
+    /// The recorded buffer sizes, oldest first.
+    pub fn recent(&self) -> Vec<u32> {
+        let written = self.written.load(Ordering::Acquire);
+        let len = self.sizes.len() as u64;
+        let first = written.saturating_sub(len);
+
+        (first..written)
+            .map(|i| self.sizes[(i % len) as usize].load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_size_history_wraps() {
+        let history = BufferSizeHistory::new(3);
+        assert!(history.recent().is_empty());
+
+        history.push(128);
+        history.push(256);
+        assert_eq!(history.recent(), vec![128, 256]);
+
+        history.push(64);
+        history.push(512);
+        assert_eq!(history.recent(), vec![256, 64, 512]);
+
+        let disabled = BufferSizeHistory::new(0);
+        disabled.push(128);
+        assert!(disabled.recent().is_empty());
+    }
+
+    #[test]
+    fn ring_buffer_sources_are_added() {
+        let message_channel = Arc::new(RingBufferCounters::default());
+        let recorder = Arc::new(RingBufferCounters::default());
+        let sources = RingBufferSources {
+            message_channel: Some(Arc::clone(&message_channel)),
+            recorder: vec![(String::from("Mic In"), Arc::clone(&recorder))],
+        };
+
+        recorder.overrun();
+        recorder.overrun();
+
+        let mut diagnostics = StreamDiagnostics::default();
+        sources.add_to(&mut diagnostics);
+        assert_eq!(
+            diagnostics.message_channel,
+            Some(RingBufferStats::default())
+        );
+        assert_eq!(
+            diagnostics.recorder,
+            vec![(
+                String::from("Mic In"),
+                RingBufferStats {
+                    overruns: 2,
+                    underruns: 0
+                }
+            )]
+        );
+        assert_eq!(
+            diagnostics.to_string(),
+            "Stream diagnostics:\n    frames processed: 0\n    xruns: 0\n    \
+             cycle debug queue: 0 overruns, 0 underruns\n    \
+             message channel: 0 overruns, 0 underruns\n    \
+             recorder (Mic In): 2 overruns, 0 underruns"
+        );
+    }
+
+    #[test]
+    fn counts_overruns_and_underruns_of_a_ring_buffer() {
+        let counters = RingBufferCounters::default();
+        let (mut producer, mut consumer) = ringbuf::RingBuffer::<f32>::new(4).split();
+
+        // Everything fits, then the ring buffer fills up.
+        assert_eq!(
+            counters.push_iter(&mut producer, vec![1.0, 2.0, 3.0].into_iter()),
+            3
+        );
+        assert_eq!(
+            counters.push_iter(&mut producer, vec![4.0, 5.0].into_iter()),
+            1
+        );
+
+        // A whole cycle is there, then only part of one.
+        let mut out = [0.0; 3];
+        assert_eq!(counters.pop_slice(&mut consumer, &mut out), 3);
+        assert_eq!(out, [1.0, 2.0, 3.0]);
+        assert_eq!(counters.pop_slice(&mut consumer, &mut out), 1);
+        assert_eq!(out[0], 4.0);
+
+        assert_eq!(
+            counters.stats(),
+            RingBufferStats {
+                overruns: 1,
+                underruns: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,1753 @@
+use log::{info, warn};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::channel_gain::{ChannelGains, ChannelGainsHandle};
+use crate::cycle_debug::{cycle_debugger, CycleDebugHandle, CycleDebugInfo, CycleDebugger};
+use crate::denormals::DenormalGuard;
+use crate::diagnostics::BufferSizeHistory;
+use crate::master_gain::{MasterGain, MasterGainHandle};
+use crate::midi_buffer::MidiDropCounters;
+use crate::port_mixing::{channel_routes, ChannelRoute};
+use crate::process_panic::catch_process_panic;
+use crate::shutdown_hook::ShutdownHook;
+use crate::silence_detect::{SilenceDetectHandle, SilenceDetector};
+use crate::spawn_deadline::SpawnDeadline;
+use crate::xruns::Xruns;
+use crate::{
+    AddBusError, AudioBus, AudioBusBuffer, AudioBusConfig, AudioDeviceInfo, AudioServerInfo,
+    Backend, BufferSizeRange, Config, DeviceIndex, DisconnectReason, FatalErrorHandler,
+    FatalStreamError, FreewheelError, FreewheelHook, LatencyRange, MidiController,
+    MidiControllerBuffer, MidiControllerConfig, MidiDeviceInfo, MidiServerInfo, MidiTimestamps,
+    OsStreamHandle, PortConnections, ProcessControl, ProcessInfo, RtProcessHandler, SampleFormat,
+    SpawnError, SpawnPhase, SpawnRtThreadError, StreamDiagnostics, StreamHandle, StreamInfo,
+};
+
+/// The name of the dummy audio and midi server. Set `Config::audio_server` (and optionally
+/// `Config::midi_server`) to this to use the dummy backend.
+pub const DUMMY_SERVER_NAME: &str = "Dummy";
+
+/// The name of the only device of the dummy audio server.
+pub const DUMMY_DEVICE_NAME: &str = "Dummy Device";
+
+const PLAYBACK_PORT_PREFIX: &str = "playback_";
+const CAPTURE_PORT_PREFIX: &str = "capture_";
+const MIDI_PLAYBACK_PORT_PREFIX: &str = "midi_playback_";
+const MIDI_CAPTURE_PORT_PREFIX: &str = "midi_capture_";
+
+/// A backend that needs no audio hardware or server.
+///
+/// It reports a single device with the configured channels, sample rate, and buffer
+/// size, and drives `RtProcessHandler::process` from a timer thread at the same
+/// cadence a real device would. The audio inputs are silent and the midi inputs are
+/// empty unless `input_samples` and `midi_in_events` are set, and the outputs are
+/// discarded.
+///
+/// This is mainly useful for running the whole pipeline in tests and on CI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DummyBackend {
+    /// The sample rate used when `Config::sample_rate` is `None`.
+    pub sample_rate: u32,
+
+    /// The buffer size used when `Config::buffer_size` is `None`.
+    pub buffer_size: u32,
+
+    /// The number of capture ports ("capture_1", "capture_2", ...).
+    pub in_channels: u16,
+
+    /// The number of playback ports ("playback_1", "playback_2", ...).
+    pub out_channels: u16,
+
+    /// The number of midi capture ports ("midi_capture_1", ...).
+    pub midi_in_ports: u16,
+
+    /// The number of midi playback ports ("midi_playback_1", ...).
+    pub midi_out_ports: u16,
+
+    /// The number of frames of each process cycle, repeated in order. This simulates a
+    /// backend whose buffer size changes from cycle to cycle (e.g. PipeWire).
+    ///
+    /// Each size is clamped to the buffer size of the stream. If this is empty every cycle
+    /// uses the full buffer size.
+    pub cycle_buffer_sizes: Vec<u32>,
+
+    /// How long opening the device takes. This simulates a slow server or driver, e.g. to
+    /// test `Config::spawn_timeout`.
+    pub open_delay: Duration,
+
+    /// Simulate a disconnected device once this many frames have been processed. The
+    /// stream then stops and `FatalErrorHandler::fatal_stream_error` is called with
+    /// `FatalStreamError::AudioDeviceDisconnected` and `DisconnectReason::DeviceRemoved`.
+    pub disconnect_after_frames: Option<u64>,
+
+    /// The samples of each capture port (the first for "capture_1"), played in a loop from
+    /// the start of the stream. Ports without samples are silent.
+    pub input_samples: Vec<Vec<f32>>,
+
+    /// The events that arrive on the midi capture ports. Each one is delivered to every
+    /// midi input controller on its port, in the cycle that contains its frame.
+    pub midi_in_events: Vec<DummyMidiEvent>,
+}
+
+/// A midi event that arrives on a midi capture port of the dummy backend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DummyMidiEvent {
+    /// The index of the midi capture port (0 for "midi_capture_1").
+    pub port: usize,
+
+    /// The frame since the stream started.
+    pub frame: u64,
+
+    pub data: Vec<u8>,
+}
+
+impl Default for DummyBackend {
+    fn default() -> Self {
+        Self {
+            sample_rate: 48000,
+            buffer_size: 512,
+            in_channels: 2,
+            out_channels: 2,
+            midi_in_ports: 1,
+            midi_out_ports: 1,
+            cycle_buffer_sizes: Vec::new(),
+            open_delay: Duration::ZERO,
+            disconnect_after_frames: None,
+            input_samples: Vec::new(),
+            midi_in_events: Vec::new(),
+        }
+    }
+}
+
+impl DummyBackend {
+    /// The dummy audio server with its single device.
+    pub fn audio_server_info(&self) -> AudioServerInfo {
+        let mut server = AudioServerInfo::new(String::from(DUMMY_SERVER_NAME), None);
+
+        server.devices.push(AudioDeviceInfo {
+            name: String::from(DUMMY_DEVICE_NAME),
+            in_ports: port_names(CAPTURE_PORT_PREFIX, self.in_channels),
+            out_ports: port_names(PLAYBACK_PORT_PREFIX, self.out_channels),
+            sample_rates: vec![self.sample_rate],
+            buffer_size_range: BufferSizeRange {
+                min: self.buffer_size,
+                max: self.buffer_size,
+            },
+            duplex_buffer_size_range: if self.in_channels > 0 {
+                Some(BufferSizeRange {
+                    min: self.buffer_size,
+                    max: self.buffer_size,
+                })
+            } else {
+                None
+            },
+
+            default_in_port: if self.in_channels > 0 { Some(0) } else { None },
+            default_out_port_left: 0,
+            default_out_port_right: 1.min(usize::from(self.out_channels.max(1)) - 1), // Second port if stereo, first if mono.
+            default_sample_rate_index: 0,
+            default_buffer_size: self.buffer_size,
+        });
+        server.available = self.out_channels > 0;
+
+        server
+    }
+
+    /// The dummy midi server.
+    pub fn midi_server_info(&self) -> MidiServerInfo {
+        let mut server = MidiServerInfo::new(String::from(DUMMY_SERVER_NAME), None);
+
+        server.in_devices = port_names(MIDI_CAPTURE_PORT_PREFIX, self.midi_in_ports)
+            .into_iter()
+            .map(|name| MidiDeviceInfo { name })
+            .collect();
+        server.out_devices = port_names(MIDI_PLAYBACK_PORT_PREFIX, self.midi_out_ports)
+            .into_iter()
+            .map(|name| MidiDeviceInfo { name })
+            .collect();
+        server.available = true;
+
+        server
+    }
+
+    /// Spawn a stream on the dummy backend.
+    ///
+    /// `config.audio_server` is ignored, so any config that only uses the port names of
+    /// this backend's device can be used.
+    pub fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
+        &self,
+        config: &Config,
+        rt_process_handler: P,
+        fatal_error_handler: E,
+    ) -> Result<StreamHandle<P, E>, SpawnError<P>> {
+        let config = match crate::spawn_config(config) {
+            Ok(config) => config,
+            Err(e) => return Err(SpawnError::new(e, Some(rt_process_handler))),
+        };
+        let config = &*config;
+
+        StreamHandle::spawn(config, rt_process_handler, |rt_process_handler| {
+            Ok(crate::BackendStreamHandle::Dummy(spawn_rt_thread(
+                self,
+                config,
+                rt_process_handler,
+                fatal_error_handler,
+            )?))
+        })
+    }
+}
+
+pub(crate) struct DummyStreamHandle<P: RtProcessHandler, E: FatalErrorHandler> {
+    stream_info: StreamInfo,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<P>>,
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_debug_handle: CycleDebugHandle,
+    master_gain_handle: MasterGainHandle,
+    channel_gains_handle: ChannelGainsHandle,
+    silence_detect_handle: SilenceDetectHandle,
+    shutdown_hook: Arc<ShutdownHook>,
+
+    // The dummy device never has xruns, so this stays at zero.
+    xruns: Xruns,
+    midi_in_drops: MidiDropCounters,
+
+    // The fatal error handler is owned by the audio thread.
+    _fatal_error_handler: PhantomData<E>,
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> OsStreamHandle for DummyStreamHandle<P, E> {
+    type P = P;
+    type E = E;
+
+    fn stream_info(&self) -> &StreamInfo {
+        &self.stream_info
+    }
+
+    fn frames_processed(&self) -> u64 {
+        self.frames_processed.load(Ordering::Relaxed)
+    }
+
+    fn debug_next_cycles(&self, n: u32) {
+        self.cycle_debug_handle.debug_next_cycles(n);
+    }
+
+    fn diagnostics(&self) -> StreamDiagnostics {
+        StreamDiagnostics {
+            frames_processed: self.frames_processed(),
+            xruns: self.xruns.count(),
+            cycle_debug_queue: self.cycle_debug_handle.queue_stats(),
+            ..StreamDiagnostics::default()
+        }
+    }
+
+    fn recent_buffer_sizes(&self) -> Vec<u32> {
+        self.buffer_size_history.recent()
+    }
+
+    fn midi_in_dropped_events(&self, controller_id: &str) -> Option<u64> {
+        self.midi_in_drops.dropped_events(controller_id)
+    }
+
+    fn set_master_gain(&self, gain: f32) {
+        self.master_gain_handle.set_master_gain(gain);
+    }
+
+    fn set_master_gain_ramp_time(&self, ramp_time: Duration) {
+        self.master_gain_handle.set_master_gain_ramp_time(ramp_time);
+    }
+
+    fn master_gain(&self) -> f32 {
+        self.master_gain_handle.master_gain()
+    }
+
+    fn channel_gains_handle(&self) -> Option<&ChannelGainsHandle> {
+        Some(&self.channel_gains_handle)
+    }
+
+    fn silence_detect_handle(&self) -> Option<&SilenceDetectHandle> {
+        Some(&self.silence_detect_handle)
+    }
+
+    fn shutdown_hook(&self) -> &ShutdownHook {
+        &self.shutdown_hook
+    }
+
+    fn bus_latency(&self, _bus_id: &str) -> Option<LatencyRange> {
+        None
+    }
+
+    fn port_connections(&self, _bus_id: &str) -> Option<Vec<PortConnections>> {
+        None
+    }
+
+    fn set_freewheel(&self, _enabled: bool) -> Result<(), FreewheelError> {
+        Err(FreewheelError::NotSupported(Backend::Dummy))
+    }
+
+    fn is_freewheeling(&self) -> bool {
+        false
+    }
+
+    fn on_freewheel(&self, _hook: FreewheelHook) {}
+
+    fn add_audio_bus(
+        &mut self,
+        _bus: &AudioBusConfig,
+        _is_input: bool,
+    ) -> Result<AudioBus, AddBusError> {
+        Err(AddBusError::NotSupported(Backend::Dummy))
+    }
+
+    fn add_midi_controller(
+        &mut self,
+        _controller: &MidiControllerConfig,
+        _is_input: bool,
+    ) -> Result<MidiController, AddBusError> {
+        Err(AddBusError::NotSupported(Backend::Dummy))
+    }
+
+    fn xruns(&self) -> &Xruns {
+        &self.xruns
+    }
+
+    fn stop(&mut self) -> Option<P> {
+        let thread = self.thread.take()?;
+
+        self.shutdown_hook.run();
+
+        self.running.store(false, Ordering::Relaxed);
+
+        match thread.join() {
+            Ok(rt_process_handler) => Some(rt_process_handler),
+            Err(_) => {
+                warn!("Dummy: audio thread panicked");
+                None
+            }
+        }
+    }
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> Drop for DummyStreamHandle<P, E> {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+pub(crate) fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
+    backend: &DummyBackend,
+    config: &Config,
+    rt_process_handler: P,
+    fatal_error_handler: E,
+) -> Result<DummyStreamHandle<P, E>, SpawnError<P>> {
+    let mut rt_process_handler = Some(rt_process_handler);
+    spawn(
+        backend,
+        config,
+        &mut rt_process_handler,
+        fatal_error_handler,
+    )
+    .map_err(|e| SpawnError::new(e, rt_process_handler))
+}
+
+/// Spawn the stream, leaving the handler in `rt_process_handler_slot` if it fails.
+fn spawn<P: RtProcessHandler, E: FatalErrorHandler>(
+    backend: &DummyBackend,
+    config: &Config,
+    rt_process_handler_slot: &mut Option<P>,
+    fatal_error_handler: E,
+) -> Result<DummyStreamHandle<P, E>, SpawnRtThreadError> {
+    info!("Spawning Dummy thread...");
+
+    let deadline = SpawnDeadline::new(config.spawn_timeout);
+
+    let open_delay = backend.open_delay;
+    if !open_delay.is_zero() {
+        deadline.run(SpawnPhase::Open, move || std::thread::sleep(open_delay))?;
+    }
+
+    if config.system_audio_device != DUMMY_DEVICE_NAME {
+        return Err(SpawnRtThreadError::SystemDeviceNotFound(
+            config.system_audio_device.clone(),
+        ));
+    }
+
+    let config = &crate::port_pattern::resolve_config_patterns(
+        config,
+        &port_names(CAPTURE_PORT_PREFIX, backend.in_channels),
+        &port_names(PLAYBACK_PORT_PREFIX, backend.out_channels),
+    );
+
+    let audio_in = map_busses(
+        &config.audio_in_busses,
+        CAPTURE_PORT_PREFIX,
+        backend.in_channels,
+    )?;
+    let audio_out = map_busses(
+        &config.audio_out_busses,
+        PLAYBACK_PORT_PREFIX,
+        backend.out_channels,
+    )?;
+
+    if let Some(reason) = config.skipped_midi_reason(Backend::Dummy) {
+        warn!("Dummy: {}", reason);
+    }
+    let (midi_in, midi_out) = if config.creates_midi_controllers(Backend::Dummy) {
+        let midi_server = backend.midi_server_info();
+
+        (
+            map_controllers(&config.midi_in_controllers, &midi_server.in_devices, true)?,
+            map_controllers(
+                &config.midi_out_controllers,
+                &midi_server.out_devices,
+                false,
+            )?,
+        )
+    } else {
+        (Vec::new(), Vec::new())
+    };
+
+    deadline.check(SpawnPhase::RegisterPorts)?;
+
+    let sample_rate = config.sample_rate.unwrap_or(backend.sample_rate).max(1);
+    let buffer_size = config.buffer_size.unwrap_or(backend.buffer_size).max(1);
+
+    let stream_info = StreamInfo {
+        server_name: String::from(DUMMY_SERVER_NAME),
+        audio_in,
+        audio_out,
+        midi_in,
+        midi_out,
+        sample_rate,
+        max_audio_buffer_size: buffer_size,
+        latency_frames_in: 0,
+        latency_frames_out: 0,
+        sample_format: SampleFormat::F32,
+    };
+
+    // From here on the handler can only be given back by stopping the audio thread.
+    let mut rt_process_handler = rt_process_handler_slot
+        .take()
+        .expect("the handler is only taken once");
+    rt_process_handler.init(&stream_info);
+
+    let (cycle_debugger, cycle_debug_handle) = cycle_debugger();
+
+    let mut midi_in_events = backend.midi_in_events.clone();
+    midi_in_events.sort_by_key(|event| event.frame);
+
+    let process = DummyProcess {
+        rt_process_handler,
+        fatal_error_handler: Some(fatal_error_handler),
+        stream_info: stream_info.clone(),
+        running: Arc::new(AtomicBool::new(true)),
+        frames_processed: Arc::new(AtomicU64::new(0)),
+        buffer_size_history: Arc::new(BufferSizeHistory::new(config.buffer_size_history_len())),
+        cycle_buffer_sizes: if backend.cycle_buffer_sizes.is_empty() {
+            vec![buffer_size]
+        } else {
+            backend
+                .cycle_buffer_sizes
+                .iter()
+                .map(|size| (*size).clamp(1, buffer_size))
+                .collect()
+        },
+        cycle_debugger,
+        master_gain: MasterGain::new(),
+        channel_gains: ChannelGains::new(&config.audio_in_busses, &config.audio_out_busses),
+        silence_detector: SilenceDetector::new(
+            stream_info
+                .audio_in
+                .iter()
+                .map(|b| b.id_name.clone())
+                .collect(),
+        ),
+        input_peaks: config.input_peaks,
+        flush_denormals: !config.allow_denormals,
+        shutdown_hook: Arc::new(ShutdownHook::new()),
+        disconnect_after_frames: backend.disconnect_after_frames,
+        input_samples: backend.input_samples.clone(),
+        in_ports: config
+            .audio_in_busses
+            .iter()
+            .map(|bus| {
+                let ports: Vec<usize> = bus
+                    .system_ports
+                    .iter()
+                    .map(|port| port_index(port, CAPTURE_PORT_PREFIX))
+                    .collect();
+                channel_routes(bus, &ports, true)
+            })
+            .collect(),
+        midi_in_events,
+        midi_in_ports: stream_info
+            .midi_in
+            .iter()
+            .map(|controller| port_index(&controller.system_port, MIDI_CAPTURE_PORT_PREFIX))
+            .collect(),
+        midi_in_buffers: config
+            .midi_in_controllers
+            .iter()
+            .take(stream_info.midi_in.len())
+            .map(|controller| {
+                MidiControllerBuffer::for_input(controller.filter, controller.buffer_capacity)
+            })
+            .collect(),
+        midi_in_timestamps: config.midi_in_timestamps,
+    };
+
+    let running = Arc::clone(&process.running);
+    let frames_processed = Arc::clone(&process.frames_processed);
+    let buffer_size_history = Arc::clone(&process.buffer_size_history);
+    let master_gain_handle = process.master_gain.handle();
+    let channel_gains_handle = process.channel_gains.handle();
+    let silence_detect_handle = process.silence_detector.handle();
+    let shutdown_hook = Arc::clone(&process.shutdown_hook);
+    shutdown_hook.set_stream_info(stream_info.clone());
+    let mut midi_in_drops = MidiDropCounters::default();
+    for (controller, buffer) in stream_info
+        .midi_in
+        .iter()
+        .zip(process.midi_in_buffers.iter())
+    {
+        midi_in_drops.push(&controller.id_name, buffer);
+    }
+
+    let thread = std::thread::Builder::new()
+        .name(String::from("rusty-daw-io Dummy"))
+        .spawn(move || process.run())
+        .map_err(|e| SpawnRtThreadError::PlatformSpecific(Box::new(e)))?;
+
+    let mut handle = DummyStreamHandle {
+        stream_info,
+        running,
+        thread: Some(thread),
+        frames_processed,
+        buffer_size_history,
+        cycle_debug_handle,
+        master_gain_handle,
+        channel_gains_handle,
+        silence_detect_handle,
+        shutdown_hook,
+        xruns: Xruns::new(),
+        midi_in_drops,
+        _fatal_error_handler: PhantomData,
+    };
+
+    if let Err(e) = deadline.check(SpawnPhase::Activate) {
+        *rt_process_handler_slot = handle.stop();
+        return Err(e);
+    }
+
+    info!(
+        "Successfully spawned Dummy thread. Sample rate: {}, Max audio buffer size: {}",
+        sample_rate, buffer_size
+    );
+
+    Ok(handle)
+}
+
+fn port_names(prefix: &str, count: u16) -> Vec<String> {
+    (1..=count).map(|i| format!("{}{}", prefix, i)).collect()
+}
+
+/// The index of a port returned by `port_names()`.
+fn port_index(port: &str, prefix: &str) -> usize {
+    port[prefix.len()..].parse::<usize>().unwrap_or(1) - 1
+}
+
+fn map_busses(
+    busses: &[AudioBusConfig],
+    port_prefix: &str,
+    device_channels: u16,
+) -> Result<Vec<AudioBus>, SpawnRtThreadError> {
+    let device_ports = port_names(port_prefix, device_channels);
+
+    let mut audio_busses = Vec::<AudioBus>::new();
+    for (bus_i, bus) in busses.iter().enumerate() {
+        if bus.system_ports.is_empty() {
+            return Err(SpawnRtThreadError::NoSystemPortsGiven(bus.id.clone()));
+        }
+
+        for system_port in bus.system_ports.iter() {
+            if !device_ports.contains(system_port) {
+                return Err(SpawnRtThreadError::SystemPortNotFound(
+                    system_port.clone(),
+                    bus.id.clone(),
+                ));
+            }
+        }
+
+        let channel_layout = bus.channel_layout();
+        let num_channels = channel_layout.num_channels() as u16;
+        audio_busses.push(AudioBus {
+            id_name: bus.id.clone(),
+            id_index: DeviceIndex::new(bus_i),
+            system_device: String::from(DUMMY_DEVICE_NAME),
+            system_half_duplex_device: None,
+            system_ports: bus.system_ports.clone(),
+            client_ports: Vec::new(),
+            channels: num_channels,
+            channel_layout,
+            connected_ports: num_channels,
+        });
+    }
+
+    Ok(audio_busses)
+}
+
+fn map_controllers(
+    controllers: &[MidiControllerConfig],
+    devices: &[MidiDeviceInfo],
+    is_input: bool,
+) -> Result<Vec<MidiController>, SpawnRtThreadError> {
+    let device_ports: Vec<String> = devices.iter().map(|d| d.name.clone()).collect();
+
+    let mut midi_controllers = Vec::<MidiController>::new();
+    for (controller_i, controller) in controllers.iter().enumerate() {
+        let system_port = if let Some(role) = controller.system_port_role {
+            role.resolve(&device_ports, is_input).ok_or_else(|| {
+                SpawnRtThreadError::SystemPortNotFound(role.to_string(), controller.id.clone())
+            })?
+        } else if let Some(port) =
+            crate::port_pattern::resolve_system_port(&controller.system_port, &device_ports)
+        {
+            port
+        } else {
+            return Err(SpawnRtThreadError::SystemPortNotFound(
+                controller.system_port.clone(),
+                controller.id.clone(),
+            ));
+        };
+
+        midi_controllers.push(MidiController {
+            id_name: controller.id.clone(),
+            id_index: DeviceIndex::new(controller_i),
+            system_port: String::from(system_port),
+            client_port: None,
+        });
+    }
+
+    Ok(midi_controllers)
+}
+
+struct DummyProcess<P: RtProcessHandler, E: FatalErrorHandler> {
+    rt_process_handler: P,
+    fatal_error_handler: Option<E>,
+
+    stream_info: StreamInfo,
+
+    running: Arc<AtomicBool>,
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_buffer_sizes: Vec<u32>,
+    cycle_debugger: CycleDebugger,
+    master_gain: MasterGain,
+    channel_gains: ChannelGains,
+    silence_detector: SilenceDetector,
+    input_peaks: bool,
+    flush_denormals: bool,
+    shutdown_hook: Arc<ShutdownHook>,
+    disconnect_after_frames: Option<u64>,
+    input_samples: Vec<Vec<f32>>,
+
+    /// The capture ports of every channel of every input bus.
+    in_ports: Vec<Vec<ChannelRoute<usize>>>,
+
+    /// Sorted by frame.
+    midi_in_events: Vec<DummyMidiEvent>,
+
+    /// The midi capture port of every input controller.
+    midi_in_ports: Vec<usize>,
+    midi_in_buffers: Vec<MidiControllerBuffer>,
+    midi_in_timestamps: MidiTimestamps,
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> DummyProcess<P, E> {
+    /// Run the process loop until the stream is dropped. This returns the process handler
+    /// so it is dropped outside of the audio thread.
+    fn run(self) -> P {
+        let DummyProcess {
+            mut rt_process_handler,
+            mut fatal_error_handler,
+            stream_info,
+            running,
+            frames_processed,
+            buffer_size_history,
+            cycle_buffer_sizes,
+            mut cycle_debugger,
+            mut master_gain,
+            mut channel_gains,
+            mut silence_detector,
+            input_peaks,
+            flush_denormals,
+            shutdown_hook,
+            disconnect_after_frames,
+            input_samples,
+            in_ports,
+            midi_in_events,
+            midi_in_ports,
+            mut midi_in_buffers,
+            midi_in_timestamps,
+        } = self;
+
+        let max_audio_frames = stream_info.max_audio_buffer_size as usize;
+
+        let mut audio_in_buffers = Vec::<AudioBusBuffer>::new();
+        let mut audio_out_buffers = Vec::<AudioBusBuffer>::new();
+        let mut midi_out_buffers = Vec::<MidiControllerBuffer>::new();
+
+        for bus in stream_info.audio_in.iter() {
+            let mut buffer =
+                AudioBusBuffer::for_input_bus(bus, max_audio_frames as u32, input_peaks);
+            buffer.clear_and_resize(max_audio_frames);
+            audio_in_buffers.push(buffer);
+        }
+        for bus in stream_info.audio_out.iter() {
+            audio_out_buffers.push(AudioBusBuffer::for_bus(bus, max_audio_frames as u32))
+        }
+        for _ in stream_info.midi_out.iter() {
+            midi_out_buffers.push(MidiControllerBuffer::new())
+        }
+
+        let audio_in_channels: usize = audio_in_buffers.iter().map(|b| b.num_channels()).sum();
+        let audio_out_channels: usize = audio_out_buffers.iter().map(|b| b.num_channels()).sum();
+
+        let mut fail = |error: FatalStreamError| {
+            info!("{}", error);
+
+            running.store(false, Ordering::Relaxed);
+
+            shutdown_hook.run();
+
+            if let Some(fatal_error_handler) = fatal_error_handler.take() {
+                fatal_error_handler.fatal_stream_error(error)
+            }
+        };
+
+        let start = Instant::now();
+        let mut total_frames: u64 = 0;
+        let mut cycle_sizes = cycle_buffer_sizes.iter().cycle();
+        let mut next_midi_in_event = 0;
+
+        while running.load(Ordering::Relaxed) {
+            let cycle_start_time = Instant::now();
+            let audio_frames = cycle_sizes
+                .next()
+                .map(|s| *s as usize)
+                .unwrap_or(max_audio_frames);
+
+            // The inputs are cleared every cycle even without a signal, in case the handler
+            // wrote to them.
+
+            for audio_buffer in audio_in_buffers.iter_mut() {
+                audio_buffer.clear_and_resize(audio_frames);
+            }
+            for audio_buffer in audio_out_buffers.iter_mut() {
+                audio_buffer.clear_and_resize(audio_frames);
+            }
+            for midi_buffer in midi_in_buffers.iter_mut() {
+                midi_buffer.clear();
+            }
+            for midi_buffer in midi_out_buffers.iter_mut() {
+                midi_buffer.clear();
+            }
+
+            for (audio_buffer, routes) in audio_in_buffers.iter_mut().zip(in_ports.iter()) {
+                for (channel, route) in audio_buffer.channels_mut().iter_mut().zip(routes) {
+                    for port in route.ports.iter() {
+                        let samples = match input_samples.get(*port) {
+                            Some(samples) if !samples.is_empty() => samples,
+                            _ => continue,
+                        };
+                        for (frame, s) in (total_frames..).zip(channel.iter_mut()) {
+                            *s += samples[(frame % samples.len() as u64) as usize] * route.gain;
+                        }
+                    }
+                }
+            }
+
+            let cycle_end = total_frames + audio_frames as u64;
+            while let Some(event) = midi_in_events.get(next_midi_in_event) {
+                if event.frame >= cycle_end {
+                    break;
+                }
+                next_midi_in_event += 1;
+
+                let delta_frames = midi_in_timestamps.delta_frames(
+                    total_frames,
+                    event.frame.saturating_sub(total_frames) as u32,
+                );
+                for (midi_buffer, port) in midi_in_buffers.iter_mut().zip(midi_in_ports.iter()) {
+                    if *port == event.port {
+                        if let Err(e) = midi_buffer.push_input(delta_frames, &event.data) {
+                            warn!("Dummy: dropped a midi input event: {}", e);
+                        }
+                    }
+                }
+            }
+
+            channel_gains.apply_inputs(
+                &mut audio_in_buffers,
+                audio_frames,
+                stream_info.sample_rate,
+            );
+            for audio_buffer in audio_in_buffers.iter_mut() {
+                audio_buffer.update_peaks();
+            }
+            silence_detector.process(&audio_in_buffers, audio_frames, stream_info.sample_rate);
+
+            let result = catch_process_panic(|| {
+                let _denormals = DenormalGuard::new(flush_denormals);
+                rt_process_handler.process(ProcessInfo {
+                    audio_in: audio_in_buffers.as_slice(),
+                    audio_out: audio_out_buffers.as_mut_slice(),
+                    audio_frames,
+
+                    midi_in: midi_in_buffers.as_slice(),
+                    midi_out: midi_out_buffers.as_mut_slice(),
+
+                    sample_rate: stream_info.sample_rate,
+                    transport: None,
+                    cycle_start_frame: total_frames,
+                    cycle_start_time,
+                })
+            });
+            let control = match result {
+                Ok(control) => control,
+                Err(error) => {
+                    fail(error);
+                    break;
+                }
+            };
+
+            master_gain.apply(
+                &mut audio_out_buffers,
+                audio_frames,
+                stream_info.sample_rate,
+            );
+            channel_gains.apply_outputs(
+                &mut audio_out_buffers,
+                audio_frames,
+                stream_info.sample_rate,
+            );
+
+            let frames_processed =
+                frames_processed.fetch_add(audio_frames as u64, Ordering::Relaxed);
+            buffer_size_history.push(audio_frames);
+
+            if cycle_debugger.begin_cycle() {
+                cycle_debugger.log(CycleDebugInfo {
+                    frames_processed,
+                    audio_frames,
+                    audio_in_channels,
+                    audio_out_channels,
+                    midi_in_events: midi_in_buffers.iter().map(|b| b.len()).sum(),
+                    midi_out_events: midi_out_buffers.iter().map(|b| b.len()).sum(),
+                });
+            }
+
+            total_frames += audio_frames as u64;
+
+            if control == ProcessControl::Quit {
+                info!("Dummy: the process handler stopped the stream");
+                running.store(false, Ordering::Relaxed);
+                shutdown_hook.run();
+                break;
+            }
+
+            if let Some(disconnect_after_frames) = disconnect_after_frames {
+                if total_frames >= disconnect_after_frames {
+                    let msg = format!("Dummy: simulated disconnect after {} frames", total_frames);
+
+                    fail(FatalStreamError::AudioDeviceDisconnected {
+                        backend: Backend::Dummy,
+                        reason: DisconnectReason::DeviceRemoved,
+                        message: msg,
+                    });
+                    break;
+                }
+            }
+
+            // Wait until the next cycle is due. The deadline is computed from the start of
+            // the stream so the cadence doesn't drift.
+            let deadline = start
+                + Duration::from_secs_f64(total_frames as f64 / f64::from(stream_info.sample_rate));
+            let now = Instant::now();
+            if deadline > now {
+                std::thread::sleep(deadline - now);
+            }
+        }
+
+        rt_process_handler.deinit();
+        rt_process_handler
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::RecordingProcessHandler;
+    use crate::{CycleSummary, FatalStreamError, MidiPortRole, ResampleConfig, ResampleQuality};
+    use std::sync::Mutex;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[derive(Default)]
+    struct Recorded {
+        init_sample_rate: u32,
+        cycles: usize,
+        all_silent: bool,
+        outputs_cleared: bool,
+        midi_in_empty: bool,
+        frames: Vec<usize>,
+        channels: (usize, usize),
+        midi_busses: (usize, usize),
+    }
+
+    struct TestHandler {
+        recorded: Arc<Mutex<Recorded>>,
+    }
+
+    impl RtProcessHandler for TestHandler {
+        fn init(&mut self, stream_info: &StreamInfo) {
+            let mut recorded = self.recorded.lock().unwrap();
+            recorded.init_sample_rate = stream_info.sample_rate;
+            recorded.all_silent = true;
+            recorded.outputs_cleared = true;
+            recorded.midi_in_empty = true;
+        }
+
+        fn process(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+            let mut recorded = self.recorded.lock().unwrap();
+
+            recorded.cycles += 1;
+            recorded.frames.push(proc_info.audio_frames);
+            recorded.all_silent &= proc_info
+                .audio_in
+                .iter()
+                .all(|b| b.channels().iter().flatten().all(|s| *s == 0.0));
+            recorded.midi_in_empty &= proc_info.midi_in.iter().all(|b| b.len() == 0);
+            recorded.channels = (
+                proc_info.audio_in.iter().map(|b| b.num_channels()).sum(),
+                proc_info.audio_out.iter().map(|b| b.num_channels()).sum(),
+            );
+            recorded.midi_busses = (proc_info.midi_in.len(), proc_info.midi_out.len());
+
+            // Writing to the buffers must not leak into the next cycle.
+            recorded.outputs_cleared &= proc_info
+                .audio_out
+                .iter()
+                .all(|b| b.channels().iter().flatten().all(|s| *s == 0.0));
+            for bus in proc_info.audio_out.iter_mut() {
+                for channel in bus.channels_mut() {
+                    for s in channel.iter_mut() {
+                        *s = 1.0;
+                    }
+                }
+            }
+
+            ProcessControl::Continue
+        }
+    }
+
+    struct TestErrorHandler;
+
+    impl FatalErrorHandler for TestErrorHandler {
+        fn fatal_stream_error(self, error: FatalStreamError) {
+            panic!("unexpected fatal stream error: {}", error);
+        }
+    }
+
+    pub(crate) fn test_config() -> Config {
+        Config {
+            audio_server: String::from(DUMMY_SERVER_NAME),
+            system_audio_device: String::from(DUMMY_DEVICE_NAME),
+
+            audio_in_busses: vec![AudioBusConfig {
+                id: String::from("Mic In"),
+                system_ports: vec![String::from("capture_1")],
+                ..Default::default()
+            }],
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Speakers Out"),
+                system_ports: vec![String::from("playback_1"), String::from("playback_2")],
+                ..Default::default()
+            }],
+
+            midi_server: Some(String::from(DUMMY_SERVER_NAME)),
+            midi_in_controllers: vec![MidiControllerConfig {
+                id: String::from("Keys"),
+                system_port: String::new(),
+                system_port_role: Some(MidiPortRole::DefaultHardwareIn),
+                ..Default::default()
+            }],
+
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn reports_configured_device() {
+        let backend = DummyBackend {
+            sample_rate: 44100,
+            buffer_size: 128,
+            in_channels: 1,
+            out_channels: 4,
+            midi_in_ports: 2,
+            midi_out_ports: 0,
+            cycle_buffer_sizes: Vec::new(),
+            open_delay: Duration::ZERO,
+            disconnect_after_frames: None,
+            input_samples: Vec::new(),
+            midi_in_events: Vec::new(),
+        };
+
+        let audio_server = backend.audio_server_info();
+        assert!(audio_server.available);
+        assert_eq!(audio_server.devices.len(), 1);
+
+        let device = &audio_server.devices[0];
+        assert_eq!(device.name, DUMMY_DEVICE_NAME);
+        assert_eq!(device.in_ports, vec!["capture_1"]);
+        assert_eq!(device.out_ports.len(), 4);
+        assert_eq!(device.sample_rates, vec![44100]);
+        assert_eq!(device.default_buffer_size, 128);
+        assert_eq!(device.default_out_port_right, 1);
+        assert!(device.supports_duplex(44100, 128));
+        assert!(!device.supports_duplex(48000, 128));
+        assert!(!device.supports_duplex(44100, 256));
+
+        assert_eq!(
+            audio_server
+                .device_by_name(DUMMY_DEVICE_NAME)
+                .map(|d| &d.name),
+            audio_server.default_device_info().map(|d| &d.name)
+        );
+        assert!(audio_server.device_by_name("hw:7").is_none());
+
+        let midi_server = backend.midi_server_info();
+        assert_eq!(midi_server.in_devices.len(), 2);
+        assert!(midi_server.out_devices.is_empty());
+    }
+
+    #[test]
+    fn drives_process_with_silence() {
+        let backend = DummyBackend {
+            sample_rate: 48000,
+            buffer_size: 48,
+            ..DummyBackend::default()
+        };
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+
+        let start = Instant::now();
+        let stream = backend
+            .spawn_rt_thread(
+                &test_config(),
+                TestHandler {
+                    recorded: Arc::clone(&recorded),
+                },
+                TestErrorHandler,
+            )
+            .unwrap();
+
+        assert_eq!(stream.stream_info().server_name, DUMMY_SERVER_NAME);
+        assert_eq!(stream.stream_info().max_audio_buffer_size, 48);
+        assert_eq!(
+            stream.stream_info().midi_in[0].system_port,
+            "midi_capture_1"
+        );
+
+        while stream.frames_processed() < 48 * 10 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        drop(stream);
+        let elapsed = start.elapsed();
+
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(recorded.init_sample_rate, 48000);
+        assert!(recorded.cycles >= 10);
+        assert!(recorded.frames.iter().all(|f| *f == 48));
+        assert!(recorded.all_silent);
+        assert!(recorded.outputs_cleared);
+        assert!(recorded.midi_in_empty);
+        assert_eq!(recorded.channels, (1, 2));
+        assert_eq!(recorded.midi_busses, (1, 0));
+
+        // Each cycle is 1ms, so the timer must not run ahead of the audio clock. The first
+        // cycle runs immediately.
+        assert!(recorded.cycles as u128 <= elapsed.as_millis() + 1);
+    }
+
+    #[test]
+    fn records_varying_buffer_sizes() {
+        let backend = DummyBackend {
+            buffer_size: 64,
+            cycle_buffer_sizes: vec![64, 32, 100, 16],
+            ..DummyBackend::default()
+        };
+        let config = Config {
+            buffer_size_history: Some(6),
+            ..test_config()
+        };
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+
+        let start = Instant::now();
+        let stream = backend
+            .spawn_rt_thread(
+                &config,
+                TestHandler {
+                    recorded: Arc::clone(&recorded),
+                },
+                TestErrorHandler,
+            )
+            .unwrap();
+
+        // 64 + 32 + 64 + 16 frames per round, the 100 is clamped to the buffer size.
+        while stream.frames_processed() < 176 * 2 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let recent = stream.recent_buffer_sizes();
+        drop(stream);
+
+        let recorded = recorded.lock().unwrap();
+        assert!(recorded.frames.len() >= 8);
+        assert_eq!(recorded.frames[..8], [64, 32, 64, 16, 64, 32, 64, 16]);
+
+        // Only the last 6 cycles are kept, and they match what the handler saw.
+        assert_eq!(recent.len(), 6);
+        let seen = &recorded.frames[..];
+        assert!(seen
+            .windows(6)
+            .any(|w| w.iter().zip(recent.iter()).all(|(a, b)| *a == *b as usize)));
+    }
+
+    /// The samples of each capture port, of different lengths so the loops don't line up.
+    fn test_samples() -> Vec<Vec<f32>> {
+        vec![
+            (0..50).map(|s| s as f32).collect(),
+            (1000..1037).map(|s| s as f32).collect(),
+        ]
+    }
+
+    /// Every midi input event that was recorded, with its frame counted from the start of
+    /// the stream.
+    fn midi_in_frames(cycles: &[CycleSummary]) -> Vec<(u64, Vec<u8>)> {
+        cycles
+            .iter()
+            .flat_map(|cycle| {
+                cycle.midi_in.iter().map(move |event| {
+                    (
+                        cycle.cycle_start_frame + u64::from(event.delta_frames),
+                        event.data.clone(),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn passes_input_through_the_pipeline() {
+        let backend = DummyBackend {
+            buffer_size: 32,
+            cycle_buffer_sizes: vec![32, 20],
+            input_samples: test_samples(),
+            ..DummyBackend::default()
+        };
+        let config = Config {
+            audio_in_busses: vec![AudioBusConfig {
+                id: String::from("Mic In"),
+                system_ports: vec![String::from("capture_2"), String::from("capture_1")],
+                ..Default::default()
+            }],
+            ..test_config()
+        };
+        let handler = RecordingProcessHandler::new().record_audio_in();
+        let recording = handler.recording();
+
+        let stream = backend
+            .spawn_rt_thread(&config, handler, TestErrorHandler)
+            .unwrap();
+        assert!(recording.wait_for_cycles(20, TIMEOUT));
+        drop(stream);
+
+        let cycles = recording.cycles();
+        assert!(cycles
+            .iter()
+            .zip([32, 20].iter().cycle())
+            .all(|(cycle, frames)| cycle.audio_frames == *frames));
+
+        // Every cycle starts where the previous one ended.
+        let mut next_start = 0;
+        for cycle in cycles.iter() {
+            assert_eq!(cycle.cycle_start_frame, next_start);
+            next_start += cycle.audio_frames as u64;
+        }
+
+        // The channels of the bus are fed by the second and the first capture port.
+        let samples = test_samples();
+        for cycle in cycles.iter() {
+            for (channel, port) in cycle.audio_in[0].iter().zip([1, 0].iter()) {
+                let samples = &samples[*port];
+                assert!((cycle.cycle_start_frame..)
+                    .zip(channel.iter())
+                    .all(|(frame, s)| *s == samples[(frame % samples.len() as u64) as usize]));
+            }
+        }
+    }
+
+    #[test]
+    fn delivers_midi_in_events() {
+        let note_on = vec![0x90, 60, 100];
+        let note_off = vec![0x80, 60, 0];
+
+        let backend = DummyBackend {
+            buffer_size: 64,
+            midi_in_ports: 2,
+            midi_in_events: vec![
+                DummyMidiEvent {
+                    port: 0,
+                    frame: 70,
+                    data: note_off.clone(),
+                },
+                DummyMidiEvent {
+                    port: 0,
+                    frame: 10,
+                    data: note_on.clone(),
+                },
+                // No controller is on the second port.
+                DummyMidiEvent {
+                    port: 1,
+                    frame: 5,
+                    data: note_on.clone(),
+                },
+                // The controller filters out clock messages.
+                DummyMidiEvent {
+                    port: 0,
+                    frame: 20,
+                    data: vec![0xF8],
+                },
+            ],
+            ..DummyBackend::default()
+        };
+        let mut config = test_config();
+        config.midi_in_controllers[0].filter.drop_realtime = true;
+        let handler = RecordingProcessHandler::new();
+        let recording = handler.recording();
+
+        let stream = backend
+            .spawn_rt_thread(&config, handler, TestErrorHandler)
+            .unwrap();
+        assert!(recording.wait_for_cycles(3, TIMEOUT));
+        drop(stream);
+
+        let cycles = recording.cycles();
+        assert_eq!(midi_in_frames(&cycles), vec![(10, note_on), (70, note_off)]);
+        assert_eq!(cycles[1].midi_in[0].delta_frames, 6);
+        assert!(cycles
+            .iter()
+            .flat_map(|cycle| cycle.midi_in.iter())
+            .all(|event| event.controller == 0));
+    }
+
+    #[test]
+    fn counts_midi_in_times_from_the_stream_start() {
+        let backend = DummyBackend {
+            buffer_size: 64,
+            midi_in_events: [10, 70, 200]
+                .iter()
+                .map(|frame| DummyMidiEvent {
+                    port: 0,
+                    frame: *frame,
+                    data: vec![0x90, 60, 100],
+                })
+                .collect(),
+            ..DummyBackend::default()
+        };
+        let mut config = test_config();
+        config.midi_in_timestamps = MidiTimestamps::Absolute;
+        let handler = RecordingProcessHandler::new();
+        let recording = handler.recording();
+
+        let stream = backend
+            .spawn_rt_thread(&config, handler, TestErrorHandler)
+            .unwrap();
+        assert!(recording.wait_for_cycles(4, TIMEOUT));
+        drop(stream);
+
+        let delta_frames: Vec<u32> = recording
+            .cycles()
+            .iter()
+            .flat_map(|cycle| cycle.midi_in.iter().map(|event| event.delta_frames))
+            .collect();
+        assert_eq!(delta_frames, vec![10, 70, 200]);
+    }
+
+    #[test]
+    fn runs_the_handler_at_the_resample_rate() {
+        let backend = DummyBackend {
+            sample_rate: 44100,
+            buffer_size: 441,
+            ..DummyBackend::default()
+        };
+        let mut config = test_config();
+        config.resample = Some(ResampleConfig {
+            sample_rate: 48000,
+            quality: ResampleQuality::Linear,
+        });
+        let handler = RecordingProcessHandler::new();
+        let recording = handler.recording();
+
+        let stream = backend
+            .spawn_rt_thread(&config, handler, TestErrorHandler)
+            .unwrap();
+        assert_eq!(stream.stream_info().sample_rate, 44100);
+        assert!(recording.wait_for_cycles(4, TIMEOUT));
+        assert!(stream.stop().is_some());
+
+        assert_eq!(recording.stream_info().unwrap().sample_rate, 48000);
+        let cycles = recording.cycles();
+        assert!(cycles.iter().all(|cycle| cycle.sample_rate == 48000));
+        assert!(cycles.iter().all(|cycle| cycle.audio_frames == 480));
+    }
+
+    #[test]
+    fn counts_dropped_midi_in_events() {
+        let backend = DummyBackend {
+            buffer_size: 64,
+            midi_in_events: (0..5)
+                .map(|note| DummyMidiEvent {
+                    port: 0,
+                    frame: 10,
+                    data: vec![0x90, note, 100],
+                })
+                .collect(),
+            ..DummyBackend::default()
+        };
+        let mut config = test_config();
+        config.midi_in_controllers[0].buffer_capacity = Some(2);
+        let handler = RecordingProcessHandler::new();
+        let recording = handler.recording();
+
+        let stream = backend
+            .spawn_rt_thread(&config, handler, TestErrorHandler)
+            .unwrap();
+        assert!(recording.wait_for_cycles(2, TIMEOUT));
+
+        assert_eq!(stream.midi_in_dropped_events("Keys"), Some(3));
+        assert_eq!(stream.midi_in_dropped_events("Pads"), None);
+        drop(stream);
+
+        assert_eq!(
+            midi_in_frames(&recording.cycles()),
+            vec![(10, vec![0x90, 0, 100]), (10, vec![0x90, 1, 100])]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_ports() {
+        let mut config = test_config();
+        config.audio_out_busses[0]
+            .system_ports
+            .push(String::from("playback_3"));
+
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+        let result = DummyBackend::default().spawn_rt_thread(
+            &config,
+            TestHandler {
+                recorded: Arc::clone(&recorded),
+            },
+            TestErrorHandler,
+        );
+
+        let error = match result {
+            Err(error) => error,
+            Ok(_) => panic!("spawned with an unknown port"),
+        };
+        assert!(matches!(
+            &error.error,
+            SpawnRtThreadError::SystemPortNotFound(port, _) if port == "playback_3"
+        ));
+
+        // The handler comes back, so the stream can be spawned again with a fixed config.
+        let handler = error.rt_process_handler.unwrap();
+        config.audio_out_busses[0].system_ports.pop();
+        let stream = DummyBackend::default()
+            .spawn_rt_thread(&config, handler, TestErrorHandler)
+            .unwrap();
+        assert_eq!(recorded.lock().unwrap().init_sample_rate, 48000);
+        stream.stop();
+    }
+
+    #[test]
+    fn spawn_times_out_on_slow_open() {
+        let backend = DummyBackend {
+            open_delay: Duration::from_millis(500),
+            ..DummyBackend::default()
+        };
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+
+        let start = Instant::now();
+        let result = backend.spawn_rt_thread(
+            &Config {
+                spawn_timeout: Some(Duration::from_millis(50)),
+                ..test_config()
+            },
+            TestHandler {
+                recorded: Arc::clone(&recorded),
+            },
+            TestErrorHandler,
+        );
+
+        assert!(matches!(
+            result,
+            Err(SpawnError {
+                error: SpawnRtThreadError::Timeout {
+                    phase: SpawnPhase::Open
+                },
+                rt_process_handler: Some(_),
+            })
+        ));
+        assert!(start.elapsed() < Duration::from_millis(400));
+
+        // The handler was never initialized.
+        assert_eq!(recorded.lock().unwrap().init_sample_rate, 0);
+
+        // A timeout longer than the delay does not get in the way.
+        let stream = backend.spawn_rt_thread(
+            &Config {
+                spawn_timeout: Some(Duration::from_secs(10)),
+                ..test_config()
+            },
+            TestHandler {
+                recorded: Arc::clone(&recorded),
+            },
+            TestErrorHandler,
+        );
+        assert!(stream.is_ok());
+    }
+
+    struct LoggingErrorHandler {
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FatalErrorHandler for LoggingErrorHandler {
+        fn fatal_stream_error(self, error: FatalStreamError) {
+            let entry = match error.disconnect_reason() {
+                Some(reason) => format!("fatal error: {:?}", reason),
+                None => String::from("fatal error"),
+            };
+            self.log.lock().unwrap().push(entry);
+        }
+    }
+
+    #[test]
+    fn shutdown_hook_runs_before_shutdown() {
+        let log = Arc::new(Mutex::new(Vec::<String>::new()));
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+        let hook = |log: &Arc<Mutex<Vec<String>>>| {
+            let log = Arc::clone(log);
+            move |stream_info: &StreamInfo| {
+                log.lock()
+                    .unwrap()
+                    .push(format!("hook {}", stream_info.audio_out[0].id_name));
+            }
+        };
+
+        // Dropping the handle.
+        let stream = DummyBackend::default()
+            .spawn_rt_thread(
+                &test_config(),
+                TestHandler {
+                    recorded: Arc::clone(&recorded),
+                },
+                LoggingErrorHandler {
+                    log: Arc::clone(&log),
+                },
+            )
+            .unwrap();
+        stream.on_before_shutdown(hook(&log));
+        drop(stream);
+        assert_eq!(*log.lock().unwrap(), vec!["hook Speakers Out"]);
+
+        // A disconnect.
+        log.lock().unwrap().clear();
+        let backend = DummyBackend {
+            disconnect_after_frames: Some(1024),
+            ..DummyBackend::default()
+        };
+        let stream = backend
+            .spawn_rt_thread(
+                &Config {
+                    buffer_size: Some(64),
+                    ..test_config()
+                },
+                TestHandler {
+                    recorded: Arc::clone(&recorded),
+                },
+                LoggingErrorHandler {
+                    log: Arc::clone(&log),
+                },
+            )
+            .unwrap();
+        stream.on_before_shutdown(hook(&log));
+
+        let start = Instant::now();
+        while log.lock().unwrap().len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(stream.frames_processed(), 1024);
+
+        // The hook is only called once.
+        drop(stream);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["hook Speakers Out", "fatal error: DeviceRemoved"]
+        );
+    }
+
+    #[test]
+    fn stop_returns_the_handler() {
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+
+        let stream = DummyBackend::default()
+            .spawn_rt_thread(
+                &test_config(),
+                TestHandler {
+                    recorded: Arc::clone(&recorded),
+                },
+                TestErrorHandler,
+            )
+            .unwrap();
+
+        let start = Instant::now();
+        while stream.frames_processed() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let hook_stopped = Arc::clone(&stopped);
+        stream.on_before_shutdown(move |_| hook_stopped.store(true, Ordering::Relaxed));
+
+        let handler = stream.stop().unwrap();
+        assert!(stopped.load(Ordering::Relaxed));
+        assert!(Arc::ptr_eq(&handler.recorded, &recorded));
+
+        // The audio thread is gone, so the handler isn't called anymore.
+        let cycles = recorded.lock().unwrap().cycles;
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(recorded.lock().unwrap().cycles, cycles);
+
+        // The handler can be handed to the next stream.
+        let stream = DummyBackend::default()
+            .spawn_rt_thread(&test_config(), handler, TestErrorHandler)
+            .unwrap();
+        while recorded.lock().unwrap().cycles == cycles {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(stream.stop().is_some());
+    }
+
+    struct LoggingHandler {
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RtProcessHandler for LoggingHandler {
+        fn init(&mut self, _stream_info: &StreamInfo) {}
+        fn process(&mut self, _proc_info: ProcessInfo) -> ProcessControl {
+            ProcessControl::Continue
+        }
+
+        fn deinit(&mut self) {
+            self.log.lock().unwrap().push(String::from("deinit"));
+        }
+    }
+
+    #[test]
+    fn deinit_on_stop_and_disconnect() {
+        let log = Arc::new(Mutex::new(Vec::<String>::new()));
+
+        let stream = DummyBackend::default()
+            .spawn_rt_thread(
+                &test_config(),
+                LoggingHandler {
+                    log: Arc::clone(&log),
+                },
+                LoggingErrorHandler {
+                    log: Arc::clone(&log),
+                },
+            )
+            .unwrap();
+        assert!(log.lock().unwrap().is_empty());
+        assert!(stream.stop().is_some());
+        assert_eq!(*log.lock().unwrap(), vec!["deinit"]);
+
+        log.lock().unwrap().clear();
+        let backend = DummyBackend {
+            disconnect_after_frames: Some(512),
+            ..DummyBackend::default()
+        };
+        let stream = backend
+            .spawn_rt_thread(
+                &test_config(),
+                LoggingHandler {
+                    log: Arc::clone(&log),
+                },
+                LoggingErrorHandler {
+                    log: Arc::clone(&log),
+                },
+            )
+            .unwrap();
+
+        let start = Instant::now();
+        while log.lock().unwrap().len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // Only called once, even though the handle is dropped afterwards.
+        drop(stream);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["fatal error: DeviceRemoved", "deinit"]
+        );
+    }
+
+    struct PanickingHandler {
+        cycles: usize,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RtProcessHandler for PanickingHandler {
+        fn init(&mut self, _stream_info: &StreamInfo) {}
+
+        fn process(&mut self, _proc_info: ProcessInfo) -> ProcessControl {
+            self.cycles += 1;
+            if self.cycles == 3 {
+                panic!("DSP broke on cycle {}", self.cycles);
+            }
+            ProcessControl::Continue
+        }
+
+        fn deinit(&mut self) {
+            self.log.lock().unwrap().push(String::from("deinit"));
+        }
+    }
+
+    struct ErrorLogHandler {
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FatalErrorHandler for ErrorLogHandler {
+        fn fatal_stream_error(self, error: FatalStreamError) {
+            self.log.lock().unwrap().push(error.to_string());
+        }
+    }
+
+    #[test]
+    fn process_panic_stops_the_stream() {
+        let log = Arc::new(Mutex::new(Vec::<String>::new()));
+
+        let stream = DummyBackend::default()
+            .spawn_rt_thread(
+                &Config {
+                    buffer_size: Some(64),
+                    ..test_config()
+                },
+                PanickingHandler {
+                    cycles: 0,
+                    log: Arc::clone(&log),
+                },
+                ErrorLogHandler {
+                    log: Arc::clone(&log),
+                },
+            )
+            .unwrap();
+
+        let start = Instant::now();
+        while log.lock().unwrap().len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // Only the two cycles that finished were counted.
+        assert_eq!(stream.frames_processed(), 128);
+
+        // The handler is still handed back.
+        let handler = stream.stop().unwrap();
+        assert_eq!(handler.cycles, 3);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "The realtime process handler panicked: DSP broke on cycle 3",
+                "deinit"
+            ]
+        );
+    }
+
+    struct QuittingHandler {
+        cycles: usize,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RtProcessHandler for QuittingHandler {
+        fn init(&mut self, _stream_info: &StreamInfo) {}
+
+        fn process(&mut self, _proc_info: ProcessInfo) -> ProcessControl {
+            self.cycles += 1;
+            if self.cycles == 2 {
+                ProcessControl::Quit
+            } else {
+                ProcessControl::Continue
+            }
+        }
+
+        fn deinit(&mut self) {
+            self.log.lock().unwrap().push(String::from("deinit"));
+        }
+    }
+
+    #[test]
+    fn process_handler_can_stop_the_stream() {
+        let log = Arc::new(Mutex::new(Vec::<String>::new()));
+
+        let stream = DummyBackend::default()
+            .spawn_rt_thread(
+                &Config {
+                    buffer_size: Some(64),
+                    ..test_config()
+                },
+                QuittingHandler {
+                    cycles: 0,
+                    log: Arc::clone(&log),
+                },
+                ErrorLogHandler {
+                    log: Arc::clone(&log),
+                },
+            )
+            .unwrap();
+
+        let start = Instant::now();
+        while log.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // The cycle that quit is still counted, and no error is reported.
+        assert_eq!(stream.frames_processed(), 128);
+        let handler = stream.stop().unwrap();
+        assert_eq!(handler.cycles, 2);
+        assert_eq!(*log.lock().unwrap(), vec!["deinit"]);
+    }
+}
@@ -0,0 +1,593 @@
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::{AudioServerInfo, Backend, DummyBackend, EnumerationError, MidiServerInfo};
+
+/// Enumerate the devices of a single backend, blocking until it is done.
+///
+/// Unlike `DevicesInfo::new()`, this only talks to the server of the given backend, so a
+/// UI can enumerate just the backend the user selected. The returned server is marked as
+/// unavailable if its server isn't running.
+pub fn enumerate_audio_devices(backend: Backend) -> Result<AudioServerInfo, EnumerationError> {
+    start_audio_device_enumeration(backend)?.wait()
+}
+
+/// Enumerate the devices of a single backend, giving up after `timeout`.
+///
+/// See `PendingEnumeration::wait_timeout()`.
+pub fn enumerate_audio_devices_timeout(
+    backend: Backend,
+    timeout: Duration,
+) -> Result<AudioServerInfo, EnumerationError> {
+    start_audio_device_enumeration(backend)?.wait_timeout(timeout)
+}
+
+/// Start enumerating the devices of a single backend on a helper thread.
+///
+/// This returns immediately. Every call gets its own thread, so a backend that hangs
+/// doesn't hold up the enumeration of any other backend.
+pub fn start_audio_device_enumeration(
+    backend: Backend,
+) -> Result<PendingEnumeration, EnumerationError> {
+    if !backend.is_compiled() {
+        return Err(EnumerationError::BackendNotCompiled(backend));
+    }
+
+    let (result_tx, result_rx) = mpsc::channel();
+
+    std::thread::Builder::new()
+        .name(format!("rusty-daw-io {} enumeration", backend))
+        .spawn(move || {
+            // The receiver is gone if the enumeration was cancelled or timed out.
+            let _ = result_tx.send(enumerate_audio_server(backend));
+        })
+        .map_err(|_| EnumerationError::EnumerationThreadFailed(backend))?;
+
+    Ok(PendingEnumeration { backend, result_rx })
+}
+
+/// Enumerate the midi devices of a single backend, blocking until it is done.
+///
+/// The returned server is marked as unavailable if its server isn't running. Backends
+/// without midi (such as CPAL) return a server without any devices.
+pub fn enumerate_midi_devices(backend: Backend) -> Result<MidiServerInfo, EnumerationError> {
+    if !backend.is_compiled() {
+        return Err(EnumerationError::BackendNotCompiled(backend));
+    }
+
+    enumerate_midi_server(backend).ok_or(EnumerationError::BackendNotCompiled(backend))
+}
+
+/// Enumerate the audio and the midi devices of a single backend, blocking until it is
+/// done.
+///
+/// This is quicker than `enumerate_audio_devices()` followed by `enumerate_midi_devices()`,
+/// since backends that have both (such as Jack) only connect to their server once.
+pub fn enumerate_devices(
+    backend: Backend,
+) -> Result<(AudioServerInfo, MidiServerInfo), EnumerationError> {
+    if !backend.is_compiled() {
+        return Err(EnumerationError::BackendNotCompiled(backend));
+    }
+
+    enumerate_servers(backend).ok_or(EnumerationError::BackendNotCompiled(backend))
+}
+
+/// Enumerate the audio devices of the Jack server with the given name (as given to
+/// `jackd --name`), blocking until it is done. Every other enumeration only sees the
+/// default Jack server.
+///
+/// Use the same name for `Config::jack_server_name` to spawn a stream on that server.
+pub fn enumerate_jack_server_audio_devices(
+    server_name: &str,
+) -> Result<AudioServerInfo, EnumerationError> {
+    #[cfg(target_os = "linux")]
+    {
+        Ok(crate::linux::enumerate_named_jack_audio_server(server_name))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = server_name;
+        Err(EnumerationError::BackendNotCompiled(Backend::Jack))
+    }
+}
+
+/// Enumerate the midi devices of the Jack server with the given name, blocking until it is
+/// done.
+///
+/// See `enumerate_jack_server_audio_devices()`.
+pub fn enumerate_jack_server_midi_devices(
+    server_name: &str,
+) -> Result<MidiServerInfo, EnumerationError> {
+    #[cfg(target_os = "linux")]
+    {
+        Ok(crate::linux::enumerate_named_jack_midi_server(server_name))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = server_name;
+        Err(EnumerationError::BackendNotCompiled(Backend::Jack))
+    }
+}
+
+pub(crate) fn enumerate_audio_server(backend: Backend) -> Option<AudioServerInfo> {
+    let mut server = AudioServerInfo::new(String::from(backend.as_str()), None);
+    // The server is marked as unavailable if it fails.
+    let _ = refresh_audio_server(backend, &mut server)?;
+
+    Some(server)
+}
+
+/// Refresh the devices of a single audio server, or return `None` if the backend isn't
+/// compiled in.
+fn refresh_audio_server(
+    backend: Backend,
+    server: &mut AudioServerInfo,
+) -> Option<Result<(), EnumerationError>> {
+    if backend == Backend::Dummy {
+        *server = DummyBackend::default().audio_server_info();
+        return Some(Ok(()));
+    }
+
+    #[cfg(feature = "cpal")]
+    if backend == Backend::Cpal {
+        return Some(crate::cpal_backend::refresh_audio_server(server));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    {
+        #[cfg(target_os = "linux")]
+        use crate::linux::refresh_audio_server;
+        #[cfg(target_os = "windows")]
+        use crate::windows::refresh_audio_server;
+
+        refresh_audio_server(backend, server)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    {
+        let _ = server;
+        None
+    }
+}
+
+pub(crate) fn enumerate_midi_server(backend: Backend) -> Option<MidiServerInfo> {
+    if backend == Backend::Dummy {
+        return Some(DummyBackend::default().midi_server_info());
+    }
+
+    // CPAL has no MIDI, so its server never has any devices.
+    #[cfg(feature = "cpal")]
+    if backend == Backend::Cpal {
+        return Some(MidiServerInfo::new(String::from(backend.as_str()), None));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    {
+        #[cfg(target_os = "linux")]
+        use crate::linux::enumerate_midi_server;
+        #[cfg(target_os = "windows")]
+        use crate::windows::enumerate_midi_server;
+
+        enumerate_midi_server(backend)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    None
+}
+
+pub(crate) fn enumerate_servers(backend: Backend) -> Option<(AudioServerInfo, MidiServerInfo)> {
+    #[cfg(target_os = "linux")]
+    if matches!(backend, Backend::Jack | Backend::Alsa) {
+        return crate::linux::enumerate_servers(backend);
+    }
+
+    // Every other backend has nothing to share between its audio and midi servers.
+    enumerate_audio_server(backend).zip(enumerate_midi_server(backend))
+}
+
+/// Whether a backend can be used, as returned by `available_backends()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendStatus {
+    pub backend: Backend,
+    /// Whether the backend was compiled into the current build. See `Backend::is_compiled()`.
+    pub compiled: bool,
+    /// Whether a stream can be spawned on the backend right now, e.g. for Jack, whether a
+    /// client could connect to the server.
+    pub available: bool,
+    /// Why the backend isn't available, e.g. "The Jack server is not running". This is
+    /// meant to be shown to the user next to the greyed out backend.
+    pub detail: Option<String>,
+}
+
+impl BackendStatus {
+    fn probe(backend: Backend) -> Self {
+        if !backend.is_compiled() {
+            return Self::unavailable(backend, EnumerationError::BackendNotCompiled(backend));
+        }
+
+        let mut server = AudioServerInfo::new(String::from(backend.as_str()), None);
+        match refresh_audio_server(backend, &mut server) {
+            Some(Ok(())) if server.available => Self {
+                backend,
+                compiled: true,
+                available: true,
+                detail: None,
+            },
+            Some(Ok(())) => Self::unavailable(backend, EnumerationError::ServerNotRunning(backend)),
+            Some(Err(e)) => Self::unavailable(backend, e),
+            None => Self::unavailable(backend, EnumerationError::BackendNotCompiled(backend)),
+        }
+    }
+
+    fn unavailable(backend: Backend, error: EnumerationError) -> Self {
+        Self {
+            backend,
+            compiled: backend.is_compiled(),
+            available: false,
+            detail: Some(error.to_string()),
+        }
+    }
+}
+
+/// The status of every backend this crate knows about, in the order of `Backend::ALL`.
+///
+/// Every compiled-in backend is probed by talking to its server (for Jack, by connecting a
+/// client), which blocks until each server has answered. Use `available_backends_timeout()`
+/// if a misbehaving server shouldn't be able to hang the caller.
+pub fn available_backends() -> Vec<BackendStatus> {
+    Backend::ALL
+        .iter()
+        .map(|b| BackendStatus::probe(*b))
+        .collect()
+}
+
+/// The status of every backend, giving up on each one that hasn't answered after `timeout`.
+///
+/// The backends are probed in parallel, each on a helper thread of its own, so this returns
+/// after `timeout` at most. A backend that timed out is marked as unavailable.
+pub fn available_backends_timeout(timeout: Duration) -> Vec<BackendStatus> {
+    let deadline = std::time::Instant::now() + timeout;
+
+    let pending: Vec<(Backend, Option<mpsc::Receiver<BackendStatus>>)> = Backend::ALL
+        .iter()
+        .map(|backend| {
+            let backend = *backend;
+            let (status_tx, status_rx) = mpsc::channel();
+
+            let spawned = std::thread::Builder::new()
+                .name(format!("rusty-daw-io {} enumeration", backend))
+                .spawn(move || {
+                    // The receiver is gone if the probe timed out.
+                    let _ = status_tx.send(BackendStatus::probe(backend));
+                });
+
+            (backend, spawned.ok().map(|_| status_rx))
+        })
+        .collect();
+
+    pending
+        .into_iter()
+        .map(|(backend, status_rx)| {
+            let status_rx = match status_rx {
+                Some(status_rx) => status_rx,
+                None => {
+                    return BackendStatus::unavailable(
+                        backend,
+                        EnumerationError::EnumerationThreadFailed(backend),
+                    )
+                }
+            };
+
+            let timeout = deadline.saturating_duration_since(std::time::Instant::now());
+            match status_rx.recv_timeout(timeout) {
+                Ok(status) => status,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    BackendStatus::unavailable(backend, EnumerationError::TimedOut(backend))
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => BackendStatus::unavailable(
+                    backend,
+                    EnumerationError::EnumerationThreadFailed(backend),
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Refresh `server` on a helper thread, giving up after `timeout`.
+///
+/// If this times out (or the thread fails) `server` is left without any devices and is
+/// marked as unavailable. The helper thread keeps running until `refresh` returns, but its
+/// result is thrown away. Otherwise this returns the result of `refresh`.
+pub(crate) fn refresh_with_timeout(
+    server: &mut AudioServerInfo,
+    backend: Backend,
+    timeout: Duration,
+    refresh: fn(&mut AudioServerInfo) -> Result<(), EnumerationError>,
+) -> Result<(), EnumerationError> {
+    let mut refreshed = AudioServerInfo::new(server.name.clone(), server.version.clone());
+    let (result_tx, result_rx) = mpsc::channel();
+
+    let spawned = std::thread::Builder::new()
+        .name(format!("rusty-daw-io {} enumeration", backend))
+        .spawn(move || {
+            let result = refresh(&mut refreshed);
+            let _ = result_tx.send((refreshed, result));
+        });
+
+    let result = match spawned {
+        Ok(_) => result_rx.recv_timeout(timeout),
+        Err(_) => Err(mpsc::RecvTimeoutError::Disconnected),
+    };
+
+    match result {
+        Ok((refreshed, result)) => {
+            *server = refreshed;
+            result
+        }
+        Err(e) => {
+            server.devices.clear();
+            server.available = false;
+
+            Err(match e {
+                mpsc::RecvTimeoutError::Timeout => EnumerationError::TimedOut(backend),
+                mpsc::RecvTimeoutError::Disconnected => {
+                    EnumerationError::EnumerationThreadFailed(backend)
+                }
+            })
+        }
+    }
+}
+
+/// The maximum number of playback channels of a device, or `None` if the device can't be
+/// found or opened.
+///
+/// Some backends (such as ALSA) have no fixed maximum, so the device is opened and tried with
+/// decreasing channel counts until one is accepted. Every other backend (such as Jack) reports
+/// a fixed number of ports, so this just enumerates the backend and returns that.
+///
+/// This blocks until the device has been probed.
+pub fn probe_max_channels(backend: Backend, device: &str) -> Option<u16> {
+    #[cfg(all(target_os = "linux", feature = "alsa-backend"))]
+    if backend == Backend::Alsa {
+        return crate::linux::probe_alsa_max_channels(device);
+    }
+
+    enumerate_audio_server(backend)?
+        .devices
+        .iter()
+        .find(|d| d.name == device)
+        .map(|d| d.out_ports.len() as u16)
+}
+
+/// Try each of the channel counts in `candidates`, largest first, and return the first one that
+/// `try_channels` accepts.
+#[cfg(any(test, all(target_os = "linux", feature = "alsa-backend")))]
+pub(crate) fn probe_channels<F: FnMut(u16) -> bool>(
+    candidates: &[u16],
+    mut try_channels: F,
+) -> Option<u16> {
+    let mut candidates = candidates.to_vec();
+    candidates.sort_unstable_by(|a, b| b.cmp(a));
+
+    candidates
+        .into_iter()
+        .find(|channels| try_channels(*channels))
+}
+
+/// An enumeration started by `start_audio_device_enumeration()`.
+///
+/// The helper thread can't be interrupted while it is talking to the server. Cancelling
+/// (or dropping) this, or timing out, only means that its result is thrown away once it
+/// is done.
+pub struct PendingEnumeration {
+    backend: Backend,
+    result_rx: mpsc::Receiver<Option<AudioServerInfo>>,
+}
+
+impl PendingEnumeration {
+    /// The backend that is being enumerated.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Returns the result if the enumeration is done, without blocking.
+    pub fn try_result(&self) -> Option<Result<AudioServerInfo, EnumerationError>> {
+        match self.result_rx.try_recv() {
+            Ok(server) => Some(self.result(server)),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                Some(Err(EnumerationError::EnumerationThreadFailed(self.backend)))
+            }
+        }
+    }
+
+    /// Block until the enumeration is done.
+    pub fn wait(self) -> Result<AudioServerInfo, EnumerationError> {
+        match self.result_rx.recv() {
+            Ok(server) => self.result(server),
+            Err(_) => Err(EnumerationError::EnumerationThreadFailed(self.backend)),
+        }
+    }
+
+    /// Block until the enumeration is done, or return `EnumerationError::TimedOut` if it
+    /// takes longer than `timeout`.
+    pub fn wait_timeout(self, timeout: Duration) -> Result<AudioServerInfo, EnumerationError> {
+        match self.result_rx.recv_timeout(timeout) {
+            Ok(server) => self.result(server),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(EnumerationError::TimedOut(self.backend)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err(EnumerationError::EnumerationThreadFailed(self.backend))
+            }
+        }
+    }
+
+    /// Stop waiting for the enumeration.
+    pub fn cancel(self) {}
+
+    fn result(&self, server: Option<AudioServerInfo>) -> Result<AudioServerInfo, EnumerationError> {
+        server.ok_or(EnumerationError::BackendNotCompiled(self.backend))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DUMMY_DEVICE_NAME;
+
+    #[test]
+    fn enumerates_single_backend() {
+        let server = enumerate_audio_devices(Backend::Dummy).unwrap();
+        assert_eq!(server.name, Backend::Dummy.as_str());
+        assert!(server.available);
+        assert_eq!(server.devices[0].name, DUMMY_DEVICE_NAME);
+
+        let server =
+            enumerate_audio_devices_timeout(Backend::Dummy, Duration::from_secs(10)).unwrap();
+        assert!(server.available);
+
+        assert!(matches!(
+            enumerate_audio_devices(Backend::CoreAudio),
+            Err(EnumerationError::BackendNotCompiled(Backend::CoreAudio))
+        ));
+    }
+
+    #[test]
+    fn reports_backend_status() {
+        for statuses in [
+            available_backends(),
+            available_backends_timeout(Duration::from_secs(10)),
+        ] {
+            assert_eq!(statuses.len(), Backend::ALL.len());
+
+            let dummy = statuses
+                .iter()
+                .find(|s| s.backend == Backend::Dummy)
+                .unwrap();
+            assert!(dummy.compiled);
+            assert!(dummy.available);
+            assert_eq!(dummy.detail, None);
+
+            let core_audio = statuses
+                .iter()
+                .find(|s| s.backend == Backend::CoreAudio)
+                .unwrap();
+            assert!(!core_audio.compiled);
+            assert!(!core_audio.available);
+            assert_eq!(
+                core_audio.detail.as_deref(),
+                Some("The CoreAudio backend was not compiled into this build")
+            );
+        }
+    }
+
+    #[test]
+    fn enumerates_audio_and_midi_together() {
+        let (audio, midi) = enumerate_devices(Backend::Dummy).unwrap();
+        assert!(audio.available);
+        assert_eq!(audio.devices[0].name, DUMMY_DEVICE_NAME);
+        assert_eq!(midi, DummyBackend::default().midi_server_info());
+
+        let midi = enumerate_midi_devices(Backend::Dummy).unwrap();
+        assert!(midi.available);
+
+        assert!(matches!(
+            enumerate_devices(Backend::CoreAudio),
+            Err(EnumerationError::BackendNotCompiled(Backend::CoreAudio))
+        ));
+    }
+
+    #[test]
+    fn probes_max_channels() {
+        let candidates = [1, 2, 4, 6, 8, 16, 32];
+
+        // A device that accepts anything up to 10 channels.
+        let mut tried = Vec::new();
+        let max = probe_channels(&candidates, |channels| {
+            tried.push(channels);
+            channels <= 10
+        });
+        assert_eq!(max, Some(8));
+        assert_eq!(tried, vec![32, 16, 8]);
+
+        assert_eq!(
+            probe_channels(&candidates, |channels| channels <= 64),
+            Some(32)
+        );
+        assert_eq!(probe_channels(&candidates, |_| false), None);
+
+        // Backends with a fixed number of ports just report it.
+        assert_eq!(
+            probe_max_channels(Backend::Dummy, DUMMY_DEVICE_NAME),
+            Some(2)
+        );
+        assert_eq!(probe_max_channels(Backend::Dummy, "Not A Device"), None);
+    }
+
+    fn hanging_refresh(_server: &mut AudioServerInfo) -> Result<(), EnumerationError> {
+        std::thread::sleep(Duration::from_secs(2));
+        Ok(())
+    }
+
+    fn dummy_refresh(server: &mut AudioServerInfo) -> Result<(), EnumerationError> {
+        *server = DummyBackend::default().audio_server_info();
+        Ok(())
+    }
+
+    fn stopped_refresh(server: &mut AudioServerInfo) -> Result<(), EnumerationError> {
+        server.available = false;
+        Err(EnumerationError::ServerNotRunning(Backend::Jack))
+    }
+
+    #[test]
+    fn refresh_times_out() {
+        let mut server = DummyBackend::default().audio_server_info();
+
+        let start = std::time::Instant::now();
+        assert!(matches!(
+            refresh_with_timeout(
+                &mut server,
+                Backend::Jack,
+                Duration::from_millis(10),
+                hanging_refresh
+            ),
+            Err(EnumerationError::TimedOut(Backend::Jack))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!server.available);
+        assert!(server.devices.is_empty());
+
+        refresh_with_timeout(&mut server, Backend::Dummy, Duration::MAX, dummy_refresh).unwrap();
+        assert!(server.available);
+        assert_eq!(server.devices.len(), 1);
+
+        // The reason a server is unavailable is passed on.
+        assert!(matches!(
+            refresh_with_timeout(&mut server, Backend::Jack, Duration::MAX, stopped_refresh),
+            Err(EnumerationError::ServerNotRunning(Backend::Jack))
+        ));
+        assert!(!server.available);
+    }
+
+    #[test]
+    fn pending_enumeration_can_be_polled() {
+        let pending = start_audio_device_enumeration(Backend::Dummy).unwrap();
+        assert_eq!(pending.backend(), Backend::Dummy);
+
+        let start = std::time::Instant::now();
+        let server = loop {
+            if let Some(result) = pending.try_result() {
+                break result.unwrap();
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert!(server.available);
+
+        start_audio_device_enumeration(Backend::Dummy)
+            .unwrap()
+            .cancel();
+    }
+}
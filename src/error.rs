@@ -1,11 +1,50 @@
+use crate::{Backend, BufferSizeRange};
+
+/// The phases of spawning a stream, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnPhase {
+    /// Connecting to the audio server or opening the device.
+    Open,
+    /// Creating the busses and controllers.
+    RegisterPorts,
+    /// Starting the audio thread.
+    Activate,
+    /// Connecting the busses and controllers to the system ports.
+    Connect,
+}
+
+impl std::fmt::Display for SpawnPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpawnPhase::Open => write!(f, "open"),
+            SpawnPhase::RegisterPorts => write!(f, "register ports"),
+            SpawnPhase::Activate => write!(f, "activate"),
+            SpawnPhase::Connect => write!(f, "connect"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum SpawnRtThreadError {
     AudioServerUnavailable(String),
+    ServerNotRunning(String),
+    ServerStartFailed(String),
+    BackendNotCompiled(Backend),
     SystemDeviceNotFound(String),
     SystemHalfDuplexDeviceNotFound(String),
     SystemPortNotFound(String, String),
     NoSystemPortsGiven(String),
     IdNotUnique(String),
+    PortNameNotUnique(String),
+    ChannelLayoutMismatch(String),
+    UnsupportedBufferSize {
+        requested: u32,
+        range: BufferSizeRange,
+    },
+    Timeout {
+        phase: SpawnPhase,
+    },
+    RtPriorityDenied(String),
     PlatformSpecific(Box<dyn std::error::Error + Send + 'static>),
 }
 
@@ -17,6 +56,19 @@ impl std::fmt::Display for SpawnRtThreadError {
             SpawnRtThreadError::AudioServerUnavailable(server) => {
                 write!(f, "The audio sever is unavailable: {}", server)
             }
+            SpawnRtThreadError::ServerNotRunning(server) => {
+                write!(f, "The audio server is not running: {}", server)
+            }
+            SpawnRtThreadError::ServerStartFailed(server) => {
+                write!(f, "The audio server could not be started: {}", server)
+            }
+            SpawnRtThreadError::BackendNotCompiled(backend) => {
+                write!(
+                    f,
+                    "The {} backend was not compiled into this build",
+                    backend
+                )
+            }
             SpawnRtThreadError::SystemDeviceNotFound(device) => {
                 write!(f, "The system audio device {} could not be found", device)
             }
@@ -41,6 +93,41 @@ impl std::fmt::Display for SpawnRtThreadError {
             SpawnRtThreadError::IdNotUnique(id) => {
                 write!(f, "Two or more busses/controllers have the same id {}", id,)
             }
+            SpawnRtThreadError::PortNameNotUnique(id) => {
+                write!(
+                    f,
+                    "The bus/controller with id {} would create a port with the same name as another bus/controller",
+                    id
+                )
+            }
+            SpawnRtThreadError::ChannelLayoutMismatch(id) => {
+                write!(
+                    f,
+                    "The channel layout of the bus with id {} doesn't have one channel per system port",
+                    id
+                )
+            }
+            SpawnRtThreadError::UnsupportedBufferSize { requested, range } => {
+                write!(
+                    f,
+                    "The buffer size of {} frames is outside the range the device supports ({} to {} frames)",
+                    requested, range.min, range.max
+                )
+            }
+            SpawnRtThreadError::Timeout { phase } => {
+                write!(
+                    f,
+                    "Spawning the stream timed out during the {} phase",
+                    phase
+                )
+            }
+            SpawnRtThreadError::RtPriorityDenied(e) => {
+                write!(
+                    f,
+                    "The OS denied the realtime priority of the audio thread: {}",
+                    e
+                )
+            }
             SpawnRtThreadError::PlatformSpecific(e) => {
                 write!(f, "Platform error: {}", e)
             }
@@ -48,23 +135,234 @@ impl std::fmt::Display for SpawnRtThreadError {
     }
 }
 
+/// The error from `spawn_rt_thread()`, which gives back the process handler so the stream
+/// can be spawned again (e.g. with a fixed `Config`) without building a new one.
+pub struct SpawnError<P> {
+    pub error: SpawnRtThreadError,
+
+    /// The handler given to `spawn_rt_thread()`. If its `init` was called, so was its
+    /// `deinit`.
+    ///
+    /// This is `None` if the handler couldn't be recovered, which happens when Jack fails to
+    /// activate the client, or when spawning timed out while the audio thread was still
+    /// opening the device.
+    pub rt_process_handler: Option<P>,
+}
+
+impl<P> SpawnError<P> {
+    pub(crate) fn new(error: SpawnRtThreadError, rt_process_handler: Option<P>) -> Self {
+        Self {
+            error,
+            rt_process_handler,
+        }
+    }
+}
+
+impl<P> std::fmt::Debug for SpawnError<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpawnError")
+            .field("error", &self.error)
+            .field(
+                "rt_process_handler",
+                &self.rt_process_handler.as_ref().map(|_| ".."),
+            )
+            .finish()
+    }
+}
+
+impl<P> std::error::Error for SpawnError<P> {}
+
+impl<P> std::fmt::Display for SpawnError<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<P> From<SpawnError<P>> for SpawnRtThreadError {
+    fn from(e: SpawnError<P>) -> Self {
+        e.error
+    }
+}
+
+/// The error from adding a bus or controller to a running stream, e.g. with
+/// `StreamHandle::add_audio_out_bus()`.
+#[derive(Debug)]
+pub enum AddBusError {
+    /// Only the Jack backend can add busses/controllers while the stream runs.
+    NotSupported(Backend),
+    /// The stream has already stopped.
+    StreamStopped,
+    /// The stream was spawned without Jack midi, e.g. because `Config::midi_server` is
+    /// `None`.
+    MidiUnavailable,
+    /// The bus/controller would not be valid in the config of the stream.
+    InvalidConfig(SpawnRtThreadError),
+    /// Too many busses/controllers (more than 16) were added before the audio thread could
+    /// pick them up. Nothing was added, so try again after the next process cycle.
+    QueueFull,
+    /// The stream doesn't open this side of the device (see `Config::direction`).
+    DirectionNotOpened,
+}
+
+impl std::error::Error for AddBusError {}
+
+impl std::fmt::Display for AddBusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddBusError::NotSupported(backend) => {
+                write!(
+                    f,
+                    "The {} backend can't add busses/controllers to a running stream",
+                    backend
+                )
+            }
+            AddBusError::StreamStopped => {
+                write!(f, "The stream has already stopped")
+            }
+            AddBusError::MidiUnavailable => {
+                write!(f, "The stream was spawned without midi")
+            }
+            AddBusError::InvalidConfig(e) => {
+                write!(f, "Invalid config: {}", e)
+            }
+            AddBusError::QueueFull => {
+                write!(
+                    f,
+                    "Too many busses/controllers were added before the audio thread could pick them up"
+                )
+            }
+            AddBusError::DirectionNotOpened => {
+                write!(f, "The stream doesn't open this side of the device")
+            }
+        }
+    }
+}
+
+impl From<SpawnRtThreadError> for AddBusError {
+    fn from(e: SpawnRtThreadError) -> Self {
+        AddBusError::InvalidConfig(e)
+    }
+}
+
+/// The error from `StreamHandle::set_freewheel()`.
+#[derive(Debug)]
+pub enum FreewheelError {
+    /// Only the Jack backend has a freewheel mode.
+    NotSupported(Backend),
+    /// The stream has already stopped.
+    StreamStopped,
+    /// The audio server didn't switch freewheel mode.
+    Refused,
+}
+
+impl std::error::Error for FreewheelError {}
+
+impl std::fmt::Display for FreewheelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FreewheelError::NotSupported(backend) => {
+                write!(f, "The {} backend has no freewheel mode", backend)
+            }
+            FreewheelError::StreamStopped => {
+                write!(f, "The stream has already stopped")
+            }
+            FreewheelError::Refused => {
+                write!(f, "The audio server didn't switch freewheel mode")
+            }
+        }
+    }
+}
+
+/// The error from `StreamHandle::set_channel_gain()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelGainError {
+    /// The stream has no audio bus with this ID. Busses added to a running stream (e.g.
+    /// with `StreamHandle::add_audio_out_bus()`) are always at unity gain.
+    BusNotFound(String),
+    /// The bus has fewer channels than this.
+    ChannelNotFound { bus_id: String, channel: usize },
+}
+
+impl std::error::Error for ChannelGainError {}
+
+impl std::fmt::Display for ChannelGainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelGainError::BusNotFound(bus_id) => {
+                write!(f, "The stream has no audio bus with ID {}", bus_id)
+            }
+            ChannelGainError::ChannelNotFound { bus_id, channel } => {
+                write!(f, "The audio bus {} has no channel {}", bus_id, channel)
+            }
+        }
+    }
+}
+
+/// Why a stream lost its audio server or device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The audio server shut down, e.g. the Jack server was stopped. Spawning the stream
+    /// again once the server is back is likely to work.
+    ServerQuit,
+    /// The device was unplugged or otherwise went away. Enumerate the devices again
+    /// before spawning the stream again.
+    DeviceRemoved,
+    /// The audio server kicked this client out, e.g. because it took too long to process
+    /// a cycle.
+    Killed,
+}
+
 #[derive(Debug)]
 pub enum FatalStreamError {
-    AudioServerDisconnected(String),
-    AudioDeviceDisconnected(String),
+    AudioServerDisconnected {
+        backend: Backend,
+        reason: DisconnectReason,
+        /// A description of what happened, for the user.
+        message: String,
+    },
+    AudioDeviceDisconnected {
+        backend: Backend,
+        reason: DisconnectReason,
+        /// A description of what happened, for the user.
+        message: String,
+    },
+    ProcessPanicked(String),
     PlatformSpecific(Box<dyn std::error::Error + Send + 'static>),
 }
 
+impl FatalStreamError {
+    /// The backend that lost its audio server or device, if that's what happened.
+    pub fn backend(&self) -> Option<Backend> {
+        match self {
+            FatalStreamError::AudioServerDisconnected { backend, .. }
+            | FatalStreamError::AudioDeviceDisconnected { backend, .. } => Some(*backend),
+            _ => None,
+        }
+    }
+
+    /// Why the audio server or device was lost, if that's what happened.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        match self {
+            FatalStreamError::AudioServerDisconnected { reason, .. }
+            | FatalStreamError::AudioDeviceDisconnected { reason, .. } => Some(*reason),
+            _ => None,
+        }
+    }
+}
+
 impl std::error::Error for FatalStreamError {}
 
 impl std::fmt::Display for FatalStreamError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            FatalStreamError::AudioServerDisconnected(server) => {
-                write!(f, "The audio sever was disconnected: {}", server)
+            FatalStreamError::AudioServerDisconnected { message, .. } => {
+                write!(f, "The audio sever was disconnected: {}", message)
             }
-            FatalStreamError::AudioDeviceDisconnected(device) => {
-                write!(f, "The audio device was disconnected: {}", device)
+            FatalStreamError::AudioDeviceDisconnected { message, .. } => {
+                write!(f, "The audio device was disconnected: {}", message)
+            }
+            FatalStreamError::ProcessPanicked(msg) => {
+                write!(f, "The realtime process handler panicked: {}", msg)
             }
             FatalStreamError::PlatformSpecific(e) => {
                 write!(f, "Platform error: {}", e)
@@ -72,3 +370,222 @@ impl std::fmt::Display for FatalStreamError {
         }
     }
 }
+
+#[derive(Debug)]
+pub enum OfflineRenderError {
+    InvalidConfig(SpawnRtThreadError),
+    InvalidBlockSize,
+    OutputBusCountMismatch {
+        expected: usize,
+        got: usize,
+    },
+    OutputChannelCountMismatch {
+        bus_id: String,
+        expected: usize,
+        got: usize,
+    },
+}
+
+impl std::error::Error for OfflineRenderError {}
+
+impl std::fmt::Display for OfflineRenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OfflineRenderError::InvalidConfig(e) => {
+                write!(f, "Invalid config: {}", e)
+            }
+            OfflineRenderError::InvalidBlockSize => {
+                write!(f, "The block size must be greater than zero")
+            }
+            OfflineRenderError::OutputBusCountMismatch { expected, got } => {
+                write!(
+                    f,
+                    "Expected output buffers for {} busses, got {}",
+                    expected, got
+                )
+            }
+            OfflineRenderError::OutputChannelCountMismatch {
+                bus_id,
+                expected,
+                got,
+            } => {
+                write!(
+                    f,
+                    "Expected {} output buffers for the bus with id {}, got {}",
+                    expected, bus_id, got
+                )
+            }
+        }
+    }
+}
+
+impl From<SpawnRtThreadError> for OfflineRenderError {
+    fn from(e: SpawnRtThreadError) -> Self {
+        OfflineRenderError::InvalidConfig(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum EnumerationError {
+    BackendNotCompiled(Backend),
+    TimedOut(Backend),
+    EnumerationThreadFailed(Backend),
+    /// The server of the backend isn't running, e.g. Jack was never started.
+    ServerNotRunning(Backend),
+    /// The OS denied access to the devices of the backend, e.g. because the user isn't in
+    /// the `audio` group.
+    PermissionDenied(Backend),
+    PlatformSpecific(Box<dyn std::error::Error + Send + 'static>),
+}
+
+impl std::error::Error for EnumerationError {}
+
+impl std::fmt::Display for EnumerationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnumerationError::BackendNotCompiled(backend) => {
+                write!(
+                    f,
+                    "The {} backend was not compiled into this build",
+                    backend
+                )
+            }
+            EnumerationError::TimedOut(backend) => {
+                write!(f, "Timed out while enumerating the {} devices", backend)
+            }
+            EnumerationError::EnumerationThreadFailed(backend) => {
+                write!(
+                    f,
+                    "The thread enumerating the {} devices exited without a result",
+                    backend
+                )
+            }
+            EnumerationError::ServerNotRunning(backend) => {
+                write!(f, "The {} server is not running", backend)
+            }
+            EnumerationError::PermissionDenied(backend) => {
+                write!(f, "Permission to access the {} devices was denied", backend)
+            }
+            EnumerationError::PlatformSpecific(e) => {
+                write!(f, "Platform error: {}", e)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigBuildError {
+    EmptyId,
+    IdNotUnique(String),
+    PortNameNotUnique(String),
+}
+
+impl std::error::Error for ConfigBuildError {}
+
+impl std::fmt::Display for ConfigBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigBuildError::EmptyId => {
+                write!(f, "A bus/controller has an empty id")
+            }
+            ConfigBuildError::IdNotUnique(id) => {
+                write!(f, "Two or more busses/controllers have the same id {}", id)
+            }
+            ConfigBuildError::PortNameNotUnique(id) => {
+                write!(
+                    f,
+                    "The bus/controller with id {} would create a port with the same name as another bus/controller",
+                    id
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    AudioServerMismatch(String),
+    AudioServerUnavailable(String),
+    ChannelLayoutMismatch(String),
+    IdNotUnique(String),
+    NoAudioDeviceAvailable(String),
+    NoPlaybackPorts(String),
+    NoSystemPortsGiven(String),
+    PortNameNotUnique(String),
+    SystemDeviceNotFound(String),
+    SystemPortNotFound(String, String),
+    UnknownMidiServer(String),
+    UnsupportedBufferSize {
+        requested: u32,
+        range: BufferSizeRange,
+    },
+}
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::AudioServerMismatch(server) => {
+                write!(
+                    f,
+                    "The config uses the audio server {}, but was checked against a different server",
+                    server
+                )
+            }
+            ConfigError::AudioServerUnavailable(server) => {
+                write!(f, "The audio sever is unavailable: {}", server)
+            }
+            ConfigError::ChannelLayoutMismatch(id) => {
+                write!(
+                    f,
+                    "The channel layout of the bus with id {} doesn't have one channel per system port",
+                    id
+                )
+            }
+            ConfigError::IdNotUnique(id) => {
+                write!(f, "Two or more busses/controllers have the same id {}", id)
+            }
+            ConfigError::NoAudioDeviceAvailable(server) => {
+                write!(f, "The audio server {} has no devices", server)
+            }
+            ConfigError::NoPlaybackPorts(device) => {
+                write!(
+                    f,
+                    "The system audio device {} has no playback ports",
+                    device
+                )
+            }
+            ConfigError::NoSystemPortsGiven(id) => {
+                write!(f, "No system ports were set for the bus with id {}", id)
+            }
+            ConfigError::PortNameNotUnique(id) => {
+                write!(
+                    f,
+                    "The bus/controller with id {} would create a port with the same name as another bus/controller",
+                    id
+                )
+            }
+            ConfigError::SystemDeviceNotFound(device) => {
+                write!(f, "The system audio device {} could not be found", device)
+            }
+            ConfigError::SystemPortNotFound(port, id) => {
+                write!(
+                    f,
+                    "The system port {} could not be found. This port was requested for bus/controller with id {}",
+                    port, id,
+                )
+            }
+            ConfigError::UnknownMidiServer(server) => {
+                write!(f, "Unknown midi server {}", server)
+            }
+            ConfigError::UnsupportedBufferSize { requested, range } => {
+                write!(
+                    f,
+                    "The buffer size of {} frames is outside the range the device supports ({} to {} frames)",
+                    requested, range.min, range.max
+                )
+            }
+        }
+    }
+}
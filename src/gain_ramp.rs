@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+/// The number of frames a ramp of `ramp_time` takes at `sample_rate`, at least 1.
+pub(crate) fn ramp_frames(ramp_time: Duration, sample_rate: u32) -> u64 {
+    (ramp_time.as_micros() as u64 * u64::from(sample_rate) / 1_000_000).max(1)
+}
+
+/// A gain that moves linearly toward its target, one sample at a time. This is the
+/// realtime part of the master gain and of the per-channel gains.
+///
+/// The ramp counts down the frames it has left instead of comparing the gain to the
+/// target, so it always lands exactly on the target after the number of frames it was
+/// given, however the steps round.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct GainRamp {
+    current: f32,
+    target: f32,
+    step: f32,
+    /// The frames left until `current` reaches `target`.
+    remaining: u64,
+}
+
+impl GainRamp {
+    pub fn new(gain: f32) -> Self {
+        Self {
+            current: gain,
+            target: gain,
+            step: 0.0,
+            remaining: 0,
+        }
+    }
+
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    pub fn is_ramping(&self) -> bool {
+        self.remaining > 0
+    }
+
+    /// Start ramping from the current gain to `target` over `ramp_frames` frames. This
+    /// does nothing if `target` already is the target.
+    pub fn set_target(&mut self, target: f32, ramp_frames: u64) {
+        if target == self.target {
+            return;
+        }
+
+        self.target = target;
+        self.remaining = ramp_frames.max(1);
+        self.step = (target - self.current) / self.remaining as f32;
+    }
+
+    /// Move the gain one sample toward the target and return it.
+    pub fn next(&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.current = if self.remaining == 0 {
+                self.target
+            } else {
+                self.current + self.step
+            };
+        }
+
+        self.current
+    }
+
+    /// Move the gain `frames` samples toward the target.
+    pub fn advance(&mut self, frames: usize) {
+        for _ in 0..frames {
+            if !self.is_ramping() {
+                break;
+            }
+            self.next();
+        }
+    }
+
+    /// Multiply every sample by the gain, moving the gain one sample toward the target for
+    /// each of them.
+    pub fn apply(&mut self, samples: &mut [f32]) {
+        if !self.is_ramping() {
+            if self.current != 1.0 {
+                for s in samples.iter_mut() {
+                    *s *= self.current;
+                }
+            }
+
+            return;
+        }
+
+        for s in samples.iter_mut() {
+            *s *= self.next();
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::AudioBusBuffer;
+
+    /// A stereo bus with every sample set to 1.0.
+    pub(crate) fn ones(frames: usize) -> Vec<AudioBusBuffer> {
+        let mut bus = AudioBusBuffer::new(2, frames as u32);
+        bus.channel_buffers[0].resize(frames, 1.0);
+        bus.channel_buffers[1].resize(frames, 1.0);
+        vec![bus]
+    }
+
+    #[test]
+    fn lands_on_target_after_ramp_frames() {
+        // Steps of a tenth don't add up to exactly 0.0 in `f32`.
+        let mut ramp = GainRamp::new(1.0);
+        ramp.set_target(0.0, 10);
+
+        let mut samples = vec![1.0; 12];
+        ramp.apply(&mut samples);
+        assert!((samples[0] - 0.9).abs() < 1e-6);
+        assert_eq!(&samples[9..], &[0.0, 0.0, 0.0]);
+        assert!(!ramp.is_ramping());
+
+        ramp.set_target(0.3, 7);
+        ramp.advance(7);
+        assert_eq!(ramp.current(), 0.3);
+    }
+
+    #[test]
+    fn ramp_time_is_converted_to_frames() {
+        assert_eq!(ramp_frames(Duration::from_millis(10), 48000), 480);
+        assert_eq!(ramp_frames(Duration::from_millis(0), 48000), 1);
+    }
+}
@@ -0,0 +1,46 @@
+/// New items for one of the lists of the process handler, and a list with room for those
+/// and the existing items, so the process handler can add them without allocating.
+pub(crate) struct Growth<T> {
+    new: Vec<T>,
+    spare: Vec<T>,
+}
+
+impl<T> Growth<T> {
+    /// * `existing` - The length of the list the items are added to.
+    pub fn new(new: Vec<T>, existing: usize) -> Self {
+        let spare = Vec::with_capacity(existing + new.len());
+
+        Self { new, spare }
+    }
+
+    /// Add the new items to the end of `list`. The old allocation of `list` is left in
+    /// `self`.
+    pub fn apply(&mut self, list: &mut Vec<T>) {
+        self.spare.append(list);
+        self.spare.append(&mut self.new);
+        std::mem::swap(list, &mut self.spare);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn growth_adds_items_without_allocating() {
+        let mut list = vec![1, 2];
+        let old = list.as_ptr();
+
+        let mut growth = Growth::new(vec![3], list.len());
+        let spare = growth.spare.as_ptr();
+        growth.apply(&mut list);
+
+        assert_eq!(list, vec![1, 2, 3]);
+        assert_eq!(list.as_ptr(), spare);
+
+        // The old allocation is freed with the update, off the realtime thread.
+        assert!(growth.new.is_empty());
+        assert!(growth.spare.is_empty());
+        assert_eq!(growth.spare.as_ptr(), old);
+    }
+}
@@ -8,37 +8,213 @@ mod windows;
 #[cfg(target_os = "windows")]
 use windows::{WindowsDevicesInfo, WindowsStreamHandle};
 
+mod channel_gain;
+#[cfg(feature = "cpal")]
+mod cpal_backend;
+mod cycle_debug;
+mod denormals;
+mod gain_ramp;
+mod growth;
+mod master_gain;
+mod port_pattern;
+mod process_panic;
+#[cfg(any(
+    target_os = "windows",
+    all(
+        target_os = "linux",
+        any(test, feature = "alsa-backend", feature = "pulse")
+    )
+))]
+mod rt_thread;
+mod shutdown_hook;
+mod spawn_deadline;
+mod xruns;
+
 pub mod audio_buffer;
+pub mod backend;
+pub mod channel_layout;
 pub mod config;
+pub mod config_builder;
+pub mod device_group;
+pub mod device_watch;
+pub mod diagnostics;
+pub mod dummy_backend;
+pub mod enumeration;
 pub mod error;
 pub mod midi_buffer;
+pub mod midi_event;
+pub mod midi_filter;
+pub mod offline_render;
+pub mod port_mixing;
+pub mod probe;
+pub mod recorder;
+pub mod resample;
+pub mod sample_format;
+pub mod silence_detect;
 pub mod stream_info;
 pub mod system_options;
+pub mod transport;
 
 pub use audio_buffer::*;
+pub use backend::*;
+pub use channel_layout::*;
 pub use config::*;
+pub use config_builder::*;
+pub use device_group::*;
+pub use device_watch::*;
+pub use diagnostics::*;
+pub use dummy_backend::{DummyBackend, DummyMidiEvent, DUMMY_DEVICE_NAME, DUMMY_SERVER_NAME};
+pub use enumeration::*;
 pub use error::*;
 pub use midi_buffer::*;
+pub use midi_event::*;
+pub use midi_filter::*;
+pub use offline_render::*;
+pub use port_mixing::*;
+pub use probe::*;
+pub use recorder::*;
+pub use resample::*;
+pub use sample_format::SampleFormat;
+pub use silence_detect::*;
 pub use stream_info::*;
 pub use system_options::*;
+pub use transport::*;
+
+#[cfg(feature = "message-channel")]
+pub mod message_channel;
+#[cfg(feature = "message-channel")]
+pub use message_channel::*;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(any(test, feature = "testing"))]
+pub use testing::*;
+#[cfg(feature = "async")]
+pub mod async_enumeration;
+#[cfg(feature = "async")]
+pub use async_enumeration::*;
 
 #[cfg(feature = "save-file")]
 pub mod save_file;
 #[cfg(feature = "save-file")]
 pub use save_file::*;
 
+use std::any::Any;
+use std::time::Instant;
+
 pub trait RtProcessHandler: 'static + Send {
     /// Initialize/allocate any buffers here. This will only be called once
     /// on creation.
     fn init(&mut self, stream_info: &StreamInfo);
 
-    fn process(&mut self, proc_info: ProcessInfo);
+    /// Called once when the stream is torn down, after the last call to `process`. Flush
+    /// state and close anything that was opened in `init` here.
+    ///
+    /// This is called when the stream is stopped or its handle is dropped, and when the
+    /// stream fails right after `FatalErrorHandler::fatal_stream_error`. On Jack the handler
+    /// can only be reached again once the client is deactivated, so after a fatal error this
+    /// is only called when the stream handle is stopped or dropped, and not at all if Jack
+    /// doesn't give the handler back.
+    ///
+    /// By default this does nothing.
+    fn deinit(&mut self) {}
+
+    /// Process one cycle of audio and midi.
+    ///
+    /// Return `ProcessControl::Quit` to stop the stream after this cycle, e.g. once a
+    /// render has reached the end of the timeline. The output of this cycle is still
+    /// played.
+    ///
+    /// If this (or any of the other calls on the realtime thread) panics, the panic is
+    /// caught and the stream is stopped, and `FatalErrorHandler::fatal_stream_error` is
+    /// called with `FatalStreamError::ProcessPanicked`.
+    fn process(&mut self, proc_info: ProcessInfo) -> ProcessControl;
+
+    /// Called instead of `process` when the stream isn't running in realtime, i.e. while
+    /// Jack is in freewheel mode and during `render_offline()`. There is no deadline here,
+    /// so this is the place for extra work such as higher-quality rendering.
+    ///
+    /// By default this calls `process`.
+    fn process_offline(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+        self.process(proc_info)
+    }
+
+    /// Called on the realtime thread before the next `process` when the sample rate of
+    /// the stream changed, which only the Jack server can do while a stream is running.
+    /// `stream_info` is the one given to `init` with the new `sample_rate`, which is also
+    /// the one in every `ProcessInfo` from now on. Mark anything that depends on the sample
+    /// rate (filters, oscillators, delay times...) for reinitialization here.
+    ///
+    /// This is realtime, so it must not allocate or block. If the handler needs to be
+    /// rebuilt, stop the stream with `StreamHandle::stop()` and spawn it again with
+    /// `spawn_rt_thread()`, which calls `init` with a `StreamInfo` for the new rate.
+    /// `StreamHandle::stream_info()` keeps the sample rate the stream was spawned with.
+    ///
+    /// By default this does nothing.
+    fn sample_rate_changed(&mut self, _stream_info: &StreamInfo) {}
+
+    /// Called when the Jack server changed its buffer size while the stream is running,
+    /// which only Jack can do. `stream_info` has the new `max_audio_buffer_size`, and every
+    /// `ProcessInfo` from now on has at most that many frames. The buffers given to
+    /// `process` are already made large enough.
+    ///
+    /// Jack calls this between cycles, outside of the process deadline, so unlike
+    /// `sample_rate_changed` it's fine to reallocate the handler's own buffers here.
+    /// `StreamHandle::stream_info()` keeps the buffer size the stream was spawned with.
+    ///
+    /// By default this does nothing.
+    fn buffer_size_changed(&mut self, _stream_info: &StreamInfo) {}
+
+    /// Called on the realtime thread before the next `process` once busses or controllers
+    /// that were added with e.g. `StreamHandle::add_audio_out_bus()` are running. They are
+    /// at the end of the lists in `stream_info`, and their buffers at the end of the ones in
+    /// every `ProcessInfo` from now on.
+    ///
+    /// This is realtime, so it must not allocate or block.
+    ///
+    /// By default this does nothing.
+    fn busses_added(&mut self, _stream_info: &StreamInfo) {}
+}
+
+/// Whether the stream keeps running after a call to `RtProcessHandler::process`.
+///
+/// When the handler quits, the stream stops the same way as when its handle is stopped:
+/// the hook set with `StreamHandle::on_before_shutdown()` is called on the realtime thread,
+/// and `RtProcessHandler::deinit` is called right after on every backend but Jack, which
+/// only gives the handler back once the stream handle is stopped or dropped.
+/// `FatalErrorHandler::fatal_stream_error` isn't called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProcessControl {
+    #[default]
+    Continue,
+    Quit,
 }
 
 pub trait FatalErrorHandler: 'static + Send + Sync {
     fn fatal_stream_error(self, error: FatalStreamError);
 }
 
+/// A `FatalErrorHandler` that only logs the error, for apps that don't need to react to
+/// the stream failing. See `spawn_rt_thread_simple()`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogFatalErrorHandler;
+
+impl FatalErrorHandler for LogFatalErrorHandler {
+    fn fatal_stream_error(self, error: FatalStreamError) {
+        log::error!("The stream failed: {}", error);
+    }
+}
+
+/// Receives notifications about a running stream. These are called from a separate
+/// (non-realtime) thread.
+pub trait StreamEventHandler: 'static + Send {
+    /// The input bus with the given ID has been silent for the duration set in
+    /// `StreamHandle::enable_input_silence_detection()`.
+    fn input_silent(&mut self, bus_id: &str);
+
+    /// The input bus with the given ID has a signal again after `input_silent` was called.
+    fn input_active(&mut self, bus_id: &str);
+}
+
 pub struct ProcessInfo<'a> {
     pub audio_in: &'a [AudioBusBuffer],
     pub audio_out: &'a mut [AudioBusBuffer],
@@ -48,19 +224,575 @@ pub struct ProcessInfo<'a> {
     pub midi_out: &'a mut [MidiControllerBuffer],
 
     pub sample_rate: u32,
+
+    /// The state of the audio server's transport at the start of this cycle. This is
+    /// `None` on backends that don't have a transport, which is every backend except Jack.
+    pub transport: Option<TransportInfo>,
+
+    /// The frame at the start of this cycle, for timestamping midi events and syncing
+    /// with other clients. For Jack this is the server's frame clock (`last_frame_time`,
+    /// widened so it never wraps), which keeps counting while the stream isn't running.
+    /// Every other backend counts the frames processed since the stream started.
+    pub cycle_start_frame: u64,
+
+    /// When the backend woke the realtime thread for this cycle.
+    pub cycle_start_time: Instant,
+}
+
+impl<'a> ProcessInfo<'a> {
+    /// Every event of every midi input controller, controller by controller.
+    ///
+    /// Each event is tagged with the index of its controller in `StreamInfo::midi_in`.
+    pub fn midi_in_events(&self) -> impl Iterator<Item = (DeviceIndex, &RawMidi)> {
+        self.midi_in
+            .iter()
+            .enumerate()
+            .flat_map(|(controller_i, buffer)| {
+                buffer
+                    .events()
+                    .iter()
+                    .map(move |event| (DeviceIndex::new(controller_i), event))
+            })
+    }
+
+    /// Every event of every midi input controller, in time order across all controllers.
+    ///
+    /// Each event is tagged with the index of its controller in `StreamInfo::midi_in`.
+    pub fn midi_in_events_sorted(&self) -> SortedMidiEvents<'_> {
+        SortedMidiEvents::new(self.midi_in)
+    }
 }
 
 pub struct StreamHandle<P: RtProcessHandler, E: FatalErrorHandler> {
+    os_handle: BackendStreamHandle<Resampled<P>, E>,
+    ring_buffers: diagnostics::RingBufferSources,
+    new_buffers: Option<resample::NewBuffersSender>,
+}
+
+enum BackendStreamHandle<P: RtProcessHandler, E: FatalErrorHandler> {
     #[cfg(target_os = "linux")]
-    os_handle: LinuxStreamHandle<P, E>,
+    Os(LinuxStreamHandle<P, E>),
 
     #[cfg(target_os = "windows")]
-    os_handle: WindowsStreamHandle<P, E>,
+    Os(WindowsStreamHandle<P, E>),
+
+    #[cfg(feature = "cpal")]
+    Cpal(cpal_backend::CpalStreamHandle<P, E>),
+
+    Dummy(dummy_backend::DummyStreamHandle<P, E>),
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> BackendStreamHandle<P, E> {
+    fn get(&self) -> &dyn OsStreamHandle<P = P, E = E> {
+        match self {
+            #[cfg(any(target_os = "linux", target_os = "windows"))]
+            BackendStreamHandle::Os(h) => h,
+            #[cfg(feature = "cpal")]
+            BackendStreamHandle::Cpal(h) => h,
+            BackendStreamHandle::Dummy(h) => h,
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut dyn OsStreamHandle<P = P, E = E> {
+        match self {
+            #[cfg(any(target_os = "linux", target_os = "windows"))]
+            BackendStreamHandle::Os(h) => h,
+            #[cfg(feature = "cpal")]
+            BackendStreamHandle::Cpal(h) => h,
+            BackendStreamHandle::Dummy(h) => h,
+        }
+    }
 }
 
 impl<P: RtProcessHandler, E: FatalErrorHandler> StreamHandle<P, E> {
+    /// Spawn a stream with `spawn`, which is given the process handler wrapped in
+    /// `Resampled` for `config.resample`.
+    fn spawn<F>(config: &Config, rt_process_handler: P, spawn: F) -> Result<Self, SpawnError<P>>
+    where
+        F: FnOnce(
+            Resampled<P>,
+        )
+            -> Result<BackendStreamHandle<Resampled<P>, E>, SpawnError<Resampled<P>>>,
+    {
+        let (rt_process_handler, new_buffers) = resample::resampled(config, rt_process_handler);
+
+        match spawn(rt_process_handler) {
+            Ok(os_handle) => Ok(Self {
+                os_handle,
+                ring_buffers: diagnostics::RingBufferSources::default(),
+                new_buffers,
+            }),
+            Err(e) => Err(SpawnError::new(
+                e.error,
+                e.rt_process_handler.map(Resampled::into_inner),
+            )),
+        }
+    }
+
+    fn os_handle(&self) -> &dyn OsStreamHandle<P = Resampled<P>, E = E> {
+        self.os_handle.get()
+    }
+
+    fn os_handle_mut(&mut self) -> &mut dyn OsStreamHandle<P = Resampled<P>, E = E> {
+        self.os_handle.get_mut()
+    }
+
+    /// Stop the stream and return the process handler, e.g. to reuse it for the stream of
+    /// the next device.
+    ///
+    /// This calls the hook set with `on_before_shutdown()`, then stops the audio thread and
+    /// closes the device (for Jack, deactivates the client, which disconnects its ports).
+    /// Everything is stopped by the time this returns. Dropping the handle does the same,
+    /// but drops the process handler.
+    ///
+    /// Returns `None` if the handler was lost, i.e. if the audio thread panicked or the Jack
+    /// client could not be deactivated after the server shut down.
+    pub fn stop(mut self) -> Option<P> {
+        self.os_handle_mut().stop().map(Resampled::into_inner)
+    }
+
     pub fn stream_info(&self) -> &StreamInfo {
-        self.os_handle.stream_info()
+        self.os_handle().stream_info()
+    }
+
+    /// The total number of frames that have been processed since the stream started.
+    pub fn frames_processed(&self) -> u64 {
+        self.os_handle().frames_processed()
+    }
+
+    /// Log detailed diagnostics (frame counts, midi event counts, buffer sizes) for the
+    /// next `n` process cycles, after which logging stops automatically.
+    ///
+    /// The diagnostics are collected without blocking the realtime thread and are
+    /// flushed to the `debug` log level from a separate thread.
+    pub fn debug_next_cycles(&self, n: u32) {
+        self.os_handle().debug_next_cycles(n)
+    }
+
+    /// A snapshot of the health of the stream, including the under/overrun counters of
+    /// the ring buffers managed by this crate.
+    pub fn diagnostics(&self) -> StreamDiagnostics {
+        let mut diagnostics = self.os_handle().diagnostics();
+        self.ring_buffers.add_to(&mut diagnostics);
+        diagnostics
+    }
+
+    /// Include the ring buffer of every bus recorded by the given recorder in
+    /// `diagnostics()`, e.g. after `disk_recorder()` was set up for this stream.
+    pub fn add_recorder_diagnostics(&mut self, recorder: &Recorder) {
+        self.ring_buffers.recorder.extend(recorder.ring_counters());
+    }
+
+    /// The number of frames of each of the last few process cycles, oldest first.
+    ///
+    /// Most backends always use the same buffer size, but some (e.g. PipeWire) can change
+    /// it from cycle to cycle. The number of cycles that are kept is set with
+    /// `Config::buffer_size_history`.
+    pub fn recent_buffer_sizes(&self) -> Vec<u32> {
+        self.os_handle().recent_buffer_sizes()
+    }
+
+    /// The number of events the midi input controller with the given ID has dropped since
+    /// the stream started, because they didn't fit in its buffer (see
+    /// `MidiControllerConfig::buffer_capacity`). Poll this to tell the user about a midi
+    /// buffer overflow.
+    ///
+    /// Returns `None` if there is no such controller, and on every backend without midi
+    /// (all but Jack and the dummy backend).
+    pub fn midi_in_dropped_events(&self, controller_id: &str) -> Option<u64> {
+        self.os_handle().midi_in_dropped_events(controller_id)
+    }
+
+    /// Set the gain applied to all audio outputs.
+    ///
+    /// The applied gain ramps smoothly to the new value on the realtime thread to avoid
+    /// clicks. By default the ramp takes 10ms.
+    pub fn set_master_gain(&self, gain: f32) {
+        self.os_handle().set_master_gain(gain)
+    }
+
+    /// Set how long it takes the master gain to ramp to a new value.
+    pub fn set_master_gain_ramp_time(&self, ramp_time: std::time::Duration) {
+        self.os_handle().set_master_gain_ramp_time(ramp_time)
+    }
+
+    /// The master gain that is currently applied to the audio outputs. While a ramp is in
+    /// progress this can differ from the last value given to `set_master_gain`.
+    pub fn master_gain(&self) -> f32 {
+        self.os_handle().master_gain()
+    }
+
+    /// Set the linear gain of one channel (starting at 0) of an audio bus, e.g. to trim an
+    /// output or to monitor an input at a lower level. See `AudioBusConfig::channel_gains`
+    /// for where it is applied.
+    ///
+    /// Like the master gain, the applied gain ramps smoothly to the new value on the
+    /// realtime thread (over 5ms), so this can follow a fader without zipper noise.
+    pub fn set_channel_gain(
+        &self,
+        bus_id: &str,
+        channel: usize,
+        gain: f32,
+    ) -> Result<(), ChannelGainError> {
+        self.os_handle()
+            .channel_gains_handle()
+            .ok_or_else(|| ChannelGainError::BusNotFound(String::from(bus_id)))?
+            .set_channel_gain(bus_id, channel, gain)
+    }
+
+    /// The gain that is currently applied to one channel of an audio bus. While a ramp is
+    /// in progress this can differ from the last value given to `set_channel_gain`.
+    pub fn channel_gain(&self, bus_id: &str, channel: usize) -> Result<f32, ChannelGainError> {
+        self.os_handle()
+            .channel_gains_handle()
+            .ok_or_else(|| ChannelGainError::BusNotFound(String::from(bus_id)))?
+            .channel_gain(bus_id, channel)
+    }
+
+    /// Detect when an audio input bus has been silent for a while, e.g. to warn about a
+    /// muted or disconnected mic.
+    ///
+    /// The level of every input bus is tracked on the realtime thread, and `handler` is
+    /// notified from a separate thread. This replaces any handler that was set before.
+    pub fn enable_input_silence_detection<H: StreamEventHandler>(
+        &self,
+        settings: SilenceDetection,
+        handler: H,
+    ) {
+        if let Some(h) = self.os_handle().silence_detect_handle() {
+            h.enable(settings, Box::new(handler));
+        }
+    }
+
+    /// Stop detecting silence on the audio input busses and drop the handler.
+    pub fn disable_input_silence_detection(&self) {
+        if let Some(h) = self.os_handle().silence_detect_handle() {
+            h.disable();
+        }
+    }
+
+    /// Set a hook that is called once right before the stream shuts down, while it is
+    /// still running. Use this to flush recorders or save state. This replaces any hook
+    /// that was set before.
+    ///
+    /// When the stream handle is dropped, the hook is called on the thread that drops it.
+    /// When the stream fails, it is called right before `FatalErrorHandler::fatal_stream_error`
+    /// on the thread that reports the error (the audio thread for every backend but Jack).
+    /// Jack reports server failures on its notification thread, and panics of the process
+    /// handler on a thread of the stream, never on the realtime thread. That thread also
+    /// calls the hook when `RtProcessHandler::process` stops a Jack stream. If the stream
+    /// has already failed, the hook is never called.
+    pub fn on_before_shutdown<F: FnOnce(&StreamInfo) + Send + 'static>(&self, hook: F) {
+        self.os_handle().shutdown_hook().set(Box::new(hook));
+    }
+
+    /// Set a callback that is called every time the stream has an xrun (a dropout), with
+    /// the total number of xruns so far. This replaces any callback that was set before.
+    ///
+    /// On Jack this is called from the notification thread, and on ALSA from the audio
+    /// thread right after it has recovered, so keep it short and don't block. CPAL reports
+    /// the cycles its duplex streams run out of (or overflow with) captured samples, from
+    /// its own audio threads. WASAPI and the dummy backend don't report xruns.
+    pub fn on_xrun<F: FnMut(u64) + Send + 'static>(&self, hook: F) {
+        self.os_handle().xruns().set_hook(Box::new(hook));
+    }
+
+    /// The total number of xruns since the stream started. This never blocks, so it can be
+    /// polled from the UI thread.
+    pub fn xrun_count(&self) -> u64 {
+        self.os_handle().xruns().count()
+    }
+
+    /// When the last xrun happened, or `None` if the stream hasn't had one yet. Like
+    /// `xrun_count()` this never blocks.
+    pub fn last_xrun(&self) -> Option<Instant> {
+        self.os_handle().xruns().last()
+    }
+
+    /// The latency of the audio bus with the given ID along the connections it currently
+    /// has, e.g. to compensate busses with different latencies separately.
+    ///
+    /// For an input bus this is the capture latency, for an output bus the playback
+    /// latency. Channels that aren't connected are left out. Returns `None` if there is no
+    /// such bus or none of its channels are connected, and on every backend except Jack.
+    pub fn bus_latency(&self, bus_id: &str) -> Option<LatencyRange> {
+        self.os_handle().bus_latency(bus_id)
+    }
+
+    /// What every port of the audio bus with the given ID is connected to right now, one
+    /// entry per channel, e.g. for a patchbay view. Unlike `AudioBus::system_ports` this
+    /// follows the connections the user made (or removed) with other tools.
+    ///
+    /// Returns `None` if there is no such bus, and on every backend except Jack, which is
+    /// the only one whose busses have ports of their own.
+    pub fn port_connections(&self, bus_id: &str) -> Option<Vec<PortConnections>> {
+        self.os_handle().port_connections(bus_id)
+    }
+
+    /// Start or stop Jack's freewheel mode, e.g. to bounce the session faster than
+    /// realtime. Only Jack has a freewheel mode.
+    ///
+    /// In freewheel mode the server runs the graph as fast as it can instead of waiting for
+    /// the device, and `RtProcessHandler::process_offline` is called instead of `process`
+    /// with the usual `ProcessInfo`: every cycle has the server's buffer size, and
+    /// `cycle_start_frame` keeps counting the frames that were processed. Nothing is played
+    /// or captured while freewheeling.
+    ///
+    /// This only asks the server to switch. The hook set with `on_freewheel()` is called
+    /// once it has.
+    pub fn set_freewheel(&self, enabled: bool) -> Result<(), FreewheelError> {
+        self.os_handle().set_freewheel(enabled)
+    }
+
+    /// Whether the stream is in freewheel mode, as last reported by the server. This is
+    /// always `false` on every backend except Jack.
+    pub fn is_freewheeling(&self) -> bool {
+        self.os_handle().is_freewheeling()
+    }
+
+    /// Set a callback that is called every time the stream enters (`true`) or leaves
+    /// (`false`) freewheel mode, whether that was asked for with `set_freewheel()` or by
+    /// another Jack client. This replaces any callback that was set before.
+    ///
+    /// This is called from Jack's notification thread, so keep it short and don't block.
+    /// It's never called on any other backend.
+    pub fn on_freewheel<F: FnMut(bool) + Send + 'static>(&self, hook: F) {
+        self.os_handle().on_freewheel(Box::new(hook));
+    }
+
+    /// Add an audio input bus to the running stream, without interrupting it. Only Jack can
+    /// do this.
+    ///
+    /// The bus is checked like the busses of the `Config` the stream was spawned with, and
+    /// connected to its system ports if `Config::jack_self_connect` allows it. Its buffer is
+    /// added to the end of `ProcessInfo::audio_in` from the cycle on in which
+    /// `RtProcessHandler::busses_added` is called, and `stream_info()` lists it right away.
+    /// Input silence detection covers the new bus from then on as well.
+    ///
+    /// At most 16 busses and controllers can be added between two process cycles, since
+    /// the realtime thread picks them up at the start of a cycle. The next one is refused
+    /// with `AddBusError::QueueFull` before anything is registered, so it can be added again
+    /// once the stream has run a cycle.
+    ///
+    /// With `Config::resample`, the buffers the bus needs at the handler's rate are
+    /// allocated here too. `busses_added` is called once they have reached the realtime
+    /// thread, which can be a cycle later.
+    pub fn add_audio_in_bus(&mut self, bus: &AudioBusConfig) -> Result<AudioBus, AddBusError> {
+        self.add_audio_bus(bus, true)
+    }
+
+    /// Add an audio output bus to the running stream. See `add_audio_in_bus()`.
+    pub fn add_audio_out_bus(&mut self, bus: &AudioBusConfig) -> Result<AudioBus, AddBusError> {
+        self.add_audio_bus(bus, false)
+    }
+
+    /// Add a midi input controller to the running stream. See `add_audio_in_bus()`.
+    ///
+    /// Since the ports of the stream can't be renamed, the id of the controller may not be
+    /// used by any other controller, in either direction.
+    pub fn add_midi_in_controller(
+        &mut self,
+        controller: &MidiControllerConfig,
+    ) -> Result<MidiController, AddBusError> {
+        self.add_midi_controller(controller, true)
+    }
+
+    /// Add a midi output controller to the running stream. See `add_midi_in_controller()`.
+    pub fn add_midi_out_controller(
+        &mut self,
+        controller: &MidiControllerConfig,
+    ) -> Result<MidiController, AddBusError> {
+        self.add_midi_controller(controller, false)
+    }
+
+    fn add_audio_bus(
+        &mut self,
+        bus: &AudioBusConfig,
+        is_input: bool,
+    ) -> Result<AudioBus, AddBusError> {
+        if let Some(new_buffers) = &mut self.new_buffers {
+            new_buffers.reserve()?;
+        }
+
+        let bus = self.os_handle.get_mut().add_audio_bus(bus, is_input)?;
+        if let Some(new_buffers) = &mut self.new_buffers {
+            new_buffers.send_audio_bus(self.os_handle.get().stream_info(), is_input);
+        }
+
+        Ok(bus)
+    }
+
+    fn add_midi_controller(
+        &mut self,
+        controller: &MidiControllerConfig,
+        is_input: bool,
+    ) -> Result<MidiController, AddBusError> {
+        if let Some(new_buffers) = &mut self.new_buffers {
+            new_buffers.reserve()?;
+        }
+
+        let controller = self
+            .os_handle
+            .get_mut()
+            .add_midi_controller(controller, is_input)?;
+        if let Some(new_buffers) = &mut self.new_buffers {
+            new_buffers.send_midi_controller(self.os_handle.get().stream_info(), is_input);
+        }
+
+        Ok(controller)
+    }
+}
+
+/// A running stream, whatever its backend and handlers are.
+///
+/// `StreamHandle` already works the same on every backend, but it is generic over the
+/// types of its handlers. This trait leaves those out, so the current stream can be stored
+/// as a `Box<dyn AnyStreamHandle>` by code that doesn't know (or care) what it runs.
+///
+/// Every method does the same as the one of `StreamHandle` with the same name. The ones
+/// that take a generic callback there take a boxed one here.
+pub trait AnyStreamHandle {
+    /// Stop the stream and return the process handler, boxed so it can be downcast back to
+    /// its type. See `StreamHandle::stop()`.
+    fn stop(self: Box<Self>) -> Option<Box<dyn Any + Send>>;
+
+    fn stream_info(&self) -> &StreamInfo;
+    fn frames_processed(&self) -> u64;
+    fn diagnostics(&self) -> StreamDiagnostics;
+    fn recent_buffer_sizes(&self) -> Vec<u32>;
+    fn midi_in_dropped_events(&self, controller_id: &str) -> Option<u64>;
+
+    fn set_master_gain(&self, gain: f32);
+    fn master_gain(&self) -> f32;
+    fn set_channel_gain(
+        &self,
+        bus_id: &str,
+        channel: usize,
+        gain: f32,
+    ) -> Result<(), ChannelGainError>;
+    fn channel_gain(&self, bus_id: &str, channel: usize) -> Result<f32, ChannelGainError>;
+
+    fn on_before_shutdown(&self, hook: Box<dyn FnOnce(&StreamInfo) + Send + 'static>);
+    fn on_xrun(&self, hook: Box<dyn FnMut(u64) + Send + 'static>);
+    fn xrun_count(&self) -> u64;
+    fn last_xrun(&self) -> Option<Instant>;
+
+    fn bus_latency(&self, bus_id: &str) -> Option<LatencyRange>;
+    fn port_connections(&self, bus_id: &str) -> Option<Vec<PortConnections>>;
+
+    fn set_freewheel(&self, enabled: bool) -> Result<(), FreewheelError>;
+    fn is_freewheeling(&self) -> bool;
+
+    fn add_audio_in_bus(&mut self, bus: &AudioBusConfig) -> Result<AudioBus, AddBusError>;
+    fn add_audio_out_bus(&mut self, bus: &AudioBusConfig) -> Result<AudioBus, AddBusError>;
+    fn add_midi_in_controller(
+        &mut self,
+        controller: &MidiControllerConfig,
+    ) -> Result<MidiController, AddBusError>;
+    fn add_midi_out_controller(
+        &mut self,
+        controller: &MidiControllerConfig,
+    ) -> Result<MidiController, AddBusError>;
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> AnyStreamHandle for StreamHandle<P, E> {
+    fn stop(self: Box<Self>) -> Option<Box<dyn Any + Send>> {
+        StreamHandle::stop(*self).map(|p| Box::new(p) as Box<dyn Any + Send>)
+    }
+
+    fn stream_info(&self) -> &StreamInfo {
+        StreamHandle::stream_info(self)
+    }
+
+    fn frames_processed(&self) -> u64 {
+        StreamHandle::frames_processed(self)
+    }
+
+    fn diagnostics(&self) -> StreamDiagnostics {
+        StreamHandle::diagnostics(self)
+    }
+
+    fn recent_buffer_sizes(&self) -> Vec<u32> {
+        StreamHandle::recent_buffer_sizes(self)
+    }
+
+    fn midi_in_dropped_events(&self, controller_id: &str) -> Option<u64> {
+        StreamHandle::midi_in_dropped_events(self, controller_id)
+    }
+
+    fn set_master_gain(&self, gain: f32) {
+        StreamHandle::set_master_gain(self, gain)
+    }
+
+    fn master_gain(&self) -> f32 {
+        StreamHandle::master_gain(self)
+    }
+
+    fn set_channel_gain(
+        &self,
+        bus_id: &str,
+        channel: usize,
+        gain: f32,
+    ) -> Result<(), ChannelGainError> {
+        StreamHandle::set_channel_gain(self, bus_id, channel, gain)
+    }
+
+    fn channel_gain(&self, bus_id: &str, channel: usize) -> Result<f32, ChannelGainError> {
+        StreamHandle::channel_gain(self, bus_id, channel)
+    }
+
+    fn on_before_shutdown(&self, hook: Box<dyn FnOnce(&StreamInfo) + Send + 'static>) {
+        self.os_handle().shutdown_hook().set(hook);
+    }
+
+    fn on_xrun(&self, hook: Box<dyn FnMut(u64) + Send + 'static>) {
+        self.os_handle().xruns().set_hook(hook);
+    }
+
+    fn xrun_count(&self) -> u64 {
+        StreamHandle::xrun_count(self)
+    }
+
+    fn last_xrun(&self) -> Option<Instant> {
+        StreamHandle::last_xrun(self)
+    }
+
+    fn bus_latency(&self, bus_id: &str) -> Option<LatencyRange> {
+        StreamHandle::bus_latency(self, bus_id)
+    }
+
+    fn port_connections(&self, bus_id: &str) -> Option<Vec<PortConnections>> {
+        StreamHandle::port_connections(self, bus_id)
+    }
+
+    fn set_freewheel(&self, enabled: bool) -> Result<(), FreewheelError> {
+        StreamHandle::set_freewheel(self, enabled)
+    }
+
+    fn is_freewheeling(&self) -> bool {
+        StreamHandle::is_freewheeling(self)
+    }
+
+    fn add_audio_in_bus(&mut self, bus: &AudioBusConfig) -> Result<AudioBus, AddBusError> {
+        StreamHandle::add_audio_in_bus(self, bus)
+    }
+
+    fn add_audio_out_bus(&mut self, bus: &AudioBusConfig) -> Result<AudioBus, AddBusError> {
+        StreamHandle::add_audio_out_bus(self, bus)
+    }
+
+    fn add_midi_in_controller(
+        &mut self,
+        controller: &MidiControllerConfig,
+    ) -> Result<MidiController, AddBusError> {
+        StreamHandle::add_midi_in_controller(self, controller)
+    }
+
+    fn add_midi_out_controller(
+        &mut self,
+        controller: &MidiControllerConfig,
+    ) -> Result<MidiController, AddBusError> {
+        StreamHandle::add_midi_out_controller(self, controller)
     }
 }
 
@@ -74,17 +806,47 @@ pub struct DevicesInfo {
 }
 
 impl DevicesInfo {
+    /// Enumerate the devices of every compiled-in backend. This blocks until each server
+    /// has answered, so use `enumerate_audio_devices()` to only enumerate a single backend.
     pub fn new() -> Self {
         Self {
             os_info: Default::default(),
         }
     }
 
-    pub fn refresh_audio_servers(&mut self) {
-        self.os_info.refresh_audio_servers();
+    /// Refresh every audio server.
+    ///
+    /// A server that fails is marked as unavailable. Every server is refreshed even if one
+    /// of them fails, and the first error is returned, e.g.
+    /// `EnumerationError::ServerNotRunning` if Jack isn't running. A server that is running
+    /// but has no devices with playback is marked as unavailable without an error.
+    pub fn refresh_audio_servers(&mut self) -> Result<(), EnumerationError> {
+        self.os_info.refresh_audio_servers()
     }
-    pub fn refresh_midi_servers(&mut self) {
-        self.os_info.refresh_midi_servers();
+
+    /// Refresh every audio server, giving up on each server that takes longer than
+    /// `timeout`.
+    ///
+    /// A server that times out is marked as unavailable. Every server is refreshed even if
+    /// one of them fails, and the first error is returned.
+    pub fn refresh_audio_servers_timeout(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> Result<(), EnumerationError> {
+        self.os_info.refresh_audio_servers_timeout(timeout)
+    }
+
+    /// Refresh every midi server. See `refresh_audio_servers()`.
+    pub fn refresh_midi_servers(&mut self) -> Result<(), EnumerationError> {
+        self.os_info.refresh_midi_servers()
+    }
+
+    /// Refresh every audio and midi server. See `refresh_audio_servers()`.
+    ///
+    /// This is quicker than refreshing the audio servers and then the midi servers, since
+    /// servers that have both (such as Jack) are only connected to once.
+    pub fn refresh_servers(&mut self) -> Result<(), EnumerationError> {
+        self.os_info.refresh_servers()
     }
 
     pub fn audio_servers_info(&self) -> &[AudioServerInfo] {
@@ -109,16 +871,57 @@ impl DevicesInfo {
     }
 }
 
+type FreewheelHook = Box<dyn FnMut(bool) + Send + 'static>;
+
 trait OsStreamHandle {
     type P: RtProcessHandler;
     type E: FatalErrorHandler;
 
     fn stream_info(&self) -> &StreamInfo;
+
+    fn frames_processed(&self) -> u64;
+
+    fn debug_next_cycles(&self, n: u32);
+
+    fn diagnostics(&self) -> StreamDiagnostics;
+    fn recent_buffer_sizes(&self) -> Vec<u32>;
+    fn midi_in_dropped_events(&self, controller_id: &str) -> Option<u64>;
+
+    fn set_master_gain(&self, gain: f32);
+    fn set_master_gain_ramp_time(&self, ramp_time: std::time::Duration);
+    fn master_gain(&self) -> f32;
+
+    fn channel_gains_handle(&self) -> Option<&channel_gain::ChannelGainsHandle>;
+    fn silence_detect_handle(&self) -> Option<&silence_detect::SilenceDetectHandle>;
+    fn shutdown_hook(&self) -> &shutdown_hook::ShutdownHook;
+    fn xruns(&self) -> &xruns::Xruns;
+    fn bus_latency(&self, bus_id: &str) -> Option<LatencyRange>;
+    fn port_connections(&self, bus_id: &str) -> Option<Vec<PortConnections>>;
+    fn set_freewheel(&self, enabled: bool) -> Result<(), FreewheelError>;
+    fn is_freewheeling(&self) -> bool;
+    fn on_freewheel(&self, hook: FreewheelHook);
+    fn add_audio_bus(
+        &mut self,
+        bus: &AudioBusConfig,
+        is_input: bool,
+    ) -> Result<AudioBus, AddBusError>;
+    fn add_midi_controller(
+        &mut self,
+        controller: &MidiControllerConfig,
+        is_input: bool,
+    ) -> Result<MidiController, AddBusError>;
+
+    fn stop(&mut self) -> Option<Self::P>;
 }
 
 trait OsDevicesInfo {
-    fn refresh_audio_servers(&mut self);
-    fn refresh_midi_servers(&mut self);
+    fn refresh_audio_servers(&mut self) -> Result<(), EnumerationError>;
+    fn refresh_audio_servers_timeout(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> Result<(), EnumerationError>;
+    fn refresh_midi_servers(&mut self) -> Result<(), EnumerationError>;
+    fn refresh_servers(&mut self) -> Result<(), EnumerationError>;
 
     fn audio_servers_info(&self) -> &[AudioServerInfo];
     fn midi_servers_info(&self) -> &[MidiServerInfo];
@@ -130,39 +933,175 @@ trait OsDevicesInfo {
     fn sample_rate(&self, config: &Config) -> Option<u32>;
 }
 
+/// Spawn a stream with the given config.
+///
+/// If this fails, the returned `SpawnError` gives `rt_process_handler` back, so the stream
+/// can be spawned again (e.g. with a fixed config) without building a new handler.
 pub fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
     config: &Config,
     use_client_name: Option<String>,
     rt_process_handler: P,
     fatal_error_hanlder: E,
-) -> Result<StreamHandle<P, E>, SpawnRtThreadError> {
-    check_duplicate_ids(config)?;
+) -> Result<StreamHandle<P, E>, SpawnError<P>> {
+    let (config, backend) = match spawn_config(config).and_then(|config| {
+        let backend = config.backend().ok_or_else(|| {
+            SpawnRtThreadError::AudioServerUnavailable(config.audio_server.clone())
+        })?;
+        if !backend.is_compiled() {
+            return Err(SpawnRtThreadError::BackendNotCompiled(backend));
+        }
+        Ok((config, backend))
+    }) {
+        Ok(checked) => checked,
+        Err(e) => return Err(SpawnError::new(e, Some(rt_process_handler))),
+    };
+    let config = &*config;
+
+    StreamHandle::spawn(config, rt_process_handler, |rt_process_handler| {
+        spawn_backend(
+            backend,
+            config,
+            use_client_name,
+            rt_process_handler,
+            fatal_error_hanlder,
+        )
+    })
+}
+
+/// Spawn a stream with a config that has been checked with `spawn_config()`.
+fn spawn_backend<P: RtProcessHandler, E: FatalErrorHandler>(
+    backend: Backend,
+    config: &Config,
+    use_client_name: Option<String>,
+    rt_process_handler: P,
+    fatal_error_hanlder: E,
+) -> Result<BackendStreamHandle<P, E>, SpawnError<P>> {
+    if backend == Backend::Dummy {
+        return Ok(BackendStreamHandle::Dummy(dummy_backend::spawn_rt_thread(
+            &DummyBackend::default(),
+            config,
+            rt_process_handler,
+            fatal_error_hanlder,
+        )?));
+    }
+
+    #[cfg(feature = "cpal")]
+    if backend == Backend::Cpal {
+        return Ok(BackendStreamHandle::Cpal(cpal_backend::spawn_rt_thread(
+            config,
+            rt_process_handler,
+            fatal_error_hanlder,
+        )?));
+    }
 
     #[cfg(target_os = "linux")]
     {
-        Ok(StreamHandle {
-            os_handle: linux::spawn_rt_thread(
-                config,
-                use_client_name,
-                rt_process_handler,
-                fatal_error_hanlder,
-            )?,
-        })
+        Ok(BackendStreamHandle::Os(linux::spawn_rt_thread(
+            backend,
+            config,
+            use_client_name,
+            rt_process_handler,
+            fatal_error_hanlder,
+        )?))
     }
 
     #[cfg(target_os = "windows")]
     {
-        Ok(StreamHandle {
-            os_handle: windows::spawn_rt_thread(
-                config,
-                use_client_name,
-                rt_process_handler,
-                fatal_error_hanlder,
-            )?,
-        })
+        Ok(BackendStreamHandle::Os(windows::spawn_rt_thread(
+            backend,
+            config,
+            use_client_name,
+            rt_process_handler,
+            fatal_error_hanlder,
+        )?))
     }
 }
 
+/// Spawn a stream like `spawn_rt_thread()`, with a `LogFatalErrorHandler` that logs the
+/// error if the stream fails. Nothing else finds out that it failed, so use
+/// `spawn_rt_thread()` with a handler of its own to e.g. tell the user.
+pub fn spawn_rt_thread_simple<P: RtProcessHandler>(
+    config: &Config,
+    use_client_name: Option<String>,
+    rt_process_handler: P,
+) -> Result<StreamHandle<P, LogFatalErrorHandler>, SpawnError<P>> {
+    spawn_rt_thread(
+        config,
+        use_client_name,
+        rt_process_handler,
+        LogFatalErrorHandler,
+    )
+}
+
+/// Apply `config.on_duplicate_id` and check the port names and channel layouts, returning
+/// the config to spawn the stream with.
+fn spawn_config(config: &Config) -> Result<std::borrow::Cow<'_, Config>, SpawnRtThreadError> {
+    let mut config = resolve_duplicate_ids(config)?;
+    drop_closed_direction(&mut config);
+    check_channel_layouts(&config)?;
+
+    Ok(config)
+}
+
+/// Apply `config.on_duplicate_id` and check the port names, returning the config to spawn
+/// the stream with.
+fn resolve_duplicate_ids(
+    config: &Config,
+) -> Result<std::borrow::Cow<'_, Config>, SpawnRtThreadError> {
+    match config.on_duplicate_id {
+        FailOrRename::Fail => {
+            check_duplicate_ids(config)?;
+            Ok(std::borrow::Cow::Borrowed(config))
+        }
+        FailOrRename::Rename => {
+            let mut config = config.clone();
+            for rename in config.rename_duplicate_ids() {
+                log::info!(
+                    "Renamed duplicate bus/controller id {} to {}",
+                    rename.old_id,
+                    rename.new_id
+                );
+            }
+            // Renaming makes the ids unique, but not the names set with `port_names`.
+            check_port_names(&config)?;
+            Ok(std::borrow::Cow::Owned(config))
+        }
+    }
+}
+
+/// Leave out the busses of the side of the device that `config.direction` doesn't open.
+fn drop_closed_direction(config: &mut std::borrow::Cow<'_, Config>) {
+    if !config.direction.has_input() && !config.audio_in_busses.is_empty() {
+        log::warn!("The stream only opens outputs, no audio input busses will be created");
+        config.to_mut().audio_in_busses.clear();
+    }
+    if !config.direction.has_output() && !config.audio_out_busses.is_empty() {
+        log::warn!("The stream only opens inputs, no audio output busses will be created");
+        config.to_mut().audio_out_busses.clear();
+    }
+}
+
+fn check_channel_layouts(config: &Config) -> Result<(), SpawnRtThreadError> {
+    for bus in config
+        .audio_in_busses
+        .iter()
+        .chain(config.audio_out_busses.iter())
+    {
+        if let Some(layout) = &bus.channel_layout {
+            if bus.port_mixing == PortMixing::Off && layout.num_channels() != bus.system_ports.len()
+            {
+                return Err(SpawnRtThreadError::ChannelLayoutMismatch(bus.id.clone()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that every bus/controller has a unique ID, and that the port names generated from
+/// the IDs are unique too. The input port of the bidirectional controller "Pad" is named
+/// "Pad_in", so it collides with an input controller whose ID is "Pad_in", even though
+/// the IDs differ.
 fn check_duplicate_ids(config: &Config) -> Result<(), SpawnRtThreadError> {
     let mut ids = std::collections::HashSet::new();
 
@@ -177,16 +1116,526 @@ fn check_duplicate_ids(config: &Config) -> Result<(), SpawnRtThreadError> {
         }
     }
 
+    // A midi controller may appear once in the input list and once in the output list
+    // to represent a bidirectional controller, so each direction is checked separately.
+    let mut midi_in_ids = std::collections::HashSet::new();
     for in_controller in config.midi_in_controllers.iter() {
-        if !ids.insert(in_controller.id.clone()) {
+        if ids.contains(&in_controller.id) || !midi_in_ids.insert(in_controller.id.clone()) {
             return Err(SpawnRtThreadError::IdNotUnique(in_controller.id.clone()));
         }
     }
+    let mut midi_out_ids = std::collections::HashSet::new();
     for out_controller in config.midi_out_controllers.iter() {
-        if !ids.insert(out_controller.id.clone()) {
+        if ids.contains(&out_controller.id) || !midi_out_ids.insert(out_controller.id.clone()) {
             return Err(SpawnRtThreadError::IdNotUnique(out_controller.id.clone()));
         }
     }
 
+    check_port_names(config)
+}
+
+/// Check that no two busses/controllers would create ports with the same name, e.g. the
+/// audio bus "Mic" (with the port "Mic_1") and the midi controller "Mic_1". Backends like
+/// Jack would otherwise fail to register the second port.
+fn check_port_names(config: &Config) -> Result<(), SpawnRtThreadError> {
+    let mut port_names = std::collections::HashSet::new();
+
+    for bus in config
+        .audio_in_busses
+        .iter()
+        .chain(config.audio_out_busses.iter())
+    {
+        for channel in 0..bus.channel_layout().num_channels() {
+            if !port_names.insert(bus.port_name(channel)) {
+                return Err(SpawnRtThreadError::PortNameNotUnique(bus.id.clone()));
+            }
+        }
+    }
+    for controller in config.midi_in_controllers.iter() {
+        if !port_names.insert(config.midi_in_port_name(&controller.id)) {
+            return Err(SpawnRtThreadError::PortNameNotUnique(controller.id.clone()));
+        }
+    }
+    for controller in config.midi_out_controllers.iter() {
+        if !port_names.insert(config.midi_out_port_name(&controller.id)) {
+            return Err(SpawnRtThreadError::PortNameNotUnique(controller.id.clone()));
+        }
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoopHandler;
+
+    impl RtProcessHandler for NoopHandler {
+        fn init(&mut self, _stream_info: &StreamInfo) {}
+        fn process(&mut self, _proc_info: ProcessInfo) -> ProcessControl {
+            ProcessControl::Continue
+        }
+    }
+
+    impl FatalErrorHandler for NoopHandler {
+        fn fatal_stream_error(self, _error: FatalStreamError) {}
+    }
+
+    #[test]
+    fn backend_not_compiled() {
+        let config = Config {
+            audio_server: Backend::CoreAudio.to_string(),
+            ..Config::default()
+        };
+        assert_eq!(config.backend(), Some(Backend::CoreAudio));
+
+        assert!(matches!(
+            spawn_rt_thread(&config, None, NoopHandler, NoopHandler),
+            Err(SpawnError {
+                error: SpawnRtThreadError::BackendNotCompiled(Backend::CoreAudio),
+                rt_process_handler: Some(NoopHandler),
+            })
+        ));
+
+        let config = Config {
+            audio_server: String::from("Not A Server"),
+            ..Config::default()
+        };
+        assert!(matches!(
+            spawn_rt_thread(&config, None, NoopHandler, NoopHandler),
+            Err(SpawnError {
+                error: SpawnRtThreadError::AudioServerUnavailable(_),
+                rt_process_handler: Some(_),
+            })
+        ));
+    }
+
+    #[test]
+    fn half_duplex_streams() {
+        let mut config = Config {
+            audio_server: String::from(DUMMY_SERVER_NAME),
+            system_audio_device: String::from(DUMMY_DEVICE_NAME),
+            audio_in_busses: vec![AudioBusConfig {
+                id: String::from("Mic"),
+                system_ports: vec![String::from("capture_1")],
+                ..Default::default()
+            }],
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Speakers"),
+                system_ports: vec![String::from("playback_1"), String::from("playback_2")],
+                ..Default::default()
+            }],
+            direction: StreamDirection::OutputOnly,
+            ..Config::default()
+        };
+
+        let stream = spawn_rt_thread(&config, None, NoopHandler, NoopHandler).unwrap();
+        assert!(stream.stream_info().audio_in.is_empty());
+        assert_eq!(stream.stream_info().audio_out.len(), 1);
+        drop(stream);
+
+        config.direction = StreamDirection::InputOnly;
+        let stream = spawn_rt_thread(&config, None, NoopHandler, NoopHandler).unwrap();
+        assert_eq!(stream.stream_info().audio_in.len(), 1);
+        assert!(stream.stream_info().audio_out.is_empty());
+        drop(stream);
+
+        // The same without a fatal error handler of its own.
+        let stream = spawn_rt_thread_simple(&config, None, NoopHandler).unwrap();
+        assert_eq!(stream.stream_info().audio_in.len(), 1);
+        assert!(stream.stop().is_some());
+    }
+
+    #[test]
+    fn streams_can_be_stored_without_their_handler_types() {
+        let config = Config {
+            audio_server: String::from(DUMMY_SERVER_NAME),
+            system_audio_device: String::from(DUMMY_DEVICE_NAME),
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Speakers"),
+                system_ports: vec![String::from("playback_1"), String::from("playback_2")],
+                ..Default::default()
+            }],
+            ..Config::default()
+        };
+
+        let mut stream: Box<dyn AnyStreamHandle> =
+            Box::new(spawn_rt_thread(&config, None, NoopHandler, NoopHandler).unwrap());
+        assert_eq!(stream.stream_info().audio_out[0].id_name, "Speakers");
+        assert_eq!(stream.xrun_count(), 0);
+        assert!(stream.port_connections("Speakers").is_none());
+        assert!(matches!(
+            stream.add_audio_out_bus(&config.audio_out_busses[0]),
+            Err(AddBusError::NotSupported(Backend::Dummy))
+        ));
+
+        let handler = stream.stop().unwrap();
+        assert!(handler.downcast::<NoopHandler>().is_ok());
+    }
+
+    fn midi_controller(id: &str, system_port: &str) -> MidiControllerConfig {
+        MidiControllerConfig {
+            id: String::from(id),
+            system_port: String::from(system_port),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bidirectional_midi_controller() {
+        let config = Config {
+            audio_server: String::from("Jack"),
+            system_audio_device: String::from("Jack"),
+
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Speakers Out"),
+                system_ports: vec![String::from("system:playback_1")],
+                ..Default::default()
+            }],
+
+            midi_server: Some(String::from("Jack")),
+            midi_in_controllers: vec![
+                midi_controller("Control Surface", "system:midi_capture_2"),
+                midi_controller("Keys", "system:midi_capture_3"),
+            ],
+            midi_out_controllers: vec![midi_controller(
+                "Control Surface",
+                "system:midi_playback_2",
+            )],
+
+            ..Config::default()
+        };
+
+        assert!(check_duplicate_ids(&config).is_ok());
+
+        assert!(config.is_bidirectional_midi_controller("Control Surface"));
+        assert!(!config.is_bidirectional_midi_controller("Keys"));
+
+        assert_eq!(
+            config.midi_in_port_name("Control Surface"),
+            "Control Surface_in"
+        );
+        assert_eq!(
+            config.midi_out_port_name("Control Surface"),
+            "Control Surface_out"
+        );
+        assert_eq!(config.midi_in_port_name("Keys"), "Keys");
+
+        // The same id must still be unique within a single direction.
+        let mut dup_config = config.clone();
+        dup_config
+            .midi_out_controllers
+            .push(midi_controller("Control Surface", "system:midi_playback_3"));
+        assert!(matches!(
+            check_duplicate_ids(&dup_config),
+            Err(SpawnRtThreadError::IdNotUnique(_))
+        ));
+
+        // Midi controllers may not share an id with an audio bus.
+        let mut dup_config = config.clone();
+        dup_config
+            .midi_in_controllers
+            .push(midi_controller("Speakers Out", "system:midi_capture_4"));
+        assert!(matches!(
+            check_duplicate_ids(&dup_config),
+            Err(SpawnRtThreadError::IdNotUnique(_))
+        ));
+
+        // The id differs, but the port name is the one generated for the input of the
+        // bidirectional controller.
+        let mut dup_config = config;
+        dup_config.midi_in_controllers.push(midi_controller(
+            "Control Surface_in",
+            "system:midi_capture_4",
+        ));
+        assert!(matches!(
+            check_duplicate_ids(&dup_config),
+            Err(SpawnRtThreadError::PortNameNotUnique(id)) if id == "Control Surface_in"
+        ));
+    }
+
+    #[test]
+    fn colliding_port_names() {
+        let mut config = Config {
+            audio_in_busses: vec![AudioBusConfig {
+                id: String::from("Mic"),
+                system_ports: vec![String::from("system:capture_1")],
+                ..Default::default()
+            }],
+            midi_server: Some(String::from("Jack")),
+            midi_in_controllers: vec![
+                midi_controller("Pad", "system:midi_capture_1"),
+                midi_controller("Mic_2", "system:midi_capture_2"),
+            ],
+            midi_out_controllers: vec![midi_controller("Pad", "system:midi_playback_1")],
+            ..Config::default()
+        };
+        assert!(check_duplicate_ids(&config).is_ok());
+        assert!(check_port_names(&config).is_ok());
+
+        // The ids differ, but the ports would both be named "Mic_1".
+        config.midi_in_controllers[1].id = String::from("Mic_1");
+        assert!(matches!(
+            spawn_config(&config),
+            Err(SpawnRtThreadError::PortNameNotUnique(id)) if id == "Mic_1"
+        ));
+
+        // The input port of the bidirectional "Pad" is named "Pad_in".
+        config.midi_in_controllers[1].id = String::from("Pad_in");
+        assert!(matches!(
+            check_port_names(&config),
+            Err(SpawnRtThreadError::PortNameNotUnique(id)) if id == "Pad_in"
+        ));
+    }
+
+    #[test]
+    fn custom_port_names() {
+        let mut config = Config {
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Speakers"),
+                system_ports: vec![
+                    String::from("system:playback_1"),
+                    String::from("system:playback_2"),
+                ],
+                port_names: Some(vec![String::from("out_L")]),
+                ..Default::default()
+            }],
+            midi_server: Some(String::from("Jack")),
+            midi_in_controllers: vec![midi_controller("Pad", "system:midi_capture_1")],
+            midi_out_controllers: vec![midi_controller("Pad", "system:midi_playback_1")],
+            ..Config::default()
+        };
+        assert_eq!(config.audio_out_busses[0].port_name(0), "out_L");
+        assert_eq!(config.audio_out_busses[0].port_name(1), "Speakers_2");
+
+        config.midi_in_controllers[0].port_name = Some(String::from("pads"));
+        assert_eq!(config.midi_in_port_name("Pad"), "pads");
+        assert_eq!(config.midi_out_port_name("Pad"), "Pad_out");
+        assert!(check_port_names(&config).is_ok());
+
+        // Custom names are checked for collisions too.
+        config.midi_in_controllers[0].port_name = Some(String::from("out_L"));
+        assert!(matches!(
+            check_port_names(&config),
+            Err(SpawnRtThreadError::PortNameNotUnique(id)) if id == "Pad"
+        ));
+
+        // Renaming duplicate ids doesn't rename the custom names.
+        config.on_duplicate_id = FailOrRename::Rename;
+        assert!(matches!(
+            spawn_config(&config),
+            Err(SpawnRtThreadError::PortNameNotUnique(id)) if id == "Pad"
+        ));
+    }
+
+    #[test]
+    fn duplicate_id_policy() {
+        let mut config = Config {
+            audio_server: String::from("Jack"),
+            system_audio_device: String::from("Jack"),
+
+            audio_in_busses: vec![
+                AudioBusConfig {
+                    id: String::from("Mic In"),
+                    system_ports: vec![String::from("system:capture_1")],
+                    ..Default::default()
+                },
+                AudioBusConfig {
+                    id: String::from("Mic In"),
+                    system_ports: vec![String::from("system:capture_2")],
+                    ..Default::default()
+                },
+            ],
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Mic In #2"),
+                system_ports: vec![String::from("system:playback_1")],
+                ..Default::default()
+            }],
+
+            midi_server: Some(String::from("Jack")),
+            midi_in_controllers: vec![
+                midi_controller("Control Surface", "system:midi_capture_2"),
+                midi_controller("Control Surface", "system:midi_capture_3"),
+            ],
+            midi_out_controllers: vec![midi_controller(
+                "Control Surface",
+                "system:midi_playback_2",
+            )],
+
+            ..Config::default()
+        };
+
+        assert!(matches!(
+            resolve_duplicate_ids(&config),
+            Err(SpawnRtThreadError::IdNotUnique(id)) if id == "Mic In"
+        ));
+
+        config.on_duplicate_id = FailOrRename::Rename;
+        let renamed = resolve_duplicate_ids(&config).unwrap();
+        assert!(check_duplicate_ids(&renamed).is_ok());
+
+        let ids = |busses: &[AudioBusConfig]| -> Vec<String> {
+            busses.iter().map(|b| b.id.clone()).collect()
+        };
+        assert_eq!(ids(&renamed.audio_in_busses), vec!["Mic In", "Mic In #3"]);
+        assert_eq!(ids(&renamed.audio_out_busses), vec!["Mic In #2"]);
+        assert_eq!(renamed.midi_in_controllers[1].id, "Control Surface #2");
+        assert_eq!(renamed.midi_out_controllers[0].id, "Control Surface");
+
+        // The generated port names follow the new ids, and the first "Control Surface"
+        // is still a bidirectional controller.
+        assert_eq!(
+            renamed.midi_in_port_name("Control Surface #2"),
+            "Control Surface #2"
+        );
+        assert_eq!(
+            renamed.midi_in_port_name("Control Surface"),
+            "Control Surface_in"
+        );
+
+        assert_eq!(
+            config.rename_duplicate_ids(),
+            vec![
+                IdRename {
+                    old_id: String::from("Mic In"),
+                    new_id: String::from("Mic In #3"),
+                },
+                IdRename {
+                    old_id: String::from("Control Surface"),
+                    new_id: String::from("Control Surface #2"),
+                },
+            ]
+        );
+        assert!(config.rename_duplicate_ids().is_empty());
+    }
+
+    #[test]
+    fn channel_layouts() {
+        let surround = ChannelLayout::Positions(vec![
+            ChannelPosition::Left,
+            ChannelPosition::Right,
+            ChannelPosition::Center,
+        ]);
+        let mut config = Config {
+            audio_server: String::from("Jack"),
+            system_audio_device: String::from("Jack"),
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Speakers Out"),
+                system_ports: vec![
+                    String::from("system:playback_1"),
+                    String::from("system:playback_2"),
+                ],
+                channel_layout: Some(surround.clone()),
+                ..Default::default()
+            }],
+            ..Config::default()
+        };
+
+        assert!(matches!(
+            check_channel_layouts(&config),
+            Err(SpawnRtThreadError::ChannelLayoutMismatch(id)) if id == "Speakers Out"
+        ));
+
+        // The center channel can be summed into the stereo ports instead.
+        config.audio_out_busses[0].port_mixing = PortMixing::Minus3Db;
+        assert!(check_channel_layouts(&config).is_ok());
+        config.audio_out_busses[0].port_mixing = PortMixing::Off;
+
+        config.audio_out_busses[0]
+            .system_ports
+            .push(String::from("system:playback_3"));
+        assert!(check_channel_layouts(&config).is_ok());
+
+        let bus = AudioBus {
+            id_name: String::from("Speakers Out"),
+            id_index: DeviceIndex::new(0),
+            system_device: String::from("Jack"),
+            system_half_duplex_device: None,
+            system_ports: config.audio_out_busses[0].system_ports.clone(),
+            client_ports: Vec::new(),
+            channels: 3,
+            channel_layout: config.audio_out_busses[0].channel_layout(),
+            connected_ports: 3,
+        };
+        let mut buffer = AudioBusBuffer::for_bus(&bus, 4);
+        buffer.clear_and_resize(4);
+        buffer.channel_mut(ChannelPosition::Center).unwrap()[0] = 1.0;
+
+        assert_eq!(buffer.layout(), &surround);
+        assert_eq!(buffer[2][0], 1.0);
+        assert_eq!(buffer.channel(ChannelPosition::Left), Some(&[0.0; 4][..]));
+        assert_eq!(buffer.channel(ChannelPosition::Lfe), None);
+    }
+
+    #[test]
+    fn input_buffers_measure_peaks() {
+        let bus = AudioBus {
+            id_name: String::from("Mic In"),
+            id_index: DeviceIndex::new(0),
+            system_device: String::from("Jack"),
+            system_half_duplex_device: None,
+            system_ports: vec![
+                String::from("system:capture_1"),
+                String::from("system:capture_2"),
+            ],
+            client_ports: Vec::new(),
+            channels: 2,
+            channel_layout: ChannelLayout::Stereo,
+            connected_ports: 2,
+        };
+
+        let mut buffer = AudioBusBuffer::for_input_bus(&bus, 4, false);
+        buffer.clear_and_resize(4);
+        buffer.update_peaks();
+        assert_eq!(buffer.peak(0), None);
+
+        let mut buffer = AudioBusBuffer::for_input_bus(&bus, 4, true);
+        buffer.clear_and_resize(4);
+        buffer[0].copy_from_slice(&[0.25, -0.5, 0.0, 0.1]);
+        buffer.update_peaks();
+        assert_eq!(buffer.peaks(), Some(&[0.5, 0.0][..]));
+
+        // Only the frames of this cycle count.
+        buffer.clear_and_resize(1);
+        buffer[1][0] = 0.75;
+        buffer.update_peaks();
+        assert_eq!(buffer.peak(0), Some(0.0));
+        assert_eq!(buffer.peak(1), Some(0.75));
+        assert_eq!(buffer.peak(2), None);
+    }
+
+    #[test]
+    fn midi_in_events_are_tagged_with_their_controller() {
+        let mut keys = MidiControllerBuffer::new();
+        keys.push_raw(0, &[0x90, 60, 100]).unwrap();
+        keys.push_raw(20, &[0x80, 60, 0]).unwrap();
+        let mut pads = MidiControllerBuffer::new();
+        pads.push_raw(10, &[0x99, 36, 127]).unwrap();
+
+        let midi_in = [keys, pads];
+        let proc_info = ProcessInfo {
+            audio_in: &[],
+            audio_out: &mut [],
+            audio_frames: 32,
+            midi_in: &midi_in,
+            midi_out: &mut [],
+            sample_rate: 48000,
+            transport: None,
+            cycle_start_frame: 0,
+            cycle_start_time: Instant::now(),
+        };
+
+        let events: Vec<(usize, u32)> = proc_info
+            .midi_in_events()
+            .map(|(controller, event)| (controller.index(), event.delta_frames))
+            .collect();
+        assert_eq!(events, vec![(0, 0), (0, 20), (1, 10)]);
+
+        let events: Vec<(usize, u32)> = proc_info
+            .midi_in_events_sorted()
+            .map(|(controller, event)| (controller.index(), event.delta_frames))
+            .collect();
+        assert_eq!(events, vec![(0, 0), (1, 10), (0, 20)]);
+    }
+}
@@ -0,0 +1,1154 @@
+use alsa::pcm::{Access, Format, HwParams, IoFormat, PCM};
+use alsa::{Direction, ValueOr};
+use log::{debug, info, warn};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use crate::channel_gain::{ChannelGains, ChannelGainsHandle};
+use crate::cycle_debug::{cycle_debugger, CycleDebugHandle, CycleDebugInfo, CycleDebugger};
+use crate::denormals::DenormalGuard;
+use crate::diagnostics::BufferSizeHistory;
+use crate::enumeration::probe_channels;
+use crate::master_gain::{MasterGain, MasterGainHandle};
+use crate::port_mixing::{channel_routes, ChannelRoute};
+use crate::process_panic::catch_process_panic;
+use crate::rt_thread::promote_thread;
+use crate::sample_format::{Dither, Sample, I24};
+use crate::shutdown_hook::ShutdownHook;
+use crate::silence_detect::{SilenceDetectHandle, SilenceDetector};
+use crate::spawn_deadline::SpawnDeadline;
+use crate::xruns::Xruns;
+use crate::{
+    AudioBus, AudioBusBuffer, AudioBusConfig, AudioDeviceInfo, AudioServerInfo, Backend,
+    BufferSizeRange, Config, DeviceIndex, DisconnectReason, EnumerationError, FatalErrorHandler,
+    FatalStreamError, MidiServerInfo, ProcessControl, ProcessInfo, RtProcessHandler, SampleFormat,
+    SpawnError, SpawnPhase, SpawnRtThreadError, StreamDiagnostics, StreamDirection, StreamInfo,
+};
+
+/// ALSA has no real concept of a "maximum channel count", so these are the configurations that
+/// are probed when enumerating devices.
+static PROBE_CHANNELS: [u16; 12] = [1, 2, 4, 6, 8, 10, 12, 16, 18, 20, 24, 32];
+static PROBE_SAMPLE_RATES: [u32; 6] = [44100, 48000, 88200, 96000, 176400, 192000];
+
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+const DEFAULT_BUFFER_SIZE: u32 = 512;
+const NUM_PERIODS: u32 = 2;
+
+const PLAYBACK_PORT_PREFIX: &str = "playback_";
+const CAPTURE_PORT_PREFIX: &str = "capture_";
+
+pub fn refresh_audio_server(server: &mut AudioServerInfo) -> Result<(), EnumerationError> {
+    info!("Refreshing list of available ALSA audio devices...");
+
+    server.devices.clear();
+
+    // Whether a device could not be opened because the OS denied access to it.
+    let mut permission_denied = false;
+
+    for card in alsa::card::Iter::new() {
+        let card = match card {
+            Ok(card) => card,
+            Err(e) => {
+                warn!("ALSA: failed to enumerate sound card: {}", e);
+                permission_denied |= is_permission_denied(&e);
+                continue;
+            }
+        };
+
+        let name = format!("hw:{}", card.get_index());
+
+        let playback = match probe_pcm(&name, Direction::Playback) {
+            Ok(Some(playback)) => playback,
+            result => {
+                if let Err(e) = &result {
+                    permission_denied |= is_permission_denied(e);
+                }
+
+                // This crate only allows devices with playback.
+                info!(
+                    "ALSA: skipping device {} since it has no usable playback",
+                    &name
+                );
+                continue;
+            }
+        };
+        let capture = probe_pcm(&name, Direction::Capture).ok().flatten();
+
+        let in_ports = capture
+            .as_ref()
+            .map(|c| port_names(CAPTURE_PORT_PREFIX, c.max_channels))
+            .unwrap_or_default();
+        let out_ports = port_names(PLAYBACK_PORT_PREFIX, playback.max_channels);
+
+        // Only report the sample rates both directions can run at.
+        let sample_rates: Vec<u32> = if let Some(capture) = &capture {
+            playback
+                .sample_rates
+                .iter()
+                .copied()
+                .filter(|rate| capture.sample_rates.contains(rate))
+                .collect()
+        } else {
+            playback.sample_rates.clone()
+        };
+        if sample_rates.is_empty() {
+            info!(
+                "ALSA: skipping device {} since it supports none of the probed sample rates",
+                &name
+            );
+            continue;
+        }
+
+        let default_sample_rate_index = sample_rates
+            .iter()
+            .position(|rate| *rate == DEFAULT_SAMPLE_RATE)
+            .or_else(|| sample_rates.iter().position(|rate| *rate == 44100))
+            .unwrap_or(0);
+
+        let buffer_size_range = playback.buffer_size_range;
+        let duplex_buffer_size_range = capture
+            .as_ref()
+            .and_then(|c| buffer_size_range.intersection(&c.buffer_size_range));
+        let default_buffer_size =
+            DEFAULT_BUFFER_SIZE.clamp(buffer_size_range.min, buffer_size_range.max);
+        let default_in_port = if in_ports.is_empty() { None } else { Some(0) };
+
+        debug!(
+            "ALSA: found device {} with {} in ports, {} out ports, sample rates {:?}, buffer sizes {}..{}",
+            &name,
+            in_ports.len(),
+            out_ports.len(),
+            &sample_rates,
+            buffer_size_range.min,
+            buffer_size_range.max,
+        );
+
+        server.devices.push(AudioDeviceInfo {
+            name,
+            in_ports,
+            out_ports,
+            sample_rates,
+            buffer_size_range,
+            duplex_buffer_size_range,
+
+            default_in_port,
+            default_out_port_left: 0,
+            default_out_port_right: 1.min(playback.max_channels as usize - 1), // Second port if stereo, first if mono.
+            default_sample_rate_index,
+            default_buffer_size,
+        });
+    }
+
+    server.available = !server.devices.is_empty();
+
+    if !server.available {
+        if permission_denied {
+            info!("ALSA server is unavailable: access to the ALSA devices was denied.");
+
+            return Err(EnumerationError::PermissionDenied(Backend::Alsa));
+        }
+
+        info!("ALSA server is unavailable: no ALSA device with playback was found.");
+    }
+
+    Ok(())
+}
+
+fn is_permission_denied(e: &alsa::Error) -> bool {
+    match e.errno() {
+        Some(errno) => errno as i32 == libc::EACCES || errno as i32 == libc::EPERM,
+        None => false,
+    }
+}
+
+pub fn refresh_midi_server(server: &mut MidiServerInfo) -> Result<(), EnumerationError> {
+    info!("Refreshing list of available ALSA MIDI devices...");
+
+    server.in_devices.clear();
+    server.out_devices.clear();
+
+    // TODO: Support ALSA sequencer MIDI.
+    server.available = false;
+
+    info!("ALSA MIDI server is unavailable: ALSA MIDI is not supported yet.");
+
+    Ok(())
+}
+
+/// The configurations a PCM could actually be opened with.
+struct PcmProbe {
+    max_channels: u32,
+    sample_rates: Vec<u32>,
+    buffer_size_range: BufferSizeRange,
+}
+
+/// Probe a PCM, or `None` if it opens but can't be used. Fails if it can't be opened.
+fn probe_pcm(name: &str, direction: Direction) -> Result<Option<PcmProbe>, alsa::Error> {
+    // Open in non-blocking mode so a device that is in use doesn't stall the enumeration.
+    let pcm = match PCM::new(name, direction, true) {
+        Ok(pcm) => pcm,
+        Err(e) => {
+            debug!("ALSA: could not open {} for {:?}: {}", name, direction, e);
+            return Err(e);
+        }
+    };
+
+    Ok(probe_hw_params(&pcm))
+}
+
+fn probe_hw_params(pcm: &PCM) -> Option<PcmProbe> {
+    let hwp = HwParams::any(pcm).ok()?;
+
+    let max_channels = u32::from(probe_channels(&PROBE_CHANNELS, |channels| {
+        hwp.test_channels(u32::from(channels)).is_ok()
+    })?);
+
+    let sample_rates: Vec<u32> = PROBE_SAMPLE_RATES
+        .iter()
+        .copied()
+        .filter(|rate| hwp.test_rate(*rate).is_ok())
+        .collect();
+
+    let min = hwp.get_period_size_min().ok()?;
+    let max = hwp.get_period_size_max().ok()?;
+
+    Some(PcmProbe {
+        max_channels,
+        sample_rates,
+        buffer_size_range: BufferSizeRange {
+            min: min.max(1) as u32,
+            max: max.min(u32::MAX as alsa::pcm::Frames) as u32,
+        },
+    })
+}
+
+/// Fail if an opened PCM doesn't support a period size of `requested` frames, instead of
+/// letting ALSA pick the nearest one.
+fn check_period_size(requested: u32, pcms: &[&Option<PCM>]) -> Result<(), SpawnRtThreadError> {
+    for pcm in pcms.iter().filter_map(|pcm| pcm.as_ref()) {
+        if let Some(probe) = probe_hw_params(pcm) {
+            if !probe.buffer_size_range.contains(requested) {
+                return Err(SpawnRtThreadError::UnsupportedBufferSize {
+                    requested,
+                    range: probe.buffer_size_range,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The maximum number of playback channels of a device out of `PROBE_CHANNELS`.
+pub fn probe_max_channels(device: &str) -> Option<u16> {
+    probe_pcm(device, Direction::Playback)
+        .ok()
+        .flatten()
+        .map(|probe| probe.max_channels as u16)
+}
+
+fn port_names(prefix: &str, channels: u32) -> Vec<String> {
+    (1..=channels).map(|i| format!("{}{}", prefix, i)).collect()
+}
+
+/// Parse the device channel a port like "playback_1" refers to.
+fn parse_port_channel(port: &str, prefix: &str) -> Option<usize> {
+    let n: usize = port.strip_prefix(prefix)?.parse().ok()?;
+
+    if n == 0 {
+        None
+    } else {
+        Some(n - 1)
+    }
+}
+
+pub struct AlsaRtThreadHandle<P: RtProcessHandler> {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<P>>,
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_debug_handle: CycleDebugHandle,
+    pub(super) master_gain_handle: MasterGainHandle,
+    pub(super) channel_gains_handle: ChannelGainsHandle,
+    pub(super) silence_detect_handle: SilenceDetectHandle,
+    pub(super) shutdown_hook: Arc<ShutdownHook>,
+    pub(super) xruns: Arc<Xruns>,
+}
+
+impl<P: RtProcessHandler> AlsaRtThreadHandle<P> {
+    /// Stop the audio thread, close the device, and return the process handler.
+    ///
+    /// Returns `None` if the stream was already stopped or the audio thread panicked.
+    pub fn stop(&mut self) -> Option<P> {
+        let thread = self.thread.take()?;
+
+        self.shutdown_hook.run();
+
+        self.running.store(false, Ordering::Relaxed);
+
+        match thread.join() {
+            Ok(rt_process_handler) => Some(rt_process_handler),
+            Err(_) => {
+                warn!("ALSA: audio thread panicked");
+                None
+            }
+        }
+    }
+
+    /// The total number of frames that have been processed since the stream started.
+    pub fn frames_processed(&self) -> u64 {
+        self.frames_processed.load(Ordering::Relaxed)
+    }
+
+    /// Log detailed diagnostics for the next `n` process cycles, after which logging
+    /// stops automatically.
+    pub fn debug_next_cycles(&self, n: u32) {
+        self.cycle_debug_handle.debug_next_cycles(n);
+    }
+
+    /// A snapshot of the health of the stream.
+    pub fn diagnostics(&self) -> StreamDiagnostics {
+        StreamDiagnostics {
+            frames_processed: self.frames_processed(),
+            xruns: self.xruns.count(),
+            cycle_debug_queue: self.cycle_debug_handle.queue_stats(),
+            ..StreamDiagnostics::default()
+        }
+    }
+
+    /// The number of frames of each of the last few process cycles, oldest first.
+    pub fn recent_buffer_sizes(&self) -> Vec<u32> {
+        self.buffer_size_history.recent()
+    }
+}
+
+impl<P: RtProcessHandler> Drop for AlsaRtThreadHandle<P> {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+pub fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
+    config: &Config,
+    rt_process_handler: P,
+    fatal_error_handler: E,
+) -> Result<(StreamInfo, AlsaRtThreadHandle<P>), SpawnError<P>> {
+    let mut rt_process_handler = Some(rt_process_handler);
+    spawn(config, &mut rt_process_handler, fatal_error_handler)
+        .map_err(|e| SpawnError::new(e, rt_process_handler))
+}
+
+/// Spawn the stream, leaving the handler in `rt_process_handler_slot` if it fails.
+fn spawn<P: RtProcessHandler, E: FatalErrorHandler>(
+    config: &Config,
+    rt_process_handler_slot: &mut Option<P>,
+    fatal_error_handler: E,
+) -> Result<(StreamInfo, AlsaRtThreadHandle<P>), SpawnRtThreadError> {
+    info!("Spawning ALSA thread...");
+
+    let deadline = SpawnDeadline::new(config.spawn_timeout);
+
+    let device = config.system_audio_device.clone();
+
+    // Map every bus channel to its device channel.
+
+    let (audio_in_busses, in_channel_map) =
+        map_busses(&config.audio_in_busses, &device, CAPTURE_PORT_PREFIX, true)?;
+    let (audio_out_busses, out_channel_map) = map_busses(
+        &config.audio_out_busses,
+        &device,
+        PLAYBACK_PORT_PREFIX,
+        false,
+    )?;
+
+    if config.midi_enabled() {
+        warn!("ALSA: MIDI is not supported yet, no MIDI controllers will be created");
+    }
+
+    // Open the device. This blocks while another client has it open.
+
+    let open_device = |direction| {
+        let device_name = device.clone();
+        deadline
+            .run(SpawnPhase::Open, move || {
+                PCM::new(&device_name, direction, false)
+            })?
+            .map_err(|_| SpawnRtThreadError::SystemDeviceNotFound(device.clone()))
+    };
+
+    let playback = if config.direction.has_output() {
+        Some(open_device(Direction::Playback)?)
+    } else {
+        None
+    };
+    let capture = if config.direction == StreamDirection::InputOnly || !audio_in_busses.is_empty() {
+        Some(open_device(Direction::Capture)?)
+    } else {
+        None
+    };
+
+    // Use the first sample format that every opened PCM supports.
+    let format = [
+        <f32 as IoFormat>::FORMAT,
+        <i32 as IoFormat>::FORMAT,
+        <I24 as IoFormat>::FORMAT,
+        <i16 as IoFormat>::FORMAT,
+    ]
+    .iter()
+    .copied()
+    .find(|format| {
+        [&playback, &capture].iter().all(|pcm| {
+            pcm.as_ref()
+                .map(|pcm| supports_format(pcm, *format))
+                .unwrap_or(true)
+        })
+    })
+    .ok_or_else(|| SpawnRtThreadError::SystemDeviceNotFound(device.clone()))?;
+
+    if let Some(requested) = config.buffer_size {
+        check_period_size(requested, &[&playback, &capture])?;
+    }
+
+    let mut sample_rate = config.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+    let mut period_size = config.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+
+    let out_channels = if let Some(playback) = &playback {
+        let (out_channels, out_sample_rate, out_period_size) = configure_pcm(
+            playback,
+            format,
+            min_channels(&out_channel_map),
+            sample_rate,
+            period_size,
+        )?;
+        sample_rate = out_sample_rate;
+        period_size = out_period_size;
+
+        out_channels
+    } else {
+        0
+    };
+
+    let in_channels = if let Some(capture) = &capture {
+        let (in_channels, in_sample_rate, in_period_size) = configure_pcm(
+            capture,
+            format,
+            min_channels(&in_channel_map),
+            sample_rate,
+            period_size,
+        )?;
+
+        if let Some(playback) = &playback {
+            if in_sample_rate != sample_rate || in_period_size != period_size {
+                return Err(SpawnRtThreadError::PlatformSpecific(Box::new(
+                    AlsaConfigMismatch {
+                        device: device.clone(),
+                    },
+                )));
+            }
+
+            if let Err(e) = playback.link(capture) {
+                // Not every device supports linking. The streams will still run in lockstep
+                // since every cycle does one blocking read and one blocking write.
+                debug!("ALSA: could not link capture and playback: {}", e);
+            }
+        } else {
+            sample_rate = in_sample_rate;
+            period_size = in_period_size;
+        }
+
+        in_channels
+    } else {
+        0
+    };
+
+    deadline.check(SpawnPhase::RegisterPorts)?;
+
+    let stream_info = StreamInfo {
+        server_name: String::from("ALSA"),
+        audio_in: audio_in_busses,
+        audio_out: audio_out_busses,
+        midi_in: Vec::new(),
+        midi_out: Vec::new(),
+        sample_rate,
+        max_audio_buffer_size: period_size,
+        // A captured period can only be read once it is full, and a written period is
+        // played after the ones that are already queued.
+        latency_frames_in: if capture.is_some() { period_size } else { 0 },
+        latency_frames_out: if playback.is_some() {
+            period_size * NUM_PERIODS
+        } else {
+            0
+        },
+        sample_format: match format {
+            f if f == <f32 as IoFormat>::FORMAT => SampleFormat::F32,
+            f if f == <i32 as IoFormat>::FORMAT => SampleFormat::I32,
+            f if f == <I24 as IoFormat>::FORMAT => SampleFormat::I24,
+            _ => SampleFormat::I16,
+        },
+    };
+
+    // From here on the handler can only be given back by stopping the audio thread.
+    let mut rt_process_handler = rt_process_handler_slot
+        .take()
+        .expect("the handler is only taken once");
+    rt_process_handler.init(&stream_info);
+
+    let (cycle_debugger, cycle_debug_handle) = cycle_debugger();
+
+    let process = AlsaProcess {
+        rt_process_handler,
+        fatal_error_handler: Some(fatal_error_handler),
+        device: device.clone(),
+        playback,
+        capture,
+        in_channels: in_channels as usize,
+        out_channels: out_channels as usize,
+        in_channel_map,
+        out_channel_map,
+        stream_info: stream_info.clone(),
+        running: Arc::new(AtomicBool::new(true)),
+        frames_processed: Arc::new(AtomicU64::new(0)),
+        buffer_size_history: Arc::new(BufferSizeHistory::new(config.buffer_size_history_len())),
+        cycle_debugger,
+        master_gain: MasterGain::new(),
+        channel_gains: ChannelGains::new(&config.audio_in_busses, &config.audio_out_busses),
+        silence_detector: SilenceDetector::new(
+            stream_info
+                .audio_in
+                .iter()
+                .map(|b| b.id_name.clone())
+                .collect(),
+        ),
+        input_peaks: config.input_peaks,
+        flush_denormals: !config.allow_denormals,
+        shutdown_hook: Arc::new(ShutdownHook::new()),
+        xruns: Arc::new(Xruns::new()),
+    };
+
+    let running = Arc::clone(&process.running);
+    let frames_processed = Arc::clone(&process.frames_processed);
+    let buffer_size_history = Arc::clone(&process.buffer_size_history);
+    let master_gain_handle = process.master_gain.handle();
+    let channel_gains_handle = process.channel_gains.handle();
+    let silence_detect_handle = process.silence_detector.handle();
+    let shutdown_hook = Arc::clone(&process.shutdown_hook);
+    shutdown_hook.set_stream_info(stream_info.clone());
+    let xruns = Arc::clone(&process.xruns);
+
+    let thread = std::thread::Builder::new()
+        .name(String::from("rusty-daw-io ALSA"))
+        .spawn(move || match format {
+            f if f == <f32 as IoFormat>::FORMAT => process.run::<f32>(),
+            f if f == <i32 as IoFormat>::FORMAT => process.run::<i32>(),
+            f if f == <I24 as IoFormat>::FORMAT => process.run::<I24>(),
+            _ => process.run::<i16>(),
+        })
+        .map_err(|e| SpawnRtThreadError::PlatformSpecific(Box::new(e)))?;
+
+    let mut handle = AlsaRtThreadHandle {
+        running,
+        thread: Some(thread),
+        frames_processed,
+        buffer_size_history,
+        cycle_debug_handle,
+        master_gain_handle,
+        channel_gains_handle,
+        silence_detect_handle,
+        shutdown_hook,
+        xruns,
+    };
+
+    let started = match &handle.thread {
+        Some(thread) => promote_thread(thread, &config.rt_thread),
+        None => Ok(()),
+    }
+    .and_then(|()| deadline.check(SpawnPhase::Activate));
+    if let Err(e) = started {
+        *rt_process_handler_slot = handle.stop();
+        return Err(e);
+    }
+
+    info!(
+        "Successfully spawned ALSA thread. Device: {}, Sample rate: {}, Max audio buffer size: {}, Format: {:?}",
+        &device, sample_rate, period_size, format
+    );
+
+    Ok((stream_info, handle))
+}
+
+/// Create the busses for a direction and route each of their channels to device channels.
+fn map_busses(
+    busses: &[AudioBusConfig],
+    device: &str,
+    port_prefix: &str,
+    is_input: bool,
+) -> Result<(Vec<AudioBus>, Vec<Vec<ChannelRoute<usize>>>), SpawnRtThreadError> {
+    let mut audio_busses = Vec::<AudioBus>::new();
+    let mut channel_map = Vec::<Vec<ChannelRoute<usize>>>::new();
+
+    for (bus_i, bus) in busses.iter().enumerate() {
+        if bus.system_ports.is_empty() {
+            return Err(SpawnRtThreadError::NoSystemPortsGiven(bus.id.clone()));
+        }
+
+        let mut channels = Vec::<usize>::new();
+        for system_port in bus.system_ports.iter() {
+            let channel = parse_port_channel(system_port, port_prefix).ok_or_else(|| {
+                SpawnRtThreadError::SystemPortNotFound(system_port.clone(), bus.id.clone())
+            })?;
+
+            channels.push(channel);
+        }
+
+        let channel_layout = bus.channel_layout();
+        let num_channels = channel_layout.num_channels() as u16;
+        audio_busses.push(AudioBus {
+            id_name: bus.id.clone(),
+            id_index: DeviceIndex::new(bus_i),
+            system_device: String::from(device),
+            system_half_duplex_device: None,
+            system_ports: bus.system_ports.clone(),
+            client_ports: Vec::new(),
+            channels: num_channels,
+            channel_layout,
+            connected_ports: num_channels,
+        });
+        channel_map.push(channel_routes(bus, &channels, is_input));
+    }
+
+    Ok((audio_busses, channel_map))
+}
+
+/// The minimum number of device channels needed to reach every channel in the map.
+fn min_channels(channel_map: &[Vec<ChannelRoute<usize>>]) -> u32 {
+    channel_map
+        .iter()
+        .flatten()
+        .flat_map(|route| route.ports.iter())
+        .map(|channel| *channel as u32 + 1)
+        .max()
+        .unwrap_or(1)
+}
+
+fn supports_format(pcm: &PCM, format: Format) -> bool {
+    HwParams::any(pcm)
+        .map(|hwp| hwp.test_format(format).is_ok())
+        .unwrap_or(false)
+}
+
+/// Apply the hardware parameters, returning the actual number of channels, sample rate, and
+/// period size the device was configured with.
+fn configure_pcm(
+    pcm: &PCM,
+    format: Format,
+    min_channels: u32,
+    sample_rate: u32,
+    period_size: u32,
+) -> Result<(u32, u32, u32), alsa::Error> {
+    let hwp = HwParams::any(pcm)?;
+
+    // Many devices only open with an exact channel count, so use the smallest one that
+    // fits all the requested ports.
+    let max_channels = hwp.get_channels_max()?;
+    let channels = (min_channels..=max_channels)
+        .find(|channels| hwp.test_channels(*channels).is_ok())
+        .unwrap_or(min_channels);
+
+    hwp.set_channels(channels)?;
+    hwp.set_access(Access::RWInterleaved)?;
+    hwp.set_format(format)?;
+    let sample_rate = hwp.set_rate_near(sample_rate, ValueOr::Nearest)?;
+    let period_size =
+        hwp.set_period_size_near(period_size as alsa::pcm::Frames, ValueOr::Nearest)?;
+    hwp.set_periods(NUM_PERIODS, ValueOr::Nearest)?;
+
+    pcm.hw_params(&hwp)?;
+
+    Ok((channels, sample_rate, period_size as u32))
+}
+
+struct AlsaProcess<P: RtProcessHandler, E: FatalErrorHandler> {
+    rt_process_handler: P,
+    fatal_error_handler: Option<E>,
+
+    device: String,
+    playback: Option<PCM>,
+    capture: Option<PCM>,
+
+    in_channels: usize,
+    out_channels: usize,
+
+    in_channel_map: Vec<Vec<ChannelRoute<usize>>>,
+    out_channel_map: Vec<Vec<ChannelRoute<usize>>>,
+
+    stream_info: StreamInfo,
+
+    running: Arc<AtomicBool>,
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_debugger: CycleDebugger,
+    master_gain: MasterGain,
+    channel_gains: ChannelGains,
+    silence_detector: SilenceDetector,
+    input_peaks: bool,
+    flush_denormals: bool,
+    shutdown_hook: Arc<ShutdownHook>,
+    xruns: Arc<Xruns>,
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> AlsaProcess<P, E> {
+    /// Run the stream until it is stopped or fails. This returns the process handler so
+    /// it can be handed back from `AlsaRtThreadHandle::stop()`.
+    fn run<S: AlsaSample>(self) -> P {
+        let mut rt_process_handler = self.run_stream::<S>();
+        rt_process_handler.deinit();
+        rt_process_handler
+    }
+
+    fn run_stream<S: AlsaSample>(self) -> P {
+        let AlsaProcess {
+            mut rt_process_handler,
+            mut fatal_error_handler,
+            device,
+            playback,
+            capture,
+            in_channels,
+            out_channels,
+            in_channel_map,
+            out_channel_map,
+            stream_info,
+            running,
+            frames_processed,
+            buffer_size_history,
+            mut cycle_debugger,
+            mut master_gain,
+            mut channel_gains,
+            mut silence_detector,
+            input_peaks,
+            flush_denormals,
+            shutdown_hook,
+            xruns,
+        } = self;
+
+        let device_lost = |e: alsa::Error| FatalStreamError::AudioDeviceDisconnected {
+            backend: Backend::Alsa,
+            reason: DisconnectReason::DeviceRemoved,
+            message: format!("ALSA: stream on device {} failed: {}", &device, e),
+        };
+
+        let mut fatal = |error: FatalStreamError| {
+            info!("{}", error);
+
+            running.store(false, Ordering::Relaxed);
+
+            shutdown_hook.run();
+
+            if let Some(fatal_error_handler) = fatal_error_handler.take() {
+                fatal_error_handler.fatal_stream_error(error)
+            }
+        };
+
+        let period_size = stream_info.max_audio_buffer_size as usize;
+
+        let mut audio_in_buffers = Vec::<AudioBusBuffer>::new();
+        let mut audio_out_buffers = Vec::<AudioBusBuffer>::new();
+
+        for bus in stream_info.audio_in.iter() {
+            audio_in_buffers.push(AudioBusBuffer::for_input_bus(
+                bus,
+                period_size as u32,
+                input_peaks,
+            ))
+        }
+        for bus in stream_info.audio_out.iter() {
+            audio_out_buffers.push(AudioBusBuffer::for_bus(bus, period_size as u32))
+        }
+
+        let mut in_raw: Vec<S> = vec![S::default(); period_size * in_channels];
+        let mut out_raw: Vec<S> = vec![S::default(); period_size * out_channels];
+        let mut out_mix: Vec<f32> = vec![0.0; period_size * out_channels];
+        let mut dither = Dither::new();
+
+        let playback_io = match playback.as_ref().map(|p| p.io_checked::<S>()).transpose() {
+            Ok(io) => io,
+            Err(e) => {
+                fatal(device_lost(e));
+                return rt_process_handler;
+            }
+        };
+        let capture_io = match capture.as_ref().map(|c| c.io_checked::<S>()).transpose() {
+            Ok(io) => io,
+            Err(e) => {
+                fatal(device_lost(e));
+                return rt_process_handler;
+            }
+        };
+
+        // Prime the playback buffer with silence so the first read doesn't cause an underrun.
+        if let (Some(playback), Some(playback_io)) = (&playback, &playback_io) {
+            for _ in 0..NUM_PERIODS {
+                if let Err(e) = playback_io.writei(&out_raw) {
+                    if let Err(e) = recover_xrun(playback, e, "playback", &xruns) {
+                        fatal(device_lost(e));
+                        return rt_process_handler;
+                    }
+                }
+            }
+        }
+
+        while running.load(Ordering::Relaxed) {
+            // Collect Audio Inputs
+
+            let mut audio_frames = period_size;
+
+            if let (Some(capture), Some(capture_io)) = (&capture, &capture_io) {
+                match capture_io.readi(&mut in_raw) {
+                    Ok(frames) => audio_frames = frames,
+                    Err(e) => match recover_xrun(capture, e, "capture", &xruns) {
+                        Ok(()) => continue,
+                        Err(e) => {
+                            fatal(device_lost(e));
+                            return rt_process_handler;
+                        }
+                    },
+                }
+
+                deinterleave(
+                    &in_raw,
+                    in_channels,
+                    audio_frames,
+                    &in_channel_map,
+                    &mut audio_in_buffers,
+                );
+                channel_gains.apply_inputs(
+                    &mut audio_in_buffers,
+                    audio_frames,
+                    stream_info.sample_rate,
+                );
+
+                for audio_buffer in audio_in_buffers.iter_mut() {
+                    audio_buffer.update_peaks();
+                }
+                silence_detector.process(&audio_in_buffers, audio_frames, stream_info.sample_rate);
+            }
+
+            // Either the capture read or the last playback write is what blocks until the
+            // device is ready for this cycle.
+            let cycle_start_time = Instant::now();
+
+            // Clear Audio Outputs
+
+            for audio_buffer in audio_out_buffers.iter_mut() {
+                audio_buffer.clear_and_resize(audio_frames);
+            }
+
+            let result = catch_process_panic(|| {
+                let _denormals = DenormalGuard::new(flush_denormals);
+                rt_process_handler.process(ProcessInfo {
+                    audio_in: audio_in_buffers.as_slice(),
+                    audio_out: audio_out_buffers.as_mut_slice(),
+                    audio_frames,
+
+                    midi_in: &[],
+                    midi_out: &mut [],
+
+                    sample_rate: stream_info.sample_rate,
+                    transport: None,
+                    cycle_start_frame: frames_processed.load(Ordering::Relaxed),
+                    cycle_start_time,
+                })
+            });
+            let control = match result {
+                Ok(control) => control,
+                Err(error) => {
+                    fatal(error);
+                    break;
+                }
+            };
+
+            master_gain.apply(
+                &mut audio_out_buffers,
+                audio_frames,
+                stream_info.sample_rate,
+            );
+            channel_gains.apply_outputs(
+                &mut audio_out_buffers,
+                audio_frames,
+                stream_info.sample_rate,
+            );
+
+            // Copy processed data to Audio Outputs
+
+            interleave(
+                &audio_out_buffers,
+                &out_channel_map,
+                out_channels,
+                audio_frames,
+                &mut out_mix,
+            );
+            for (raw, mixed) in out_raw.iter_mut().zip(out_mix.iter()) {
+                *raw = S::from_f32(*mixed, &mut dither);
+            }
+
+            if let (Some(playback), Some(playback_io)) = (&playback, &playback_io) {
+                let mut written = 0;
+                while written < audio_frames {
+                    let start = written * out_channels;
+                    let end = audio_frames * out_channels;
+
+                    match playback_io.writei(&out_raw[start..end]) {
+                        Ok(frames) => written += frames,
+                        Err(e) => {
+                            if let Err(e) = recover_xrun(playback, e, "playback", &xruns) {
+                                fatal(device_lost(e));
+                                return rt_process_handler;
+                            }
+                        }
+                    }
+                }
+            }
+
+            let frames_processed =
+                frames_processed.fetch_add(audio_frames as u64, Ordering::Relaxed);
+            buffer_size_history.push(audio_frames);
+
+            if cycle_debugger.begin_cycle() {
+                cycle_debugger.log(CycleDebugInfo {
+                    frames_processed,
+                    audio_frames,
+                    audio_in_channels: in_channels,
+                    audio_out_channels: out_channels,
+                    midi_in_events: 0,
+                    midi_out_events: 0,
+                });
+            }
+
+            if control == ProcessControl::Quit {
+                info!("ALSA: the process handler stopped the stream");
+                running.store(false, Ordering::Relaxed);
+                shutdown_hook.run();
+                break;
+            }
+        }
+
+        if let Some(playback) = &playback {
+            if let Err(e) = playback.drop() {
+                debug!("ALSA: failed to stop playback: {}", e);
+            }
+        }
+
+        rt_process_handler
+    }
+}
+
+/// Try to recover from an xrun (or suspend). This returns the error if it was unrecoverable.
+fn recover_xrun(
+    pcm: &PCM,
+    e: alsa::Error,
+    direction: &str,
+    xruns: &Xruns,
+) -> Result<(), alsa::Error> {
+    pcm.try_recover(e, true)?;
+
+    warn!("ALSA: xrun occurred on {}", direction);
+    xruns.xrun();
+
+    Ok(())
+}
+
+/// A sample format that ALSA can read/write to.
+trait AlsaSample: Sample + IoFormat {}
+
+impl<S: Sample + IoFormat> AlsaSample for S {}
+
+impl IoFormat for I24 {
+    const FORMAT: Format = Format::s24();
+}
+
+/// Copy an interleaved device buffer into the channels of each bus. Channels that are routed
+/// from more than one device channel get their sum.
+fn deinterleave<S: AlsaSample>(
+    interleaved: &[S],
+    device_channels: usize,
+    frames: usize,
+    channel_map: &[Vec<ChannelRoute<usize>>],
+    busses: &mut [AudioBusBuffer],
+) {
+    for (audio_buffer, routes) in busses.iter_mut().zip(channel_map.iter()) {
+        for (channel, route) in audio_buffer.channel_buffers.iter_mut().zip(routes.iter()) {
+            // This should never allocate because each buffer was given a capacity of
+            // the period size.
+            channel.clear();
+            channel.resize(frames, 0.0);
+
+            for device_channel in route.ports.iter() {
+                for (dst, src) in channel.iter_mut().zip(
+                    interleaved
+                        .iter()
+                        .skip(*device_channel)
+                        .step_by(device_channels),
+                ) {
+                    *dst += src.to_f32() * route.gain;
+                }
+            }
+        }
+
+        audio_buffer.frames = frames;
+    }
+}
+
+/// Mix the channels of each bus into an interleaved device buffer. Device channels that more
+/// than one channel is routed to are summed.
+fn interleave(
+    busses: &[AudioBusBuffer],
+    channel_map: &[Vec<ChannelRoute<usize>>],
+    device_channels: usize,
+    frames: usize,
+    interleaved: &mut [f32],
+) {
+    for s in interleaved.iter_mut() {
+        *s = 0.0;
+    }
+
+    for (audio_buffer, routes) in busses.iter().zip(channel_map.iter()) {
+        for (channel, route) in audio_buffer.channel_buffers.iter().zip(routes.iter()) {
+            // Just in case the user resized the output buffer for some reason.
+            let len = channel.len().min(frames);
+            if len != frames {
+                warn!(
+                    "Warning: An audio output buffer was resized from {} to {} by the user",
+                    frames, len
+                );
+            }
+
+            for device_channel in route.ports.iter() {
+                for (dst, src) in interleaved
+                    .iter_mut()
+                    .skip(*device_channel)
+                    .step_by(device_channels)
+                    .zip(channel[0..len].iter())
+                {
+                    *dst += *src * route.gain;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AlsaConfigMismatch {
+    device: String,
+}
+
+impl std::error::Error for AlsaConfigMismatch {}
+
+impl std::fmt::Display for AlsaConfigMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The capture and playback streams of ALSA device {} could not be opened with the same sample rate and buffer size",
+            &self.device
+        )
+    }
+}
+
+impl From<alsa::Error> for SpawnRtThreadError {
+    fn from(e: alsa::Error) -> Self {
+        SpawnRtThreadError::PlatformSpecific(Box::new(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Routes every channel to one device channel at unity gain.
+    fn direct(device_channels: &[usize]) -> Vec<ChannelRoute<usize>> {
+        device_channels
+            .iter()
+            .map(|channel| ChannelRoute {
+                ports: vec![*channel],
+                gain: 1.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_ports() {
+        assert_eq!(
+            parse_port_channel("playback_1", PLAYBACK_PORT_PREFIX),
+            Some(0)
+        );
+        assert_eq!(
+            parse_port_channel("playback_12", PLAYBACK_PORT_PREFIX),
+            Some(11)
+        );
+        assert_eq!(parse_port_channel("playback_0", PLAYBACK_PORT_PREFIX), None);
+        assert_eq!(parse_port_channel("capture_1", PLAYBACK_PORT_PREFIX), None);
+        assert_eq!(
+            parse_port_channel("system:playback_1", PLAYBACK_PORT_PREFIX),
+            None
+        );
+    }
+
+    #[test]
+    fn deinterleave_into_busses() {
+        // Three device channels, two frames.
+        let interleaved: [i16; 6] = [0, 16384, -16384, 8192, -8192, 32767];
+        let channel_map = vec![direct(&[2]), direct(&[0, 1])];
+        let mut busses = vec![AudioBusBuffer::new(1, 2), AudioBusBuffer::new(2, 2)];
+
+        deinterleave(&interleaved, 3, 2, &channel_map, &mut busses);
+
+        assert_eq!(busses[0].frames(), 2);
+        assert_eq!(&busses[0][0], &[-0.5, 32767.0 / 32768.0]);
+        assert_eq!(&busses[1][0], &[0.0, 0.25]);
+        assert_eq!(&busses[1][1], &[0.5, -0.25]);
+    }
+
+    #[test]
+    fn interleave_mixes_shared_channels() {
+        let mut bus_a = AudioBusBuffer::new(2, 2);
+        bus_a.clear_and_resize(2);
+        bus_a.channel_buffers[0].copy_from_slice(&[0.125, 0.25]);
+        bus_a.channel_buffers[1].copy_from_slice(&[0.375, 0.5]);
+
+        let mut bus_b = AudioBusBuffer::new(1, 2);
+        bus_b.clear_and_resize(2);
+        bus_b.channel_buffers[0].copy_from_slice(&[0.5, 0.5]);
+
+        let channel_map = vec![direct(&[0, 1]), direct(&[1])];
+        let mut interleaved = [1.0; 6];
+
+        interleave(&[bus_a, bus_b], &channel_map, 3, 2, &mut interleaved);
+
+        assert_eq!(interleaved, [0.125, 0.875, 0.0, 0.25, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn interleave_sums_routed_channels() {
+        let mut bus = AudioBusBuffer::new(2, 1);
+        bus.clear_and_resize(1);
+        bus.channel_buffers[0][0] = 0.5;
+        bus.channel_buffers[1][0] = 0.25;
+
+        // A stereo bus summed at -6 dB into the first of two device channels.
+        let channel_map = vec![vec![
+            ChannelRoute {
+                ports: vec![0],
+                gain: 0.5,
+            },
+            ChannelRoute {
+                ports: vec![0],
+                gain: 0.5,
+            },
+        ]];
+        let mut interleaved = [1.0; 2];
+
+        interleave(&[bus], &channel_map, 2, 1, &mut interleaved);
+
+        assert_eq!(interleaved, [0.375, 0.0]);
+    }
+}
@@ -1,149 +1,822 @@
 use log::{debug, info, warn};
-
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::channel_gain::{ChannelGains, ChannelGainsHandle};
+use crate::cycle_debug::{cycle_debugger, CycleDebugHandle, CycleDebugInfo, CycleDebugger};
+use crate::denormals::DenormalGuard;
+use crate::device_watch::DeviceNotifier;
+use crate::diagnostics::BufferSizeHistory;
+use crate::enumeration::refresh_with_timeout;
+use crate::growth::Growth;
+use crate::master_gain::{MasterGain, MasterGainHandle};
+use crate::midi_buffer::MidiDropCounters;
+use crate::port_mixing::channel_routes;
+use crate::process_panic::catch_process_panic;
+use crate::shutdown_hook::ShutdownHook;
+use crate::silence_detect::{BusState, SilenceDetectHandle, SilenceDetector};
+use crate::spawn_deadline::SpawnDeadline;
+use crate::xruns::Xruns;
 use crate::{
-    AudioBus, AudioBusBuffer, AudioDeviceInfo, AudioServerInfo, BufferSizeRange, Config,
-    DeviceIndex, FatalErrorHandler, FatalStreamError, MidiController, MidiControllerBuffer,
-    MidiDeviceInfo, MidiServerInfo, ProcessInfo, RtProcessHandler, SpawnRtThreadError, StreamInfo,
+    AddBusError, AudioBus, AudioBusBuffer, AudioBusConfig, AudioDeviceInfo, AudioServerInfo,
+    Backend, BufferSizeRange, Config, DeviceIndex, DisconnectReason, EnumerationError,
+    FatalErrorHandler, FatalStreamError, FreewheelError, FreewheelHook, JackSelfConnect,
+    LatencyRange, MidiController, MidiControllerBuffer, MidiControllerConfig, MidiDeviceInfo,
+    MidiServerInfo, MidiTimestamps, PortConnections, ProcessControl, ProcessInfo, RtProcessHandler,
+    SampleFormat, SpawnError, SpawnPhase, SpawnRtThreadError, StreamDiagnostics, StreamInfo,
+    TransportBbt, TransportInfo, MIDI_BUFFER_SIZE,
 };
 
-pub fn refresh_audio_server(server: &mut AudioServerInfo) {
-    info!("Refreshing list of available Jack audio devices...");
+pub fn refresh_audio_server(server: &mut AudioServerInfo) -> Result<(), EnumerationError> {
+    refresh_audio_server_timeout(server, Duration::MAX)
+}
 
-    server.devices.clear();
+/// Refresh the Jack server, giving up after `timeout`.
+///
+/// Creating the client that is used to query the server can hang if jackd is misbehaving.
+/// If this times out, `server` is marked as unavailable.
+pub fn refresh_audio_server_timeout(
+    server: &mut AudioServerInfo,
+    timeout: Duration,
+) -> Result<(), EnumerationError> {
+    refresh_with_timeout(server, Backend::Jack, timeout, |server| {
+        enumerate_audio_server(server, None)
+    })
+}
 
-    match jack::Client::new("rustydaw_io_dummy_client", jack::ClientOptions::empty()) {
-        Ok((client, _status)) => {
-            let system_audio_in_ports: Vec<String> = client.ports(
-                None,
-                Some("32 bit float mono audio"),
-                jack::PortFlags::IS_OUTPUT,
-            );
-            let system_audio_out_ports: Vec<String> = client.ports(
-                None,
-                Some("32 bit float mono audio"),
-                jack::PortFlags::IS_INPUT,
-            );
+/// Refresh the devices of the Jack server with the given name instead of the default one.
+pub fn refresh_named_audio_server(
+    server: &mut AudioServerInfo,
+    server_name: &str,
+) -> Result<(), EnumerationError> {
+    enumerate_audio_server(server, Some(server_name))
+}
 
-            if system_audio_out_ports.len() == 0 {
-                // This crate only allows devices with playback.
+fn enumerate_audio_server(
+    server: &mut AudioServerInfo,
+    server_name: Option<&str>,
+) -> Result<(), EnumerationError> {
+    info!("Refreshing list of available Jack audio devices...");
 
-                server.available = false;
+    server.devices.clear();
 
-                warn!("Jack server is unavailable: Jack system device has no available audio outputs.");
-            } else {
-                // Find index of default in ports.
-                let mut default_in_port = 0; // Fallback to first available port.
-                for (i, port) in system_audio_in_ports.iter().enumerate() {
-                    if port == "system:capture_1" {
-                        default_in_port = i;
-                        break;
-                    }
-                }
+    match open_dummy_client(server_name) {
+        Ok(client) => {
+            list_audio_devices(server, &client);
+            Ok(())
+        }
+        Err(e) => {
+            server.available = false;
 
-                // Find index of default out left port.
-                let mut default_out_port_left = 0; // Fallback to first available port.
-                for (i, port) in system_audio_out_ports.iter().enumerate() {
-                    if port == "system:playback_1" {
-                        default_out_port_left = i;
-                        break;
-                    }
-                }
+            info!("Jack server is unavailable: {}", e);
 
-                // Find index of default out right port.
-                let mut default_out_port_right = 1.min(system_audio_out_ports.len() - 1); // Fallback to second available port if stereo, first if mono.
-                for (i, port) in system_audio_out_ports.iter().enumerate() {
-                    if port == "system:playback_2" {
-                        default_out_port_right = i;
-                        break;
-                    }
-                }
+            Err(enumeration_error(e))
+        }
+    }
+}
 
-                // Jack only ever has one "device".
-                server.devices.push(AudioDeviceInfo {
-                    name: String::from("Jack Device"),
-                    in_ports: system_audio_in_ports,
-                    out_ports: system_audio_out_ports,
-                    sample_rates: vec![client.sample_rate() as u32], // Only one sample rate is available.
-                    buffer_size_range: BufferSizeRange {
-                        // Only one buffer size is available.
-                        min: client.buffer_size() as u32,
-                        max: client.buffer_size() as u32,
-                    },
-
-                    default_in_port,
-                    default_out_port_left,
-                    default_out_port_right,
-                    default_sample_rate_index: 0, // Only one sample rate is available.
-                    default_buffer_size: client.buffer_size() as u32, // Only one buffer size is available.
-                });
-
-                server.available = true;
-            }
+/// Refresh the audio and the midi devices of the Jack server with a single client, which
+/// saves a connection to the server over `refresh_audio_server()` followed by
+/// `refresh_midi_server()`.
+pub fn refresh_servers(
+    audio_server: &mut AudioServerInfo,
+    midi_server: &mut MidiServerInfo,
+) -> Result<(), EnumerationError> {
+    info!("Refreshing list of available Jack audio and MIDI devices...");
+
+    audio_server.devices.clear();
+    midi_server.in_devices.clear();
+    midi_server.out_devices.clear();
+
+    match open_dummy_client(None) {
+        Ok(client) => {
+            list_audio_devices(audio_server, &client);
+            list_midi_devices(midi_server, &client);
+            Ok(())
         }
         Err(e) => {
-            server.available = false;
+            audio_server.available = false;
+            midi_server.available = false;
 
             info!("Jack server is unavailable: {}", e);
+
+            Err(enumeration_error(e))
         }
     }
 }
 
-pub fn refresh_midi_server(server: &mut MidiServerInfo) {
-    info!("Refreshing list of available Jack MIDI devices...");
+/// The client that is used to query the server during enumeration.
+fn open_dummy_client(server_name: Option<&str>) -> Result<jack::Client, jack::Error> {
+    open_client(
+        "rustydaw_io_dummy_client",
+        jack::ClientOptions::empty(),
+        server_name,
+    )
+    .map(|(client, _status)| client)
+}
 
-    server.in_devices.clear();
-    server.out_devices.clear();
+fn list_audio_devices(server: &mut AudioServerInfo, client: &jack::Client) {
+    let system_audio_in_ports: Vec<String> = client.ports(
+        None,
+        Some("32 bit float mono audio"),
+        jack::PortFlags::IS_OUTPUT,
+    );
+    let system_audio_out_ports: Vec<String> = client.ports(
+        None,
+        Some("32 bit float mono audio"),
+        jack::PortFlags::IS_INPUT,
+    );
 
-    match jack::Client::new("rustydaw_io_dummy_client", jack::ClientOptions::empty()) {
-        Ok((client, _status)) => {
-            let system_midi_in_ports: Vec<String> =
-                client.ports(None, Some("8 bit raw midi"), jack::PortFlags::IS_OUTPUT);
-            let system_midi_out_ports: Vec<String> =
-                client.ports(None, Some("8 bit raw midi"), jack::PortFlags::IS_INPUT);
-
-            for system_port_name in system_midi_in_ports.iter() {
-                server.in_devices.push(MidiDeviceInfo {
-                    name: system_port_name.clone(),
-                });
-            }
+    if system_audio_out_ports.len() == 0 {
+        // This crate only allows devices with playback.
+
+        server.available = false;
 
-            for system_port_name in system_midi_out_ports.iter() {
-                server.out_devices.push(MidiDeviceInfo {
-                    name: system_port_name.clone(),
-                });
+        warn!("Jack server is unavailable: Jack system device has no available audio outputs.");
+    } else {
+        // Find index of default in ports. There is none if the server has no inputs.
+        let default_in_port = system_audio_in_ports
+            .iter()
+            .position(|port| port == "system:capture_1")
+            .or(if system_audio_in_ports.is_empty() {
+                None
+            } else {
+                Some(0) // Fallback to first available port.
+            });
+
+        // Find index of default out left port.
+        let mut default_out_port_left = 0; // Fallback to first available port.
+        for (i, port) in system_audio_out_ports.iter().enumerate() {
+            if port == "system:playback_1" {
+                default_out_port_left = i;
+                break;
             }
+        }
 
-            // Find index of default in port.
-            let mut default_in_port = 0; // Fallback to first available port.
-            for (i, port) in system_midi_in_ports.iter().enumerate() {
-                // "system:midi_capture_1" is usually Jack's built-in `Midi-Through` device.
-                // What we usually want is first available port of the user's hardware MIDI controller, which is
-                // commonly mapped to "system:midi_capture_2".
-                if port == "system:midi_capture_2" {
-                    default_in_port = i;
-                    break;
-                }
+        // Find index of default out right port.
+        let mut default_out_port_right = 1.min(system_audio_out_ports.len() - 1); // Fallback to second available port if stereo, first if mono.
+        for (i, port) in system_audio_out_ports.iter().enumerate() {
+            if port == "system:playback_2" {
+                default_out_port_right = i;
+                break;
             }
+        }
+
+        // Jack only ever has one "device".
+        let buffer_size_range = BufferSizeRange {
+            // Only one buffer size is available.
+            min: client.buffer_size() as u32,
+            max: client.buffer_size() as u32,
+        };
+        let duplex_buffer_size_range = if system_audio_in_ports.is_empty() {
+            None
+        } else {
+            Some(buffer_size_range)
+        };
+
+        server.devices.push(AudioDeviceInfo {
+            name: String::from("Jack Device"),
+            in_ports: system_audio_in_ports,
+            out_ports: system_audio_out_ports,
+            sample_rates: vec![client.sample_rate() as u32], // Only one sample rate is available.
+            buffer_size_range,
+            duplex_buffer_size_range,
+
+            default_in_port,
+            default_out_port_left,
+            default_out_port_right,
+            default_sample_rate_index: 0, // Only one sample rate is available.
+            default_buffer_size: client.buffer_size() as u32, // Only one buffer size is available.
+        });
 
-            server.default_in_port = default_in_port;
+        server.available = true;
+    }
+}
+
+pub fn refresh_midi_server(server: &mut MidiServerInfo) -> Result<(), EnumerationError> {
+    enumerate_midi_server(server, None)
+}
 
-            server.available = true;
+/// Refresh the midi devices of the Jack server with the given name instead of the default
+/// one.
+pub fn refresh_named_midi_server(
+    server: &mut MidiServerInfo,
+    server_name: &str,
+) -> Result<(), EnumerationError> {
+    enumerate_midi_server(server, Some(server_name))
+}
+
+fn enumerate_midi_server(
+    server: &mut MidiServerInfo,
+    server_name: Option<&str>,
+) -> Result<(), EnumerationError> {
+    info!("Refreshing list of available Jack MIDI devices...");
+
+    server.in_devices.clear();
+    server.out_devices.clear();
+
+    match open_dummy_client(server_name) {
+        Ok(client) => {
+            list_midi_devices(server, &client);
+            Ok(())
         }
         Err(e) => {
             server.available = false;
 
             info!("Jack server is unavailable: {}", e);
+
+            Err(enumeration_error(e))
+        }
+    }
+}
+
+fn list_midi_devices(server: &mut MidiServerInfo, client: &jack::Client) {
+    let system_midi_in_ports: Vec<String> =
+        client.ports(None, Some("8 bit raw midi"), jack::PortFlags::IS_OUTPUT);
+    let system_midi_out_ports: Vec<String> =
+        client.ports(None, Some("8 bit raw midi"), jack::PortFlags::IS_INPUT);
+
+    for system_port_name in system_midi_in_ports.iter() {
+        server.in_devices.push(MidiDeviceInfo {
+            name: system_port_name.clone(),
+        });
+    }
+
+    for system_port_name in system_midi_out_ports.iter() {
+        server.out_devices.push(MidiDeviceInfo {
+            name: system_port_name.clone(),
+        });
+    }
+
+    // Find index of default in port.
+    //
+    // "system:midi_capture_1" is usually Jack's built-in `Midi-Through` device.
+    // What we usually want is first available port of the user's hardware MIDI controller, which is
+    // commonly mapped to "system:midi_capture_2".
+    let default_in_port = system_midi_in_ports
+        .iter()
+        .position(|port| port == "system:midi_capture_2")
+        .unwrap_or(0); // Fallback to first available port.
+
+    server.default_in_port = default_in_port;
+
+    server.available = true;
+}
+
+/// Tell "the server isn't running" apart from other failures to open the client that
+/// enumerates the server.
+fn enumeration_error(e: jack::Error) -> EnumerationError {
+    match e {
+        jack::Error::ClientError(status) if status.contains(jack::ClientStatus::SERVER_FAILED) => {
+            EnumerationError::ServerNotRunning(Backend::Jack)
         }
+        e => EnumerationError::PlatformSpecific(Box::new(e)),
     }
 }
 
 pub struct JackRtThreadHandle<P: RtProcessHandler, E: FatalErrorHandler> {
-    _async_client: jack::AsyncClient<JackNotificationHandler<E>, JackProcessHandler<P>>,
+    async_client: Option<JackAsyncClient<P, E>>,
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_debug_handle: CycleDebugHandle,
+    pub(super) master_gain_handle: MasterGainHandle,
+    pub(super) channel_gains_handle: ChannelGainsHandle,
+    pub(super) silence_detect_handle: SilenceDetectHandle,
+    pub(super) shutdown_hook: Arc<ShutdownHook>,
+    pub(super) xruns: Arc<Xruns>,
+    freewheel: Arc<Freewheel>,
+    bus_ports: Vec<BusPorts>,
+    midi_in_drops: MidiDropCounters,
+    /// The config the stream was spawned with, plus every bus/controller added since.
+    /// Boxed to keep the stream handle small.
+    config: Box<Config>,
+    new_ports: NewPortsSender,
+    /// Stops the stop watcher thread when the handle is dropped.
+    _stop_watcher: StopWatcher,
+}
+
+/// The ports of this client that belong to an audio bus.
+struct BusPorts {
+    id: String,
+    latency_type: jack::LatencyType,
+    port_names: Vec<String>,
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> Drop for JackRtThreadHandle<P, E> {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> JackRtThreadHandle<P, E> {
+    /// Deactivate and close the client, and return the process handler. Deactivating the
+    /// client disconnects all of its ports.
+    ///
+    /// Returns `None` if the stream was already stopped or the client could not be
+    /// deactivated (e.g. because the server has shut down).
+    pub fn stop(&mut self) -> Option<P> {
+        let async_client = self.async_client.take()?;
+
+        deactivate(async_client, &self.shutdown_hook)
+    }
+
+    /// The total number of frames that have been processed since the stream started.
+    ///
+    /// Unlike Jack's transport frame, this counter is monotonic and never jumps.
+    pub fn frames_processed(&self) -> u64 {
+        self.frames_processed.load(Ordering::Relaxed)
+    }
+
+    /// Log detailed diagnostics for the next `n` process cycles, after which logging
+    /// stops automatically.
+    pub fn debug_next_cycles(&self, n: u32) {
+        self.cycle_debug_handle.debug_next_cycles(n);
+    }
+
+    /// A snapshot of the health of the stream.
+    pub fn diagnostics(&self) -> StreamDiagnostics {
+        StreamDiagnostics {
+            frames_processed: self.frames_processed(),
+            xruns: self.xruns.count(),
+            cycle_debug_queue: self.cycle_debug_handle.queue_stats(),
+            ..StreamDiagnostics::default()
+        }
+    }
+
+    /// The number of frames of each of the last few process cycles, oldest first.
+    pub fn recent_buffer_sizes(&self) -> Vec<u32> {
+        self.buffer_size_history.recent()
+    }
+
+    /// The number of events the midi input controller with the given ID has dropped
+    /// because its buffer was full, including the controllers added since the stream was
+    /// spawned.
+    pub fn midi_in_dropped_events(&self, controller_id: &str) -> Option<u64> {
+        self.midi_in_drops.dropped_events(controller_id)
+    }
+
+    /// Start or stop Jack's freewheel mode, in which the server runs the whole graph as fast
+    /// as it can instead of waiting for the device, e.g. to bounce the session.
+    ///
+    /// This only asks the server for it. Whether freewheel mode engaged is reported to the
+    /// hook set with `on_freewheel()` once the server has switched.
+    pub fn set_freewheel(&self, enabled: bool) -> Result<(), FreewheelError> {
+        let client = match &self.async_client {
+            Some(async_client) => async_client.as_client(),
+            None => return Err(FreewheelError::StreamStopped),
+        };
+
+        match unsafe { jack_sys::jack_set_freewheel(client.raw(), enabled as libc::c_int) } {
+            0 => Ok(()),
+            _ => Err(FreewheelError::Refused),
+        }
+    }
+
+    /// Whether Jack is in freewheel mode, as last reported by the server.
+    pub fn is_freewheeling(&self) -> bool {
+        self.freewheel.is_enabled()
+    }
+
+    /// Set a callback that is called from the notification thread every time the server
+    /// enters (`true`) or leaves (`false`) freewheel mode. This replaces any callback that
+    /// was set before.
+    pub fn on_freewheel(&self, hook: FreewheelHook) {
+        self.freewheel.set_hook(hook);
+    }
+
+    /// The latency of the bus with the given ID along its connections, over all of its
+    /// connected channels.
+    ///
+    /// Jack sums the latencies of the ports along each connection path into the latency of
+    /// this client's port, so this is read fresh from Jack on every call and always
+    /// reflects the current connections.
+    pub fn bus_latency(&self, bus_id: &str) -> Option<LatencyRange> {
+        let client = self.async_client.as_ref()?.as_client();
+        let bus = self.bus_ports.iter().find(|bus| bus.id == bus_id)?;
+
+        LatencyRange::span(bus.port_names.iter().filter_map(|name| {
+            let port = client.port_by_name(name)?;
+            if port.connected_count().ok()? == 0 {
+                return None;
+            }
+
+            let (min, max) = port.get_latency_range(bus.latency_type);
+            Some(LatencyRange { min, max })
+        }))
+    }
+
+    /// What every port of the bus with the given ID is connected to, read fresh from Jack.
+    pub fn port_connections(&self, bus_id: &str) -> Option<Vec<PortConnections>> {
+        let client = self.async_client.as_ref()?.as_client();
+        let bus = self.bus_ports.iter().find(|bus| bus.id == bus_id)?;
+
+        // Only ports going the other way can be connected to the ports of the bus.
+        let candidates = client.ports(
+            None,
+            None,
+            match bus.latency_type {
+                jack::LatencyType::Capture => jack::PortFlags::IS_OUTPUT,
+                jack::LatencyType::Playback => jack::PortFlags::IS_INPUT,
+            },
+        );
+
+        Some(
+            bus.port_names
+                .iter()
+                .map(|name| {
+                    let port = client
+                        .port_by_name(name)
+                        .filter(|port| port.connected_count().unwrap_or(0) > 0);
+
+                    PortConnections {
+                        port: name.clone(),
+                        connected_to: match port {
+                            Some(port) => candidates
+                                .iter()
+                                .filter(|other| port.is_connected_to(other).unwrap_or(false))
+                                .cloned()
+                                .collect(),
+                            None => Vec::new(),
+                        },
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Register a new audio bus on the running client, and hand its ports to the process
+    /// handler. `stream_info` is the one of the stream handle, which gets the new bus.
+    pub fn add_audio_bus(
+        &mut self,
+        stream_info: &mut StreamInfo,
+        bus: &AudioBusConfig,
+        is_input: bool,
+    ) -> Result<AudioBus, AddBusError> {
+        let client = match &self.async_client {
+            Some(async_client) => async_client.as_client(),
+            None => return Err(AddBusError::StreamStopped),
+        };
+
+        let opened = if is_input {
+            self.config.direction.has_input()
+        } else {
+            self.config.direction.has_output()
+        };
+        if !opened {
+            return Err(AddBusError::DirectionNotOpened);
+        }
+
+        let mut config = (*self.config).clone();
+        if is_input {
+            config.audio_in_busses.push(bus.clone());
+        } else {
+            config.audio_out_busses.push(bus.clone());
+        }
+        crate::check_duplicate_ids(&config)?;
+        crate::check_channel_layouts(&config)?;
+
+        check_system_ports(std::slice::from_ref(bus))?;
+        self.new_ports.reserve()?;
+        let bus = &crate::port_pattern::resolve_bus_patterns(
+            std::slice::from_ref(bus),
+            &graph_audio_ports(client, is_input),
+        )[0];
+
+        // The buffer size may have changed since the stream was spawned.
+        let max_audio_buffer_size = stream_info.max_audio_buffer_size.max(client.buffer_size());
+        let mut next_stream_info = stream_info.clone();
+        read_latencies(client, &mut next_stream_info);
+        let (mut audio_bus, ports) = if is_input {
+            let busses = &stream_info.audio_in;
+            let (audio_bus, ports) = register_audio_bus::<jack::AudioIn>(
+                client,
+                bus,
+                busses.len(),
+                config.jack_pretty_names,
+            )?;
+            let buffer = AudioBusBuffer::for_input_bus(
+                &audio_bus,
+                max_audio_buffer_size,
+                config.input_peaks,
+            );
+
+            next_stream_info.audio_in.push(audio_bus.clone());
+            let ports = NewPorts::AudioIn(
+                Growth::new(ports, channel_count(busses)),
+                Growth::new(
+                    port_gains(std::slice::from_ref(bus), true),
+                    channel_count(busses),
+                ),
+                Growth::new(vec![buffer], busses.len()),
+                Growth::new(vec![BusState::default()], busses.len()),
+            );
+            (audio_bus, ports)
+        } else {
+            let busses = &stream_info.audio_out;
+            let (audio_bus, ports) = register_audio_bus::<jack::AudioOut>(
+                client,
+                bus,
+                busses.len(),
+                config.jack_pretty_names,
+            )?;
+            let buffer = AudioBusBuffer::for_bus(&audio_bus, max_audio_buffer_size);
+
+            next_stream_info.audio_out.push(audio_bus.clone());
+            let ports = NewPorts::AudioOut(
+                Growth::new(ports, channel_count(busses)),
+                Growth::new(
+                    port_gains(std::slice::from_ref(bus), false),
+                    channel_count(busses),
+                ),
+                Growth::new(vec![buffer], busses.len()),
+            );
+            (audio_bus, ports)
+        };
+
+        // The silence detector can report the bus as soon as the process handler has it, so
+        // its ID has to be known first.
+        if is_input {
+            self.silence_detect_handle.add_bus(&audio_bus.id_name);
+        }
+
+        // The ports are only connected once the process handler has them, so an output
+        // never plays a port buffer that nothing has written to.
+        self.new_ports.send(PortsUpdate {
+            ports,
+            stream_info: next_stream_info,
+        });
+
+        let (routed, connected) = routed_ports(bus, &audio_bus.client_ports, is_input);
+        connect_added_ports(
+            client,
+            config.jack_self_connect,
+            &routed,
+            &connected,
+            is_input,
+        );
+        let port_names = audio_bus.client_ports.clone();
+        count_connected_ports(client, std::slice::from_mut(&mut audio_bus), &port_names);
+
+        self.bus_ports.push(BusPorts {
+            id: bus.id.clone(),
+            latency_type: if is_input {
+                jack::LatencyType::Capture
+            } else {
+                jack::LatencyType::Playback
+            },
+            port_names,
+        });
+
+        info!(
+            "JACK: added the audio {} bus {}",
+            if is_input { "input" } else { "output" },
+            &bus.id
+        );
+
+        if is_input {
+            stream_info.audio_in.push(audio_bus.clone());
+        } else {
+            stream_info.audio_out.push(audio_bus.clone());
+        }
+        self.shutdown_hook.set_stream_info(stream_info.clone());
+        *self.config = config;
+
+        Ok(audio_bus)
+    }
+
+    /// Register a new midi controller on the running client, and hand its port to the
+    /// process handler. See `add_audio_bus()`.
+    ///
+    /// The id may not be used by any other controller, since that would make them a
+    /// bidirectional controller whose ports are named differently.
+    pub fn add_midi_controller(
+        &mut self,
+        stream_info: &mut StreamInfo,
+        controller: &MidiControllerConfig,
+        is_input: bool,
+    ) -> Result<MidiController, AddBusError> {
+        let client = match &self.async_client {
+            Some(async_client) => async_client.as_client(),
+            None => return Err(AddBusError::StreamStopped),
+        };
+
+        if !self.config.creates_midi_controllers(Backend::Jack) {
+            return Err(AddBusError::MidiUnavailable);
+        }
+
+        if self
+            .config
+            .midi_in_controllers
+            .iter()
+            .chain(self.config.midi_out_controllers.iter())
+            .any(|c| c.id == controller.id)
+        {
+            return Err(SpawnRtThreadError::IdNotUnique(controller.id.clone()).into());
+        }
+
+        let mut config = (*self.config).clone();
+        if is_input {
+            config.midi_in_controllers.push(controller.clone());
+        } else {
+            config.midi_out_controllers.push(controller.clone());
+        }
+        crate::check_duplicate_ids(&config)?;
+        self.new_ports.reserve()?;
+
+        let system_ports = client.ports(
+            None,
+            Some("8 bit raw midi"),
+            if is_input {
+                jack::PortFlags::IS_OUTPUT
+            } else {
+                jack::PortFlags::IS_INPUT
+            },
+        );
+
+        let mut next_stream_info = stream_info.clone();
+        read_latencies(client, &mut next_stream_info);
+        let (midi_controller, ports) = if is_input {
+            let controllers = stream_info.midi_in.len();
+            let (midi_controller, port) = register_midi_controller::<jack::MidiIn>(
+                client,
+                controller,
+                controllers,
+                &config.midi_in_port_name(&controller.id),
+                &system_ports,
+                true,
+                config.jack_pretty_names,
+            )?;
+
+            next_stream_info.midi_in.push(midi_controller.clone());
+            let buffer =
+                MidiControllerBuffer::for_input(controller.filter, controller.buffer_capacity);
+            self.midi_in_drops.push(&controller.id, &buffer);
+            let ports = NewPorts::MidiIn(
+                Growth::new(vec![port], controllers),
+                Growth::new(vec![buffer], controllers),
+            );
+            (midi_controller, ports)
+        } else {
+            let controllers = stream_info.midi_out.len();
+            let (midi_controller, port) = register_midi_controller::<jack::MidiOut>(
+                client,
+                controller,
+                controllers,
+                &config.midi_out_port_name(&controller.id),
+                &system_ports,
+                false,
+                config.jack_pretty_names,
+            )?;
+
+            next_stream_info.midi_out.push(midi_controller.clone());
+            let ports = NewPorts::MidiOut(
+                Growth::new(vec![port], controllers),
+                Growth::new(vec![MidiControllerBuffer::new()], controllers),
+            );
+            (midi_controller, ports)
+        };
+
+        self.new_ports.send(PortsUpdate {
+            ports,
+            stream_info: next_stream_info,
+        });
+
+        if let Some(client_port) = &midi_controller.client_port {
+            let system_ports = midi_connections(controller, &midi_controller);
+            connect_added_ports(
+                client,
+                config.jack_self_connect,
+                &vec![client_port.clone(); system_ports.len()],
+                &system_ports,
+                is_input,
+            );
+        }
+
+        info!(
+            "JACK: added the midi {} controller {}",
+            if is_input { "input" } else { "output" },
+            &controller.id
+        );
+
+        if is_input {
+            stream_info.midi_in.push(midi_controller.clone());
+        } else {
+            stream_info.midi_out.push(midi_controller.clone());
+        }
+        self.shutdown_hook.set_stream_info(stream_info.clone());
+        *self.config = config;
+
+        Ok(midi_controller)
+    }
+}
+
+/// Connect the ports of a bus/controller that was added while the stream runs, as far as
+/// `policy` allows. Failed connections are only logged, since the bus is already running.
+fn connect_added_ports(
+    client: &jack::Client,
+    policy: JackSelfConnect,
+    port_names: &[String],
+    system_ports: &[String],
+    is_input: bool,
+) {
+    let connections = if is_input {
+        self_connections(policy, port_names, system_ports, &[], &[])
+    } else {
+        self_connections(policy, &[], &[], port_names, system_ports)
+    };
+
+    for (source, destination) in connections {
+        if let Err(e) = client.connect_ports_by_name(source, destination) {
+            warn!(
+                "JACK: failed to connect {} to {}: {}",
+                source, destination, e
+            );
+        }
+    }
+}
+
+/// The `(client ports, system ports)` of every connection of a bus, following its
+/// `PortMixing`.
+fn routed_ports(
+    bus: &AudioBusConfig,
+    client_ports: &[String],
+    is_input: bool,
+) -> (Vec<String>, Vec<String>) {
+    let mut routed = Vec::<String>::new();
+    let mut connected = Vec::<String>::new();
+
+    let routes = channel_routes(bus, &bus.system_ports, is_input);
+    for (client_port, route) in client_ports.iter().zip(routes.iter()) {
+        for system_port in route.ports.iter() {
+            routed.push(client_port.clone());
+            connected.push(system_port.clone());
+        }
+    }
+
+    (routed, connected)
+}
+
+/// The gain of every channel of the given busses, in order. Jack sums the connections to a
+/// port on its own, so only the gain of `PortMixing` is left to the process handler.
+fn port_gains(busses: &[AudioBusConfig], is_input: bool) -> Vec<f32> {
+    busses
+        .iter()
+        .flat_map(|bus| channel_routes(bus, &bus.system_ports, is_input))
+        .map(|route| route.gain)
+        .collect()
+}
+
+fn channel_count(busses: &[AudioBus]) -> usize {
+    busses.iter().map(|bus| usize::from(bus.channels)).sum()
+}
+
+type JackAsyncClient<P, E> = jack::AsyncClient<JackNotificationHandler<E>, JackProcessHandler<P>>;
+
+/// Deactivate and close the client, and return the process handler.
+fn deactivate<P: RtProcessHandler, E: FatalErrorHandler>(
+    async_client: JackAsyncClient<P, E>,
+    shutdown_hook: &ShutdownHook,
+) -> Option<P> {
+    shutdown_hook.run();
+
+    info!("Deactivating Jack client...");
+
+    match async_client.deactivate() {
+        Ok((_client, _notification_handler, process)) => {
+            let mut rt_process_handler = process.rt_process_handler;
+            rt_process_handler.deinit();
+            Some(rt_process_handler)
+        }
+        Err(e) => {
+            warn!("JACK: failed to deactivate the client: {}", e);
+            None
+        }
+    }
 }
 
 pub fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
     config: &Config,
-    mut rt_process_handler: P,
+    rt_process_handler: P,
+    fatal_error_handler: E,
+    use_client_name: Option<String>,
+) -> Result<(StreamInfo, JackRtThreadHandle<P, E>), SpawnError<P>> {
+    let mut rt_process_handler = Some(rt_process_handler);
+    spawn(
+        config,
+        &mut rt_process_handler,
+        fatal_error_handler,
+        use_client_name,
+    )
+    .map_err(|e| SpawnError::new(e, rt_process_handler))
+}
+
+/// Spawn the stream, leaving the handler in `rt_process_handler_slot` if it fails.
+fn spawn<P: RtProcessHandler, E: FatalErrorHandler>(
+    config: &Config,
+    rt_process_handler_slot: &mut Option<P>,
     fatal_error_handler: E,
     use_client_name: Option<String>,
 ) -> Result<(StreamInfo, JackRtThreadHandle<P, E>), SpawnRtThreadError> {
@@ -153,142 +826,145 @@ pub fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
 
     info!("Registering Jack client with name {}", &client_name);
 
-    let (client, _status) = jack::Client::new(&client_name, jack::ClientOptions::empty())?;
+    // Everything created below is dropped (and so cleaned up) if the deadline passes.
+    let deadline = SpawnDeadline::new(config.spawn_timeout);
 
-    // Find system ports
+    if let Some(server_name) = &config.jack_server_name {
+        info!("Connecting to the Jack server named {}", server_name);
+    }
 
-    let system_audio_in_ports: Vec<String> = client.ports(
-        None,
-        Some("32 bit float mono audio"),
-        jack::PortFlags::IS_OUTPUT,
-    );
-    let system_audio_out_ports: Vec<String> = client.ports(
-        None,
-        Some("32 bit float mono audio"),
-        jack::PortFlags::IS_INPUT,
+    let options = jack::ClientOptions::empty();
+    let server_name = config.jack_server_name.clone();
+    let (client, _status) = deadline
+        .run(SpawnPhase::Open, move || {
+            open_client(&client_name, options, server_name.as_deref())
+        })?
+        .map_err(|e| client_open_error(e, server_start_attempted(options)))?;
+
+    let config = &crate::port_pattern::resolve_config_patterns(
+        config,
+        &graph_audio_ports(&client, true),
+        &graph_audio_ports(&client, false),
     );
 
+    // Check every bus before registering any ports.
+
+    check_system_ports(&config.audio_in_busses)?;
+    check_system_ports(&config.audio_out_busses)?;
+
     // Register new ports.
 
+    // The `*_routed_port_names` are the client port of every connection to a system port,
+    // which only differs from `*_port_names` with `PortMixing`.
+
     let mut audio_in_ports = Vec::<jack::Port<jack::AudioIn>>::new();
     let mut audio_in_port_names = Vec::<String>::new();
+    let mut audio_in_routed_port_names = Vec::<String>::new();
     let mut audio_in_connected_port_names = Vec::<String>::new();
     let mut audio_in_busses = Vec::<AudioBus>::new();
     for (bus_i, bus) in config.audio_in_busses.iter().enumerate() {
-        if bus.system_ports.len() == 0 {
-            return Err(SpawnRtThreadError::NoSystemPortsGiven(bus.id.clone()));
-        }
-
-        audio_in_busses.push(AudioBus {
-            id_name: bus.id.clone(),
-            id_index: DeviceIndex::new(bus_i),
-            system_device: String::from("Jack"),
-            system_half_duplex_device: None,
-            system_ports: bus.system_ports.clone(),
-            channels: bus.system_ports.len() as u16,
-        });
-
-        for (i, system_port) in bus.system_ports.iter().enumerate() {
-            if !system_audio_in_ports.contains(&system_port) {
-                return Err(SpawnRtThreadError::SystemPortNotFound(
-                    system_port.clone(),
-                    bus.id.clone(),
-                ));
-            }
-
-            let user_port_name = format!("{}_{}", &bus.id, i + 1);
-            let user_port = client.register_port(&user_port_name, jack::AudioIn::default())?;
-
-            audio_in_port_names.push(user_port.name()?);
-            audio_in_connected_port_names.push(system_port.clone());
-            audio_in_ports.push(user_port);
-        }
+        let (audio_bus, mut ports) =
+            register_audio_bus::<jack::AudioIn>(&client, bus, bus_i, config.jack_pretty_names)?;
+
+        let (routed, connected) = routed_ports(bus, &audio_bus.client_ports, true);
+        audio_in_port_names.extend(audio_bus.client_ports.iter().cloned());
+        audio_in_routed_port_names.extend(routed);
+        audio_in_connected_port_names.extend(connected);
+        audio_in_ports.append(&mut ports);
+        audio_in_busses.push(audio_bus);
     }
 
     let mut audio_out_ports = Vec::<jack::Port<jack::AudioOut>>::new();
     let mut audio_out_port_names = Vec::<String>::new();
+    let mut audio_out_routed_port_names = Vec::<String>::new();
     let mut audio_out_connected_port_names = Vec::<String>::new();
     let mut audio_out_busses = Vec::<AudioBus>::new();
     for (bus_i, bus) in config.audio_out_busses.iter().enumerate() {
-        if bus.system_ports.len() == 0 {
-            return Err(SpawnRtThreadError::NoSystemPortsGiven(bus.id.clone()));
-        }
-
-        audio_out_busses.push(AudioBus {
-            id_name: bus.id.clone(),
-            id_index: DeviceIndex::new(bus_i),
-            system_device: String::from("Jack"),
-            system_half_duplex_device: None,
-            system_ports: bus.system_ports.clone(),
-            channels: bus.system_ports.len() as u16,
-        });
-
-        for (i, system_port) in bus.system_ports.iter().enumerate() {
-            if !system_audio_out_ports.contains(&system_port) {
-                return Err(SpawnRtThreadError::SystemPortNotFound(
-                    system_port.clone(),
-                    bus.id.clone(),
-                ));
-            }
-
-            let user_port_name = format!("{}_{}", &bus.id, i + 1);
-            let user_port = client.register_port(&user_port_name, jack::AudioOut::default())?;
-
-            audio_out_port_names.push(user_port.name()?);
-            audio_out_connected_port_names.push(system_port.clone());
-            audio_out_ports.push(user_port);
-        }
+        let (audio_bus, mut ports) =
+            register_audio_bus::<jack::AudioOut>(&client, bus, bus_i, config.jack_pretty_names)?;
+
+        let (routed, connected) = routed_ports(bus, &audio_bus.client_ports, false);
+        audio_out_port_names.extend(audio_bus.client_ports.iter().cloned());
+        audio_out_routed_port_names.extend(routed);
+        audio_out_connected_port_names.extend(connected);
+        audio_out_ports.append(&mut ports);
+        audio_out_busses.push(audio_bus);
     }
 
     let mut midi_in_ports = Vec::<jack::Port<jack::MidiIn>>::new();
-    let mut midi_in_port_names = Vec::<String>::new();
+    let mut midi_in_routed_port_names = Vec::<String>::new();
     let mut midi_in_connected_port_names = Vec::<String>::new();
     let mut midi_in_controllers = Vec::<MidiController>::new();
 
     let mut midi_out_ports = Vec::<jack::Port<jack::MidiOut>>::new();
-    let mut midi_out_port_names = Vec::<String>::new();
+    let mut midi_out_routed_port_names = Vec::<String>::new();
     let mut midi_out_connected_port_names = Vec::<String>::new();
     let mut midi_out_controllers = Vec::<MidiController>::new();
 
-    if let Some(midi_server) = &config.midi_server {
-        if midi_server == "Jack" {
-            for (controller_i, controller) in config.midi_in_controllers.iter().enumerate() {
-                let system_port_name = &controller.system_port;
-
-                midi_in_controllers.push(MidiController {
-                    id_name: controller.id.clone(),
-                    id_index: DeviceIndex::new(controller_i),
-                    system_port: String::from(system_port_name),
-                });
-
-                let port = client.register_port(&controller.id, jack::MidiIn::default())?;
-
-                midi_in_port_names.push(port.name()?);
-                midi_in_connected_port_names.push(String::from(system_port_name));
-                midi_in_ports.push(port);
+    if let Some(reason) = config.skipped_midi_reason(Backend::Jack) {
+        warn!("JACK: {}", reason);
+    }
+    if config.creates_midi_controllers(Backend::Jack) {
+        let system_midi_in_ports: Vec<String> =
+            client.ports(None, Some("8 bit raw midi"), jack::PortFlags::IS_OUTPUT);
+        let system_midi_out_ports: Vec<String> =
+            client.ports(None, Some("8 bit raw midi"), jack::PortFlags::IS_INPUT);
+
+        for (controller_i, controller) in config.midi_in_controllers.iter().enumerate() {
+            let (midi_controller, port) = register_midi_controller::<jack::MidiIn>(
+                &client,
+                controller,
+                controller_i,
+                &config.midi_in_port_name(&controller.id),
+                &system_midi_in_ports,
+                true,
+                config.jack_pretty_names,
+            )?;
+
+            let port_name = port.name()?;
+            for system_port in midi_connections(controller, &midi_controller) {
+                midi_in_routed_port_names.push(port_name.clone());
+                midi_in_connected_port_names.push(system_port);
             }
+            midi_in_ports.push(port);
+            midi_in_controllers.push(midi_controller);
+        }
 
-            for (controller_i, controller) in config.midi_out_controllers.iter().enumerate() {
-                let system_port_name = &controller.system_port;
-
-                midi_out_controllers.push(MidiController {
-                    id_name: controller.id.clone(),
-                    id_index: DeviceIndex::new(controller_i),
-                    system_port: String::from(system_port_name),
-                });
-
-                let port = client.register_port(&controller.id, jack::MidiOut::default())?;
-
-                midi_out_port_names.push(port.name()?);
-                midi_out_connected_port_names.push(String::from(system_port_name));
-                midi_out_ports.push(port);
+        for (controller_i, controller) in config.midi_out_controllers.iter().enumerate() {
+            let (midi_controller, port) = register_midi_controller::<jack::MidiOut>(
+                &client,
+                controller,
+                controller_i,
+                &config.midi_out_port_name(&controller.id),
+                &system_midi_out_ports,
+                false,
+                config.jack_pretty_names,
+            )?;
+
+            let port_name = port.name()?;
+            for system_port in midi_connections(controller, &midi_controller) {
+                midi_out_routed_port_names.push(port_name.clone());
+                midi_out_connected_port_names.push(system_port);
             }
+            midi_out_ports.push(port);
+            midi_out_controllers.push(midi_controller);
         }
     }
 
+    deadline.check(SpawnPhase::RegisterPorts)?;
+
     let sample_rate = client.sample_rate() as u32;
     let max_audio_buffer_size = client.buffer_size() as u32;
 
+    if let Some(requested) = config.buffer_size {
+        if requested != max_audio_buffer_size {
+            warn!(
+                "JACK: ignored the requested buffer size of {} frames, the server runs at {} frames",
+                requested, max_audio_buffer_size
+            );
+        }
+    }
+
     let stream_info = StreamInfo {
         server_name: String::from("Jack"),
         audio_in: audio_in_busses,
@@ -297,74 +973,772 @@ pub fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
         midi_out: midi_out_controllers,
         sample_rate: sample_rate as u32,
         max_audio_buffer_size,
+        latency_frames_in: 0, // Read once the ports are connected.
+        latency_frames_out: 0,
+        sample_format: SampleFormat::F32,
     };
 
+    let shutdown_hook = Arc::new(ShutdownHook::new());
+    let fatal_error_handler = Arc::new(Mutex::new(Some(fatal_error_handler)));
+    let (stop_watcher, rt_stop) =
+        spawn_stop_watcher(Arc::clone(&shutdown_hook), Arc::clone(&fatal_error_handler))?;
+
+    // From here on the handler can only be given back by deactivating the client. If Jack
+    // fails to activate it, the handler is lost.
+    let mut rt_process_handler = rt_process_handler_slot
+        .take()
+        .expect("the handler is only taken once");
     rt_process_handler.init(&stream_info);
 
-    let process = JackProcessHandler::new(
+    let (cycle_debugger, cycle_debug_handle) = cycle_debugger();
+
+    let mut process = JackProcessHandler::new(
         rt_process_handler,
         audio_in_ports,
         audio_out_ports,
         midi_in_ports,
         midi_out_ports,
         stream_info.clone(),
-        max_audio_buffer_size,
+        cycle_debugger,
     );
 
+    process.buffer_size_history =
+        Arc::new(BufferSizeHistory::new(config.buffer_size_history_len()));
+    process.flush_denormals = !config.allow_denormals;
+    process.midi_in_timestamps = config.midi_in_timestamps;
+    process.audio_in_gains = port_gains(&config.audio_in_busses, true);
+    process.audio_out_gains = port_gains(&config.audio_out_busses, false);
+    process.channel_gains = ChannelGains::new(&config.audio_in_busses, &config.audio_out_busses);
+    process.midi_in_buffers = config
+        .midi_in_controllers
+        .iter()
+        .take(stream_info.midi_in.len())
+        .map(|controller| {
+            MidiControllerBuffer::for_input(controller.filter, controller.buffer_capacity)
+        })
+        .collect();
+    let mut midi_in_drops = MidiDropCounters::default();
+    for (controller, buffer) in config
+        .midi_in_controllers
+        .iter()
+        .zip(process.midi_in_buffers.iter())
+    {
+        midi_in_drops.push(&controller.id, buffer);
+    }
+    if config.input_peaks {
+        process.audio_in_buffers = stream_info
+            .audio_in
+            .iter()
+            .map(|bus| AudioBusBuffer::for_input_bus(bus, max_audio_buffer_size, true))
+            .collect();
+    }
+    let (new_ports, new_ports_receiver) = new_ports_queue();
+    process.new_ports = new_ports_receiver;
+    process.rt_stop = rt_stop;
+
+    let frames_processed = Arc::clone(&process.frames_processed);
+    let buffer_size_history = Arc::clone(&process.buffer_size_history);
+    let master_gain_handle = process.master_gain.handle();
+    let channel_gains_handle = process.channel_gains.handle();
+    let silence_detect_handle = process.silence_detector.handle();
+    shutdown_hook.set_stream_info(stream_info.clone());
+    let xruns = Arc::new(Xruns::new());
+    let freewheel = Arc::clone(&process.freewheel);
+
     info!("Activating Jack client...");
 
     // Activate the client, which starts the processing.
     let async_client = client.activate_async(
         JackNotificationHandler {
-            fatal_error_handler: Some(fatal_error_handler),
+            fatal_error_handler,
+            freewheel: Arc::clone(&freewheel),
+            sample_rate: Arc::clone(&process.sample_rate),
+            shutdown_hook: Arc::clone(&shutdown_hook),
+            xruns: Arc::clone(&xruns),
         },
         process,
     )?;
 
     // Try to automatically connect to system inputs/outputs.
 
-    for (in_port, system_in_port) in audio_in_port_names
+    let connections = self_connections(
+        config.jack_self_connect,
+        &audio_in_routed_port_names,
+        &audio_in_connected_port_names,
+        &audio_out_routed_port_names,
+        &audio_out_connected_port_names,
+    )
+    .into_iter()
+    .chain(self_connections(
+        config.jack_self_connect,
+        &midi_in_routed_port_names,
+        &midi_in_connected_port_names,
+        &midi_out_routed_port_names,
+        &midi_out_connected_port_names,
+    ));
+
+    if config.jack_self_connect == JackSelfConnect::Never {
+        info!("Not connecting any ports, self-connect is disabled in the config");
+    }
+
+    let connected = deadline
+        .check(SpawnPhase::Activate)
+        .and_then(|()| connect_ports(async_client.as_client(), connections, &deadline));
+    if let Err(e) = connected {
+        *rt_process_handler_slot = deactivate(async_client, &shutdown_hook);
+        return Err(e);
+    }
+
+    let mut stream_info = stream_info;
+    count_connected_ports(
+        async_client.as_client(),
+        &mut stream_info.audio_in,
+        &audio_in_port_names,
+    );
+    count_connected_ports(
+        async_client.as_client(),
+        &mut stream_info.audio_out,
+        &audio_out_port_names,
+    );
+
+    read_latencies(async_client.as_client(), &mut stream_info);
+
+    // Now with the number of connected ports and the latency.
+    shutdown_hook.set_stream_info(stream_info.clone());
+
+    let bus_ports = bus_ports(
+        &stream_info.audio_in,
+        &audio_in_port_names,
+        jack::LatencyType::Capture,
+    )
+    .into_iter()
+    .chain(bus_ports(
+        &stream_info.audio_out,
+        &audio_out_port_names,
+        jack::LatencyType::Playback,
+    ))
+    .collect();
+
+    info!(
+        "Successfully spawned Jack thread. Sample rate: {}, Max audio buffer size: {}",
+        sample_rate, max_audio_buffer_size
+    );
+
+    Ok((
+        stream_info,
+        JackRtThreadHandle {
+            async_client: Some(async_client),
+            frames_processed,
+            buffer_size_history,
+            cycle_debug_handle,
+            master_gain_handle,
+            channel_gains_handle,
+            silence_detect_handle,
+            shutdown_hook,
+            xruns,
+            freewheel,
+            bus_ports,
+            midi_in_drops,
+            config: Box::new(config.clone()),
+            new_ports,
+            _stop_watcher: stop_watcher,
+        },
+    ))
+}
+
+/// The `(source, destination)` pairs of ports the client connects on its own after it is
+/// activated.
+///
+/// * `in_ports`/`out_ports` - The names of the ports of this client.
+/// * `in_system_ports`/`out_system_ports` - The system port each of those ports connects to.
+fn self_connections<'a>(
+    policy: JackSelfConnect,
+    in_ports: &'a [String],
+    in_system_ports: &'a [String],
+    out_ports: &'a [String],
+    out_system_ports: &'a [String],
+) -> Vec<(&'a str, &'a str)> {
+    match policy {
+        JackSelfConnect::SystemPorts => in_system_ports
+            .iter()
+            .zip(in_ports.iter())
+            .chain(out_ports.iter().zip(out_system_ports.iter()))
+            .map(|(source, destination)| (source.as_str(), destination.as_str()))
+            .collect(),
+        JackSelfConnect::Never => Vec::new(),
+    }
+}
+
+fn connect_ports<'a, I: Iterator<Item = (&'a str, &'a str)>>(
+    client: &jack::Client,
+    connections: I,
+    deadline: &SpawnDeadline,
+) -> Result<(), SpawnRtThreadError> {
+    for (source, destination) in connections {
+        if let Err(e) = client.connect_ports_by_name(source, destination) {
+            warn!(
+                "JACK: failed to connect {} to {}: {}",
+                source, destination, e
+            );
+            return Err(e.into());
+        }
+
+        deadline.check(SpawnPhase::Connect)?;
+    }
+
+    Ok(())
+}
+
+/// Check that every bus has system ports.
+///
+/// Whether they exist is left to connecting them, since they can be any port Jack knows by
+/// that name: a port of another client (e.g. the inputs of a reverb), an alias, or one
+/// that only appears after the enumeration.
+/// The audio ports in the graph that the ports of an input or output bus can connect to,
+/// which port patterns are resolved against.
+fn graph_audio_ports(client: &jack::Client, is_input: bool) -> Vec<String> {
+    client.ports(
+        None,
+        Some("32 bit float mono audio"),
+        if is_input {
+            jack::PortFlags::IS_OUTPUT
+        } else {
+            jack::PortFlags::IS_INPUT
+        },
+    )
+}
+
+fn check_system_ports(busses: &[AudioBusConfig]) -> Result<(), SpawnRtThreadError> {
+    for bus in busses.iter() {
+        if bus.system_ports.is_empty() {
+            return Err(SpawnRtThreadError::NoSystemPortsGiven(bus.id.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether opening a client with these options makes Jack try to start the server if it
+/// isn't running.
+/// Open a client on the Jack server with the given name (as given to `jackd --name`), or
+/// on the default server if `server_name` is `None`.
+///
+/// The jack crate can't pass a server name, so that case calls `jack_client_open` directly.
+fn open_client(
+    client_name: &str,
+    options: jack::ClientOptions,
+    server_name: Option<&str>,
+) -> Result<(jack::Client, jack::ClientStatus), jack::Error> {
+    let server_name = match server_name {
+        Some(server_name) => server_name,
+        None => return jack::Client::new(client_name, options),
+    };
+
+    let invalid = || jack::Error::ClientError(jack::ClientStatus::INVALID_OPTION);
+    let client_name = CString::new(client_name).map_err(|_| invalid())?;
+    let server_name = CString::new(server_name).map_err(|_| invalid())?;
+
+    let options = options | jack::ClientOptions::SERVER_NAME;
+    let mut status_bits = 0;
+    let client = unsafe {
+        jack_sys::jack_client_open(
+            client_name.as_ptr(),
+            options.bits(),
+            &mut status_bits,
+            server_name.as_ptr(),
+        )
+    };
+
+    let status =
+        jack::ClientStatus::from_bits(status_bits).unwrap_or_else(jack::ClientStatus::empty);
+    if client.is_null() {
+        Err(jack::Error::ClientError(status))
+    } else {
+        // The client is closed when it's dropped, just like one from `jack::Client::new()`.
+        Ok((unsafe { jack::Client::from_raw(client) }, status))
+    }
+}
+
+fn server_start_attempted(options: jack::ClientOptions) -> bool {
+    !options.contains(jack::ClientOptions::NO_START_SERVER)
+        && std::env::var_os("JACK_NO_START_SERVER").is_none()
+}
+
+/// Tell "the server isn't running" apart from other failures to open a client.
+fn client_open_error(e: jack::Error, server_start_attempted: bool) -> SpawnRtThreadError {
+    match e {
+        jack::Error::ClientError(status) if status.contains(jack::ClientStatus::SERVER_FAILED) => {
+            if server_start_attempted {
+                SpawnRtThreadError::ServerStartFailed(String::from(Backend::Jack.as_str()))
+            } else {
+                SpawnRtThreadError::ServerNotRunning(String::from(Backend::Jack.as_str()))
+            }
+        }
+        e => e.into(),
+    }
+}
+
+/// Count the ports of each bus that ended up connected to at least one other port.
+///
+/// * `port_names` - The names of the ports of all the busses, in order.
+fn count_connected_ports(client: &jack::Client, busses: &mut [AudioBus], port_names: &[String]) {
+    let mut port_names = port_names.iter();
+
+    for bus in busses.iter_mut() {
+        bus.connected_ports = port_names
+            .by_ref()
+            .take(usize::from(bus.channels))
+            .filter(|name| {
+                client
+                    .port_by_name(name)
+                    .and_then(|port| port.connected_count().ok())
+                    .unwrap_or(0)
+                    > 0
+            })
+            .count() as u16;
+    }
+}
+
+/// Read the latencies of `stream_info` from the system ports its busses are connected to.
+/// Jack's latencies change with the buffer size, so these are read again whenever the
+/// process handler gets a new `StreamInfo`.
+fn read_latencies(client: &jack::Client, stream_info: &mut StreamInfo) {
+    stream_info.latency_frames_in = system_port_latency(
+        client,
+        &stream_info.audio_in,
+        &client_port_names(&stream_info.audio_in),
+        jack::LatencyType::Capture,
+    );
+    stream_info.latency_frames_out = system_port_latency(
+        client,
+        &stream_info.audio_out,
+        &client_port_names(&stream_info.audio_out),
+        jack::LatencyType::Playback,
+    );
+}
+
+fn client_port_names(busses: &[AudioBus]) -> Vec<String> {
+    busses
         .iter()
-        .zip(audio_in_connected_port_names)
-    {
-        async_client
-            .as_client()
-            .connect_ports_by_name(&system_in_port, in_port)?;
+        .flat_map(|bus| bus.client_ports.iter().cloned())
+        .collect()
+}
+
+/// The largest latency of the system ports that the given busses are connected to.
+///
+/// * `port_names` - The names of the ports of this client, one for each channel of `busses`.
+fn system_port_latency(
+    client: &jack::Client,
+    busses: &[AudioBus],
+    port_names: &[String],
+    latency_type: jack::LatencyType,
+) -> u32 {
+    let mut port_names = port_names.iter();
+    let mut latency = 0;
+
+    for bus in busses.iter() {
+        // With `PortMixing` a channel can be connected to several system ports, or several
+        // channels to the same one.
+        let channels: Vec<&String> = port_names
+            .by_ref()
+            .take(usize::from(bus.channels))
+            .collect();
+        for system_port in bus.system_ports.iter() {
+            let is_connected = channels.iter().any(|port_name| {
+                client
+                    .port_by_name(port_name)
+                    .and_then(|port| port.is_connected_to(system_port).ok())
+                    .unwrap_or(false)
+            });
+
+            if let Some(system_port) = client.port_by_name(system_port).filter(|_| is_connected) {
+                let (_min, max) = system_port.get_latency_range(latency_type);
+                latency = latency.max(max);
+            }
+        }
     }
-    for (out_port, system_out_port) in audio_out_port_names
+
+    latency
+}
+
+/// Group the names of this client's ports by bus.
+fn bus_ports(
+    busses: &[AudioBus],
+    port_names: &[String],
+    latency_type: jack::LatencyType,
+) -> Vec<BusPorts> {
+    let mut port_names = port_names.iter();
+
+    busses
         .iter()
-        .zip(audio_out_connected_port_names)
-    {
-        async_client
-            .as_client()
-            .connect_ports_by_name(out_port, &system_out_port)?;
+        .map(|bus| BusPorts {
+            id: bus.id_name.clone(),
+            latency_type,
+            port_names: port_names
+                .by_ref()
+                .take(usize::from(bus.channels))
+                .cloned()
+                .collect(),
+        })
+        .collect()
+}
+
+/// Register a port on the client for every channel of an audio bus.
+fn register_audio_bus<PS: jack::PortSpec + Default>(
+    client: &jack::Client,
+    bus: &AudioBusConfig,
+    bus_i: usize,
+    pretty_names: bool,
+) -> Result<(AudioBus, Vec<jack::Port<PS>>), SpawnRtThreadError> {
+    let channel_layout = bus.channel_layout();
+
+    let mut ports = Vec::<jack::Port<PS>>::new();
+    for i in 0..channel_layout.num_channels() {
+        match client.register_port(&bus.port_name(i), PS::default()) {
+            Ok(port) => ports.push(port),
+            Err(e) => {
+                unregister_ports(client, ports);
+                return Err(e.into());
+            }
+        }
+    }
+
+    if pretty_names {
+        for (i, port) in ports.iter().enumerate() {
+            set_pretty_name(client, port, &bus.pretty_port_name(i));
+        }
+    }
+
+    let mut client_ports = Vec::<String>::new();
+    for port in ports.iter() {
+        client_ports.push(port.name()?);
+    }
+
+    let audio_bus = AudioBus {
+        id_name: bus.id.clone(),
+        id_index: DeviceIndex::new(bus_i),
+        system_device: String::from("Jack"),
+        system_half_duplex_device: None,
+        system_ports: bus.system_ports.clone(),
+        client_ports,
+        channels: channel_layout.num_channels() as u16,
+        channel_layout,
+        connected_ports: 0, // Counted once the ports are connected.
+    };
+
+    Ok((audio_bus, ports))
+}
+
+/// Register the port of a midi controller on the client.
+fn register_midi_controller<PS: jack::PortSpec + Default>(
+    client: &jack::Client,
+    controller: &MidiControllerConfig,
+    controller_i: usize,
+    port_name: &str,
+    system_ports: &[String],
+    is_input: bool,
+    pretty_names: bool,
+) -> Result<(MidiController, jack::Port<PS>), SpawnRtThreadError> {
+    let system_port_name = resolve_midi_system_port(controller, system_ports, is_input)?;
+
+    let port = client.register_port(port_name, PS::default())?;
+    if pretty_names {
+        set_pretty_name(client, &port, &controller.id);
+    }
+
+    let midi_controller = MidiController {
+        id_name: controller.id.clone(),
+        id_index: DeviceIndex::new(controller_i),
+        system_port: system_port_name,
+        client_port: Some(port.name()?),
+    };
+
+    Ok((midi_controller, port))
+}
+
+/// The system ports a midi controller connects to: its (resolved) system port and its
+/// extra connections, or none if it doesn't auto-connect.
+fn midi_connections(
+    controller: &MidiControllerConfig,
+    midi_controller: &MidiController,
+) -> Vec<String> {
+    if !controller.auto_connect {
+        info!(
+            "Not connecting the midi controller {}, auto-connect is disabled for it",
+            &controller.id
+        );
+        return Vec::new();
+    }
+
+    std::iter::once(&midi_controller.system_port)
+        .chain(controller.connections.iter())
+        .cloned()
+        .collect()
+}
+
+/// The metadata key of the name patchbays show for a port, `JACK_METADATA_PRETTY_NAME`.
+const JACK_METADATA_PRETTY_NAME: &str = "http://jackaudio.org/metadata/pretty-name";
+
+/// Set the pretty name of a port in Jack's metadata. Failing is only logged, since the name
+/// is only cosmetic.
+fn set_pretty_name<PS>(client: &jack::Client, port: &jack::Port<PS>, pretty_name: &str) {
+    let (key, value) = match (
+        CString::new(JACK_METADATA_PRETTY_NAME),
+        CString::new(pretty_name),
+    ) {
+        (Ok(key), Ok(value)) => (key, value),
+        _ => {
+            warn!(
+                "JACK: the pretty name {:?} contains a nul byte",
+                pretty_name
+            );
+            return;
+        }
+    };
+
+    let result = unsafe {
+        let uuid = jack_sys::jack_port_uuid(port.raw());
+        jack_sys::jack_set_property(
+            client.raw(),
+            uuid,
+            key.as_ptr(),
+            value.as_ptr(),
+            std::ptr::null(),
+        )
+    };
+    if result != 0 {
+        warn!(
+            "JACK: failed to set the pretty name of {} to {}",
+            port.name().unwrap_or_default(),
+            pretty_name
+        );
+    }
+}
+
+fn unregister_ports<PS>(client: &jack::Client, ports: Vec<jack::Port<PS>>) {
+    for port in ports {
+        if let Err(e) = client.unregister_port(port) {
+            warn!("JACK: failed to unregister a port: {}", e);
+        }
+    }
+}
+
+/// Find the name of the system port a midi controller should connect to.
+fn resolve_midi_system_port(
+    controller: &MidiControllerConfig,
+    system_ports: &[String],
+    is_input: bool,
+) -> Result<String, SpawnRtThreadError> {
+    if let Some(role) = controller.system_port_role {
+        let port = role.resolve(system_ports, is_input).ok_or_else(|| {
+            SpawnRtThreadError::SystemPortNotFound(role.to_string(), controller.id.clone())
+        })?;
+
+        info!(
+            "Resolved midi port role {} to {} for controller {}",
+            role, port, &controller.id
+        );
+
+        Ok(port.clone())
+    } else if crate::port_pattern::is_port_pattern(&controller.system_port) {
+        let port = crate::port_pattern::resolve_system_port(&controller.system_port, system_ports)
+            .ok_or_else(|| {
+                SpawnRtThreadError::SystemPortNotFound(
+                    controller.system_port.clone(),
+                    controller.id.clone(),
+                )
+            })?;
+
+        info!(
+            "Resolved midi port pattern {} to {} for controller {}",
+            &controller.system_port, port, &controller.id
+        );
+
+        Ok(String::from(port))
+    } else {
+        Ok(controller.system_port.clone())
+    }
+}
+
+/// How many busses/controllers can be added before the process handler picks them up. See
+/// `StreamHandle::add_audio_in_bus()`.
+const NEW_PORTS_QUEUE_SIZE: usize = 16;
+
+/// The ports and buffers of a bus or controller that was added while the stream runs.
+enum NewPorts {
+    AudioIn(
+        Growth<jack::Port<jack::AudioIn>>,
+        Growth<f32>,
+        Growth<AudioBusBuffer>,
+        Growth<BusState>,
+    ),
+    AudioOut(
+        Growth<jack::Port<jack::AudioOut>>,
+        Growth<f32>,
+        Growth<AudioBusBuffer>,
+    ),
+    MidiIn(
+        Growth<jack::Port<jack::MidiIn>>,
+        Growth<MidiControllerBuffer>,
+    ),
+    MidiOut(
+        Growth<jack::Port<jack::MidiOut>>,
+        Growth<MidiControllerBuffer>,
+    ),
+}
+
+/// New ports for the process handler, along with its `StreamInfo` with the new bus or
+/// controller.
+///
+/// Once it has been applied this holds the old lists and `StreamInfo` of the process
+/// handler instead, and is sent back so they are freed off the realtime thread.
+struct PortsUpdate {
+    ports: NewPorts,
+    stream_info: StreamInfo,
+}
+
+/// The stream handle's half of the queue that brings new ports to the process handler.
+struct NewPortsSender {
+    updates: Producer<PortsUpdate>,
+    applied: Consumer<PortsUpdate>,
+}
+
+/// The process handler's half of the queue that brings new ports to it.
+struct NewPortsReceiver {
+    updates: Consumer<PortsUpdate>,
+    applied: Producer<PortsUpdate>,
+}
+
+fn new_ports_queue() -> (NewPortsSender, NewPortsReceiver) {
+    let (updates_producer, updates_consumer) =
+        RingBuffer::<PortsUpdate>::new(NEW_PORTS_QUEUE_SIZE).split();
+    let (applied_producer, applied_consumer) =
+        RingBuffer::<PortsUpdate>::new(NEW_PORTS_QUEUE_SIZE).split();
+
+    (
+        NewPortsSender {
+            updates: updates_producer,
+            applied: applied_consumer,
+        },
+        NewPortsReceiver {
+            updates: updates_consumer,
+            applied: applied_producer,
+        },
+    )
+}
+
+impl NewPortsSender {
+    /// Free whatever the process handler is done with, and make sure there is room to send
+    /// one more update. Call this before registering the ports of a new bus/controller.
+    fn reserve(&mut self) -> Result<(), AddBusError> {
+        while self.applied.pop().is_some() {}
+
+        if self.updates.is_full() {
+            Err(AddBusError::QueueFull)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Queue an update for the process handler.
+    fn send(&mut self, update: PortsUpdate) {
+        // `reserve()` made room for it, and only the stream handle pushes.
+        let _ = self.updates.push(update);
+    }
+}
+
+/// How often the stop watcher checks whether the process handler stopped the client.
+const STOP_WATCH_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The process handler's half of the stop watcher. Stopping the client from the realtime
+/// thread only raises this flag and leaves the error (if any) in a preallocated slot, and
+/// the watcher does the rest.
+struct RtStop {
+    stopped: Arc<AtomicBool>,
+    error: Producer<FatalStreamError>,
+}
+
+impl RtStop {
+    /// An `RtStop` that nothing watches, until the stream gives the process handler one.
+    fn unwatched() -> Self {
+        Self {
+            stopped: Arc::new(AtomicBool::new(false)),
+            error: RingBuffer::<FatalStreamError>::new(1).split().0,
+        }
     }
 
-    for (in_port, system_in_port) in midi_in_port_names.iter().zip(midi_in_connected_port_names) {
-        async_client
-            .as_client()
-            .connect_ports_by_name(&system_in_port, in_port)?;
+    fn stop(&mut self, error: Option<FatalStreamError>) {
+        if let Some(error) = error {
+            // The client is only stopped once, so the slot is always free.
+            let _ = self.error.push(error);
+        }
+
+        self.stopped.store(true, Ordering::Release);
     }
-    for (out_port, system_out_port) in midi_out_port_names
-        .iter()
-        .zip(midi_out_connected_port_names)
-    {
-        async_client
-            .as_client()
-            .connect_ports_by_name(out_port, &system_out_port)?;
+}
+
+/// Stops the stop watcher thread once this is dropped. It is only signalled rather than
+/// joined, since the stream handle may be dropped by the hook that the watcher runs.
+struct StopWatcher {
+    running: Arc<AtomicBool>,
+}
+
+impl Drop for StopWatcher {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
     }
+}
 
-    info!(
-        "Successfully spawned Jack thread. Sample rate: {}, Max audio buffer size: {}",
-        sample_rate, max_audio_buffer_size
-    );
+/// Spawn the thread that runs the shutdown hook and reports the error (if any) once the
+/// process handler has stopped the client. Jack doesn't tell a client that its process
+/// callback stopped it, so the watcher polls for it.
+fn spawn_stop_watcher<E: FatalErrorHandler>(
+    shutdown_hook: Arc<ShutdownHook>,
+    fatal_error_handler: Arc<Mutex<Option<E>>>,
+) -> Result<(StopWatcher, RtStop), SpawnRtThreadError> {
+    let (error, mut error_consumer) = RingBuffer::<FatalStreamError>::new(1).split();
+    let rt_stop = RtStop {
+        stopped: Arc::new(AtomicBool::new(false)),
+        error,
+    };
+    let stopped = Arc::clone(&rt_stop.stopped);
+    let running = Arc::new(AtomicBool::new(true));
+    let thread_running = Arc::clone(&running);
+
+    std::thread::Builder::new()
+        .name(String::from("rusty-daw-io Jack stop watcher"))
+        .spawn(move || loop {
+            // Checked first, so a stop right before the stream handle is dropped is still
+            // handled.
+            let done = !thread_running.load(Ordering::Relaxed);
+
+            if stopped.load(Ordering::Acquire) {
+                let error = error_consumer.pop();
+                match &error {
+                    Some(error) => info!("JACK: {}", error),
+                    None => info!("JACK: the process handler stopped the stream"),
+                }
 
-    Ok((
-        stream_info,
-        JackRtThreadHandle {
-            _async_client: async_client,
-        },
-    ))
+                shutdown_hook.run();
+
+                if let Some(error) = error {
+                    // Shared with the notification handler, whichever notices a failure
+                    // first.
+                    if let Some(fatal_error_handler) = fatal_error_handler.lock().unwrap().take() {
+                        fatal_error_handler.fatal_stream_error(error);
+                    }
+                }
+                break;
+            }
+            if done {
+                break;
+            }
+
+            std::thread::sleep(STOP_WATCH_INTERVAL);
+        })
+        .map_err(|e| SpawnRtThreadError::PlatformSpecific(Box::new(e)))?;
+
+    Ok((StopWatcher { running }, rt_stop))
 }
 
 struct JackProcessHandler<P: RtProcessHandler> {
@@ -373,6 +1747,10 @@ struct JackProcessHandler<P: RtProcessHandler> {
     audio_in_ports: Vec<jack::Port<jack::AudioIn>>,
     audio_out_ports: Vec<jack::Port<jack::AudioOut>>,
 
+    /// The gain of every port from its bus's `PortMixing`, in the same order as the ports.
+    audio_in_gains: Vec<f32>,
+    audio_out_gains: Vec<f32>,
+
     audio_in_buffers: Vec<AudioBusBuffer>,
     audio_out_buffers: Vec<AudioBusBuffer>,
 
@@ -382,8 +1760,31 @@ struct JackProcessHandler<P: RtProcessHandler> {
     midi_in_buffers: Vec<MidiControllerBuffer>,
     midi_out_buffers: Vec<MidiControllerBuffer>,
 
+    /// The order to write the events of a midi output buffer in, preallocated so sorting
+    /// doesn't allocate.
+    midi_out_order: Vec<usize>,
+
     stream_info: StreamInfo,
     max_audio_buffer_size: usize,
+
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    freewheel: Arc<Freewheel>,
+    /// The sample rate Jack last reported, shared with the notification handler.
+    sample_rate: Arc<AtomicU32>,
+    frame_clock: FrameClock,
+    cycle_debugger: CycleDebugger,
+    master_gain: MasterGain,
+    /// The gains set with `StreamHandle::set_channel_gain()`, on top of the fixed gains of
+    /// the port mixing.
+    channel_gains: ChannelGains,
+    silence_detector: SilenceDetector,
+    flush_denormals: bool,
+    midi_in_timestamps: MidiTimestamps,
+
+    /// Busses and controllers added by the stream handle.
+    new_ports: NewPortsReceiver,
+    rt_stop: RtStop,
 }
 
 impl<P: RtProcessHandler> JackProcessHandler<P> {
@@ -394,16 +1795,18 @@ impl<P: RtProcessHandler> JackProcessHandler<P> {
         midi_in_ports: Vec<jack::Port<jack::MidiIn>>,
         midi_out_ports: Vec<jack::Port<jack::MidiOut>>,
         stream_info: StreamInfo,
-        max_audio_buffer_size: u32,
+        cycle_debugger: CycleDebugger,
     ) -> Self {
+        let max_audio_buffer_size = stream_info.max_audio_buffer_size;
+
         let mut audio_in_buffers = Vec::<AudioBusBuffer>::new();
         let mut audio_out_buffers = Vec::<AudioBusBuffer>::new();
 
         for bus in stream_info.audio_in.iter() {
-            audio_in_buffers.push(AudioBusBuffer::new(bus.channels, max_audio_buffer_size))
+            audio_in_buffers.push(AudioBusBuffer::for_bus(bus, max_audio_buffer_size))
         }
         for bus in stream_info.audio_out.iter() {
-            audio_out_buffers.push(AudioBusBuffer::new(bus.channels, max_audio_buffer_size))
+            audio_out_buffers.push(AudioBusBuffer::for_bus(bus, max_audio_buffer_size))
         }
 
         let mut midi_in_buffers = Vec::<MidiControllerBuffer>::new();
@@ -416,8 +1819,19 @@ impl<P: RtProcessHandler> JackProcessHandler<P> {
             midi_out_buffers.push(MidiControllerBuffer::new())
         }
 
+        let silence_detector = SilenceDetector::new(
+            stream_info
+                .audio_in
+                .iter()
+                .map(|b| b.id_name.clone())
+                .collect(),
+        );
+        let sample_rate = Arc::new(AtomicU32::new(stream_info.sample_rate));
+
         Self {
             rt_process_handler,
+            audio_in_gains: vec![1.0; audio_in_ports.len()],
+            audio_out_gains: vec![1.0; audio_out_ports.len()],
             audio_in_ports,
             audio_out_ports,
             audio_in_buffers,
@@ -426,14 +1840,143 @@ impl<P: RtProcessHandler> JackProcessHandler<P> {
             midi_out_ports,
             midi_in_buffers,
             midi_out_buffers,
+            midi_out_order: Vec::with_capacity(MIDI_BUFFER_SIZE),
             stream_info,
             max_audio_buffer_size: max_audio_buffer_size as usize,
+            frames_processed: Arc::new(AtomicU64::new(0)),
+            buffer_size_history: Arc::new(BufferSizeHistory::new(0)),
+            freewheel: Arc::new(Freewheel::new()),
+            sample_rate,
+            frame_clock: FrameClock::default(),
+            cycle_debugger,
+            master_gain: MasterGain::new(),
+            channel_gains: ChannelGains::new(&[], &[]),
+            silence_detector,
+            flush_denormals: true,
+            midi_in_timestamps: MidiTimestamps::default(),
+            new_ports: new_ports_queue().1,
+            rt_stop: RtStop::unwatched(),
+        }
+    }
+
+    /// Add the busses and controllers that were added since the last cycle. Returns
+    /// `true` if there were any.
+    fn apply_new_ports(&mut self) -> bool {
+        let mut added = false;
+
+        while let Some(mut update) = self.new_ports.updates.pop() {
+            match &mut update.ports {
+                NewPorts::AudioIn(ports, gains, buffers, silence) => {
+                    ports.apply(&mut self.audio_in_ports);
+                    gains.apply(&mut self.audio_in_gains);
+                    buffers.apply(&mut self.audio_in_buffers);
+                    silence.apply(self.silence_detector.bus_states_mut());
+                }
+                NewPorts::AudioOut(ports, gains, buffers) => {
+                    ports.apply(&mut self.audio_out_ports);
+                    gains.apply(&mut self.audio_out_gains);
+                    buffers.apply(&mut self.audio_out_buffers);
+                }
+                NewPorts::MidiIn(ports, buffers) => {
+                    ports.apply(&mut self.midi_in_ports);
+                    buffers.apply(&mut self.midi_in_buffers);
+                }
+                NewPorts::MidiOut(ports, buffers) => {
+                    ports.apply(&mut self.midi_out_ports);
+                    buffers.apply(&mut self.midi_out_buffers);
+                }
+            }
+
+            // The sample rate and buffer size may have changed since the update was sent.
+            // The latencies were read fresh by the stream handle when it sent the update.
+            update.stream_info.sample_rate = self.stream_info.sample_rate;
+            update.stream_info.max_audio_buffer_size = self.stream_info.max_audio_buffer_size;
+            std::mem::swap(&mut self.stream_info, &mut update.stream_info);
+
+            // If the stream handle hasn't freed the previous updates yet, this one is
+            // freed here.
+            let _ = self.new_ports.applied.push(update);
+
+            added = true;
+        }
+
+        added
+    }
+}
+
+impl<P: RtProcessHandler> JackProcessHandler<P> {
+    /// Silence the outputs and report the panic. The handler may be in any state now, so
+    /// this stops the client instead of calling it again.
+    fn process_panicked(
+        &mut self,
+        ps: &jack::ProcessScope,
+        error: FatalStreamError,
+    ) -> jack::Control {
+        for port in self.audio_out_ports.iter_mut() {
+            write_output_port(port.as_mut_slice(ps), &[], 1.0);
         }
+
+        self.stop_with_error(error)
+    }
+
+    /// Stop the stream because of a fatal error outside of `process`, where the output
+    /// ports can't be silenced. The stop watcher reports the error, the fatal error handler
+    /// is never called on the realtime thread.
+    fn stop_with_error(&mut self, error: FatalStreamError) -> jack::Control {
+        self.rt_stop.stop(Some(error));
+        jack::Control::Quit
     }
 }
 
 impl<P: RtProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
-    fn process(&mut self, _: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+    fn buffer_size(&mut self, _: &jack::Client, size: jack::Frames) -> jack::Control {
+        let changed = match catch_process_panic(|| {
+            let buffers = self
+                .audio_in_buffers
+                .iter_mut()
+                .chain(self.audio_out_buffers.iter_mut());
+            update_buffer_size(
+                &mut self.rt_process_handler,
+                &mut self.stream_info,
+                buffers,
+                size,
+            )
+        }) {
+            Ok(changed) => changed,
+            Err(error) => return self.stop_with_error(error),
+        };
+
+        if changed {
+            self.max_audio_buffer_size = size as usize;
+            info!("JACK: buffer size changed to {}", size);
+        }
+
+        jack::Control::Continue
+    }
+
+    fn process(&mut self, client: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+        let cycle_start_time = Instant::now();
+        let cycle_start_frame = self.frame_clock.extend(ps.last_frame_time());
+
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        if let Err(error) = catch_process_panic(|| {
+            update_sample_rate(
+                &mut self.rt_process_handler,
+                &mut self.stream_info,
+                sample_rate,
+            )
+        }) {
+            return self.process_panicked(ps, error);
+        }
+
+        if self.apply_new_ports() {
+            if let Err(error) =
+                catch_process_panic(|| self.rt_process_handler.busses_added(&self.stream_info))
+            {
+                return self.process_panicked(ps, error);
+            }
+        }
+
         let mut audio_frames = 0;
 
         // Collect Audio Inputs
@@ -452,10 +1995,18 @@ impl<P: RtProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
 
                 // The compiler should in-theory optimize by not filling in zeros before copying
                 // the slice. This should never allocate because each buffer was given a capacity of
-                // the maximum buffer size that jack will send.
+                // the maximum buffer size that jack will send, and is grown in `buffer_size`
+                // when that changes.
                 channel.resize(audio_frames, 0.0);
                 channel.copy_from_slice(port_slice);
 
+                let gain = self.audio_in_gains[port];
+                if gain != 1.0 {
+                    for s in channel.iter_mut() {
+                        *s *= gain;
+                    }
+                }
+
                 port += 1;
             }
 
@@ -469,6 +2020,20 @@ impl<P: RtProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
             }
         }
 
+        self.channel_gains.apply_inputs(
+            &mut self.audio_in_buffers,
+            audio_frames,
+            self.stream_info.sample_rate,
+        );
+        for audio_buffer in self.audio_in_buffers.iter_mut() {
+            audio_buffer.update_peaks();
+        }
+        self.silence_detector.process(
+            &self.audio_in_buffers,
+            audio_frames,
+            self.stream_info.sample_rate,
+        );
+
         // Clear Audio Outputs
 
         for audio_buffer in self.audio_out_buffers.iter_mut() {
@@ -485,7 +2050,10 @@ impl<P: RtProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
             midi_buffer.clear();
 
             for event in port.iter(ps) {
-                if let Err(e) = midi_buffer.push_raw(event.time, event.bytes) {
+                let delta_frames = self
+                    .midi_in_timestamps
+                    .delta_frames(cycle_start_frame, event.time);
+                if let Err(e) = midi_buffer.push_input(delta_frames, event.bytes) {
                     warn!(
                         "Warning: Dropping midi event because of the push error: {}",
                         e
@@ -500,20 +2068,49 @@ impl<P: RtProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
             midi_buffer.clear();
         }
 
-        self.rt_process_handler.process(ProcessInfo {
-            audio_in: self.audio_in_buffers.as_slice(),
-            audio_out: self.audio_out_buffers.as_mut_slice(),
+        let freewheel = self.freewheel.is_enabled();
+        let transport = transport_info(client);
+        let control = match catch_process_panic(|| {
+            let _denormals = DenormalGuard::new(self.flush_denormals);
+            dispatch_process(
+                &mut self.rt_process_handler,
+                freewheel,
+                ProcessInfo {
+                    audio_in: self.audio_in_buffers.as_slice(),
+                    audio_out: self.audio_out_buffers.as_mut_slice(),
+                    audio_frames,
+
+                    midi_in: self.midi_in_buffers.as_slice(),
+                    midi_out: self.midi_out_buffers.as_mut_slice(),
+
+                    sample_rate: self.stream_info.sample_rate,
+                    transport,
+                    cycle_start_frame,
+                    cycle_start_time,
+                },
+            )
+        }) {
+            Ok(control) => control,
+            Err(error) => return self.process_panicked(ps, error),
+        };
+
+        self.master_gain.apply(
+            &mut self.audio_out_buffers,
             audio_frames,
-
-            midi_in: self.midi_in_buffers.as_slice(),
-            midi_out: self.midi_out_buffers.as_mut_slice(),
-
-            sample_rate: self.stream_info.sample_rate,
-        });
-
-        // TODO: Properly mix outputs in the case where a system port is connected to more than one bus/controller.
+            self.stream_info.sample_rate,
+        );
+        self.channel_gains.apply_outputs(
+            &mut self.audio_out_buffers,
+            audio_frames,
+            self.stream_info.sample_rate,
+        );
 
         // Copy processed data to Audio Outputs
+        //
+        // Every channel of every bus has a port of its own, and Jack sums all of the
+        // connections to an input port. So busses that are connected to the same system port
+        // are mixed by the server, and a system capture port that is connected to several
+        // busses is copied into each of them.
 
         let mut port = 0; // Ports are in order.
         for audio_buffer in self.audio_out_buffers.iter() {
@@ -521,45 +2118,252 @@ impl<P: RtProcessHandler> jack::ProcessHandler for JackProcessHandler<P> {
                 let port_slice = self.audio_out_ports[port].as_mut_slice(ps);
 
                 // Just in case the user resized the output buffer for some reason.
-                let len = channel.len().min(port_slice.len());
-                if len != audio_frames {
+                if channel.len() < audio_frames {
                     warn!(
                         "Warning: An audio output buffer was resized from {} to {} by the user",
-                        audio_frames, len
+                        audio_frames,
+                        channel.len()
                     );
                 }
 
-                &mut port_slice[0..len].copy_from_slice(&channel[0..len]);
+                write_output_port(port_slice, channel, self.audio_out_gains[port]);
 
                 port += 1;
             }
         }
 
         // Copy processed data to MIDI Outputs
+        //
+        // Jack needs the events of a cycle in time order, and drops events past the end of
+        // the cycle, so those are moved to the last frame instead.
 
-        for (midi_buffer, port) in self
+        let last_frame = (audio_frames as u32).saturating_sub(1);
+
+        for ((midi_buffer, port), controller) in self
             .midi_out_buffers
             .iter()
             .zip(self.midi_out_ports.iter_mut())
+            .zip(self.stream_info.midi_out.iter())
         {
             let mut port_writer = port.writer(ps);
 
-            for event in midi_buffer.events() {
+            midi_buffer.time_order(&mut self.midi_out_order);
+
+            let mut previous_time = 0;
+            for event in self
+                .midi_out_order
+                .iter()
+                .map(|i| &midi_buffer.events()[*i])
+            {
+                let time = event.delta_frames.min(last_frame);
+
+                // Jack rejects an event that is earlier than the one before it.
+                debug_assert!(
+                    time >= previous_time,
+                    "the midi events of output controller {} are not in time order ({} after {})",
+                    &controller.id_name,
+                    time,
+                    previous_time
+                );
+                previous_time = time;
+
                 if let Err(e) = port_writer.write(&jack::RawMidi {
-                    time: event.delta_frames,
-                    bytes: &event.data(),
+                    time,
+                    bytes: event.data(),
                 }) {
-                    warn!("Warning: Could not copy midi data to Jack output: {}", e);
+                    warn!(
+                        "JACK: could not write a midi event at frame {} to output controller {}: {}",
+                        time, &controller.id_name, e
+                    );
                 }
             }
         }
 
-        jack::Control::Continue
+        let frames_processed = self
+            .frames_processed
+            .fetch_add(audio_frames as u64, Ordering::Relaxed);
+        self.buffer_size_history.push(audio_frames);
+
+        if self.cycle_debugger.begin_cycle() {
+            self.cycle_debugger.log(CycleDebugInfo {
+                frames_processed,
+                audio_frames,
+                audio_in_channels: self.audio_in_ports.len(),
+                audio_out_channels: self.audio_out_ports.len(),
+                midi_in_events: self.midi_in_buffers.iter().map(|b| b.len()).sum(),
+                midi_out_events: self.midi_out_buffers.iter().map(|b| b.len()).sum(),
+            });
+        }
+
+        match control {
+            ProcessControl::Continue => jack::Control::Continue,
+            ProcessControl::Quit => {
+                // The stop watcher runs the shutdown hook, since that locks and allocates.
+                self.rt_stop.stop(None);
+                jack::Control::Quit
+            }
+        }
+    }
+}
+
+/// Query Jack's transport. This is realtime-safe.
+fn transport_info(client: &jack::Client) -> Option<TransportInfo> {
+    let jack::TransportStatePosition { pos, state } = client.transport().query().ok()?;
+
+    Some(TransportInfo {
+        playing: state == jack::TransportState::Rolling,
+        frame: u64::from(pos.frame()),
+        bbt: pos.bbt().map(bbt_from_jack),
+    })
+}
+
+fn bbt_from_jack(bbt: jack::TransportBBT) -> TransportBbt {
+    TransportBbt {
+        bar: bbt.bar as u32,
+        beat: bbt.beat as u32,
+        tick: bbt.tick as u32,
+        beats_per_bar: bbt.sig_num,
+        beat_type: bbt.sig_denom,
+        ticks_per_beat: bbt.ticks_per_beat,
+        beats_per_minute: bbt.bpm,
+    }
+}
+
+/// Widens Jack's 32 bit frame clock, which wraps after about a day at 48 kHz, to 64 bits.
+#[derive(Debug, Default)]
+struct FrameClock {
+    last: u32,
+    wraps: u64,
+}
+
+impl FrameClock {
+    /// The 64 bit frame for `frame`. This must be called at least once before the clock
+    /// wraps again, which is always the case when it's called every cycle.
+    fn extend(&mut self, frame: u32) -> u64 {
+        if frame < self.last {
+            self.wraps += 1;
+        }
+        self.last = frame;
+
+        (self.wraps << 32) | u64::from(frame)
+    }
+}
+
+/// Tell the process handler about a new sample rate, if it changed since the last cycle.
+fn update_sample_rate<P: RtProcessHandler>(
+    rt_process_handler: &mut P,
+    stream_info: &mut StreamInfo,
+    sample_rate: u32,
+) {
+    if stream_info.sample_rate != sample_rate {
+        stream_info.sample_rate = sample_rate;
+        rt_process_handler.sample_rate_changed(stream_info);
+    }
+}
+
+/// Grow `buffers` to the new buffer size and tell the handler, if the size changed. Jack
+/// also calls its buffer size callback once before the first cycle, with the size the
+/// stream was spawned with. Returns `true` if the size changed.
+fn update_buffer_size<'a, P: RtProcessHandler>(
+    rt_process_handler: &mut P,
+    stream_info: &mut StreamInfo,
+    buffers: impl Iterator<Item = &'a mut AudioBusBuffer>,
+    buffer_size: u32,
+) -> bool {
+    if stream_info.max_audio_buffer_size == buffer_size {
+        return false;
+    }
+
+    for buffer in buffers {
+        buffer.reserve_frames(buffer_size as usize);
+    }
+    stream_info.max_audio_buffer_size = buffer_size;
+    rt_process_handler.buffer_size_changed(stream_info);
+    true
+}
+
+/// Call `RtProcessHandler::process_offline` while Jack is in freewheel mode, and
+/// `RtProcessHandler::process` otherwise.
+fn dispatch_process<P: RtProcessHandler>(
+    rt_process_handler: &mut P,
+    freewheel: bool,
+    proc_info: ProcessInfo,
+) -> ProcessControl {
+    if freewheel {
+        rt_process_handler.process_offline(proc_info)
+    } else {
+        rt_process_handler.process(proc_info)
+    }
+}
+
+/// Copy a channel into the buffer of its output port at the given gain. The part of the port
+/// that the channel doesn't cover is silenced, since Jack doesn't clear port buffers between
+/// cycles.
+fn write_output_port(port_slice: &mut [f32], channel: &[f32], gain: f32) {
+    let len = channel.len().min(port_slice.len());
+
+    if gain == 1.0 {
+        port_slice[0..len].copy_from_slice(&channel[0..len]);
+    } else {
+        for (dst, src) in port_slice[0..len].iter_mut().zip(channel[0..len].iter()) {
+            *dst = *src * gain;
+        }
+    }
+    for s in port_slice[len..].iter_mut() {
+        *s = 0.0;
+    }
+}
+
+/// Whether Jack is in freewheel mode, and the hook set with `StreamHandle::on_freewheel()`.
+struct Freewheel {
+    enabled: AtomicBool,
+    hook: Mutex<Option<FreewheelHook>>,
+}
+
+impl Freewheel {
+    fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            hook: Mutex::new(None),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn set_hook(&self, hook: FreewheelHook) {
+        *self.hook.lock().unwrap() = Some(hook);
+    }
+
+    /// Store the new mode and call the hook. Like `Xruns::xrun()`, the hook is skipped if
+    /// it's being replaced at the same time.
+    fn changed(&self, is_enabled: bool) {
+        self.enabled.store(is_enabled, Ordering::Relaxed);
+
+        if let Ok(mut hook) = self.hook.try_lock() {
+            if let Some(hook) = hook.as_mut() {
+                hook(is_enabled);
+            }
+        }
     }
 }
 
 struct JackNotificationHandler<E: FatalErrorHandler> {
-    fatal_error_handler: Option<E>,
+    /// Shared with the process handler.
+    fatal_error_handler: Arc<Mutex<Option<E>>>,
+
+    /// Shared with the process handler and the stream handle.
+    freewheel: Arc<Freewheel>,
+
+    /// Shared with the process handler.
+    sample_rate: Arc<AtomicU32>,
+
+    /// Shared with the stream handle.
+    shutdown_hook: Arc<ShutdownHook>,
+
+    /// Shared with the stream handle.
+    xruns: Arc<Xruns>,
 }
 
 impl<E: FatalErrorHandler> jack::NotificationHandler for JackNotificationHandler<E> {
@@ -575,12 +2379,27 @@ impl<E: FatalErrorHandler> jack::NotificationHandler for JackNotificationHandler
 
         info!("{}", msg);
 
-        if let Some(fatal_error_handler) = self.fatal_error_handler.take() {
-            fatal_error_handler.fatal_stream_error(FatalStreamError::AudioServerDisconnected(msg))
+        self.shutdown_hook.run();
+
+        if let Some(fatal_error_handler) = self.fatal_error_handler.lock().unwrap().take() {
+            // A zombified client was kicked out by the server, e.g. for taking too long.
+            let reason = if status.contains(jack::ClientStatus::CLIENT_ZOMBIE) {
+                DisconnectReason::Killed
+            } else {
+                DisconnectReason::ServerQuit
+            };
+
+            fatal_error_handler.fatal_stream_error(FatalStreamError::AudioServerDisconnected {
+                backend: Backend::Jack,
+                reason,
+                message: msg,
+            })
         }
     }
 
     fn freewheel(&mut self, _: &jack::Client, is_enabled: bool) {
+        self.freewheel.changed(is_enabled);
+
         debug!(
             "JACK: freewheel mode is {}",
             if is_enabled { "on" } else { "off" }
@@ -588,7 +2407,8 @@ impl<E: FatalErrorHandler> jack::NotificationHandler for JackNotificationHandler
     }
 
     fn sample_rate(&mut self, _: &jack::Client, srate: jack::Frames) -> jack::Control {
-        debug!("JACK: sample rate changed to {}", srate);
+        info!("JACK: sample rate changed to {}", srate);
+        self.sample_rate.store(srate, Ordering::Relaxed);
         jack::Control::Continue
     }
 
@@ -648,17 +2468,64 @@ impl<E: FatalErrorHandler> jack::NotificationHandler for JackNotificationHandler
 
     fn xrun(&mut self, _: &jack::Client) -> jack::Control {
         warn!("JACK: xrun occurred");
+        self.xruns.xrun();
         jack::Control::Continue
     }
+}
 
-    fn latency(&mut self, _: &jack::Client, mode: jack::LatencyType) {
-        debug!(
-            "JACK: {} latency has changed",
-            match mode {
-                jack::LatencyType::Capture => "capture",
-                jack::LatencyType::Playback => "playback",
-            }
-        );
+/// A client that only listens for ports being registered, for `watch_devices()`.
+pub struct JackDeviceNotifier {
+    _client: jack::AsyncClient<JackDeviceNotificationHandler, ()>,
+    connected: Arc<AtomicBool>,
+}
+
+impl DeviceNotifier for JackDeviceNotifier {
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+}
+
+/// Start listening for ports being registered, or `None` if the Jack server isn't running.
+pub fn device_notifier(wake_tx: mpsc::Sender<()>) -> Option<JackDeviceNotifier> {
+    let (client, _status) = jack::Client::new(
+        "rustydaw_io_device_watcher",
+        jack::ClientOptions::NO_START_SERVER,
+    )
+    .ok()?;
+
+    let connected = Arc::new(AtomicBool::new(true));
+    let client = client
+        .activate_async(
+            JackDeviceNotificationHandler {
+                wake_tx,
+                connected: Arc::clone(&connected),
+            },
+            (),
+        )
+        .ok()?;
+
+    Some(JackDeviceNotifier {
+        _client: client,
+        connected,
+    })
+}
+
+struct JackDeviceNotificationHandler {
+    wake_tx: mpsc::Sender<()>,
+
+    /// Shared with the notifier.
+    connected: Arc<AtomicBool>,
+}
+
+impl jack::NotificationHandler for JackDeviceNotificationHandler {
+    fn shutdown(&mut self, _status: jack::ClientStatus, _reason: &str) {
+        self.connected.store(false, Ordering::Relaxed);
+        let _ = self.wake_tx.send(());
+    }
+
+    // Clients aren't watched, since enumerating the devices registers a client of its own.
+    fn port_registration(&mut self, _: &jack::Client, _port_id: jack::PortId, _is_reg: bool) {
+        let _ = self.wake_tx.send(());
     }
 }
 
@@ -667,3 +2534,528 @@ impl From<jack::Error> for SpawnRtThreadError {
         SpawnRtThreadError::PlatformSpecific(Box::new(e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChannelLayout, MidiPortRole};
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| String::from(*n)).collect()
+    }
+
+    #[test]
+    fn classifies_client_open_errors() {
+        let not_running = jack::Error::ClientError(
+            jack::ClientStatus::FAILURE | jack::ClientStatus::SERVER_FAILED,
+        );
+
+        assert!(matches!(
+            client_open_error(not_running, false),
+            SpawnRtThreadError::ServerNotRunning(server) if server == "Jack"
+        ));
+
+        let not_running = jack::Error::ClientError(
+            jack::ClientStatus::FAILURE | jack::ClientStatus::SERVER_FAILED,
+        );
+        assert!(matches!(
+            client_open_error(not_running, true),
+            SpawnRtThreadError::ServerStartFailed(server) if server == "Jack"
+        ));
+
+        let other = jack::Error::ClientError(
+            jack::ClientStatus::FAILURE | jack::ClientStatus::VERSION_ERROR,
+        );
+        assert!(matches!(
+            client_open_error(other, false),
+            SpawnRtThreadError::PlatformSpecific(_)
+        ));
+
+        assert!(!server_start_attempted(
+            jack::ClientOptions::NO_START_SERVER
+        ));
+
+        let not_running = jack::Error::ClientError(
+            jack::ClientStatus::FAILURE | jack::ClientStatus::SERVER_FAILED,
+        );
+        assert!(matches!(
+            enumeration_error(not_running),
+            EnumerationError::ServerNotRunning(Backend::Jack)
+        ));
+        assert!(matches!(
+            enumeration_error(jack::Error::ClientError(jack::ClientStatus::FAILURE)),
+            EnumerationError::PlatformSpecific(_)
+        ));
+    }
+
+    #[derive(Default)]
+    struct FreewheelHandler {
+        realtime_cycles: usize,
+        offline_cycles: usize,
+    }
+
+    impl RtProcessHandler for FreewheelHandler {
+        fn init(&mut self, _stream_info: &StreamInfo) {}
+
+        fn process(&mut self, _proc_info: ProcessInfo) -> ProcessControl {
+            self.realtime_cycles += 1;
+            ProcessControl::Continue
+        }
+
+        fn process_offline(&mut self, _proc_info: ProcessInfo) -> ProcessControl {
+            self.offline_cycles += 1;
+            ProcessControl::Continue
+        }
+    }
+
+    #[test]
+    fn freewheel_reports_changes() {
+        let freewheel = Freewheel::new();
+        freewheel.changed(true);
+        assert!(freewheel.is_enabled());
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = Arc::clone(&seen);
+        freewheel.set_hook(Box::new(move |enabled| {
+            hook_seen.lock().unwrap().push(enabled)
+        }));
+
+        freewheel.changed(false);
+        freewheel.changed(true);
+
+        assert!(freewheel.is_enabled());
+        assert_eq!(*seen.lock().unwrap(), vec![false, true]);
+    }
+
+    #[test]
+    fn freewheel_calls_process_offline() {
+        let mut handler = FreewheelHandler::default();
+        let freewheel = AtomicBool::new(false);
+
+        let cycle = |handler: &mut FreewheelHandler| {
+            dispatch_process(
+                handler,
+                freewheel.load(Ordering::Relaxed),
+                ProcessInfo {
+                    audio_in: &[],
+                    audio_out: &mut [],
+                    audio_frames: 0,
+                    midi_in: &[],
+                    midi_out: &mut [],
+                    sample_rate: 48000,
+                    transport: None,
+                    cycle_start_frame: 0,
+                    cycle_start_time: Instant::now(),
+                },
+            )
+        };
+
+        cycle(&mut handler);
+        assert_eq!((handler.realtime_cycles, handler.offline_cycles), (1, 0));
+
+        freewheel.store(true, Ordering::Relaxed);
+        cycle(&mut handler);
+        cycle(&mut handler);
+        assert_eq!((handler.realtime_cycles, handler.offline_cycles), (1, 2));
+
+        freewheel.store(false, Ordering::Relaxed);
+        cycle(&mut handler);
+        assert_eq!((handler.realtime_cycles, handler.offline_cycles), (2, 2));
+    }
+
+    #[derive(Default)]
+    struct SampleRateHandler {
+        sample_rates: Vec<u32>,
+    }
+
+    impl RtProcessHandler for SampleRateHandler {
+        fn init(&mut self, _stream_info: &StreamInfo) {}
+
+        fn process(&mut self, _proc_info: ProcessInfo) -> ProcessControl {
+            ProcessControl::Continue
+        }
+
+        fn sample_rate_changed(&mut self, stream_info: &StreamInfo) {
+            self.sample_rates.push(stream_info.sample_rate);
+        }
+    }
+
+    #[test]
+    fn sample_rate_change_calls_handler_once() {
+        let mut handler = SampleRateHandler::default();
+        let mut stream_info = StreamInfo {
+            server_name: String::from("Jack"),
+            audio_in: Vec::new(),
+            audio_out: Vec::new(),
+            midi_in: Vec::new(),
+            midi_out: Vec::new(),
+            sample_rate: 48000,
+            max_audio_buffer_size: 256,
+            latency_frames_in: 0,
+            latency_frames_out: 0,
+            sample_format: SampleFormat::F32,
+        };
+
+        update_sample_rate(&mut handler, &mut stream_info, 48000);
+        assert!(handler.sample_rates.is_empty());
+
+        update_sample_rate(&mut handler, &mut stream_info, 44100);
+        update_sample_rate(&mut handler, &mut stream_info, 44100);
+        assert_eq!(stream_info.sample_rate, 44100);
+        assert_eq!(handler.sample_rates, vec![44100]);
+    }
+
+    #[derive(Default)]
+    struct BufferSizeHandler {
+        buffer_sizes: Vec<u32>,
+    }
+
+    impl RtProcessHandler for BufferSizeHandler {
+        fn init(&mut self, _stream_info: &StreamInfo) {}
+
+        fn process(&mut self, _proc_info: ProcessInfo) -> ProcessControl {
+            ProcessControl::Continue
+        }
+
+        fn buffer_size_changed(&mut self, stream_info: &StreamInfo) {
+            self.buffer_sizes.push(stream_info.max_audio_buffer_size);
+        }
+    }
+
+    #[test]
+    fn buffer_size_change_grows_buffers() {
+        let mut handler = BufferSizeHandler::default();
+        let mut stream_info = StreamInfo {
+            server_name: String::from("Jack"),
+            audio_in: Vec::new(),
+            audio_out: Vec::new(),
+            midi_in: Vec::new(),
+            midi_out: Vec::new(),
+            sample_rate: 48000,
+            max_audio_buffer_size: 256,
+            latency_frames_in: 0,
+            latency_frames_out: 0,
+            sample_format: SampleFormat::F32,
+        };
+        let mut buffers = [AudioBusBuffer::new(2, 256)];
+
+        assert!(!update_buffer_size(
+            &mut handler,
+            &mut stream_info,
+            buffers.iter_mut(),
+            256
+        ));
+        assert!(handler.buffer_sizes.is_empty());
+
+        assert!(update_buffer_size(
+            &mut handler,
+            &mut stream_info,
+            buffers.iter_mut(),
+            1024
+        ));
+        assert_eq!(stream_info.max_audio_buffer_size, 1024);
+        assert_eq!(handler.buffer_sizes, vec![1024]);
+        for channel in buffers[0].channel_buffers.iter() {
+            assert!(channel.capacity() >= 1024);
+        }
+
+        // Shrinking keeps the larger buffers.
+        assert!(update_buffer_size(
+            &mut handler,
+            &mut stream_info,
+            buffers.iter_mut(),
+            128
+        ));
+        assert_eq!(handler.buffer_sizes, vec![1024, 128]);
+        assert!(buffers[0].channel_buffers[0].capacity() >= 1024);
+    }
+
+    #[test]
+    fn refuses_new_ports_until_a_cycle_has_picked_them_up() {
+        let stream_info = StreamInfo {
+            server_name: String::from("Jack"),
+            audio_in: Vec::new(),
+            audio_out: Vec::new(),
+            midi_in: Vec::new(),
+            midi_out: Vec::new(),
+            sample_rate: 48000,
+            max_audio_buffer_size: 256,
+            latency_frames_in: 0,
+            latency_frames_out: 0,
+            sample_format: SampleFormat::F32,
+        };
+        let mut process = JackProcessHandler::new(
+            BufferSizeHandler::default(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            stream_info.clone(),
+            cycle_debugger().0,
+        );
+        let (mut sender, receiver) = new_ports_queue();
+        process.new_ports = receiver;
+
+        let update = |controllers: usize| PortsUpdate {
+            ports: NewPorts::MidiOut(
+                Growth::new(Vec::new(), controllers),
+                Growth::new(vec![MidiControllerBuffer::new()], controllers),
+            ),
+            stream_info: stream_info.clone(),
+        };
+        for controllers in 0..NEW_PORTS_QUEUE_SIZE {
+            sender.reserve().unwrap();
+            sender.send(update(controllers));
+        }
+        assert!(matches!(sender.reserve(), Err(AddBusError::QueueFull)));
+
+        assert!(process.apply_new_ports());
+        assert_eq!(process.midi_out_buffers.len(), NEW_PORTS_QUEUE_SIZE);
+
+        // The next cycle has made room again.
+        sender.reserve().unwrap();
+        sender.send(update(NEW_PORTS_QUEUE_SIZE));
+        assert!(process.apply_new_ports());
+        assert_eq!(process.midi_out_buffers.len(), NEW_PORTS_QUEUE_SIZE + 1);
+    }
+
+    #[test]
+    fn busses_added_after_a_buffer_size_change_keep_it() {
+        let stream_info = StreamInfo {
+            server_name: String::from("Jack"),
+            audio_in: Vec::new(),
+            audio_out: Vec::new(),
+            midi_in: Vec::new(),
+            midi_out: Vec::new(),
+            sample_rate: 48000,
+            max_audio_buffer_size: 256,
+            latency_frames_in: 0,
+            latency_frames_out: 0,
+            sample_format: SampleFormat::F32,
+        };
+        let mut process = JackProcessHandler::new(
+            BufferSizeHandler::default(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            stream_info.clone(),
+            cycle_debugger().0,
+        );
+        let (mut sender, receiver) = new_ports_queue();
+        process.new_ports = receiver;
+
+        assert!(update_buffer_size(
+            &mut process.rt_process_handler,
+            &mut process.stream_info,
+            process.audio_out_buffers.iter_mut(),
+            1024
+        ));
+
+        // The stream handle still has the buffer size the stream was spawned with.
+        let mut next_stream_info = stream_info;
+        next_stream_info.audio_out.push(AudioBus {
+            id_name: String::from("Speakers Out"),
+            id_index: DeviceIndex::new(0),
+            system_device: String::from("Jack Device"),
+            system_half_duplex_device: None,
+            system_ports: Vec::new(),
+            client_ports: Vec::new(),
+            channels: 2,
+            channel_layout: ChannelLayout::Stereo,
+            connected_ports: 0,
+        });
+        next_stream_info.latency_frames_out = 2048;
+        let update = PortsUpdate {
+            ports: NewPorts::AudioOut(
+                Growth::new(Vec::new(), 0),
+                Growth::new(vec![1.0, 1.0], 0),
+                Growth::new(vec![AudioBusBuffer::new(2, 1024)], 0),
+            ),
+            stream_info: next_stream_info,
+        };
+        assert!(sender.updates.push(update).is_ok());
+
+        assert!(process.apply_new_ports());
+        assert_eq!(process.stream_info.max_audio_buffer_size, 1024);
+        assert_eq!(process.stream_info.sample_rate, 48000);
+        assert_eq!(process.stream_info.latency_frames_out, 2048);
+        assert_eq!(process.stream_info.audio_out.len(), 1);
+        assert_eq!(process.audio_out_buffers.len(), 1);
+    }
+
+    #[test]
+    fn stop_watcher_runs_the_shutdown_hook_off_the_realtime_thread() {
+        let shutdown_hook = Arc::new(ShutdownHook::new());
+        let (_stop_watcher, mut rt_stop) =
+            spawn_stop_watcher::<crate::CollectingFatalErrorHandler>(
+                Arc::clone(&shutdown_hook),
+                Arc::new(Mutex::new(None)),
+            )
+            .unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        shutdown_hook.set_stream_info(StreamInfo {
+            server_name: String::from("Jack"),
+            audio_in: Vec::new(),
+            audio_out: Vec::new(),
+            midi_in: Vec::new(),
+            midi_out: Vec::new(),
+            sample_rate: 48000,
+            max_audio_buffer_size: 256,
+            latency_frames_in: 0,
+            latency_frames_out: 0,
+            sample_format: SampleFormat::F32,
+        });
+        shutdown_hook.set(Box::new(move |_| {
+            let _ = sender.send(std::thread::current().name().map(String::from));
+        }));
+
+        rt_stop.stop(None);
+
+        let thread = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(thread.as_deref(), Some("rusty-daw-io Jack stop watcher"));
+    }
+
+    #[test]
+    fn stop_watcher_reports_errors_off_the_realtime_thread() {
+        let fatal_error_handler = crate::CollectingFatalErrorHandler::new();
+        let (_stop_watcher, mut rt_stop) = spawn_stop_watcher(
+            Arc::new(ShutdownHook::new()),
+            Arc::new(Mutex::new(Some(fatal_error_handler.clone()))),
+        )
+        .unwrap();
+
+        rt_stop.stop(Some(FatalStreamError::ProcessPanicked(String::from(
+            "oops",
+        ))));
+
+        assert!(fatal_error_handler.wait_for_error(Duration::from_secs(10)));
+        assert!(matches!(
+            fatal_error_handler.take_errors().as_slice(),
+            [FatalStreamError::ProcessPanicked(msg)] if msg == "oops"
+        ));
+    }
+
+    #[test]
+    fn frame_clock_survives_wrapping() {
+        let mut clock = FrameClock::default();
+
+        assert_eq!(clock.extend(1024), 1024);
+        assert_eq!(clock.extend(u32::MAX - 255), u64::from(u32::MAX - 255));
+        assert_eq!(clock.extend(256), (1 << 32) + 256);
+        assert_eq!(clock.extend(512), (1 << 32) + 512);
+        assert_eq!(clock.extend(100), (2 << 32) + 100);
+    }
+
+    #[test]
+    fn server_names_with_nul_are_rejected() {
+        let result = open_client("rusty-daw-io", jack::ClientOptions::empty(), Some("a\0b"));
+        assert!(matches!(
+            result,
+            Err(jack::Error::ClientError(status)) if status == jack::ClientStatus::INVALID_OPTION
+        ));
+    }
+
+    #[test]
+    fn self_connect_policy() {
+        let in_ports = names(&["rusty-daw-io:Mic In_1"]);
+        let in_system_ports = names(&["system:capture_1"]);
+        let out_ports = names(&["rusty-daw-io:Out_1", "rusty-daw-io:Out_2"]);
+        let out_system_ports = names(&["system:playback_1", "system:playback_2"]);
+
+        assert_eq!(
+            self_connections(
+                JackSelfConnect::SystemPorts,
+                &in_ports,
+                &in_system_ports,
+                &out_ports,
+                &out_system_ports,
+            ),
+            vec![
+                ("system:capture_1", "rusty-daw-io:Mic In_1"),
+                ("rusty-daw-io:Out_1", "system:playback_1"),
+                ("rusty-daw-io:Out_2", "system:playback_2"),
+            ]
+        );
+
+        assert!(self_connections(
+            JackSelfConnect::Never,
+            &in_ports,
+            &in_system_ports,
+            &out_ports,
+            &out_system_ports,
+        )
+        .is_empty());
+
+        assert_eq!(
+            Config::default().jack_self_connect,
+            JackSelfConnect::SystemPorts
+        );
+    }
+
+    #[test]
+    fn midi_controller_connections() {
+        let mut controller = MidiControllerConfig {
+            id: String::from("Out"),
+            system_port: String::new(),
+            system_port_role: Some(MidiPortRole::MidiThrough),
+            connections: names(&["synth:midi_in", "monitor:midi_in"]),
+            ..Default::default()
+        };
+        let midi_controller = MidiController {
+            id_name: String::from("Out"),
+            id_index: DeviceIndex::new(0),
+            system_port: String::from("system:midi_playback_1"),
+            client_port: Some(String::from("rusty-daw-io:Out")),
+        };
+
+        assert_eq!(
+            midi_connections(&controller, &midi_controller),
+            names(&["system:midi_playback_1", "synth:midi_in", "monitor:midi_in"])
+        );
+
+        controller.auto_connect = false;
+        assert!(midi_connections(&controller, &midi_controller).is_empty());
+    }
+
+    #[test]
+    fn write_output_port_silences_the_rest() {
+        let mut port_slice = [0.5; 4];
+        write_output_port(&mut port_slice, &[1.0, 2.0, 3.0, 4.0], 1.0);
+        assert_eq!(port_slice, [1.0, 2.0, 3.0, 4.0]);
+
+        // A buffer that was shrunk by the user doesn't leave the last cycle behind.
+        write_output_port(&mut port_slice, &[5.0, 6.0], 1.0);
+        assert_eq!(port_slice, [5.0, 6.0, 0.0, 0.0]);
+
+        write_output_port(&mut port_slice, &[1.0; 8], 1.0);
+        assert_eq!(port_slice, [1.0; 4]);
+
+        // A channel that's summed with others into a system port.
+        write_output_port(&mut port_slice, &[1.0, 2.0, 3.0, 4.0], 0.5);
+        assert_eq!(port_slice, [0.5, 1.0, 1.5, 2.0]);
+    }
+
+    #[test]
+    fn bbt_from_jack_position() {
+        let mut bbt = jack::TransportBBT::default();
+        bbt.with_bbt(3, 2, 480)
+            .with_timesig(6.0, 8.0)
+            .with_ticks_per_beat(1920.0)
+            .with_bpm(132.5);
+
+        assert_eq!(
+            bbt_from_jack(bbt.validated().unwrap()),
+            TransportBbt {
+                bar: 3,
+                beat: 2,
+                tick: 480,
+                beats_per_bar: 6.0,
+                beat_type: 8.0,
+                ticks_per_beat: 1920.0,
+                beats_per_minute: 132.5,
+            }
+        );
+    }
+}
@@ -1,13 +1,40 @@
+#[cfg(any(feature = "alsa-backend", feature = "pulse"))]
+use super::AudioDeviceInfo;
 use super::{
-    AudioServerInfo, Config, FatalErrorHandler, MidiServerInfo, OsDevicesInfo, OsStreamHandle,
-    RtProcessHandler, SpawnRtThreadError, StreamInfo,
+    AddBusError, AudioBus, AudioBusConfig, AudioServerInfo, Backend, Config, EnumerationError,
+    FatalErrorHandler, FreewheelError, FreewheelHook, LatencyRange, MidiController,
+    MidiControllerConfig, MidiServerInfo, OsDevicesInfo, OsStreamHandle, PortConnections,
+    RtProcessHandler, SpawnError, SpawnRtThreadError, StreamDiagnostics, StreamInfo,
 };
 
+#[cfg(feature = "alsa-backend")]
+mod alsa_backend;
 mod jack_backend;
+#[cfg(feature = "pulse")]
+mod pulse_backend;
+
+#[cfg(feature = "alsa-backend")]
+pub(crate) use alsa_backend::probe_max_channels as probe_alsa_max_channels;
+
+use crate::channel_gain::ChannelGainsHandle;
+use crate::device_watch::DeviceNotifier;
+use crate::master_gain::MasterGainHandle;
+use crate::shutdown_hook::ShutdownHook;
+use crate::silence_detect::SilenceDetectHandle;
+use crate::xruns::Xruns;
 
 pub struct LinuxStreamHandle<P: RtProcessHandler, E: FatalErrorHandler> {
     stream_info: StreamInfo,
-    _jack_server_handle: Option<jack_backend::JackRtThreadHandle<P, E>>,
+    backend_handle: BackendHandle<P, E>,
+}
+
+/// The handle of the stream on the backend it was spawned on.
+enum BackendHandle<P: RtProcessHandler, E: FatalErrorHandler> {
+    Jack(jack_backend::JackRtThreadHandle<P, E>),
+    #[cfg(feature = "alsa-backend")]
+    Alsa(alsa_backend::AlsaRtThreadHandle<P>),
+    #[cfg(feature = "pulse")]
+    Pulse(pulse_backend::PulseRtThreadHandle<P>),
 }
 
 impl<P: RtProcessHandler, E: FatalErrorHandler> OsStreamHandle for LinuxStreamHandle<P, E> {
@@ -17,41 +44,367 @@ impl<P: RtProcessHandler, E: FatalErrorHandler> OsStreamHandle for LinuxStreamHa
     fn stream_info(&self) -> &StreamInfo {
         &self.stream_info
     }
+
+    fn frames_processed(&self) -> u64 {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => h.frames_processed(),
+            #[cfg(feature = "alsa-backend")]
+            BackendHandle::Alsa(h) => h.frames_processed(),
+            #[cfg(feature = "pulse")]
+            BackendHandle::Pulse(h) => h.frames_processed(),
+        }
+    }
+
+    fn debug_next_cycles(&self, n: u32) {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => h.debug_next_cycles(n),
+            #[cfg(feature = "alsa-backend")]
+            BackendHandle::Alsa(h) => h.debug_next_cycles(n),
+            #[cfg(feature = "pulse")]
+            BackendHandle::Pulse(h) => h.debug_next_cycles(n),
+        }
+    }
+
+    fn diagnostics(&self) -> StreamDiagnostics {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => h.diagnostics(),
+            #[cfg(feature = "alsa-backend")]
+            BackendHandle::Alsa(h) => h.diagnostics(),
+            #[cfg(feature = "pulse")]
+            BackendHandle::Pulse(h) => h.diagnostics(),
+        }
+    }
+
+    fn recent_buffer_sizes(&self) -> Vec<u32> {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => h.recent_buffer_sizes(),
+            #[cfg(feature = "alsa-backend")]
+            BackendHandle::Alsa(h) => h.recent_buffer_sizes(),
+            #[cfg(feature = "pulse")]
+            BackendHandle::Pulse(h) => h.recent_buffer_sizes(),
+        }
+    }
+
+    fn midi_in_dropped_events(&self, controller_id: &str) -> Option<u64> {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => h.midi_in_dropped_events(controller_id),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    fn set_master_gain(&self, gain: f32) {
+        self.master_gain_handle().set_master_gain(gain);
+    }
+
+    fn set_master_gain_ramp_time(&self, ramp_time: std::time::Duration) {
+        self.master_gain_handle()
+            .set_master_gain_ramp_time(ramp_time);
+    }
+
+    fn master_gain(&self) -> f32 {
+        self.master_gain_handle().master_gain()
+    }
+
+    fn channel_gains_handle(&self) -> Option<&ChannelGainsHandle> {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => Some(&h.channel_gains_handle),
+            #[cfg(feature = "alsa-backend")]
+            BackendHandle::Alsa(h) => Some(&h.channel_gains_handle),
+            #[cfg(feature = "pulse")]
+            BackendHandle::Pulse(h) => Some(&h.channel_gains_handle),
+        }
+    }
+
+    fn silence_detect_handle(&self) -> Option<&SilenceDetectHandle> {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => Some(&h.silence_detect_handle),
+            #[cfg(feature = "alsa-backend")]
+            BackendHandle::Alsa(h) => Some(&h.silence_detect_handle),
+            #[cfg(feature = "pulse")]
+            BackendHandle::Pulse(h) => Some(&h.silence_detect_handle),
+        }
+    }
+
+    fn shutdown_hook(&self) -> &ShutdownHook {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => &h.shutdown_hook,
+            #[cfg(feature = "alsa-backend")]
+            BackendHandle::Alsa(h) => &h.shutdown_hook,
+            #[cfg(feature = "pulse")]
+            BackendHandle::Pulse(h) => &h.shutdown_hook,
+        }
+    }
+
+    fn bus_latency(&self, bus_id: &str) -> Option<LatencyRange> {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => h.bus_latency(bus_id),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    fn port_connections(&self, bus_id: &str) -> Option<Vec<PortConnections>> {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => h.port_connections(bus_id),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    fn set_freewheel(&self, enabled: bool) -> Result<(), FreewheelError> {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => h.set_freewheel(enabled),
+            #[allow(unreachable_patterns)]
+            _ => Err(FreewheelError::NotSupported(self.backend())),
+        }
+    }
+
+    fn is_freewheeling(&self) -> bool {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => h.is_freewheeling(),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    fn on_freewheel(&self, hook: FreewheelHook) {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => h.on_freewheel(hook),
+            // Only Jack has a freewheel mode, so the hook would never run.
+            #[allow(unreachable_patterns)]
+            _ => drop(hook),
+        }
+    }
+
+    fn add_audio_bus(
+        &mut self,
+        bus: &AudioBusConfig,
+        is_input: bool,
+    ) -> Result<AudioBus, AddBusError> {
+        let backend = self.backend();
+        match &mut self.backend_handle {
+            BackendHandle::Jack(h) => h.add_audio_bus(&mut self.stream_info, bus, is_input),
+            #[allow(unreachable_patterns)]
+            _ => Err(AddBusError::NotSupported(backend)),
+        }
+    }
+
+    fn add_midi_controller(
+        &mut self,
+        controller: &MidiControllerConfig,
+        is_input: bool,
+    ) -> Result<MidiController, AddBusError> {
+        let backend = self.backend();
+        match &mut self.backend_handle {
+            BackendHandle::Jack(h) => {
+                h.add_midi_controller(&mut self.stream_info, controller, is_input)
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(AddBusError::NotSupported(backend)),
+        }
+    }
+
+    fn xruns(&self) -> &Xruns {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => &h.xruns,
+            #[cfg(feature = "alsa-backend")]
+            BackendHandle::Alsa(h) => &h.xruns,
+            #[cfg(feature = "pulse")]
+            BackendHandle::Pulse(h) => &h.xruns,
+        }
+    }
+
+    fn stop(&mut self) -> Option<P> {
+        match &mut self.backend_handle {
+            BackendHandle::Jack(h) => h.stop(),
+            #[cfg(feature = "alsa-backend")]
+            BackendHandle::Alsa(h) => h.stop(),
+            #[cfg(feature = "pulse")]
+            BackendHandle::Pulse(h) => h.stop(),
+        }
+    }
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> LinuxStreamHandle<P, E> {
+    /// The backend the stream was spawned on.
+    fn backend(&self) -> Backend {
+        match &self.backend_handle {
+            BackendHandle::Jack(_) => Backend::Jack,
+            #[cfg(feature = "alsa-backend")]
+            BackendHandle::Alsa(_) => Backend::Alsa,
+            #[cfg(feature = "pulse")]
+            BackendHandle::Pulse(_) => Backend::PulseAudio,
+        }
+    }
+
+    fn master_gain_handle(&self) -> &MasterGainHandle {
+        match &self.backend_handle {
+            BackendHandle::Jack(h) => &h.master_gain_handle,
+            #[cfg(feature = "alsa-backend")]
+            BackendHandle::Alsa(h) => &h.master_gain_handle,
+            #[cfg(feature = "pulse")]
+            BackendHandle::Pulse(h) => &h.master_gain_handle,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct LinuxDevicesInfo {
-    audio_servers_info: [AudioServerInfo; 1],
-    midi_servers_info: [MidiServerInfo; 1],
+    audio_servers_info: Vec<AudioServerInfo>,
+    midi_servers_info: Vec<MidiServerInfo>,
 }
 
 impl Default for LinuxDevicesInfo {
     fn default() -> Self {
+        #[allow(unused_mut)]
         let mut new_self = Self {
-            audio_servers_info: [
-                AudioServerInfo::new(String::from("Jack"), None), // TODO: Get Jack version?
+            audio_servers_info: vec![
+                AudioServerInfo::new(String::from(Backend::Jack.as_str()), None), // TODO: Get Jack version?
             ],
-            midi_servers_info: [
-                MidiServerInfo::new(String::from("Jack"), None), // TODO: Get Jack version?
+            midi_servers_info: vec![
+                MidiServerInfo::new(String::from(Backend::Jack.as_str()), None), // TODO: Get Jack version?
             ],
         };
 
-        new_self.refresh_audio_servers();
-        new_self.refresh_midi_servers();
+        // Second server is ALSA (if enabled).
+        #[cfg(feature = "alsa-backend")]
+        {
+            new_self.audio_servers_info.push(AudioServerInfo::new(
+                String::from(Backend::Alsa.as_str()),
+                None,
+            ));
+            new_self.midi_servers_info.push(MidiServerInfo::new(
+                String::from(Backend::Alsa.as_str()),
+                None,
+            ));
+        }
+
+        // Then PulseAudio (if enabled), which has no MIDI.
+        #[cfg(feature = "pulse")]
+        new_self.audio_servers_info.push(AudioServerInfo::new(
+            String::from(Backend::PulseAudio.as_str()),
+            None,
+        ));
+
+        // The last server is CPAL (if enabled), as a fallback to the native ones.
+        #[cfg(feature = "cpal")]
+        new_self.audio_servers_info.push(AudioServerInfo::new(
+            String::from(Backend::Cpal.as_str()),
+            None,
+        ));
+
+        // Each server is marked as unavailable if it fails.
+        let _ = new_self.refresh_servers();
 
         new_self
     }
 }
 
 impl OsDevicesInfo for LinuxDevicesInfo {
-    fn refresh_audio_servers(&mut self) {
+    fn refresh_audio_servers(&mut self) -> Result<(), EnumerationError> {
+        // First server is Jack
+        let result = jack_backend::refresh_audio_server(&mut self.audio_servers_info[0]);
+
+        // Second server is ALSA
+        #[cfg(feature = "alsa-backend")]
+        let result = result.and(alsa_backend::refresh_audio_server(
+            &mut self.audio_servers_info[1],
+        ));
+
+        #[cfg(feature = "pulse")]
+        let result = result.and(pulse_backend::refresh_audio_server(
+            self.audio_server_mut(Backend::PulseAudio),
+        ));
+
+        // Last server is CPAL
+        #[cfg(feature = "cpal")]
+        let result = result.and(crate::cpal_backend::refresh_audio_server(
+            self.audio_servers_info.last_mut().unwrap(),
+        ));
+
+        result
+    }
+
+    fn refresh_audio_servers_timeout(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> Result<(), EnumerationError> {
         // First server is Jack
-        jack_backend::refresh_audio_server(&mut self.audio_servers_info[0]);
+        let result =
+            jack_backend::refresh_audio_server_timeout(&mut self.audio_servers_info[0], timeout);
+
+        // Second server is ALSA
+        #[cfg(feature = "alsa-backend")]
+        let result = result.and(crate::enumeration::refresh_with_timeout(
+            &mut self.audio_servers_info[1],
+            Backend::Alsa,
+            timeout,
+            alsa_backend::refresh_audio_server,
+        ));
+
+        #[cfg(feature = "pulse")]
+        let result = result.and(crate::enumeration::refresh_with_timeout(
+            self.audio_server_mut(Backend::PulseAudio),
+            Backend::PulseAudio,
+            timeout,
+            pulse_backend::refresh_audio_server,
+        ));
+
+        // Last server is CPAL
+        #[cfg(feature = "cpal")]
+        let result = result.and(crate::enumeration::refresh_with_timeout(
+            self.audio_servers_info.last_mut().unwrap(),
+            Backend::Cpal,
+            timeout,
+            crate::cpal_backend::refresh_audio_server,
+        ));
+
+        result
     }
 
-    fn refresh_midi_servers(&mut self) {
+    fn refresh_midi_servers(&mut self) -> Result<(), EnumerationError> {
         // First server is Jack
-        jack_backend::refresh_midi_server(&mut self.midi_servers_info[0]);
+        let result = jack_backend::refresh_midi_server(&mut self.midi_servers_info[0]);
+
+        // Second server is ALSA
+        #[cfg(feature = "alsa-backend")]
+        let result = result.and(alsa_backend::refresh_midi_server(
+            &mut self.midi_servers_info[1],
+        ));
+
+        result
+    }
+
+    fn refresh_servers(&mut self) -> Result<(), EnumerationError> {
+        // First servers are Jack, which share a single client.
+        let result = jack_backend::refresh_servers(
+            &mut self.audio_servers_info[0],
+            &mut self.midi_servers_info[0],
+        );
+
+        // Second servers are ALSA
+        #[cfg(feature = "alsa-backend")]
+        let result = result
+            .and(alsa_backend::refresh_audio_server(
+                &mut self.audio_servers_info[1],
+            ))
+            .and(alsa_backend::refresh_midi_server(
+                &mut self.midi_servers_info[1],
+            ));
+
+        #[cfg(feature = "pulse")]
+        let result = result.and(pulse_backend::refresh_audio_server(
+            self.audio_server_mut(Backend::PulseAudio),
+        ));
+
+        // Last audio server is CPAL
+        #[cfg(feature = "cpal")]
+        let result = result.and(crate::cpal_backend::refresh_audio_server(
+            self.audio_servers_info.last_mut().unwrap(),
+        ));
+
+        result
     }
 
     fn audio_servers_info(&self) -> &[AudioServerInfo] {
@@ -71,8 +424,8 @@ impl OsDevicesInfo for LinuxDevicesInfo {
     }
 
     fn estimated_latency(&self, config: &Config) -> Option<u32> {
-        match config.audio_server.as_str() {
-            "Jack" => {
+        match config.backend() {
+            Some(Backend::Jack) => {
                 // First server is Jack.
                 // Jack only ever uses one device.
                 // Buffer size in Jack is always constant.
@@ -80,6 +433,26 @@ impl OsDevicesInfo for LinuxDevicesInfo {
                     return Some(device.default_buffer_size);
                 }
             }
+            #[cfg(feature = "alsa-backend")]
+            Some(Backend::Alsa) => {
+                // Second server is ALSA.
+                if let Some(device) = self.device(Backend::Alsa, config) {
+                    return Some(config.buffer_size.unwrap_or(device.default_buffer_size));
+                }
+            }
+            #[cfg(feature = "pulse")]
+            Some(Backend::PulseAudio) => {
+                if let Some(device) = self.device(Backend::PulseAudio, config) {
+                    return Some(pulse_backend::estimated_latency(device, config));
+                }
+            }
+            #[cfg(feature = "cpal")]
+            Some(Backend::Cpal) => {
+                // Last server is CPAL.
+                if let Some(server) = self.audio_servers_info.last() {
+                    return crate::cpal_backend::estimated_latency(server, config);
+                }
+            }
             _ => {}
         }
 
@@ -87,8 +460,8 @@ impl OsDevicesInfo for LinuxDevicesInfo {
     }
 
     fn sample_rate(&self, config: &Config) -> Option<u32> {
-        match config.audio_server.as_str() {
-            "Jack" => {
+        match config.backend() {
+            Some(Backend::Jack) => {
                 // First server is Jack.
                 // Jack only ever uses one device.
                 // Only one sample rate is available, which is the sample rate of the running Jack server.
@@ -96,6 +469,34 @@ impl OsDevicesInfo for LinuxDevicesInfo {
                     return Some(device.sample_rates[0]);
                 }
             }
+            #[cfg(feature = "alsa-backend")]
+            Some(Backend::Alsa) => {
+                // Second server is ALSA.
+                if let Some(device) = self.device(Backend::Alsa, config) {
+                    return Some(
+                        config
+                            .sample_rate
+                            .unwrap_or(device.sample_rates[device.default_sample_rate_index]),
+                    );
+                }
+            }
+            #[cfg(feature = "pulse")]
+            Some(Backend::PulseAudio) => {
+                if let Some(device) = self.device(Backend::PulseAudio, config) {
+                    return Some(
+                        config
+                            .sample_rate
+                            .unwrap_or(device.sample_rates[device.default_sample_rate_index]),
+                    );
+                }
+            }
+            #[cfg(feature = "cpal")]
+            Some(Backend::Cpal) => {
+                // Last server is CPAL.
+                if let Some(server) = self.audio_servers_info.last() {
+                    return crate::cpal_backend::sample_rate(server, config);
+                }
+            }
             _ => {}
         }
 
@@ -103,14 +504,131 @@ impl OsDevicesInfo for LinuxDevicesInfo {
     }
 }
 
+impl LinuxDevicesInfo {
+    /// The server of a backend in `audio_servers_info`.
+    #[cfg(any(feature = "alsa-backend", feature = "pulse"))]
+    fn audio_server(&self, backend: Backend) -> &AudioServerInfo {
+        self.audio_servers_info
+            .iter()
+            .find(|s| s.name == backend.as_str())
+            .expect("every compiled backend has a server")
+    }
+
+    #[cfg(feature = "pulse")]
+    fn audio_server_mut(&mut self, backend: Backend) -> &mut AudioServerInfo {
+        self.audio_servers_info
+            .iter_mut()
+            .find(|s| s.name == backend.as_str())
+            .expect("every compiled backend has a server")
+    }
+
+    #[cfg(any(feature = "alsa-backend", feature = "pulse"))]
+    fn device(&self, backend: Backend, config: &Config) -> Option<&AudioDeviceInfo> {
+        self.audio_server(backend)
+            .devices
+            .iter()
+            .find(|d| d.name == config.system_audio_device)
+    }
+}
+
+/// Refresh the devices of a single audio server, or return `None` if the backend isn't
+/// compiled in.
+pub fn refresh_audio_server(
+    backend: Backend,
+    server: &mut AudioServerInfo,
+) -> Option<Result<(), EnumerationError>> {
+    match backend {
+        Backend::Jack => Some(jack_backend::refresh_audio_server(server)),
+        #[cfg(feature = "alsa-backend")]
+        Backend::Alsa => Some(alsa_backend::refresh_audio_server(server)),
+        #[cfg(feature = "pulse")]
+        Backend::PulseAudio => Some(pulse_backend::refresh_audio_server(server)),
+        _ => None,
+    }
+}
+
+/// Enumerate the devices of a single midi server, or `None` if the backend isn't compiled in.
+pub fn enumerate_midi_server(backend: Backend) -> Option<MidiServerInfo> {
+    let mut server = MidiServerInfo::new(String::from(backend.as_str()), None);
+
+    match backend {
+        // The server is marked as unavailable if it fails.
+        Backend::Jack => {
+            let _ = jack_backend::refresh_midi_server(&mut server);
+        }
+        #[cfg(feature = "alsa-backend")]
+        Backend::Alsa => {
+            let _ = alsa_backend::refresh_midi_server(&mut server);
+        }
+        // PulseAudio has no MIDI, so its server never has any devices.
+        #[cfg(feature = "pulse")]
+        Backend::PulseAudio => {}
+        _ => return None,
+    }
+
+    Some(server)
+}
+
+/// Enumerate the audio and midi devices of a single server, or `None` if the backend isn't
+/// compiled in. Backends that have both only connect to their server once.
+pub fn enumerate_servers(backend: Backend) -> Option<(AudioServerInfo, MidiServerInfo)> {
+    let mut audio_server = AudioServerInfo::new(String::from(backend.as_str()), None);
+    let mut midi_server = MidiServerInfo::new(String::from(backend.as_str()), None);
+
+    match backend {
+        // The servers are marked as unavailable if it fails.
+        Backend::Jack => {
+            let _ = jack_backend::refresh_servers(&mut audio_server, &mut midi_server);
+        }
+        #[cfg(feature = "alsa-backend")]
+        Backend::Alsa => {
+            let _ = alsa_backend::refresh_audio_server(&mut audio_server);
+            let _ = alsa_backend::refresh_midi_server(&mut midi_server);
+        }
+        _ => return None,
+    }
+
+    Some((audio_server, midi_server))
+}
+
+/// Enumerate the devices of the Jack server with the given name.
+pub fn enumerate_named_jack_audio_server(server_name: &str) -> AudioServerInfo {
+    let mut server = AudioServerInfo::new(String::from(Backend::Jack.as_str()), None);
+    // The server is marked as unavailable if it fails.
+    let _ = jack_backend::refresh_named_audio_server(&mut server, server_name);
+    server
+}
+
+/// Enumerate the midi devices of the Jack server with the given name.
+pub fn enumerate_named_jack_midi_server(server_name: &str) -> MidiServerInfo {
+    let mut server = MidiServerInfo::new(String::from(Backend::Jack.as_str()), None);
+    // The server is marked as unavailable if it fails.
+    let _ = jack_backend::refresh_named_midi_server(&mut server, server_name);
+    server
+}
+
+/// Start listening for device changes on the given backend, or `None` if it has no
+/// notifications (or its server isn't running).
+pub(crate) fn device_notifier(
+    backend: Backend,
+    wake_tx: std::sync::mpsc::Sender<()>,
+) -> Option<Box<dyn DeviceNotifier>> {
+    match backend {
+        Backend::Jack => jack_backend::device_notifier(wake_tx)
+            .map(|notifier| Box::new(notifier) as Box<dyn DeviceNotifier>),
+        _ => None,
+    }
+}
+
 pub fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
+    backend: Backend,
     config: &Config,
     use_client_name: Option<String>,
     rt_process_handler: P,
     fatal_error_handler: E,
-) -> Result<LinuxStreamHandle<P, E>, SpawnRtThreadError> {
-    match config.audio_server.as_str() {
-        "Jack" => {
+) -> Result<LinuxStreamHandle<P, E>, SpawnError<P>> {
+    match backend {
+        Backend::Jack => {
             let (stream_info, jack_server_handle) = jack_backend::spawn_rt_thread(
                 config,
                 rt_process_handler,
@@ -118,14 +636,34 @@ pub fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
                 use_client_name,
             )?;
 
-            return Ok(LinuxStreamHandle {
+            Ok(LinuxStreamHandle {
+                stream_info,
+                backend_handle: BackendHandle::Jack(jack_server_handle),
+            })
+        }
+        #[cfg(feature = "alsa-backend")]
+        Backend::Alsa => {
+            let (stream_info, alsa_handle) =
+                alsa_backend::spawn_rt_thread(config, rt_process_handler, fatal_error_handler)?;
+
+            Ok(LinuxStreamHandle {
                 stream_info,
-                _jack_server_handle: Some(jack_server_handle),
-            });
+                backend_handle: BackendHandle::Alsa(alsa_handle),
+            })
         }
-        s => {
-            let s = String::from(s);
-            Err(SpawnRtThreadError::AudioServerUnavailable(s))
+        #[cfg(feature = "pulse")]
+        Backend::PulseAudio => {
+            let (stream_info, pulse_handle) =
+                pulse_backend::spawn_rt_thread(config, rt_process_handler, fatal_error_handler)?;
+
+            Ok(LinuxStreamHandle {
+                stream_info,
+                backend_handle: BackendHandle::Pulse(pulse_handle),
+            })
         }
+        backend => Err(SpawnError::new(
+            SpawnRtThreadError::BackendNotCompiled(backend),
+            Some(rt_process_handler),
+        )),
     }
 }
@@ -0,0 +1,1164 @@
+use libpulse_binding::callbacks::ListResult;
+use libpulse_binding::context::{Context, FlagSet as ContextFlagSet, State as ContextState};
+use libpulse_binding::def::BufferAttr;
+use libpulse_binding::error::{Code, PAErr};
+use libpulse_binding::mainloop::standard::{IterateResult, Mainloop};
+use libpulse_binding::operation::{Operation, State as OperationState};
+use libpulse_binding::sample::{Format, Spec};
+use libpulse_binding::stream::Direction;
+use libpulse_simple_binding::Simple;
+use log::{debug, info, warn};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use crate::channel_gain::{ChannelGains, ChannelGainsHandle};
+use crate::cycle_debug::{cycle_debugger, CycleDebugHandle, CycleDebugInfo, CycleDebugger};
+use crate::denormals::DenormalGuard;
+use crate::diagnostics::BufferSizeHistory;
+use crate::master_gain::{MasterGain, MasterGainHandle};
+use crate::port_mixing::{channel_routes, ChannelRoute};
+use crate::process_panic::catch_process_panic;
+use crate::rt_thread::promote_thread;
+use crate::shutdown_hook::ShutdownHook;
+use crate::silence_detect::{SilenceDetectHandle, SilenceDetector};
+use crate::spawn_deadline::SpawnDeadline;
+use crate::xruns::Xruns;
+use crate::{
+    AudioBus, AudioBusBuffer, AudioBusConfig, AudioDeviceInfo, AudioServerInfo, Backend,
+    BufferSizeRange, Config, DeviceIndex, DisconnectReason, EnumerationError, FatalErrorHandler,
+    FatalStreamError, ProcessControl, ProcessInfo, RtProcessHandler, SampleFormat, SpawnError,
+    SpawnPhase, SpawnRtThreadError, StreamDiagnostics, StreamInfo,
+};
+
+/// PulseAudio resamples every stream to the rate of its sink, so these are offered on top of
+/// the rate the sink runs at.
+static SAMPLE_RATES: [u32; 6] = [44100, 48000, 88200, 96000, 176400, 192000];
+
+/// The periods a stream can be processed in. PulseAudio takes audio in chunks of any size, so
+/// this is only limited by what is sensible for a period.
+const MIN_BUFFER_SIZE: u32 = 32;
+const MAX_BUFFER_SIZE: u32 = 8192;
+const DEFAULT_BUFFER_SIZE: u32 = 512;
+const NUM_PERIODS: u32 = 2;
+
+const CLIENT_NAME: &str = "rusty-daw-io";
+
+const PLAYBACK_PORT_PREFIX: &str = "playback_";
+const CAPTURE_PORT_PREFIX: &str = "capture_";
+
+/// Enumerate every sink as a device. Any source can be recorded along with any sink, so every
+/// device gets the ports of all sources (except for the monitors of sinks).
+pub fn refresh_audio_server(server: &mut AudioServerInfo) -> Result<(), EnumerationError> {
+    info!("Refreshing list of available PulseAudio devices...");
+
+    server.devices.clear();
+    server.available = false;
+
+    let pulse = match query_server() {
+        Ok(pulse) => pulse,
+        Err(e) => {
+            info!("PulseAudio server is unavailable: {}", e);
+
+            return Err(enumeration_error(e));
+        }
+    };
+
+    server.version = pulse.version.clone();
+
+    let mut in_ports = Vec::<String>::new();
+    let mut default_in_port = None;
+    for source in pulse.sources.iter() {
+        if Some(&source.name) == pulse.default_source.as_ref() {
+            default_in_port = Some(in_ports.len());
+        }
+
+        in_ports.append(&mut capture_port_names(&source.name, source.channels));
+    }
+    if default_in_port.is_none() && !in_ports.is_empty() {
+        default_in_port = Some(0); // Fallback to the first capture port.
+    }
+
+    let buffer_size_range = BufferSizeRange {
+        min: MIN_BUFFER_SIZE,
+        max: MAX_BUFFER_SIZE,
+    };
+
+    for sink in pulse.sinks.iter() {
+        if Some(&sink.name) == pulse.default_sink.as_ref() {
+            server.default_device = server.devices.len();
+        }
+
+        let sample_rates = sample_rates(sink.sample_rate);
+        let default_sample_rate_index = sample_rates
+            .iter()
+            .position(|rate| *rate == sink.sample_rate)
+            .unwrap_or(0);
+
+        debug!(
+            "PulseAudio: found sink {} with {} channels at {}Hz",
+            &sink.name, sink.channels, sink.sample_rate
+        );
+
+        server.devices.push(AudioDeviceInfo {
+            name: sink.name.clone(),
+            in_ports: in_ports.clone(),
+            out_ports: port_names(PLAYBACK_PORT_PREFIX, sink.channels),
+            sample_rates,
+            buffer_size_range,
+            duplex_buffer_size_range: if in_ports.is_empty() {
+                None
+            } else {
+                Some(buffer_size_range)
+            },
+
+            default_in_port,
+            default_out_port_left: 0,
+            default_out_port_right: 1.min(usize::from(sink.channels) - 1), // Second port if stereo, first if mono.
+            default_sample_rate_index,
+            default_buffer_size: DEFAULT_BUFFER_SIZE,
+        });
+    }
+
+    server.available = !server.devices.is_empty();
+
+    if !server.available {
+        info!("PulseAudio server is unavailable: it has no sinks.");
+    }
+
+    Ok(())
+}
+
+/// The latency of a stream on a sink before it is open, which is the periods that are queued
+/// for playback. The latency of the sink itself comes on top of this.
+pub fn estimated_latency(device: &AudioDeviceInfo, config: &Config) -> u32 {
+    config.buffer_size.unwrap_or(device.default_buffer_size) * NUM_PERIODS
+}
+
+/// Tell "the server isn't running" and "access was denied" apart from other failures to
+/// connect to the server.
+fn enumeration_error(e: PAErr) -> EnumerationError {
+    match Code::try_from(e) {
+        Ok(Code::ConnectionRefused) | Ok(Code::NoEntity) | Ok(Code::InvalidServer) => {
+            EnumerationError::ServerNotRunning(Backend::PulseAudio)
+        }
+        Ok(Code::Access) | Ok(Code::AuthKey) => {
+            EnumerationError::PermissionDenied(Backend::PulseAudio)
+        }
+        _ => EnumerationError::PlatformSpecific(Box::new(e)),
+    }
+}
+
+/// A sink or source, as reported by the server.
+#[derive(Debug, Clone)]
+struct PulseNode {
+    name: String,
+    channels: u8,
+    sample_rate: u32,
+}
+
+#[derive(Debug, Default)]
+struct PulseServer {
+    version: Option<String>,
+    default_sink: Option<String>,
+    default_source: Option<String>,
+    sinks: Vec<PulseNode>,
+    sources: Vec<PulseNode>,
+}
+
+/// Connect to the server and list its sinks and sources.
+fn query_server() -> Result<PulseServer, PAErr> {
+    let mut mainloop = Mainloop::new().ok_or_else(|| PAErr::from(Code::Internal))?;
+    let mut context =
+        Context::new(&mainloop, CLIENT_NAME).ok_or_else(|| PAErr::from(Code::Internal))?;
+
+    // Don't start a server just to enumerate it.
+    context.connect(None, ContextFlagSet::NOAUTOSPAWN, None)?;
+    loop {
+        iterate(&mut mainloop)?;
+
+        match context.get_state() {
+            ContextState::Ready => break,
+            ContextState::Failed | ContextState::Terminated => return Err(context.errno()),
+            _ => {}
+        }
+    }
+
+    let server = Rc::new(RefCell::new(PulseServer::default()));
+    let introspect = context.introspect();
+
+    let info_server = Rc::clone(&server);
+    let operation = introspect.get_server_info(move |info| {
+        let mut server = info_server.borrow_mut();
+        server.version = info.server_version.as_ref().map(|v| v.to_string());
+        server.default_sink = info.default_sink_name.as_ref().map(|n| n.to_string());
+        server.default_source = info.default_source_name.as_ref().map(|n| n.to_string());
+    });
+    wait_for(&mut mainloop, &operation)?;
+
+    let sink_server = Rc::clone(&server);
+    let operation = introspect.get_sink_info_list(move |result| {
+        if let ListResult::Item(info) = result {
+            if let Some(name) = &info.name {
+                sink_server.borrow_mut().sinks.push(PulseNode {
+                    name: name.to_string(),
+                    channels: info.sample_spec.channels,
+                    sample_rate: info.sample_spec.rate,
+                });
+            }
+        }
+    });
+    wait_for(&mut mainloop, &operation)?;
+
+    let source_server = Rc::clone(&server);
+    let operation = introspect.get_source_info_list(move |result| {
+        if let ListResult::Item(info) = result {
+            // The monitor of a sink records what is played on it, so it isn't an input.
+            if info.monitor_of_sink.is_some() {
+                return;
+            }
+
+            if let Some(name) = &info.name {
+                source_server.borrow_mut().sources.push(PulseNode {
+                    name: name.to_string(),
+                    channels: info.sample_spec.channels,
+                    sample_rate: info.sample_spec.rate,
+                });
+            }
+        }
+    });
+    wait_for(&mut mainloop, &operation)?;
+
+    context.disconnect();
+
+    Ok(server.take())
+}
+
+fn iterate(mainloop: &mut Mainloop) -> Result<(), PAErr> {
+    match mainloop.iterate(true) {
+        IterateResult::Success(_) => Ok(()),
+        IterateResult::Quit(_) => Err(PAErr::from(Code::ConnectionTerminated)),
+        IterateResult::Err(e) => Err(e),
+    }
+}
+
+/// Run the mainloop until an operation is done.
+fn wait_for<T: ?Sized>(mainloop: &mut Mainloop, operation: &Operation<T>) -> Result<(), PAErr> {
+    while operation.get_state() == OperationState::Running {
+        iterate(mainloop)?;
+    }
+
+    Ok(())
+}
+
+fn sample_rates(sink_rate: u32) -> Vec<u32> {
+    let mut sample_rates = SAMPLE_RATES.to_vec();
+    if !sample_rates.contains(&sink_rate) {
+        sample_rates.push(sink_rate);
+        sample_rates.sort_unstable();
+    }
+
+    sample_rates
+}
+
+fn port_names(prefix: &str, channels: u8) -> Vec<String> {
+    (1..=channels).map(|i| format!("{}{}", prefix, i)).collect()
+}
+
+fn capture_port_names(source: &str, channels: u8) -> Vec<String> {
+    (1..=channels)
+        .map(|i| format!("{}:{}{}", source, CAPTURE_PORT_PREFIX, i))
+        .collect()
+}
+
+/// Parse the device channel a port like "playback_1" refers to.
+fn parse_port_channel(port: &str, prefix: &str) -> Option<usize> {
+    let n: usize = port.strip_prefix(prefix)?.parse().ok()?;
+
+    if n == 0 {
+        None
+    } else {
+        Some(n - 1)
+    }
+}
+
+/// Parse the source and the channel of that source a port like
+/// "alsa_input.usb-mic.analog-stereo:capture_1" refers to.
+fn parse_capture_port(port: &str) -> Option<(&str, usize)> {
+    let (source, channel) = port.rsplit_once(':')?;
+
+    Some((source, parse_port_channel(channel, CAPTURE_PORT_PREFIX)?))
+}
+
+pub struct PulseRtThreadHandle<P: RtProcessHandler> {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<P>>,
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_debug_handle: CycleDebugHandle,
+    pub(super) master_gain_handle: MasterGainHandle,
+    pub(super) channel_gains_handle: ChannelGainsHandle,
+    pub(super) silence_detect_handle: SilenceDetectHandle,
+    pub(super) shutdown_hook: Arc<ShutdownHook>,
+
+    // The simple API doesn't report xruns, so this stays at zero.
+    pub(super) xruns: Arc<Xruns>,
+}
+
+impl<P: RtProcessHandler> PulseRtThreadHandle<P> {
+    /// Stop the audio thread, close the streams, and return the process handler.
+    ///
+    /// Returns `None` if the stream was already stopped or the audio thread panicked.
+    pub fn stop(&mut self) -> Option<P> {
+        let thread = self.thread.take()?;
+
+        self.shutdown_hook.run();
+
+        self.running.store(false, Ordering::Relaxed);
+
+        match thread.join() {
+            Ok(rt_process_handler) => Some(rt_process_handler),
+            Err(_) => {
+                warn!("PulseAudio: audio thread panicked");
+                None
+            }
+        }
+    }
+
+    /// The total number of frames that have been processed since the stream started.
+    pub fn frames_processed(&self) -> u64 {
+        self.frames_processed.load(Ordering::Relaxed)
+    }
+
+    /// Log detailed diagnostics for the next `n` process cycles, after which logging
+    /// stops automatically.
+    pub fn debug_next_cycles(&self, n: u32) {
+        self.cycle_debug_handle.debug_next_cycles(n);
+    }
+
+    /// A snapshot of the health of the stream.
+    pub fn diagnostics(&self) -> StreamDiagnostics {
+        StreamDiagnostics {
+            frames_processed: self.frames_processed(),
+            xruns: self.xruns.count(),
+            cycle_debug_queue: self.cycle_debug_handle.queue_stats(),
+            ..StreamDiagnostics::default()
+        }
+    }
+
+    /// The number of frames of each of the last few process cycles, oldest first.
+    pub fn recent_buffer_sizes(&self) -> Vec<u32> {
+        self.buffer_size_history.recent()
+    }
+}
+
+impl<P: RtProcessHandler> Drop for PulseRtThreadHandle<P> {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+pub fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
+    config: &Config,
+    rt_process_handler: P,
+    fatal_error_handler: E,
+) -> Result<(StreamInfo, PulseRtThreadHandle<P>), SpawnError<P>> {
+    let mut rt_process_handler = Some(rt_process_handler);
+    spawn(config, &mut rt_process_handler, fatal_error_handler)
+        .map_err(|e| SpawnError::new(e, rt_process_handler))
+}
+
+/// Spawn the stream, leaving the handler in `rt_process_handler_slot` if it fails.
+fn spawn<P: RtProcessHandler, E: FatalErrorHandler>(
+    config: &Config,
+    rt_process_handler_slot: &mut Option<P>,
+    fatal_error_handler: E,
+) -> Result<(StreamInfo, PulseRtThreadHandle<P>), SpawnRtThreadError> {
+    info!("Spawning PulseAudio thread...");
+
+    let deadline = SpawnDeadline::new(config.spawn_timeout);
+
+    // The sinks and sources can have changed since they were enumerated.
+    let pulse = deadline
+        .run(SpawnPhase::Open, query_server)?
+        .map_err(spawn_error)?;
+
+    let sink = pulse
+        .sinks
+        .iter()
+        .find(|s| s.name == config.system_audio_device)
+        .ok_or_else(|| {
+            SpawnRtThreadError::SystemDeviceNotFound(config.system_audio_device.clone())
+        })?;
+
+    // Map every bus channel to its sink or source channel.
+
+    let (audio_in_busses, in_channel_map, sources) =
+        map_in_busses(&config.audio_in_busses, &sink.name, &pulse.sources)?;
+    let (audio_out_busses, out_channel_map) =
+        map_out_busses(&config.audio_out_busses, &sink.name, sink.channels)?;
+
+    if config.midi_enabled() {
+        warn!("PulseAudio: PulseAudio has no MIDI, no MIDI controllers will be created");
+    }
+
+    let sample_rate = config.sample_rate.unwrap_or(sink.sample_rate);
+    let period_size = config.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+
+    let buffer_size_range = BufferSizeRange {
+        min: MIN_BUFFER_SIZE,
+        max: MAX_BUFFER_SIZE,
+    };
+    if !buffer_size_range.contains(period_size) {
+        return Err(SpawnRtThreadError::UnsupportedBufferSize {
+            requested: period_size,
+            range: buffer_size_range,
+        });
+    }
+
+    // Open the streams. A stream without any sources is paced by the sink, so that is opened
+    // even for input only streams.
+
+    let playback = if config.direction.has_output() || sources.is_empty() {
+        Some(open_stream(
+            &deadline,
+            Direction::Playback,
+            sink,
+            sample_rate,
+            period_size,
+        )?)
+    } else {
+        None
+    };
+
+    let mut captures = Vec::<CaptureStream>::new();
+    for source in sources.iter() {
+        captures.push(CaptureStream {
+            stream: open_stream(
+                &deadline,
+                Direction::Record,
+                source,
+                sample_rate,
+                period_size,
+            )?,
+            channels: usize::from(source.channels),
+            raw: vec![0.0; period_size as usize * usize::from(source.channels)],
+        });
+    }
+
+    // Queue the periods of silence that every written period is played after, so the
+    // latency reported by the server includes them.
+    let out_channels = if playback.is_some() {
+        usize::from(sink.channels)
+    } else {
+        0
+    };
+    if let Some(playback) = &playback {
+        let silence = vec![0.0f32; period_size as usize * out_channels];
+        for _ in 0..NUM_PERIODS {
+            playback.write(as_bytes(&silence)).map_err(spawn_error)?;
+        }
+    }
+
+    deadline.check(SpawnPhase::RegisterPorts)?;
+
+    let stream_info = StreamInfo {
+        server_name: String::from("PulseAudio"),
+        audio_in: audio_in_busses,
+        audio_out: audio_out_busses,
+        midi_in: Vec::new(),
+        midi_out: Vec::new(),
+        sample_rate,
+        max_audio_buffer_size: period_size,
+        // A recorded period can only be read once it is full.
+        latency_frames_in: captures
+            .iter()
+            .map(|c| period_size + stream_latency_frames(&c.stream, sample_rate, 0))
+            .max()
+            .unwrap_or(0),
+        latency_frames_out: playback
+            .as_ref()
+            .map(|p| stream_latency_frames(p, sample_rate, period_size * NUM_PERIODS))
+            .unwrap_or(0),
+        sample_format: SampleFormat::F32,
+    };
+
+    // From here on the handler can only be given back by stopping the audio thread.
+    let mut rt_process_handler = rt_process_handler_slot
+        .take()
+        .expect("the handler is only taken once");
+    rt_process_handler.init(&stream_info);
+
+    let (cycle_debugger, cycle_debug_handle) = cycle_debugger();
+
+    let process = PulseProcess {
+        rt_process_handler,
+        fatal_error_handler: Some(fatal_error_handler),
+        device: sink.name.clone(),
+        playback,
+        captures,
+        out_channels,
+        in_channel_map,
+        out_channel_map,
+        stream_info: stream_info.clone(),
+        running: Arc::new(AtomicBool::new(true)),
+        frames_processed: Arc::new(AtomicU64::new(0)),
+        buffer_size_history: Arc::new(BufferSizeHistory::new(config.buffer_size_history_len())),
+        cycle_debugger,
+        master_gain: MasterGain::new(),
+        channel_gains: ChannelGains::new(&config.audio_in_busses, &config.audio_out_busses),
+        silence_detector: SilenceDetector::new(
+            stream_info
+                .audio_in
+                .iter()
+                .map(|b| b.id_name.clone())
+                .collect(),
+        ),
+        input_peaks: config.input_peaks,
+        flush_denormals: !config.allow_denormals,
+        shutdown_hook: Arc::new(ShutdownHook::new()),
+    };
+
+    let running = Arc::clone(&process.running);
+    let frames_processed = Arc::clone(&process.frames_processed);
+    let buffer_size_history = Arc::clone(&process.buffer_size_history);
+    let master_gain_handle = process.master_gain.handle();
+    let channel_gains_handle = process.channel_gains.handle();
+    let silence_detect_handle = process.silence_detector.handle();
+    let shutdown_hook = Arc::clone(&process.shutdown_hook);
+    shutdown_hook.set_stream_info(stream_info.clone());
+
+    let thread = std::thread::Builder::new()
+        .name(String::from("rusty-daw-io PulseAudio"))
+        .spawn(move || process.run())
+        .map_err(|e| SpawnRtThreadError::PlatformSpecific(Box::new(e)))?;
+
+    let mut handle = PulseRtThreadHandle {
+        running,
+        thread: Some(thread),
+        frames_processed,
+        buffer_size_history,
+        cycle_debug_handle,
+        master_gain_handle,
+        channel_gains_handle,
+        silence_detect_handle,
+        shutdown_hook,
+        xruns: Arc::new(Xruns::new()),
+    };
+
+    let started = match &handle.thread {
+        Some(thread) => promote_thread(thread, &config.rt_thread),
+        None => Ok(()),
+    }
+    .and_then(|()| deadline.check(SpawnPhase::Activate));
+    if let Err(e) = started {
+        *rt_process_handler_slot = handle.stop();
+        return Err(e);
+    }
+
+    info!(
+        "Successfully spawned PulseAudio thread. Sink: {}, Sample rate: {}, Max audio buffer size: {}, Latency: {} in, {} out",
+        &sink.name, sample_rate, period_size, stream_info.latency_frames_in, stream_info.latency_frames_out
+    );
+
+    Ok((stream_info, handle))
+}
+
+/// Create the input busses and route each of their channels to channels of the sources they
+/// record from. This also returns the sources that need a record stream, in the order the
+/// routes refer to them.
+#[allow(clippy::type_complexity)]
+fn map_in_busses(
+    busses: &[AudioBusConfig],
+    device: &str,
+    sources: &[PulseNode],
+) -> Result<
+    (
+        Vec<AudioBus>,
+        Vec<Vec<ChannelRoute<(usize, usize)>>>,
+        Vec<PulseNode>,
+    ),
+    SpawnRtThreadError,
+> {
+    let mut audio_busses = Vec::<AudioBus>::new();
+    let mut channel_map = Vec::<Vec<ChannelRoute<(usize, usize)>>>::new();
+    let mut used_sources = Vec::<PulseNode>::new();
+
+    for (bus_i, bus) in busses.iter().enumerate() {
+        if bus.system_ports.is_empty() {
+            return Err(SpawnRtThreadError::NoSystemPortsGiven(bus.id.clone()));
+        }
+
+        let mut channels = Vec::<(usize, usize)>::new();
+        let mut half_duplex_device = None;
+        for system_port in bus.system_ports.iter() {
+            let not_found =
+                || SpawnRtThreadError::SystemPortNotFound(system_port.clone(), bus.id.clone());
+
+            let (source_name, channel) = parse_capture_port(system_port).ok_or_else(not_found)?;
+            let source = sources
+                .iter()
+                .find(|s| s.name == source_name && channel < usize::from(s.channels))
+                .ok_or_else(not_found)?;
+
+            // Sources are opened in the order they are first used.
+            let stream_i = match used_sources.iter().position(|s| s.name == source.name) {
+                Some(stream_i) => stream_i,
+                None => {
+                    used_sources.push(source.clone());
+                    used_sources.len() - 1
+                }
+            };
+
+            half_duplex_device = Some(source.name.clone());
+            channels.push((stream_i, channel));
+        }
+
+        let channel_layout = bus.channel_layout();
+        let num_channels = channel_layout.num_channels() as u16;
+        audio_busses.push(AudioBus {
+            id_name: bus.id.clone(),
+            id_index: DeviceIndex::new(bus_i),
+            system_device: String::from(device),
+            system_half_duplex_device: half_duplex_device,
+            system_ports: bus.system_ports.clone(),
+            client_ports: Vec::new(),
+            channels: num_channels,
+            channel_layout,
+            connected_ports: num_channels,
+        });
+        channel_map.push(channel_routes(bus, &channels, true));
+    }
+
+    Ok((audio_busses, channel_map, used_sources))
+}
+
+/// Create the output busses and route each of their channels to channels of the sink.
+#[allow(clippy::type_complexity)]
+fn map_out_busses(
+    busses: &[AudioBusConfig],
+    device: &str,
+    device_channels: u8,
+) -> Result<(Vec<AudioBus>, Vec<Vec<ChannelRoute<usize>>>), SpawnRtThreadError> {
+    let mut audio_busses = Vec::<AudioBus>::new();
+    let mut channel_map = Vec::<Vec<ChannelRoute<usize>>>::new();
+
+    for (bus_i, bus) in busses.iter().enumerate() {
+        if bus.system_ports.is_empty() {
+            return Err(SpawnRtThreadError::NoSystemPortsGiven(bus.id.clone()));
+        }
+
+        let mut channels = Vec::<usize>::new();
+        for system_port in bus.system_ports.iter() {
+            let channel = parse_port_channel(system_port, PLAYBACK_PORT_PREFIX)
+                .filter(|channel| *channel < usize::from(device_channels))
+                .ok_or_else(|| {
+                    SpawnRtThreadError::SystemPortNotFound(system_port.clone(), bus.id.clone())
+                })?;
+
+            channels.push(channel);
+        }
+
+        let channel_layout = bus.channel_layout();
+        let num_channels = channel_layout.num_channels() as u16;
+        audio_busses.push(AudioBus {
+            id_name: bus.id.clone(),
+            id_index: DeviceIndex::new(bus_i),
+            system_device: String::from(device),
+            system_half_duplex_device: None,
+            system_ports: bus.system_ports.clone(),
+            client_ports: Vec::new(),
+            channels: num_channels,
+            channel_layout,
+            connected_ports: num_channels,
+        });
+        channel_map.push(channel_routes(bus, &channels, false));
+    }
+
+    Ok((audio_busses, channel_map))
+}
+
+/// Open a blocking stream on a sink or source, with every channel it has.
+fn open_stream(
+    deadline: &SpawnDeadline,
+    direction: Direction,
+    node: &PulseNode,
+    sample_rate: u32,
+    period_size: u32,
+) -> Result<Simple, SpawnRtThreadError> {
+    let spec = Spec {
+        format: Format::FLOAT32NE,
+        rate: sample_rate,
+        channels: node.channels,
+    };
+    let period_bytes = period_size * spec.frame_size() as u32;
+
+    // Playback keeps `NUM_PERIODS` periods queued, and recorded audio is handed over one
+    // period at a time. `u32::MAX` leaves the rest up to the server.
+    let attr = BufferAttr {
+        maxlength: u32::MAX,
+        tlength: period_bytes * NUM_PERIODS,
+        prebuf: u32::MAX,
+        minreq: period_bytes,
+        fragsize: period_bytes,
+    };
+
+    let name = node.name.clone();
+    deadline
+        .run(SpawnPhase::Open, move || {
+            Simple::new(
+                None,
+                CLIENT_NAME,
+                direction,
+                Some(&name),
+                CLIENT_NAME,
+                &spec,
+                None,
+                Some(&attr),
+            )
+        })?
+        .map_err(|e| match Code::try_from(e) {
+            Ok(Code::NoEntity) => SpawnRtThreadError::SystemDeviceNotFound(node.name.clone()),
+            _ => spawn_error(e),
+        })
+}
+
+/// The latency the server reports for a stream, or `fallback` if it can't be queried.
+fn stream_latency_frames(stream: &Simple, sample_rate: u32, fallback: u32) -> u32 {
+    match stream.get_latency() {
+        Ok(latency) => (latency.0 * u64::from(sample_rate) / 1_000_000) as u32,
+        Err(e) => {
+            debug!("PulseAudio: could not query the stream latency: {}", e);
+            fallback
+        }
+    }
+}
+
+fn spawn_error(e: PAErr) -> SpawnRtThreadError {
+    match Code::try_from(e) {
+        Ok(Code::ConnectionRefused) | Ok(Code::InvalidServer) => {
+            SpawnRtThreadError::ServerNotRunning(String::from(Backend::PulseAudio.as_str()))
+        }
+        _ => SpawnRtThreadError::PlatformSpecific(Box::new(e)),
+    }
+}
+
+/// The bytes of interleaved samples, which every stream is opened with as `FLOAT32NE`.
+fn as_bytes(samples: &[f32]) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts(
+            samples.as_ptr() as *const u8,
+            std::mem::size_of_val(samples),
+        )
+    }
+}
+
+fn as_bytes_mut(samples: &mut [f32]) -> &mut [u8] {
+    unsafe {
+        std::slice::from_raw_parts_mut(
+            samples.as_mut_ptr() as *mut u8,
+            std::mem::size_of_val(samples),
+        )
+    }
+}
+
+/// A record stream on one source, and its last period of interleaved audio.
+struct CaptureStream {
+    stream: Simple,
+    channels: usize,
+    raw: Vec<f32>,
+}
+
+struct PulseProcess<P: RtProcessHandler, E: FatalErrorHandler> {
+    rt_process_handler: P,
+    fatal_error_handler: Option<E>,
+
+    device: String,
+    playback: Option<Simple>,
+    captures: Vec<CaptureStream>,
+
+    out_channels: usize,
+
+    in_channel_map: Vec<Vec<ChannelRoute<(usize, usize)>>>,
+    out_channel_map: Vec<Vec<ChannelRoute<usize>>>,
+
+    stream_info: StreamInfo,
+
+    running: Arc<AtomicBool>,
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_debugger: CycleDebugger,
+    master_gain: MasterGain,
+    channel_gains: ChannelGains,
+    silence_detector: SilenceDetector,
+    input_peaks: bool,
+    flush_denormals: bool,
+    shutdown_hook: Arc<ShutdownHook>,
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> PulseProcess<P, E> {
+    /// Run the stream until it is stopped or fails. This returns the process handler so
+    /// it can be handed back from `PulseRtThreadHandle::stop()`.
+    fn run(self) -> P {
+        let mut rt_process_handler = self.run_stream();
+        rt_process_handler.deinit();
+        rt_process_handler
+    }
+
+    fn run_stream(self) -> P {
+        let PulseProcess {
+            mut rt_process_handler,
+            mut fatal_error_handler,
+            device,
+            playback,
+            mut captures,
+            out_channels,
+            in_channel_map,
+            out_channel_map,
+            stream_info,
+            running,
+            frames_processed,
+            buffer_size_history,
+            mut cycle_debugger,
+            mut master_gain,
+            mut channel_gains,
+            mut silence_detector,
+            input_peaks,
+            flush_denormals,
+            shutdown_hook,
+        } = self;
+
+        let mut fatal = |error: FatalStreamError| {
+            info!("{}", error);
+
+            running.store(false, Ordering::Relaxed);
+
+            shutdown_hook.run();
+
+            if let Some(fatal_error_handler) = fatal_error_handler.take() {
+                fatal_error_handler.fatal_stream_error(error)
+            }
+        };
+
+        let audio_frames = stream_info.max_audio_buffer_size as usize;
+
+        let mut audio_in_buffers = Vec::<AudioBusBuffer>::new();
+        let mut audio_out_buffers = Vec::<AudioBusBuffer>::new();
+
+        for bus in stream_info.audio_in.iter() {
+            audio_in_buffers.push(AudioBusBuffer::for_input_bus(
+                bus,
+                audio_frames as u32,
+                input_peaks,
+            ))
+        }
+        for bus in stream_info.audio_out.iter() {
+            audio_out_buffers.push(AudioBusBuffer::for_bus(bus, audio_frames as u32))
+        }
+
+        let mut out_raw: Vec<f32> = vec![0.0; audio_frames * out_channels];
+
+        while running.load(Ordering::Relaxed) {
+            // Collect Audio Inputs
+
+            for capture in captures.iter_mut() {
+                if let Err(e) = capture.stream.read(as_bytes_mut(&mut capture.raw)) {
+                    fatal(stream_error(e, &device));
+                    return rt_process_handler;
+                }
+            }
+
+            if !captures.is_empty() {
+                deinterleave(
+                    &captures,
+                    audio_frames,
+                    &in_channel_map,
+                    &mut audio_in_buffers,
+                );
+                channel_gains.apply_inputs(
+                    &mut audio_in_buffers,
+                    audio_frames,
+                    stream_info.sample_rate,
+                );
+
+                for audio_buffer in audio_in_buffers.iter_mut() {
+                    audio_buffer.update_peaks();
+                }
+                silence_detector.process(&audio_in_buffers, audio_frames, stream_info.sample_rate);
+            }
+
+            // Either the reads or the last playback write is what blocks until the server is
+            // ready for this cycle.
+            let cycle_start_time = Instant::now();
+
+            // Clear Audio Outputs
+
+            for audio_buffer in audio_out_buffers.iter_mut() {
+                audio_buffer.clear_and_resize(audio_frames);
+            }
+
+            let result = catch_process_panic(|| {
+                let _denormals = DenormalGuard::new(flush_denormals);
+                rt_process_handler.process(ProcessInfo {
+                    audio_in: audio_in_buffers.as_slice(),
+                    audio_out: audio_out_buffers.as_mut_slice(),
+                    audio_frames,
+
+                    midi_in: &[],
+                    midi_out: &mut [],
+
+                    sample_rate: stream_info.sample_rate,
+                    transport: None,
+                    cycle_start_frame: frames_processed.load(Ordering::Relaxed),
+                    cycle_start_time,
+                })
+            });
+            let control = match result {
+                Ok(control) => control,
+                Err(error) => {
+                    fatal(error);
+                    break;
+                }
+            };
+
+            master_gain.apply(
+                &mut audio_out_buffers,
+                audio_frames,
+                stream_info.sample_rate,
+            );
+            channel_gains.apply_outputs(
+                &mut audio_out_buffers,
+                audio_frames,
+                stream_info.sample_rate,
+            );
+
+            // Copy processed data to Audio Outputs
+
+            if let Some(playback) = &playback {
+                interleave(
+                    &audio_out_buffers,
+                    &out_channel_map,
+                    out_channels,
+                    audio_frames,
+                    &mut out_raw,
+                );
+
+                if let Err(e) = playback.write(as_bytes(&out_raw)) {
+                    fatal(stream_error(e, &device));
+                    return rt_process_handler;
+                }
+            }
+
+            let frames_processed =
+                frames_processed.fetch_add(audio_frames as u64, Ordering::Relaxed);
+            buffer_size_history.push(audio_frames);
+
+            if cycle_debugger.begin_cycle() {
+                cycle_debugger.log(CycleDebugInfo {
+                    frames_processed,
+                    audio_frames,
+                    audio_in_channels: captures.iter().map(|c| c.channels).sum(),
+                    audio_out_channels: out_channels,
+                    midi_in_events: 0,
+                    midi_out_events: 0,
+                });
+            }
+
+            if control == ProcessControl::Quit {
+                info!("PulseAudio: the process handler stopped the stream");
+                running.store(false, Ordering::Relaxed);
+                shutdown_hook.run();
+                break;
+            }
+        }
+
+        // Don't play out what is still queued after the stream was stopped.
+        if let Some(playback) = &playback {
+            if let Err(e) = playback.flush() {
+                debug!("PulseAudio: failed to flush playback: {}", e);
+            }
+        }
+
+        rt_process_handler
+    }
+}
+
+/// Tell why a stream failed, from the error of a blocking read or write.
+fn stream_error(e: PAErr, device: &str) -> FatalStreamError {
+    match Code::try_from(e) {
+        Ok(Code::ConnectionTerminated) => FatalStreamError::AudioServerDisconnected {
+            backend: Backend::PulseAudio,
+            reason: DisconnectReason::ServerQuit,
+            message: format!("PulseAudio: the server shut down: {}", e),
+        },
+        Ok(Code::Killed) => FatalStreamError::AudioServerDisconnected {
+            backend: Backend::PulseAudio,
+            reason: DisconnectReason::Killed,
+            message: format!("PulseAudio: the server killed the stream on {}", device),
+        },
+        Ok(Code::NoEntity) => FatalStreamError::AudioDeviceDisconnected {
+            backend: Backend::PulseAudio,
+            reason: DisconnectReason::DeviceRemoved,
+            message: format!("PulseAudio: stream on sink {} failed: {}", device, e),
+        },
+        _ => FatalStreamError::PlatformSpecific(Box::new(e)),
+    }
+}
+
+/// Copy the interleaved periods of the sources into the channels of each bus. Channels that
+/// are routed from more than one source channel get their sum.
+fn deinterleave(
+    captures: &[CaptureStream],
+    frames: usize,
+    channel_map: &[Vec<ChannelRoute<(usize, usize)>>],
+    busses: &mut [AudioBusBuffer],
+) {
+    for (audio_buffer, routes) in busses.iter_mut().zip(channel_map.iter()) {
+        for (channel, route) in audio_buffer.channel_buffers.iter_mut().zip(routes.iter()) {
+            // This should never allocate because each buffer was given a capacity of
+            // the period size.
+            channel.clear();
+            channel.resize(frames, 0.0);
+
+            for (capture_i, capture_channel) in route.ports.iter() {
+                let capture = &captures[*capture_i];
+                for (dst, src) in channel.iter_mut().zip(
+                    capture
+                        .raw
+                        .iter()
+                        .skip(*capture_channel)
+                        .step_by(capture.channels),
+                ) {
+                    *dst += *src * route.gain;
+                }
+            }
+        }
+
+        audio_buffer.frames = frames;
+    }
+}
+
+/// Mix the channels of each bus into an interleaved sink buffer. Sink channels that more
+/// than one channel is routed to are summed.
+fn interleave(
+    busses: &[AudioBusBuffer],
+    channel_map: &[Vec<ChannelRoute<usize>>],
+    device_channels: usize,
+    frames: usize,
+    interleaved: &mut [f32],
+) {
+    for s in interleaved.iter_mut() {
+        *s = 0.0;
+    }
+
+    for (audio_buffer, routes) in busses.iter().zip(channel_map.iter()) {
+        for (channel, route) in audio_buffer.channel_buffers.iter().zip(routes.iter()) {
+            // Just in case the user resized the output buffer for some reason.
+            let len = channel.len().min(frames);
+            if len != frames {
+                warn!(
+                    "Warning: An audio output buffer was resized from {} to {} by the user",
+                    frames, len
+                );
+            }
+
+            for device_channel in route.ports.iter() {
+                for (dst, src) in interleaved
+                    .iter_mut()
+                    .skip(*device_channel)
+                    .step_by(device_channels)
+                    .zip(channel[0..len].iter())
+                {
+                    *dst += *src * route.gain;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ports() {
+        assert_eq!(
+            parse_port_channel("playback_2", PLAYBACK_PORT_PREFIX),
+            Some(1)
+        );
+        assert_eq!(parse_port_channel("playback_0", PLAYBACK_PORT_PREFIX), None);
+
+        assert_eq!(
+            parse_capture_port("alsa_input.usb-mic.analog-stereo:capture_1"),
+            Some(("alsa_input.usb-mic.analog-stereo", 0))
+        );
+        assert_eq!(
+            parse_capture_port("tcp:host:capture_12"),
+            Some(("tcp:host", 11))
+        );
+        assert_eq!(parse_capture_port("capture_1"), None);
+        assert_eq!(parse_capture_port("mic:playback_1"), None);
+    }
+
+    #[test]
+    fn capture_ports_round_trip() {
+        for (channel, port) in capture_port_names("mic", 2).iter().enumerate() {
+            assert_eq!(parse_capture_port(port), Some(("mic", channel)));
+        }
+    }
+
+    #[test]
+    fn sink_rate_is_offered() {
+        assert_eq!(sample_rates(48000), SAMPLE_RATES.to_vec());
+        assert_eq!(
+            sample_rates(32000),
+            vec![32000, 44100, 48000, 88200, 96000, 176400, 192000]
+        );
+    }
+
+    /// Needs a running PulseAudio server, so this only runs with `--ignored` (CI starts a
+    /// server with a null sink for it).
+    #[test]
+    #[ignore]
+    fn plays_on_the_default_sink() {
+        let mut server = AudioServerInfo::new(String::from("PulseAudio"), None);
+        refresh_audio_server(&mut server).unwrap();
+        assert!(server.available);
+        let device = &server.devices[server.default_device];
+
+        let mut config = crate::dummy_backend::tests::test_config();
+        config.audio_server = server.name.clone();
+        config.system_audio_device = device.name.clone();
+        config.audio_in_busses.clear();
+        config.audio_out_busses[0].system_ports = vec![
+            device.out_ports[device.default_out_port_left].clone(),
+            device.out_ports[device.default_out_port_right].clone(),
+        ];
+        config.midi_server = None;
+        config.midi_in_controllers.clear();
+        config.midi_out_controllers.clear();
+
+        let handler = crate::RecordingProcessHandler::new();
+        let recording = handler.recording();
+        let (stream_info, mut stream) =
+            spawn_rt_thread(&config, handler, crate::CollectingFatalErrorHandler::new())
+                .map_err(|e| e.error)
+                .unwrap();
+
+        assert!(recording.wait_for_cycles(4, std::time::Duration::from_secs(10)));
+        assert!(stream.stop().is_some());
+        assert!(recording.deinit_called());
+        assert_eq!(
+            recording.stream_info().unwrap().sample_rate,
+            stream_info.sample_rate
+        );
+        assert!(recording
+            .cycles()
+            .iter()
+            .all(|cycle| cycle.audio_out_channels == vec![2]));
+    }
+}
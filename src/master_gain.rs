@@ -0,0 +1,146 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::gain_ramp::{ramp_frames, GainRamp};
+use crate::AudioBusBuffer;
+
+/// The default time it takes the master gain to ramp to a new value.
+const DEFAULT_RAMP_TIME: Duration = Duration::from_millis(10);
+
+/// The gain values shared between the realtime thread and the stream handle.
+///
+/// Gains are stored as the bits of an `f32`.
+#[derive(Debug)]
+struct MasterGainShared {
+    current: AtomicU32,
+    target: AtomicU32,
+    ramp_time_micros: AtomicU32,
+}
+
+/// The realtime half of the master gain. This smoothly ramps the gain applied to the
+/// audio outputs toward the target gain set by the user.
+#[derive(Debug)]
+pub(crate) struct MasterGain {
+    shared: Arc<MasterGainShared>,
+    ramp: GainRamp,
+}
+
+impl MasterGain {
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(MasterGainShared {
+                current: AtomicU32::new(1.0f32.to_bits()),
+                target: AtomicU32::new(1.0f32.to_bits()),
+                ramp_time_micros: AtomicU32::new(DEFAULT_RAMP_TIME.as_micros() as u32),
+            }),
+            ramp: GainRamp::new(1.0),
+        }
+    }
+
+    pub fn handle(&self) -> MasterGainHandle {
+        MasterGainHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Apply the master gain to the first `frames` frames of every channel in the given busses.
+    pub fn apply(&mut self, busses: &mut [AudioBusBuffer], frames: usize, sample_rate: u32) {
+        let target = f32::from_bits(self.shared.target.load(Ordering::Relaxed));
+        let ramp_time_micros = self.shared.ramp_time_micros.load(Ordering::Relaxed);
+        self.ramp.set_target(
+            target,
+            ramp_frames(
+                Duration::from_micros(u64::from(ramp_time_micros)),
+                sample_rate,
+            ),
+        );
+
+        let ramping = self.ramp.is_ramping();
+
+        // Every channel gets the same per-sample ramp.
+        for audio_buffer in busses.iter_mut() {
+            for channel in audio_buffer.channel_buffers.iter_mut() {
+                let frames = frames.min(channel.len());
+                let mut ramp = self.ramp;
+                ramp.apply(&mut channel[..frames]);
+            }
+        }
+
+        if ramping {
+            self.ramp.advance(frames);
+            self.shared
+                .current
+                .store(self.ramp.current().to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// The non-realtime half of the master gain.
+#[derive(Debug)]
+pub(crate) struct MasterGainHandle {
+    shared: Arc<MasterGainShared>,
+}
+
+impl MasterGainHandle {
+    pub fn set_master_gain(&self, gain: f32) {
+        self.shared.target.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_master_gain_ramp_time(&self, ramp_time: Duration) {
+        self.shared
+            .ramp_time_micros
+            .store(ramp_time.as_micros() as u32, Ordering::Relaxed);
+    }
+
+    pub fn master_gain(&self) -> f32 {
+        f32::from_bits(self.shared.current.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gain_ramp::tests::ones;
+
+    #[test]
+    fn ramp_reaches_target() {
+        let mut gain = MasterGain::new();
+        let handle = gain.handle();
+
+        // 10ms at 1000Hz is a ramp of 10 frames.
+        handle.set_master_gain_ramp_time(Duration::from_millis(10));
+        handle.set_master_gain(0.0);
+
+        let mut busses = ones(4);
+        gain.apply(&mut busses, 4, 1000);
+        assert_eq!(&busses[0][0], &busses[0][1]);
+        assert!((busses[0][0][0] - 0.9).abs() < 1e-6);
+        assert!((busses[0][0][3] - 0.6).abs() < 1e-6);
+
+        let mut busses = ones(4);
+        gain.apply(&mut busses, 4, 1000);
+        assert!(handle.master_gain() > 0.0);
+
+        let mut busses = ones(4);
+        gain.apply(&mut busses, 4, 1000);
+        assert_eq!(handle.master_gain(), 0.0);
+        assert_eq!(&busses[0][0][2..], &[0.0, 0.0]);
+
+        // Once the target is reached the gain stays constant.
+        let mut busses = ones(4);
+        gain.apply(&mut busses, 4, 1000);
+        assert_eq!(&busses[0][1], &[0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn unity_gain_is_untouched() {
+        let mut gain = MasterGain::new();
+
+        let mut busses = ones(4);
+        gain.apply(&mut busses, 4, 48000);
+
+        assert_eq!(&busses[0][0], &[1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(gain.handle().master_gain(), 1.0);
+    }
+}
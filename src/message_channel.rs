@@ -0,0 +1,281 @@
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::sync::Arc;
+
+use crate::diagnostics::{RingBufferCounters, RingBufferStats};
+use crate::{
+    spawn_rt_thread, FatalErrorHandler, ProcessControl, ProcessInfo, RtProcessHandler, SpawnError,
+    StreamHandle, StreamInfo,
+};
+
+/// Create a lock-free single-producer single-consumer channel that holds up to `capacity`
+/// messages.
+///
+/// Neither half ever blocks or allocates, so either can be used on the realtime thread.
+/// For the common case of sending commands from the UI to the process handler, use
+/// `spawn_rt_thread_with_messages()`, which sets this up for you. For events going the
+/// other way, keep the sender in the handler and poll the receiver from the UI.
+pub fn message_channel<T: Send + 'static>(
+    capacity: usize,
+) -> (MessageSender<T>, MessageReceiver<T>) {
+    let (producer, consumer) = RingBuffer::<T>::new(capacity.max(1)).split();
+    let counters = Arc::new(RingBufferCounters::default());
+
+    (
+        MessageSender {
+            producer,
+            counters: Arc::clone(&counters),
+        },
+        MessageReceiver { consumer },
+    )
+}
+
+/// The sending half of a `message_channel()`.
+pub struct MessageSender<T> {
+    producer: Producer<T>,
+    counters: Arc<RingBufferCounters>,
+}
+
+impl<T> MessageSender<T> {
+    /// Queue a message. If the channel is full the message is given back and an overrun
+    /// is counted.
+    pub fn send(&mut self, message: T) -> Result<(), T> {
+        let result = self.producer.push(message);
+        if result.is_err() {
+            self.counters.overrun();
+        }
+        result
+    }
+
+    /// The number of messages that can be sent before the channel is full.
+    pub fn free_len(&self) -> usize {
+        self.producer.remaining()
+    }
+
+    /// How often a message couldn't be sent because the channel was full.
+    pub fn stats(&self) -> RingBufferStats {
+        self.counters.stats()
+    }
+}
+
+/// The receiving half of a `message_channel()`.
+pub struct MessageReceiver<T> {
+    consumer: Consumer<T>,
+}
+
+impl<T> MessageReceiver<T> {
+    /// The oldest message, or `None` if there are none.
+    pub fn recv(&mut self) -> Option<T> {
+        self.consumer.pop()
+    }
+
+    /// Every message that is queued right now, oldest first.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.consumer.pop())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.consumer.is_empty()
+    }
+}
+
+/// A process handler that receives messages from the non-realtime side of the
+/// application. See `spawn_rt_thread_with_messages()`.
+pub trait RtMessageHandler: RtProcessHandler {
+    type Message: Send + 'static;
+
+    /// Handle a message, on the realtime thread right before the next `process` (or
+    /// `process_offline`). This must not allocate or block.
+    ///
+    /// The message is dropped on the realtime thread too, so messages that own heap
+    /// memory should be sent back (e.g. with another `message_channel()`) instead of
+    /// dropped here.
+    fn message(&mut self, message: Self::Message);
+}
+
+/// Wraps an `RtMessageHandler` so that its messages are delivered at the top of every
+/// process cycle. This is the process handler of a stream spawned with
+/// `spawn_rt_thread_with_messages()`.
+pub struct WithMessages<H: RtMessageHandler> {
+    handler: H,
+    receiver: MessageReceiver<H::Message>,
+}
+
+impl<H: RtMessageHandler> WithMessages<H> {
+    pub fn new(handler: H, receiver: MessageReceiver<H::Message>) -> Self {
+        Self { handler, receiver }
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// The handler and the receiver, e.g. to spawn the next stream with the same ones.
+    pub fn into_parts(self) -> (H, MessageReceiver<H::Message>) {
+        (self.handler, self.receiver)
+    }
+
+    fn deliver_messages(&mut self) {
+        let handler = &mut self.handler;
+        for message in self.receiver.drain() {
+            handler.message(message);
+        }
+    }
+}
+
+impl<H: RtMessageHandler> RtProcessHandler for WithMessages<H> {
+    fn init(&mut self, stream_info: &StreamInfo) {
+        self.handler.init(stream_info)
+    }
+
+    fn deinit(&mut self) {
+        self.handler.deinit()
+    }
+
+    fn process(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+        self.deliver_messages();
+        self.handler.process(proc_info)
+    }
+
+    fn process_offline(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+        self.deliver_messages();
+        self.handler.process_offline(proc_info)
+    }
+
+    fn sample_rate_changed(&mut self, stream_info: &StreamInfo) {
+        self.handler.sample_rate_changed(stream_info)
+    }
+
+    fn buffer_size_changed(&mut self, stream_info: &StreamInfo) {
+        self.handler.buffer_size_changed(stream_info)
+    }
+
+    fn busses_added(&mut self, stream_info: &StreamInfo) {
+        self.handler.busses_added(stream_info)
+    }
+}
+
+/// A stream spawned with `spawn_rt_thread_with_messages()` and the sender of its messages.
+pub type StreamWithMessages<H, E> = (
+    StreamHandle<WithMessages<H>, E>,
+    MessageSender<<H as RtMessageHandler>::Message>,
+);
+
+/// Spawn a stream like `spawn_rt_thread()`, along with a channel that holds up to
+/// `capacity` messages for the process handler.
+///
+/// The returned sender is for the non-realtime side (e.g. the UI thread). Every message
+/// that was sent is handed to `RtMessageHandler::message` at the top of the next process
+/// cycle, in the order they were sent.
+pub fn spawn_rt_thread_with_messages<H: RtMessageHandler, E: FatalErrorHandler>(
+    config: &crate::Config,
+    use_client_name: Option<String>,
+    rt_process_handler: H,
+    fatal_error_handler: E,
+    capacity: usize,
+) -> Result<StreamWithMessages<H, E>, SpawnError<WithMessages<H>>> {
+    let (sender, receiver) = message_channel(capacity);
+
+    let mut stream = spawn_rt_thread(
+        config,
+        use_client_name,
+        WithMessages::new(rt_process_handler, receiver),
+        fatal_error_handler,
+    )?;
+    stream.ring_buffers.message_channel = Some(Arc::clone(&sender.counters));
+
+    Ok((stream, sender))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AudioBusConfig, Config, FatalStreamError, PortMixing, DUMMY_DEVICE_NAME, DUMMY_SERVER_NAME,
+    };
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn full_channel_gives_messages_back() {
+        let (mut sender, mut receiver) = message_channel::<u32>(2);
+
+        assert_eq!(sender.send(1), Ok(()));
+        assert_eq!(sender.send(2), Ok(()));
+        assert_eq!(sender.send(3), Err(3));
+        assert_eq!(sender.free_len(), 0);
+        assert_eq!(sender.stats().overruns, 1);
+
+        assert_eq!(receiver.drain().collect::<Vec<u32>>(), vec![1, 2]);
+        assert!(receiver.is_empty());
+        assert_eq!(receiver.recv(), None);
+    }
+
+    struct GainHandler {
+        gain: Arc<AtomicU32>,
+    }
+
+    impl RtProcessHandler for GainHandler {
+        fn init(&mut self, _stream_info: &StreamInfo) {}
+
+        fn process(&mut self, _proc_info: ProcessInfo) -> ProcessControl {
+            ProcessControl::Continue
+        }
+    }
+
+    impl RtMessageHandler for GainHandler {
+        type Message = u32;
+
+        fn message(&mut self, gain: u32) {
+            self.gain.store(gain, Ordering::Relaxed);
+        }
+    }
+
+    struct NoopErrorHandler;
+
+    impl FatalErrorHandler for NoopErrorHandler {
+        fn fatal_stream_error(self, _error: FatalStreamError) {}
+    }
+
+    #[test]
+    fn messages_reach_the_handler() {
+        let config = Config {
+            audio_server: String::from(DUMMY_SERVER_NAME),
+            system_audio_device: String::from(DUMMY_DEVICE_NAME),
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Speakers Out"),
+                system_ports: vec![String::from("playback_1"), String::from("playback_2")],
+                ..Default::default()
+            }],
+            ..Config::default()
+        };
+
+        let gain = Arc::new(AtomicU32::new(0));
+        let (stream, mut sender) = spawn_rt_thread_with_messages(
+            &config,
+            None,
+            GainHandler {
+                gain: Arc::clone(&gain),
+            },
+            NoopErrorHandler,
+            4,
+        )
+        .unwrap();
+
+        sender.send(7).unwrap();
+
+        let start = Instant::now();
+        while gain.load(Ordering::Relaxed) != 7 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(stream.diagnostics().message_channel, Some(sender.stats()));
+
+        let (handler, receiver) = stream.stop().unwrap().into_parts();
+        assert!(Arc::ptr_eq(&handler.gain, &gain));
+        assert!(receiver.is_empty());
+    }
+}
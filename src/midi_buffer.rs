@@ -1,6 +1,18 @@
-// TODO: Increase message size to allow more complex midi messages?
-pub const MAX_MIDI_MSG_SIZE: usize = 8;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
+use crate::{DeviceIndex, MidiFilter};
+
+/// The maximum size of a single midi event in bytes.
+///
+/// This fits every channel and system message as well as most SysEx dumps. A longer SysEx
+/// message has to be split into several events, which `MidiParser` decodes as the parts of
+/// one message (see `SysExPart`). Jack can still drop an output event if it doesn't fit in
+/// the rest of the port's buffer for the cycle, in which case a warning is logged.
+pub const MAX_MIDI_MSG_SIZE: usize = 512;
+
+/// The number of events a midi controller buffer holds per cycle, unless
+/// `MidiControllerConfig::buffer_capacity` says otherwise.
 pub const MIDI_BUFFER_SIZE: usize = 1024;
 
 #[derive(Clone, Copy)]
@@ -10,7 +22,7 @@ pub struct RawMidi {
 
     data: [u8; MAX_MIDI_MSG_SIZE],
 
-    len: u8,
+    len: u16,
 }
 
 impl RawMidi {
@@ -19,7 +31,7 @@ impl RawMidi {
     /// * `delta_frames` - The amount of time passed, in frames, relative to the start of the process cycle.
     /// * `data` - The raw bytes of the midi message.
     ///
-    /// This returns an error if the length of `data` is greater than `MAX_MIDI_MSG_SIZE` (7).
+    /// This returns an error if the length of `data` is greater than `MAX_MIDI_MSG_SIZE`.
     pub fn new(delta_frames: u32, data: &[u8]) -> Result<Self, usize> {
         if data.len() <= MAX_MIDI_MSG_SIZE {
             let mut cp_data = [0; MAX_MIDI_MSG_SIZE];
@@ -28,7 +40,7 @@ impl RawMidi {
             Ok(Self {
                 delta_frames,
                 data: cp_data,
-                len: data.len() as u8,
+                len: data.len() as u16,
             })
         } else {
             Err(data.len())
@@ -67,18 +79,46 @@ impl Default for RawMidi {
 }
 
 pub struct MidiControllerBuffer {
-    events: [RawMidi; MIDI_BUFFER_SIZE],
+    /// Allocated up front with room for all of its events (`MIDI_BUFFER_SIZE` by default), so
+    /// pushing never allocates. This is too big to live on the stack.
+    events: Box<[RawMidi]>,
     len: usize,
+    filter: MidiFilter,
+    /// The number of input events that didn't fit, shared with the stream handle.
+    dropped: Arc<AtomicU64>,
 }
 
 impl MidiControllerBuffer {
     pub(crate) fn new() -> Self {
+        Self::for_input(MidiFilter::default(), None)
+    }
+
+    /// A buffer for a midi input controller, which drops the events `filter` doesn't pass
+    /// in `push_input()`.
+    ///
+    /// * `capacity` - The number of events it holds, or `None` for `MIDI_BUFFER_SIZE`.
+    pub(crate) fn for_input(filter: MidiFilter, capacity: Option<usize>) -> Self {
+        let capacity = capacity.unwrap_or(MIDI_BUFFER_SIZE);
+
         Self {
-            events: [RawMidi::default(); MIDI_BUFFER_SIZE],
+            events: vec![RawMidi::default(); capacity].into_boxed_slice(),
             len: 0,
+            filter,
+            dropped: Arc::new(AtomicU64::new(0)),
         }
     }
 
+    /// The number of events this buffer holds.
+    pub fn capacity(&self) -> usize {
+        self.events.len()
+    }
+
+    /// The counter of the input events that were dropped because they didn't fit (see
+    /// `push_input()`).
+    pub(crate) fn dropped_counter(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.dropped)
+    }
+
     pub fn events(&self) -> &[RawMidi] {
         &self.events[0..self.len]
     }
@@ -88,7 +128,7 @@ impl MidiControllerBuffer {
     }
 
     pub fn push(&mut self, event: RawMidi) -> Result<(), MidiBufferPushError> {
-        if self.len >= MIDI_BUFFER_SIZE {
+        if self.len >= self.capacity() {
             return Err(MidiBufferPushError::BufferFull);
         }
 
@@ -100,12 +140,12 @@ impl MidiControllerBuffer {
     }
 
     pub fn extend_from_slice(&mut self, events: &[RawMidi]) -> Result<(), MidiBufferPushError> {
-        if self.len >= MIDI_BUFFER_SIZE {
+        if self.len >= self.capacity() {
             return Err(MidiBufferPushError::BufferFull);
         }
 
         let total_len = self.len + events.len();
-        let len = total_len.min(MIDI_BUFFER_SIZE);
+        let len = total_len.min(self.capacity());
 
         &mut self.events[self.len..len].copy_from_slice(&events[0..len - self.len]);
 
@@ -119,7 +159,7 @@ impl MidiControllerBuffer {
     }
 
     pub fn push_raw(&mut self, delta_frames: u32, data: &[u8]) -> Result<(), MidiBufferPushError> {
-        if self.len >= MIDI_BUFFER_SIZE {
+        if self.len >= self.capacity() {
             return Err(MidiBufferPushError::BufferFull);
         }
 
@@ -135,15 +175,58 @@ impl MidiControllerBuffer {
         }
     }
 
-    /// Replaces the contents of this buffer with the contents of the given buffer.
+    /// Push an event that was received from the system port, unless the controller's
+    /// `MidiFilter` drops it.
+    ///
+    /// An event that doesn't fit is dropped and counted, and the error is returned.
+    pub(crate) fn push_input(
+        &mut self,
+        delta_frames: u32,
+        data: &[u8],
+    ) -> Result<(), MidiBufferPushError> {
+        if !self.filter.passes(data) {
+            return Ok(());
+        }
+
+        let result = self.push_raw(delta_frames, data);
+        if result.is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    /// Replaces the contents of this buffer with the contents of the given buffer, as far
+    /// as they fit.
     pub fn clear_and_copy_from(&mut self, buffer: &MidiControllerBuffer) {
-        self.len = buffer.len;
-        &mut self.events[0..buffer.len].copy_from_slice(&buffer.events[0..buffer.len]);
+        self.len = buffer.len.min(self.capacity());
+        self.events[0..self.len].copy_from_slice(&buffer.events[0..self.len]);
     }
 
     pub fn len(&self) -> usize {
         self.len
     }
+
+    /// Fill `order` with the indices of the events in time order. Events at the same time
+    /// keep the order they were pushed in.
+    ///
+    /// This doesn't allocate as long as `order` has at least the capacity of this buffer. The events are usually already in order, which this is fastest for.
+    #[cfg(any(test, target_os = "linux"))]
+    pub(crate) fn time_order(&self, order: &mut Vec<usize>) {
+        let events = self.events();
+
+        order.clear();
+        order.extend(0..events.len());
+
+        // Insertion sort, since it is stable and sorts in place.
+        for i in 1..order.len() {
+            let mut j = i;
+            while j > 0 && events[order[j - 1]].delta_frames > events[order[j]].delta_frames {
+                order.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+    }
 }
 
 impl std::fmt::Debug for MidiControllerBuffer {
@@ -157,6 +240,93 @@ impl std::fmt::Debug for MidiControllerBuffer {
     }
 }
 
+/// The events of several midi controllers merged in time order. This is returned by
+/// `ProcessInfo::midi_in_events_sorted()`.
+///
+/// Events at the same time are ordered by controller, and then by their order in the
+/// controller's buffer. This doesn't allocate, so it can be used on the realtime thread.
+pub struct SortedMidiEvents<'a> {
+    buffers: &'a [MidiControllerBuffer],
+
+    /// The (`delta_frames`, controller index, event index) of the last returned event.
+    last: Option<(u32, usize, usize)>,
+}
+
+impl<'a> SortedMidiEvents<'a> {
+    /// The events in each buffer must already be in time order, like they are from every
+    /// backend.
+    pub(crate) fn new(buffers: &'a [MidiControllerBuffer]) -> Self {
+        Self {
+            buffers,
+            last: None,
+        }
+    }
+}
+
+impl<'a> Iterator for SortedMidiEvents<'a> {
+    type Item = (DeviceIndex, &'a RawMidi);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<(u32, usize, usize)> = None;
+
+        for (controller_i, buffer) in self.buffers.iter().enumerate() {
+            let events = buffer.events();
+
+            // The first event of this controller that hasn't been returned yet.
+            let event_i = match self.last {
+                None => 0,
+                Some((_, last_controller_i, last_event_i)) if controller_i == last_controller_i => {
+                    last_event_i + 1
+                }
+                Some((last_delta, last_controller_i, _)) if controller_i < last_controller_i => {
+                    events.partition_point(|e| e.delta_frames <= last_delta)
+                }
+                Some((last_delta, _, _)) => events.partition_point(|e| e.delta_frames < last_delta),
+            };
+
+            if let Some(event) = events.get(event_i) {
+                let is_earlier = match next {
+                    Some((delta, _, _)) => event.delta_frames < delta,
+                    None => true,
+                };
+                if is_earlier {
+                    next = Some((event.delta_frames, controller_i, event_i));
+                }
+            }
+        }
+
+        self.last = next;
+
+        next.map(|(_, controller_i, event_i)| {
+            (
+                DeviceIndex::new(controller_i),
+                &self.buffers[controller_i].events()[event_i],
+            )
+        })
+    }
+}
+
+/// The counters of the midi input events each controller of a stream dropped because they
+/// didn't fit in its buffer, for `StreamHandle::midi_in_dropped_events()`.
+#[derive(Debug, Default)]
+pub(crate) struct MidiDropCounters {
+    controllers: Vec<(String, Arc<AtomicU64>)>,
+}
+
+impl MidiDropCounters {
+    pub fn push(&mut self, controller_id: &str, buffer: &MidiControllerBuffer) {
+        self.controllers
+            .push((String::from(controller_id), buffer.dropped_counter()));
+    }
+
+    pub fn dropped_events(&self, controller_id: &str) -> Option<u64> {
+        self.controllers
+            .iter()
+            .find(|(id, _)| id == controller_id)
+            .map(|(_, dropped)| dropped.load(Ordering::Relaxed))
+    }
+}
+
 #[derive(Debug)]
 pub enum MidiBufferPushError {
     /// The buffer is full.
@@ -164,6 +334,9 @@ pub enum MidiBufferPushError {
 
     /// The given midi event is too long.
     EventTooLong(usize),
+
+    /// The given `MidiEvent` can't be encoded, e.g. because a value is out of range.
+    InvalidEvent(&'static str),
 }
 
 impl std::error::Error for MidiBufferPushError {}
@@ -181,6 +354,101 @@ impl std::fmt::Display for MidiBufferPushError {
                     len, MAX_MIDI_MSG_SIZE,
                 )
             }
+            MidiBufferPushError::InvalidEvent(reason) => {
+                write!(f, "Invalid midi event: {}", reason)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(events: &[(u32, u8)]) -> MidiControllerBuffer {
+        let mut buffer = MidiControllerBuffer::new();
+        for (delta_frames, note) in events.iter() {
+            buffer.push_raw(*delta_frames, &[0x90, *note, 100]).unwrap();
+        }
+        buffer
+    }
+
+    #[test]
+    fn time_order_is_stable() {
+        let buffer = buffer(&[(10, 1), (0, 2), (10, 3), (5, 4), (0, 5)]);
+
+        let mut order = Vec::with_capacity(MIDI_BUFFER_SIZE);
+        buffer.time_order(&mut order);
+
+        let notes: Vec<u8> = order
+            .iter()
+            .map(|i| buffer.events()[*i].data()[1])
+            .collect();
+        assert_eq!(notes, vec![2, 5, 4, 1, 3]);
+    }
+
+    #[test]
+    fn large_sysex() {
+        let mut dump = vec![0xF0, 0x43, 0x00, 0x09];
+        dump.extend((0..300).map(|i| (i % 128) as u8));
+        dump.push(0xF7);
+
+        let mut buffer = MidiControllerBuffer::new();
+        buffer.push_raw(3, &dump).unwrap();
+        assert_eq!(buffer.events()[0].len(), dump.len());
+        assert_eq!(buffer.events()[0].data(), dump.as_slice());
+
+        let too_long = vec![0; MAX_MIDI_MSG_SIZE + 1];
+        assert!(matches!(
+            buffer.push_raw(4, &too_long),
+            Err(MidiBufferPushError::EventTooLong(len)) if len == MAX_MIDI_MSG_SIZE + 1
+        ));
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn sorted_events_interleave_controllers() {
+        let buffers = [
+            buffer(&[(0, 1), (10, 2), (10, 3), (40, 4)]),
+            buffer(&[]),
+            buffer(&[(5, 5), (10, 6), (50, 7)]),
+        ];
+
+        let events: Vec<(usize, u32, u8)> = SortedMidiEvents::new(&buffers)
+            .map(|(controller, event)| (controller.index(), event.delta_frames, event.data()[1]))
+            .collect();
+
+        assert_eq!(
+            events,
+            vec![
+                (0, 0, 1),
+                (2, 5, 5),
+                (0, 10, 2),
+                (0, 10, 3),
+                (2, 10, 6),
+                (0, 40, 4),
+                (2, 50, 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn full_input_buffers_count_drops() {
+        let mut buffer = MidiControllerBuffer::for_input(MidiFilter::default(), Some(2));
+        let mut counters = MidiDropCounters::default();
+        counters.push("Keys", &buffer);
+
+        for note in 0..5 {
+            let _ = buffer.push_input(0, &[0x90, note, 100]);
         }
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.capacity(), 2);
+        assert_eq!(counters.dropped_events("Keys"), Some(3));
+        assert_eq!(counters.dropped_events("Pads"), None);
+
+        // Clearing the buffer for the next cycle doesn't reset the counter.
+        buffer.clear();
+        buffer.push_input(0, &[0x90, 60, 100]).unwrap();
+        assert_eq!(counters.dropped_events("Keys"), Some(3));
     }
 }
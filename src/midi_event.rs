@@ -0,0 +1,678 @@
+use crate::{MidiBufferPushError, MidiControllerBuffer, RawMidi, MAX_MIDI_MSG_SIZE};
+
+/// A decoded midi message. Use `MidiParser` (or `MidiControllerBuffer::parsed_events()`)
+/// to decode raw midi bytes.
+///
+/// Channels are in the range `0..16`, and every other value is in the range `0..128`
+/// unless noted otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiEvent<'a> {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    /// Many devices send a note on with a velocity of zero instead of a note off. Those
+    /// aren't turned into `NoteOff`s.
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    /// Polyphonic (per-note) aftertouch.
+    PolyAftertouch {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    /// Channel aftertouch.
+    Aftertouch {
+        channel: u8,
+        pressure: u8,
+    },
+    /// The pitch bend wheel, in the range `0..16384`. The center is 8192.
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+
+    /// A system exclusive message, or a part of one if it spans several events.
+    SysEx {
+        /// The bytes of this part, without the leading `0xF0` and the trailing `0xF7`.
+        data: &'a [u8],
+        part: SysExPart,
+    },
+
+    MtcQuarterFrame(u8),
+    /// The song position in midi beats (sixteenth notes), in the range `0..16384`.
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+
+    /// Bytes that don't form a valid message, e.g. data bytes without a status byte or a
+    /// message that was cut short.
+    Unknown(&'a [u8]),
+}
+
+impl<'a> MidiEvent<'a> {
+    /// Encode this message as a raw midi event, e.g. to push it to an output buffer
+    /// without building the bytes by hand. This never allocates.
+    ///
+    /// This fails with `MidiBufferPushError::InvalidEvent` if a value is out of range or
+    /// this is an `Unknown` event, so the result is always a well formed message.
+    pub fn to_raw(&self, delta_frames: u32) -> Result<RawMidi, MidiBufferPushError> {
+        let mut data = [0u8; MAX_MIDI_MSG_SIZE];
+        let len = self.encode(&mut data)?;
+        RawMidi::new(delta_frames, &data[..len]).map_err(MidiBufferPushError::EventTooLong)
+    }
+
+    fn encode(&self, data: &mut [u8; MAX_MIDI_MSG_SIZE]) -> Result<usize, MidiBufferPushError> {
+        let mut put = |bytes: &[u8]| {
+            data[..bytes.len()].copy_from_slice(bytes);
+            Ok(bytes.len())
+        };
+
+        match *self {
+            MidiEvent::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => put(&[status(0x80, channel)?, u7(note)?, u7(velocity)?]),
+            MidiEvent::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => put(&[status(0x90, channel)?, u7(note)?, u7(velocity)?]),
+            MidiEvent::PolyAftertouch {
+                channel,
+                note,
+                pressure,
+            } => put(&[status(0xA0, channel)?, u7(note)?, u7(pressure)?]),
+            MidiEvent::ControlChange {
+                channel,
+                controller,
+                value,
+            } => put(&[status(0xB0, channel)?, u7(controller)?, u7(value)?]),
+            MidiEvent::ProgramChange { channel, program } => {
+                put(&[status(0xC0, channel)?, u7(program)?])
+            }
+            MidiEvent::Aftertouch { channel, pressure } => {
+                put(&[status(0xD0, channel)?, u7(pressure)?])
+            }
+            MidiEvent::PitchBend { channel, value } => {
+                let (lsb, msb) = split_u14(value)?;
+                put(&[status(0xE0, channel)?, lsb, msb])
+            }
+
+            MidiEvent::SysEx { data: bytes, part } => {
+                if bytes.iter().any(|b| *b >= 0x80) {
+                    return Err(MidiBufferPushError::InvalidEvent(
+                        "system exclusive data bytes must be below 0x80",
+                    ));
+                }
+
+                let start = matches!(part, SysExPart::Complete | SysExPart::Start);
+                let end = matches!(part, SysExPart::Complete | SysExPart::End);
+                let len = bytes.len() + usize::from(start) + usize::from(end);
+                if len > MAX_MIDI_MSG_SIZE {
+                    return Err(MidiBufferPushError::EventTooLong(len));
+                }
+
+                if start {
+                    data[0] = 0xF0;
+                }
+                let offset = usize::from(start);
+                data[offset..offset + bytes.len()].copy_from_slice(bytes);
+                if end {
+                    data[len - 1] = 0xF7;
+                }
+                Ok(len)
+            }
+
+            MidiEvent::MtcQuarterFrame(value) => put(&[0xF1, u7(value)?]),
+            MidiEvent::SongPosition(position) => {
+                let (lsb, msb) = split_u14(position)?;
+                put(&[0xF2, lsb, msb])
+            }
+            MidiEvent::SongSelect(song) => put(&[0xF3, u7(song)?]),
+            MidiEvent::TuneRequest => put(&[0xF6]),
+
+            MidiEvent::Clock => put(&[0xF8]),
+            MidiEvent::Start => put(&[0xFA]),
+            MidiEvent::Continue => put(&[0xFB]),
+            MidiEvent::Stop => put(&[0xFC]),
+            MidiEvent::ActiveSensing => put(&[0xFE]),
+            MidiEvent::Reset => put(&[0xFF]),
+
+            MidiEvent::Unknown(_) => Err(MidiBufferPushError::InvalidEvent(
+                "unknown events can't be encoded, push the raw bytes instead",
+            )),
+        }
+    }
+}
+
+fn status(kind: u8, channel: u8) -> Result<u8, MidiBufferPushError> {
+    if channel < 16 {
+        Ok(kind | channel)
+    } else {
+        Err(MidiBufferPushError::InvalidEvent(
+            "the channel must be below 16",
+        ))
+    }
+}
+
+fn u7(value: u8) -> Result<u8, MidiBufferPushError> {
+    if value < 0x80 {
+        Ok(value)
+    } else {
+        Err(MidiBufferPushError::InvalidEvent(
+            "data values must be below 128",
+        ))
+    }
+}
+
+fn split_u14(value: u16) -> Result<(u8, u8), MidiBufferPushError> {
+    if value < 0x4000 {
+        Ok(((value & 0x7F) as u8, (value >> 7) as u8))
+    } else {
+        Err(MidiBufferPushError::InvalidEvent(
+            "14 bit values must be below 16384",
+        ))
+    }
+}
+
+/// Which part of a system exclusive message a `MidiEvent::SysEx` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysExPart {
+    /// The whole message.
+    Complete,
+    /// The start of a message that continues in the next events.
+    Start,
+    /// The middle of a message.
+    Continue,
+    /// The end of a message.
+    End,
+}
+
+/// Decodes raw midi messages one event at a time.
+///
+/// This keeps track of running status (channel messages that leave out the status byte
+/// when it is the same as that of the last message), and of system exclusive messages that
+/// span several events. Feed it every event of a single controller, in order. It never
+/// allocates, so it can be used on the realtime thread.
+#[derive(Debug, Clone, Default)]
+pub struct MidiParser {
+    running_status: Option<u8>,
+    in_sysex: bool,
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode the message in `data`. Only the first message is decoded if `data` contains
+    /// more than one.
+    pub fn parse<'a>(&mut self, data: &'a [u8]) -> MidiEvent<'a> {
+        let status = match data.first() {
+            Some(status) => *status,
+            None => return MidiEvent::Unknown(data),
+        };
+
+        if status >= 0xF8 {
+            // Realtime messages can appear anywhere, even in the middle of a system
+            // exclusive message, and don't change any state.
+            return match status {
+                0xF8 => MidiEvent::Clock,
+                0xFA => MidiEvent::Start,
+                0xFB => MidiEvent::Continue,
+                0xFC => MidiEvent::Stop,
+                0xFE => MidiEvent::ActiveSensing,
+                0xFF => MidiEvent::Reset,
+                _ => MidiEvent::Unknown(data),
+            };
+        }
+
+        if status < 0x80 {
+            if self.in_sysex {
+                return self.sysex_continuation(data);
+            }
+
+            return match self.running_status {
+                Some(running_status) => {
+                    channel_message(running_status, data).unwrap_or(MidiEvent::Unknown(data))
+                }
+                None => MidiEvent::Unknown(data),
+            };
+        }
+
+        // Any other status byte ends a system exclusive message that wasn't terminated.
+        self.in_sysex = false;
+
+        if status < 0xF0 {
+            self.running_status = Some(status);
+            return channel_message(status, &data[1..]).unwrap_or(MidiEvent::Unknown(data));
+        }
+
+        // System common messages cancel running status.
+        self.running_status = None;
+
+        match status {
+            0xF0 => {
+                let data = &data[1..];
+                match data.iter().position(|b| *b == 0xF7) {
+                    Some(end) => MidiEvent::SysEx {
+                        data: &data[..end],
+                        part: SysExPart::Complete,
+                    },
+                    None => {
+                        self.in_sysex = true;
+                        MidiEvent::SysEx {
+                            data,
+                            part: SysExPart::Start,
+                        }
+                    }
+                }
+            }
+            0xF1 => match data.get(1) {
+                Some(value) => MidiEvent::MtcQuarterFrame(*value),
+                None => MidiEvent::Unknown(data),
+            },
+            0xF2 => match (data.get(1), data.get(2)) {
+                (Some(lsb), Some(msb)) => MidiEvent::SongPosition(u14(*lsb, *msb)),
+                _ => MidiEvent::Unknown(data),
+            },
+            0xF3 => match data.get(1) {
+                Some(song) => MidiEvent::SongSelect(*song),
+                None => MidiEvent::Unknown(data),
+            },
+            0xF6 => MidiEvent::TuneRequest,
+            _ => MidiEvent::Unknown(data),
+        }
+    }
+
+    fn sysex_continuation<'a>(&mut self, data: &'a [u8]) -> MidiEvent<'a> {
+        match data.iter().position(|b| *b == 0xF7) {
+            Some(end) => {
+                self.in_sysex = false;
+                MidiEvent::SysEx {
+                    data: &data[..end],
+                    part: SysExPart::End,
+                }
+            }
+            None => MidiEvent::SysEx {
+                data,
+                part: SysExPart::Continue,
+            },
+        }
+    }
+}
+
+/// Decode a channel message from its status byte and the data bytes that follow it.
+fn channel_message(status: u8, data: &[u8]) -> Option<MidiEvent<'_>> {
+    let channel = status & 0x0F;
+
+    let event = match status & 0xF0 {
+        0x80 => MidiEvent::NoteOff {
+            channel,
+            note: *data.first()?,
+            velocity: *data.get(1)?,
+        },
+        0x90 => MidiEvent::NoteOn {
+            channel,
+            note: *data.first()?,
+            velocity: *data.get(1)?,
+        },
+        0xA0 => MidiEvent::PolyAftertouch {
+            channel,
+            note: *data.first()?,
+            pressure: *data.get(1)?,
+        },
+        0xB0 => MidiEvent::ControlChange {
+            channel,
+            controller: *data.first()?,
+            value: *data.get(1)?,
+        },
+        0xC0 => MidiEvent::ProgramChange {
+            channel,
+            program: *data.first()?,
+        },
+        0xD0 => MidiEvent::Aftertouch {
+            channel,
+            pressure: *data.first()?,
+        },
+        0xE0 => MidiEvent::PitchBend {
+            channel,
+            value: u14(*data.first()?, *data.get(1)?),
+        },
+        _ => return None,
+    };
+
+    Some(event)
+}
+
+fn u14(lsb: u8, msb: u8) -> u16 {
+    u16::from(lsb & 0x7F) | (u16::from(msb & 0x7F) << 7)
+}
+
+/// The events of a `MidiControllerBuffer` along with their decoded messages. This is
+/// returned by `MidiControllerBuffer::parsed_events()`.
+pub struct ParsedMidiEvents<'a> {
+    events: std::slice::Iter<'a, RawMidi>,
+    parser: MidiParser,
+}
+
+impl<'a> Iterator for ParsedMidiEvents<'a> {
+    type Item = (&'a RawMidi, MidiEvent<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = self.events.next()?;
+        Some((event, self.parser.parse(event.data())))
+    }
+}
+
+impl MidiControllerBuffer {
+    /// Every event in this buffer along with its decoded message. The raw event gives
+    /// access to `delta_frames` and the raw bytes.
+    ///
+    /// This starts with a fresh `MidiParser` every time. Use a `MidiParser` directly to
+    /// keep running status and unfinished system exclusive messages across process cycles.
+    pub fn parsed_events(&self) -> ParsedMidiEvents<'_> {
+        ParsedMidiEvents {
+            events: self.events().iter(),
+            parser: MidiParser::new(),
+        }
+    }
+
+    /// Encode `event` (see `MidiEvent::to_raw()`) and push it to the end of this buffer.
+    pub fn push_event(
+        &mut self,
+        delta_frames: u32,
+        event: MidiEvent<'_>,
+    ) -> Result<(), MidiBufferPushError> {
+        if self.len() >= self.capacity() {
+            return Err(MidiBufferPushError::BufferFull);
+        }
+
+        self.push(event.to_raw(delta_frames)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_messages() {
+        let mut parser = MidiParser::new();
+
+        assert_eq!(
+            parser.parse(&[0x93, 60, 100]),
+            MidiEvent::NoteOn {
+                channel: 3,
+                note: 60,
+                velocity: 100
+            }
+        );
+        assert_eq!(
+            parser.parse(&[0x80, 60, 0]),
+            MidiEvent::NoteOff {
+                channel: 0,
+                note: 60,
+                velocity: 0
+            }
+        );
+        assert_eq!(
+            parser.parse(&[0xBF, 7, 127]),
+            MidiEvent::ControlChange {
+                channel: 15,
+                controller: 7,
+                value: 127
+            }
+        );
+        assert_eq!(
+            parser.parse(&[0xC1, 5]),
+            MidiEvent::ProgramChange {
+                channel: 1,
+                program: 5
+            }
+        );
+        assert_eq!(
+            parser.parse(&[0xD2, 64]),
+            MidiEvent::Aftertouch {
+                channel: 2,
+                pressure: 64
+            }
+        );
+        assert_eq!(
+            parser.parse(&[0xE0, 0x00, 0x40]),
+            MidiEvent::PitchBend {
+                channel: 0,
+                value: 8192
+            }
+        );
+        assert_eq!(parser.parse(&[0x90, 60]), MidiEvent::Unknown(&[0x90, 60]));
+    }
+
+    #[test]
+    fn running_status() {
+        let mut parser = MidiParser::new();
+
+        // Data bytes without any status byte before them.
+        assert_eq!(parser.parse(&[60, 100]), MidiEvent::Unknown(&[60, 100]));
+
+        parser.parse(&[0x91, 60, 100]);
+        assert_eq!(
+            parser.parse(&[62, 90]),
+            MidiEvent::NoteOn {
+                channel: 1,
+                note: 62,
+                velocity: 90
+            }
+        );
+
+        // Realtime messages don't cancel running status.
+        assert_eq!(parser.parse(&[0xF8]), MidiEvent::Clock);
+        assert_eq!(
+            parser.parse(&[64, 0]),
+            MidiEvent::NoteOn {
+                channel: 1,
+                note: 64,
+                velocity: 0
+            }
+        );
+
+        // System common messages do.
+        assert_eq!(parser.parse(&[0xF3, 2]), MidiEvent::SongSelect(2));
+        assert_eq!(parser.parse(&[64, 0]), MidiEvent::Unknown(&[64, 0]));
+    }
+
+    #[test]
+    fn sysex_spanning_events() {
+        let mut parser = MidiParser::new();
+
+        assert_eq!(
+            parser.parse(&[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7]),
+            MidiEvent::SysEx {
+                data: &[0x7E, 0x7F, 0x06, 0x01],
+                part: SysExPart::Complete
+            }
+        );
+
+        assert_eq!(
+            parser.parse(&[0xF0, 0x43, 0x10]),
+            MidiEvent::SysEx {
+                data: &[0x43, 0x10],
+                part: SysExPart::Start
+            }
+        );
+        // A realtime message in the middle of the dump.
+        assert_eq!(parser.parse(&[0xF8]), MidiEvent::Clock);
+        assert_eq!(
+            parser.parse(&[0x4C, 0x00, 0x00]),
+            MidiEvent::SysEx {
+                data: &[0x4C, 0x00, 0x00],
+                part: SysExPart::Continue
+            }
+        );
+        assert_eq!(
+            parser.parse(&[0x7E, 0xF7]),
+            MidiEvent::SysEx {
+                data: &[0x7E],
+                part: SysExPart::End
+            }
+        );
+
+        // An unterminated message is ended by the next status byte.
+        parser.parse(&[0xF0, 0x43]);
+        assert_eq!(
+            parser.parse(&[0xB0, 64, 127]),
+            MidiEvent::ControlChange {
+                channel: 0,
+                controller: 64,
+                value: 127
+            }
+        );
+    }
+
+    #[test]
+    fn parsed_buffer_events() {
+        let mut buffer = MidiControllerBuffer::new();
+        buffer.push_raw(0, &[0x90, 60, 100]).unwrap();
+        buffer.push_raw(12, &[62, 100]).unwrap();
+        buffer.push_raw(30, &[0xF2, 0x10, 0x01]).unwrap();
+
+        let events: Vec<(u32, MidiEvent)> = buffer
+            .parsed_events()
+            .map(|(raw, event)| (raw.delta_frames, event))
+            .collect();
+
+        assert_eq!(
+            events,
+            vec![
+                (
+                    0,
+                    MidiEvent::NoteOn {
+                        channel: 0,
+                        note: 60,
+                        velocity: 100
+                    }
+                ),
+                (
+                    12,
+                    MidiEvent::NoteOn {
+                        channel: 0,
+                        note: 62,
+                        velocity: 100
+                    }
+                ),
+                (30, MidiEvent::SongPosition(144)),
+            ]
+        );
+    }
+
+    #[test]
+    fn encoded_events_parse_back() {
+        let events = [
+            MidiEvent::NoteOn {
+                channel: 9,
+                note: 36,
+                velocity: 127,
+            },
+            MidiEvent::PitchBend {
+                channel: 0,
+                value: 16383,
+            },
+            MidiEvent::SysEx {
+                data: &[0x7E, 0x7F, 0x06, 0x01],
+                part: SysExPart::Complete,
+            },
+            MidiEvent::SongPosition(300),
+            MidiEvent::Clock,
+        ];
+
+        let mut buffer = MidiControllerBuffer::new();
+        for (i, event) in events.iter().enumerate() {
+            buffer.push_event(i as u32, *event).unwrap();
+        }
+
+        assert_eq!(buffer.events()[0].data(), &[0x99, 36, 127]);
+        assert_eq!(buffer.events()[1].data(), &[0xE0, 0x7F, 0x7F]);
+        let parsed: Vec<MidiEvent> = buffer.parsed_events().map(|(_, event)| event).collect();
+        assert_eq!(parsed, events);
+
+        let mut parser = MidiParser::new();
+        let start = MidiEvent::SysEx {
+            data: &[0x43, 0x10],
+            part: SysExPart::Start,
+        };
+        let end = MidiEvent::SysEx {
+            data: &[0x7E],
+            part: SysExPart::End,
+        };
+        assert_eq!(start.to_raw(0).unwrap().data(), &[0xF0, 0x43, 0x10]);
+        assert_eq!(end.to_raw(0).unwrap().data(), &[0x7E, 0xF7]);
+        assert_eq!(parser.parse(start.to_raw(0).unwrap().data()), start);
+        assert_eq!(parser.parse(end.to_raw(0).unwrap().data()), end);
+    }
+
+    #[test]
+    fn invalid_events_are_rejected() {
+        let invalid = [
+            MidiEvent::NoteOn {
+                channel: 16,
+                note: 60,
+                velocity: 100,
+            },
+            MidiEvent::ControlChange {
+                channel: 0,
+                controller: 128,
+                value: 0,
+            },
+            MidiEvent::PitchBend {
+                channel: 0,
+                value: 16384,
+            },
+            MidiEvent::SysEx {
+                data: &[0x43, 0xF7],
+                part: SysExPart::Complete,
+            },
+            MidiEvent::Unknown(&[0x90, 60]),
+        ];
+
+        let mut buffer = MidiControllerBuffer::new();
+        for event in invalid.iter() {
+            assert!(matches!(
+                buffer.push_event(0, *event),
+                Err(MidiBufferPushError::InvalidEvent(_))
+            ));
+        }
+        assert_eq!(buffer.len(), 0);
+
+        let dump = [0u8; MAX_MIDI_MSG_SIZE - 1];
+        let too_long = MidiEvent::SysEx {
+            data: &dump,
+            part: SysExPart::Complete,
+        };
+        assert!(matches!(
+            buffer.push_event(0, too_long),
+            Err(MidiBufferPushError::EventTooLong(len)) if len == MAX_MIDI_MSG_SIZE + 1
+        ));
+    }
+}
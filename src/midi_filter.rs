@@ -0,0 +1,80 @@
+use std::ops::RangeInclusive;
+
+/// The midi messages a midi input controller drops before they reach its
+/// `MidiControllerBuffer`, e.g. the active sensing and clock messages some hardware sends
+/// many times per cycle. The default passes every message.
+///
+/// This is plain data so it can be saved with the config. Anything more specific can be
+/// filtered in `RtProcessHandler::process`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct MidiFilter {
+    /// Drop the system realtime messages (0xF8 to 0xFF): clock, start, continue, stop,
+    /// active sensing and reset.
+    pub drop_realtime: bool,
+
+    /// Drop system exclusive messages.
+    pub drop_sysex: bool,
+
+    /// Drop the channel messages (notes, controllers, pitch bend, ...) of every channel whose
+    /// bit is set, bit 0 for the first channel. See `drop_channels()`.
+    pub dropped_channels: u16,
+}
+
+impl MidiFilter {
+    /// Also drop the channel messages of `channels`, counting the first channel as 0.
+    /// Channels above 15 are ignored.
+    pub fn drop_channels(mut self, channels: RangeInclusive<u8>) -> Self {
+        for channel in channels.filter(|channel| *channel < 16) {
+            self.dropped_channels |= 1 << channel;
+        }
+        self
+    }
+
+    /// Returns `true` if the message in `data` passes the filter. Data that doesn't start
+    /// with a status byte (a running status or a sysex continuation) is always passed.
+    pub fn passes(&self, data: &[u8]) -> bool {
+        match data.first() {
+            Some(0xF8..=0xFF) => !self.drop_realtime,
+            Some(0xF0) => !self.drop_sysex,
+            Some(status @ 0x80..=0xEF) => self.dropped_channels & (1 << (status & 0x0F)) == 0,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_messages() {
+        let clock = [0xF8];
+        let active_sensing = [0xFE];
+        let sysex = [0xF0, 0x7E, 0x7F, 0xF7];
+        let note_on_1 = [0x90, 60, 100];
+        let note_on_10 = [0x99, 36, 100];
+        let song_position = [0xF2, 0, 0];
+
+        let pass_all = MidiFilter::default();
+        for data in [&clock[..], &sysex, &note_on_1, &note_on_10, &[]].iter() {
+            assert!(pass_all.passes(data));
+        }
+
+        let filter = MidiFilter {
+            drop_realtime: true,
+            drop_sysex: true,
+            ..MidiFilter::default()
+        }
+        .drop_channels(9..=20);
+        assert_eq!(filter.dropped_channels, 0xFE00);
+
+        assert!(!filter.passes(&clock));
+        assert!(!filter.passes(&active_sensing));
+        assert!(!filter.passes(&sysex));
+        assert!(!filter.passes(&note_on_10));
+        assert!(filter.passes(&note_on_1));
+        assert!(filter.passes(&song_position));
+    }
+}
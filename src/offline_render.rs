@@ -0,0 +1,343 @@
+use log::info;
+use std::time::Instant;
+
+use crate::denormals::DenormalGuard;
+use crate::{
+    AudioBus, AudioBusBuffer, AudioBusConfig, Config, DeviceIndex, MidiController,
+    MidiControllerBuffer, MidiControllerConfig, OfflineRenderError, ProcessControl, ProcessInfo,
+    RtProcessHandler, SampleFormat, SpawnRtThreadError, StreamInfo,
+};
+
+/// The sample rate used for offline rendering when `Config::sample_rate` is `None`.
+pub const DEFAULT_OFFLINE_SAMPLE_RATE: u32 = 48000;
+
+/// The name of the "server" in the `StreamInfo` of an offline render.
+const OFFLINE_SERVER_NAME: &str = "Offline";
+
+/// Render `total_frames` frames as fast as possible, without an audio server or device.
+///
+/// This calls `RtProcessHandler::init` once and then `RtProcessHandler::process_offline`
+/// repeatedly on the calling thread with blocks of `block_size` frames (the last block may be
+/// shorter) until `total_frames` frames have been processed, and finally
+/// `RtProcessHandler::deinit`. The audio inputs are silent and the midi inputs are empty.
+///
+/// If `process_offline` returns `ProcessControl::Quit`, the render stops after that block
+/// and every channel of `audio_out` is cut to the frames that were rendered.
+///
+/// The audio output busses are written into `audio_out`. There must be one entry per bus in
+/// `config.audio_out_busses`, each with one `Vec` per channel. Every channel is resized to
+/// `total_frames`.
+///
+/// The system ports in `config` are only used to determine the number of channels of each
+/// bus, so they don't have to exist on the current system. The output only depends on the
+/// config and the process handler, so rendering the same project twice gives the same result.
+pub fn render_offline<P: RtProcessHandler>(
+    config: &Config,
+    rt_process_handler: &mut P,
+    total_frames: usize,
+    block_size: u32,
+    audio_out: &mut [Vec<Vec<f32>>],
+) -> Result<StreamInfo, OfflineRenderError> {
+    let config = &*crate::spawn_config(config)?;
+
+    if block_size == 0 {
+        return Err(OfflineRenderError::InvalidBlockSize);
+    }
+
+    let stream_info = StreamInfo {
+        server_name: String::from(OFFLINE_SERVER_NAME),
+        audio_in: offline_busses(&config.audio_in_busses)?,
+        audio_out: offline_busses(&config.audio_out_busses)?,
+        midi_in: offline_controllers(config, &config.midi_in_controllers),
+        midi_out: offline_controllers(config, &config.midi_out_controllers),
+        sample_rate: config.sample_rate.unwrap_or(DEFAULT_OFFLINE_SAMPLE_RATE),
+        max_audio_buffer_size: block_size,
+        latency_frames_in: 0,
+        latency_frames_out: 0,
+        sample_format: SampleFormat::F32,
+    };
+
+    if audio_out.len() != stream_info.audio_out.len() {
+        return Err(OfflineRenderError::OutputBusCountMismatch {
+            expected: stream_info.audio_out.len(),
+            got: audio_out.len(),
+        });
+    }
+    for (bus, out) in stream_info.audio_out.iter().zip(audio_out.iter()) {
+        if out.len() != usize::from(bus.channels) {
+            return Err(OfflineRenderError::OutputChannelCountMismatch {
+                bus_id: bus.id_name.clone(),
+                expected: usize::from(bus.channels),
+                got: out.len(),
+            });
+        }
+    }
+
+    info!(
+        "Rendering {} frames offline. Sample rate: {}, Block size: {}",
+        total_frames, stream_info.sample_rate, block_size
+    );
+
+    for channel in audio_out.iter_mut().flatten() {
+        channel.clear();
+        channel.resize(total_frames, 0.0);
+    }
+
+    rt_process_handler.init(&stream_info);
+
+    let mut audio_in_buffers = Vec::<AudioBusBuffer>::new();
+    let mut audio_out_buffers = Vec::<AudioBusBuffer>::new();
+    let mut midi_in_buffers = Vec::<MidiControllerBuffer>::new();
+    let mut midi_out_buffers = Vec::<MidiControllerBuffer>::new();
+
+    for bus in stream_info.audio_in.iter() {
+        audio_in_buffers.push(AudioBusBuffer::for_bus(bus, block_size))
+    }
+    for bus in stream_info.audio_out.iter() {
+        audio_out_buffers.push(AudioBusBuffer::for_bus(bus, block_size))
+    }
+    for _ in stream_info.midi_in.iter() {
+        midi_in_buffers.push(MidiControllerBuffer::new())
+    }
+    for _ in stream_info.midi_out.iter() {
+        midi_out_buffers.push(MidiControllerBuffer::new())
+    }
+
+    let mut frames_processed = 0;
+    while frames_processed < total_frames {
+        let audio_frames = (total_frames - frames_processed).min(block_size as usize);
+
+        for audio_buffer in audio_in_buffers.iter_mut() {
+            audio_buffer.clear_and_resize(audio_frames);
+        }
+        for audio_buffer in audio_out_buffers.iter_mut() {
+            audio_buffer.clear_and_resize(audio_frames);
+        }
+        for midi_buffer in midi_in_buffers.iter_mut() {
+            midi_buffer.clear();
+        }
+        for midi_buffer in midi_out_buffers.iter_mut() {
+            midi_buffer.clear();
+        }
+
+        let _denormals = DenormalGuard::new(!config.allow_denormals);
+        let control = rt_process_handler.process_offline(ProcessInfo {
+            audio_in: audio_in_buffers.as_slice(),
+            audio_out: audio_out_buffers.as_mut_slice(),
+            audio_frames,
+
+            midi_in: midi_in_buffers.as_slice(),
+            midi_out: midi_out_buffers.as_mut_slice(),
+
+            sample_rate: stream_info.sample_rate,
+            transport: None,
+            cycle_start_frame: frames_processed as u64,
+            cycle_start_time: Instant::now(),
+        });
+
+        // Copy processed data to the caller's buffers.
+
+        for (audio_buffer, out) in audio_out_buffers.iter().zip(audio_out.iter_mut()) {
+            for (channel, out_channel) in audio_buffer.channels().iter().zip(out.iter_mut()) {
+                out_channel[frames_processed..frames_processed + audio_frames]
+                    .copy_from_slice(&channel[0..audio_frames]);
+            }
+        }
+
+        frames_processed += audio_frames;
+
+        if control == ProcessControl::Quit {
+            info!(
+                "The process handler stopped the render after {} frames",
+                frames_processed
+            );
+            for channel in audio_out.iter_mut().flatten() {
+                channel.truncate(frames_processed);
+            }
+            break;
+        }
+    }
+
+    rt_process_handler.deinit();
+
+    Ok(stream_info)
+}
+
+fn offline_busses(busses: &[AudioBusConfig]) -> Result<Vec<AudioBus>, OfflineRenderError> {
+    let mut audio_busses = Vec::<AudioBus>::new();
+    for (bus_i, bus) in busses.iter().enumerate() {
+        if bus.system_ports.is_empty() {
+            return Err(SpawnRtThreadError::NoSystemPortsGiven(bus.id.clone()).into());
+        }
+
+        let channel_layout = bus.channel_layout();
+        let num_channels = channel_layout.num_channels() as u16;
+        audio_busses.push(AudioBus {
+            id_name: bus.id.clone(),
+            id_index: DeviceIndex::new(bus_i),
+            system_device: String::from(OFFLINE_SERVER_NAME),
+            system_half_duplex_device: None,
+            system_ports: bus.system_ports.clone(),
+            client_ports: Vec::new(),
+            channels: num_channels,
+            channel_layout,
+            connected_ports: num_channels,
+        });
+    }
+
+    Ok(audio_busses)
+}
+
+fn offline_controllers(
+    config: &Config,
+    controllers: &[MidiControllerConfig],
+) -> Vec<MidiController> {
+    if !config.midi_enabled() {
+        return Vec::new();
+    }
+
+    controllers
+        .iter()
+        .enumerate()
+        .map(|(controller_i, controller)| MidiController {
+            id_name: controller.id.clone(),
+            id_index: DeviceIndex::new(controller_i),
+            system_port: controller.system_port.clone(),
+            client_port: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a ramp that continues across blocks, and records the block sizes.
+    struct RampHandler {
+        next: f32,
+        blocks: Vec<usize>,
+        quit_after_blocks: Option<usize>,
+    }
+
+    impl RtProcessHandler for RampHandler {
+        fn init(&mut self, stream_info: &StreamInfo) {
+            assert_eq!(stream_info.max_audio_buffer_size, 100);
+            self.next = 0.0;
+        }
+
+        fn process(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+            self.blocks.push(proc_info.audio_frames);
+
+            for frame in 0..proc_info.audio_frames {
+                proc_info.audio_out[0][0][frame] = self.next;
+                proc_info.audio_out[0][1][frame] = -self.next;
+                self.next += 1.0;
+            }
+
+            if Some(self.blocks.len()) == self.quit_after_blocks {
+                ProcessControl::Quit
+            } else {
+                ProcessControl::Continue
+            }
+        }
+    }
+
+    fn test_config() -> Config {
+        Config {
+            audio_server: String::from("Jack"),
+            system_audio_device: String::from("Jack"),
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Master Out"),
+                system_ports: vec![
+                    String::from("system:playback_1"),
+                    String::from("system:playback_2"),
+                ],
+                ..Default::default()
+            }],
+            ..Config::default()
+        }
+    }
+
+    fn render() -> (Vec<Vec<Vec<f32>>>, Vec<usize>) {
+        let mut handler = RampHandler {
+            next: 0.0,
+            blocks: Vec::new(),
+            quit_after_blocks: None,
+        };
+        let mut audio_out = vec![vec![Vec::new(), Vec::new()]];
+
+        let stream_info =
+            render_offline(&test_config(), &mut handler, 250, 100, &mut audio_out).unwrap();
+        assert_eq!(stream_info.sample_rate, DEFAULT_OFFLINE_SAMPLE_RATE);
+
+        (audio_out, handler.blocks)
+    }
+
+    #[test]
+    fn renders_every_frame() {
+        let (audio_out, blocks) = render();
+
+        assert_eq!(blocks, vec![100, 100, 50]);
+        assert_eq!(audio_out[0][0].len(), 250);
+        assert!(audio_out[0][0]
+            .iter()
+            .enumerate()
+            .all(|(i, s)| *s == i as f32));
+        assert!(audio_out[0][1]
+            .iter()
+            .enumerate()
+            .all(|(i, s)| *s == -(i as f32)));
+
+        // Rendering again gives the exact same output.
+        assert_eq!(render(), (audio_out, blocks));
+    }
+
+    #[test]
+    fn handler_can_end_the_render() {
+        let mut handler = RampHandler {
+            next: 0.0,
+            blocks: Vec::new(),
+            quit_after_blocks: Some(2),
+        };
+        let mut audio_out = vec![vec![Vec::new(), Vec::new()]];
+
+        render_offline(&test_config(), &mut handler, 250, 100, &mut audio_out).unwrap();
+        assert_eq!(handler.blocks, vec![100, 100]);
+        assert_eq!(audio_out[0][0].len(), 200);
+        assert_eq!(audio_out[0][1][199], -199.0);
+    }
+
+    #[test]
+    fn rejects_mismatched_output_buffers() {
+        let mut handler = RampHandler {
+            next: 0.0,
+            blocks: Vec::new(),
+            quit_after_blocks: None,
+        };
+
+        let mut audio_out = vec![vec![Vec::new()]];
+        assert!(matches!(
+            render_offline(&test_config(), &mut handler, 250, 100, &mut audio_out),
+            Err(OfflineRenderError::OutputChannelCountMismatch {
+                expected: 2,
+                got: 1,
+                ..
+            })
+        ));
+
+        assert!(matches!(
+            render_offline(&test_config(), &mut handler, 250, 100, &mut []),
+            Err(OfflineRenderError::OutputBusCountMismatch {
+                expected: 1,
+                got: 0
+            })
+        ));
+
+        let mut audio_out = vec![vec![Vec::new(), Vec::new()]];
+        assert!(matches!(
+            render_offline(&test_config(), &mut handler, 250, 0, &mut audio_out),
+            Err(OfflineRenderError::InvalidBlockSize)
+        ));
+        assert!(handler.blocks.is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+use crate::AudioBusConfig;
+
+/// How the channels of a bus are spread over its system ports when their numbers differ,
+/// e.g. a stereo output bus on the single playback port of a mono speaker, or a mono
+/// input bus fed by both capture ports of a stereo microphone.
+///
+/// When there are fewer ports than channels, channel `n` uses port `n % ports`, so a stereo
+/// bus on a mono port has both channels summed into it, and a quad bus on a stereo pair
+/// has its rear channels summed with the front ones. When there are more ports than
+/// channels, port `n` uses channel `n % channels`, so a mono bus is spread over every port.
+/// Only summed channels are attenuated; a channel that's copied to several ports keeps its
+/// level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PortMixing {
+    /// The bus must have one channel per system port.
+    #[default]
+    Off,
+    /// Sum at -3 dB, which keeps the loudness of uncorrelated channels (e.g. a wide stereo
+    /// mix).
+    Minus3Db,
+    /// Sum at -6 dB, which keeps a signal that's the same in every channel (e.g. a centred
+    /// mono source) at its level instead of clipping.
+    Minus6Db,
+}
+
+impl PortMixing {
+    /// The gain applied to each of the channels (or input ports) that are summed.
+    pub fn sum_gain(&self) -> f32 {
+        match self {
+            PortMixing::Off => 1.0,
+            PortMixing::Minus3Db => std::f32::consts::FRAC_1_SQRT_2,
+            PortMixing::Minus6Db => 0.5,
+        }
+    }
+
+    /// The identifier used for this mode in config files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PortMixing::Off => "off",
+            PortMixing::Minus3Db => "minus_3db",
+            PortMixing::Minus6Db => "minus_6db",
+        }
+    }
+}
+
+impl std::str::FromStr for PortMixing {
+    type Err = ();
+
+    /// Parse a mode from the identifier returned by `as_str()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(PortMixing::Off),
+            "minus_3db" => Ok(PortMixing::Minus3Db),
+            "minus_6db" => Ok(PortMixing::Minus6Db),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The system ports one channel of a bus is connected to.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ChannelRoute<T> {
+    /// What each backend needs to reach the port, e.g. a device channel.
+    pub(crate) ports: Vec<T>,
+    /// Applied to the channel when it's written to its output ports, or to the sum of its
+    /// input ports when it's read.
+    pub(crate) gain: f32,
+}
+
+/// The route of every channel of `bus`, where `ports` has what the backend needs to reach
+/// each of `bus.system_ports`, in the same order.
+pub(crate) fn channel_routes<T: Clone>(
+    bus: &AudioBusConfig,
+    ports: &[T],
+    is_input: bool,
+) -> Vec<ChannelRoute<T>> {
+    let channels = bus.channel_layout().num_channels();
+
+    let mut routes: Vec<ChannelRoute<T>> = (0..channels)
+        .map(|_| ChannelRoute {
+            ports: Vec::new(),
+            gain: 1.0,
+        })
+        .collect();
+    if ports.is_empty() {
+        return routes;
+    }
+
+    for i in 0..channels.max(ports.len()) {
+        routes[i % channels]
+            .ports
+            .push(ports[i % ports.len()].clone());
+    }
+
+    // Input channels sum all of their ports, and output ports sum every channel they're
+    // given.
+    let gain = bus.port_mixing.sum_gain();
+    for (channel, route) in routes.iter_mut().enumerate() {
+        let summed = if is_input {
+            route.ports.len() > 1
+        } else {
+            (0..channels)
+                .filter(|other| other % ports.len() == channel % ports.len())
+                .count()
+                > 1
+        };
+        if summed {
+            route.gain = gain;
+        }
+    }
+
+    routes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChannelLayout;
+
+    fn bus(channels: u16, ports: usize, port_mixing: PortMixing) -> AudioBusConfig {
+        AudioBusConfig {
+            id: String::from("Bus"),
+            system_ports: (1..=ports).map(|i| format!("port_{}", i)).collect(),
+            channel_layout: Some(ChannelLayout::for_channels(channels)),
+            port_mixing,
+            ..Default::default()
+        }
+    }
+
+    fn summary(routes: &[ChannelRoute<usize>]) -> Vec<(Vec<usize>, f32)> {
+        routes
+            .iter()
+            .map(|route| (route.ports.clone(), route.gain))
+            .collect()
+    }
+
+    #[test]
+    fn routes_channels_to_ports() {
+        let half = 0.5;
+        let ports = [0, 1, 2, 3];
+
+        // One channel per port.
+        let routes = channel_routes(&bus(2, 2, PortMixing::Off), &ports[..2], false);
+        assert_eq!(summary(&routes), vec![(vec![0], 1.0), (vec![1], 1.0)]);
+
+        // Stereo to a mono port, and a stereo microphone into a mono bus.
+        let stereo_to_mono = bus(2, 1, PortMixing::Minus6Db);
+        let routes = channel_routes(&stereo_to_mono, &ports[..1], false);
+        assert_eq!(summary(&routes), vec![(vec![0], half), (vec![0], half)]);
+        let routes = channel_routes(&bus(1, 2, PortMixing::Minus6Db), &ports[..2], true);
+        assert_eq!(summary(&routes), vec![(vec![0, 1], half)]);
+
+        // Mono spread over stereo ports keeps its level.
+        let routes = channel_routes(&bus(1, 2, PortMixing::Minus6Db), &ports[..2], false);
+        assert_eq!(summary(&routes), vec![(vec![0, 1], 1.0)]);
+        let routes = channel_routes(&bus(2, 1, PortMixing::Minus6Db), &ports[..1], true);
+        assert_eq!(summary(&routes), vec![(vec![0], 1.0), (vec![0], 1.0)]);
+
+        // Three channels on two ports only attenuate the ones that share a port.
+        let gain = PortMixing::Minus3Db.sum_gain();
+        let routes = channel_routes(&bus(3, 2, PortMixing::Minus3Db), &ports[..2], false);
+        assert_eq!(
+            summary(&routes),
+            vec![(vec![0], gain), (vec![1], 1.0), (vec![0], gain)]
+        );
+        let routes = channel_routes(&bus(2, 3, PortMixing::Minus3Db), &ports[..3], true);
+        assert_eq!(summary(&routes), vec![(vec![0, 2], gain), (vec![1], 1.0)]);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{AudioBusConfig, Config};
+
+/// Whether a system port name given in a config is a pattern, i.e. it contains a `*`
+/// (any run of characters) or a `?` (any single character).
+pub(crate) fn is_port_pattern(port: &str) -> bool {
+    port.contains(&['*', '?'][..])
+}
+
+/// Whether `name` matches the glob `pattern`.
+pub(crate) fn port_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*` and of the name character it currently ends at.
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the last `*` take one more character.
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// The system port that a port name or pattern from a config resolves to: the port with
+/// exactly that name, or else the first of `available` that matches the pattern.
+pub(crate) fn resolve_system_port<'a>(port: &'a str, available: &'a [String]) -> Option<&'a str> {
+    if available.iter().any(|a| a == port) {
+        return Some(port);
+    }
+
+    if !is_port_pattern(port) {
+        return None;
+    }
+
+    available
+        .iter()
+        .find(|a| port_matches(port, a))
+        .map(|a| a.as_str())
+}
+
+/// The busses with every system port pattern replaced by the port it resolves to. Patterns
+/// that match nothing are left as they are, so they are reported as not found.
+pub(crate) fn resolve_bus_patterns(
+    busses: &[AudioBusConfig],
+    available: &[String],
+) -> Vec<AudioBusConfig> {
+    busses
+        .iter()
+        .map(|bus| AudioBusConfig {
+            system_ports: bus
+                .system_ports
+                .iter()
+                .map(|port| {
+                    String::from(resolve_system_port(port, available).unwrap_or(port.as_str()))
+                })
+                .collect(),
+            ..bus.clone()
+        })
+        .collect()
+}
+
+/// The config with the system port patterns of its busses resolved against the given
+/// input and output ports of the device.
+pub(crate) fn resolve_config_patterns(
+    config: &Config,
+    in_ports: &[String],
+    out_ports: &[String],
+) -> Config {
+    Config {
+        audio_in_busses: resolve_bus_patterns(&config.audio_in_busses, in_ports),
+        audio_out_busses: resolve_bus_patterns(&config.audio_out_busses, out_ports),
+        ..config.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| String::from(*n)).collect()
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(port_matches("system:playback_*", "system:playback_12"));
+        assert!(port_matches("*:capture_?", "system:capture_1"));
+        assert!(port_matches("*a*b", "xaab"));
+        assert!(!port_matches("*:capture_?", "system:capture_10"));
+        assert!(!port_matches("system:*_1", "system:capture_2"));
+        assert!(port_matches("*", ""));
+    }
+
+    #[test]
+    fn exact_names_win_over_patterns() {
+        let available = names(&["system:capture_1", "system:capture_2", "odd?name"]);
+
+        assert_eq!(
+            resolve_system_port("system:capture_2", &available),
+            Some("system:capture_2")
+        );
+        assert_eq!(
+            resolve_system_port("odd?name", &available),
+            Some("odd?name")
+        );
+        assert_eq!(
+            resolve_system_port("*capture_?", &available),
+            Some("system:capture_1")
+        );
+        assert_eq!(resolve_system_port("system:capture_3", &available), None);
+        assert_eq!(resolve_system_port("*playback*", &available), None);
+    }
+}
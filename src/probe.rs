@@ -0,0 +1,75 @@
+use crate::{
+    spawn_rt_thread, Config, FatalErrorHandler, FatalStreamError, ProcessControl, ProcessInfo,
+    RtProcessHandler, SpawnRtThreadError, StreamInfo,
+};
+
+/// Check whether a stream can be spawned with `config`, e.g. before a settings dialog
+/// applies it, without spawning the real process handler.
+///
+/// This opens the backend and registers (and on Jack connects) the ports of every bus and
+/// controller, just like `spawn_rt_thread()`, and then stops the stream right away. The
+/// outputs are silent while it runs.
+///
+/// The returned `StreamInfo` has what the stream would get: the actual sample rate, buffer
+/// size, sample format, latency, and connected ports.
+pub fn probe_config(config: &Config) -> Result<StreamInfo, SpawnRtThreadError> {
+    let stream =
+        spawn_rt_thread(config, None, ProbeHandler, ProbeErrorHandler).map_err(|e| e.error)?;
+
+    let stream_info = stream.stream_info().clone();
+    stream.stop();
+
+    Ok(stream_info)
+}
+
+/// Leaves the outputs silent for the few cycles the probe runs.
+struct ProbeHandler;
+
+impl RtProcessHandler for ProbeHandler {
+    fn init(&mut self, _stream_info: &StreamInfo) {}
+
+    fn process(&mut self, _proc_info: ProcessInfo) -> ProcessControl {
+        ProcessControl::Continue
+    }
+}
+
+/// The probe is stopped right away, so a failure after spawning doesn't matter.
+struct ProbeErrorHandler;
+
+impl FatalErrorHandler for ProbeErrorHandler {
+    fn fatal_stream_error(self, error: FatalStreamError) {
+        log::debug!("The probed stream failed: {}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AudioBusConfig, DUMMY_DEVICE_NAME, DUMMY_SERVER_NAME};
+
+    #[test]
+    fn probe_reports_the_stream() {
+        let mut config = Config {
+            audio_server: String::from(DUMMY_SERVER_NAME),
+            system_audio_device: String::from(DUMMY_DEVICE_NAME),
+            audio_out_busses: vec![AudioBusConfig {
+                id: String::from("Speakers Out"),
+                system_ports: vec![String::from("playback_1"), String::from("playback_2")],
+                ..Default::default()
+            }],
+            sample_rate: Some(48_000),
+            ..Config::default()
+        };
+
+        let stream_info = probe_config(&config).unwrap();
+        assert_eq!(stream_info.sample_rate, 48_000);
+        assert_eq!(stream_info.audio_out.len(), 1);
+        assert_eq!(stream_info.audio_out[0].channels, 2);
+
+        config.audio_out_busses[0].system_ports[1] = String::from("playback_9");
+        assert!(matches!(
+            probe_config(&config),
+            Err(SpawnRtThreadError::SystemPortNotFound(port, _)) if port == "playback_9"
+        ));
+    }
+}
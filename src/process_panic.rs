@@ -0,0 +1,49 @@
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::FatalStreamError;
+
+/// Run a call into the `RtProcessHandler`, turning a panic into
+/// `FatalStreamError::ProcessPanicked` instead of letting it unwind into the backend. For
+/// Jack that would unwind across the FFI boundary of the process callback.
+///
+/// This costs next to nothing unless the handler actually panics.
+pub(crate) fn catch_process_panic<T, F: FnOnce() -> T>(f: F) -> Result<T, FatalStreamError> {
+    catch_unwind(AssertUnwindSafe(f))
+        .map_err(|payload| FatalStreamError::ProcessPanicked(panic_message(payload.as_ref())))
+}
+
+/// The message given to `panic!()`, if it was a string.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        String::from(*msg)
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("unknown panic payload")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catches_panics() {
+        let mut calls = 0;
+        assert!(catch_process_panic(|| calls += 1).is_ok());
+        assert_eq!(calls, 1);
+
+        let error = catch_process_panic(|| panic!("filter blew up at {} Hz", 440)).unwrap_err();
+        assert!(matches!(
+            error,
+            FatalStreamError::ProcessPanicked(msg) if msg == "filter blew up at 440 Hz"
+        ));
+
+        let error = catch_process_panic(|| std::panic::panic_any(7u8)).unwrap_err();
+        assert!(matches!(
+            error,
+            FatalStreamError::ProcessPanicked(msg) if msg == "unknown panic payload"
+        ));
+    }
+}
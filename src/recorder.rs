@@ -0,0 +1,542 @@
+use log::{info, warn};
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::diagnostics::{RingBufferCounters, RingBufferStats};
+use crate::{AudioBusBuffer, StreamInfo};
+
+/// How many seconds of audio each recorder ring buffer can hold before the disk thread
+/// has to catch up.
+const RING_BUFFER_SECONDS: usize = 2;
+
+/// How often the disk thread writes queued audio to disk.
+const WRITE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// What to write to the file while recording of a bus is paused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GapPolicy {
+    /// Write silence for the paused duration, so the file stays in sync with the stream.
+    InsertSilence,
+
+    /// Write nothing, so the audio before and after the pause is joined together.
+    Skip,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordBusConfig {
+    /// The ID of the audio input bus to record.
+    pub bus_id: String,
+
+    /// The path of the WAV file to record to. Any existing file is overwritten.
+    pub path: PathBuf,
+
+    /// What to write to the file while recording is paused.
+    pub gap_policy: GapPolicy,
+}
+
+/// Create a disk recorder for the given audio input busses.
+///
+/// This returns the realtime half, which should be fed from `RtProcessHandler::process`, and
+/// the non-realtime half, which writes the audio to 32 bit float WAV files on its own thread.
+pub fn disk_recorder(
+    stream_info: &StreamInfo,
+    busses: &[RecordBusConfig],
+) -> Result<(RtRecorder, Recorder), std::io::Error> {
+    let mut rt_busses = Vec::<RtRecordBus>::new();
+    let mut shared = Vec::<RecordBusShared>::new();
+    let mut writers = Vec::<BusWriter>::new();
+
+    for config in busses.iter() {
+        let bus = stream_info
+            .audio_in_bus_by_id(&config.bus_id)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("No audio input bus with id {}", &config.bus_id),
+                )
+            })?;
+
+        let channels = bus.channels as usize;
+        let capacity = stream_info.sample_rate as usize * channels * RING_BUFFER_SECONDS;
+        let (producer, consumer) = RingBuffer::<f32>::new(capacity.max(1)).split();
+
+        let paused = Arc::new(AtomicBool::new(false));
+        let ring_counters = Arc::new(RingBufferCounters::default());
+
+        rt_busses.push(RtRecordBus {
+            bus_index: bus.id_index.index(),
+            gap_policy: config.gap_policy,
+            paused: Arc::clone(&paused),
+            producer,
+            ring_counters: Arc::clone(&ring_counters),
+        });
+        shared.push(RecordBusShared {
+            bus_id: config.bus_id.clone(),
+            paused,
+            ring_counters,
+        });
+        writers.push(BusWriter::new(
+            &config.path,
+            consumer,
+            bus.channels,
+            stream_info.sample_rate,
+        )?);
+
+        info!(
+            "Recording audio bus {} to {}",
+            &config.bus_id,
+            config.path.display()
+        );
+    }
+
+    let running = Arc::new(AtomicBool::new(true));
+    let thread_running = Arc::clone(&running);
+
+    let thread = std::thread::Builder::new()
+        .name(String::from("rusty-daw-io recorder"))
+        .spawn(move || write_loop(writers, thread_running))?;
+
+    Ok((
+        RtRecorder { busses: rt_busses },
+        Recorder {
+            busses: shared,
+            running,
+            thread: Some(thread),
+        },
+    ))
+}
+
+/// The realtime half of the disk recorder.
+pub struct RtRecorder {
+    busses: Vec<RtRecordBus>,
+}
+
+struct RtRecordBus {
+    bus_index: usize,
+    gap_policy: GapPolicy,
+    paused: Arc<AtomicBool>,
+    producer: Producer<f32>,
+    ring_counters: Arc<RingBufferCounters>,
+}
+
+impl RtRecorder {
+    /// Queue the audio of this cycle to be written to disk. Call this once per process cycle
+    /// with `ProcessInfo::audio_in`.
+    ///
+    /// This never blocks or allocates. If the disk thread can't keep up the audio of the whole
+    /// cycle is dropped and an overrun is counted, so the channels of the file never shift.
+    pub fn record(&mut self, audio_in: &[AudioBusBuffer]) {
+        for bus in self.busses.iter_mut() {
+            let buffer = match audio_in.get(bus.bus_index) {
+                Some(buffer) => buffer,
+                None => continue,
+            };
+
+            let paused = bus.paused.load(Ordering::Relaxed);
+            if paused && bus.gap_policy == GapPolicy::Skip {
+                continue;
+            }
+
+            let samples = buffer.frames() * buffer.num_channels();
+            if bus.producer.remaining() < samples {
+                bus.ring_counters.overrun();
+                continue;
+            }
+
+            if paused {
+                bus.producer
+                    .push_iter(&mut std::iter::repeat_n(0.0, samples));
+            } else {
+                let channel_buffers = buffer.channels();
+                bus.producer.push_iter(
+                    &mut (0..buffer.frames())
+                        .flat_map(|frame| channel_buffers.iter().map(move |c| c[frame])),
+                );
+            }
+        }
+    }
+}
+
+/// The non-realtime half of the disk recorder.
+pub struct Recorder {
+    busses: Vec<RecordBusShared>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<(), std::io::Error>>>,
+}
+
+struct RecordBusShared {
+    bus_id: String,
+    paused: Arc<AtomicBool>,
+    ring_counters: Arc<RingBufferCounters>,
+}
+
+impl Recorder {
+    /// Stop feeding the recording of the given bus without closing its file. What gets written
+    /// in the meantime depends on the bus's `GapPolicy`.
+    ///
+    /// This returns `false` if no bus with the given ID is being recorded.
+    pub fn pause_recording(&self, bus_id: &str) -> bool {
+        self.set_paused(bus_id, true)
+    }
+
+    /// Resume a recording paused with `pause_recording`.
+    ///
+    /// This returns `false` if no bus with the given ID is being recorded.
+    pub fn resume_recording(&self, bus_id: &str) -> bool {
+        self.set_paused(bus_id, false)
+    }
+
+    pub fn is_recording_paused(&self, bus_id: &str) -> Option<bool> {
+        self.bus(bus_id)
+            .map(|bus| bus.paused.load(Ordering::Relaxed))
+    }
+
+    /// The under/overrun counters of the ring buffer of the given bus.
+    pub fn ring_buffer_stats(&self, bus_id: &str) -> Option<RingBufferStats> {
+        self.bus(bus_id).map(|bus| bus.ring_counters.stats())
+    }
+
+    /// The ring buffer counters of every bus, by bus ID.
+    pub(crate) fn ring_counters(&self) -> Vec<(String, Arc<RingBufferCounters>)> {
+        self.busses
+            .iter()
+            .map(|bus| (bus.bus_id.clone(), Arc::clone(&bus.ring_counters)))
+            .collect()
+    }
+
+    /// Write any remaining audio and close the files.
+    ///
+    /// The `RtRecorder` should be dropped or no longer fed by the time this is called, otherwise
+    /// the audio that is queued afterwards is lost.
+    pub fn finish(mut self) -> Result<(), std::io::Error> {
+        self.stop()
+    }
+
+    fn bus(&self, bus_id: &str) -> Option<&RecordBusShared> {
+        self.busses.iter().find(|bus| bus.bus_id == bus_id)
+    }
+
+    fn set_paused(&self, bus_id: &str, paused: bool) -> bool {
+        if let Some(bus) = self.bus(bus_id) {
+            bus.paused.store(paused, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn stop(&mut self) -> Result<(), std::io::Error> {
+        self.running.store(false, Ordering::Relaxed);
+
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(std::io::Error::other("The recorder thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            warn!("Failed to finish recording: {}", e);
+        }
+    }
+}
+
+fn write_loop(mut writers: Vec<BusWriter>, running: Arc<AtomicBool>) -> Result<(), std::io::Error> {
+    loop {
+        // Check before writing so the queued audio is drained one last time after stopping.
+        let stop = !running.load(Ordering::Relaxed);
+
+        for writer in writers.iter_mut() {
+            writer.write_queued()?;
+        }
+
+        if stop {
+            break;
+        }
+
+        std::thread::sleep(WRITE_INTERVAL);
+    }
+
+    for writer in writers.into_iter() {
+        writer.finish()?;
+    }
+
+    Ok(())
+}
+
+/// Writes the audio of one bus to a 32 bit float WAV file.
+struct BusWriter {
+    file: BufWriter<File>,
+    consumer: Consumer<f32>,
+    data_bytes: u32,
+}
+
+impl BusWriter {
+    fn new(
+        path: &PathBuf,
+        consumer: Consumer<f32>,
+        channels: u16,
+        sample_rate: u32,
+    ) -> Result<Self, std::io::Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        // The sizes are filled in when the recording is finished.
+        write_wav_header(&mut file, channels, sample_rate, 0)?;
+
+        Ok(Self {
+            file,
+            consumer,
+            data_bytes: 0,
+        })
+    }
+
+    fn write_queued(&mut self) -> Result<(), std::io::Error> {
+        let Self {
+            file,
+            consumer,
+            data_bytes,
+        } = self;
+
+        let mut result = Ok(());
+        consumer.pop_each(
+            |s| match file.write_all(&s.to_le_bytes()) {
+                Ok(()) => {
+                    *data_bytes = data_bytes.saturating_add(4);
+                    true
+                }
+                Err(e) => {
+                    result = Err(e);
+                    false
+                }
+            },
+            None,
+        );
+
+        result
+    }
+
+    fn finish(mut self) -> Result<(), std::io::Error> {
+        self.file.flush()?;
+
+        let mut file = self.file.into_inner().map_err(|e| e.into_error())?;
+
+        // RIFF chunk size.
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&(36u32.saturating_add(self.data_bytes)).to_le_bytes())?;
+
+        // Data chunk size.
+        file.seek(SeekFrom::Start(40))?;
+        file.write_all(&self.data_bytes.to_le_bytes())?;
+
+        file.sync_all()
+    }
+}
+
+fn write_wav_header<W: Write>(
+    w: &mut W,
+    channels: u16,
+    sample_rate: u32,
+    data_bytes: u32,
+) -> Result<(), std::io::Error> {
+    const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+    const BITS_PER_SAMPLE: u16 = 32;
+
+    let block_align = channels * (BITS_PER_SAMPLE / 8);
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&(36 + data_bytes).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    w.write_all(&WAVE_FORMAT_IEEE_FLOAT.to_le_bytes())?;
+    w.write_all(&channels.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * u32::from(block_align)).to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    w.write_all(b"data")?;
+    w.write_all(&data_bytes.to_le_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AudioBus, ChannelLayout, DeviceIndex, SampleFormat};
+
+    fn test_stream_info() -> StreamInfo {
+        StreamInfo {
+            server_name: String::from("Test"),
+            audio_in: vec![AudioBus {
+                id_name: String::from("Mic In"),
+                id_index: DeviceIndex::new(0),
+                system_device: String::from("Test Device"),
+                system_half_duplex_device: None,
+                system_ports: vec![String::from("capture_1"), String::from("capture_2")],
+                client_ports: Vec::new(),
+                channels: 2,
+                channel_layout: ChannelLayout::Stereo,
+                connected_ports: 2,
+            }],
+            audio_out: Vec::new(),
+            midi_in: Vec::new(),
+            midi_out: Vec::new(),
+            sample_rate: 1000,
+            max_audio_buffer_size: 4,
+            latency_frames_in: 0,
+            latency_frames_out: 0,
+            sample_format: SampleFormat::F32,
+        }
+    }
+
+    fn cycle(value: f32) -> Vec<AudioBusBuffer> {
+        let mut buffer = AudioBusBuffer::new(2, 4);
+        buffer.clear_and_resize(4);
+        for channel in buffer.channels_mut() {
+            for s in channel.iter_mut() {
+                *s = value;
+            }
+        }
+        vec![buffer]
+    }
+
+    fn stereo_cycle(frames: usize, left: f32, right: f32) -> Vec<AudioBusBuffer> {
+        let mut buffer = AudioBusBuffer::new(2, 4);
+        buffer.clear_and_resize(frames);
+        for (channel, value) in buffer.channels_mut().iter_mut().zip([left, right].iter()) {
+            for s in channel.iter_mut() {
+                *s = *value;
+            }
+        }
+        vec![buffer]
+    }
+
+    fn read_samples(path: &PathBuf) -> Vec<f32> {
+        let bytes = std::fs::read(path).unwrap();
+
+        let data_bytes = u32::from_le_bytes([bytes[40], bytes[41], bytes[42], bytes[43]]) as usize;
+        assert_eq!(bytes.len(), 44 + data_bytes);
+
+        bytes[44..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }
+
+    fn record_with_pause(gap_policy: GapPolicy, file_name: &str) -> Vec<f32> {
+        let path = std::env::temp_dir().join(file_name);
+
+        let (mut rt_recorder, recorder) = disk_recorder(
+            &test_stream_info(),
+            &[RecordBusConfig {
+                bus_id: String::from("Mic In"),
+                path: path.clone(),
+                gap_policy,
+            }],
+        )
+        .unwrap();
+
+        rt_recorder.record(&cycle(1.0));
+
+        assert!(recorder.pause_recording("Mic In"));
+        assert_eq!(recorder.is_recording_paused("Mic In"), Some(true));
+        rt_recorder.record(&cycle(0.5));
+
+        assert!(recorder.resume_recording("Mic In"));
+        rt_recorder.record(&cycle(0.25));
+
+        assert_eq!(
+            recorder.ring_buffer_stats("Mic In"),
+            Some(RingBufferStats::default())
+        );
+
+        drop(rt_recorder);
+        recorder.finish().unwrap();
+
+        let samples = read_samples(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        samples
+    }
+
+    #[test]
+    fn paused_audio_is_skipped() {
+        let samples = record_with_pause(GapPolicy::Skip, "rusty_daw_io_test_record_skip.wav");
+
+        let mut expected = vec![1.0; 8];
+        expected.append(&mut vec![0.25; 8]);
+        assert_eq!(samples, expected);
+    }
+
+    #[test]
+    fn paused_audio_is_replaced_with_silence() {
+        let samples = record_with_pause(
+            GapPolicy::InsertSilence,
+            "rusty_daw_io_test_record_silence.wav",
+        );
+
+        let mut expected = vec![1.0; 8];
+        expected.append(&mut vec![0.0; 8]);
+        expected.append(&mut vec![0.25; 8]);
+        assert_eq!(samples, expected);
+    }
+
+    #[test]
+    fn overruns_drop_whole_cycles() {
+        let path = std::env::temp_dir().join("rusty_daw_io_test_record_overrun.wav");
+
+        let (mut rt_recorder, recorder) = disk_recorder(
+            &test_stream_info(),
+            &[RecordBusConfig {
+                bus_id: String::from("Mic In"),
+                path: path.clone(),
+                gap_policy: GapPolicy::Skip,
+            }],
+        )
+        .unwrap();
+
+        // The ring holds 4000 samples, which isn't a whole number of these 6 sample cycles,
+        // and the disk thread only drains it every few milliseconds.
+        for _ in 0..2000 {
+            rt_recorder.record(&stereo_cycle(3, 1.0, -1.0));
+        }
+
+        assert!(recorder.ring_buffer_stats("Mic In").unwrap().overruns > 0);
+
+        drop(rt_recorder);
+        recorder.finish().unwrap();
+
+        let samples = read_samples(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!samples.is_empty());
+        assert_eq!(samples.len() % 6, 0);
+        assert!(samples
+            .chunks(2)
+            .all(|frame| frame[0] == 1.0 && frame[1] == -1.0));
+    }
+
+    #[test]
+    fn unknown_bus_is_rejected() {
+        assert!(disk_recorder(
+            &test_stream_info(),
+            &[RecordBusConfig {
+                bus_id: String::from("Nonexistent"),
+                path: std::env::temp_dir().join("rusty_daw_io_test_record_unknown.wav"),
+                gap_policy: GapPolicy::Skip,
+            }],
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,1157 @@
+use ringbuf::{Consumer, Producer, RingBuffer};
+
+use crate::growth::Growth;
+use crate::{
+    AddBusError, AudioBus, AudioBusBuffer, Config, MidiControllerBuffer, MidiTimestamps,
+    ProcessControl, ProcessInfo, RtProcessHandler, StreamInfo, TransportInfo,
+};
+
+/// The sample rate a process handler runs at, no matter the sample rate of the device. See
+/// `Config::resample`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResampleConfig {
+    /// The sample rate the process handler sees.
+    pub sample_rate: u32,
+
+    pub quality: ResampleQuality,
+}
+
+/// How the samples between two device samples (or two handler samples) are interpolated.
+///
+/// Neither filters out what's above the lower of the two Nyquist frequencies, so converting
+/// to a lower rate can alias. For the usual small steps (44.1 kHz to 48 kHz and back) the
+/// difference is mostly in the top octave.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ResampleQuality {
+    /// Linear interpolation. The cheapest, but it dulls the highs.
+    Linear,
+
+    /// 4-point cubic (Catmull-Rom) interpolation.
+    #[default]
+    Cubic,
+}
+
+impl ResampleQuality {
+    /// The identifier used for this quality in config files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResampleQuality::Linear => "linear",
+            ResampleQuality::Cubic => "cubic",
+        }
+    }
+}
+
+impl std::str::FromStr for ResampleQuality {
+    type Err = ();
+
+    /// Parse a quality from the identifier returned by `as_str()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(ResampleQuality::Linear),
+            "cubic" => Ok(ResampleQuality::Cubic),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How far the interpolated input lags behind the device, in device frames, so the samples
+/// after each interpolated one have already arrived.
+const INPUT_DELAY: u64 = 2;
+
+/// How far the interpolated output lags behind the handler, in handler frames.
+const OUTPUT_DELAY: u64 = 3;
+
+/// The samples each output channel keeps from the previous cycle.
+const OUTPUT_HISTORY: usize = OUTPUT_DELAY as usize + 1;
+
+/// The `StreamInfo` the handler of a stream with the given device `StreamInfo` sees: the
+/// sample rate and buffer size of the handler, and latencies that include the resampling.
+/// `StreamHandle::stream_info()` keeps the one of the device.
+pub fn resampled_stream_info(device_info: &StreamInfo, config: &ResampleConfig) -> StreamInfo {
+    let rates = Rates::new(device_info.sample_rate, config.sample_rate);
+
+    let mut handler_info = device_info.clone();
+    handler_info.sample_rate = config.sample_rate;
+    handler_info.max_audio_buffer_size =
+        rates.max_handler_frames(device_info.max_audio_buffer_size);
+    if !device_info.audio_in.is_empty() {
+        handler_info.latency_frames_in = rates
+            .handler_frames_ceil(u64::from(device_info.latency_frames_in) + INPUT_DELAY)
+            as u32;
+    }
+    if !device_info.audio_out.is_empty() {
+        handler_info.latency_frames_out =
+            rates.handler_frames_ceil(u64::from(device_info.latency_frames_out)) as u32
+                + OUTPUT_DELAY as u32;
+    }
+
+    handler_info
+}
+
+/// Wraps an `RtProcessHandler` so that it runs at the sample rate of a `ResampleConfig`.
+/// `spawn_rt_thread()` wraps every process handler in this, with `Config::resample`.
+///
+/// Each device cycle, the inputs are converted to the handler's rate, the handler processes
+/// however many frames of its rate that cycle covers (so `ProcessInfo::audio_frames` can
+/// differ by one from cycle to cycle), and its outputs are converted back. The frame counts
+/// are exact, so the two sides never drift apart, and every buffer is allocated in `init`.
+/// The midi event times, `ProcessInfo::cycle_start_frame` and the transport frame are
+/// converted to the handler's frames too. `AudioBusBuffer::peak()` isn't measured at the
+/// handler's rate, so it's always `None` for the handler.
+///
+/// If the device already runs at the handler's rate, everything is passed through as is.
+pub struct Resampled<H: RtProcessHandler> {
+    handler: H,
+    config: Option<ResampleConfig>,
+    /// How the midi input times of the stream are counted. The handler's times are
+    /// converted to its rate, but counted the same way. Set from the `Config` of the stream.
+    midi_in_timestamps: MidiTimestamps,
+    state: Option<Box<ResampleState>>,
+
+    /// The buffers of the busses/controllers added to a running stream, allocated by its
+    /// stream handle. `None` if the handler isn't run by a stream.
+    new_buffers: Option<Box<NewBuffersReceiver>>,
+    /// The number of busses/controllers of the device, as of the last `StreamInfo`.
+    device_counts: BusCounts,
+}
+
+impl<H: RtProcessHandler> Resampled<H> {
+    /// Run `handler` at the sample rate of `config`, or at the device's if it's `None`.
+    pub fn new(handler: H, config: Option<ResampleConfig>) -> Self {
+        Self {
+            handler,
+            config,
+            midi_in_timestamps: MidiTimestamps::default(),
+            state: None,
+            new_buffers: None,
+            device_counts: BusCounts::default(),
+        }
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    pub fn into_inner(self) -> H {
+        self.handler
+    }
+
+    /// Start over for a stream with the given device `StreamInfo`, returning the one of the
+    /// handler. This allocates.
+    fn reset(&mut self, device_info: &StreamInfo) -> StreamInfo {
+        self.device_counts = BusCounts::of(device_info);
+
+        let config = match self.config {
+            Some(config) if config.sample_rate != device_info.sample_rate => config,
+            _ => {
+                self.state = None;
+                return device_info.clone();
+            }
+        };
+
+        let state = Box::new(ResampleState::new(
+            device_info,
+            &config,
+            self.midi_in_timestamps,
+        ));
+        let handler_info = state.handler_info.clone();
+        self.state = Some(state);
+        handler_info
+    }
+
+    /// Add the buffers the stream handle sent for the busses/controllers the device has by
+    /// now. Returns `true` if there were any.
+    fn apply_new_buffers(&mut self) -> bool {
+        let receiver = match &mut self.new_buffers {
+            Some(receiver) => receiver,
+            None => return false,
+        };
+
+        let mut added = false;
+        loop {
+            if receiver.next.is_none() {
+                receiver.next = receiver.updates.pop();
+            }
+            let update = match &mut receiver.next {
+                Some(update) => update,
+                None => break,
+            };
+
+            match &mut self.state {
+                // Wait until the device has the bus/controller as well.
+                Some(_) if !self.device_counts.covers(&update.counts) => break,
+                // If the state was started over since the update was sent, it has the
+                // buffers already.
+                Some(state) if !state.counts().covers(&update.counts) => {
+                    state.apply(update);
+                    added = true;
+                }
+                // If the handler runs at the device's rate, it doesn't need any.
+                _ => {}
+            }
+
+            // If the stream handle hasn't freed the previous updates yet, this one is freed
+            // here.
+            if let Some(update) = receiver.next.take() {
+                let _ = receiver.applied.push(update);
+            }
+        }
+
+        added
+    }
+
+    fn run(&mut self, proc_info: ProcessInfo, offline: bool) -> ProcessControl {
+        if self.apply_new_buffers() {
+            if let Some(state) = &self.state {
+                self.handler.busses_added(&state.handler_info);
+            }
+        }
+
+        let handler = &mut self.handler;
+        let call = |handler: &mut H, proc_info: ProcessInfo| {
+            if offline {
+                handler.process_offline(proc_info)
+            } else {
+                handler.process(proc_info)
+            }
+        };
+
+        match &mut self.state {
+            Some(state) => state.process(proc_info, |proc_info| call(handler, proc_info)),
+            None => call(handler, proc_info),
+        }
+    }
+}
+
+impl<H: RtProcessHandler> RtProcessHandler for Resampled<H> {
+    fn init(&mut self, stream_info: &StreamInfo) {
+        let handler_info = self.reset(stream_info);
+        self.handler.init(&handler_info)
+    }
+
+    fn deinit(&mut self) {
+        self.handler.deinit()
+    }
+
+    fn process(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+        self.run(proc_info, false)
+    }
+
+    fn process_offline(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+        self.run(proc_info, true)
+    }
+
+    /// The handler keeps running at its own rate, so this only starts the conversion over
+    /// for the new device rate, which allocates (on the realtime thread, once per change).
+    /// The handler is only told if it runs at the device's rate.
+    fn sample_rate_changed(&mut self, stream_info: &StreamInfo) {
+        let resampled = self.state.is_some();
+        let handler_info = self.reset(stream_info);
+        if !resampled && self.state.is_none() {
+            self.handler.sample_rate_changed(&handler_info)
+        }
+    }
+
+    /// The resampler is rebuilt for the new buffer size, which is allowed to allocate.
+    fn buffer_size_changed(&mut self, stream_info: &StreamInfo) {
+        let handler_info = self.reset(stream_info);
+        self.handler.buffer_size_changed(&handler_info)
+    }
+
+    /// In a stream, the buffers of the new busses/controllers are allocated by the stream
+    /// handle when they are added. The handler is told once they have arrived, which can be
+    /// a cycle later. Otherwise they are allocated here, on the realtime thread.
+    fn busses_added(&mut self, stream_info: &StreamInfo) {
+        self.device_counts = BusCounts::of(stream_info);
+
+        if self.new_buffers.is_some() {
+            if self.apply_new_buffers() {
+                if let Some(state) = &self.state {
+                    self.handler.busses_added(&state.handler_info);
+                }
+            }
+            if self.state.is_some() {
+                return;
+            }
+        }
+
+        match &mut self.state {
+            Some(state) => {
+                state.add_busses(stream_info);
+                self.handler.busses_added(&state.handler_info)
+            }
+            None => self.handler.busses_added(stream_info),
+        }
+    }
+}
+
+/// Wrap the process handler of a stream with the given config in `Resampled`. If it
+/// resamples, this also returns the stream handle's half of the queue that brings the
+/// buffers of added busses to it.
+pub(crate) fn resampled<H: RtProcessHandler>(
+    config: &Config,
+    rt_process_handler: H,
+) -> (Resampled<H>, Option<NewBuffersSender>) {
+    let mut resampled = Resampled::new(rt_process_handler, config.resample);
+    resampled.midi_in_timestamps = config.midi_in_timestamps;
+
+    let sender = config.resample.map(|resample| {
+        let (sender, receiver) = new_buffers_queue(resample);
+        resampled.new_buffers = Some(Box::new(receiver));
+        sender
+    });
+
+    (resampled, sender)
+}
+
+/// How many busses/controllers can be added before the process handler picks up their
+/// buffers. This is the size of Jack's queue of new ports, and only Jack can add them.
+const NEW_BUFFERS_QUEUE_SIZE: usize = 16;
+
+/// The number of busses/controllers of each kind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct BusCounts {
+    audio_in: usize,
+    audio_out: usize,
+    midi_in: usize,
+    midi_out: usize,
+}
+
+impl BusCounts {
+    fn of(info: &StreamInfo) -> Self {
+        Self {
+            audio_in: info.audio_in.len(),
+            audio_out: info.audio_out.len(),
+            midi_in: info.midi_in.len(),
+            midi_out: info.midi_out.len(),
+        }
+    }
+
+    /// Whether there are at least as many of every kind as in `other`.
+    fn covers(&self, other: &BusCounts) -> bool {
+        self.audio_in >= other.audio_in
+            && self.audio_out >= other.audio_out
+            && self.midi_in >= other.midi_in
+            && self.midi_out >= other.midi_out
+    }
+}
+
+/// The buffers of a bus or controller that was added while the stream runs.
+enum NewBuffers {
+    AudioIn(Growth<Vec<Vec<f32>>>, Growth<AudioBusBuffer>),
+    AudioOut(Growth<Vec<Vec<f32>>>, Growth<AudioBusBuffer>),
+    MidiIn(Growth<MidiControllerBuffer>),
+    MidiOut(Growth<MidiControllerBuffer>),
+}
+
+/// New buffers for the resampler, along with the `StreamInfo` of the handler with the new
+/// bus or controller.
+///
+/// Once it has been applied this holds the old lists and `StreamInfo` of the resampler
+/// instead, and is sent back so they are freed off the realtime thread.
+struct BuffersUpdate {
+    buffers: NewBuffers,
+    handler_info: StreamInfo,
+    /// The busses/controllers of the device that the buffers are for.
+    counts: BusCounts,
+}
+
+/// The stream handle's half of the queue that brings the buffers of new busses/controllers
+/// to the resampler.
+pub(crate) struct NewBuffersSender {
+    config: ResampleConfig,
+    updates: Producer<BuffersUpdate>,
+    applied: Consumer<BuffersUpdate>,
+}
+
+/// The resampler's half of the queue that brings the buffers of new busses/controllers to
+/// it.
+struct NewBuffersReceiver {
+    updates: Consumer<BuffersUpdate>,
+    applied: Producer<BuffersUpdate>,
+    /// The update that waits for the device to have its bus/controller.
+    next: Option<BuffersUpdate>,
+}
+
+fn new_buffers_queue(config: ResampleConfig) -> (NewBuffersSender, NewBuffersReceiver) {
+    let (updates_producer, updates_consumer) =
+        RingBuffer::<BuffersUpdate>::new(NEW_BUFFERS_QUEUE_SIZE).split();
+    let (applied_producer, applied_consumer) =
+        RingBuffer::<BuffersUpdate>::new(NEW_BUFFERS_QUEUE_SIZE).split();
+
+    (
+        NewBuffersSender {
+            config,
+            updates: updates_producer,
+            applied: applied_consumer,
+        },
+        NewBuffersReceiver {
+            updates: updates_consumer,
+            applied: applied_producer,
+            next: None,
+        },
+    )
+}
+
+impl NewBuffersSender {
+    /// Free whatever the resampler is done with, and make sure there is room to send the
+    /// buffers of one more bus/controller. Call this before it is added to the stream.
+    pub fn reserve(&mut self) -> Result<(), AddBusError> {
+        while self.applied.pop().is_some() {}
+
+        if self.updates.is_full() {
+            Err(AddBusError::QueueFull)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send the buffers of the audio bus that was just added to the stream, whose device
+    /// `StreamInfo` is `device_info` now.
+    pub fn send_audio_bus(&mut self, device_info: &StreamInfo, is_input: bool) {
+        let handler_info = resampled_stream_info(device_info, &self.config);
+        let rates = Rates::new(device_info.sample_rate, self.config.sample_rate);
+
+        let buffers = if is_input {
+            let existing = device_info.audio_in.len() - 1;
+            let (history, buffers) = input_buffers(
+                &handler_info.audio_in[existing..],
+                &rates,
+                device_info.max_audio_buffer_size,
+                handler_info.max_audio_buffer_size,
+            );
+            NewBuffers::AudioIn(
+                Growth::new(history, existing),
+                Growth::new(buffers, existing),
+            )
+        } else {
+            let existing = device_info.audio_out.len() - 1;
+            let (history, buffers) = output_buffers(
+                &handler_info.audio_out[existing..],
+                handler_info.max_audio_buffer_size,
+            );
+            NewBuffers::AudioOut(
+                Growth::new(history, existing),
+                Growth::new(buffers, existing),
+            )
+        };
+
+        self.send(buffers, handler_info, device_info);
+    }
+
+    /// Send the buffer of the midi controller that was just added to the stream. See
+    /// `send_audio_bus()`.
+    pub fn send_midi_controller(&mut self, device_info: &StreamInfo, is_input: bool) {
+        let handler_info = resampled_stream_info(device_info, &self.config);
+
+        let buffers = if is_input {
+            NewBuffers::MidiIn(Growth::new(
+                vec![MidiControllerBuffer::new()],
+                device_info.midi_in.len() - 1,
+            ))
+        } else {
+            NewBuffers::MidiOut(Growth::new(
+                vec![MidiControllerBuffer::new()],
+                device_info.midi_out.len() - 1,
+            ))
+        };
+
+        self.send(buffers, handler_info, device_info);
+    }
+
+    fn send(&mut self, buffers: NewBuffers, handler_info: StreamInfo, device_info: &StreamInfo) {
+        let update = BuffersUpdate {
+            buffers,
+            handler_info,
+            counts: BusCounts::of(device_info),
+        };
+
+        // `reserve()` made room for it, and only the stream handle pushes.
+        let _ = self.updates.push(update);
+    }
+}
+
+/// The device and handler sample rates.
+#[derive(Debug, Clone, Copy)]
+struct Rates {
+    device: u64,
+    handler: u64,
+}
+
+impl Rates {
+    fn new(device: u32, handler: u32) -> Self {
+        Self {
+            device: u64::from(device.max(1)),
+            handler: u64::from(handler.max(1)),
+        }
+    }
+
+    /// The number of handler frames in the first `device_frames` frames of the stream.
+    fn handler_frames(&self, device_frames: u64) -> u64 {
+        scale(device_frames, self.handler, self.device)
+    }
+
+    fn handler_frames_ceil(&self, device_frames: u64) -> u64 {
+        scale(
+            device_frames * self.handler + self.device - 1,
+            1,
+            self.device,
+        )
+    }
+
+    /// The number of device frames in the first `handler_frames` frames of the stream.
+    fn device_frames(&self, handler_frames: u64) -> u64 {
+        scale(handler_frames, self.device, self.handler)
+    }
+
+    /// The most handler frames a cycle of `device_frames` frames can cover.
+    fn max_handler_frames(&self, device_frames: u32) -> u32 {
+        self.handler_frames_ceil(u64::from(device_frames)) as u32 + 1
+    }
+
+    /// The samples each input channel keeps from the previous cycle.
+    fn input_history(&self) -> usize {
+        self.device.div_ceil(self.handler) as usize + INPUT_DELAY as usize + 1
+    }
+}
+
+/// `value * num / den`, rounded down.
+fn scale(value: u64, num: u64, den: u64) -> u64 {
+    (u128::from(value) * u128::from(num) / u128::from(den)) as u64
+}
+
+/// Fill `out` with the samples `first..` of the destination rate, interpolated from
+/// `samples`, whose first sample is sample `samples_start` of the source rate. Destination
+/// sample `m` is at source sample `m * src_rate / dst_rate - delay`.
+#[allow(clippy::too_many_arguments)]
+fn interpolate(
+    samples: &[f32],
+    samples_start: i64,
+    first: u64,
+    src_rate: u64,
+    dst_rate: u64,
+    delay: u64,
+    quality: ResampleQuality,
+    out: &mut [f32],
+) {
+    for (m, out) in (first..).zip(out.iter_mut()) {
+        let pos = u128::from(m) * u128::from(src_rate);
+        let q = (pos / u128::from(dst_rate)) as i64;
+        let t = (pos % u128::from(dst_rate)) as f64 / dst_rate as f64;
+
+        // The index of the sample before the one at `q - delay`.
+        let i = (q - delay as i64 - 1 - samples_start) as usize;
+        let (x0, x1, x2, x3) = (samples[i], samples[i + 1], samples[i + 2], samples[i + 3]);
+        let t = t as f32;
+
+        *out = match quality {
+            ResampleQuality::Linear => x1 + (x2 - x1) * t,
+            ResampleQuality::Cubic => {
+                let a = -0.5 * x0 + 1.5 * x1 - 1.5 * x2 + 0.5 * x3;
+                let b = x0 - 2.5 * x1 + 2.0 * x2 - 0.5 * x3;
+                let c = -0.5 * x0 + 0.5 * x2;
+                ((a * t + b) * t + c) * t + x1
+            }
+        };
+    }
+}
+
+/// Keep the last `history` samples for the next cycle. This never allocates.
+fn keep_history(samples: &mut Vec<f32>, history: usize) {
+    let start = samples.len() - history;
+    samples.copy_within(start.., 0);
+    samples.truncate(history);
+}
+
+/// The last samples of every channel of every bus, preallocated for a whole cycle.
+fn histories(busses: &[AudioBus], history: usize, max_frames: u32) -> Vec<Vec<Vec<f32>>> {
+    busses
+        .iter()
+        .map(|bus| {
+            (0..bus.channels)
+                .map(|_| {
+                    let mut samples = Vec::with_capacity(history + max_frames as usize);
+                    samples.resize(history, 0.0);
+                    samples
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The histories and handler buffers of the given input busses.
+fn input_buffers(
+    busses: &[AudioBus],
+    rates: &Rates,
+    max_device_frames: u32,
+    max_handler_frames: u32,
+) -> (Vec<Vec<Vec<f32>>>, Vec<AudioBusBuffer>) {
+    (
+        histories(busses, rates.input_history(), max_device_frames),
+        busses
+            .iter()
+            .map(|bus| AudioBusBuffer::for_input_bus(bus, max_handler_frames, false))
+            .collect(),
+    )
+}
+
+/// The histories and handler buffers of the given output busses.
+fn output_buffers(
+    busses: &[AudioBus],
+    max_handler_frames: u32,
+) -> (Vec<Vec<Vec<f32>>>, Vec<AudioBusBuffer>) {
+    (
+        histories(busses, OUTPUT_HISTORY, max_handler_frames),
+        busses
+            .iter()
+            .map(|bus| AudioBusBuffer::for_bus(bus, max_handler_frames))
+            .collect(),
+    )
+}
+
+struct ResampleState {
+    rates: Rates,
+    quality: ResampleQuality,
+    input_history: usize,
+    midi_in_timestamps: MidiTimestamps,
+
+    handler_info: StreamInfo,
+
+    /// The device frames processed so far.
+    device_frames: u64,
+    /// The handler frame of the first cycle's `cycle_start_frame`.
+    start_frame: Option<u64>,
+
+    in_history: Vec<Vec<Vec<f32>>>,
+    out_history: Vec<Vec<Vec<f32>>>,
+
+    audio_in: Vec<AudioBusBuffer>,
+    audio_out: Vec<AudioBusBuffer>,
+    midi_in: Vec<MidiControllerBuffer>,
+    midi_out: Vec<MidiControllerBuffer>,
+}
+
+impl ResampleState {
+    fn new(
+        device_info: &StreamInfo,
+        config: &ResampleConfig,
+        midi_in_timestamps: MidiTimestamps,
+    ) -> Self {
+        let rates = Rates::new(device_info.sample_rate, config.sample_rate);
+
+        let mut state = Self {
+            rates,
+            midi_in_timestamps,
+            quality: config.quality,
+            input_history: rates.input_history(),
+            handler_info: resampled_stream_info(device_info, config),
+            device_frames: 0,
+            start_frame: None,
+            in_history: Vec::new(),
+            out_history: Vec::new(),
+            audio_in: Vec::new(),
+            audio_out: Vec::new(),
+            midi_in: Vec::new(),
+            midi_out: Vec::new(),
+        };
+        state.add_busses(device_info);
+        state
+    }
+
+    /// Allocate the buffers of the busses/controllers of `device_info` that don't have any
+    /// yet.
+    fn add_busses(&mut self, device_info: &StreamInfo) {
+        let config = ResampleConfig {
+            sample_rate: self.handler_info.sample_rate,
+            quality: self.quality,
+        };
+        self.handler_info = resampled_stream_info(device_info, &config);
+
+        let max_handler_frames = self.handler_info.max_audio_buffer_size;
+
+        let (mut history, mut buffers) = input_buffers(
+            &self.handler_info.audio_in[self.audio_in.len()..],
+            &self.rates,
+            device_info.max_audio_buffer_size,
+            max_handler_frames,
+        );
+        self.in_history.append(&mut history);
+        self.audio_in.append(&mut buffers);
+
+        let (mut history, mut buffers) = output_buffers(
+            &self.handler_info.audio_out[self.audio_out.len()..],
+            max_handler_frames,
+        );
+        self.out_history.append(&mut history);
+        self.audio_out.append(&mut buffers);
+
+        for _ in self.midi_in.len()..device_info.midi_in.len() {
+            self.midi_in.push(MidiControllerBuffer::new());
+        }
+        for _ in self.midi_out.len()..device_info.midi_out.len() {
+            self.midi_out.push(MidiControllerBuffer::new());
+        }
+    }
+
+    /// The busses/controllers of the device this has buffers for.
+    fn counts(&self) -> BusCounts {
+        BusCounts {
+            audio_in: self.audio_in.len(),
+            audio_out: self.audio_out.len(),
+            midi_in: self.midi_in.len(),
+            midi_out: self.midi_out.len(),
+        }
+    }
+
+    /// Add the buffers the stream handle allocated for a new bus or controller. The old
+    /// lists and `StreamInfo` are left in `update`.
+    fn apply(&mut self, update: &mut BuffersUpdate) {
+        match &mut update.buffers {
+            NewBuffers::AudioIn(history, buffers) => {
+                let existing = self.in_history.len();
+                history.apply(&mut self.in_history);
+                buffers.apply(&mut self.audio_in);
+
+                // The history is as long as the device's sample rate needs when the bus was
+                // added. If the rate has changed since, this may allocate.
+                for history in self.in_history[existing..].iter_mut().flatten() {
+                    history.resize(self.input_history, 0.0);
+                }
+            }
+            NewBuffers::AudioOut(history, buffers) => {
+                history.apply(&mut self.out_history);
+                buffers.apply(&mut self.audio_out);
+            }
+            NewBuffers::MidiIn(buffers) => buffers.apply(&mut self.midi_in),
+            NewBuffers::MidiOut(buffers) => buffers.apply(&mut self.midi_out),
+        }
+
+        // The buffer size may have changed since the update was sent.
+        update.handler_info.max_audio_buffer_size = self.handler_info.max_audio_buffer_size;
+        std::mem::swap(&mut self.handler_info, &mut update.handler_info);
+    }
+
+    fn process<F: FnOnce(ProcessInfo) -> ProcessControl>(
+        &mut self,
+        proc_info: ProcessInfo,
+        process: F,
+    ) -> ProcessControl {
+        let rates = self.rates;
+        let quality = self.quality;
+        let input_history = self.input_history;
+
+        let device_frames = proc_info.audio_frames;
+        let d0 = self.device_frames;
+        let d1 = d0 + device_frames as u64;
+        let j0 = rates.handler_frames(d0);
+        let j1 = rates.handler_frames(d1);
+        let handler_frames = (j1 - j0) as usize;
+
+        // Convert the inputs to the handler's rate.
+
+        for ((device_bus, history), bus) in proc_info
+            .audio_in
+            .iter()
+            .zip(self.in_history.iter_mut())
+            .zip(self.audio_in.iter_mut())
+        {
+            bus.clear_and_resize(handler_frames);
+
+            for ((device_channel, history), channel) in device_bus
+                .channel_buffers
+                .iter()
+                .zip(history.iter_mut())
+                .zip(bus.channel_buffers.iter_mut())
+            {
+                history.extend_from_slice(&device_channel[..device_frames]);
+                interpolate(
+                    history,
+                    d0 as i64 - input_history as i64,
+                    j0,
+                    rates.device,
+                    rates.handler,
+                    INPUT_DELAY,
+                    quality,
+                    channel,
+                );
+                keep_history(history, input_history);
+            }
+        }
+
+        let start_frame = *self
+            .start_frame
+            .get_or_insert_with(|| rates.handler_frames(proc_info.cycle_start_frame));
+
+        let last_handler_frame = handler_frames.saturating_sub(1) as u64;
+        let timestamps = self.midi_in_timestamps;
+        for (device_buffer, buffer) in proc_info.midi_in.iter().zip(self.midi_in.iter_mut()) {
+            buffer.clear();
+            for event in device_buffer.events() {
+                let mut event = *event;
+                let device_frame =
+                    timestamps.cycle_frame(proc_info.cycle_start_frame, event.delta_frames);
+                let frame = rates.handler_frames(d0 + u64::from(device_frame)) - j0;
+                event.delta_frames =
+                    timestamps.delta_frames(start_frame + j0, frame.min(last_handler_frame) as u32);
+
+                // Both buffers hold the same number of events.
+                let _ = buffer.push(event);
+            }
+        }
+
+        // Run the handler.
+
+        for bus in self.audio_out.iter_mut() {
+            bus.clear_and_resize(handler_frames);
+        }
+        for buffer in self.midi_out.iter_mut() {
+            buffer.clear();
+        }
+
+        let control = if handler_frames == 0 {
+            ProcessControl::Continue
+        } else {
+            process(ProcessInfo {
+                audio_in: self.audio_in.as_slice(),
+                audio_out: self.audio_out.as_mut_slice(),
+                audio_frames: handler_frames,
+
+                midi_in: self.midi_in.as_slice(),
+                midi_out: self.midi_out.as_mut_slice(),
+
+                sample_rate: self.handler_info.sample_rate,
+                transport: proc_info.transport.map(|transport| TransportInfo {
+                    frame: rates.handler_frames(transport.frame),
+                    ..transport
+                }),
+                cycle_start_frame: start_frame + j0,
+                cycle_start_time: proc_info.cycle_start_time,
+            })
+        };
+
+        // Convert the outputs back to the device's rate.
+
+        for ((bus, history), device_bus) in self
+            .audio_out
+            .iter()
+            .zip(self.out_history.iter_mut())
+            .zip(proc_info.audio_out.iter_mut())
+        {
+            for ((channel, history), device_channel) in bus
+                .channel_buffers
+                .iter()
+                .zip(history.iter_mut())
+                .zip(device_bus.channel_buffers.iter_mut())
+            {
+                history.extend_from_slice(&channel[..handler_frames]);
+                interpolate(
+                    history,
+                    j0 as i64 - OUTPUT_HISTORY as i64,
+                    d0,
+                    rates.handler,
+                    rates.device,
+                    OUTPUT_DELAY,
+                    quality,
+                    &mut device_channel[..device_frames],
+                );
+                keep_history(history, OUTPUT_HISTORY);
+            }
+        }
+
+        let last_device_frame = device_frames.saturating_sub(1) as u64;
+        for (buffer, device_buffer) in self.midi_out.iter().zip(proc_info.midi_out.iter_mut()) {
+            for event in buffer.events() {
+                let mut event = *event;
+                let frame = rates
+                    .device_frames(j0 + u64::from(event.delta_frames))
+                    .saturating_sub(d0);
+                event.delta_frames = frame.min(last_device_frame) as u32;
+
+                let _ = device_buffer.push(event);
+            }
+        }
+
+        self.device_frames = d1;
+
+        control
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AudioBus, ChannelLayout, DeviceIndex, MidiController, SampleFormat};
+    use std::time::Instant;
+
+    fn bus(id: &str) -> AudioBus {
+        AudioBus {
+            id_name: String::from(id),
+            id_index: DeviceIndex::new(0),
+            system_device: String::from("Dummy Device"),
+            system_half_duplex_device: None,
+            system_ports: vec![String::from("port_1")],
+            client_ports: Vec::new(),
+            channels: 1,
+            channel_layout: ChannelLayout::for_channels(1),
+            connected_ports: 1,
+        }
+    }
+
+    fn device_info(sample_rate: u32) -> StreamInfo {
+        let controller = |id: &str| MidiController {
+            id_name: String::from(id),
+            id_index: DeviceIndex::new(0),
+            system_port: String::from("midi_1"),
+            client_port: None,
+        };
+
+        StreamInfo {
+            server_name: String::from("Dummy"),
+            audio_in: vec![bus("Mic")],
+            audio_out: vec![bus("Speakers")],
+            midi_in: vec![controller("Keys")],
+            midi_out: vec![controller("Synth")],
+            sample_rate,
+            max_audio_buffer_size: 128,
+            latency_frames_in: 128,
+            latency_frames_out: 256,
+            sample_format: SampleFormat::F32,
+        }
+    }
+
+    /// Copies its input to its output and echoes every midi event.
+    #[derive(Default)]
+    struct Thru {
+        stream_info: Option<StreamInfo>,
+        frames: u64,
+        next_start_frame: Option<u64>,
+        busses_added: usize,
+    }
+
+    impl RtProcessHandler for Thru {
+        fn init(&mut self, stream_info: &StreamInfo) {
+            self.stream_info = Some(stream_info.clone());
+        }
+
+        fn process(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+            let max_frames = self.stream_info.as_ref().unwrap().max_audio_buffer_size;
+            assert!(proc_info.audio_frames <= max_frames as usize);
+            if let Some(start_frame) = self.next_start_frame {
+                assert_eq!(proc_info.cycle_start_frame, start_frame);
+            }
+            self.next_start_frame =
+                Some(proc_info.cycle_start_frame + proc_info.audio_frames as u64);
+
+            let input = proc_info.audio_in[0].channels()[0].clone();
+            proc_info.audio_out[0][0].copy_from_slice(&input);
+            let events = proc_info.midi_in[0].events().to_vec();
+            proc_info.midi_out[0].extend_from_slice(&events).unwrap();
+
+            self.frames += proc_info.audio_frames as u64;
+            ProcessControl::Continue
+        }
+
+        fn busses_added(&mut self, stream_info: &StreamInfo) {
+            self.stream_info = Some(stream_info.clone());
+            self.busses_added += 1;
+        }
+    }
+
+    /// Run `cycles` device cycles of `frames` frames, with a constant input of 0.5 and a
+    /// midi event at frame 100 of every cycle. Returns the output and the times of the
+    /// echoed midi events.
+    fn run(resampled: &mut Resampled<Thru>, cycles: usize, frames: usize) -> (Vec<f32>, Vec<u32>) {
+        let mut audio_in = vec![AudioBusBuffer::new(1, 128)];
+        let mut audio_out = vec![AudioBusBuffer::new(1, 128)];
+        let mut midi_in = vec![MidiControllerBuffer::new()];
+        let mut midi_out = vec![MidiControllerBuffer::new()];
+
+        let mut output = Vec::new();
+        let mut midi_times = Vec::new();
+        for cycle in 0..cycles {
+            audio_in[0].clear_and_resize(frames);
+            audio_in[0][0].iter_mut().for_each(|s| *s = 0.5);
+            audio_out[0].clear_and_resize(frames);
+            midi_in[0].clear();
+            midi_in[0].push_raw(100, &[0x90, 60, 100]).unwrap();
+            midi_out[0].clear();
+
+            let control = resampled.process(ProcessInfo {
+                audio_in: &audio_in,
+                audio_out: &mut audio_out,
+                audio_frames: frames,
+                midi_in: &midi_in,
+                midi_out: &mut midi_out,
+                sample_rate: 44100,
+                transport: None,
+                cycle_start_frame: 1000 + (cycle * frames) as u64,
+                cycle_start_time: Instant::now(),
+            });
+            assert_eq!(control, ProcessControl::Continue);
+
+            output.extend_from_slice(&audio_out[0][0]);
+            midi_times.extend(midi_out[0].events().iter().map(|e| e.delta_frames));
+        }
+
+        (output, midi_times)
+    }
+
+    #[test]
+    fn converts_between_rates() {
+        let config = ResampleConfig {
+            sample_rate: 48000,
+            quality: ResampleQuality::Cubic,
+        };
+        let mut resampled = Resampled::new(Thru::default(), Some(config));
+        resampled.init(&device_info(44100));
+
+        let handler_info = resampled.handler().stream_info.clone().unwrap();
+        assert_eq!(handler_info.sample_rate, 48000);
+        assert_eq!(handler_info.max_audio_buffer_size, 141);
+        assert_eq!(handler_info.latency_frames_in, 142);
+        assert_eq!(handler_info.latency_frames_out, 282);
+
+        let (output, midi_times) = run(&mut resampled, 100, 128);
+
+        // Exactly as many handler frames as there are in 100 cycles at 48 kHz.
+        assert_eq!(resampled.handler().frames, 12800 * 48000 / 44100);
+
+        // A constant signal passes through unchanged once the delay has passed.
+        assert!(output[..3].iter().all(|s| *s < 0.5));
+        assert!(output[8..].iter().all(|s| (s - 0.5).abs() < 1e-6));
+
+        // The midi events keep their device time, give or take a frame of rounding.
+        assert!(midi_times.iter().all(|t| (99..=100).contains(t)));
+        assert_eq!(midi_times.len(), 100);
+    }
+
+    #[test]
+    fn passes_through_at_the_same_rate() {
+        let config = ResampleConfig {
+            sample_rate: 44100,
+            quality: ResampleQuality::Linear,
+        };
+        let mut resampled = Resampled::new(Thru::default(), Some(config));
+        resampled.init(&device_info(44100));
+        assert!(resampled.state.is_none());
+
+        let (output, midi_times) = run(&mut resampled, 2, 128);
+        assert!(output.iter().all(|s| *s == 0.5));
+        assert_eq!(midi_times, vec![100, 100]);
+    }
+
+    #[test]
+    fn downsamples_odd_cycle_sizes() {
+        let config = ResampleConfig {
+            sample_rate: 22050,
+            quality: ResampleQuality::Linear,
+        };
+        let mut resampled = Resampled::new(Thru::default(), Some(config));
+        resampled.init(&device_info(96000));
+
+        let (output, _) = run(&mut resampled, 50, 3);
+        assert_eq!(resampled.handler().frames, 150 * 22050 / 96000);
+        assert!(output[20..].iter().all(|s| (s - 0.5).abs() < 1e-6));
+    }
+
+    fn resampled_stream(device: &StreamInfo) -> (Resampled<Thru>, NewBuffersSender) {
+        let config = Config {
+            resample: Some(ResampleConfig {
+                sample_rate: 48000,
+                quality: ResampleQuality::Cubic,
+            }),
+            ..Config::default()
+        };
+        let (mut resampled, sender) = resampled(&config, Thru::default());
+        resampled.init(device);
+
+        (resampled, sender.unwrap())
+    }
+
+    #[test]
+    fn adds_the_buffers_sent_by_the_stream_handle() {
+        let mut device = device_info(44100);
+        let (mut resampled, mut sender) = resampled_stream(&device);
+
+        device.audio_in.push(bus("Guitar"));
+        sender.reserve().unwrap();
+        sender.send_audio_bus(&device, true);
+
+        // The buffers wait for the device to add the bus.
+        run(&mut resampled, 1, 128);
+        assert_eq!(resampled.handler().busses_added, 0);
+        assert_eq!(resampled.state.as_ref().unwrap().audio_in.len(), 1);
+
+        resampled.busses_added(&device);
+        assert_eq!(resampled.handler().busses_added, 1);
+        let handler_info = resampled.handler().stream_info.clone().unwrap();
+        assert_eq!(handler_info.audio_in.len(), 2);
+        assert_eq!(handler_info.sample_rate, 48000);
+        assert_eq!(handler_info.max_audio_buffer_size, 141);
+
+        let state = resampled.state.as_ref().unwrap();
+        assert_eq!(state.audio_in.len(), 2);
+        assert_eq!(state.in_history[1][0].len(), state.input_history);
+
+        // The old lists come back to be freed off the realtime thread.
+        assert!(sender.applied.pop().is_some());
+        run(&mut resampled, 1, 128);
+    }
+
+    #[test]
+    fn adds_buffers_that_arrive_after_the_bus() {
+        let mut device = device_info(44100);
+        let (mut resampled, mut sender) = resampled_stream(&device);
+
+        device.midi_out.push(device.midi_out[0].clone());
+        resampled.busses_added(&device);
+        assert_eq!(resampled.handler().busses_added, 0);
+
+        sender.reserve().unwrap();
+        sender.send_midi_controller(&device, false);
+        run(&mut resampled, 1, 128);
+
+        assert_eq!(resampled.handler().busses_added, 1);
+        assert_eq!(resampled.state.as_ref().unwrap().midi_out.len(), 2);
+    }
+
+    #[test]
+    fn skips_buffers_the_resampler_already_has() {
+        let mut device = device_info(44100);
+        let (mut resampled, mut sender) = resampled_stream(&device);
+
+        device.audio_out.push(bus("Phones"));
+        sender.reserve().unwrap();
+        sender.send_audio_bus(&device, false);
+
+        // A new buffer size starts the conversion over with every bus of the device.
+        device.max_audio_buffer_size = 256;
+        resampled.buffer_size_changed(&device);
+        resampled.busses_added(&device);
+
+        assert_eq!(resampled.state.as_ref().unwrap().audio_out.len(), 2);
+        assert_eq!(resampled.handler().busses_added, 0);
+        assert!(sender.applied.pop().is_some());
+    }
+
+    #[test]
+    fn refuses_more_busses_than_the_queue_holds() {
+        let mut device = device_info(44100);
+        let (_resampled, mut sender) = resampled_stream(&device);
+
+        for _ in 0..NEW_BUFFERS_QUEUE_SIZE {
+            device.midi_in.push(device.midi_in[0].clone());
+            sender.reserve().unwrap();
+            sender.send_midi_controller(&device, true);
+        }
+
+        assert!(matches!(sender.reserve(), Err(AddBusError::QueueFull)));
+    }
+}
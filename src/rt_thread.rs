@@ -0,0 +1,159 @@
+use log::warn;
+
+use crate::{RtThreadConfig, RtThreadPolicy, SpawnRtThreadError};
+
+/// Apply `config` to an audio thread that was just spawned.
+///
+/// If the OS refuses, this fails with `SpawnRtThreadError::RtPriorityDenied` if the config
+/// requires it, and otherwise logs a warning and leaves the thread as it is.
+#[cfg(all(
+    target_os = "linux",
+    any(test, feature = "alsa-backend", feature = "pulse")
+))]
+pub(crate) fn promote_thread<T>(
+    thread: &std::thread::JoinHandle<T>,
+    config: &RtThreadConfig,
+) -> Result<(), SpawnRtThreadError> {
+    use std::os::unix::thread::JoinHandleExt;
+
+    denied_unless_required(set_scheduling(thread.as_pthread_t(), config), config)
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(test, feature = "alsa-backend", feature = "pulse")
+))]
+fn set_scheduling(thread: libc::pthread_t, config: &RtThreadConfig) -> Result<(), String> {
+    let (policy, policy_name) = match config.policy {
+        RtThreadPolicy::Normal => return Ok(()),
+        RtThreadPolicy::Fifo => (libc::SCHED_FIFO, "SCHED_FIFO"),
+        RtThreadPolicy::RoundRobin => (libc::SCHED_RR, "SCHED_RR"),
+    };
+
+    let param = libc::sched_param {
+        sched_priority: i32::from(config.priority),
+    };
+    let result = unsafe { libc::pthread_setschedparam(thread, policy, &param) };
+    if result == 0 {
+        return Ok(());
+    }
+
+    let mut msg = format!(
+        "{} priority {}: {}",
+        policy_name,
+        config.priority,
+        std::io::Error::from_raw_os_error(result)
+    );
+    if result == libc::EPERM {
+        msg.push_str(". The rtprio limit of the user (e.g. in /etc/security/limits.conf) needs to be at least the priority");
+    }
+
+    Err(msg)
+}
+
+/// Moves the current thread into the MMCSS "Pro Audio" task until it's dropped.
+#[cfg(target_os = "windows")]
+pub(crate) struct MmcssTask(winapi::um::winnt::HANDLE);
+
+#[cfg(target_os = "windows")]
+impl Drop for MmcssTask {
+    fn drop(&mut self) {
+        unsafe { winapi::um::avrt::AvRevertMmThreadCharacteristics(self.0) };
+    }
+}
+
+/// Apply `config` to the current thread, which must be the audio thread. Keep the returned
+/// task for as long as the thread runs.
+///
+/// See `promote_thread()` for what happens if the OS refuses.
+#[cfg(target_os = "windows")]
+pub(crate) fn promote_current_thread(
+    config: &RtThreadConfig,
+) -> Result<Option<MmcssTask>, SpawnRtThreadError> {
+    use winapi::um::avrt::{
+        AvSetMmThreadCharacteristicsW, AvSetMmThreadPriority, AVRT_PRIORITY_CRITICAL,
+        AVRT_PRIORITY_HIGH, AVRT_PRIORITY_NORMAL,
+    };
+
+    if config.policy == RtThreadPolicy::Normal {
+        return Ok(None);
+    }
+
+    let task_name: Vec<u16> = "Pro Audio\0".encode_utf16().collect();
+    let mut task_index = 0;
+    let handle = unsafe { AvSetMmThreadCharacteristicsW(task_name.as_ptr(), &mut task_index) };
+    if handle.is_null() {
+        let msg = format!(
+            "MMCSS task \"Pro Audio\": {}",
+            std::io::Error::last_os_error()
+        );
+        return denied_unless_required(Err(msg), config).map(|()| None);
+    }
+    let task = MmcssTask(handle);
+
+    let priority = match config.priority {
+        0..=49 => AVRT_PRIORITY_NORMAL,
+        50..=89 => AVRT_PRIORITY_HIGH,
+        _ => AVRT_PRIORITY_CRITICAL,
+    };
+    if unsafe { AvSetMmThreadPriority(handle, priority) } == 0 {
+        let msg = format!(
+            "MMCSS priority {}: {}",
+            config.priority,
+            std::io::Error::last_os_error()
+        );
+        // The thread stays in the task at normal priority.
+        denied_unless_required(Err(msg), config)?;
+    }
+
+    Ok(Some(task))
+}
+
+fn denied_unless_required(
+    result: Result<(), String>,
+    config: &RtThreadConfig,
+) -> Result<(), SpawnRtThreadError> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(msg) if config.required => Err(SpawnRtThreadError::RtPriorityDenied(msg)),
+        Err(msg) => {
+            warn!(
+                "Could not raise the priority of the audio thread, it keeps running with normal scheduling: {}",
+                msg
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denied_priority_only_fails_when_required() {
+        let thread =
+            std::thread::spawn(|| std::thread::sleep(std::time::Duration::from_millis(50)));
+
+        let normal = RtThreadConfig {
+            policy: RtThreadPolicy::Normal,
+            ..RtThreadConfig::default()
+        };
+        assert!(promote_thread(&thread, &normal).is_ok());
+
+        // Out of range for `SCHED_FIFO`, so this is refused whatever the rtprio limit is.
+        let mut invalid = RtThreadConfig {
+            priority: 200,
+            ..RtThreadConfig::default()
+        };
+        assert!(promote_thread(&thread, &invalid).is_ok());
+
+        invalid.required = true;
+        assert!(matches!(
+            promote_thread(&thread, &invalid),
+            Err(SpawnRtThreadError::RtPriorityDenied(msg)) if msg.starts_with("SCHED_FIFO priority 200")
+        ));
+
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+/// The format of the samples a device reads and writes natively. The `AudioBusBuffer`s
+/// given to the realtime thread are always `f32`, so any other format is converted by the
+/// backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SampleFormat {
+    /// 16 bit signed integer. Output to this format is dithered.
+    I16,
+    /// 24 bit signed integer, stored in the low bits of 32 bits.
+    I24,
+    /// 32 bit signed integer.
+    I32,
+    /// 32 bit float. No conversion is needed for this format.
+    F32,
+}
+
+impl SampleFormat {
+    /// The number of bytes one sample takes up in the device's buffers.
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            SampleFormat::I16 => 2,
+            SampleFormat::I24 | SampleFormat::I32 | SampleFormat::F32 => 4,
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        *self == SampleFormat::F32
+    }
+}
+
+/// A sample of a device buffer that can be converted to and from `f32`.
+#[cfg(any(
+    test,
+    feature = "cpal",
+    all(target_os = "linux", feature = "alsa-backend")
+))]
+pub(crate) trait Sample: Copy + Default + Send + 'static {
+    fn to_f32(self) -> f32;
+    fn from_f32(s: f32, dither: &mut Dither) -> Self;
+}
+
+/// A 24 bit sample in the low bits of an `i32`.
+#[cfg(any(test, all(target_os = "linux", feature = "alsa-backend")))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub(crate) struct I24(pub i32);
+
+#[cfg(any(test, all(target_os = "linux", feature = "alsa-backend")))]
+impl I24 {
+    const MAX: i32 = (1 << 23) - 1;
+}
+
+#[cfg(any(
+    test,
+    feature = "cpal",
+    all(target_os = "linux", feature = "alsa-backend")
+))]
+impl Sample for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+    fn from_f32(s: f32, _dither: &mut Dither) -> Self {
+        s
+    }
+}
+
+#[cfg(any(test, all(target_os = "linux", feature = "alsa-backend")))]
+impl Sample for i32 {
+    fn to_f32(self) -> f32 {
+        self as f32 / 2_147_483_648.0
+    }
+    fn from_f32(s: f32, _dither: &mut Dither) -> Self {
+        (s.clamp(-1.0, 1.0) as f64 * 2_147_483_647.0) as i32
+    }
+}
+
+#[cfg(any(test, all(target_os = "linux", feature = "alsa-backend")))]
+impl Sample for I24 {
+    fn to_f32(self) -> f32 {
+        // Sign extend in case the device leaves garbage in the high byte.
+        ((self.0 << 8) >> 8) as f32 / 8_388_608.0
+    }
+    fn from_f32(s: f32, _dither: &mut Dither) -> Self {
+        I24((s.clamp(-1.0, 1.0) * I24::MAX as f32).round() as i32)
+    }
+}
+
+#[cfg(any(
+    test,
+    feature = "cpal",
+    all(target_os = "linux", feature = "alsa-backend")
+))]
+impl Sample for i16 {
+    fn to_f32(self) -> f32 {
+        self as f32 / 32_768.0
+    }
+    fn from_f32(s: f32, dither: &mut Dither) -> Self {
+        let max = i16::MAX as f32;
+        if s.abs() >= 1.0 {
+            // Clipped samples don't need dither.
+            return (s.signum() * max) as i16;
+        }
+
+        (s * max + dither.next()).round().clamp(-max, max) as i16
+    }
+}
+
+/// Unsigned 16 bit samples, which are signed ones offset by half their range.
+#[cfg(any(test, feature = "cpal"))]
+impl Sample for u16 {
+    fn to_f32(self) -> f32 {
+        ((self ^ 0x8000) as i16).to_f32()
+    }
+    fn from_f32(s: f32, dither: &mut Dither) -> Self {
+        i16::from_f32(s, dither) as u16 ^ 0x8000
+    }
+}
+
+/// Triangular (TPDF) dither of up to one step in either direction, for the downconvert to
+/// 16 bit where truncation would otherwise be audible on quiet signals.
+///
+/// This uses a xorshift generator so it is cheap and never allocates.
+#[cfg(any(
+    test,
+    feature = "cpal",
+    all(target_os = "linux", feature = "alsa-backend")
+))]
+pub(crate) struct Dither {
+    state: u32,
+}
+
+#[cfg(any(
+    test,
+    feature = "cpal",
+    all(target_os = "linux", feature = "alsa-backend")
+))]
+impl Dither {
+    pub fn new() -> Self {
+        Self { state: 0x9E37_79B9 }
+    }
+
+    /// The dither to add to a sample that was scaled to the integer range.
+    pub fn next(&mut self) -> f32 {
+        self.uniform() - self.uniform()
+    }
+
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+
+        (self.state >> 8) as f32 / (1u32 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Convert every value in `-1.0..=1.0` (in steps of `step`) to `S` and back, and
+    /// check it's within `tolerance` of where it started.
+    fn round_trip<S: Sample>(step: f32, tolerance: f32) {
+        let mut dither = Dither::new();
+        let mut s = -1.0;
+        while s <= 1.0 {
+            let back = S::from_f32(s, &mut dither).to_f32();
+            assert!(
+                (back - s).abs() <= tolerance,
+                "{}: {} came back as {}",
+                std::any::type_name::<S>(),
+                s,
+                back
+            );
+            s += step;
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip::<f32>(0.001, 0.0);
+        round_trip::<i32>(0.001, 2.0 / 2_147_483_648.0);
+        round_trip::<I24>(0.001, 2.0 / 8_388_608.0);
+        // Up to one step of dither, half a step of rounding, and one step from scaling
+        // by `i16::MAX` on the way out.
+        round_trip::<i16>(0.001, 3.0 / 32_768.0);
+        round_trip::<u16>(0.001, 3.0 / 32_768.0);
+    }
+
+    #[test]
+    fn integer_round_trips() {
+        let mut dither = Dither::new();
+
+        for x in [i32::MIN + 1, -1 << 20, -1, 0, 1, 1 << 20, i32::MAX - 127] {
+            let back = i32::from_f32(x.to_f32(), &mut dither);
+            assert!((back as i64 - x as i64).abs() <= 256, "{} -> {}", x, back);
+        }
+        for x in [-I24::MAX, -1 << 12, -1, 0, 1, 1 << 12, I24::MAX] {
+            let back = I24::from_f32(I24(x).to_f32(), &mut dither);
+            assert!((back.0 - x).abs() <= 1, "{} -> {}", x, back.0);
+        }
+        for x in [-i16::MAX, -1000, -1, 0, 1, 1000, i16::MAX] {
+            let back = i16::from_f32(x.to_f32(), &mut dither);
+            assert!((back - x).abs() <= 2, "{} -> {}", x, back);
+        }
+    }
+
+    #[test]
+    fn conversion_clamps() {
+        let mut dither = Dither::new();
+
+        assert_eq!(i16::from_f32(2.0, &mut dither), 32767);
+        assert_eq!(i16::from_f32(-2.0, &mut dither), -32767);
+        assert_eq!(I24::from_f32(2.0, &mut dither), I24(I24::MAX));
+        assert_eq!(i32::from_f32(1.0, &mut dither), i32::MAX);
+        assert_eq!(f32::from_f32(0.25, &mut dither), 0.25);
+
+        // The high byte of a 24 bit sample is ignored.
+        assert_eq!(I24(0x7F80_0000u32 as i32).to_f32(), -1.0);
+    }
+
+    #[test]
+    fn dither_is_triangular() {
+        let mut dither = Dither::new();
+        let samples: Vec<f32> = (0..10_000).map(|_| dither.next()).collect();
+
+        assert!(samples.iter().all(|d| *d > -1.0 && *d < 1.0));
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        assert!(mean.abs() < 0.05);
+
+        // Triangular noise lands near zero more often than near the edges.
+        let near_zero = samples.iter().filter(|d| d.abs() < 0.25).count();
+        let near_edges = samples.iter().filter(|d| d.abs() > 0.75).count();
+        assert!(near_zero > near_edges * 4);
+    }
+}
@@ -2,6 +2,8 @@ use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
 use quick_xml::Reader;
 use quick_xml::Writer;
 use std::io::{Cursor, Write};
+use std::str::FromStr;
+use std::time::Duration;
 
 use std::fs::File;
 use std::path::PathBuf;
@@ -10,7 +12,11 @@ pub const VERSION: &'static str = "0.1";
 
 static XML_INDENT_SPACES: usize = 3;
 
-use crate::{AudioBusConfig, Config, MidiControllerConfig};
+use crate::{
+    AudioBusConfig, ChannelLayout, ChannelPosition, Config, FailOrRename, JackSelfConnect,
+    MidiControllerConfig, MidiFilter, MidiPortRole, MidiTimestamps, PortMixing, ResampleConfig,
+    RtThreadConfig, StreamDirection,
+};
 
 pub fn load_config_from_file<P: Into<PathBuf>>(path: P) -> Result<Config, ConfigFileError> {
     let mut xml_reader = Reader::from_file(path.into())?;
@@ -27,21 +33,62 @@ pub fn load_config_from_file<P: Into<PathBuf>>(path: P) -> Result<Config, Config
 
         sample_rate: None,
         buffer_size: None,
+        direction: StreamDirection::default(),
 
         midi_server: None,
 
         midi_in_controllers: Vec::new(),
         midi_out_controllers: Vec::new(),
+
+        midi_in_timestamps: MidiTimestamps::default(),
+
+        jack_self_connect: JackSelfConnect::default(),
+        jack_server_name: None,
+        jack_pretty_names: false,
+        buffer_size_history: None,
+        rt_thread: RtThreadConfig::default(),
+        spawn_timeout: None,
+        on_duplicate_id: FailOrRename::default(),
+        input_peaks: false,
+        allow_denormals: false,
+        resample: None,
     };
 
     enum ReadState {
         Invalid,
+        MidiInTimestamps,
+        JackPrettyNames,
+        BufferCapacity,
+        Connection,
+        AutoConnect,
+        ChannelGain,
+        PortMixing,
+        DropRealtime,
+        DropSysex,
+        DroppedChannels,
+        AllowDenormals,
+        ResampleRate,
+        ResampleQuality,
+        Direction,
+        PortName,
+        InputPeaks,
+        RtPolicy,
+        RtPriority,
+        RtRequired,
         AudioServer,
         MidiServer,
         SystemAudioDevice,
         Port,
+        PortRole,
         SampleRate,
         BufferSize,
+        BufferSizeHistory,
+        SpawnTimeout,
+        OnDuplicateId,
+        JackSelfConnect,
+        ChannelLayout,
+        ChannelPosition,
+        JackServerName,
     }
 
     enum BusControllerState {
@@ -52,6 +99,34 @@ pub fn load_config_from_file<P: Into<PathBuf>>(path: P) -> Result<Config, Config
         Invalid,
     }
 
+    /// The audio bus that is being read.
+    fn current_bus<'a>(
+        config: &'a mut Config,
+        bus_controller_state: &BusControllerState,
+        position: usize,
+    ) -> Result<&'a mut AudioBusConfig, ConfigFileError> {
+        match bus_controller_state {
+            BusControllerState::AudioIn => config.audio_in_busses.last_mut(),
+            BusControllerState::AudioOut => config.audio_out_busses.last_mut(),
+            _ => None,
+        }
+        .ok_or(ConfigFileError::InvalidConfigFile(position))
+    }
+
+    /// The midi controller that is being read.
+    fn current_controller<'a>(
+        config: &'a mut Config,
+        bus_controller_state: &BusControllerState,
+        position: usize,
+    ) -> Result<&'a mut MidiControllerConfig, ConfigFileError> {
+        match bus_controller_state {
+            BusControllerState::MidiIn => config.midi_in_controllers.last_mut(),
+            BusControllerState::MidiOut => config.midi_out_controllers.last_mut(),
+            _ => None,
+        }
+        .ok_or(ConfigFileError::InvalidConfigFile(position))
+    }
+
     let mut read_state = ReadState::Invalid;
     let mut bus_controller_state = BusControllerState::Invalid;
 
@@ -79,13 +154,13 @@ pub fn load_config_from_file<P: Into<PathBuf>>(path: P) -> Result<Config, Config
                         BusControllerState::AudioIn => {
                             config.audio_in_busses.push(AudioBusConfig {
                                 id,
-                                system_ports: Vec::new(),
+                                ..Default::default()
                             });
                         }
                         BusControllerState::AudioOut => {
                             config.audio_out_busses.push(AudioBusConfig {
                                 id,
-                                system_ports: Vec::new(),
+                                ..Default::default()
                             });
                         }
                         _ => {
@@ -113,13 +188,13 @@ pub fn load_config_from_file<P: Into<PathBuf>>(path: P) -> Result<Config, Config
                         BusControllerState::MidiIn => {
                             config.midi_in_controllers.push(MidiControllerConfig {
                                 id,
-                                system_port: String::new(),
+                                ..Default::default()
                             });
                         }
                         BusControllerState::MidiOut => {
                             config.midi_out_controllers.push(MidiControllerConfig {
                                 id,
-                                system_port: String::new(),
+                                ..Default::default()
                             });
                         }
                         _ => {
@@ -129,13 +204,65 @@ pub fn load_config_from_file<P: Into<PathBuf>>(path: P) -> Result<Config, Config
                         }
                     }
                 }
+                b"channel_layout" => {
+                    current_bus(
+                        &mut config,
+                        &bus_controller_state,
+                        xml_reader.buffer_position(),
+                    )?
+                    .channel_layout = Some(ChannelLayout::Positions(Vec::new()));
+                    read_state = ReadState::ChannelLayout;
+                }
+                b"position" => read_state = ReadState::ChannelPosition,
+                b"policy" => read_state = ReadState::RtPolicy,
+                b"priority" => read_state = ReadState::RtPriority,
+                b"required" => read_state = ReadState::RtRequired,
+                b"input_peaks" => read_state = ReadState::InputPeaks,
+                b"port_names" => {
+                    current_bus(
+                        &mut config,
+                        &bus_controller_state,
+                        xml_reader.buffer_position(),
+                    )?
+                    .port_names = Some(Vec::new());
+                }
+                b"port_name" => read_state = ReadState::PortName,
+                b"direction" => read_state = ReadState::Direction,
+                b"allow_denormals" => read_state = ReadState::AllowDenormals,
+                b"rate" => read_state = ReadState::ResampleRate,
+                b"quality" => read_state = ReadState::ResampleQuality,
+                b"drop_realtime" => read_state = ReadState::DropRealtime,
+                b"drop_sysex" => read_state = ReadState::DropSysex,
+                b"dropped_channels" => read_state = ReadState::DroppedChannels,
+                b"port_mixing" => read_state = ReadState::PortMixing,
+                b"channel_gains" => {
+                    current_bus(
+                        &mut config,
+                        &bus_controller_state,
+                        xml_reader.buffer_position(),
+                    )?
+                    .channel_gains = Some(Vec::new());
+                }
+                b"gain" => read_state = ReadState::ChannelGain,
+                b"connection" => read_state = ReadState::Connection,
+                b"auto_connect" => read_state = ReadState::AutoConnect,
+                b"buffer_capacity" => read_state = ReadState::BufferCapacity,
+                b"jack_pretty_names" => read_state = ReadState::JackPrettyNames,
+                b"midi_in_timestamps" => read_state = ReadState::MidiInTimestamps,
                 b"port" => read_state = ReadState::Port,
+                b"port_role" => read_state = ReadState::PortRole,
                 b"sample_rate" => read_state = ReadState::SampleRate,
                 b"buffer_size" => read_state = ReadState::BufferSize,
+                b"buffer_size_history" => read_state = ReadState::BufferSizeHistory,
+                b"spawn_timeout_micros" => read_state = ReadState::SpawnTimeout,
+                b"on_duplicate_id" => read_state = ReadState::OnDuplicateId,
+                b"jack_self_connect" => read_state = ReadState::JackSelfConnect,
+                b"jack_server_name" => read_state = ReadState::JackServerName,
                 _ => read_state = ReadState::Invalid,
             },
             Ok(Event::Text(ref event)) => {
                 let mut text = event.unescape_and_decode(&xml_reader)?;
+                let position = xml_reader.buffer_position();
 
                 match &read_state {
                     ReadState::AudioServer => config.audio_server = text,
@@ -205,6 +332,39 @@ pub fn load_config_from_file<P: Into<PathBuf>>(path: P) -> Result<Config, Config
                             }
                         };
                     }
+                    ReadState::PortRole => {
+                        let role = Some(parse_text::<MidiPortRole>(&text, position)?);
+
+                        match &bus_controller_state {
+                            BusControllerState::MidiIn => {
+                                config
+                                    .midi_in_controllers
+                                    .last_mut()
+                                    .ok_or_else(|| {
+                                        ConfigFileError::InvalidConfigFile(
+                                            xml_reader.buffer_position(),
+                                        )
+                                    })?
+                                    .system_port_role = role;
+                            }
+                            BusControllerState::MidiOut => {
+                                config
+                                    .midi_out_controllers
+                                    .last_mut()
+                                    .ok_or_else(|| {
+                                        ConfigFileError::InvalidConfigFile(
+                                            xml_reader.buffer_position(),
+                                        )
+                                    })?
+                                    .system_port_role = role;
+                            }
+                            _ => {
+                                return Err(ConfigFileError::InvalidConfigFile(
+                                    xml_reader.buffer_position(),
+                                ));
+                            }
+                        };
+                    }
                     ReadState::SampleRate => {
                         text.make_ascii_lowercase();
 
@@ -227,6 +387,126 @@ pub fn load_config_from_file<P: Into<PathBuf>>(path: P) -> Result<Config, Config
                             None
                         };
                     }
+                    ReadState::BufferSizeHistory => {
+                        config.buffer_size_history = Some(parse_text(&text, position)?);
+                    }
+                    ReadState::SpawnTimeout => {
+                        config.spawn_timeout =
+                            Some(Duration::from_micros(parse_text(&text, position)?));
+                    }
+                    ReadState::OnDuplicateId => {
+                        config.on_duplicate_id = parse_text(&text, position)?;
+                    }
+                    ReadState::JackServerName => config.jack_server_name = Some(text),
+                    ReadState::ChannelLayout => {
+                        let layout = match text.as_str() {
+                            "mono" => ChannelLayout::Mono,
+                            "stereo" => ChannelLayout::Stereo,
+                            _ => return Err(ConfigFileError::InvalidConfigFile(position)),
+                        };
+                        current_bus(&mut config, &bus_controller_state, position)?.channel_layout =
+                            Some(layout);
+                    }
+                    ReadState::ChannelPosition => {
+                        let channel_position = parse_channel_position(&text)
+                            .ok_or(ConfigFileError::InvalidConfigFile(position))?;
+                        match &mut current_bus(&mut config, &bus_controller_state, position)?
+                            .channel_layout
+                        {
+                            Some(ChannelLayout::Positions(positions)) => {
+                                positions.push(channel_position)
+                            }
+                            _ => return Err(ConfigFileError::InvalidConfigFile(position)),
+                        }
+                    }
+                    ReadState::JackSelfConnect => {
+                        config.jack_self_connect = parse_text(&text, position)?;
+                    }
+                    ReadState::RtPolicy => config.rt_thread.policy = parse_text(&text, position)?,
+                    ReadState::RtPriority => {
+                        config.rt_thread.priority = parse_text(&text, position)?
+                    }
+                    ReadState::RtRequired => {
+                        config.rt_thread.required = parse_text(&text, position)?
+                    }
+                    ReadState::InputPeaks => config.input_peaks = parse_text(&text, position)?,
+                    ReadState::PortName => match &bus_controller_state {
+                        BusControllerState::AudioIn | BusControllerState::AudioOut => {
+                            current_bus(&mut config, &bus_controller_state, position)?
+                                .port_names
+                                .as_mut()
+                                .ok_or(ConfigFileError::InvalidConfigFile(position))?
+                                .push(text);
+                        }
+                        _ => {
+                            current_controller(&mut config, &bus_controller_state, position)?
+                                .port_name = Some(text);
+                        }
+                    },
+                    ReadState::Direction => config.direction = parse_text(&text, position)?,
+                    ReadState::AllowDenormals => {
+                        config.allow_denormals = parse_text(&text, position)?
+                    }
+                    ReadState::ResampleRate => {
+                        let quality = config.resample.map(|r| r.quality).unwrap_or_default();
+                        config.resample = Some(ResampleConfig {
+                            sample_rate: parse_text(&text, position)?,
+                            quality,
+                        });
+                    }
+                    ReadState::ResampleQuality => {
+                        config
+                            .resample
+                            .as_mut()
+                            .ok_or(ConfigFileError::InvalidConfigFile(position))?
+                            .quality = parse_text(&text, position)?;
+                    }
+                    ReadState::DropRealtime => {
+                        current_controller(&mut config, &bus_controller_state, position)?
+                            .filter
+                            .drop_realtime = parse_text(&text, position)?;
+                    }
+                    ReadState::DropSysex => {
+                        current_controller(&mut config, &bus_controller_state, position)?
+                            .filter
+                            .drop_sysex = parse_text(&text, position)?;
+                    }
+                    ReadState::DroppedChannels => {
+                        current_controller(&mut config, &bus_controller_state, position)?
+                            .filter
+                            .dropped_channels = parse_text(&text, position)?;
+                    }
+                    ReadState::PortMixing => {
+                        current_bus(&mut config, &bus_controller_state, position)?.port_mixing =
+                            parse_text(&text, position)?;
+                    }
+                    ReadState::ChannelGain => {
+                        let gain = parse_text(&text, position)?;
+                        current_bus(&mut config, &bus_controller_state, position)?
+                            .channel_gains
+                            .as_mut()
+                            .ok_or(ConfigFileError::InvalidConfigFile(position))?
+                            .push(gain);
+                    }
+                    ReadState::Connection => {
+                        current_controller(&mut config, &bus_controller_state, position)?
+                            .connections
+                            .push(text);
+                    }
+                    ReadState::AutoConnect => {
+                        current_controller(&mut config, &bus_controller_state, position)?
+                            .auto_connect = parse_text(&text, position)?;
+                    }
+                    ReadState::BufferCapacity => {
+                        current_controller(&mut config, &bus_controller_state, position)?
+                            .buffer_capacity = Some(parse_text(&text, position)?);
+                    }
+                    ReadState::JackPrettyNames => {
+                        config.jack_pretty_names = parse_text(&text, position)?
+                    }
+                    ReadState::MidiInTimestamps => {
+                        config.midi_in_timestamps = parse_text(&text, position)?
+                    }
                     ReadState::Invalid => (),
                 }
             }
@@ -263,6 +543,48 @@ pub fn write_config_to_file<P: Into<PathBuf>>(
         }
         xml_writer.write_event(Event::End(BytesEnd::borrowed(b"system_ports")))?;
 
+        // Port Names
+        if let Some(port_names) = &bus.port_names {
+            xml_writer.write_event(Event::Start(BytesStart::borrowed_name(b"port_names")))?;
+            for name in port_names.iter() {
+                write_text_element(xml_writer, "port_name", name)?;
+            }
+            xml_writer.write_event(Event::End(BytesEnd::borrowed(b"port_names")))?;
+        }
+
+        // Port Mixing
+        if bus.port_mixing != PortMixing::Off {
+            write_text_element(xml_writer, "port_mixing", bus.port_mixing.as_str())?;
+        }
+
+        // Channel Layout
+        match &bus.channel_layout {
+            None => (),
+            Some(ChannelLayout::Mono) => {
+                write_text_element(xml_writer, "channel_layout", "mono")?;
+            }
+            Some(ChannelLayout::Stereo) => {
+                write_text_element(xml_writer, "channel_layout", "stereo")?;
+            }
+            Some(ChannelLayout::Positions(positions)) => {
+                xml_writer
+                    .write_event(Event::Start(BytesStart::borrowed_name(b"channel_layout")))?;
+                for position in positions.iter() {
+                    write_text_element(xml_writer, "position", &channel_position_text(position))?;
+                }
+                xml_writer.write_event(Event::End(BytesEnd::borrowed(b"channel_layout")))?;
+            }
+        }
+
+        // Channel Gains
+        if let Some(channel_gains) = &bus.channel_gains {
+            xml_writer.write_event(Event::Start(BytesStart::borrowed_name(b"channel_gains")))?;
+            for gain in channel_gains.iter() {
+                write_text_element(xml_writer, "gain", &gain.to_string())?;
+            }
+            xml_writer.write_event(Event::End(BytesEnd::borrowed(b"channel_gains")))?;
+        }
+
         xml_writer.write_event(Event::End(BytesEnd::borrowed(b"bus")))?;
 
         Ok(())
@@ -283,6 +605,54 @@ pub fn write_config_to_file<P: Into<PathBuf>>(
         )))?;
         xml_writer.write_event(Event::End(BytesEnd::borrowed(b"port")))?;
 
+        // System Port Role
+        if let Some(role) = &controller.system_port_role {
+            let port_role_elem = BytesStart::owned(b"port_role".to_vec(), "port_role".len());
+            xml_writer.write_event(Event::Start(port_role_elem))?;
+            xml_writer.write_event(Event::Text(BytesText::from_plain_str(role.as_str())))?;
+            xml_writer.write_event(Event::End(BytesEnd::borrowed(b"port_role")))?;
+        }
+
+        // Port Name
+        if let Some(port_name) = &controller.port_name {
+            write_text_element(xml_writer, "port_name", port_name)?;
+        }
+
+        // Filter
+        if controller.filter != MidiFilter::default() {
+            let filter = &controller.filter;
+            xml_writer.write_event(Event::Start(BytesStart::borrowed_name(b"filter")))?;
+            write_text_element(
+                xml_writer,
+                "drop_realtime",
+                &filter.drop_realtime.to_string(),
+            )?;
+            write_text_element(xml_writer, "drop_sysex", &filter.drop_sysex.to_string())?;
+            write_text_element(
+                xml_writer,
+                "dropped_channels",
+                &filter.dropped_channels.to_string(),
+            )?;
+            xml_writer.write_event(Event::End(BytesEnd::borrowed(b"filter")))?;
+        }
+
+        // Buffer Capacity
+        if let Some(capacity) = controller.buffer_capacity {
+            write_text_element(xml_writer, "buffer_capacity", &capacity.to_string())?;
+        }
+
+        // Connections
+        if !controller.connections.is_empty() {
+            xml_writer.write_event(Event::Start(BytesStart::borrowed_name(b"connections")))?;
+            for port in controller.connections.iter() {
+                write_text_element(xml_writer, "connection", port)?;
+            }
+            xml_writer.write_event(Event::End(BytesEnd::borrowed(b"connections")))?;
+        }
+        if !controller.auto_connect {
+            write_text_element(xml_writer, "auto_connect", "false")?;
+        }
+
         xml_writer.write_event(Event::End(BytesEnd::borrowed(b"controller")))?;
 
         Ok(())
@@ -357,6 +727,20 @@ pub fn write_config_to_file<P: Into<PathBuf>>(
     xml_writer.write_event(Event::Text(BytesText::from_plain_str(&t)))?;
     xml_writer.write_event(Event::End(BytesEnd::borrowed(b"buffer_size")))?;
 
+    write_text_element(&mut xml_writer, "direction", config.direction.as_str())?;
+
+    if let Some(len) = config.buffer_size_history {
+        write_text_element(&mut xml_writer, "buffer_size_history", &len.to_string())?;
+    }
+
+    if let Some(timeout) = config.spawn_timeout {
+        write_text_element(
+            &mut xml_writer,
+            "spawn_timeout_micros",
+            &timeout.as_micros().to_string(),
+        )?;
+    }
+
     // Midi Server
 
     let midi_server_elem = BytesStart::owned(b"midi_server".to_vec(), "midi_server".len());
@@ -391,6 +775,71 @@ pub fn write_config_to_file<P: Into<PathBuf>>(
     }
     xml_writer.write_event(Event::End(BytesEnd::borrowed(b"midi_out_controllers")))?;
 
+    write_text_element(
+        &mut xml_writer,
+        "midi_in_timestamps",
+        config.midi_in_timestamps.as_str(),
+    )?;
+
+    write_text_element(
+        &mut xml_writer,
+        "input_peaks",
+        &config.input_peaks.to_string(),
+    )?;
+
+    write_text_element(
+        &mut xml_writer,
+        "allow_denormals",
+        &config.allow_denormals.to_string(),
+    )?;
+
+    // Resampling
+
+    if let Some(resample) = &config.resample {
+        xml_writer.write_event(Event::Start(BytesStart::borrowed_name(b"resample")))?;
+        write_text_element(&mut xml_writer, "rate", &resample.sample_rate.to_string())?;
+        write_text_element(&mut xml_writer, "quality", resample.quality.as_str())?;
+        xml_writer.write_event(Event::End(BytesEnd::borrowed(b"resample")))?;
+    }
+
+    // Realtime Thread
+
+    xml_writer.write_event(Event::Start(BytesStart::borrowed_name(b"rt_thread")))?;
+    write_text_element(&mut xml_writer, "policy", config.rt_thread.policy.as_str())?;
+    write_text_element(
+        &mut xml_writer,
+        "priority",
+        &config.rt_thread.priority.to_string(),
+    )?;
+    write_text_element(
+        &mut xml_writer,
+        "required",
+        &config.rt_thread.required.to_string(),
+    )?;
+    xml_writer.write_event(Event::End(BytesEnd::borrowed(b"rt_thread")))?;
+
+    write_text_element(
+        &mut xml_writer,
+        "on_duplicate_id",
+        config.on_duplicate_id.as_str(),
+    )?;
+
+    // Jack
+
+    write_text_element(
+        &mut xml_writer,
+        "jack_self_connect",
+        config.jack_self_connect.as_str(),
+    )?;
+    if let Some(server_name) = &config.jack_server_name {
+        write_text_element(&mut xml_writer, "jack_server_name", server_name)?;
+    }
+    write_text_element(
+        &mut xml_writer,
+        "jack_pretty_names",
+        &config.jack_pretty_names.to_string(),
+    )?;
+
     // End
 
     xml_writer.write_event(Event::End(BytesEnd::borrowed(b"config")))?;
@@ -403,6 +852,59 @@ pub fn write_config_to_file<P: Into<PathBuf>>(
     Ok(())
 }
 
+/// Write an element that only holds the given text.
+fn write_text_element(
+    xml_writer: &mut Writer<Cursor<Vec<u8>>>,
+    name: &str,
+    text: &str,
+) -> Result<(), ConfigFileError> {
+    xml_writer.write_event(Event::Start(BytesStart::borrowed_name(name.as_bytes())))?;
+    xml_writer.write_event(Event::Text(BytesText::from_plain_str(text)))?;
+    xml_writer.write_event(Event::End(BytesEnd::borrowed(name.as_bytes())))?;
+
+    Ok(())
+}
+
+/// The identifier of a channel position in config files. Discrete channels are counted
+/// from one, like in `ChannelPosition`'s `Display`.
+fn channel_position_text(position: &ChannelPosition) -> String {
+    match position {
+        ChannelPosition::Center => String::from("center"),
+        ChannelPosition::Left => String::from("left"),
+        ChannelPosition::Right => String::from("right"),
+        ChannelPosition::Lfe => String::from("lfe"),
+        ChannelPosition::SurroundLeft => String::from("surround_left"),
+        ChannelPosition::SurroundRight => String::from("surround_right"),
+        ChannelPosition::RearLeft => String::from("rear_left"),
+        ChannelPosition::RearRight => String::from("rear_right"),
+        ChannelPosition::Discrete(i) => (u32::from(*i) + 1).to_string(),
+    }
+}
+
+/// Parse a channel position from the identifier returned by `channel_position_text()`.
+fn parse_channel_position(text: &str) -> Option<ChannelPosition> {
+    Some(match text {
+        "center" => ChannelPosition::Center,
+        "left" => ChannelPosition::Left,
+        "right" => ChannelPosition::Right,
+        "lfe" => ChannelPosition::Lfe,
+        "surround_left" => ChannelPosition::SurroundLeft,
+        "surround_right" => ChannelPosition::SurroundRight,
+        "rear_left" => ChannelPosition::RearLeft,
+        "rear_right" => ChannelPosition::RearRight,
+        _ => match text.parse::<u16>() {
+            Ok(channel) if channel > 0 => ChannelPosition::Discrete(channel - 1),
+            _ => return None,
+        },
+    })
+}
+
+/// Parse the text of an element, which ends at `position` in the file.
+fn parse_text<T: FromStr>(text: &str, position: usize) -> Result<T, ConfigFileError> {
+    text.parse()
+        .map_err(|_| ConfigFileError::InvalidConfigFile(position))
+}
+
 #[derive(Debug)]
 pub enum ConfigFileError {
     Xml(quick_xml::Error),
@@ -447,6 +949,7 @@ impl From<std::io::Error> for ConfigFileError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{ResampleQuality, RtThreadPolicy};
 
     #[test]
     fn write_and_load_config() {
@@ -458,10 +961,14 @@ mod tests {
                 AudioBusConfig {
                     id: String::from("Mic #1"),
                     system_ports: vec![String::from("system:capture_1")],
+                    channel_layout: Some(ChannelLayout::Mono),
+                    ..Default::default()
                 },
                 AudioBusConfig {
                     id: String::from("Mic #2"),
                     system_ports: vec![String::from("system:capture_2")],
+                    port_mixing: PortMixing::Minus3Db,
+                    ..Default::default()
                 },
             ],
 
@@ -472,6 +979,9 @@ mod tests {
                         String::from("system:playback_1"),
                         String::from("system:playback_2"),
                     ],
+                    channel_layout: Some(ChannelLayout::Stereo),
+                    port_names: Some(vec![String::from("out_L"), String::from("out_R")]),
+                    ..Default::default()
                 },
                 AudioBusConfig {
                     id: String::from("Speaker #2"),
@@ -479,6 +989,12 @@ mod tests {
                         String::from("system:playback_3"),
                         String::from("system:playback_4"),
                     ],
+                    channel_layout: Some(ChannelLayout::Positions(vec![
+                        ChannelPosition::Lfe,
+                        ChannelPosition::Discrete(2),
+                    ])),
+                    channel_gains: Some(vec![0.5, 0.1]),
+                    ..Default::default()
                 },
             ],
 
@@ -488,10 +1004,20 @@ mod tests {
                 MidiControllerConfig {
                     id: String::from("Midi In #1"),
                     system_port: String::from("system:midi_capture_1"),
+                    port_name: Some(String::from("keys_in")),
+                    buffer_capacity: Some(2048),
+                    ..Default::default()
                 },
                 MidiControllerConfig {
                     id: String::from("Midi In #2"),
                     system_port: String::from("system:midi_capture_2"),
+                    system_port_role: Some(MidiPortRole::DefaultHardwareIn),
+                    filter: MidiFilter {
+                        drop_realtime: true,
+                        ..MidiFilter::default()
+                    }
+                    .drop_channels(9..=9),
+                    ..Default::default()
                 },
             ],
 
@@ -499,15 +1025,41 @@ mod tests {
                 MidiControllerConfig {
                     id: String::from("Midi Out #1"),
                     system_port: String::from("system:midi_playback_1"),
+                    system_port_role: Some(MidiPortRole::MidiThrough),
+                    auto_connect: false,
+                    ..Default::default()
                 },
                 MidiControllerConfig {
                     id: String::from("Midi Out #2"),
                     system_port: String::from("system:midi_playback_2"),
+                    connections: vec![String::from("a2j:Synth (playback): Synth MIDI 1")],
+                    ..Default::default()
                 },
             ],
 
             sample_rate: Some(44100),
             buffer_size: None,
+            direction: StreamDirection::OutputOnly,
+
+            midi_in_timestamps: MidiTimestamps::Absolute,
+
+            jack_self_connect: JackSelfConnect::Never,
+            jack_server_name: Some(String::from("studio")),
+            jack_pretty_names: true,
+            buffer_size_history: Some(64),
+            rt_thread: RtThreadConfig {
+                policy: RtThreadPolicy::RoundRobin,
+                priority: 80,
+                required: true,
+            },
+            spawn_timeout: Some(Duration::from_millis(2500)),
+            on_duplicate_id: FailOrRename::Rename,
+            input_peaks: true,
+            allow_denormals: true,
+            resample: Some(ResampleConfig {
+                sample_rate: 96000,
+                quality: ResampleQuality::Linear,
+            }),
         };
 
         write_config_to_file("test_config.xml", &config).unwrap();
@@ -516,4 +1068,22 @@ mod tests {
 
         assert_eq!(config, read_config);
     }
+
+    #[test]
+    fn unknown_port_role_is_invalid() {
+        std::fs::write(
+            "test_unknown_port_role.xml",
+            "<config version=\"0.1\">\n\
+             <midi_in_controllers>\n\
+             <controller id=\"Keys\"><port_role>default_keyboard</port_role></controller>\n\
+             </midi_in_controllers>\n\
+             </config>\n",
+        )
+        .unwrap();
+
+        let result = load_config_from_file("test_unknown_port_role.xml");
+        std::fs::remove_file("test_unknown_port_role.xml").unwrap();
+
+        assert!(matches!(result, Err(ConfigFileError::InvalidConfigFile(_))));
+    }
 }
@@ -0,0 +1,58 @@
+use std::sync::Mutex;
+
+use crate::StreamInfo;
+
+type Hook = Box<dyn FnOnce(&StreamInfo) + Send + 'static>;
+
+struct HookState {
+    hook: Option<Hook>,
+    stream_info: Option<StreamInfo>,
+    shut_down: bool,
+}
+
+/// The hook set with `StreamHandle::on_before_shutdown()`, shared between the stream handle
+/// and whichever thread notices that the stream has failed.
+pub(crate) struct ShutdownHook {
+    state: Mutex<HookState>,
+}
+
+impl ShutdownHook {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(HookState {
+                hook: None,
+                stream_info: None,
+                shut_down: false,
+            }),
+        }
+    }
+
+    /// Set the info that is passed to the hook once the stream is running. The hook is only
+    /// called after this.
+    pub fn set_stream_info(&self, stream_info: StreamInfo) {
+        self.state.lock().unwrap().stream_info = Some(stream_info);
+    }
+
+    /// Replace the hook. It is dropped without being called if the stream has already shut
+    /// down.
+    pub fn set(&self, hook: Hook) {
+        let mut state = self.state.lock().unwrap();
+        if !state.shut_down {
+            state.hook = Some(hook);
+        }
+    }
+
+    /// Call the hook if it hasn't been called yet. Every later call does nothing.
+    pub fn run(&self) {
+        let (hook, stream_info) = {
+            let mut state = self.state.lock().unwrap();
+            state.shut_down = true;
+            (state.hook.take(), state.stream_info.clone())
+        };
+
+        // The lock isn't held here in case the hook drops the stream handle.
+        if let (Some(hook), Some(stream_info)) = (hook, stream_info) {
+            hook(&stream_info);
+        }
+    }
+}
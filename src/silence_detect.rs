@@ -0,0 +1,397 @@
+use log::warn;
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::{AudioBusBuffer, StreamEventHandler};
+
+/// The maximum number of silence events that can be queued before the event thread gets to them.
+const EVENT_QUEUE_SIZE: usize = 256;
+
+/// How often the event thread checks for new silence events.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Settings for detecting when an audio input bus has gone silent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SilenceDetection {
+    /// A bus counts as silent while the peak level of all of its channels stays below this
+    /// (linear) threshold.
+    pub threshold: f32,
+
+    /// How long a bus has to be silent before `StreamEventHandler::input_silent` is called.
+    pub duration: Duration,
+}
+
+impl Default for SilenceDetection {
+    fn default() -> Self {
+        Self {
+            // -60 dBFS
+            threshold: 0.001,
+            duration: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SilenceEvent {
+    Silent(usize),
+    Active(usize),
+}
+
+/// The state shared between the realtime thread and the stream handle.
+struct SilenceDetectShared {
+    enabled: AtomicBool,
+
+    /// The bits of an `f32`.
+    threshold: AtomicU32,
+    duration_micros: AtomicU64,
+
+    /// Only ever locked by the event thread.
+    consumer: Mutex<Consumer<SilenceEvent>>,
+}
+
+/// The silence state of one audio input bus.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct BusState {
+    silent_frames: u64,
+    silent: bool,
+}
+
+/// The realtime half of the input silence detector. This never allocates or blocks.
+pub(crate) struct SilenceDetector {
+    shared: Arc<SilenceDetectShared>,
+    producer: Producer<SilenceEvent>,
+    bus_ids: Arc<Mutex<Vec<String>>>,
+    busses: Vec<BusState>,
+    was_enabled: bool,
+}
+
+impl SilenceDetector {
+    /// * `bus_ids` - The IDs of the audio input busses, in order.
+    pub fn new(bus_ids: Vec<String>) -> Self {
+        let (producer, consumer) = RingBuffer::<SilenceEvent>::new(EVENT_QUEUE_SIZE).split();
+        let defaults = SilenceDetection::default();
+
+        Self {
+            shared: Arc::new(SilenceDetectShared {
+                enabled: AtomicBool::new(false),
+                threshold: AtomicU32::new(defaults.threshold.to_bits()),
+                duration_micros: AtomicU64::new(defaults.duration.as_micros() as u64),
+                consumer: Mutex::new(consumer),
+            }),
+            producer,
+            busses: vec![BusState::default(); bus_ids.len()],
+            bus_ids: Arc::new(Mutex::new(bus_ids)),
+            was_enabled: false,
+        }
+    }
+
+    pub fn handle(&self) -> SilenceDetectHandle {
+        SilenceDetectHandle {
+            shared: Arc::clone(&self.shared),
+            bus_ids: Arc::clone(&self.bus_ids),
+            event_thread: Mutex::new(None),
+        }
+    }
+
+    /// The state of every input bus, in order. Busses added to a running stream get their
+    /// state appended to this, after their ID was given to `SilenceDetectHandle::add_bus()`.
+    #[cfg(any(test, target_os = "linux"))]
+    pub fn bus_states_mut(&mut self) -> &mut Vec<BusState> {
+        &mut self.busses
+    }
+
+    /// Update the silence state of every input bus with the first `frames` frames of this cycle.
+    pub fn process(&mut self, audio_in: &[AudioBusBuffer], frames: usize, sample_rate: u32) {
+        let enabled = self.shared.enabled.load(Ordering::Acquire);
+        if !enabled {
+            if self.was_enabled {
+                // Start from scratch once detection is enabled again.
+                for bus in self.busses.iter_mut() {
+                    *bus = BusState::default();
+                }
+                self.was_enabled = false;
+            }
+            return;
+        }
+        self.was_enabled = true;
+
+        let threshold = f32::from_bits(self.shared.threshold.load(Ordering::Relaxed));
+        let duration_frames = self.shared.duration_micros.load(Ordering::Relaxed)
+            * u64::from(sample_rate)
+            / 1_000_000;
+
+        for (bus_i, (bus, audio_buffer)) in self.busses.iter_mut().zip(audio_in.iter()).enumerate()
+        {
+            let peak = audio_buffer
+                .channel_buffers
+                .iter()
+                .flat_map(|channel| channel.iter().take(frames))
+                .fold(0.0f32, |peak, s| peak.max(s.abs()));
+
+            if peak < threshold {
+                bus.silent_frames = bus.silent_frames.saturating_add(frames as u64);
+
+                if !bus.silent && bus.silent_frames >= duration_frames {
+                    bus.silent = true;
+                    // If the queue is full the event is dropped. The state is still updated so
+                    // the next transition is reported correctly.
+                    let _ = self.producer.push(SilenceEvent::Silent(bus_i));
+                }
+            } else {
+                bus.silent_frames = 0;
+
+                if bus.silent {
+                    bus.silent = false;
+                    let _ = self.producer.push(SilenceEvent::Active(bus_i));
+                }
+            }
+        }
+    }
+}
+
+/// The non-realtime half of the input silence detector.
+pub(crate) struct SilenceDetectHandle {
+    shared: Arc<SilenceDetectShared>,
+    bus_ids: Arc<Mutex<Vec<String>>>,
+    event_thread: Mutex<Option<(Arc<AtomicBool>, JoinHandle<()>)>>,
+}
+
+impl SilenceDetectHandle {
+    /// Start detecting silence on the input busses. The handler is called from a separate
+    /// (non-realtime) thread. This replaces any handler that was set before.
+    pub fn enable(&self, settings: SilenceDetection, handler: Box<dyn StreamEventHandler>) {
+        self.disable();
+
+        // Drop any events from before.
+        if let Ok(mut consumer) = self.shared.consumer.lock() {
+            consumer.discard(EVENT_QUEUE_SIZE);
+        }
+
+        self.shared
+            .threshold
+            .store(settings.threshold.to_bits(), Ordering::Relaxed);
+        self.shared
+            .duration_micros
+            .store(settings.duration.as_micros() as u64, Ordering::Relaxed);
+        self.shared.enabled.store(true, Ordering::Release);
+
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = Arc::clone(&running);
+        let shared = Arc::clone(&self.shared);
+        let bus_ids = Arc::clone(&self.bus_ids);
+
+        let thread = std::thread::spawn(move || {
+            let mut handler = handler;
+
+            while thread_running.load(Ordering::Relaxed) {
+                if let Ok(mut consumer) = shared.consumer.lock() {
+                    while let Some(event) = consumer.pop() {
+                        let bus_i = match event {
+                            SilenceEvent::Silent(bus_i) | SilenceEvent::Active(bus_i) => bus_i,
+                        };
+                        let bus_id = match bus_ids.lock() {
+                            Ok(bus_ids) => bus_ids.get(bus_i).cloned(),
+                            Err(_) => None,
+                        };
+
+                        if let Some(bus_id) = bus_id {
+                            match event {
+                                SilenceEvent::Silent(_) => handler.input_silent(&bus_id),
+                                SilenceEvent::Active(_) => handler.input_active(&bus_id),
+                            }
+                        }
+                    }
+                }
+
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        });
+
+        if let Ok(mut event_thread) = self.event_thread.lock() {
+            *event_thread = Some((running, thread));
+        }
+    }
+
+    /// Report the silence of an input bus added to the end of the running stream's inputs.
+    /// This has to be called before the detector gets the bus's state.
+    #[cfg(any(test, target_os = "linux"))]
+    pub fn add_bus(&self, bus_id: &str) {
+        if let Ok(mut bus_ids) = self.bus_ids.lock() {
+            bus_ids.push(String::from(bus_id));
+        }
+    }
+
+    /// Stop detecting silence and drop the handler.
+    pub fn disable(&self) {
+        self.shared.enabled.store(false, Ordering::Release);
+
+        let event_thread = self.event_thread.lock().ok().and_then(|mut t| t.take());
+        if let Some((running, thread)) = event_thread {
+            running.store(false, Ordering::Relaxed);
+
+            if thread.join().is_err() {
+                warn!("The input silence event thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for SilenceDetectHandle {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestEventHandler {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl StreamEventHandler for TestEventHandler {
+        fn input_silent(&mut self, bus_id: &str) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("silent {}", bus_id));
+        }
+
+        fn input_active(&mut self, bus_id: &str) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("active {}", bus_id));
+        }
+    }
+
+    fn bus_ids() -> Vec<String> {
+        vec![String::from("Mic In"), String::from("Line In")]
+    }
+
+    fn cycle(mic: f32, line: f32) -> Vec<AudioBusBuffer> {
+        [mic, line]
+            .iter()
+            .map(|value| {
+                let mut buffer = AudioBusBuffer::new(1, 100);
+                buffer.clear_and_resize(100);
+                buffer[0][50] = *value;
+                buffer
+            })
+            .collect()
+    }
+
+    fn drain(detector: &SilenceDetector) -> Vec<SilenceEvent> {
+        let mut consumer = detector.shared.consumer.lock().unwrap();
+        std::iter::from_fn(|| consumer.pop()).collect()
+    }
+
+    #[test]
+    fn silence_then_signal() {
+        let mut detector = SilenceDetector::new(bus_ids());
+        let handle = detector.handle();
+
+        // Nothing is detected while disabled.
+        detector.process(&cycle(0.0, 0.0), 100, 1000);
+        assert!(drain(&detector).is_empty());
+
+        // 250ms at 1000Hz is 250 frames, so the third silent cycle fires.
+        handle.shared.enabled.store(true, Ordering::Release);
+        handle
+            .shared
+            .duration_micros
+            .store(250_000, Ordering::Relaxed);
+
+        detector.process(&cycle(0.0, 0.5), 100, 1000);
+        detector.process(&cycle(0.0005, 0.5), 100, 1000);
+        assert!(drain(&detector).is_empty());
+
+        detector.process(&cycle(0.0, 0.5), 100, 1000);
+        assert_eq!(drain(&detector), vec![SilenceEvent::Silent(0)]);
+
+        // Staying silent doesn't fire again.
+        detector.process(&cycle(0.0, 0.5), 100, 1000);
+        assert!(drain(&detector).is_empty());
+
+        // Any signal above the threshold is active again.
+        detector.process(&cycle(-0.25, 0.5), 100, 1000);
+        assert_eq!(drain(&detector), vec![SilenceEvent::Active(0)]);
+
+        // And the timer restarts.
+        detector.process(&cycle(0.0, 0.5), 100, 1000);
+        detector.process(&cycle(0.0, 0.5), 100, 1000);
+        assert!(drain(&detector).is_empty());
+    }
+
+    #[test]
+    fn handler_receives_bus_ids() {
+        let mut detector = SilenceDetector::new(bus_ids());
+        let handle = detector.handle();
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        handle.enable(
+            SilenceDetection {
+                threshold: 0.001,
+                duration: Duration::from_millis(100),
+            },
+            Box::new(TestEventHandler {
+                events: Arc::clone(&events),
+            }),
+        );
+
+        detector.process(&cycle(0.0, 0.5), 100, 1000);
+        detector.process(&cycle(0.5, 0.5), 100, 1000);
+
+        let start = std::time::Instant::now();
+        while events.lock().unwrap().len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        handle.disable();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![String::from("silent Mic In"), String::from("active Mic In")]
+        );
+    }
+
+    #[test]
+    fn added_busses_are_detected() {
+        let mut detector = SilenceDetector::new(vec![String::from("Mic In")]);
+        let handle = detector.handle();
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        handle.add_bus("Line In");
+        detector.bus_states_mut().push(BusState::default());
+
+        handle.enable(
+            SilenceDetection {
+                threshold: 0.001,
+                duration: Duration::from_millis(100),
+            },
+            Box::new(TestEventHandler {
+                events: Arc::clone(&events),
+            }),
+        );
+
+        detector.process(&cycle(0.5, 0.0), 100, 1000);
+
+        let start = std::time::Instant::now();
+        while events.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        handle.disable();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![String::from("silent Line In")]
+        );
+    }
+}
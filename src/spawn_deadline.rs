@@ -0,0 +1,117 @@
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::{SpawnPhase, SpawnRtThreadError};
+
+/// Bounds the time the whole spawn operation of a backend may take.
+pub(crate) struct SpawnDeadline {
+    deadline: Option<Instant>,
+}
+
+impl SpawnDeadline {
+    /// * `timeout` - `Config::spawn_timeout`. `None` means no limit.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            deadline: timeout.and_then(|t| Instant::now().checked_add(t)),
+        }
+    }
+
+    /// Return `SpawnRtThreadError::Timeout` if the deadline has passed.
+    ///
+    /// * `phase` - The phase that has just finished (or is about to start).
+    pub fn check(&self, phase: SpawnPhase) -> Result<(), SpawnRtThreadError> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(SpawnRtThreadError::Timeout { phase })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Run a call that might block for a long time, giving up once the deadline has passed.
+    ///
+    /// Without a deadline this just calls `f`. Otherwise `f` runs on a worker thread. If it
+    /// times out, the worker keeps running, and whatever `f` returns is dropped on the worker
+    /// once it is done.
+    pub fn run<T, F>(&self, phase: SpawnPhase, f: F) -> Result<T, SpawnRtThreadError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        if self.deadline.is_none() {
+            return Ok(f());
+        }
+
+        let (result_tx, result_rx) = mpsc::channel();
+
+        std::thread::Builder::new()
+            .name(format!("rusty-daw-io spawn ({})", phase))
+            .spawn(move || {
+                let _ = result_tx.send(f());
+            })
+            .map_err(|e| SpawnRtThreadError::PlatformSpecific(Box::new(e)))?;
+
+        self.recv(phase, &result_rx)?.ok_or_else(|| {
+            SpawnRtThreadError::PlatformSpecific(Box::new(std::io::Error::other(format!(
+                "the {} phase panicked",
+                phase
+            ))))
+        })
+    }
+
+    /// Wait for a message from a thread that does the work of a phase.
+    ///
+    /// Returns `Ok(None)` if the thread hung up without sending anything.
+    pub fn recv<T>(
+        &self,
+        phase: SpawnPhase,
+        rx: &mpsc::Receiver<T>,
+    ) -> Result<Option<T>, SpawnRtThreadError> {
+        match self.deadline {
+            Some(deadline) => {
+                match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(msg) => Ok(Some(msg)),
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        Err(SpawnRtThreadError::Timeout { phase })
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => Ok(None),
+                }
+            }
+            None => Ok(rx.recv().ok()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_out_slow_calls() {
+        let deadline = SpawnDeadline::new(Some(Duration::from_millis(50)));
+        assert!(deadline.check(SpawnPhase::Open).is_ok());
+        assert_eq!(deadline.run(SpawnPhase::Open, || 5).unwrap(), 5);
+
+        let start = Instant::now();
+        assert!(matches!(
+            deadline.run(SpawnPhase::Activate, || std::thread::sleep(
+                Duration::from_secs(2)
+            )),
+            Err(SpawnRtThreadError::Timeout {
+                phase: SpawnPhase::Activate
+            })
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        assert!(matches!(
+            deadline.check(SpawnPhase::Connect),
+            Err(SpawnRtThreadError::Timeout {
+                phase: SpawnPhase::Connect
+            })
+        ));
+
+        // Without a timeout nothing ever times out.
+        let deadline = SpawnDeadline::new(None);
+        assert!(deadline.check(SpawnPhase::Open).is_ok());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::{ChannelLayout, SampleFormat};
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub struct DeviceIndex(usize);
 
@@ -40,8 +42,31 @@ pub struct AudioBus {
     /// The ports of the system device that are connected to this bus.
     pub system_ports: Vec<String>,
 
+    /// The full names of the ports this client registered for this bus, one per channel,
+    /// e.g. to connect them with a patchbay when `JackSelfConnect::Never` is used.
+    ///
+    /// Only Jack creates ports of its own, so this is empty on every other backend.
+    pub client_ports: Vec<String>,
+
     /// The number of channels in this bus.
     pub channels: u16,
+
+    /// What each channel of this bus is for.
+    pub channel_layout: ChannelLayout,
+
+    /// The number of channels of this bus that were actually connected to a system port
+    /// when the stream was spawned.
+    pub connected_ports: u16,
+}
+
+impl AudioBus {
+    /// Returns `true` if every channel of this bus is connected to a system port.
+    ///
+    /// A bus can end up only partially connected if some of its connections couldn't be
+    /// made, in which case the channels that aren't connected are silent.
+    pub fn is_fully_connected(&self) -> bool {
+        self.connected_ports >= self.channels
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -59,6 +84,10 @@ pub struct MidiController {
 
     /// The name of the system port this controller is connected to.
     pub system_port: String,
+
+    /// The full name of the port this client registered for this controller. Only Jack
+    /// creates ports of its own, so this is `None` on every other backend.
+    pub client_port: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -70,4 +99,295 @@ pub struct StreamInfo {
     pub midi_out: Vec<MidiController>,
     pub sample_rate: u32,
     pub max_audio_buffer_size: u32,
+
+    /// The latency of the audio inputs in frames, i.e. how long it takes sound to get from
+    /// the device into the input buffers. This is the largest latency of all input busses,
+    /// or zero if there are none.
+    ///
+    /// For Jack this is the largest capture latency of the system ports the input busses
+    /// are connected to, as it was when the stream was spawned. Use
+    /// `StreamHandle::bus_latency()` for the current latency of a single bus.
+    pub latency_frames_in: u32,
+
+    /// The latency of the audio outputs in frames, i.e. how long it takes sound to get from
+    /// the output buffers to the device. This is the largest latency of all output busses,
+    /// or zero if there are none.
+    ///
+    /// For Jack this is the largest playback latency of the system ports the output busses
+    /// are connected to, as it was when the stream was spawned.
+    pub latency_frames_out: u32,
+
+    /// The format of the samples the device reads and writes. If this isn't
+    /// `SampleFormat::F32`, the backend converts between it and the `f32` buffers given to
+    /// the realtime thread.
+    ///
+    /// Every backend uses `f32` when the device supports it, so this can be checked to
+    /// find out whether the stream runs without any conversion. Jack is always `f32`. WASAPI
+    /// shared mode streams are always opened as `f32` too, and Windows converts to the
+    /// device's mix format.
+    pub sample_format: SampleFormat,
+}
+
+impl StreamInfo {
+    /// The number of channels of all audio input busses together.
+    pub fn total_audio_in_channels(&self) -> usize {
+        self.audio_in
+            .iter()
+            .map(|bus| usize::from(bus.channels))
+            .sum()
+    }
+
+    /// The number of channels of all audio output busses together.
+    pub fn total_audio_out_channels(&self) -> usize {
+        self.audio_out
+            .iter()
+            .map(|bus| usize::from(bus.channels))
+            .sum()
+    }
+
+    pub fn total_midi_in_controllers(&self) -> usize {
+        self.midi_in.len()
+    }
+
+    pub fn total_midi_out_controllers(&self) -> usize {
+        self.midi_out.len()
+    }
+
+    /// The audio input bus with the given `AudioBus::id_name`.
+    pub fn audio_in_bus_by_id(&self, id: &str) -> Option<&AudioBus> {
+        self.audio_in.iter().find(|bus| bus.id_name == id)
+    }
+
+    /// The audio output bus with the given `AudioBus::id_name`.
+    pub fn audio_out_bus_by_id(&self, id: &str) -> Option<&AudioBus> {
+        self.audio_out.iter().find(|bus| bus.id_name == id)
+    }
+
+    /// A flat summary of every audio bus, inputs first, each in the order they were
+    /// configured.
+    pub fn bus_summaries(&self) -> Vec<BusSummary> {
+        let summary = |bus: &AudioBus, direction: BusDirection| BusSummary {
+            id: bus.id_name.clone(),
+            direction,
+            channels: bus.channels,
+            system_ports: bus.system_ports.clone(),
+        };
+
+        self.audio_in
+            .iter()
+            .map(|bus| summary(bus, BusDirection::Input))
+            .chain(
+                self.audio_out
+                    .iter()
+                    .map(|bus| summary(bus, BusDirection::Output)),
+            )
+            .collect()
+    }
+
+    /// The full name of every port this client registered, in the order of `audio_in`,
+    /// `audio_out`, `midi_in` and `midi_out`, e.g. to list them for the user to connect in a
+    /// patchbay. See `AudioBus::client_ports` and `MidiController::client_port` for the
+    /// ports of a single bus/controller.
+    ///
+    /// Only Jack creates ports of its own, so this is empty on every other backend.
+    pub fn client_ports(&self) -> Vec<&str> {
+        self.audio_in
+            .iter()
+            .chain(self.audio_out.iter())
+            .flat_map(|bus| bus.client_ports.iter().map(|port| port.as_str()))
+            .chain(
+                self.midi_in
+                    .iter()
+                    .chain(self.midi_out.iter())
+                    .filter_map(|controller| controller.client_port.as_deref()),
+            )
+            .collect()
+    }
+}
+
+/// A latency in frames. The latency of a signal path can be anywhere between `min` and
+/// `max`, e.g. when it goes through several ports with different latencies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl LatencyRange {
+    /// The smallest range that covers all of `ranges`, or `None` if there are none.
+    pub fn span<I: IntoIterator<Item = LatencyRange>>(ranges: I) -> Option<LatencyRange> {
+        ranges.into_iter().reduce(|a, b| LatencyRange {
+            min: a.min.min(b.min),
+            max: a.max.max(b.max),
+        })
+    }
+}
+
+/// What one port of this client is connected to right now, which can differ from the
+/// config once the user rewired it in a patchbay, or if connecting a system port failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortConnections {
+    /// The full name of the port of this client, as in `AudioBus::client_ports`.
+    pub port: String,
+
+    /// The full names of every port it's connected to.
+    pub connected_to: Vec<String>,
+}
+
+/// Whether a bus is an input or an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusDirection {
+    Input,
+    Output,
+}
+
+/// A display-ready summary of an `AudioBus`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BusSummary {
+    /// The ID of the bus.
+    pub id: String,
+
+    pub direction: BusDirection,
+
+    /// The number of channels in the bus.
+    pub channels: u16,
+
+    /// The system ports the bus is connected to, one per channel.
+    pub system_ports: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bus(channels: u16, connected_ports: u16) -> AudioBus {
+        AudioBus {
+            id_name: String::from("Drums Mic"),
+            id_index: DeviceIndex::new(0),
+            system_device: String::from("Jack"),
+            system_half_duplex_device: None,
+            system_ports: vec![
+                String::from("system:capture_1"),
+                String::from("system:capture_2"),
+            ],
+            client_ports: Vec::new(),
+            channels,
+            channel_layout: ChannelLayout::for_channels(channels),
+            connected_ports,
+        }
+    }
+
+    #[test]
+    fn partially_connected_bus() {
+        assert!(bus(2, 2).is_fully_connected());
+        assert!(!bus(2, 1).is_fully_connected());
+        assert!(!bus(2, 0).is_fully_connected());
+    }
+
+    #[test]
+    fn bus_summaries_in_config_order() {
+        let mut speakers = bus(2, 2);
+        speakers.id_name = String::from("Speakers Out");
+        speakers.system_ports = vec![
+            String::from("system:playback_1"),
+            String::from("system:playback_2"),
+        ];
+        let mut headphones = bus(1, 1);
+        headphones.id_name = String::from("Headphones Out");
+        headphones.system_ports = vec![String::from("system:playback_3")];
+
+        let stream_info = StreamInfo {
+            server_name: String::from("Jack"),
+            audio_in: vec![bus(2, 2)],
+            audio_out: vec![speakers, headphones],
+            midi_in: Vec::new(),
+            midi_out: Vec::new(),
+            sample_rate: 48000,
+            max_audio_buffer_size: 256,
+            latency_frames_in: 0,
+            latency_frames_out: 0,
+            sample_format: SampleFormat::F32,
+        };
+
+        let summaries = stream_info.bus_summaries();
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|s| (s.id.as_str(), s.direction, s.channels))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Drums Mic", BusDirection::Input, 2),
+                ("Speakers Out", BusDirection::Output, 2),
+                ("Headphones Out", BusDirection::Output, 1),
+            ]
+        );
+        assert_eq!(
+            summaries[0].system_ports,
+            vec!["system:capture_1", "system:capture_2"]
+        );
+        assert_eq!(summaries[2].system_ports, vec!["system:playback_3"]);
+
+        assert_eq!(stream_info.total_audio_in_channels(), 2);
+        assert_eq!(stream_info.total_audio_out_channels(), 3);
+        assert_eq!(stream_info.total_midi_in_controllers(), 0);
+        assert_eq!(
+            stream_info
+                .audio_out_bus_by_id("Headphones Out")
+                .map(|bus| bus.channels),
+            Some(1)
+        );
+        assert!(stream_info.audio_in_bus_by_id("Headphones Out").is_none());
+    }
+
+    #[test]
+    fn client_ports_of_every_bus() {
+        let mut mic = bus(2, 2);
+        mic.client_ports = vec![
+            String::from("rusty-daw-io:Drums Mic_1"),
+            String::from("rusty-daw-io:Drums Mic_2"),
+        ];
+        let pads = MidiController {
+            id_name: String::from("Pads"),
+            id_index: DeviceIndex::new(0),
+            system_port: String::from("system:midi_capture_1"),
+            client_port: Some(String::from("rusty-daw-io:Pads")),
+        };
+
+        let stream_info = StreamInfo {
+            server_name: String::from("Jack"),
+            audio_in: vec![mic],
+            audio_out: vec![bus(2, 2)],
+            midi_in: vec![pads],
+            midi_out: Vec::new(),
+            sample_rate: 48000,
+            max_audio_buffer_size: 256,
+            latency_frames_in: 0,
+            latency_frames_out: 0,
+            sample_format: SampleFormat::F32,
+        };
+
+        assert_eq!(
+            stream_info.client_ports(),
+            vec![
+                "rusty-daw-io:Drums Mic_1",
+                "rusty-daw-io:Drums Mic_2",
+                "rusty-daw-io:Pads"
+            ]
+        );
+    }
+
+    #[test]
+    fn latency_span() {
+        let range = |min, max| LatencyRange { min, max };
+
+        assert_eq!(LatencyRange::span(Vec::new()), None);
+        assert_eq!(
+            LatencyRange::span(vec![range(256, 256)]),
+            Some(range(256, 256))
+        );
+        assert_eq!(
+            LatencyRange::span(vec![range(256, 512), range(128, 256), range(300, 1024)]),
+            Some(range(128, 1024))
+        );
+    }
 }
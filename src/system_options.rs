@@ -1,4 +1,8 @@
-use crate::{AudioBusConfig, Config, DevicesInfo, MidiControllerConfig};
+use crate::device_group::group_device_ports;
+use crate::{
+    AudioBusConfig, Config, DeviceGroup, DevicesInfo, FailOrRename, JackSelfConnect,
+    MidiControllerConfig, MidiTimestamps, RtThreadConfig, StreamDirection,
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct DisplayState {
@@ -81,12 +85,30 @@ pub struct MidiControllerDisplayState {
     pub system_port: SystemPortDisplayState,
 }
 
-#[derive(Debug, Copy, Clone, Default)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct BufferSizeRange {
     pub min: u32,
     pub max: u32,
 }
 
+impl BufferSizeRange {
+    pub fn contains(&self, buffer_size: u32) -> bool {
+        buffer_size >= self.min && buffer_size <= self.max
+    }
+
+    /// The buffer sizes that are in both ranges, or `None` if there are none.
+    pub fn intersection(&self, other: &BufferSizeRange) -> Option<BufferSizeRange> {
+        let min = self.min.max(other.min);
+        let max = self.max.min(other.max);
+        if min <= max {
+            Some(BufferSizeRange { min, max })
+        } else {
+            None
+        }
+    }
+}
+
 pub struct SystemOptions {
     devices_info: DevicesInfo,
 
@@ -890,8 +912,9 @@ impl SystemOptions {
             .get(self.display_state.current_sample_rate_index)
             .unwrap_or(&0);
 
-        self.devices_info.refresh_audio_servers();
-        self.devices_info.refresh_midi_servers();
+        // Servers that fail are marked as unavailable, which is all the options need.
+        let _ = self.devices_info.refresh_audio_servers();
+        let _ = self.devices_info.refresh_midi_servers();
 
         // Don't rebuild the config multiple times.
         self.do_build_config = false;
@@ -1236,13 +1259,16 @@ impl SystemOptions {
             self.display_state.current_buffer_size_str =
                 format!("{}", self.display_state.current_buffer_size);
 
-            if let Some(port) = device.in_ports.get(device.default_in_port) {
+            if let Some((port_i, port)) = device
+                .default_in_port
+                .and_then(|i| device.in_ports.get(i).map(|port| (i, port)))
+            {
                 self.display_state
                     .audio_in_busses
                     .push(AudioBusDisplayState {
                         id: String::from("Mic In"),
                         ports: vec![SystemPortDisplayState {
-                            current_system_port_index: device.default_in_port,
+                            current_system_port_index: port_i,
                             current_system_port_name: port.clone(),
                             can_remove: false,
                         }],
@@ -1382,6 +1408,7 @@ impl SystemOptions {
                     .iter()
                     .map(|p| p.current_system_port_name.clone())
                     .collect(),
+                ..Default::default()
             })
             .collect();
 
@@ -1396,6 +1423,7 @@ impl SystemOptions {
                     .iter()
                     .map(|p| p.current_system_port_name.clone())
                     .collect(),
+                ..Default::default()
             })
             .collect();
 
@@ -1411,6 +1439,7 @@ impl SystemOptions {
                         .map(|c| MidiControllerConfig {
                             id: c.id.clone(),
                             system_port: c.system_port.current_system_port_name.clone(),
+                            ..Default::default()
                         })
                         .collect(),
                     self.display_state
@@ -1419,6 +1448,7 @@ impl SystemOptions {
                         .map(|c| MidiControllerConfig {
                             id: c.id.clone(),
                             system_port: c.system_port.current_system_port_name.clone(),
+                            ..Default::default()
                         })
                         .collect(),
                 )
@@ -1439,10 +1469,24 @@ impl SystemOptions {
                 .get(self.display_state.current_sample_rate_index)
                 .map(|s| *s),
             buffer_size: Some(self.display_state.current_buffer_size),
+            direction: StreamDirection::default(),
 
             midi_server,
             midi_in_controllers,
             midi_out_controllers,
+
+            midi_in_timestamps: MidiTimestamps::default(),
+
+            jack_self_connect: JackSelfConnect::default(),
+            jack_server_name: None,
+            jack_pretty_names: false,
+            buffer_size_history: None,
+            rt_thread: RtThreadConfig::default(),
+            spawn_timeout: None,
+            on_duplicate_id: FailOrRename::default(),
+            input_peaks: false,
+            allow_denormals: false,
+            resample: None,
         };
         let sample_rate = self.devices_info.sample_rate(&config).unwrap_or(1);
         let latency_frames = self.devices_info.estimated_latency(&config).unwrap_or(0);
@@ -1458,6 +1502,7 @@ impl SystemOptions {
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum ConfigStatus {
     Ok {
         config: Config,
@@ -1477,27 +1522,60 @@ impl Default for ConfigStatus {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct AudioDeviceInfo {
     pub name: String,
     pub in_ports: Vec<String>,
     pub out_ports: Vec<String>,
+
+    /// The sample rates the device can run at. For a device with `in_ports` these are only
+    /// the rates that both its inputs and outputs support, so any of them works in duplex.
     pub sample_rates: Vec<u32>,
+
+    /// The buffer sizes of the outputs of the device.
     pub buffer_size_range: BufferSizeRange,
 
-    pub default_in_port: usize,
+    /// The buffer sizes at which the inputs and outputs of the device can be opened
+    /// together, or `None` if the device has no inputs. This can be narrower than
+    /// `buffer_size_range` on ALSA. See `supports_duplex()`.
+    pub duplex_buffer_size_range: Option<BufferSizeRange>,
+
+    /// The index of the default capture port in `in_ports`, or `None` if the device has no
+    /// inputs.
+    pub default_in_port: Option<usize>,
     pub default_out_port_left: usize,
     pub default_out_port_right: usize,
     pub default_sample_rate_index: usize,
     pub default_buffer_size: u32,
 }
 
+impl AudioDeviceInfo {
+    /// Whether a stream with both audio input and output busses can be opened on this device
+    /// at the given sample rate and buffer size.
+    pub fn supports_duplex(&self, sample_rate: u32, buffer_size: u32) -> bool {
+        self.sample_rates.contains(&sample_rate)
+            && self
+                .duplex_buffer_size_range
+                .map(|range| range.contains(buffer_size))
+                .unwrap_or(false)
+    }
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct AudioServerInfo {
     pub name: String,
     pub version: Option<String>,
     pub devices: Vec<AudioDeviceInfo>,
     pub available: bool,
 
+    /// The index in `devices` of the system's default device. This is 0 for Jack, which
+    /// only has one device, and for ALSA, whose first card is the default unless the ALSA
+    /// config says otherwise.
+    ///
+    /// Indices can change whenever the devices are enumerated again (e.g. when a USB device
+    /// is plugged in first after a reboot), so persist the name of a device instead and use
+    /// `device_by_name()` to find it again.
     pub default_device: usize,
 }
 
@@ -1511,14 +1589,35 @@ impl AudioServerInfo {
             default_device: 0,
         }
     }
+
+    /// The ports of every device, clustered into groups by the physical device they
+    /// (heuristically) originate from.
+    ///
+    /// This is useful for showing a grouped picker such as "Built-in Audio", "USB Interface", etc.
+    pub fn device_groups(&self) -> Vec<DeviceGroup> {
+        self.devices.iter().flat_map(group_device_ports).collect()
+    }
+
+    /// The device with the given name, e.g. as saved in a `Config::system_audio_device`.
+    pub fn device_by_name(&self, name: &str) -> Option<&AudioDeviceInfo> {
+        self.devices.iter().find(|d| d.name == name)
+    }
+
+    /// The system's default device (see `default_device`), or `None` if there are no
+    /// devices.
+    pub fn default_device_info(&self) -> Option<&AudioDeviceInfo> {
+        self.devices.get(self.default_device)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct MidiDeviceInfo {
     pub name: String,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct MidiServerInfo {
     pub name: String,
     pub version: Option<String>,
@@ -1541,3 +1640,36 @@ impl MidiServerInfo {
         }
     }
 }
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use crate::DummyBackend;
+
+    #[test]
+    fn serializes_audio_server_info() {
+        let server = DummyBackend {
+            sample_rate: 44100,
+            buffer_size: 256,
+            ..DummyBackend::default()
+        }
+        .audio_server_info();
+
+        let json = serde_json::to_value(&server).unwrap();
+
+        assert_eq!(json["name"], "Dummy");
+        assert_eq!(json["available"], true);
+        assert_eq!(json["version"], serde_json::Value::Null);
+
+        let device = &json["devices"][0];
+        assert_eq!(device["name"], "Dummy Device");
+        assert_eq!(device["in_ports"][1], "capture_2");
+        assert_eq!(device["out_ports"][0], "playback_1");
+        assert_eq!(device["sample_rates"][0], 44100);
+        assert_eq!(device["buffer_size_range"]["min"], 256);
+        assert_eq!(device["buffer_size_range"]["max"], 256);
+        assert_eq!(device["default_buffer_size"], 256);
+
+        let midi = serde_json::to_value(DummyBackend::default().midi_server_info()).unwrap();
+        assert_eq!(midi["in_devices"][0]["name"], "midi_capture_1");
+    }
+}
@@ -0,0 +1,426 @@
+//! Handlers that record what a stream did, for testing backends and the code around them.
+//!
+//! These are behind the `testing` feature and are not meant for production use: they lock
+//! a mutex and allocate on the realtime thread every cycle.
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::{FatalErrorHandler, FatalStreamError, ProcessControl, ProcessInfo, RtProcessHandler};
+use crate::{StreamInfo, TransportInfo};
+
+/// What `RecordingProcessHandler` saw in one call to `process`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleSummary {
+    pub audio_frames: usize,
+    pub sample_rate: u32,
+    pub cycle_start_frame: u64,
+    pub transport: Option<TransportInfo>,
+
+    /// The number of channels of each audio input bus.
+    pub audio_in_channels: Vec<usize>,
+    /// The number of channels of each audio output bus.
+    pub audio_out_channels: Vec<usize>,
+    /// Whether every audio input sample of the cycle was zero.
+    pub audio_in_silent: bool,
+    /// The samples of every audio input channel, bus by bus, if the handler was made with
+    /// `record_audio_in()`. Empty otherwise.
+    pub audio_in: Vec<Vec<Vec<f32>>>,
+
+    /// The number of midi input controllers.
+    pub midi_in_controllers: usize,
+    /// The number of midi output controllers.
+    pub midi_out_controllers: usize,
+    /// Every midi input event of the cycle, controller by controller.
+    pub midi_in: Vec<RecordedMidiEvent>,
+}
+
+/// A midi input event that `RecordingProcessHandler` received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedMidiEvent {
+    /// The index of its controller in `StreamInfo::midi_in`.
+    pub controller: usize,
+    pub delta_frames: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct Recording {
+    stream_info: Option<StreamInfo>,
+    cycles: Vec<CycleSummary>,
+    offline_cycles: usize,
+    sample_rate_changes: Vec<u32>,
+    buffer_size_changes: Vec<u32>,
+    busses_added: usize,
+    deinit: bool,
+}
+
+/// The state shared between a recording handler and the handle it is read through, with a
+/// condvar that is notified every time something is recorded.
+#[derive(Debug, Default)]
+struct Shared<T> {
+    state: Mutex<T>,
+    changed: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn update<F: FnOnce(&mut T)>(&self, f: F) {
+        f(&mut self.state.lock().unwrap());
+        self.changed.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, T> {
+        self.state.lock().unwrap()
+    }
+
+    /// Wait until `done` returns `true`, or up to `timeout`.
+    fn wait_until<F: FnMut(&T) -> bool>(&self, timeout: Duration, mut done: F) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+
+        while !done(&state) {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+
+        true
+    }
+}
+
+/// An `RtProcessHandler` that records a summary of every cycle. It leaves the outputs
+/// silent.
+///
+/// The handler is moved into the stream, so what it recorded is read through the
+/// `ProcessRecording` from `recording()`, even while the stream is running.
+///
+/// **Not for production use**, see the module docs.
+#[derive(Debug)]
+pub struct RecordingProcessHandler {
+    shared: Arc<Shared<Recording>>,
+    quit_after: Option<usize>,
+    record_audio_in: bool,
+}
+
+impl RecordingProcessHandler {
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(Shared::default()),
+            quit_after: None,
+            record_audio_in: false,
+        }
+    }
+
+    /// Return `ProcessControl::Quit` from the given cycle on (counting from 1), to stop the
+    /// stream once it has run that many cycles.
+    pub fn quit_after(mut self, cycles: usize) -> Self {
+        self.quit_after = Some(cycles);
+        self
+    }
+
+    /// Record the samples of the audio inputs in `CycleSummary::audio_in`, to check what the
+    /// backend fed the stream.
+    pub fn record_audio_in(mut self) -> Self {
+        self.record_audio_in = true;
+        self
+    }
+
+    /// What this handler has recorded so far.
+    pub fn recording(&self) -> ProcessRecording {
+        ProcessRecording {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Default for RecordingProcessHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecordingProcessHandler {
+    fn record(&mut self, proc_info: ProcessInfo, offline: bool) -> ProcessControl {
+        let summary = CycleSummary {
+            audio_frames: proc_info.audio_frames,
+            sample_rate: proc_info.sample_rate,
+            cycle_start_frame: proc_info.cycle_start_frame,
+            transport: proc_info.transport,
+            audio_in_channels: proc_info
+                .audio_in
+                .iter()
+                .map(|b| b.num_channels())
+                .collect(),
+            audio_out_channels: proc_info
+                .audio_out
+                .iter()
+                .map(|b| b.num_channels())
+                .collect(),
+            audio_in_silent: proc_info
+                .audio_in
+                .iter()
+                .all(|b| b.channels().iter().flatten().all(|s| *s == 0.0)),
+            audio_in: if self.record_audio_in {
+                proc_info
+                    .audio_in
+                    .iter()
+                    .map(|b| b.channels().iter().map(|c| c.to_vec()).collect())
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            midi_in_controllers: proc_info.midi_in.len(),
+            midi_out_controllers: proc_info.midi_out.len(),
+            midi_in: proc_info
+                .midi_in_events()
+                .map(|(controller, event)| RecordedMidiEvent {
+                    controller: controller.index(),
+                    delta_frames: event.delta_frames,
+                    data: event.data().to_vec(),
+                })
+                .collect(),
+        };
+
+        let mut cycles = 0;
+        self.shared.update(|recording| {
+            recording.cycles.push(summary);
+            if offline {
+                recording.offline_cycles += 1;
+            }
+            cycles = recording.cycles.len();
+        });
+
+        match self.quit_after {
+            Some(quit_after) if cycles >= quit_after => ProcessControl::Quit,
+            _ => ProcessControl::Continue,
+        }
+    }
+}
+
+impl RtProcessHandler for RecordingProcessHandler {
+    fn init(&mut self, stream_info: &StreamInfo) {
+        let stream_info = stream_info.clone();
+        self.shared
+            .update(|recording| recording.stream_info = Some(stream_info));
+    }
+
+    fn deinit(&mut self) {
+        self.shared.update(|recording| recording.deinit = true);
+    }
+
+    fn process(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+        self.record(proc_info, false)
+    }
+
+    fn process_offline(&mut self, proc_info: ProcessInfo) -> ProcessControl {
+        self.record(proc_info, true)
+    }
+
+    fn sample_rate_changed(&mut self, stream_info: &StreamInfo) {
+        let sample_rate = stream_info.sample_rate;
+        self.shared
+            .update(|recording| recording.sample_rate_changes.push(sample_rate));
+    }
+
+    fn buffer_size_changed(&mut self, stream_info: &StreamInfo) {
+        let buffer_size = stream_info.max_audio_buffer_size;
+        self.shared
+            .update(|recording| recording.buffer_size_changes.push(buffer_size));
+    }
+
+    fn busses_added(&mut self, _stream_info: &StreamInfo) {
+        self.shared.update(|recording| recording.busses_added += 1);
+    }
+}
+
+/// Reads what a `RecordingProcessHandler` recorded.
+#[derive(Debug, Clone)]
+pub struct ProcessRecording {
+    shared: Arc<Shared<Recording>>,
+}
+
+impl ProcessRecording {
+    /// The stream info given to `init`, or `None` if it hasn't been called yet.
+    pub fn stream_info(&self) -> Option<StreamInfo> {
+        self.shared.lock().stream_info.clone()
+    }
+
+    /// A summary of every cycle so far, in order. This includes the ones given to
+    /// `process_offline`.
+    pub fn cycles(&self) -> Vec<CycleSummary> {
+        self.shared.lock().cycles.clone()
+    }
+
+    /// The number of cycles so far.
+    pub fn num_cycles(&self) -> usize {
+        self.shared.lock().cycles.len()
+    }
+
+    /// The number of cycles that were given to `process_offline`.
+    pub fn num_offline_cycles(&self) -> usize {
+        self.shared.lock().offline_cycles
+    }
+
+    /// The total number of frames of every cycle so far.
+    pub fn total_frames(&self) -> u64 {
+        self.shared
+            .lock()
+            .cycles
+            .iter()
+            .map(|cycle| cycle.audio_frames as u64)
+            .sum()
+    }
+
+    /// The new sample rate of every call to `sample_rate_changed`.
+    pub fn sample_rate_changes(&self) -> Vec<u32> {
+        self.shared.lock().sample_rate_changes.clone()
+    }
+
+    /// The new max buffer size of every call to `buffer_size_changed`.
+    pub fn buffer_size_changes(&self) -> Vec<u32> {
+        self.shared.lock().buffer_size_changes.clone()
+    }
+
+    /// The number of calls to `busses_added`.
+    pub fn busses_added(&self) -> usize {
+        self.shared.lock().busses_added
+    }
+
+    /// Whether `deinit` has been called.
+    pub fn deinit_called(&self) -> bool {
+        self.shared.lock().deinit
+    }
+
+    /// Wait until the handler has run at least `cycles` cycles. Returns `false` if that
+    /// didn't happen within `timeout`.
+    pub fn wait_for_cycles(&self, cycles: usize, timeout: Duration) -> bool {
+        self.shared
+            .wait_until(timeout, |recording| recording.cycles.len() >= cycles)
+    }
+
+    /// Wait until `deinit` has been called. Returns `false` if that didn't happen within
+    /// `timeout`.
+    pub fn wait_for_deinit(&self, timeout: Duration) -> bool {
+        self.shared
+            .wait_until(timeout, |recording| recording.deinit)
+    }
+}
+
+/// A `FatalErrorHandler` that stores the error, so a test can check how the stream failed.
+///
+/// The handler is moved into the stream, so keep a clone of it to read the errors.
+///
+/// **Not for production use**, see the module docs.
+#[derive(Debug, Clone, Default)]
+pub struct CollectingFatalErrorHandler {
+    shared: Arc<Shared<Vec<FatalStreamError>>>,
+}
+
+impl CollectingFatalErrorHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of errors so far. A stream only ever reports one, but every clone of
+    /// this handler stores into the same list.
+    pub fn num_errors(&self) -> usize {
+        self.shared.lock().len()
+    }
+
+    /// Take every error that was stored so far, oldest first.
+    pub fn take_errors(&self) -> Vec<FatalStreamError> {
+        std::mem::take(&mut *self.shared.lock())
+    }
+
+    /// Wait until an error has been stored. Returns `false` if that didn't happen within
+    /// `timeout`.
+    pub fn wait_for_error(&self, timeout: Duration) -> bool {
+        self.shared.wait_until(timeout, |errors| !errors.is_empty())
+    }
+}
+
+impl FatalErrorHandler for CollectingFatalErrorHandler {
+    fn fatal_stream_error(self, error: FatalStreamError) {
+        self.shared.update(|errors| errors.push(error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dummy_backend::tests::test_config;
+    use crate::{Backend, DisconnectReason, DummyBackend, DummyMidiEvent};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn records_cycles_and_midi() {
+        let backend = DummyBackend {
+            buffer_size: 64,
+            midi_in_events: vec![DummyMidiEvent {
+                port: 0,
+                frame: 70,
+                data: vec![0x90, 60, 100],
+            }],
+            ..DummyBackend::default()
+        };
+        let handler = RecordingProcessHandler::new().quit_after(4);
+        let recording = handler.recording();
+
+        let stream = backend
+            .spawn_rt_thread(&test_config(), handler, CollectingFatalErrorHandler::new())
+            .unwrap();
+        assert!(recording.wait_for_deinit(TIMEOUT));
+        drop(stream);
+
+        assert_eq!(recording.stream_info().unwrap().max_audio_buffer_size, 64);
+
+        let cycles = recording.cycles();
+        assert_eq!(cycles.len(), 4);
+        assert_eq!(recording.total_frames(), 4 * 64);
+        assert!(cycles.iter().all(|cycle| cycle.audio_frames == 64));
+        assert_eq!(cycles[1].cycle_start_frame, 64);
+        assert_eq!(cycles[0].audio_out_channels, vec![2]);
+        assert_eq!(cycles[0].midi_in_controllers, 1);
+
+        assert!(cycles[0].midi_in.is_empty());
+        assert_eq!(
+            cycles[1].midi_in,
+            vec![RecordedMidiEvent {
+                controller: 0,
+                delta_frames: 6,
+                data: vec![0x90, 60, 100],
+            }]
+        );
+    }
+
+    #[test]
+    fn collects_fatal_errors() {
+        let backend = DummyBackend {
+            buffer_size: 64,
+            disconnect_after_frames: Some(128),
+            ..DummyBackend::default()
+        };
+        let error_handler = CollectingFatalErrorHandler::new();
+
+        let _stream = backend
+            .spawn_rt_thread(
+                &test_config(),
+                RecordingProcessHandler::new(),
+                error_handler.clone(),
+            )
+            .unwrap();
+        assert!(error_handler.wait_for_error(TIMEOUT));
+
+        let errors = error_handler.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].backend(), Some(Backend::Dummy));
+        assert_eq!(
+            errors[0].disconnect_reason(),
+            Some(DisconnectReason::DeviceRemoved)
+        );
+        assert_eq!(error_handler.num_errors(), 0);
+    }
+}
@@ -0,0 +1,36 @@
+/// The state of the audio server's transport, which is shared by every client of the
+/// server. Following it keeps the playhead in lockstep with the other clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransportInfo {
+    /// Whether the transport is rolling. This is `false` while the transport is still
+    /// waiting for slow-sync clients to get ready.
+    pub playing: bool,
+
+    /// The transport position of the first frame of this process cycle.
+    pub frame: u64,
+
+    /// The musical position, if the timebase master provides one.
+    pub bbt: Option<TransportBbt>,
+}
+
+/// A bar/beat/tick position, along with the tempo and time signature it was taken at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransportBbt {
+    /// The current bar, starting at 1.
+    pub bar: u32,
+
+    /// The current beat within the bar, starting at 1.
+    pub beat: u32,
+
+    /// The current tick within the beat, starting at 0.
+    pub tick: u32,
+
+    /// The numerator of the time signature.
+    pub beats_per_bar: f32,
+
+    /// The denominator of the time signature.
+    pub beat_type: f32,
+
+    pub ticks_per_beat: f64,
+    pub beats_per_minute: f64,
+}
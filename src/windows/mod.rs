@@ -1,134 +1,321 @@
-use std::marker::PhantomData;
-
 use super::{
-    AudioServerConfig, AudioServerInfo, BufferSizeInfo, MidiServerConfig, MidiServerInfo,
-    OsDevicesInfo, OsStreamHandle, RtProcessHandler, SpawnRtThreadError, StreamError, StreamInfo,
+    AddBusError, AudioBus, AudioBusConfig, AudioDeviceInfo, AudioServerInfo, Backend, Config,
+    EnumerationError, FatalErrorHandler, FreewheelError, FreewheelHook, LatencyRange,
+    MidiController, MidiControllerConfig, MidiServerInfo, OsDevicesInfo, OsStreamHandle,
+    PortConnections, RtProcessHandler, SpawnError, SpawnRtThreadError, StreamDiagnostics,
+    StreamInfo,
 };
+use std::marker::PhantomData;
+
+use crate::channel_gain::ChannelGainsHandle;
+use crate::shutdown_hook::ShutdownHook;
+use crate::silence_detect::SilenceDetectHandle;
+use crate::xruns::Xruns;
+
+mod wasapi_backend;
 
-pub struct WindowsStreamHandle<P: RtProcessHandler, E>
-where
-    E: 'static + Send + Sync + FnOnce(StreamError),
-{
+pub struct WindowsStreamHandle<P: RtProcessHandler, E: FatalErrorHandler> {
     stream_info: StreamInfo,
+    wasapi_handle: wasapi_backend::WasapiRtThreadHandle<P>,
     _phantom_p: PhantomData<P>,
     _phantom_e: PhantomData<E>,
 }
 
-impl<P: RtProcessHandler, E> OsStreamHandle for WindowsStreamHandle<P, E>
-where
-    E: 'static + Send + Sync + FnOnce(StreamError),
-{
+impl<P: RtProcessHandler, E: FatalErrorHandler> OsStreamHandle for WindowsStreamHandle<P, E> {
     type P = P;
     type E = E;
 
     fn stream_info(&self) -> &StreamInfo {
         &self.stream_info
     }
+
+    fn frames_processed(&self) -> u64 {
+        self.wasapi_handle.frames_processed()
+    }
+
+    fn debug_next_cycles(&self, n: u32) {
+        self.wasapi_handle.debug_next_cycles(n);
+    }
+
+    fn diagnostics(&self) -> StreamDiagnostics {
+        self.wasapi_handle.diagnostics()
+    }
+
+    fn recent_buffer_sizes(&self) -> Vec<u32> {
+        self.wasapi_handle.recent_buffer_sizes()
+    }
+
+    fn midi_in_dropped_events(&self, _controller_id: &str) -> Option<u64> {
+        None
+    }
+
+    fn set_master_gain(&self, gain: f32) {
+        self.wasapi_handle.master_gain_handle.set_master_gain(gain);
+    }
+
+    fn set_master_gain_ramp_time(&self, ramp_time: std::time::Duration) {
+        self.wasapi_handle
+            .master_gain_handle
+            .set_master_gain_ramp_time(ramp_time);
+    }
+
+    fn master_gain(&self) -> f32 {
+        self.wasapi_handle.master_gain_handle.master_gain()
+    }
+
+    fn channel_gains_handle(&self) -> Option<&ChannelGainsHandle> {
+        Some(&self.wasapi_handle.channel_gains_handle)
+    }
+
+    fn silence_detect_handle(&self) -> Option<&SilenceDetectHandle> {
+        Some(&self.wasapi_handle.silence_detect_handle)
+    }
+
+    fn shutdown_hook(&self) -> &ShutdownHook {
+        &self.wasapi_handle.shutdown_hook
+    }
+
+    fn bus_latency(&self, _bus_id: &str) -> Option<LatencyRange> {
+        None
+    }
+
+    fn port_connections(&self, _bus_id: &str) -> Option<Vec<PortConnections>> {
+        None
+    }
+
+    fn set_freewheel(&self, _enabled: bool) -> Result<(), FreewheelError> {
+        Err(FreewheelError::NotSupported(Backend::Wasapi))
+    }
+
+    fn is_freewheeling(&self) -> bool {
+        false
+    }
+
+    fn on_freewheel(&self, _hook: FreewheelHook) {}
+
+    fn add_audio_bus(
+        &mut self,
+        _bus: &AudioBusConfig,
+        _is_input: bool,
+    ) -> Result<AudioBus, AddBusError> {
+        Err(AddBusError::NotSupported(Backend::Wasapi))
+    }
+
+    fn add_midi_controller(
+        &mut self,
+        _controller: &MidiControllerConfig,
+        _is_input: bool,
+    ) -> Result<MidiController, AddBusError> {
+        Err(AddBusError::NotSupported(Backend::Wasapi))
+    }
+
+    fn xruns(&self) -> &Xruns {
+        &self.wasapi_handle.xruns
+    }
+
+    fn stop(&mut self) -> Option<P> {
+        self.wasapi_handle.stop()
+    }
 }
 
+#[derive(Debug)]
 pub struct WindowsDevicesInfo {
-    audio_servers_info: [AudioServerInfo; 1],
+    audio_servers_info: Vec<AudioServerInfo>,
     midi_servers_info: [MidiServerInfo; 1],
 }
 
 impl Default for WindowsDevicesInfo {
     fn default() -> Self {
         let mut new_self = Self {
-            audio_servers_info: [
-                AudioServerInfo::new(String::from("Jack"), None), // TODO: Get Jack version?
-            ],
-            midi_servers_info: [
-                MidiServerInfo::new(String::from("Jack"), None), // TODO: Get Jack version?
-            ],
+            audio_servers_info: vec![AudioServerInfo::new(
+                String::from(Backend::Wasapi.as_str()),
+                None,
+            )],
+            midi_servers_info: [MidiServerInfo::new(String::from("WinMM"), None)],
         };
 
-        new_self.refresh_audio_servers();
-        new_self.refresh_midi_servers();
+        // The last server is CPAL (if enabled), as a fallback to WASAPI.
+        #[cfg(feature = "cpal")]
+        new_self.audio_servers_info.push(AudioServerInfo::new(
+            String::from(Backend::Cpal.as_str()),
+            None,
+        ));
+
+        // Each server is marked as unavailable if it fails.
+        let _ = new_self.refresh_audio_servers();
+        let _ = new_self.refresh_midi_servers();
 
         new_self
     }
 }
 
 impl OsDevicesInfo for WindowsDevicesInfo {
-    fn refresh_audio_servers(&mut self) {
-        // First server is Jack
-        //jack_backend::refresh_audio_server(&mut self.audio_servers_info[0]);
+    fn refresh_audio_servers(&mut self) -> Result<(), EnumerationError> {
+        // First server is WASAPI
+        let result = wasapi_backend::refresh_audio_server(&mut self.audio_servers_info[0]);
+
+        // Last server is CPAL
+        #[cfg(feature = "cpal")]
+        let result = result.and(crate::cpal_backend::refresh_audio_server(
+            self.audio_servers_info.last_mut().unwrap(),
+        ));
+
+        result
     }
 
-    fn refresh_midi_servers(&mut self) {
-        // First server is Jack
-        //jack_backend::refresh_midi_server(&mut self.midi_servers_info[0]);
+    fn refresh_audio_servers_timeout(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> Result<(), EnumerationError> {
+        // First server is WASAPI
+        let result = crate::enumeration::refresh_with_timeout(
+            &mut self.audio_servers_info[0],
+            Backend::Wasapi,
+            timeout,
+            wasapi_backend::refresh_audio_server,
+        );
+
+        // Last server is CPAL
+        #[cfg(feature = "cpal")]
+        let result = result.and(crate::enumeration::refresh_with_timeout(
+            self.audio_servers_info.last_mut().unwrap(),
+            Backend::Cpal,
+            timeout,
+            crate::cpal_backend::refresh_audio_server,
+        ));
+
+        result
+    }
+
+    fn refresh_midi_servers(&mut self) -> Result<(), EnumerationError> {
+        // First server is WinMM
+        wasapi_backend::refresh_midi_server(&mut self.midi_servers_info[0])
+    }
+
+    fn refresh_servers(&mut self) -> Result<(), EnumerationError> {
+        // WASAPI and WinMM have nothing to share, so they are refreshed one after another.
+        let result = self.refresh_audio_servers();
+        result.and(self.refresh_midi_servers())
     }
 
     fn audio_servers_info(&self) -> &[AudioServerInfo] {
         &self.audio_servers_info
     }
-
     fn midi_servers_info(&self) -> &[MidiServerInfo] {
         &self.midi_servers_info
     }
 
-    fn default_audio_server(&self) -> String {
-        if self.audio_servers_info[0].available {
-            String::from("ALSA")
-        } else {
-            String::from("Jack")
-        }
+    fn default_audio_server(&self) -> &String {
+        // Only WASAPI server for now.
+        &self.audio_servers_info[0].name
     }
-    fn default_midi_config(&self) -> String {
-        if self.midi_servers_info[0].available {
-            String::from("ALSA")
-        } else {
-            String::from("Jack")
-        }
+    fn default_midi_server(&self) -> &String {
+        // Only WinMM server for now.
+        &self.midi_servers_info[0].name
     }
 
-    fn estimated_latency(&self, audio_config: &AudioServerConfig) -> u32 {
-        match audio_config.server.as_str() {
-            "Jack" => {
-                if self.audio_servers_info[0].available {
-                    // First server is Jack.
-                    // Jack only ever uses one "duplex device".
-                    // Buffer size in Jack is always constant.
-                    if let BufferSizeInfo::ConstantSize(size) =
-                        &self.audio_servers_info[0].devices[0].buffer_size
-                    {
-                        return *size;
-                    }
+    fn estimated_latency(&self, config: &Config) -> Option<u32> {
+        match config.backend() {
+            Some(Backend::Wasapi) => {
+                // First server is WASAPI.
+                // Shared mode always uses the default device period.
+                if let Some(device) = self.wasapi_device(config) {
+                    return Some(config.buffer_size.unwrap_or(device.default_buffer_size));
+                }
+            }
+            #[cfg(feature = "cpal")]
+            Some(Backend::Cpal) => {
+                // Last server is CPAL.
+                if let Some(server) = self.audio_servers_info.last() {
+                    return crate::cpal_backend::estimated_latency(server, config);
                 }
             }
             _ => {}
         }
 
-        0
+        None
     }
 
-    fn sample_rate(&self, audio_config: &AudioServerConfig) -> u32 {
-        match audio_config.server.as_str() {
-            "Jack" => {
-                if self.audio_servers_info[0].available {
-                    // First server is Jack.
-                    // Jack only ever uses one "duplex device".
-                    // Only one sample rate is available, which is the sample rate of the running Jack server.
-                    return self.audio_servers_info[0].devices[0].sample_rates[0];
+    fn sample_rate(&self, config: &Config) -> Option<u32> {
+        match config.backend() {
+            Some(Backend::Wasapi) => {
+                // First server is WASAPI.
+                // Shared mode runs at the sample rate of the endpoint's mix format.
+                if let Some(device) = self.wasapi_device(config) {
+                    return Some(config.sample_rate.unwrap_or(device.sample_rates[0]));
+                }
+            }
+            #[cfg(feature = "cpal")]
+            Some(Backend::Cpal) => {
+                // Last server is CPAL.
+                if let Some(server) = self.audio_servers_info.last() {
+                    return crate::cpal_backend::sample_rate(server, config);
                 }
             }
             _ => {}
         }
 
-        1
+        None
+    }
+}
+
+impl WindowsDevicesInfo {
+    fn wasapi_device(&self, config: &Config) -> Option<&AudioDeviceInfo> {
+        self.audio_servers_info[0]
+            .devices
+            .iter()
+            .find(|d| d.name == config.system_audio_device)
+    }
+}
+
+/// Refresh the devices of a single audio server, or return `None` if the backend isn't
+/// compiled in.
+pub fn refresh_audio_server(
+    backend: Backend,
+    server: &mut AudioServerInfo,
+) -> Option<Result<(), EnumerationError>> {
+    if backend != Backend::Wasapi {
+        return None;
     }
+
+    Some(wasapi_backend::refresh_audio_server(server))
 }
 
-pub fn spawn_rt_thread<P: RtProcessHandler, E>(
-    audio_config: &AudioServerConfig,
-    midi_config: Option<&MidiServerConfig>,
-    use_client_name: Option<String>,
+/// Enumerate the devices of a single midi server, or `None` if the backend isn't compiled in.
+pub fn enumerate_midi_server(backend: Backend) -> Option<MidiServerInfo> {
+    if backend != Backend::Wasapi {
+        return None;
+    }
+
+    let mut server = MidiServerInfo::new(String::from("WinMM"), None);
+    // The server is marked as unavailable if it fails.
+    let _ = wasapi_backend::refresh_midi_server(&mut server);
+
+    Some(server)
+}
+
+pub fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
+    backend: Backend,
+    config: &Config,
+    _use_client_name: Option<String>,
     rt_process_handler: P,
-    error_callback: E,
-) -> Result<WindowsStreamHandle<P, E>, SpawnRtThreadError>
-where
-    E: 'static + Send + Sync + FnOnce(StreamError),
-{
-    Err(SpawnRtThreadError::AudioServerUnavailable(String::from("")))
+    fatal_error_handler: E,
+) -> Result<WindowsStreamHandle<P, E>, SpawnError<P>> {
+    match backend {
+        Backend::Wasapi => {
+            let (stream_info, wasapi_handle) =
+                wasapi_backend::spawn_rt_thread(config, rt_process_handler, fatal_error_handler)?;
+
+            Ok(WindowsStreamHandle {
+                stream_info,
+                wasapi_handle,
+                _phantom_p: PhantomData,
+                _phantom_e: PhantomData,
+            })
+        }
+        backend => Err(SpawnError::new(
+            SpawnRtThreadError::BackendNotCompiled(backend),
+            Some(rt_process_handler),
+        )),
+    }
 }
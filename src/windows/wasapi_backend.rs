@@ -0,0 +1,1529 @@
+use log::{debug, info, warn};
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::ffi::OsString;
+use std::os::windows::ffi::OsStringExt;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use winapi::shared::guiddef::{IsEqualIID, REFIID};
+use winapi::shared::ksmedia::KSDATAFORMAT_SUBTYPE_IEEE_FLOAT;
+use winapi::shared::minwindef::{DWORD, FALSE, UINT, ULONG};
+use winapi::shared::mmreg::{WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVE_FORMAT_EXTENSIBLE};
+use winapi::shared::winerror::{
+    E_NOINTERFACE, E_POINTER, FAILED, HRESULT, HRESULT_FROM_WIN32, RPC_E_CHANGED_MODE, SUCCEEDED,
+    S_OK,
+};
+use winapi::shared::wtypes::PROPERTYKEY;
+use winapi::um::audioclient::{
+    IAudioCaptureClient, IAudioClient, IAudioRenderClient, AUDCLNT_BUFFERFLAGS_SILENT,
+    AUDCLNT_E_DEVICE_INVALIDATED,
+};
+use winapi::um::audiosessiontypes::{AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_EVENTCALLBACK};
+use winapi::um::combaseapi::{
+    CoCreateInstance, CoInitializeEx, CoTaskMemFree, CoUninitialize, PropVariantClear, CLSCTX_ALL,
+};
+use winapi::um::coml2api::STGM_READ;
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::functiondiscoverykeys_devpkey::PKEY_Device_FriendlyName;
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::mmdeviceapi::{
+    eCapture, eConsole, eRender, CLSID_MMDeviceEnumerator, EDataFlow, ERole, IMMDevice,
+    IMMDeviceCollection, IMMDeviceEnumerator, IMMNotificationClient, IMMNotificationClientVtbl,
+    DEVICE_STATE_ACTIVE,
+};
+use winapi::um::objbase::COINIT_MULTITHREADED;
+use winapi::um::propidl::PROPVARIANT;
+use winapi::um::propsys::IPropertyStore;
+use winapi::um::synchapi::{CreateEventW, WaitForSingleObject};
+use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+use winapi::um::winbase::WAIT_OBJECT_0;
+use winapi::um::winnt::{HANDLE, LPCWSTR, LPWSTR};
+use winapi::Interface;
+
+use crate::channel_gain::{ChannelGains, ChannelGainsHandle};
+use crate::cycle_debug::{cycle_debugger, CycleDebugHandle, CycleDebugInfo, CycleDebugger};
+use crate::denormals::DenormalGuard;
+use crate::diagnostics::BufferSizeHistory;
+use crate::master_gain::{MasterGain, MasterGainHandle};
+use crate::port_mixing::{channel_routes, ChannelRoute};
+use crate::process_panic::catch_process_panic;
+use crate::rt_thread::promote_current_thread;
+use crate::shutdown_hook::ShutdownHook;
+use crate::silence_detect::{SilenceDetectHandle, SilenceDetector};
+use crate::spawn_deadline::SpawnDeadline;
+use crate::xruns::Xruns;
+use crate::{
+    AudioBus, AudioBusBuffer, AudioBusConfig, AudioDeviceInfo, AudioServerInfo, Backend,
+    BufferSizeRange, Config, DeviceIndex, DisconnectReason, EnumerationError, FatalErrorHandler,
+    FatalStreamError, MidiServerInfo, ProcessControl, ProcessInfo, RtProcessHandler, SampleFormat,
+    SpawnError, SpawnPhase, SpawnRtThreadError, StreamDiagnostics, StreamInfo,
+};
+
+// These flags are missing from winapi. They let shared mode streams convert to and from the
+// engine's mix format automatically.
+const AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM: DWORD = 0x8000_0000;
+const AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY: DWORD = 0x0800_0000;
+
+/// WASAPI durations are in units of 100 nanoseconds.
+const REFTIMES_PER_SEC: i64 = 10_000_000;
+
+/// How long the render thread waits for the device before checking if it should stop.
+const EVENT_TIMEOUT_MS: DWORD = 1000;
+
+/// The capacity of the capture fifos, in multiples of the render buffer size.
+const CAPTURE_FIFO_PERIODS: usize = 4;
+
+const PLAYBACK_PORT_PREFIX: &str = "playback_";
+
+pub fn refresh_audio_server(server: &mut AudioServerInfo) -> Result<(), EnumerationError> {
+    info!("Refreshing list of available WASAPI audio devices...");
+
+    server.devices.clear();
+
+    match enumerate_devices() {
+        Ok((devices, default_device)) => {
+            if devices.is_empty() {
+                // This crate only allows devices with playback.
+
+                server.available = false;
+
+                info!("WASAPI server is unavailable: no active render endpoints were found.");
+            } else {
+                server.devices = devices;
+                server.default_device = default_device;
+                server.available = true;
+            }
+
+            Ok(())
+        }
+        Err(e) => {
+            server.available = false;
+
+            info!("WASAPI server is unavailable: {}", e);
+
+            Err(EnumerationError::PlatformSpecific(Box::new(e)))
+        }
+    }
+}
+
+pub fn refresh_midi_server(server: &mut MidiServerInfo) -> Result<(), EnumerationError> {
+    info!("Refreshing list of available Windows MIDI devices...");
+
+    server.in_devices.clear();
+    server.out_devices.clear();
+
+    // TODO: Support WinMM MIDI.
+    server.available = false;
+
+    info!("Windows MIDI server is unavailable: Windows MIDI is not supported yet.");
+
+    Ok(())
+}
+
+/// Enumerate every active render endpoint as a device. Since WASAPI can combine any capture
+/// endpoint with any render endpoint, every device gets the ports of all capture endpoints.
+fn enumerate_devices() -> Result<(Vec<AudioDeviceInfo>, usize), WasapiError> {
+    let _com = ComGuard::new()?;
+    let enumerator = create_enumerator()?;
+
+    let captures = enumerate_endpoints(&enumerator, eCapture)?;
+    let default_capture = default_endpoint_id(&enumerator, eCapture);
+
+    let mut in_ports = Vec::<String>::new();
+    let mut default_in_port = None;
+    for capture in captures.iter() {
+        let format = match probe_endpoint(&capture.device) {
+            Ok(format) => format,
+            Err(e) => {
+                debug!("WASAPI: skipping capture endpoint {}: {}", &capture.name, e);
+                continue;
+            }
+        };
+
+        if Some(&capture.id) == default_capture.as_ref() {
+            default_in_port = Some(in_ports.len());
+        }
+
+        in_ports.append(&mut capture_port_names(&capture.name, format.channels));
+    }
+    if default_in_port.is_none() && !in_ports.is_empty() {
+        default_in_port = Some(0); // Fallback to the first capture port.
+    }
+
+    let renders = enumerate_endpoints(&enumerator, eRender)?;
+    let default_render = default_endpoint_id(&enumerator, eRender);
+
+    let mut devices = Vec::<AudioDeviceInfo>::new();
+    let mut default_device = 0;
+    for render in renders.iter() {
+        let format = match probe_endpoint(&render.device) {
+            Ok(format) => format,
+            Err(e) => {
+                debug!("WASAPI: skipping render endpoint {}: {}", &render.name, e);
+                continue;
+            }
+        };
+
+        if Some(&render.id) == default_render.as_ref() {
+            default_device = devices.len();
+        }
+
+        debug!(
+            "WASAPI: found render endpoint {} with {} channels at {}Hz, period {} frames",
+            &render.name, format.channels, format.sample_rate, format.default_period_frames
+        );
+
+        let buffer_size_range = BufferSizeRange {
+            // Shared mode always uses the default device period.
+            min: format.default_period_frames,
+            max: format.default_period_frames,
+        };
+
+        devices.push(AudioDeviceInfo {
+            name: render.name.clone(),
+            in_ports: in_ports.clone(),
+            out_ports: (1..=format.channels)
+                .map(|i| format!("{}{}", PLAYBACK_PORT_PREFIX, i))
+                .collect(),
+            // Shared mode always runs at the engine's mix format.
+            sample_rates: vec![format.sample_rate],
+            buffer_size_range,
+            // Captures are read at the period of the render endpoint, and resampled to its
+            // mix format.
+            duplex_buffer_size_range: if in_ports.is_empty() {
+                None
+            } else {
+                Some(buffer_size_range)
+            },
+
+            default_in_port,
+            default_out_port_left: 0,
+            default_out_port_right: 1.min(format.channels as usize - 1), // Second port if stereo, first if mono.
+            default_sample_rate_index: 0,
+            default_buffer_size: format.default_period_frames,
+        });
+    }
+
+    Ok((devices, default_device))
+}
+
+fn capture_port_names(endpoint_name: &str, channels: u16) -> Vec<String> {
+    (1..=channels)
+        .map(|i| format!("{}:capture_{}", endpoint_name, i))
+        .collect()
+}
+
+/// Parse the device channel a port like "playback_1" refers to.
+fn parse_playback_channel(port: &str) -> Option<usize> {
+    let n: usize = port.strip_prefix(PLAYBACK_PORT_PREFIX)?.parse().ok()?;
+
+    if n == 0 {
+        None
+    } else {
+        Some(n - 1)
+    }
+}
+
+pub struct WasapiRtThreadHandle<P: RtProcessHandler> {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<P>>,
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_debug_handle: CycleDebugHandle,
+    pub(super) master_gain_handle: MasterGainHandle,
+    pub(super) channel_gains_handle: ChannelGainsHandle,
+    pub(super) silence_detect_handle: SilenceDetectHandle,
+    pub(super) shutdown_hook: Arc<ShutdownHook>,
+
+    // WASAPI doesn't report xruns, so this stays at zero.
+    pub(super) xruns: Xruns,
+}
+
+impl<P: RtProcessHandler> WasapiRtThreadHandle<P> {
+    /// Stop the audio thread, close the streams, and return the process handler.
+    ///
+    /// Returns `None` if the stream was already stopped or the audio thread panicked.
+    pub fn stop(&mut self) -> Option<P> {
+        let thread = self.thread.take()?;
+
+        self.shutdown_hook.run();
+
+        self.running.store(false, Ordering::Relaxed);
+
+        match thread.join() {
+            Ok(rt_process_handler) => Some(rt_process_handler),
+            Err(_) => {
+                warn!("WASAPI: audio thread panicked");
+                None
+            }
+        }
+    }
+
+    /// The total number of frames that have been processed since the stream started.
+    pub fn frames_processed(&self) -> u64 {
+        self.frames_processed.load(Ordering::Relaxed)
+    }
+
+    /// Log detailed diagnostics for the next `n` process cycles, after which logging
+    /// stops automatically.
+    pub fn debug_next_cycles(&self, n: u32) {
+        self.cycle_debug_handle.debug_next_cycles(n);
+    }
+
+    /// A snapshot of the health of the stream.
+    pub fn diagnostics(&self) -> StreamDiagnostics {
+        StreamDiagnostics {
+            frames_processed: self.frames_processed(),
+            xruns: self.xruns.count(),
+            cycle_debug_queue: self.cycle_debug_handle.queue_stats(),
+            ..StreamDiagnostics::default()
+        }
+    }
+
+    /// The number of frames of each of the last few process cycles, oldest first.
+    pub fn recent_buffer_sizes(&self) -> Vec<u32> {
+        self.buffer_size_history.recent()
+    }
+}
+
+impl<P: RtProcessHandler> Drop for WasapiRtThreadHandle<P> {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+pub fn spawn_rt_thread<P: RtProcessHandler, E: FatalErrorHandler>(
+    config: &Config,
+    rt_process_handler: P,
+    fatal_error_handler: E,
+) -> Result<(StreamInfo, WasapiRtThreadHandle<P>), SpawnError<P>> {
+    info!("Spawning WASAPI thread...");
+
+    let deadline = SpawnDeadline::new(config.spawn_timeout);
+
+    if config.midi_enabled() {
+        warn!("WASAPI: MIDI is not supported yet, no MIDI controllers will be created");
+    }
+
+    let (cycle_debugger, cycle_debug_handle) = cycle_debugger();
+
+    let thread = WasapiThread {
+        config: config.clone(),
+        rt_process_handler,
+        fatal_error_handler: Some(fatal_error_handler),
+        running: Arc::new(AtomicBool::new(true)),
+        frames_processed: Arc::new(AtomicU64::new(0)),
+        buffer_size_history: Arc::new(BufferSizeHistory::new(config.buffer_size_history_len())),
+        cycle_debugger,
+        master_gain: MasterGain::new(),
+        channel_gains: ChannelGains::new(&config.audio_in_busses, &config.audio_out_busses),
+        silence_detector: SilenceDetector::new(
+            config
+                .audio_in_busses
+                .iter()
+                .map(|b| b.id.clone())
+                .collect(),
+        ),
+        shutdown_hook: Arc::new(ShutdownHook::new()),
+    };
+
+    let running = Arc::clone(&thread.running);
+    let frames_processed = Arc::clone(&thread.frames_processed);
+    let buffer_size_history = Arc::clone(&thread.buffer_size_history);
+    let master_gain_handle = thread.master_gain.handle();
+    let channel_gains_handle = thread.channel_gains.handle();
+    let silence_detect_handle = thread.silence_detector.handle();
+    let shutdown_hook = Arc::clone(&thread.shutdown_hook);
+
+    // COM objects can't be sent between threads, so the streams are opened on the audio
+    // thread, which then reports back whether that succeeded.
+    let (result_tx, result_rx) = mpsc::channel();
+
+    let join_handle = std::thread::Builder::new()
+        .name(String::from("rusty-daw-io WASAPI"))
+        .spawn(move || thread.run(result_tx))
+        .map_err(|e| SpawnError::new(SpawnRtThreadError::PlatformSpecific(Box::new(e)), None))?;
+
+    // The audio thread gives the handler back if it fails to open the stream.
+    let stream_info = match deadline.recv(SpawnPhase::Open, &result_rx) {
+        Ok(Some(Ok(stream_info))) => stream_info,
+        Ok(Some(Err(e))) => {
+            return Err(SpawnError::new(e, join_handle.join().ok()));
+        }
+        Err(e) => {
+            // The audio thread might still be stuck opening the device, so don't wait for it.
+            // It stops on its own once it gets to the process loop.
+            running.store(false, Ordering::Relaxed);
+            return Err(SpawnError::new(e, None));
+        }
+        Ok(None) => {
+            // The audio thread exited without reporting whether the stream was opened.
+            return Err(SpawnError::new(
+                SpawnRtThreadError::AudioServerUnavailable(String::from("WASAPI")),
+                join_handle.join().ok(),
+            ));
+        }
+    };
+
+    info!(
+        "Successfully spawned WASAPI thread. Device: {}, Sample rate: {}, Max audio buffer size: {}",
+        &config.system_audio_device, stream_info.sample_rate, stream_info.max_audio_buffer_size
+    );
+
+    Ok((
+        stream_info,
+        WasapiRtThreadHandle {
+            running,
+            thread: Some(join_handle),
+            frames_processed,
+            buffer_size_history,
+            cycle_debug_handle,
+            master_gain_handle,
+            channel_gains_handle,
+            silence_detect_handle,
+            shutdown_hook,
+            xruns: Xruns::new(),
+        },
+    ))
+}
+
+struct WasapiThread<P: RtProcessHandler, E: FatalErrorHandler> {
+    config: Config,
+
+    rt_process_handler: P,
+    fatal_error_handler: Option<E>,
+
+    running: Arc<AtomicBool>,
+    frames_processed: Arc<AtomicU64>,
+    buffer_size_history: Arc<BufferSizeHistory>,
+    cycle_debugger: CycleDebugger,
+    master_gain: MasterGain,
+    channel_gains: ChannelGains,
+    silence_detector: SilenceDetector,
+    shutdown_hook: Arc<ShutdownHook>,
+}
+
+impl<P: RtProcessHandler, E: FatalErrorHandler> WasapiThread<P, E> {
+    /// Run the stream until it is stopped or fails. This returns the process handler so
+    /// it can be handed back from `WasapiRtThreadHandle::stop()`.
+    fn run(mut self, result_tx: mpsc::Sender<Result<StreamInfo, SpawnRtThreadError>>) -> P {
+        // Reverted when the thread is done.
+        let _mmcss_task = match promote_current_thread(&self.config.rt_thread) {
+            Ok(task) => task,
+            Err(e) => {
+                let _ = result_tx.send(Err(e));
+                return self.rt_process_handler;
+            }
+        };
+
+        let mut stream = match open_stream(&self.config) {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = result_tx.send(Err(e));
+                return self.rt_process_handler;
+            }
+        };
+
+        self.rt_process_handler.init(&stream.stream_info);
+
+        if let Err(e) = stream.start() {
+            let _ = result_tx.send(Err(e.into()));
+            self.rt_process_handler.deinit();
+            return self.rt_process_handler;
+        }
+
+        self.shutdown_hook
+            .set_stream_info(stream.stream_info.clone());
+        let _ = result_tx.send(Ok(stream.stream_info.clone()));
+
+        if let Err(e) = self.process_loop(&mut stream) {
+            let error = match e {
+                LoopError::Wasapi(e) if e.hr == AUDCLNT_E_DEVICE_INVALIDATED => {
+                    FatalStreamError::AudioDeviceDisconnected {
+                        backend: Backend::Wasapi,
+                        reason: DisconnectReason::DeviceRemoved,
+                        message: format!(
+                            "WASAPI: render endpoint {} was removed",
+                            &self.config.system_audio_device
+                        ),
+                    }
+                }
+                LoopError::Wasapi(e) => FatalStreamError::PlatformSpecific(Box::new(e)),
+                LoopError::Panicked(error) => error,
+            };
+
+            info!("{}", error);
+
+            self.shutdown_hook.run();
+
+            if let Some(fatal_error_handler) = self.fatal_error_handler.take() {
+                fatal_error_handler.fatal_stream_error(error);
+            }
+        }
+
+        stream.stop();
+
+        self.rt_process_handler.deinit();
+        self.rt_process_handler
+    }
+
+    fn process_loop(&mut self, stream: &mut WasapiStream) -> Result<(), LoopError> {
+        let sample_rate = stream.stream_info.sample_rate;
+        let max_frames = stream.buffer_frames as usize;
+        let out_channels = stream.render_channels;
+
+        let mut audio_in_buffers = Vec::<AudioBusBuffer>::new();
+        let mut audio_out_buffers = Vec::<AudioBusBuffer>::new();
+
+        for bus in stream.stream_info.audio_in.iter() {
+            audio_in_buffers.push(AudioBusBuffer::for_input_bus(
+                bus,
+                max_frames as u32,
+                self.config.input_peaks,
+            ))
+        }
+        for bus in stream.stream_info.audio_out.iter() {
+            audio_out_buffers.push(AudioBusBuffer::for_bus(bus, max_frames as u32))
+        }
+
+        while self.running.load(Ordering::Relaxed) {
+            let wait = unsafe { WaitForSingleObject(stream.event, EVENT_TIMEOUT_MS) };
+
+            if stream
+                .notification_client
+                .device_lost
+                .load(Ordering::Relaxed)
+            {
+                return Err(LoopError::Wasapi(WasapiError {
+                    hr: AUDCLNT_E_DEVICE_INVALIDATED,
+                }));
+            }
+            if wait != WAIT_OBJECT_0 {
+                continue;
+            }
+            let cycle_start_time = Instant::now();
+
+            let mut padding = 0;
+            check(unsafe { stream.render_client.GetCurrentPadding(&mut padding) })?;
+
+            let audio_frames = (stream.buffer_frames - padding) as usize;
+            if audio_frames == 0 {
+                continue;
+            }
+
+            // Collect Audio Inputs
+
+            for capture in stream.captures.iter_mut() {
+                capture.read()?;
+            }
+
+            for (audio_buffer, routes) in audio_in_buffers
+                .iter_mut()
+                .zip(stream.in_channel_map.iter())
+            {
+                for (channel, route) in audio_buffer.channel_buffers.iter_mut().zip(routes.iter()) {
+                    // This should never allocate because each buffer was given a capacity of
+                    // the maximum buffer size.
+                    channel.clear();
+                    channel.resize(audio_frames, 0.0);
+
+                    // A channel routed from several capture channels gets their sum.
+                    for (capture_i, capture_channel) in route.ports.iter() {
+                        let capture = &stream.captures[*capture_i];
+                        for (dst, src) in channel.iter_mut().zip(
+                            capture
+                                .scratch
+                                .iter()
+                                .skip(*capture_channel)
+                                .step_by(capture.channels),
+                        ) {
+                            *dst += *src * route.gain;
+                        }
+                    }
+                }
+
+                audio_buffer.frames = audio_frames;
+            }
+
+            self.channel_gains
+                .apply_inputs(&mut audio_in_buffers, audio_frames, sample_rate);
+            for audio_buffer in audio_in_buffers.iter_mut() {
+                audio_buffer.update_peaks();
+            }
+            self.silence_detector
+                .process(&audio_in_buffers, audio_frames, sample_rate);
+
+            // Clear Audio Outputs
+
+            for audio_buffer in audio_out_buffers.iter_mut() {
+                audio_buffer.clear_and_resize(audio_frames);
+            }
+
+            let cycle_start_frame = self.frames_processed.load(Ordering::Relaxed);
+            let rt_process_handler = &mut self.rt_process_handler;
+            let flush_denormals = !self.config.allow_denormals;
+            let control = catch_process_panic(|| {
+                let _denormals = DenormalGuard::new(flush_denormals);
+                rt_process_handler.process(ProcessInfo {
+                    audio_in: audio_in_buffers.as_slice(),
+                    audio_out: audio_out_buffers.as_mut_slice(),
+                    audio_frames,
+
+                    midi_in: &[],
+                    midi_out: &mut [],
+
+                    sample_rate,
+                    transport: None,
+                    cycle_start_frame,
+                    cycle_start_time,
+                })
+            })
+            .map_err(LoopError::Panicked)?;
+
+            self.master_gain
+                .apply(&mut audio_out_buffers, audio_frames, sample_rate);
+            self.channel_gains
+                .apply_outputs(&mut audio_out_buffers, audio_frames, sample_rate);
+
+            // Copy processed data to Audio Outputs
+
+            let mut data = ptr::null_mut();
+            check(unsafe { stream.render.GetBuffer(audio_frames as u32, &mut data) })?;
+
+            let interleaved = unsafe {
+                std::slice::from_raw_parts_mut(data as *mut f32, audio_frames * out_channels)
+            };
+            interleave(
+                &audio_out_buffers,
+                &stream.out_channel_map,
+                out_channels,
+                audio_frames,
+                interleaved,
+            );
+
+            check(unsafe { stream.render.ReleaseBuffer(audio_frames as u32, 0) })?;
+
+            let frames_processed = self
+                .frames_processed
+                .fetch_add(audio_frames as u64, Ordering::Relaxed);
+            self.buffer_size_history.push(audio_frames);
+
+            if self.cycle_debugger.begin_cycle() {
+                self.cycle_debugger.log(CycleDebugInfo {
+                    frames_processed,
+                    audio_frames,
+                    audio_in_channels: stream.in_channel_map.iter().map(|c| c.len()).sum(),
+                    audio_out_channels: out_channels,
+                    midi_in_events: 0,
+                    midi_out_events: 0,
+                });
+            }
+
+            if control == ProcessControl::Quit {
+                info!("WASAPI: the process handler stopped the stream");
+                self.running.store(false, Ordering::Relaxed);
+                self.shutdown_hook.run();
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Every client and resource of a running stream. This only ever lives on the audio thread.
+struct WasapiStream {
+    stream_info: StreamInfo,
+
+    render_client: ComPtr<IAudioClient>,
+    render: ComPtr<IAudioRenderClient>,
+    render_channels: usize,
+    buffer_frames: u32,
+    event: HANDLE,
+
+    captures: Vec<CaptureStream>,
+
+    in_channel_map: Vec<Vec<ChannelRoute<(usize, usize)>>>,
+    out_channel_map: Vec<Vec<ChannelRoute<usize>>>,
+
+    enumerator: ComPtr<IMMDeviceEnumerator>,
+    notification_client: Box<NotificationClient>,
+
+    // This must be dropped last.
+    _com: ComGuard,
+}
+
+impl WasapiStream {
+    fn start(&mut self) -> Result<(), WasapiError> {
+        for capture in self.captures.iter() {
+            check(unsafe { capture.client.Start() })?;
+        }
+        check(unsafe { self.render_client.Start() })
+    }
+
+    fn stop(&mut self) {
+        unsafe {
+            for capture in self.captures.iter() {
+                capture.client.Stop();
+            }
+            self.render_client.Stop();
+        }
+    }
+}
+
+impl Drop for WasapiStream {
+    fn drop(&mut self) {
+        unsafe {
+            self.enumerator.UnregisterEndpointNotificationCallback(
+                &mut *self.notification_client as *mut NotificationClient
+                    as *mut IMMNotificationClient,
+            );
+            CloseHandle(self.event);
+        }
+    }
+}
+
+/// A shared mode capture client. Captured packets are queued in a fifo until the render
+/// thread needs them.
+struct CaptureStream {
+    client: ComPtr<IAudioClient>,
+    capture: ComPtr<IAudioCaptureClient>,
+    channels: usize,
+
+    fifo_producer: Producer<f32>,
+    fifo_consumer: Consumer<f32>,
+
+    /// The interleaved samples for the current cycle.
+    scratch: Vec<f32>,
+}
+
+impl CaptureStream {
+    /// Read all available packets and fill `scratch` with one render cycle of samples.
+    fn read(&mut self) -> Result<(), WasapiError> {
+        loop {
+            let mut packet_frames = 0;
+            check(unsafe { self.capture.GetNextPacketSize(&mut packet_frames) })?;
+            if packet_frames == 0 {
+                break;
+            }
+
+            let mut data = ptr::null_mut();
+            let mut flags = 0;
+            check(unsafe {
+                self.capture.GetBuffer(
+                    &mut data,
+                    &mut packet_frames,
+                    &mut flags,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            })?;
+
+            let samples = packet_frames as usize * self.channels;
+            if flags & AUDCLNT_BUFFERFLAGS_SILENT != 0 {
+                for _ in 0..samples {
+                    if self.fifo_producer.push(0.0).is_err() {
+                        break;
+                    }
+                }
+            } else {
+                let packet = unsafe { std::slice::from_raw_parts(data as *const f32, samples) };
+
+                // If the fifo is full the newest samples are dropped.
+                self.fifo_producer.push_slice(packet);
+            }
+
+            check(unsafe { self.capture.ReleaseBuffer(packet_frames) })?;
+        }
+
+        // If not enough samples were captured the rest of the cycle is silent.
+        let read = self.fifo_consumer.pop_slice(&mut self.scratch);
+        for s in self.scratch[read..].iter_mut() {
+            *s = 0.0;
+        }
+
+        Ok(())
+    }
+}
+
+fn open_stream(config: &Config) -> Result<WasapiStream, SpawnRtThreadError> {
+    let com = ComGuard::new()?;
+    let enumerator = create_enumerator()?;
+
+    // Find the render endpoint.
+
+    let renders = enumerate_endpoints(&enumerator, eRender)?;
+    let render_endpoint = renders
+        .iter()
+        .find(|e| e.name == config.system_audio_device)
+        .ok_or_else(|| {
+            SpawnRtThreadError::SystemDeviceNotFound(config.system_audio_device.clone())
+        })?;
+    let render_format = probe_endpoint(&render_endpoint.device)?;
+
+    let sample_rate = config.sample_rate.unwrap_or(render_format.sample_rate);
+
+    // Map every bus channel to its endpoint channel.
+
+    let (audio_out_busses, out_channel_map) = map_out_busses(
+        &config.audio_out_busses,
+        &config.system_audio_device,
+        render_format.channels as usize,
+    )?;
+
+    let captures = enumerate_endpoints(&enumerator, eCapture)?;
+    let mut capture_ports = Vec::<(String, usize, usize)>::new();
+    let mut capture_formats = Vec::<Option<EndpointFormat>>::new();
+    for (capture_i, capture) in captures.iter().enumerate() {
+        let format = probe_endpoint(&capture.device).ok();
+
+        if let Some(format) = &format {
+            for (channel, port) in capture_port_names(&capture.name, format.channels)
+                .into_iter()
+                .enumerate()
+            {
+                capture_ports.push((port, capture_i, channel));
+            }
+        }
+
+        capture_formats.push(format);
+    }
+
+    let mut audio_in_busses = Vec::<AudioBus>::new();
+    let mut in_channel_map = Vec::<Vec<ChannelRoute<(usize, usize)>>>::new();
+    let mut used_captures = Vec::<usize>::new();
+    for (bus_i, bus) in config.audio_in_busses.iter().enumerate() {
+        if bus.system_ports.is_empty() {
+            return Err(SpawnRtThreadError::NoSystemPortsGiven(bus.id.clone()));
+        }
+
+        let mut channels = Vec::<(usize, usize)>::new();
+        let mut half_duplex_device = None;
+        for system_port in bus.system_ports.iter() {
+            let (_, capture_i, channel) = capture_ports
+                .iter()
+                .find(|(port, _, _)| port == system_port)
+                .ok_or_else(|| {
+                    SpawnRtThreadError::SystemPortNotFound(system_port.clone(), bus.id.clone())
+                })?;
+
+            // Captures are opened in the order they are first used.
+            let stream_i = match used_captures.iter().position(|c| c == capture_i) {
+                Some(stream_i) => stream_i,
+                None => {
+                    used_captures.push(*capture_i);
+                    used_captures.len() - 1
+                }
+            };
+
+            half_duplex_device = Some(captures[*capture_i].name.clone());
+            channels.push((stream_i, *channel));
+        }
+
+        let channel_layout = bus.channel_layout();
+        let num_channels = channel_layout.num_channels() as u16;
+        audio_in_busses.push(AudioBus {
+            id_name: bus.id.clone(),
+            id_index: DeviceIndex::new(bus_i),
+            system_device: config.system_audio_device.clone(),
+            system_half_duplex_device: half_duplex_device,
+            system_ports: bus.system_ports.clone(),
+            client_ports: Vec::new(),
+            channels: num_channels,
+            channel_layout,
+            connected_ports: num_channels,
+        });
+        in_channel_map.push(channel_routes(bus, &channels, true));
+    }
+
+    // Open the render client.
+
+    let buffer_duration = config
+        .buffer_size
+        .map(|frames| i64::from(frames) * REFTIMES_PER_SEC / i64::from(sample_rate))
+        .unwrap_or(0); // Use the default device period.
+
+    let render_client = activate_audio_client(&render_endpoint.device)?;
+    let format = float_format(
+        render_format.channels,
+        sample_rate,
+        render_format.channel_mask,
+    );
+    check(unsafe {
+        render_client.Initialize(
+            AUDCLNT_SHAREMODE_SHARED,
+            AUDCLNT_STREAMFLAGS_EVENTCALLBACK
+                | AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM
+                | AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY,
+            buffer_duration,
+            0,
+            &format as *const WAVEFORMATEXTENSIBLE as *const WAVEFORMATEX,
+            ptr::null(),
+        )
+    })?;
+
+    let event = unsafe { CreateEventW(ptr::null_mut(), FALSE, FALSE, ptr::null()) };
+    if event.is_null() {
+        return Err(WasapiError::last_os_error().into());
+    }
+    if let Err(e) = check(unsafe { render_client.SetEventHandle(event) }) {
+        unsafe { CloseHandle(event) };
+        return Err(e.into());
+    }
+
+    let mut buffer_frames = 0;
+    check(unsafe { render_client.GetBufferSize(&mut buffer_frames) })?;
+
+    let render = get_service::<IAudioRenderClient>(&render_client)?;
+
+    // A written buffer is played after the one that is already queued.
+    let latency_frames_out = buffer_frames + stream_latency_frames(&render_client, sample_rate)?;
+
+    // Open the capture clients.
+
+    let mut capture_streams = Vec::<CaptureStream>::new();
+    let mut latency_frames_in = 0;
+    for capture_i in used_captures.iter() {
+        let capture_format = capture_formats[*capture_i]
+            .as_ref()
+            .expect("capture ports only exist for probed endpoints");
+        let channels = capture_format.channels as usize;
+
+        let client = activate_audio_client(&captures[*capture_i].device)?;
+        let format = float_format(
+            capture_format.channels,
+            sample_rate,
+            capture_format.channel_mask,
+        );
+        check(unsafe {
+            client.Initialize(
+                AUDCLNT_SHAREMODE_SHARED,
+                AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM | AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY,
+                i64::from(buffer_frames) * CAPTURE_FIFO_PERIODS as i64 * REFTIMES_PER_SEC
+                    / i64::from(sample_rate),
+                0,
+                &format as *const WAVEFORMATEXTENSIBLE as *const WAVEFORMATEX,
+                ptr::null(),
+            )
+        })?;
+
+        let capture = get_service::<IAudioCaptureClient>(&client)?;
+
+        // Captured audio is only processed once a whole buffer of it is available.
+        latency_frames_in =
+            latency_frames_in.max(buffer_frames + stream_latency_frames(&client, sample_rate)?);
+
+        let (fifo_producer, fifo_consumer) =
+            RingBuffer::<f32>::new(buffer_frames as usize * channels * CAPTURE_FIFO_PERIODS)
+                .split();
+
+        capture_streams.push(CaptureStream {
+            client,
+            capture,
+            channels,
+            fifo_producer,
+            fifo_consumer,
+            scratch: vec![0.0; buffer_frames as usize * channels],
+        });
+    }
+
+    // Listen for removed devices.
+
+    let mut device_ids = vec![render_endpoint.id.clone()];
+    for capture_i in used_captures.iter() {
+        device_ids.push(captures[*capture_i].id.clone());
+    }
+    let mut notification_client = NotificationClient::new(device_ids);
+    check(unsafe {
+        enumerator.RegisterEndpointNotificationCallback(
+            &mut *notification_client as *mut NotificationClient as *mut IMMNotificationClient,
+        )
+    })?;
+
+    if let Some(requested) = config.buffer_size {
+        if requested != buffer_frames {
+            warn!(
+                "WASAPI: ignored the requested buffer size of {} frames, the device runs at {} frames",
+                requested, buffer_frames
+            );
+        }
+    }
+
+    let stream_info = StreamInfo {
+        server_name: String::from("WASAPI"),
+        audio_in: audio_in_busses,
+        audio_out: audio_out_busses,
+        midi_in: Vec::new(),
+        midi_out: Vec::new(),
+        sample_rate,
+        max_audio_buffer_size: buffer_frames,
+        latency_frames_in,
+        latency_frames_out,
+        sample_format: SampleFormat::F32,
+    };
+
+    Ok(WasapiStream {
+        stream_info,
+        render_client,
+        render,
+        render_channels: render_format.channels as usize,
+        buffer_frames,
+        event,
+        captures: capture_streams,
+        in_channel_map,
+        out_channel_map,
+        enumerator,
+        notification_client,
+        _com: com,
+    })
+}
+
+/// Create the output busses and route each of their channels to channels of the render
+/// endpoint.
+fn map_out_busses(
+    busses: &[AudioBusConfig],
+    device: &str,
+    device_channels: usize,
+) -> Result<(Vec<AudioBus>, Vec<Vec<ChannelRoute<usize>>>), SpawnRtThreadError> {
+    let mut audio_busses = Vec::<AudioBus>::new();
+    let mut channel_map = Vec::<Vec<ChannelRoute<usize>>>::new();
+
+    for (bus_i, bus) in busses.iter().enumerate() {
+        if bus.system_ports.is_empty() {
+            return Err(SpawnRtThreadError::NoSystemPortsGiven(bus.id.clone()));
+        }
+
+        let mut channels = Vec::<usize>::new();
+        for system_port in bus.system_ports.iter() {
+            let channel = parse_playback_channel(system_port)
+                .filter(|channel| *channel < device_channels)
+                .ok_or_else(|| {
+                    SpawnRtThreadError::SystemPortNotFound(system_port.clone(), bus.id.clone())
+                })?;
+
+            channels.push(channel);
+        }
+
+        let channel_layout = bus.channel_layout();
+        let num_channels = channel_layout.num_channels() as u16;
+        audio_busses.push(AudioBus {
+            id_name: bus.id.clone(),
+            id_index: DeviceIndex::new(bus_i),
+            system_device: String::from(device),
+            system_half_duplex_device: None,
+            system_ports: bus.system_ports.clone(),
+            client_ports: Vec::new(),
+            channels: num_channels,
+            channel_layout,
+            connected_ports: num_channels,
+        });
+        channel_map.push(channel_routes(bus, &channels, false));
+    }
+
+    Ok((audio_busses, channel_map))
+}
+
+/// Mix the channels of each bus into an interleaved endpoint buffer. Endpoint channels that
+/// more than one channel is routed to are summed.
+fn interleave(
+    busses: &[AudioBusBuffer],
+    channel_map: &[Vec<ChannelRoute<usize>>],
+    device_channels: usize,
+    frames: usize,
+    interleaved: &mut [f32],
+) {
+    for s in interleaved.iter_mut() {
+        *s = 0.0;
+    }
+
+    for (audio_buffer, routes) in busses.iter().zip(channel_map.iter()) {
+        for (channel, route) in audio_buffer.channel_buffers.iter().zip(routes.iter()) {
+            // Just in case the user resized the output buffer for some reason.
+            let len = channel.len().min(frames);
+            if len != frames {
+                warn!(
+                    "Warning: An audio output buffer was resized from {} to {} by the user",
+                    frames, len
+                );
+            }
+
+            for device_channel in route.ports.iter() {
+                for (dst, src) in interleaved
+                    .iter_mut()
+                    .skip(*device_channel)
+                    .step_by(device_channels)
+                    .zip(channel[0..len].iter())
+                {
+                    *dst += *src * route.gain;
+                }
+            }
+        }
+    }
+}
+
+/// The mix format and period of an endpoint.
+struct EndpointFormat {
+    channels: u16,
+    sample_rate: u32,
+    channel_mask: DWORD,
+    default_period_frames: u32,
+}
+
+fn probe_endpoint(device: &ComPtr<IMMDevice>) -> Result<EndpointFormat, WasapiError> {
+    let client = activate_audio_client(device)?;
+
+    let mut mix_format = ptr::null_mut();
+    check(unsafe { client.GetMixFormat(&mut mix_format) })?;
+
+    let format = unsafe { ptr::read_unaligned(mix_format) };
+    let channel_mask = if format.wFormatTag == WAVE_FORMAT_EXTENSIBLE {
+        unsafe { ptr::read_unaligned(mix_format as *const WAVEFORMATEXTENSIBLE) }.dwChannelMask
+    } else {
+        0
+    };
+
+    unsafe { CoTaskMemFree(mix_format as *mut _) };
+
+    let mut default_period = 0;
+    let mut min_period = 0;
+    check(unsafe { client.GetDevicePeriod(&mut default_period, &mut min_period) })?;
+
+    Ok(EndpointFormat {
+        channels: format.nChannels,
+        sample_rate: format.nSamplesPerSec,
+        channel_mask,
+        default_period_frames: (default_period * i64::from(format.nSamplesPerSec)
+            / REFTIMES_PER_SEC) as u32,
+    })
+}
+
+/// A 32 bit float format. Shared mode streams convert this to the mix format automatically.
+fn float_format(channels: u16, sample_rate: u32, channel_mask: DWORD) -> WAVEFORMATEXTENSIBLE {
+    let block_align = channels * 4;
+
+    WAVEFORMATEXTENSIBLE {
+        Format: WAVEFORMATEX {
+            wFormatTag: WAVE_FORMAT_EXTENSIBLE,
+            nChannels: channels,
+            nSamplesPerSec: sample_rate,
+            nAvgBytesPerSec: sample_rate * u32::from(block_align),
+            nBlockAlign: block_align,
+            wBitsPerSample: 32,
+            cbSize: (std::mem::size_of::<WAVEFORMATEXTENSIBLE>()
+                - std::mem::size_of::<WAVEFORMATEX>()) as u16,
+        },
+        Samples: 32,
+        dwChannelMask: channel_mask,
+        SubFormat: KSDATAFORMAT_SUBTYPE_IEEE_FLOAT,
+    }
+}
+
+struct Endpoint {
+    /// The endpoint ID string (without the terminating nul).
+    id: Vec<u16>,
+    name: String,
+    device: ComPtr<IMMDevice>,
+}
+
+fn create_enumerator() -> Result<ComPtr<IMMDeviceEnumerator>, WasapiError> {
+    let mut enumerator = ptr::null_mut();
+    check(unsafe {
+        CoCreateInstance(
+            &CLSID_MMDeviceEnumerator,
+            ptr::null_mut(),
+            CLSCTX_ALL,
+            &IMMDeviceEnumerator::uuidof(),
+            &mut enumerator,
+        )
+    })?;
+
+    Ok(unsafe { ComPtr::from_raw(enumerator as *mut IMMDeviceEnumerator) })
+}
+
+fn enumerate_endpoints(
+    enumerator: &ComPtr<IMMDeviceEnumerator>,
+    flow: EDataFlow,
+) -> Result<Vec<Endpoint>, WasapiError> {
+    let mut collection = ptr::null_mut();
+    check(unsafe { enumerator.EnumAudioEndpoints(flow, DEVICE_STATE_ACTIVE, &mut collection) })?;
+    let collection = unsafe { ComPtr::<IMMDeviceCollection>::from_raw(collection) };
+
+    let mut count: UINT = 0;
+    check(unsafe { collection.GetCount(&mut count as *mut UINT) })?;
+
+    let mut endpoints = Vec::<Endpoint>::new();
+    for i in 0..count {
+        let mut device = ptr::null_mut();
+        check(unsafe { collection.Item(i, &mut device) })?;
+        let device = unsafe { ComPtr::from_raw(device) };
+
+        let id = endpoint_id(&device)?;
+        let name = match friendly_name(&device) {
+            Ok(name) => name,
+            Err(e) => {
+                debug!("WASAPI: could not get name of endpoint: {}", e);
+                continue;
+            }
+        };
+
+        endpoints.push(Endpoint { id, name, device });
+    }
+
+    Ok(endpoints)
+}
+
+fn default_endpoint_id(
+    enumerator: &ComPtr<IMMDeviceEnumerator>,
+    flow: EDataFlow,
+) -> Option<Vec<u16>> {
+    let mut device = ptr::null_mut();
+    let hr = unsafe { enumerator.GetDefaultAudioEndpoint(flow, eConsole, &mut device) };
+    if FAILED(hr) {
+        return None;
+    }
+
+    let device = unsafe { ComPtr::from_raw(device) };
+    endpoint_id(&device).ok()
+}
+
+fn endpoint_id(device: &ComPtr<IMMDevice>) -> Result<Vec<u16>, WasapiError> {
+    let mut id: LPWSTR = ptr::null_mut();
+    check(unsafe { device.GetId(&mut id) })?;
+
+    let wide = unsafe { wide_from_ptr(id) };
+    unsafe { CoTaskMemFree(id as *mut _) };
+
+    Ok(wide)
+}
+
+fn friendly_name(device: &ComPtr<IMMDevice>) -> Result<String, WasapiError> {
+    let mut store = ptr::null_mut();
+    check(unsafe { device.OpenPropertyStore(STGM_READ, &mut store) })?;
+    let store = unsafe { ComPtr::<IPropertyStore>::from_raw(store) };
+
+    let mut value: PROPVARIANT = unsafe { std::mem::zeroed() };
+    check(unsafe { store.GetValue(&PKEY_Device_FriendlyName, &mut value) })?;
+
+    let name = unsafe {
+        let wide = wide_from_ptr(*value.data.pwszVal());
+        PropVariantClear(&mut value);
+        wide
+    };
+
+    Ok(OsString::from_wide(&name).to_string_lossy().into_owned())
+}
+
+/// Copy a nul-terminated wide string.
+unsafe fn wide_from_ptr(ptr: LPCWSTR) -> Vec<u16> {
+    if ptr.is_null() {
+        return Vec::new();
+    }
+
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+
+    std::slice::from_raw_parts(ptr, len).to_vec()
+}
+
+fn activate_audio_client(device: &ComPtr<IMMDevice>) -> Result<ComPtr<IAudioClient>, WasapiError> {
+    let mut client = ptr::null_mut();
+    check(unsafe {
+        device.Activate(
+            &IAudioClient::uuidof(),
+            CLSCTX_ALL,
+            ptr::null_mut(),
+            &mut client,
+        )
+    })?;
+
+    Ok(unsafe { ComPtr::from_raw(client as *mut IAudioClient) })
+}
+
+/// The latency that WASAPI adds to an initialized stream, in frames.
+fn stream_latency_frames(
+    client: &ComPtr<IAudioClient>,
+    sample_rate: u32,
+) -> Result<u32, WasapiError> {
+    let mut latency = 0;
+    check(unsafe { client.GetStreamLatency(&mut latency) })?;
+
+    Ok((latency * i64::from(sample_rate) / REFTIMES_PER_SEC) as u32)
+}
+
+fn get_service<T: Interface>(client: &ComPtr<IAudioClient>) -> Result<ComPtr<T>, WasapiError> {
+    let mut service = ptr::null_mut();
+    check(unsafe { client.GetService(&T::uuidof(), &mut service) })?;
+
+    Ok(unsafe { ComPtr::from_raw(service as *mut T) })
+}
+
+/// Listens for endpoints used by the stream being removed or disabled.
+///
+/// This is a hand-rolled COM object. It is owned by `WasapiStream` and outlives its registration,
+/// so the reference count is only kept for COM's sake.
+#[repr(C)]
+struct NotificationClient {
+    vtbl: *const IMMNotificationClientVtbl,
+    refs: AtomicU32,
+    device_ids: Vec<Vec<u16>>,
+    device_lost: AtomicBool,
+}
+
+static NOTIFICATION_CLIENT_VTBL: IMMNotificationClientVtbl = IMMNotificationClientVtbl {
+    parent: IUnknownVtbl {
+        QueryInterface: notification_query_interface,
+        AddRef: notification_add_ref,
+        Release: notification_release,
+    },
+    OnDeviceStateChanged: notification_device_state_changed,
+    OnDeviceAdded: notification_device_added,
+    OnDeviceRemoved: notification_device_removed,
+    OnDefaultDeviceChanged: notification_default_device_changed,
+    OnPropertyValueChanged: notification_property_value_changed,
+};
+
+impl NotificationClient {
+    fn new(device_ids: Vec<Vec<u16>>) -> Box<Self> {
+        Box::new(Self {
+            vtbl: &NOTIFICATION_CLIENT_VTBL,
+            refs: AtomicU32::new(1),
+            device_ids,
+            device_lost: AtomicBool::new(false),
+        })
+    }
+
+    unsafe fn is_used_device(&self, id: LPCWSTR) -> bool {
+        let id = wide_from_ptr(id);
+        self.device_ids.contains(&id)
+    }
+}
+
+unsafe extern "system" fn notification_query_interface(
+    this: *mut IUnknown,
+    riid: REFIID,
+    ppv: *mut *mut winapi::ctypes::c_void,
+) -> HRESULT {
+    if ppv.is_null() {
+        return E_POINTER;
+    }
+
+    if IsEqualIID(&*riid, &IUnknown::uuidof())
+        || IsEqualIID(&*riid, &IMMNotificationClient::uuidof())
+    {
+        notification_add_ref(this);
+        *ppv = this as *mut _;
+        S_OK
+    } else {
+        *ppv = ptr::null_mut();
+        E_NOINTERFACE
+    }
+}
+
+unsafe extern "system" fn notification_add_ref(this: *mut IUnknown) -> ULONG {
+    let client = &*(this as *const NotificationClient);
+    client.refs.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+unsafe extern "system" fn notification_release(this: *mut IUnknown) -> ULONG {
+    let client = &*(this as *const NotificationClient);
+    client.refs.fetch_sub(1, Ordering::Relaxed) - 1
+}
+
+unsafe extern "system" fn notification_device_state_changed(
+    this: *mut IMMNotificationClient,
+    id: LPCWSTR,
+    new_state: DWORD,
+) -> HRESULT {
+    let client = &*(this as *const NotificationClient);
+    if new_state != DEVICE_STATE_ACTIVE && client.is_used_device(id) {
+        client.device_lost.store(true, Ordering::Relaxed);
+    }
+    S_OK
+}
+
+unsafe extern "system" fn notification_device_added(
+    _this: *mut IMMNotificationClient,
+    _id: LPCWSTR,
+) -> HRESULT {
+    S_OK
+}
+
+unsafe extern "system" fn notification_device_removed(
+    this: *mut IMMNotificationClient,
+    id: LPCWSTR,
+) -> HRESULT {
+    let client = &*(this as *const NotificationClient);
+    if client.is_used_device(id) {
+        client.device_lost.store(true, Ordering::Relaxed);
+    }
+    S_OK
+}
+
+unsafe extern "system" fn notification_default_device_changed(
+    _this: *mut IMMNotificationClient,
+    _flow: EDataFlow,
+    _role: ERole,
+    _id: LPCWSTR,
+) -> HRESULT {
+    S_OK
+}
+
+unsafe extern "system" fn notification_property_value_changed(
+    _this: *mut IMMNotificationClient,
+    _id: LPCWSTR,
+    _key: PROPERTYKEY,
+) -> HRESULT {
+    S_OK
+}
+
+/// An owned reference to a COM interface that is released on drop.
+struct ComPtr<T: Interface>(*mut T);
+
+impl<T: Interface> ComPtr<T> {
+    /// Take ownership of a reference returned by a COM method.
+    unsafe fn from_raw(ptr: *mut T) -> Self {
+        Self(ptr)
+    }
+}
+
+impl<T: Interface> std::ops::Deref for ComPtr<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.0 }
+    }
+}
+
+impl<T: Interface> Drop for ComPtr<T> {
+    fn drop(&mut self) {
+        unsafe {
+            (*(self.0 as *mut IUnknown)).Release();
+        }
+    }
+}
+
+/// Initializes COM on the current thread for as long as it is alive.
+struct ComGuard {
+    uninitialize: bool,
+}
+
+impl ComGuard {
+    fn new() -> Result<Self, WasapiError> {
+        let hr = unsafe { CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED) };
+
+        if SUCCEEDED(hr) {
+            Ok(Self { uninitialize: true })
+        } else if hr == RPC_E_CHANGED_MODE {
+            // COM was already initialized on this thread in a different mode, which is fine.
+            Ok(Self {
+                uninitialize: false,
+            })
+        } else {
+            Err(WasapiError { hr })
+        }
+    }
+}
+
+impl Drop for ComGuard {
+    fn drop(&mut self) {
+        if self.uninitialize {
+            unsafe { CoUninitialize() };
+        }
+    }
+}
+
+fn check(hr: HRESULT) -> Result<(), WasapiError> {
+    if FAILED(hr) {
+        Err(WasapiError { hr })
+    } else {
+        Ok(())
+    }
+}
+
+/// Why the process loop stopped before the stream was stopped.
+enum LoopError {
+    Wasapi(WasapiError),
+    Panicked(FatalStreamError),
+}
+
+impl From<WasapiError> for LoopError {
+    fn from(e: WasapiError) -> Self {
+        LoopError::Wasapi(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct WasapiError {
+    hr: HRESULT,
+}
+
+impl WasapiError {
+    fn last_os_error() -> Self {
+        Self {
+            hr: HRESULT_FROM_WIN32(unsafe { GetLastError() }),
+        }
+    }
+}
+
+impl std::error::Error for WasapiError {}
+
+impl std::fmt::Display for WasapiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WASAPI error 0x{:08X}", self.hr as u32)
+    }
+}
+
+impl From<WasapiError> for SpawnRtThreadError {
+    fn from(e: WasapiError) -> Self {
+        SpawnRtThreadError::PlatformSpecific(Box::new(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Routes every channel to one endpoint channel at unity gain.
+    fn direct(device_channels: &[usize]) -> Vec<ChannelRoute<usize>> {
+        device_channels
+            .iter()
+            .map(|channel| ChannelRoute {
+                ports: vec![*channel],
+                gain: 1.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_playback_ports() {
+        assert_eq!(parse_playback_channel("playback_1"), Some(0));
+        assert_eq!(parse_playback_channel("playback_8"), Some(7));
+        assert_eq!(parse_playback_channel("playback_0"), None);
+        assert_eq!(parse_playback_channel("Microphone:capture_1"), None);
+    }
+
+    #[test]
+    fn interleave_mixes_shared_channels() {
+        let mut bus_a = AudioBusBuffer::new(2, 2);
+        bus_a.clear_and_resize(2);
+        bus_a.channel_buffers[0].copy_from_slice(&[0.125, 0.25]);
+        bus_a.channel_buffers[1].copy_from_slice(&[0.375, 0.5]);
+
+        let mut bus_b = AudioBusBuffer::new(1, 2);
+        bus_b.clear_and_resize(2);
+        bus_b.channel_buffers[0].copy_from_slice(&[0.5, 0.5]);
+
+        let channel_map = vec![direct(&[0, 1]), direct(&[1])];
+        let mut interleaved = [1.0; 6];
+
+        interleave(&[bus_a, bus_b], &channel_map, 3, 2, &mut interleaved);
+
+        assert_eq!(interleaved, [0.125, 0.875, 0.0, 0.25, 1.0, 0.0]);
+    }
+}
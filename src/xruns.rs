@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+type Hook = Box<dyn FnMut(u64) + Send + 'static>;
+
+/// Counts the xruns of a stream and calls the hook set with `StreamHandle::on_xrun()`.
+/// This is shared between the stream handle and whichever thread is told about xruns.
+pub(crate) struct Xruns {
+    count: AtomicU64,
+    /// When the last xrun happened, as nanoseconds after `start` plus one, or `0` if there
+    /// hasn't been one yet. This keeps reading it lock-free.
+    last: AtomicU64,
+    start: Instant,
+    hook: Mutex<Option<Hook>>,
+}
+
+impl Xruns {
+    pub fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            last: AtomicU64::new(0),
+            start: Instant::now(),
+            hook: Mutex::new(None),
+        }
+    }
+
+    /// Replace the hook.
+    pub fn set_hook(&self, hook: Hook) {
+        *self.hook.lock().unwrap() = Some(hook);
+    }
+
+    /// Count an xrun and call the hook with the new total. This never allocates or waits
+    /// for the hook to be replaced; if that happens at the same time, the hook is skipped
+    /// for this xrun.
+    #[cfg(any(test, target_os = "linux", feature = "cpal"))]
+    pub fn xrun(&self) {
+        let since_start = self
+            .start
+            .elapsed()
+            .as_nanos()
+            .min(u128::from(u64::MAX - 1)) as u64;
+        self.last.store(since_start + 1, Ordering::Relaxed);
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if let Ok(mut hook) = self.hook.try_lock() {
+            if let Some(hook) = hook.as_mut() {
+                hook(count);
+            }
+        }
+    }
+
+    /// The total number of xruns since the stream started.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// When the last xrun happened, or `None` if there hasn't been one yet.
+    pub fn last(&self) -> Option<Instant> {
+        match self.last.load(Ordering::Relaxed) {
+            0 => None,
+            last => Some(self.start + Duration::from_nanos(last - 1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn counts_and_calls_hook() {
+        let xruns = Xruns::new();
+        assert_eq!(xruns.last(), None);
+
+        let before = Instant::now();
+        xruns.xrun();
+        assert_eq!(xruns.count(), 1);
+        let first = xruns.last().unwrap();
+        assert!(first >= before && first <= Instant::now());
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = Arc::clone(&seen);
+        xruns.set_hook(Box::new(move |count| hook_seen.lock().unwrap().push(count)));
+
+        xruns.xrun();
+        xruns.xrun();
+
+        assert_eq!(xruns.count(), 3);
+        assert!(xruns.last().unwrap() >= first);
+        assert_eq!(*seen.lock().unwrap(), vec![2, 3]);
+    }
+}